@@ -0,0 +1,150 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Проверка загрузок антивирусом: every upload is streamed to a ClamAV daemon
+// with INSTREAM before anything decodes it. An infected file is deleted and
+// refused; what happens when clamd can't answer is up to `fail_open`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AntivirusConfig {
+    // "tcp://127.0.0.1:3310" or "unix:/run/clamav/clamd.ctl", off when empty
+    pub clamd: String,
+    pub timeout_secs: u64,
+    // On: uploads are let through unscanned while clamd is down or failing;
+    // off: they are refused with 503
+    pub fail_open: bool,
+    // Bytes per INSTREAM chunk; a file over clamd's StreamMaxLength fails
+    // the scan whatever the chunks
+    pub chunk_size: usize,
+}
+
+impl Default for AntivirusConfig {
+    fn default() -> Self {
+        AntivirusConfig {
+            clamd: String::new(),
+            timeout_secs: 30,
+            fail_open: false,
+            chunk_size: 64 << 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Address {
+    pub fn parse(address: &str) -> Option<Address> {
+        if let Some(host_port) = address.strip_prefix("tcp://") {
+            return Some(Address::Tcp(host_port.to_owned()));
+        }
+        match address.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => Some(Address::Unix(PathBuf::from(path))),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AntivirusError {
+    #[error("Upload is infected with {0}")]
+    Infected(String),
+    // The cause is only logged
+    #[error("Virus scanner unavailable")]
+    Unavailable,
+}
+
+// What clamd found, `None` for a clean file
+pub fn parse_reply(reply: &str) -> Result<Option<String>, String> {
+    let reply = reply.trim_end_matches(|c: char| c == '\0' || c.is_whitespace());
+    let result = reply.strip_prefix("stream: ").unwrap_or(reply);
+    if result == "OK" {
+        return Ok(None);
+    }
+    match result.strip_suffix(" FOUND") {
+        Some(signature) => Ok(Some(signature.to_owned())),
+        None => Err(reply.to_owned()),
+    }
+}
+
+async fn instream<S: AsyncRead + AsyncWrite + Unpin>(
+    mut conn: S,
+    path: &Path,
+    chunk_size: usize,
+) -> std::io::Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut chunk = vec![0; chunk_size.max(1)];
+    conn.write_all(b"zINSTREAM\0").await?;
+    loop {
+        let read = file.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+        conn.write_all(&(read as u32).to_be_bytes()).await?;
+        conn.write_all(&chunk[..read]).await?;
+    }
+    conn.write_all(&[0; 4]).await?;
+    conn.flush().await?;
+
+    // clamd closes the connection after the reply
+    let mut reply = Vec::new();
+    conn.read_to_end(&mut reply).await?;
+    Ok(String::from_utf8_lossy(&reply).into_owned())
+}
+
+async fn ask_clamd(config: &AntivirusConfig, path: &Path) -> std::io::Result<String> {
+    let invalid = || {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid clamd address {:?}", config.clamd),
+        )
+    };
+    match Address::parse(&config.clamd).ok_or_else(invalid)? {
+        Address::Tcp(address) => {
+            instream(tokio::net::TcpStream::connect(address).await?, path, config.chunk_size).await
+        }
+        #[cfg(unix)]
+        Address::Unix(socket) => {
+            instream(tokio::net::UnixStream::connect(socket).await?, path, config.chunk_size).await
+        }
+        #[cfg(not(unix))]
+        Address::Unix(_) => Err(invalid()),
+    }
+}
+
+// Scans a file if clamd is configured
+pub async fn check(config: &AntivirusConfig, path: &Path) -> Result<(), AntivirusError> {
+    if config.clamd.is_empty() {
+        return Ok(());
+    }
+
+    let reply = tokio::time::timeout(Duration::from_secs(config.timeout_secs), ask_clamd(config, path)).await;
+    let failure = match reply {
+        Ok(Ok(reply)) => match parse_reply(&reply) {
+            Ok(None) => return Ok(()),
+            Ok(Some(signature)) => {
+                tracing::warn!("{} is infected with {}", path.to_str().unwrap_or("?"), signature);
+                return Err(AntivirusError::Infected(signature));
+            }
+            Err(reply) => reply,
+        },
+        Ok(Err(err)) => err.to_string(),
+        Err(_) => "clamd timed out".to_owned(),
+    };
+
+    if config.fail_open {
+        tracing::warn!(
+            "Virus scan failed, {} let through: {}",
+            path.to_str().unwrap_or("?"),
+            failure
+        );
+        return Ok(());
+    }
+    tracing::error!("Virus scan failed for {}: {}", path.to_str().unwrap_or("?"), failure);
+    Err(AntivirusError::Unavailable)
+}
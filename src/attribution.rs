@@ -0,0 +1,77 @@
+use serde::Deserialize;
+
+// Авторство, записываемое в производные изображения (XMP)
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AttributionConfig {
+    pub copyright: String,
+    pub creator: String,
+    // `{id}` is replaced with the image id
+    pub source_url: String,
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl AttributionConfig {
+    pub fn is_empty(&self) -> bool {
+        self.copyright.is_empty() && self.creator.is_empty() && self.source_url.is_empty()
+    }
+
+    // XMP packet with the IPTC Core equivalents: dc:rights, dc:creator and
+    // photoshop:Source. `None` if nothing is configured.
+    pub fn xmp_packet(&self, id: &str) -> Option<String> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let mut fields = String::new();
+        if !self.copyright.is_empty() {
+            fields.push_str(&format!(
+                "<dc:rights><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:rights>",
+                escape_xml(&self.copyright)
+            ));
+        }
+        if !self.creator.is_empty() {
+            fields.push_str(&format!(
+                "<dc:creator><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:creator>",
+                escape_xml(&self.creator)
+            ));
+        }
+        if !self.source_url.is_empty() {
+            fields.push_str(&format!(
+                "<photoshop:Source>{}</photoshop:Source>",
+                escape_xml(&self.source_url.replace("{id}", id))
+            ));
+        }
+
+        Some(format!(
+            concat!(
+                "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>",
+                "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">",
+                "<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">",
+                "<rdf:Description rdf:about=\"\"",
+                " xmlns:dc=\"http://purl.org/dc/elements/1.1/\"",
+                " xmlns:photoshop=\"http://ns.adobe.com/photoshop/1.0/\">",
+                "{}",
+                "</rdf:Description>",
+                "</rdf:RDF>",
+                "</x:xmpmeta>",
+                "<?xpacket end=\"r\"?>"
+            ),
+            fields
+        ))
+    }
+}
@@ -0,0 +1,188 @@
+use std::path::{Path, PathBuf};
+
+use actix_web::http::header::HeaderMap;
+use actix_web::http::Method;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use crate::auth::{self, Principal};
+use crate::metadata::METADATA_DIR;
+use crate::Config;
+
+// Under `metadata::METADATA_DIR`, the upload listings skip it
+pub const AUDIT_DIR: &str = "audit";
+
+// Журнал аудита: every request that may change something, any method but
+// GET, HEAD and OPTIONS, is recorded once answered: who made it, when, what
+// it asked for, from where and how it ended. Refused ones too. Events go
+// to one JSON line each in `meta/audit/{YYYY-MM-DD}.jsonl` of their UTC
+// day; the files are only ever appended to.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub enabled: bool,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig { enabled: true }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    // Unix time of the response
+    pub at: u64,
+    // See `actor`
+    pub actor: String,
+    pub method: String,
+    // Without the query, which may carry signatures
+    pub path: String,
+    // The route it matched, `/images/{id}` and the like; none for 404s
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    // Of the connection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<String>,
+    pub status: u16,
+}
+
+pub fn is_mutating(method: &Method) -> bool {
+    !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+// `key:{name}` or `token:{sub}`, `guest` (never the guest's token),
+// `anonymous` while the API is open, `unauthenticated` for the rest
+pub fn actor(config: &Config, headers: &HeaderMap) -> String {
+    match auth::authenticate(config, headers) {
+        Some(Principal::Key(api_key)) => format!("key:{}", api_key.name),
+        Some(Principal::Token(claims)) => format!("token:{}", claims.sub),
+        Some(Principal::Guest(_)) => "guest".to_owned(),
+        Some(Principal::Anonymous) => "anonymous".to_owned(),
+        None => "unauthenticated".to_owned(),
+    }
+}
+
+fn dir<P: AsRef<Path>>(uploads_dir: P) -> PathBuf {
+    uploads_dir.as_ref().join(METADATA_DIR).join(AUDIT_DIR)
+}
+
+const DAY_SECS: u64 = 24 * 3600;
+
+// `YYYY-MM-DD` of a day since the epoch, civil calendar
+fn date(day: u64) -> String {
+    // Days from 0000-03-01, so leap days end a year
+    let days = day as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn file_name(at: u64) -> String {
+    format!("{}.jsonl", date(at / DAY_SECS))
+}
+
+// Appends in a single write, so lines of concurrent requests don't mix
+pub async fn record<P: AsRef<Path>>(uploads_dir: P, event: &AuditEvent) -> Result<()> {
+    let dir = dir(uploads_dir);
+    tokio::fs::create_dir_all(&dir).await?;
+
+    let mut line = serde_json::to_vec(event)?;
+    line.push(b'\n');
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(file_name(event.at)))
+        .await?;
+    file.write_all(&line).await?;
+    Ok(())
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditFilter {
+    // Unix times, both inclusive
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub actor: Option<String>,
+    pub method: Option<String>,
+    // Events whose path starts with it, `/images/abc` for one upload
+    pub path: Option<String>,
+}
+
+impl AuditFilter {
+    fn matches(&self, event: &AuditEvent) -> bool {
+        self.since.map(|since| event.at >= since).unwrap_or(true)
+            && self.until.map(|until| event.at <= until).unwrap_or(true)
+            && self.actor.as_ref().map(|actor| *actor == event.actor).unwrap_or(true)
+            && self
+                .method
+                .as_ref()
+                .map(|method| method.eq_ignore_ascii_case(&event.method))
+                .unwrap_or(true)
+            && self
+                .path
+                .as_ref()
+                .map(|path| event.path.starts_with(path.as_str()))
+                .unwrap_or(true)
+    }
+
+    // Whether a day's file may hold matching events
+    fn covers(&self, name: &str) -> bool {
+        let since = self.since.map(file_name);
+        let until = self.until.map(file_name);
+        since.map(|since| name >= since.as_str()).unwrap_or(true)
+            && until.map(|until| name <= until.as_str()).unwrap_or(true)
+    }
+}
+
+// Up to `limit` matching events, the latest first
+pub async fn query<P: AsRef<Path>>(uploads_dir: P, filter: &AuditFilter, limit: usize) -> Result<Vec<AuditEvent>> {
+    let mut names = Vec::new();
+    let mut entries = match tokio::fs::read_dir(dir(&uploads_dir)).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        if let Ok(name) = entry.file_name().into_string() {
+            if name.ends_with(".jsonl") && filter.covers(&name) {
+                names.push(name);
+            }
+        }
+    }
+    // Dates sort as strings
+    names.sort_unstable_by(|a, b| b.cmp(a));
+
+    let mut events = Vec::new();
+    for name in names {
+        let data = tokio::fs::read(dir(&uploads_dir).join(&name)).await?;
+        for line in data.split(|&byte| byte == b'\n').rev() {
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_slice::<AuditEvent>(line) {
+                Ok(event) if filter.matches(&event) => {
+                    events.push(event);
+                    if events.len() == limit {
+                        return Ok(events);
+                    }
+                }
+                Ok(_) => {}
+                // A line torn by a crash
+                Err(err) => tracing::warn!("Skipping audit line in {}: {}", name, err),
+            }
+        }
+    }
+    Ok(events)
+}
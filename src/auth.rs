@@ -0,0 +1,224 @@
+use actix_web::http::header::{self, HeaderMap};
+use serde::{Deserialize, Serialize};
+
+use crate::jwt::{self, Scope, TokenClaims};
+use crate::{ByteLimit, Config};
+
+// Статические ключи API из конфига
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiKey {
+    pub key: String,
+    pub name: String,
+    #[serde(default)]
+    pub admin: bool,
+    // Uploads with this key get the tenant's overrides, see `tenants::TenantStore`,
+    // and are stored under its prefix; the key lists and deletes only them
+    #[serde(default)]
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub limits: KeyLimits,
+}
+
+// Лимиты загрузок для ключа, on top of the instance's; 0 is unlimited. Usage
+// is counted by `quota`, see `quota::check_key`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyLimits {
+    // Per UTC day, deleting an upload doesn't give it back
+    pub max_uploads_per_day: u64,
+    // Of the key's stored originals
+    pub max_total_bytes: u64,
+    pub max_file_size: u64,
+}
+
+impl KeyLimits {
+    // The request's byte limit with the key's file size limit applied, so an
+    // upload over it is cut off while streaming
+    pub fn byte_limit(&self, config: &Config) -> ByteLimit {
+        let per_file = match (config.max_file_size, self.max_file_size) {
+            (0, limit) | (limit, 0) => limit,
+            (instance, key) => instance.min(key),
+        };
+        ByteLimit::new(per_file, config.max_request_size)
+    }
+}
+
+// Who is making the request
+#[derive(Debug, Clone)]
+pub enum Principal {
+    // No keys configured, the API is open
+    Anonymous,
+    Key(ApiKey),
+    // Token of a guest bucket, see `guest::GuestBuckets`
+    Guest(String),
+    // A verified JWT, see `jwt`
+    Token(TokenClaims),
+}
+
+impl Principal {
+    // Recorded as the owner of what the caller makes: the key's name or the
+    // token's subject
+    pub fn uploader(&self) -> Option<&str> {
+        match self {
+            Principal::Key(api_key) => Some(&api_key.name),
+            Principal::Token(claims) => Some(&claims.sub),
+            Principal::Anonymous | Principal::Guest(_) => None,
+        }
+    }
+}
+
+// Whose an upload finished apart from the request that started it is: tus
+// uploads and import jobs keep the one of the caller who created them
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Ownership {
+    pub owner: Option<String>,
+    pub tenant: Option<String>,
+    // Of the key, as they were at the creation
+    pub key_limits: Option<KeyLimits>,
+}
+
+impl Ownership {
+    // Whether `principal` is the creator, or an admin of the tenant
+    pub fn is_held_by(&self, principal: &Principal) -> bool {
+        let covered = principal
+            .admin_scope()
+            .map(|scope| scope.covers(self.tenant.as_deref()))
+            .unwrap_or(false);
+        let owner = principal.uploader();
+        covered || (owner.is_some() && owner == self.owner.as_deref())
+    }
+}
+
+impl Principal {
+    pub fn ownership(&self) -> Ownership {
+        match self {
+            Principal::Key(api_key) => Ownership {
+                owner: Some(api_key.name.clone()),
+                tenant: api_key.tenant.clone(),
+                key_limits: Some(api_key.limits.clone()),
+            },
+            Principal::Token(claims) => Ownership {
+                owner: Some(claims.sub.clone()),
+                tenant: None,
+                key_limits: None,
+            },
+            Principal::Anonymous | Principal::Guest(_) => Ownership::default(),
+        }
+    }
+}
+
+// `Authorization: Bearer <token>`
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let mut parts = value.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => Some(token.trim()),
+        _ => None,
+    }
+}
+
+// Compares in constant time, so a key can't be guessed byte by byte
+fn keys_equal(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub fn find_api_key<'a>(config: &'a Config, token: &str) -> Option<&'a ApiKey> {
+    config.api_keys.iter().find(|api_key| keys_equal(&api_key.key, token))
+}
+
+// `None` if the request carries no acceptable credentials. Guest tokens are
+// returned as is, the caller checks them against the bucket store.
+pub fn authenticate(config: &Config, headers: &HeaderMap) -> Option<Principal> {
+    let token = bearer_token(headers);
+
+    if let Some(api_key) = token.and_then(|token| find_api_key(config, token)) {
+        return Some(Principal::Key(api_key.clone()));
+    }
+    // Not taken for a guest token when it doesn't verify
+    if let Some(token) = token.filter(|token| config.jwt.enabled() && jwt::is_jwt(token)) {
+        return jwt::authenticate(token).map(Principal::Token);
+    }
+    if let Some(token) = token.filter(|_| config.guest.enabled) {
+        return Some(Principal::Guest(token.to_owned()));
+    }
+    if config.api_keys.is_empty() && !config.jwt.enabled() {
+        return Some(Principal::Anonymous);
+    }
+
+    None
+}
+
+// Operator-only endpoints: an admin key of no tenant or an admin token, or
+// any caller while the API is open. A tenant's admin key is an admin of the
+// tenant's uploads only, see `admin_scope`.
+pub fn is_admin(config: &Config, headers: &HeaderMap) -> bool {
+    match authenticate(config, headers) {
+        Some(Principal::Anonymous) => true,
+        Some(Principal::Key(api_key)) => api_key.admin && api_key.tenant.is_none(),
+        Some(Principal::Token(claims)) => claims.has(Scope::Admin),
+        Some(Principal::Guest(_)) | None => false,
+    }
+}
+
+// Whose uploads a caller may list and delete
+#[derive(Debug, Clone, PartialEq)]
+pub enum UploadScope {
+    // Operators: admin keys of no tenant, admin tokens, or anyone while the
+    // API is open
+    All,
+    // Any key of the tenant, admin or not
+    Tenant(String),
+}
+
+impl UploadScope {
+    pub fn tenant(&self) -> Option<&str> {
+        match self {
+            UploadScope::All => None,
+            UploadScope::Tenant(tenant) => Some(tenant),
+        }
+    }
+
+    // Whether an upload of `tenant` is in the scope
+    pub fn covers(&self, tenant: Option<&str>) -> bool {
+        match self {
+            UploadScope::All => true,
+            UploadScope::Tenant(own) => tenant == Some(own.as_str()),
+        }
+    }
+}
+
+// `None` for callers who may neither list nor delete
+pub fn upload_scope(config: &Config, headers: &HeaderMap) -> Option<UploadScope> {
+    match authenticate(config, headers)? {
+        Principal::Key(ApiKey {
+            tenant: Some(tenant), ..
+        }) => Some(UploadScope::Tenant(tenant)),
+        Principal::Key(api_key) if api_key.admin => Some(UploadScope::All),
+        Principal::Token(claims) if claims.has(Scope::Admin) => Some(UploadScope::All),
+        Principal::Anonymous => Some(UploadScope::All),
+        Principal::Key(_) | Principal::Token(_) | Principal::Guest(_) => None,
+    }
+}
+
+// Whose uploads a caller may moderate and invalidate: operators any, a
+// tenant's admin keys the tenant's. `None` for everyone else.
+pub fn admin_scope(config: &Config, headers: &HeaderMap) -> Option<UploadScope> {
+    authenticate(config, headers)?.admin_scope()
+}
+
+impl Principal {
+    pub fn admin_scope(&self) -> Option<UploadScope> {
+        match self {
+            Principal::Key(ApiKey {
+                admin: true,
+                tenant: Some(tenant),
+                ..
+            }) => Some(UploadScope::Tenant(tenant.clone())),
+            Principal::Key(api_key) if api_key.admin => Some(UploadScope::All),
+            Principal::Token(claims) if claims.has(Scope::Admin) => Some(UploadScope::All),
+            Principal::Anonymous => Some(UploadScope::All),
+            Principal::Key(_) | Principal::Token(_) | Principal::Guest(_) => None,
+        }
+    }
+}
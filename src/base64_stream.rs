@@ -0,0 +1,177 @@
+use bytes::Bytes;
+
+// Base64 text consumed per decoded chunk (multiple of 4), ~48 KiB of output
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+// Decodes a base64 string piece by piece, so only one small chunk of the
+// decoded image lives in memory at a time. Whitespace (line-wrapped input)
+// is skipped, it would otherwise shift the 4-character groups.
+pub struct Base64Chunks<'a> {
+    data: &'a [u8],
+    buf: Vec<u8>,
+}
+
+impl<'a> Base64Chunks<'a> {
+    pub fn new(data: &'a str) -> Base64Chunks<'a> {
+        Base64Chunks {
+            data: data.as_bytes(),
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+}
+
+impl<'a> Iterator for Base64Chunks<'a> {
+    type Item = Result<Bytes, base64::DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.buf.clear();
+
+        while self.buf.len() < CHUNK_SIZE {
+            match self.data.split_first() {
+                Some((&b, rest)) => {
+                    self.data = rest;
+                    if !b.is_ascii_whitespace() {
+                        self.buf.push(b);
+                    }
+                }
+                None => break,
+            }
+        }
+
+        if self.buf.is_empty() {
+            return None;
+        }
+
+        Some(base64::decode(&self.buf).map(Bytes::from))
+    }
+}
+
+// The longest `data:...;base64,` prefix waited for before giving up
+const MAX_DATA_URI_HEADER: usize = 256;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Base64Error {
+    #[error(transparent)]
+    DataUri(#[from] DataUriError),
+    #[error("Invalid base64 data: {0}")]
+    Decode(#[from] base64::DecodeError),
+}
+
+// Decodes base64 text pushed as it arrives, e.g. a JSON string read off the
+// request body, in the same chunks as `Base64Chunks`. A data URI prefix is
+// split off first, see `split_data_uri`.
+pub struct Base64Decoder {
+    // The start of the text, until it shows whether it's a data URI
+    header: Option<Vec<u8>>,
+    declared_type: Option<String>,
+    buf: Vec<u8>,
+}
+
+impl Default for Base64Decoder {
+    fn default() -> Base64Decoder {
+        Base64Decoder {
+            header: Some(Vec::new()),
+            declared_type: None,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+}
+
+impl Base64Decoder {
+    // The MIME type of a data URI, once its prefix has been pushed
+    pub fn declared_type(&self) -> Option<&str> {
+        self.declared_type.as_deref()
+    }
+
+    // Returns the chunks the text completes
+    pub fn push(&mut self, text: &[u8]) -> Result<Vec<Bytes>, Base64Error> {
+        let header = match &mut self.header {
+            Some(header) => header,
+            None => return self.decode(text),
+        };
+        header.extend_from_slice(text);
+        if maybe_data_uri(header) && !header.contains(&b',') {
+            if header.len() > MAX_DATA_URI_HEADER {
+                return Err(DataUriError::Malformed.into());
+            }
+            return Ok(Vec::new());
+        }
+        let header = self.header.take().unwrap_or_default();
+        self.start(&header)
+    }
+
+    // The last chunk, if the text didn't end on one
+    pub fn finish(mut self) -> Result<Option<Bytes>, Base64Error> {
+        // Still waiting for the ',' of a data URI, or too short to tell
+        if let Some(header) = self.header.take() {
+            if header.len() >= 5 {
+                return Err(DataUriError::Malformed.into());
+            }
+            self.decode(&header)?;
+        }
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(base64::decode(&self.buf)?.into()))
+    }
+
+    fn start(&mut self, text: &[u8]) -> Result<Vec<Bytes>, Base64Error> {
+        if text.len() < 5 || !maybe_data_uri(text) {
+            return self.decode(text);
+        }
+        let comma = text.iter().position(|&b| b == b',').ok_or(DataUriError::Malformed)?;
+        let header = std::str::from_utf8(&text[..=comma]).map_err(|_| DataUriError::Malformed)?;
+        let (declared_type, _) = split_data_uri(header)?;
+        self.declared_type = declared_type.map(str::to_owned);
+        self.decode(&text[comma + 1..])
+    }
+
+    fn decode(&mut self, text: &[u8]) -> Result<Vec<Bytes>, Base64Error> {
+        let mut chunks = Vec::new();
+        for &b in text.iter().filter(|b| !b.is_ascii_whitespace()) {
+            self.buf.push(b);
+            if self.buf.len() == CHUNK_SIZE {
+                chunks.push(base64::decode(&self.buf)?.into());
+                self.buf.clear();
+            }
+        }
+        Ok(chunks)
+    }
+}
+
+// Whether the text so far starts like `data:`, case-insensitively
+fn maybe_data_uri(text: &[u8]) -> bool {
+    let len = text.len().min(5);
+    text[..len].eq_ignore_ascii_case(&b"data:"[..len])
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DataUriError {
+    #[error("Data URI has no ',' separator")]
+    Malformed,
+    #[error("Data URI is not base64 encoded")]
+    NotBase64,
+}
+
+// Splits `data:image/png;base64,iVBOR...` into the declared MIME type and
+// the base64 payload. Plain base64 strings are passed through as is.
+pub fn split_data_uri(data: &str) -> Result<(Option<&str>, &str), DataUriError> {
+    let is_data_uri = data
+        .get(..5)
+        .map(|scheme| scheme.eq_ignore_ascii_case("data:"))
+        .unwrap_or(false);
+    if !is_data_uri {
+        return Ok((None, data));
+    }
+
+    let comma = data.find(',').ok_or(DataUriError::Malformed)?;
+    let (header, payload) = (&data[5..comma], &data[comma + 1..]);
+
+    let mut params = header.split(';').map(str::trim);
+    let mime_type = params.next().filter(|mime_type| !mime_type.is_empty());
+    if !params.any(|param| param.eq_ignore_ascii_case("base64")) {
+        return Err(DataUriError::NotBase64);
+    }
+
+    Ok((mime_type, payload))
+}
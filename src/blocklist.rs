@@ -0,0 +1,316 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{OnceLock, RwLock};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::{self, ImageMetadata};
+use crate::{clock, imagetools};
+
+// Список блокировки по отпечаткам: SHA-256 of the stored file or perceptual
+// hashes of known content, for takedown obligations. Entries come from an
+// operator file and from the admin API; every match is logged to an audit
+// file next to the API entries.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BlocklistConfig {
+    // Read at startup, one entry per line: `sha256 <hex> <reason>` or
+    // `phash <16 hex digits> <reason>`; `#` starts a comment
+    pub file: Option<PathBuf>,
+    pub action: BlockAction,
+    // Bits a perceptual hash may differ by and still match
+    pub max_distance: u32,
+}
+
+impl Default for BlocklistConfig {
+    fn default() -> Self {
+        BlocklistConfig {
+            file: None,
+            action: BlockAction::Reject,
+            max_distance: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlockAction {
+    Reject,
+    // Stored, but pending: only admins see it until approved or rejected
+    Quarantine,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashKind {
+    Sha256,
+    Phash,
+}
+
+impl HashKind {
+    fn hex_len(self) -> usize {
+        match self {
+            HashKind::Sha256 => 64,
+            HashKind::Phash => 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntrySource {
+    File,
+    Api,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockEntry {
+    pub kind: HashKind,
+    // Lowercase hex
+    pub hash: String,
+    pub reason: String,
+    #[serde(default)]
+    pub added_at: u64,
+    #[serde(skip_deserializing, default = "api_source")]
+    pub source: EntrySource,
+}
+
+fn api_source() -> EntrySource {
+    EntrySource::Api
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BlocklistError {
+    #[error("{0} must be {1} hex digits")]
+    InvalidHash(String, usize),
+    #[error("Unknown hash kind {0}")]
+    UnknownKind(String),
+    #[error("Upload matches a blocked {0:?} hash: {1}")]
+    Blocked(HashKind, String),
+}
+
+impl BlockEntry {
+    pub fn new(kind: HashKind, hash: &str, reason: &str, source: EntrySource) -> Result<BlockEntry, BlocklistError> {
+        let hash = hash.trim().to_ascii_lowercase();
+        if hash.len() != kind.hex_len() || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(BlocklistError::InvalidHash(hash, kind.hex_len()));
+        }
+        Ok(BlockEntry {
+            kind,
+            hash,
+            reason: reason.trim().to_owned(),
+            added_at: clock::unix_now(),
+            source,
+        })
+    }
+
+    // Bits apart for a perceptual hash, 0 for an equal checksum
+    fn distance(&self, checksum: Option<&str>, phash: Option<u64>) -> Option<u32> {
+        match self.kind {
+            HashKind::Sha256 => checksum.filter(|checksum| *checksum == self.hash).map(|_| 0),
+            HashKind::Phash => {
+                let blocked = u64::from_str_radix(&self.hash, 16).ok()?;
+                phash.map(|phash| imagetools::hamming_distance(blocked, phash))
+            }
+        }
+    }
+}
+
+fn parse_line(line: &str) -> Option<Result<BlockEntry, BlocklistError>> {
+    let line = line.split('#').next().unwrap_or("").trim();
+    if line.is_empty() {
+        return None;
+    }
+
+    let mut parts = line.splitn(3, char::is_whitespace);
+    let (kind, hash, reason) = (parts.next()?, parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let kind = match kind {
+        "sha256" => HashKind::Sha256,
+        "phash" => HashKind::Phash,
+        kind => return Some(Err(BlocklistError::UnknownKind(kind.to_owned()))),
+    };
+    Some(BlockEntry::new(kind, hash, reason, EntrySource::File))
+}
+
+#[derive(Default)]
+struct Entries {
+    file: Vec<BlockEntry>,
+    api: Vec<BlockEntry>,
+}
+
+static ENTRIES: OnceLock<RwLock<Entries>> = OnceLock::new();
+
+fn entries() -> &'static RwLock<Entries> {
+    ENTRIES.get_or_init(Default::default)
+}
+
+fn dir<P: AsRef<Path>>(uploads_dir: P) -> PathBuf {
+    uploads_dir.as_ref().join("blocklist")
+}
+
+fn api_entries_path<P: AsRef<Path>>(uploads_dir: P) -> PathBuf {
+    dir(uploads_dir).join("entries.json")
+}
+
+// Reads the operator file and the entries added over the API, at startup.
+// A malformed line in the file fails the startup rather than leaving
+// content unblocked. Returns how many entries there are.
+pub async fn load<P: AsRef<Path>>(config: &BlocklistConfig, uploads_dir: P) -> Result<usize> {
+    let mut loaded = Entries::default();
+
+    if let Some(file) = &config.file {
+        let data = tokio::fs::read_to_string(file).await?;
+        for (n, line) in data.lines().enumerate() {
+            match parse_line(line) {
+                Some(Ok(entry)) => loaded.file.push(entry),
+                Some(Err(err)) => return Err(anyhow::anyhow!("{:?} line {}: {}", file, n + 1, err)),
+                None => {}
+            }
+        }
+    }
+
+    match tokio::fs::read(api_entries_path(&uploads_dir)).await {
+        Ok(data) => loaded.api = serde_json::from_slice(&data)?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    let count = loaded.file.len() + loaded.api.len();
+    *entries().write().unwrap() = loaded;
+    Ok(count)
+}
+
+pub fn list() -> Vec<BlockEntry> {
+    let entries = entries().read().unwrap();
+    entries.file.iter().chain(&entries.api).cloned().collect()
+}
+
+async fn save_api_entries<P: AsRef<Path>>(uploads_dir: P, api: &[BlockEntry]) -> Result<()> {
+    tokio::fs::create_dir_all(dir(&uploads_dir)).await?;
+    let path = api_entries_path(&uploads_dir);
+    let tmp_path = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp_path, serde_json::to_vec(api)?).await?;
+    tokio::fs::rename(&tmp_path, &path).await?;
+    Ok(())
+}
+
+// Adds or replaces an API entry; entries of the file can only be changed
+// there
+pub async fn add<P: AsRef<Path>>(uploads_dir: P, entry: BlockEntry) -> Result<()> {
+    let api = {
+        let mut entries = entries().write().unwrap();
+        entries
+            .api
+            .retain(|other| other.kind != entry.kind || other.hash != entry.hash);
+        entries.api.push(entry);
+        entries.api.clone()
+    };
+    save_api_entries(uploads_dir, &api).await
+}
+
+// Returns whether there was such an API entry
+pub async fn remove<P: AsRef<Path>>(uploads_dir: P, kind: HashKind, hash: &str) -> Result<bool> {
+    let hash = hash.to_ascii_lowercase();
+    let api = {
+        let mut entries = entries().write().unwrap();
+        let before = entries.api.len();
+        entries.api.retain(|entry| entry.kind != kind || entry.hash != hash);
+        if entries.api.len() == before {
+            return Ok(false);
+        }
+        entries.api.clone()
+    };
+    save_api_entries(uploads_dir, &api).await?;
+    Ok(true)
+}
+
+// The entry an upload matches, the closest one for perceptual hashes
+pub fn find_match(config: &BlocklistConfig, checksum: Option<&str>, phash: Option<u64>) -> Option<BlockEntry> {
+    let entries = entries().read().unwrap();
+    entries
+        .file
+        .iter()
+        .chain(&entries.api)
+        .filter_map(|entry| entry.distance(checksum, phash).map(|distance| (distance, entry)))
+        .filter(|(distance, _)| *distance <= config.max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, entry)| entry.clone())
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    at: u64,
+    id: &'a str,
+    action: BlockAction,
+    kind: HashKind,
+    hash: &'a str,
+    reason: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    owner: Option<&'a str>,
+}
+
+// Appends a JSON line to `blocklist/audit.jsonl`; blocking, the file is tiny
+// and appends are rare
+fn audit<P: AsRef<Path>>(uploads_dir: P, image_metadata: &ImageMetadata, action: BlockAction, entry: &BlockEntry) {
+    let record = AuditRecord {
+        at: clock::unix_now(),
+        id: &image_metadata.id,
+        action,
+        kind: entry.kind,
+        hash: &entry.hash,
+        reason: &entry.reason,
+        owner: image_metadata.owner.as_deref(),
+    };
+    let res = std::fs::create_dir_all(dir(&uploads_dir)).and_then(|()| {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir(&uploads_dir).join("audit.jsonl"))?;
+        writeln!(file, "{}", serde_json::to_string(&record)?)
+    });
+    if let Err(err) = res {
+        tracing::error!("Blocklist audit error: {}", err);
+    }
+}
+
+// Checks an upload whose metadata is about to be saved. A rejected upload
+// is an error; a quarantined one is kept with the reason. Either way
+// the match is logged and audited.
+pub fn apply<P: AsRef<Path>>(
+    config: &BlocklistConfig,
+    uploads_dir: P,
+    image_metadata: &mut ImageMetadata,
+) -> Result<(), BlocklistError> {
+    let entry = match find_match(
+        config,
+        image_metadata.checksum.as_deref(),
+        image_metadata.perceptual_hash(),
+    ) {
+        Some(entry) => entry,
+        None => return Ok(()),
+    };
+
+    tracing::warn!(
+        "Upload {} matches blocked {:?} {} ({}), {:?}",
+        image_metadata.id,
+        entry.kind,
+        entry.hash,
+        entry.reason,
+        config.action
+    );
+    audit(uploads_dir, image_metadata, config.action, &entry);
+
+    match config.action {
+        BlockAction::Reject => Err(BlocklistError::Blocked(entry.kind, entry.reason)),
+        BlockAction::Quarantine => {
+            image_metadata.status = metadata::UploadState::Quarantined;
+            image_metadata.quarantine = Some(metadata::Quarantine {
+                kind: entry.kind,
+                hash: entry.hash,
+                reason: entry.reason,
+            });
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::thumbnails::ThumbnailFormat;
+use crate::{extension_to_mime_type, imagetools, ocr, Config, STORED_EXTENSIONS};
+
+// Formats clients ask about, whether or not any build encodes them
+const KNOWN_ENCODERS: &[&str] = &["jpg", "png", "bmp", "webp", "avif", "jxl"];
+
+#[derive(Debug, Serialize)]
+pub struct TransformCapabilities {
+    pub fits: &'static [&'static str],
+    // Query parameters applied after sizing
+    pub filters: &'static [&'static str],
+    // MIME types of `?format=`
+    pub formats: Vec<&'static str>,
+    pub max_width: u32,
+    pub max_height: u32,
+    pub require_signature: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Capabilities {
+    // `opencv` or `image`, see `imagetools`
+    pub backend: &'static str,
+    // MIME types accepted for upload
+    pub input_formats: Vec<&'static str>,
+    // By extension, the build's encoders
+    pub encoders: BTreeMap<&'static str, bool>,
+    pub thumbnail_format: ThumbnailFormat,
+    // `None` while transformations are off
+    pub transforms: Option<TransformCapabilities>,
+    pub features: BTreeMap<&'static str, bool>,
+    pub limits: BTreeMap<&'static str, u64>,
+}
+
+// Что умеет эта сборка с этим конфигом, для GET /capabilities. Only what
+// clients can act on; nothing about peers, keys or hooks.
+pub fn capabilities(config: &Config) -> Capabilities {
+    let input_formats = STORED_EXTENSIONS
+        .iter()
+        .filter(|extension| config.video.enabled || !imagetools::VIDEO_EXTENSIONS.contains(extension))
+        .filter_map(|extension| extension_to_mime_type(extension))
+        .collect();
+    let encoders = KNOWN_ENCODERS
+        .iter()
+        .map(|extension| (*extension, imagetools::can_encode(extension)))
+        .collect();
+
+    let transforms = if config.transform.enabled {
+        Some(TransformCapabilities {
+            fits: &["contain", "cover", "fill"],
+            filters: &["grayscale", "brightness", "contrast", "blur", "sharpen"],
+            formats: STORED_EXTENSIONS
+                .iter()
+                .filter(|extension| imagetools::can_encode(extension))
+                .filter_map(|extension| extension_to_mime_type(extension))
+                .collect(),
+            max_width: config.transform.max_width,
+            max_height: config.transform.max_height,
+            require_signature: config.transform.require_signature,
+        })
+    } else {
+        None
+    };
+
+    let mut features = BTreeMap::new();
+    features.insert("moderation", config.moderation.enabled);
+    features.insert("guest_uploads", config.guest.enabled);
+    features.insert("presigned_uploads", !config.signing_key.is_empty());
+    features.insert("signed_downloads_required", config.require_signed_downloads);
+    features.insert("strip_metadata", config.strip_metadata);
+    features.insert("blurhash", config.blurhash);
+    features.insert("dominant_colors", config.dominant_colors > 0);
+    features.insert("resumable_uploads", true);
+    features.insert("face_detection", imagetools::FACE_DETECTION);
+    features.insert("ocr", ocr::AVAILABLE && config.ocr.enabled);
+    features.insert("video", imagetools::VIDEO && config.video.enabled);
+
+    let mut limits = BTreeMap::new();
+    limits.insert("max_image_width", u64::from(config.max_image_width));
+    limits.insert("max_image_height", u64::from(config.max_image_height));
+    limits.insert("max_image_pixels", config.max_image_pixels);
+    limits.insert("max_json_payload_size", config.max_json_payload_size as u64);
+    limits.insert("max_tus_upload_size", config.max_tus_upload_size);
+    // 0 is unlimited, as in the config
+    limits.insert("max_file_size", config.max_file_size);
+    limits.insert("max_request_size", config.max_request_size);
+    limits.insert("max_upload_ttl_secs", config.max_upload_ttl_secs);
+    limits.insert("max_video_size", config.video.max_size);
+    limits.insert("similar_max_distance", u64::from(config.similar_max_distance));
+
+    Capabilities {
+        backend: imagetools::BACKEND,
+        input_formats,
+        encoders,
+        thumbnail_format: config.thumbnail_format,
+        transforms,
+        features,
+        limits,
+    }
+}
@@ -0,0 +1,90 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::{find_upload, is_temp_file_name, layout, parse_derivative_file_name, tiering, Config};
+
+#[derive(Debug, Default)]
+pub struct CleanupStats {
+    pub temp_files: usize,
+    pub orphaned_thumbnails: usize,
+}
+
+async fn age(path: &Path) -> std::io::Result<Duration> {
+    let modified = tokio::fs::metadata(path).await?.modified()?;
+    Ok(SystemTime::now().duration_since(modified).unwrap_or_default())
+}
+
+// Removes temp files older than `max_age` and derivatives whose original is
+// gone (not just archived). The age check keeps running uploads and
+// replication (which may copy a thumbnail before its original) safe.
+pub async fn cleanup_orphans<P: AsRef<Path>>(uploads_dir: P, max_age: Duration) -> std::io::Result<CleanupStats> {
+    let uploads_dir = uploads_dir.as_ref();
+    let mut stats = CleanupStats::default();
+
+    for dir in layout::upload_dirs(uploads_dir).await? {
+        cleanup_dir(uploads_dir, &dir, max_age, &mut stats).await?;
+    }
+
+    Ok(stats)
+}
+
+async fn cleanup_dir(uploads_dir: &Path, dir: &Path, max_age: Duration, stats: &mut CleanupStats) -> std::io::Result<()> {
+    let mut dir = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if !entry.file_type().await?.is_file() {
+            continue;
+        }
+
+        let removed = if is_temp_file_name(&name) {
+            &mut stats.temp_files
+        } else if let Some(id) = parse_derivative_file_name(&name) {
+            // A transformation may be in another format than its original
+            if find_upload(uploads_dir, id).await.is_some() || tiering::archived(uploads_dir, id).await.is_some() {
+                continue;
+            }
+            &mut stats.orphaned_thumbnails
+        } else {
+            continue;
+        };
+
+        if age(&entry.path()).await? < max_age {
+            continue;
+        }
+
+        tracing::debug!("Removing orphan {}", name);
+        tokio::fs::remove_file(entry.path()).await?;
+        *removed += 1;
+    }
+
+    Ok(())
+}
+
+pub fn spawn_cleanup(config: &Config) {
+    if config.orphan_cleanup_interval_secs == 0 {
+        return;
+    }
+
+    let uploads_dir = config.uploads_dir.clone();
+    let interval = Duration::from_secs(config.orphan_cleanup_interval_secs);
+    let max_age = Duration::from_secs(config.orphan_max_age_secs);
+    actix_rt::spawn(async move {
+        // The first tick fires right away, so this covers startup as well
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match cleanup_orphans(&uploads_dir, max_age).await {
+                Ok(stats) if stats.temp_files + stats.orphaned_thumbnails > 0 => tracing::info!(
+                    "Orphan cleanup removed {} temp file(s) and {} thumbnail(s)",
+                    stats.temp_files,
+                    stats.orphaned_thumbnails
+                ),
+                Ok(_) => {}
+                Err(err) => tracing::error!("Orphan cleanup error: {}", err),
+            }
+        }
+    });
+}
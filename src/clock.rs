@@ -0,0 +1,63 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Часы сервиса: wall clock time for everything that is stored, signed or
+// rate limited (metadata, expiry, guest buckets, signed URLs, upload
+// throttles, jobs). Timeouts and the ages of files on disk stay on the real
+// clock, they are measured against the OS.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+pub struct FixedClock(pub SystemTime);
+
+impl Clock for FixedClock {
+    fn now(&self) -> SystemTime {
+        self.0
+    }
+}
+
+static CLOCK: OnceLock<Box<dyn Clock>> = OnceLock::new();
+// Seconds added by `advance`
+static OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// The system clock until called; only the first call counts
+pub fn install(clock: Box<dyn Clock>) {
+    let _ = CLOCK.set(clock);
+}
+
+// Time travel: moves the clock forward, for tests and for watching expiry
+// without waiting for it. There is no way back.
+pub fn advance(by: Duration) {
+    OFFSET.fetch_add(by.as_secs(), Ordering::SeqCst);
+}
+
+pub fn offset() -> Duration {
+    Duration::from_secs(OFFSET.load(Ordering::SeqCst))
+}
+
+pub fn now() -> SystemTime {
+    let now = match CLOCK.get() {
+        Some(clock) => clock.now(),
+        None => SystemClock.now(),
+    };
+    now + offset()
+}
+
+pub fn unix_now() -> u64 {
+    now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Time since `earlier`, zero if the clock went back past it
+pub fn since(earlier: SystemTime) -> Duration {
+    now().duration_since(earlier).unwrap_or_default()
+}
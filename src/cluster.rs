@@ -0,0 +1,244 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::finalize::Durability;
+
+// Set on proxied requests, so the receiving node never forwards again
+pub const FORWARDED_HEADER: &str = "X-RR-Forwarded";
+pub const PING_PATH: &str = "/cluster/ping";
+// Derivatives are served to peers from here, see `Cluster::fill_from_peers`
+pub const FILES_PATH: &str = "/cluster/files";
+// Carries `ClusterConfig::token` to `FILES_PATH`
+pub const TOKEN_HEADER: &str = "X-RR-Cluster-Token";
+
+// Узлы кластера; пустой список peers — работаем в одиночку
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ClusterConfig {
+    // Base URL of this node as the peers see it, e.g. "http://10.0.0.1:8080"
+    pub self_url: String,
+    pub peers: Vec<String>,
+    pub health_check_interval_secs: u64,
+    pub health_check_timeout_ms: u64,
+    // Ask peers for a missing derivative before regenerating it
+    pub cache_fill: bool,
+    // Shared by all nodes; peers only get files with it, so there is no
+    // cache fill without one
+    pub token: String,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        ClusterConfig {
+            self_url: String::new(),
+            peers: Vec::new(),
+            health_check_interval_secs: 10,
+            health_check_timeout_ms: 2000,
+            cache_fill: true,
+            token: String::new(),
+        }
+    }
+}
+
+// FNV-1a, stable across builds and platforms unlike `DefaultHasher`,
+// every node must compute the same scores.
+fn fnv1a(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in parts {
+        for byte in part.iter() {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        // Separator, so ("ab", "c") and ("a", "bc") differ
+        hash ^= 0xff;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+pub fn rendezvous_score(node: &str, key: &str) -> u64 {
+    fnv1a(&[node.as_bytes(), key.as_bytes()])
+}
+
+#[derive(Clone)]
+pub struct Cluster {
+    self_url: String,
+    peers: Vec<String>,
+    healthy: Arc<RwLock<HashSet<String>>>,
+    client: reqwest::Client,
+    check_timeout: Duration,
+    interval: Duration,
+    cache_fill: bool,
+    token: String,
+}
+
+impl Cluster {
+    pub fn new(config: &ClusterConfig) -> Cluster {
+        let self_url = config.self_url.trim_end_matches('/').to_owned();
+        let peers: Vec<String> = config
+            .peers
+            .iter()
+            .map(|peer| peer.trim_end_matches('/').to_owned())
+            .filter(|peer| *peer != self_url)
+            .collect();
+
+        Cluster {
+            self_url,
+            // Optimistic until the first health check says otherwise
+            healthy: Arc::new(RwLock::new(peers.iter().cloned().collect())),
+            peers,
+            client: reqwest::Client::new(),
+            check_timeout: Duration::from_millis(config.health_check_timeout_ms),
+            interval: Duration::from_secs(config.health_check_interval_secs.max(1)),
+            cache_fill: config.cache_fill,
+            token: config.token.clone(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.peers.is_empty()
+    }
+
+    pub fn peers(&self) -> &[String] {
+        &self.peers
+    }
+
+    pub fn is_healthy(&self, peer: &str) -> bool {
+        self.healthy.read().unwrap().contains(peer)
+    }
+
+    pub fn is_authorized(&self, token: Option<&str>) -> bool {
+        !self.token.is_empty() && token == Some(self.token.as_str())
+    }
+
+    pub fn healthy_peers(&self) -> Vec<String> {
+        let healthy = self.healthy.read().unwrap();
+        self.peers.iter().filter(|peer| healthy.contains(*peer)).cloned().collect()
+    }
+
+    // Rendezvous (highest random weight) hashing over this node and the
+    // healthy peers. `None` means this node owns the key.
+    pub fn owner(&self, key: &str) -> Option<String> {
+        if !self.is_enabled() {
+            return None;
+        }
+
+        let mut best = (rendezvous_score(&self.self_url, key), None);
+        for peer in self.healthy_peers() {
+            let score = rendezvous_score(&peer, key);
+            if score > best.0 {
+                best = (score, Some(peer));
+            }
+        }
+
+        best.1
+    }
+
+    pub async fn check_peers(&self) {
+        for peer in &self.peers {
+            let url = format!("{}{}", peer, PING_PATH);
+            let is_up = match self.client.get(&url).timeout(self.check_timeout).send().await {
+                Ok(response) => response.status().is_success(),
+                Err(_) => false,
+            };
+
+            let mut healthy = self.healthy.write().unwrap();
+            let was_up = if is_up {
+                !healthy.insert(peer.clone())
+            } else {
+                healthy.remove(peer)
+            };
+
+            if was_up != is_up {
+                tracing::warn!("Peer {} is {}", peer, if is_up { "up" } else { "down" });
+            }
+        }
+    }
+
+    pub fn spawn_health_checks(&self) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let cluster = self.clone();
+        actix_rt::spawn(async move {
+            let mut interval = tokio::time::interval(cluster.interval);
+            loop {
+                interval.tick().await;
+                cluster.check_peers().await;
+            }
+        });
+    }
+
+    // Forwards a request to the owner node, `path` includes the query string
+    pub async fn forward(
+        &self,
+        peer: &str,
+        method: reqwest::Method,
+        path: &str,
+        headers: reqwest::header::HeaderMap,
+    ) -> Result<reqwest::Response> {
+        let response = self
+            .client
+            .request(method, format!("{}{}", peer, path))
+            .headers(headers)
+            .header(FORWARDED_HEADER, self.self_url.as_str())
+            .send()
+            .await?;
+
+        Ok(response)
+    }
+
+    // Copies `file_name` from the first healthy peer that has it, trying the
+    // key's rendezvous owner first. Returns whether `dest` was written.
+    pub async fn fill_from_peers(&self, key: &str, file_name: &str, dest: &Path) -> bool {
+        if !self.is_enabled() || !self.cache_fill || self.token.is_empty() {
+            return false;
+        }
+
+        let mut peers = self.healthy_peers();
+        peers.sort_by_key(|peer| std::cmp::Reverse(rendezvous_score(peer, key)));
+
+        for peer in peers {
+            match self.fetch_file(&peer, file_name, dest).await {
+                Ok(true) => {
+                    tracing::debug!("Copied {} from {}", file_name, peer);
+                    return true;
+                }
+                Ok(false) => {}
+                Err(err) => tracing::warn!("Fetching {} from {} failed: {}", file_name, peer, err),
+            }
+        }
+
+        false
+    }
+
+    async fn fetch_file(&self, peer: &str, file_name: &str, dest: &Path) -> Result<bool> {
+        let response = self
+            .client
+            .get(format!("{}{}/{}", peer, FILES_PATH, file_name))
+            .header(FORWARDED_HEADER, self.self_url.as_str())
+            .header(TOKEN_HEADER, self.token.as_str())
+            .send()
+            .await?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        if !response.status().is_success() {
+            return Err(anyhow::anyhow!("peer returned {}", response.status()));
+        }
+
+        // Write-then-rename, a half-copied file must never be served
+        let tmp_path = dest.with_extension("fill");
+        crate::stream_to_file(response.bytes_stream(), &tmp_path, None, Durability::Relaxed).await?;
+        tokio::fs::rename(&tmp_path, dest).await?;
+
+        Ok(true)
+    }
+}
@@ -0,0 +1,169 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{clock, concurrency, gen_rand_id, imagetools, is_valid_id};
+
+pub const COLLECTIONS_DIR: &str = "collections";
+
+// Подборки загрузок, хранятся в collections/{id}.json; the images are listed
+// by id, in order
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Collection {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub images: Vec<String>,
+    // Name of the API key that made it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CollectionsConfig {
+    pub max_images: usize,
+    // Contact sheet defaults and limits, see `contact_sheet`
+    pub sheet_columns: u32,
+    pub sheet_per_page: usize,
+    pub sheet_max_per_page: usize,
+    pub sheet_tile_size: u32,
+    pub sheet_quality: u8,
+}
+
+impl Default for CollectionsConfig {
+    fn default() -> Self {
+        CollectionsConfig {
+            max_images: 1000,
+            sheet_columns: 6,
+            sheet_per_page: 36,
+            sheet_max_per_page: 100,
+            sheet_tile_size: 100,
+            sheet_quality: 85,
+        }
+    }
+}
+
+fn dir<P: AsRef<Path>>(uploads_dir: P) -> PathBuf {
+    uploads_dir.as_ref().join(COLLECTIONS_DIR)
+}
+
+fn path<P: AsRef<Path>>(uploads_dir: P, id: &str) -> PathBuf {
+    dir(uploads_dir).join(format!("{}.json", id))
+}
+
+// `images` are valid ids; whether they exist is left to whoever shows them,
+// an image may be deleted later anyway
+pub async fn create<P: AsRef<Path>>(
+    uploads_dir: P,
+    name: Option<String>,
+    images: Vec<String>,
+    owner: Option<String>,
+) -> Result<Collection> {
+    let collection = Collection {
+        id: gen_rand_id(12),
+        name,
+        images,
+        owner,
+        created_at: clock::unix_now(),
+    };
+    tokio::fs::create_dir_all(dir(&uploads_dir)).await?;
+    let path = path(&uploads_dir, &collection.id);
+    let tmp_path = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp_path, serde_json::to_vec(&collection)?).await?;
+    tokio::fs::rename(&tmp_path, &path).await?;
+    Ok(collection)
+}
+
+pub async fn load<P: AsRef<Path>>(uploads_dir: P, id: &str) -> Result<Option<Collection>> {
+    if !is_valid_id(id) {
+        return Ok(None);
+    }
+    match tokio::fs::read(path(uploads_dir, id)).await {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Removes the collection and its cached contact sheets, returns whether it
+// existed
+pub async fn remove<P: AsRef<Path>>(uploads_dir: P, id: &str) -> std::io::Result<bool> {
+    if !is_valid_id(id) {
+        return Ok(false);
+    }
+
+    let mut entries = match tokio::fs::read_dir(dir(&uploads_dir)).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    let sheet_prefix = format!("{}_sheet_", id);
+    while let Some(entry) = entries.next_entry().await? {
+        if entry
+            .file_name()
+            .to_str()
+            .map(|name| name.starts_with(&sheet_prefix))
+            .unwrap_or(false)
+        {
+            tokio::fs::remove_file(entry.path()).await?;
+        }
+    }
+
+    match tokio::fs::remove_file(path(&uploads_dir, id)).await {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+// A page of a contact sheet: which images, and how they are laid out
+#[derive(Debug, Clone)]
+pub struct SheetSpec {
+    pub columns: u32,
+    pub tile_size: u32,
+    pub quality: u8,
+}
+
+// Cached by the images it shows, so a sheet is made again when one of them
+// is removed or hidden
+fn sheet_file_name(id: &str, spec: &SheetSpec, images: &[(String, PathBuf)]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{}:{}:{}", spec.columns, spec.tile_size, spec.quality));
+    for (image_id, _) in images {
+        hasher.update(b"\n");
+        hasher.update(image_id.as_bytes());
+    }
+    format!("{}_sheet_{}.jpg", id, &hex::encode(hasher.finalize())[..16])
+}
+
+// The JPEG contact sheet of `images` (ids and their thumbnails), made once
+// and cached next to the collection
+pub async fn contact_sheet<P: AsRef<Path>>(
+    uploads_dir: P,
+    id: &str,
+    spec: &SheetSpec,
+    images: Vec<(String, PathBuf)>,
+) -> Result<PathBuf> {
+    let path = dir(&uploads_dir).join(sheet_file_name(id, spec, &images));
+    if tokio::fs::metadata(&path).await.is_ok() {
+        return Ok(path);
+    }
+
+    let (columns, tile_size, quality) = (spec.columns, spec.tile_size, spec.quality);
+    let tiles: Vec<PathBuf> = images.into_iter().map(|(_, thumbnail)| thumbnail).collect();
+    let data = concurrency::run_blocking(move || {
+        let sheet = imagetools::contact_sheet(&tiles, columns, tile_size)?;
+        imagetools::encode_image(&sheet, "jpg", Some(quality))
+    })
+    .await?
+    .map_err(|err| anyhow::anyhow!("{}", err))?;
+
+    let tmp_path = path.with_extension("jpg.tmp");
+    tokio::fs::write(&tmp_path, data).await?;
+    tokio::fs::rename(&tmp_path, &path).await?;
+    Ok(path)
+}
@@ -0,0 +1,235 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+use tokio::task::JoinError;
+
+// Адаптивный предел одновременных загрузок по нагрузке и задержкам
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ConcurrencyConfig {
+    // Simultaneous uploads while the adaptive limit is off, 0 for no limit
+    pub max_uploads: usize,
+    // Simultaneous decoding, thumbnail and transform jobs, 0 for one per CPU
+    pub processing_slots: usize,
+    // Adaptive upload limit between `min_limit` and `max_limit`
+    pub enabled: bool,
+    pub initial_limit: usize,
+    pub min_limit: usize,
+    pub max_limit: usize,
+    // Mean time an upload holds its slot; slow clients count too, so keep
+    // it well above a typical upload
+    pub target_latency_ms: u64,
+    // PSI `some avg10` of CPU or IO, in percent; load average per CPU times
+    // 100 where /proc/pressure is missing
+    pub max_pressure: f64,
+    pub adjust_interval_secs: u64,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        ConcurrencyConfig {
+            max_uploads: 0,
+            processing_slots: 0,
+            enabled: false,
+            initial_limit: 16,
+            min_limit: 2,
+            max_limit: 256,
+            target_latency_ms: 5000,
+            max_pressure: 40.0,
+            adjust_interval_secs: 2,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    limit: AtomicUsize,
+    in_flight: AtomicUsize,
+    // Sum and count of slot hold times since the last adjustment
+    latency: Mutex<(Duration, u32)>,
+    // Whether the limit was reached since the last adjustment
+    saturated: Mutex<bool>,
+}
+
+// Upload slots; the limit grows by one while the host keeps up and shrinks
+// by a quarter when it doesn't
+#[derive(Debug, Clone)]
+pub struct AdaptiveLimit {
+    state: Arc<State>,
+}
+
+// Held for the duration of an upload
+pub struct Permit {
+    state: Arc<State>,
+    started: Instant,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.state.in_flight.fetch_sub(1, Ordering::SeqCst);
+        let mut latency = self.state.latency.lock().unwrap();
+        latency.0 += self.started.elapsed();
+        latency.1 += 1;
+    }
+}
+
+impl AdaptiveLimit {
+    // Disabled: a fixed `max_uploads` limit
+    pub fn start(config: &ConcurrencyConfig) -> AdaptiveLimit {
+        let state = Arc::new(State::default());
+        let limit = AdaptiveLimit { state };
+
+        if !config.enabled {
+            let max_uploads = if config.max_uploads == 0 { usize::MAX } else { config.max_uploads };
+            limit.state.limit.store(max_uploads, Ordering::SeqCst);
+            return limit;
+        }
+
+        let (min_limit, max_limit) = (config.min_limit.max(1), config.max_limit.max(config.min_limit.max(1)));
+        let initial_limit = config.initial_limit.max(min_limit).min(max_limit);
+        limit.state.limit.store(initial_limit, Ordering::SeqCst);
+
+        let (controller, config) = (limit.clone(), config.clone());
+        actix_rt::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(config.adjust_interval_secs.max(1)));
+            loop {
+                interval.tick().await;
+                let pressure = read_pressure().await;
+                controller.adjust(&config, min_limit, max_limit, pressure);
+            }
+        });
+
+        limit
+    }
+
+    // `None` when all slots are taken
+    pub fn try_acquire(&self) -> Option<Permit> {
+        let limit = self.state.limit.load(Ordering::SeqCst);
+        let res = self
+            .state
+            .in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |in_flight| {
+                if in_flight < limit {
+                    Some(in_flight + 1)
+                } else {
+                    None
+                }
+            });
+
+        match res {
+            Ok(_) => Some(Permit {
+                state: self.state.clone(),
+                started: Instant::now(),
+            }),
+            Err(_) => {
+                *self.state.saturated.lock().unwrap() = true;
+                None
+            }
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.limit.load(Ordering::SeqCst)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.state.in_flight.load(Ordering::SeqCst)
+    }
+
+    fn adjust(&self, config: &ConcurrencyConfig, min_limit: usize, max_limit: usize, pressure: Option<f64>) {
+        let (total, count) = std::mem::take(&mut *self.state.latency.lock().unwrap());
+        let saturated = std::mem::take(&mut *self.state.saturated.lock().unwrap());
+
+        let slow = count > 0 && total / count > Duration::from_millis(config.target_latency_ms);
+        let loaded = matches!(pressure, Some(pressure) if pressure > config.max_pressure);
+
+        let limit = self.limit();
+        let new_limit = if slow || loaded {
+            (limit - limit / 4).max(min_limit)
+        } else if saturated {
+            (limit + 1).min(max_limit)
+        } else {
+            limit
+        };
+
+        if new_limit != limit {
+            tracing::debug!(
+                "Upload concurrency limit {} -> {} (slow: {}, pressure: {:?})",
+                limit,
+                new_limit,
+                slow,
+                pressure
+            );
+            self.state.limit.store(new_limit, Ordering::SeqCst);
+        }
+    }
+}
+
+// CPU-bound image work across all callers (handlers, the thumbnail queue,
+// imports, tus), so a burst can't exhaust the blocking thread pool
+static PROCESSING: OnceLock<Semaphore> = OnceLock::new();
+// Jobs waiting for a processing slot
+static WAITING: AtomicUsize = AtomicUsize::new(0);
+
+// Unset until called, all jobs then run at once; only the first call counts
+pub fn set_processing_slots(slots: usize) {
+    let _ = PROCESSING.set(Semaphore::new(slots.max(1)));
+}
+
+pub fn default_processing_slots() -> usize {
+    std::thread::available_parallelism().map_or(1, |cpus| cpus.get())
+}
+
+pub fn processing_backlog() -> usize {
+    WAITING.load(Ordering::SeqCst)
+}
+
+// `spawn_blocking` once a processing slot is free
+pub async fn run_blocking<F, T>(f: F) -> Result<T, JoinError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let _permit = match PROCESSING.get() {
+        Some(semaphore) => {
+            WAITING.fetch_add(1, Ordering::SeqCst);
+            let permit = semaphore.acquire().await;
+            WAITING.fetch_sub(1, Ordering::SeqCst);
+            Some(permit)
+        }
+        None => None,
+    };
+    tokio::task::spawn_blocking(f).await
+}
+
+// `some avg10=1.23 avg60=...` from /proc/pressure/{cpu,io}
+fn parse_pressure(contents: &str) -> Option<f64> {
+    contents
+        .lines()
+        .find(|line| line.starts_with("some "))?
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+// The worse of CPU and IO pressure, `None` if the host exposes neither
+async fn read_pressure() -> Option<f64> {
+    let mut pressure = None;
+    for path in &["/proc/pressure/cpu", "/proc/pressure/io"] {
+        if let Some(value) = tokio::fs::read_to_string(path).await.ok().as_deref().and_then(parse_pressure) {
+            pressure = Some(pressure.map_or(value, |current: f64| current.max(value)));
+        }
+    }
+    if pressure.is_some() {
+        return pressure;
+    }
+
+    // Older kernels: the 1 minute load average per CPU
+    let loadavg = tokio::fs::read_to_string("/proc/loadavg").await.ok()?;
+    let load: f64 = loadavg.split_whitespace().next()?.parse().ok()?;
+    Some(load / default_processing_slots() as f64 * 100.0)
+}
@@ -0,0 +1,92 @@
+use serde::Deserialize;
+
+// CORS для браузерных клиентов. Off until origins are listed; "*" lets any
+// origin in, credentials (API keys) then still have to be sent explicitly
+// unless `allow_credentials` is set.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    // Empty is every method of the route
+    pub allowed_methods: Vec<String>,
+    // Request headers a preflight may ask for
+    pub allowed_headers: Vec<String>,
+    // Response headers scripts may read
+    pub exposed_headers: Vec<String>,
+    pub max_age_secs: u64,
+    // Cookies and HTTP auth; the origin is then echoed even for "*"
+    pub allow_credentials: bool,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: Vec::new(),
+            allowed_headers: [
+                "Authorization",
+                "Content-Type",
+                "Upload-Length",
+                "Upload-Offset",
+                "Upload-Metadata",
+                "Tus-Resumable",
+                "X-Content-SHA256",
+                "Idempotency-Key",
+            ]
+            .iter()
+            .map(|header| (*header).to_owned())
+            .collect(),
+            exposed_headers: [
+                "ETag",
+                "Location",
+                "Retry-After",
+                "Upload-Offset",
+                "Tus-Resumable",
+                "X-Content-SHA256",
+                "Idempotent-Replayed",
+                "X-Request-Id",
+            ]
+            .iter()
+            .map(|header| (*header).to_owned())
+            .collect(),
+            max_age_secs: 600,
+            allow_credentials: false,
+        }
+    }
+}
+
+impl CorsConfig {
+    // The Access-Control-Allow-Origin value for a request from `origin`,
+    // `None` if that origin isn't allowed
+    pub fn allow_origin(&self, origin: &str) -> Option<String> {
+        if self.allowed_origins.iter().any(|allowed| allowed == "*") {
+            Some(if self.allow_credentials { origin } else { "*" }.to_owned())
+        } else if self
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+        {
+            Some(origin.to_owned())
+        } else {
+            None
+        }
+    }
+
+    pub fn allows_method(&self, method: &str) -> bool {
+        self.allowed_methods.is_empty()
+            || self
+                .allowed_methods
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(method))
+    }
+
+    // Access-Control-Allow-Methods for a route taking `allow`, as in the
+    // Allow header
+    pub fn allow_methods(&self, allow: &str) -> String {
+        allow
+            .split(", ")
+            .filter(|method| self.allows_method(method))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
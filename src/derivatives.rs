@@ -0,0 +1,76 @@
+use std::path::Path;
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{layout, parse_derivative_file_name};
+
+// Every derivative is named after its source (see `parse_derivative_file_name`)
+// and stored in its shard, so the shard directory is the dependency index. New kinds of
+// derived assets must follow the same naming to be invalidated with their
+// source.
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DerivativeKind {
+    Thumbnail,
+    Transform,
+}
+
+// One affected asset in an invalidation report
+#[derive(Debug, Clone, Serialize)]
+pub struct Derivative {
+    pub file_name: String,
+    pub kind: DerivativeKind,
+}
+
+fn kind(file_name: &str) -> DerivativeKind {
+    if file_name.contains("_thumbnail.") {
+        DerivativeKind::Thumbnail
+    } else {
+        DerivativeKind::Transform
+    }
+}
+
+// Derivatives of `id` currently on disk, ordered by name
+pub async fn list<P: AsRef<Path>>(uploads_dir: P, id: &str) -> std::io::Result<Vec<Derivative>> {
+    let mut derivatives = Vec::new();
+
+    let mut dir = match tokio::fs::read_dir(layout::shard_dir(uploads_dir, id)).await {
+        Ok(dir) => dir,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(derivatives),
+        Err(err) => return Err(err),
+    };
+    while let Some(entry) = dir.next_entry().await? {
+        let file_name = match entry.file_name().into_string() {
+            Ok(file_name) => file_name,
+            Err(_) => continue,
+        };
+        if parse_derivative_file_name(&file_name) == Some(id) {
+            derivatives.push(Derivative {
+                kind: kind(&file_name),
+                file_name,
+            });
+        }
+    }
+    derivatives.sort_by(|a, b| a.file_name.cmp(&b.file_name));
+
+    Ok(derivatives)
+}
+
+// Removes everything derived from `id`; each is regenerated from the source
+// on its next request. Returns what was removed.
+pub async fn invalidate<P: AsRef<Path>>(uploads_dir: P, id: &str) -> std::io::Result<Vec<Derivative>> {
+    let mut removed = Vec::new();
+
+    for derivative in list(&uploads_dir, id).await? {
+        match tokio::fs::remove_file(layout::stored_file_path(&uploads_dir, &derivative.file_name)).await {
+            Ok(()) => removed.push(derivative),
+            // Raced with another invalidation or the orphan cleanup
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(removed)
+}
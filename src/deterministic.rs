@@ -0,0 +1,48 @@
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, UNIX_EPOCH};
+
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use serde::Deserialize;
+
+use crate::clock::{self, FixedClock};
+
+// Детерминированный режим для тестов и воспроизведения багов: ids, tokens
+// and stored timestamps come from a seeded generator and a fixed clock.
+// Never for production, seeded tokens are guessable.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DeterministicConfig {
+    // Seeds every generated id, token and nonce
+    pub seed: Option<u64>,
+    // Unix time the clock is frozen at
+    pub fixed_time: Option<u64>,
+}
+
+impl DeterministicConfig {
+    pub fn enabled(&self) -> bool {
+        self.seed.is_some() || self.fixed_time.is_some()
+    }
+}
+
+static SEEDED_RNG: OnceLock<Mutex<StdRng>> = OnceLock::new();
+
+// The system clock and thread-local randomness until called; only the first
+// call counts
+pub fn install(config: &DeterministicConfig) {
+    if let Some(fixed_time) = config.fixed_time {
+        clock::install(Box::new(FixedClock(UNIX_EPOCH + Duration::from_secs(fixed_time))));
+    }
+    if let Some(seed) = config.seed {
+        let _ = SEEDED_RNG.set(Mutex::new(StdRng::seed_from_u64(seed)));
+    }
+}
+
+// The seeded generator if there is one; ids made concurrently then still
+// depend on the order the requests get here
+pub fn with_rng<T>(f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+    match SEEDED_RNG.get() {
+        Some(rng) => f(&mut *rng.lock().unwrap()),
+        None => f(&mut rand::thread_rng()),
+    }
+}
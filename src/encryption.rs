@@ -0,0 +1,410 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Cursor, Read, SeekFrom, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+use aes_gcm::aead::stream::{DecryptorBE32, EncryptorBE32};
+use aes_gcm::{Aes256Gcm, KeyInit};
+use bytes::Bytes;
+use futures_util::stream::{BoxStream, StreamExt};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+// Шифрование оригиналов на диске: AES-256-GCM in the STREAM construction,
+// 64 KiB a chunk, each with its own tag, so a file is never held in memory
+// whole and a truncated or reordered one fails to open. Originals are
+// sealed as an upload is committed, after the pipeline has checked and
+// fixed the plaintext temp file; decoders get a decrypted copy for as long
+// as they read, see `plaintext`, and downloads are decrypted as they are
+// streamed. Derivatives can be made again and are left plain. Peers and the
+// cold tier get the sealed bytes, they need the same keys to read them.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct EncryptionConfig {
+    pub enabled: bool,
+    // Id of the key new originals are sealed with
+    pub active_key: String,
+    // Key id to 32 bytes in hex, or "env:NAME" for a key a KMS agent puts
+    // in the environment. Retired keys stay while files sealed with them do.
+    pub keys: BTreeMap<String, String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EncryptionError {
+    #[error("Unknown encryption key {0:?}")]
+    UnknownKey(String),
+    #[error("Invalid encryption key {0:?}, 32 bytes in hex expected")]
+    InvalidKey(String),
+    #[error("Sealed file is damaged or was tampered with")]
+    Corrupt,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl From<EncryptionError> for io::Error {
+    fn from(err: EncryptionError) -> io::Error {
+        match err {
+            EncryptionError::Io(err) => err,
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+// "RRENC", version 1; then the key id length, the key id and the nonce
+// prefix
+const MAGIC: &[u8] = b"RRENC\x01";
+const NONCE_PREFIX_SIZE: usize = 7;
+const MAX_HEADER_SIZE: usize = MAGIC.len() + 1 + 255 + NONCE_PREFIX_SIZE;
+// Plaintext per chunk, the last may be shorter
+const CHUNK_SIZE: usize = 64 << 10;
+const TAG_SIZE: usize = 16;
+
+// What an original was sealed with, as its header says
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub key_id: String,
+    // Hex STREAM nonce prefix, the chunk counter makes up the rest
+    pub nonce: String,
+}
+
+pub struct Keyring {
+    active: String,
+    keys: BTreeMap<String, Vec<u8>>,
+}
+
+// Ids only, never the keys
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("active", &self.active)
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Keyring {
+    pub fn from_config(config: &EncryptionConfig) -> Result<Keyring, EncryptionError> {
+        let mut keys = BTreeMap::new();
+        for (id, value) in &config.keys {
+            let invalid = || EncryptionError::InvalidKey(id.clone());
+            let value = match value.strip_prefix("env:") {
+                Some(name) => std::env::var(name).map_err(|_| invalid())?,
+                None => value.clone(),
+            };
+            let key = hex::decode(value.trim()).map_err(|_| invalid())?;
+            if key.len() != 32 || id.is_empty() || id.len() > 255 {
+                return Err(invalid());
+            }
+            keys.insert(id.clone(), key);
+        }
+        if !keys.contains_key(&config.active_key) {
+            return Err(EncryptionError::UnknownKey(config.active_key.clone()));
+        }
+        Ok(Keyring {
+            active: config.active_key.clone(),
+            keys,
+        })
+    }
+
+    fn cipher(&self, key_id: &str) -> Result<Aes256Gcm, EncryptionError> {
+        let key = self
+            .keys
+            .get(key_id)
+            .ok_or_else(|| EncryptionError::UnknownKey(key_id.to_owned()))?;
+        Aes256Gcm::new_from_slice(key).map_err(|_| EncryptionError::InvalidKey(key_id.to_owned()))
+    }
+
+    fn opener(&self, header: &Header) -> Result<Opener, EncryptionError> {
+        let cipher = self.cipher(&header.envelope.key_id)?;
+        Ok(Opener {
+            decryptor: Some(DecryptorBE32::from_aead(cipher, (&header.nonce_prefix[..]).into())),
+            pending: None,
+        })
+    }
+
+    // Encrypts a plain file in place with the active key; blocking
+    pub fn seal_file(&self, path: &Path) -> Result<Envelope, EncryptionError> {
+        let nonce_prefix: [u8; NONCE_PREFIX_SIZE] = rand::thread_rng().gen();
+        let mut encryptor = EncryptorBE32::from_aead(self.cipher(&self.active)?, (&nonce_prefix[..]).into());
+
+        let mut sealed_name = path.file_name().unwrap_or_default().to_owned();
+        sealed_name.push(".seal");
+        let sealed_path = path.with_file_name(sealed_name);
+        let res = (|| -> Result<(), EncryptionError> {
+            let mut src = File::open(path)?;
+            let mut dest = BufWriter::new(File::create(&sealed_path)?);
+            dest.write_all(MAGIC)?;
+            dest.write_all(&[self.active.len() as u8])?;
+            dest.write_all(self.active.as_bytes())?;
+            dest.write_all(&nonce_prefix)?;
+
+            // A chunk is sealed as the last one once the next read is empty
+            let mut chunk = vec![0; CHUNK_SIZE];
+            let mut next = vec![0; CHUNK_SIZE];
+            let mut n = read_up_to(&mut src, &mut chunk)?;
+            loop {
+                let m = read_up_to(&mut src, &mut next)?;
+                if m == 0 {
+                    let sealed = encryptor
+                        .encrypt_last(&chunk[..n])
+                        .map_err(|_| EncryptionError::Corrupt)?;
+                    dest.write_all(&sealed)?;
+                    break;
+                }
+                let sealed = encryptor
+                    .encrypt_next(&chunk[..n])
+                    .map_err(|_| EncryptionError::Corrupt)?;
+                dest.write_all(&sealed)?;
+                std::mem::swap(&mut chunk, &mut next);
+                n = m;
+            }
+            dest.flush()?;
+            Ok(())
+        })();
+        if let Err(err) = res.and_then(|()| Ok(std::fs::rename(&sealed_path, path)?)) {
+            let _ = std::fs::remove_file(&sealed_path);
+            return Err(err);
+        }
+
+        Ok(Envelope {
+            key_id: self.active.clone(),
+            nonce: hex::encode(nonce_prefix),
+        })
+    }
+}
+
+static KEYRING: OnceLock<Arc<Keyring>> = OnceLock::new();
+
+// Installed by `http::start` when encryption is enabled; the first one wins
+pub fn set_keyring(keyring: Keyring) {
+    let _ = KEYRING.set(Arc::new(keyring));
+}
+
+pub fn keyring() -> Option<Arc<Keyring>> {
+    KEYRING.get().cloned()
+}
+
+struct Header {
+    envelope: Envelope,
+    nonce_prefix: Vec<u8>,
+    size: usize,
+}
+
+// `None` for a plain file
+fn parse_header(head: &[u8]) -> Result<Option<Header>, EncryptionError> {
+    let rest = match head.strip_prefix(MAGIC) {
+        Some(rest) => rest,
+        None => return Ok(None),
+    };
+    let key_id_size = *rest.first().ok_or(EncryptionError::Corrupt)? as usize;
+    let key_id = rest.get(1..1 + key_id_size).ok_or(EncryptionError::Corrupt)?;
+    let nonce_prefix = rest
+        .get(1 + key_id_size..1 + key_id_size + NONCE_PREFIX_SIZE)
+        .ok_or(EncryptionError::Corrupt)?;
+    Ok(Some(Header {
+        envelope: Envelope {
+            key_id: String::from_utf8_lossy(key_id).into_owned(),
+            nonce: hex::encode(nonce_prefix),
+        },
+        nonce_prefix: nonce_prefix.to_vec(),
+        size: MAGIC.len() + 1 + key_id_size + NONCE_PREFIX_SIZE,
+    }))
+}
+
+// Plaintext size of a sealed file of `size` bytes with this header
+fn plain_size(header: &Header, size: u64) -> Result<u64, EncryptionError> {
+    let body = size.checked_sub(header.size as u64).ok_or(EncryptionError::Corrupt)?;
+    let sealed_chunk = (CHUNK_SIZE + TAG_SIZE) as u64;
+    match body % sealed_chunk {
+        0 if body > 0 => Ok(body / sealed_chunk * CHUNK_SIZE as u64),
+        rest if rest >= TAG_SIZE as u64 => Ok(body / sealed_chunk * CHUNK_SIZE as u64 + rest - TAG_SIZE as u64),
+        _ => Err(EncryptionError::Corrupt),
+    }
+}
+
+// Decrypts sealed chunks as they are read. A chunk is the last one once
+// the read after it comes back empty.
+struct Opener {
+    decryptor: Option<DecryptorBE32<Aes256Gcm>>,
+    pending: Option<Vec<u8>>,
+}
+
+impl Opener {
+    // `next` is a full sealed chunk, shorter for the last one and empty at
+    // the end; returns the plaintext of the chunk before it
+    fn push(&mut self, next: Vec<u8>) -> Result<Option<Vec<u8>>, EncryptionError> {
+        let at_end = next.is_empty();
+        let chunk = match self.pending.replace(next) {
+            Some(chunk) => chunk,
+            None if at_end => return Err(EncryptionError::Corrupt),
+            None => return Ok(None),
+        };
+        let plain = if at_end {
+            self.pending = None;
+            let decryptor = self.decryptor.take().ok_or(EncryptionError::Corrupt)?;
+            decryptor.decrypt_last(&chunk[..])
+        } else {
+            let decryptor = self.decryptor.as_mut().ok_or(EncryptionError::Corrupt)?;
+            decryptor.decrypt_next(&chunk[..])
+        };
+        plain.map(Some).map_err(|_| EncryptionError::Corrupt)
+    }
+}
+
+fn keyring_for(header: &Header) -> Result<Arc<Keyring>, EncryptionError> {
+    keyring().ok_or_else(|| EncryptionError::UnknownKey(header.envelope.key_id.clone()))
+}
+
+// Writes the plaintext of what follows the header
+fn open_to<R: Read, W: Write>(mut opener: Opener, reader: &mut R, writer: &mut W) -> Result<(), EncryptionError> {
+    loop {
+        let mut chunk = vec![0; CHUNK_SIZE + TAG_SIZE];
+        let n = read_up_to(reader, &mut chunk)?;
+        chunk.truncate(n);
+        if let Some(plain) = opener.push(chunk)? {
+            writer.write_all(&plain)?;
+        }
+        if n == 0 {
+            return Ok(());
+        }
+    }
+}
+
+// The envelope of a sealed file, `None` for a plain one
+pub fn envelope<P: AsRef<Path>>(path: P) -> Result<Option<Envelope>, EncryptionError> {
+    let mut head = vec![0; MAX_HEADER_SIZE];
+    let n = read_up_to(&mut File::open(path)?, &mut head)?;
+    Ok(parse_header(&head[..n])?.map(|header| header.envelope))
+}
+
+// A file as decoders should see it: the file itself, or a decrypted copy
+// next to it that is removed on drop
+pub struct Plaintext {
+    path: PathBuf,
+    copy: bool,
+}
+
+impl Deref for Plaintext {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Plaintext {
+    fn drop(&mut self) {
+        if self.copy {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+// For what only reads paths (the image backends, Tesseract); blocking. The
+// copy keeps the extension, the `image` backend goes by it.
+pub fn plaintext<P: AsRef<Path>>(path: P) -> Result<Plaintext, EncryptionError> {
+    let path = path.as_ref();
+    let mut file = File::open(path)?;
+    let mut head = vec![0; MAX_HEADER_SIZE];
+    let n = read_up_to(&mut file, &mut head)?;
+    let header = match parse_header(&head[..n])? {
+        Some(header) => header,
+        None => {
+            return Ok(Plaintext {
+                path: path.to_owned(),
+                copy: false,
+            })
+        }
+    };
+    let opener = keyring_for(&header)?.opener(&header)?;
+
+    // Named apart from the stored files, several readers may want a copy
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("");
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+    let copy_name = format!("{}.{:08x}.plain.{}", stem, rand::thread_rng().gen::<u32>(), extension);
+    let plaintext = Plaintext {
+        path: path.with_file_name(copy_name),
+        copy: true,
+    };
+
+    io::Seek::seek(&mut file, SeekFrom::Start(header.size as u64))?;
+    let mut dest = BufWriter::new(File::create(&plaintext.path)?);
+    open_to(opener, &mut file, &mut dest)?;
+    dest.flush()?;
+    Ok(plaintext)
+}
+
+// The whole plaintext of a file, sealed or not
+pub async fn read<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, EncryptionError> {
+    let data = tokio::fs::read(path).await?;
+    let header = match parse_header(&data[..data.len().min(MAX_HEADER_SIZE)])? {
+        Some(header) => header,
+        None => return Ok(data),
+    };
+    let opener = keyring_for(&header)?.opener(&header)?;
+    let mut plain = Vec::with_capacity(plain_size(&header, data.len() as u64)? as usize);
+    open_to(opener, &mut Cursor::new(&data[header.size..]), &mut plain)?;
+    Ok(plain)
+}
+
+// The plaintext of an open file of `size` bytes and its size, decrypted
+// chunk by chunk as it is sent
+pub async fn plain_stream(
+    mut file: tokio::fs::File,
+    size: u64,
+) -> Result<(u64, BoxStream<'static, io::Result<Bytes>>), EncryptionError> {
+    let mut head = vec![0; MAX_HEADER_SIZE];
+    let n = read_up_to_async(&mut file, &mut head).await?;
+    let header = match parse_header(&head[..n])? {
+        Some(header) => header,
+        None => {
+            file.seek(SeekFrom::Start(0)).await?;
+            return Ok((size, crate::file_stream(file).boxed()));
+        }
+    };
+    let plain_size = plain_size(&header, size)?;
+    let opener = keyring_for(&header)?.opener(&header)?;
+    file.seek(SeekFrom::Start(header.size as u64)).await?;
+
+    let chunks = futures_util::stream::try_unfold((file, opener, false), |(mut file, mut opener, done)| async move {
+        if done {
+            return Ok(None);
+        }
+        loop {
+            let mut chunk = vec![0; CHUNK_SIZE + TAG_SIZE];
+            let n = read_up_to_async(&mut file, &mut chunk).await?;
+            chunk.truncate(n);
+            // Nothing comes out for the first chunk read
+            if let Some(plain) = opener.push(chunk)? {
+                return Ok::<_, io::Error>(Some((Bytes::from(plain), (file, opener, n == 0))));
+            }
+        }
+    });
+    Ok((plain_size, chunks.boxed()))
+}
+
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..])? {
+            0 => break,
+            read => n += read,
+        }
+    }
+    Ok(n)
+}
+
+async fn read_up_to_async(file: &mut tokio::fs::File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match file.read(&mut buf[n..]).await? {
+            0 => break,
+            read => n += read,
+        }
+    }
+    Ok(n)
+}
@@ -0,0 +1,329 @@
+use std::error::Error as StdError;
+use std::fmt;
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use utoipa::ToSchema;
+
+use crate::antivirus::AntivirusError;
+use crate::base64_stream::DataUriError;
+use crate::blocklist::BlocklistError;
+use crate::guest::GuestError;
+use crate::idempotency::IdempotencyError;
+use crate::import::ImportError;
+use crate::limits::TypeLimitError;
+use crate::moderation::ModerationRejected;
+use crate::ocr::OcrError;
+use crate::policy::PolicyViolation;
+use crate::quota::QuotaError;
+use crate::signing::SignatureError;
+use crate::storage::StorageError;
+use crate::transform::TransformError;
+use crate::tus::TusError;
+use crate::{FetchError, UploadError};
+
+// Ошибка в ответе клиенту: the status, a stable machine-readable `code` and
+// a message, as `{"error": message, "code": code, ...details}`. Internal
+// errors get a generic message, the cause is only logged.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub code: &'static str,
+    pub message: String,
+    pub details: Map<String, Value>,
+}
+
+// What `ApiError::body` looks like, for the OpenAPI spec; some errors add
+// fields, e.g. `uploaded` or `limit`
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub error: String,
+    pub code: String,
+}
+
+impl ApiError {
+    pub fn new<M: Into<String>>(status: StatusCode, code: &'static str, message: M) -> ApiError {
+        ApiError {
+            status,
+            code,
+            message: message.into(),
+            details: Map::new(),
+        }
+    }
+
+    pub fn bad_request<M: Into<String>>(code: &'static str, message: M) -> ApiError {
+        ApiError::new(StatusCode::BAD_REQUEST, code, message)
+    }
+
+    pub fn internal() -> ApiError {
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal_error",
+            "Internal server error",
+        )
+    }
+
+    // Adds a field to the body, e.g. the files uploaded before the failure
+    pub fn with<V: Serialize>(mut self, key: &str, value: V) -> ApiError {
+        self.details
+            .insert(key.to_owned(), serde_json::to_value(value).unwrap_or(Value::Null));
+        self
+    }
+
+    pub fn body(&self) -> Value {
+        let mut body = Map::new();
+        body.insert("error".to_owned(), Value::String(self.message.clone()));
+        body.insert("code".to_owned(), Value::String(self.code.to_owned()));
+        body.extend(self.details.clone());
+        Value::Object(body)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(self.body())
+    }
+}
+
+fn client(status: StatusCode, code: &'static str, err: &dyn StdError) -> ApiError {
+    ApiError::new(status, code, err.to_string())
+}
+
+impl From<&UploadError> for ApiError {
+    fn from(err: &UploadError) -> ApiError {
+        match err {
+            UploadError::Body(_) => client(StatusCode::BAD_REQUEST, "invalid_body", err),
+            UploadError::UnrecognizedImage => client(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unrecognized_image", err),
+            UploadError::UnsupportedType(_) => client(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_type", err),
+            UploadError::NotAllowed(_) => client(StatusCode::UNSUPPORTED_MEDIA_TYPE, "type_not_allowed", err),
+            UploadError::TooLarge(width, height) => client(StatusCode::PAYLOAD_TOO_LARGE, "image_too_large", err)
+                .with("width", width)
+                .with("height", height),
+            UploadError::BodyTooLarge(limit) => {
+                client(StatusCode::PAYLOAD_TOO_LARGE, "body_too_large", err).with("limit", limit)
+            }
+            UploadError::ChecksumMismatch { expected, actual } => {
+                client(StatusCode::UNPROCESSABLE_ENTITY, "checksum_mismatch", err)
+                    .with("expected", expected)
+                    .with("actual", actual)
+            }
+            UploadError::Storage(err) => err.into(),
+            UploadError::Processing(_) => ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "processing_error",
+                "Image processing error",
+            ),
+        }
+    }
+}
+
+impl From<&StorageError> for ApiError {
+    fn from(err: &StorageError) -> ApiError {
+        match err {
+            StorageError::NotFound(_) => client(StatusCode::NOT_FOUND, "not_found", err),
+            StorageError::Io(_) => ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "storage_error", "Storage error"),
+            StorageError::Service(_) => ApiError::new(StatusCode::BAD_GATEWAY, "storage_error", "Storage error"),
+        }
+    }
+}
+
+impl From<&FetchError> for ApiError {
+    fn from(err: &FetchError) -> ApiError {
+        match err {
+            FetchError::Status(status) => client(StatusCode::BAD_GATEWAY, "fetch_failed", err).with("status", status),
+            FetchError::UnsupportedType(_) => client(StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_type", err),
+            FetchError::Request(_) => client(StatusCode::BAD_GATEWAY, "fetch_failed", err),
+            FetchError::InvalidUrl(_) => client(StatusCode::BAD_REQUEST, "invalid_url", err),
+            FetchError::UrlNotAllowed(_) => client(StatusCode::FORBIDDEN, "url_not_allowed", err),
+            FetchError::Unresolvable(_) => client(StatusCode::BAD_GATEWAY, "fetch_failed", err),
+            FetchError::TooManyRedirects(_) => client(StatusCode::BAD_GATEWAY, "too_many_redirects", err),
+            FetchError::HeaderNotAllowed(_) => client(StatusCode::BAD_REQUEST, "header_not_allowed", err),
+            FetchError::InvalidHeader(_) => client(StatusCode::BAD_REQUEST, "invalid_header", err),
+            FetchError::GaveUp { attempts, last } => ApiError {
+                message: err.to_string(),
+                ..ApiError::from(&**last)
+            }
+            .with("attempts", attempts),
+        }
+    }
+}
+
+impl From<&GuestError> for ApiError {
+    fn from(err: &GuestError) -> ApiError {
+        match err {
+            GuestError::InvalidToken => client(StatusCode::UNAUTHORIZED, "invalid_guest_token", err),
+            GuestError::QuotaExceeded => client(StatusCode::PAYLOAD_TOO_LARGE, "guest_quota_exceeded", err),
+            GuestError::RateLimited => client(StatusCode::TOO_MANY_REQUESTS, "rate_limited", err),
+        }
+    }
+}
+
+impl From<&TypeLimitError> for ApiError {
+    fn from(err: &TypeLimitError) -> ApiError {
+        let error = match err {
+            TypeLimitError::TooLarge(_) => client(StatusCode::PAYLOAD_TOO_LARGE, "type_limit_exceeded", err),
+            TypeLimitError::RateLimited(_) => client(StatusCode::TOO_MANY_REQUESTS, "type_rate_limited", err),
+        };
+        error.with("limit", err.exceeded())
+    }
+}
+
+impl From<&QuotaError> for ApiError {
+    fn from(err: &QuotaError) -> ApiError {
+        match err {
+            QuotaError::Instance(_) | QuotaError::Key(..) => client(StatusCode::INSUFFICIENT_STORAGE, "quota_exceeded", err),
+            QuotaError::KeyUploads(_, limit) => {
+                client(StatusCode::TOO_MANY_REQUESTS, "daily_upload_limit", err).with("limit", limit)
+            }
+            QuotaError::KeyFileSize(_, limit) => {
+                client(StatusCode::PAYLOAD_TOO_LARGE, "body_too_large", err).with("limit", limit)
+            }
+        }
+    }
+}
+
+impl From<&BlocklistError> for ApiError {
+    fn from(err: &BlocklistError) -> ApiError {
+        match err {
+            BlocklistError::Blocked(..) => client(StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS, "blocked", err),
+            BlocklistError::InvalidHash(..) => client(StatusCode::BAD_REQUEST, "invalid_hash", err),
+            BlocklistError::UnknownKind(_) => client(StatusCode::BAD_REQUEST, "unknown_hash_kind", err),
+        }
+    }
+}
+
+impl From<&PolicyViolation> for ApiError {
+    fn from(err: &PolicyViolation) -> ApiError {
+        client(StatusCode::UNPROCESSABLE_ENTITY, "policy_violation", err).with("rule", &err.rule)
+    }
+}
+
+impl From<&ModerationRejected> for ApiError {
+    fn from(err: &ModerationRejected) -> ApiError {
+        client(StatusCode::UNPROCESSABLE_ENTITY, "moderation_rejected", err).with("label", &err.label)
+    }
+}
+
+impl From<&AntivirusError> for ApiError {
+    fn from(err: &AntivirusError) -> ApiError {
+        match err {
+            AntivirusError::Infected(signature) => {
+                client(StatusCode::UNPROCESSABLE_ENTITY, "infected", err).with("signature", signature)
+            }
+            AntivirusError::Unavailable => client(StatusCode::SERVICE_UNAVAILABLE, "antivirus_unavailable", err),
+        }
+    }
+}
+
+impl From<&OcrError> for ApiError {
+    fn from(err: &OcrError) -> ApiError {
+        match err {
+            OcrError::UnknownLanguage(_) => client(StatusCode::BAD_REQUEST, "unsupported_language", err),
+            OcrError::Failed => client(StatusCode::INTERNAL_SERVER_ERROR, "ocr_failed", err),
+        }
+    }
+}
+
+impl From<&SignatureError> for ApiError {
+    fn from(err: &SignatureError) -> ApiError {
+        match err {
+            SignatureError::Invalid => client(StatusCode::FORBIDDEN, "invalid_signature", err),
+            SignatureError::Expired => client(StatusCode::FORBIDDEN, "signature_expired", err),
+            SignatureError::Replayed => client(StatusCode::CONFLICT, "signature_replayed", err),
+        }
+    }
+}
+
+impl From<&IdempotencyError> for ApiError {
+    fn from(err: &IdempotencyError) -> ApiError {
+        match err {
+            IdempotencyError::InvalidKey => client(StatusCode::BAD_REQUEST, "invalid_idempotency_key", err),
+            IdempotencyError::InProgress => client(StatusCode::CONFLICT, "idempotency_key_in_progress", err),
+            IdempotencyError::Reused => client(StatusCode::UNPROCESSABLE_ENTITY, "idempotency_key_reused", err),
+        }
+    }
+}
+
+impl From<&TusError> for ApiError {
+    fn from(err: &TusError) -> ApiError {
+        match err {
+            TusError::InvalidMetadata => client(StatusCode::BAD_REQUEST, "invalid_metadata", err),
+            TusError::OffsetMismatch(..) => client(StatusCode::CONFLICT, "offset_mismatch", err),
+            TusError::LengthExceeded => client(StatusCode::PAYLOAD_TOO_LARGE, "length_exceeded", err),
+            TusError::AlreadyComplete => client(StatusCode::CONFLICT, "already_complete", err),
+        }
+    }
+}
+
+impl From<&ImportError> for ApiError {
+    fn from(err: &ImportError) -> ApiError {
+        client(StatusCode::BAD_REQUEST, "invalid_manifest", err)
+    }
+}
+
+impl From<&TransformError> for ApiError {
+    fn from(err: &TransformError) -> ApiError {
+        client(StatusCode::BAD_REQUEST, "invalid_transform", err)
+    }
+}
+
+impl From<&DataUriError> for ApiError {
+    fn from(err: &DataUriError) -> ApiError {
+        client(StatusCode::BAD_REQUEST, "invalid_data_uri", err)
+    }
+}
+
+// The first error of the chain that clients are told about
+fn classify(err: &(dyn StdError + 'static)) -> Option<ApiError> {
+    macro_rules! try_as {
+        ($($type:ty),*) => {
+            $(if let Some(err) = err.downcast_ref::<$type>() {
+                return Some(err.into());
+            })*
+        };
+    }
+    try_as!(
+        UploadError,
+        StorageError,
+        FetchError,
+        GuestError,
+        TypeLimitError,
+        QuotaError,
+        BlocklistError,
+        PolicyViolation,
+        ModerationRejected,
+        AntivirusError,
+        OcrError,
+        SignatureError,
+        IdempotencyError,
+        TusError,
+        ImportError,
+        TransformError,
+        DataUriError
+    );
+    None
+}
+
+impl From<&anyhow::Error> for ApiError {
+    fn from(err: &anyhow::Error) -> ApiError {
+        err.chain().find_map(classify).unwrap_or_else(ApiError::internal)
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> ApiError {
+        (&err).into()
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use actix_web::http::{header, StatusCode};
+use actix_web::{HttpRequest, HttpResponse};
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::extension_to_mime_type;
+
+// Свои тела ошибок для маршрутов отдачи изображений
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ErrorPageConfig {
+    // `{status}` and `{reason}` are substituted, e.g.
+    // `{"error": {"code": {status}, "text": "{reason}"}}`
+    pub json: Option<String>,
+    // Served instead of the JSON to clients accepting images
+    pub image: Option<PathBuf>,
+}
+
+struct ErrorImage {
+    data: Vec<u8>,
+    mime_type: &'static str,
+}
+
+// Keyed by status code, images are read once at startup
+#[derive(Clone, Default)]
+pub struct ErrorPages {
+    json: HashMap<u16, String>,
+    images: HashMap<u16, Arc<ErrorImage>>,
+}
+
+impl ErrorPages {
+    pub fn load(config: &HashMap<String, ErrorPageConfig>) -> Result<ErrorPages> {
+        let mut pages = ErrorPages::default();
+
+        for (status, page) in config {
+            let status: u16 = status
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid error page status {:?}", status))?;
+            let status_code = StatusCode::from_u16(status)?;
+            if !status_code.is_client_error() && !status_code.is_server_error() {
+                return Err(anyhow::anyhow!("error page for non-error status {}", status));
+            }
+
+            if let Some(json) = &page.json {
+                pages.json.insert(status, json.clone());
+            }
+            if let Some(path) = &page.image {
+                let mime_type = path
+                    .extension()
+                    .and_then(|extension| extension.to_str())
+                    .and_then(extension_to_mime_type)
+                    .ok_or_else(|| anyhow::anyhow!("unsupported error image {:?}", path))?;
+                let data = std::fs::read(path)?;
+                pages.images.insert(status, Arc::new(ErrorImage { data, mime_type }));
+            }
+        }
+
+        Ok(pages)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.json.is_empty() && self.images.is_empty()
+    }
+
+    // Replacement for an error response, if one is configured; headers other
+    // than the body's own are kept
+    pub fn render(&self, req: &HttpRequest, response: &HttpResponse) -> Option<HttpResponse> {
+        let status = response.status();
+        let accepts_image = req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .map(|accept| accept.contains("image/"))
+            .unwrap_or(false);
+
+        let mut builder = HttpResponse::build(status);
+        for (name, value) in response.headers() {
+            if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH && name != header::CONTENT_ENCODING {
+                builder.append_header((name.clone(), value.clone()));
+            }
+        }
+
+        let image = self.images.get(&status.as_u16());
+        let json = self.json.get(&status.as_u16());
+        match (image, json) {
+            (Some(image), Some(_)) if accepts_image => Some(builder.content_type(image.mime_type).body(image.data.clone())),
+            (Some(image), None) => Some(builder.content_type(image.mime_type).body(image.data.clone())),
+            (_, Some(json)) => {
+                let reason = status.canonical_reason().unwrap_or("");
+                let body = json
+                    .replace("{status}", &status.as_u16().to_string())
+                    .replace("{reason}", reason);
+                Some(builder.content_type("application/json").body(body))
+            }
+            (None, None) => None,
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+// When an upload is acknowledged, see `Config::durability`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    // Written, left to the OS to flush
+    #[default]
+    Relaxed,
+    // On disk, directory entry and all, and journaled while in progress
+    Strict,
+}
+
+// Перенос готового файла на место: a rename where there can be one. Across
+// mounts, a tenant's directory mounted apart or network storage exported in
+// pieces, the file is copied next to its destination instead, synced,
+// renamed over it and the source unlinked; readers never see half a file.
+// With `fsync_dir` the destination directory is synced too, so the new
+// entry survives a power loss once the upload is answered.
+pub async fn finalize<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q, fsync_dir: bool) -> io::Result<()> {
+    let (from, to) = (from.as_ref().to_owned(), to.as_ref().to_owned());
+    match tokio::fs::rename(&from, &to).await {
+        Ok(()) if !fsync_dir => Ok(()),
+        Ok(()) => tokio::task::spawn_blocking(move || sync_parent(&to)).await?,
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            tracing::debug!("{} is on another device than {}, copying", from.display(), to.display());
+            tokio::task::spawn_blocking(move || copy_over(&from, &to)).await?
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// Beside the destination while it's copied, see `is_temp_file_name`
+fn part_path(to: &Path) -> PathBuf {
+    let mut name = to.file_name().unwrap_or_default().to_owned();
+    name.push(".finalize");
+    to.with_file_name(name)
+}
+
+// What `finalize` falls back to; blocking. The directory is synced before
+// the source goes whatever `fsync_dir` says, or a crash could lose both.
+pub fn copy_over(from: &Path, to: &Path) -> io::Result<()> {
+    let part = part_path(to);
+    let res = std::fs::copy(from, &part)
+        .and_then(|_| std::fs::File::open(&part)?.sync_all())
+        .and_then(|()| std::fs::rename(&part, to));
+    if let Err(err) = res {
+        let _ = std::fs::remove_file(&part);
+        return Err(err);
+    }
+    sync_parent(to)?;
+    std::fs::remove_file(from)
+}
+
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) => std::fs::File::open(dir)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+// Directories can't be opened for syncing elsewhere, their entries are
+// durable with the file
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::rt::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+
+// Entries of closed connections are only dropped past this many
+const MAX_CONNECTIONS: usize = 10_000;
+const MAX_AGE: Duration = Duration::from_secs(3600);
+
+// JA4-style summary of a TLS connection, `t{version}{d|i}{alpn}_{cipher}`,
+// e.g. `t13dh2_1301`: `d` if the client sent SNI, `i` if it didn't. rustls
+// exposes the negotiated parameters but not the raw ClientHello, so this is
+// coarser than a JA3/JA4 hash. The SNI hostname itself is left out.
+pub fn fingerprint(session: &rustls::ServerConnection) -> String {
+    let version = match session.protocol_version() {
+        Some(rustls::ProtocolVersion::TLSv1_3) => "13",
+        Some(rustls::ProtocolVersion::TLSv1_2) => "12",
+        _ => "00",
+    };
+    let sni = if session.server_name().is_some() { "d" } else { "i" };
+    let alpn = match session.alpn_protocol() {
+        Some(b"h2") => "h2",
+        Some(b"http/1.1") => "h1",
+        Some(_) => "xx",
+        None => "00",
+    };
+    let cipher = session
+        .negotiated_cipher_suite()
+        .map(|suite| format!("{:04x}", u16::from(suite.suite())))
+        .unwrap_or_else(|| "0000".to_owned());
+
+    format!("t{}{}{}_{}", version, sni, alpn, cipher)
+}
+
+// Fingerprints by peer address. actix hands connection data only to the
+// first request of a connection, later keep-alive requests look it up here.
+#[derive(Clone, Default)]
+pub struct TlsFingerprints {
+    connections: Arc<Mutex<HashMap<SocketAddr, (Instant, String)>>>,
+}
+
+impl TlsFingerprints {
+    // For `HttpServer::on_connect` of HTTPS listeners
+    pub fn on_connect(&self, connection: &dyn Any) {
+        let stream = match connection.downcast_ref::<TlsStream<TcpStream>>() {
+            Some(stream) => stream,
+            None => return,
+        };
+        let (socket, session) = stream.get_ref();
+        let peer = match socket.peer_addr() {
+            Ok(peer) => peer,
+            Err(_) => return,
+        };
+
+        let mut connections = self.connections.lock().unwrap();
+        if connections.len() >= MAX_CONNECTIONS {
+            let now = Instant::now();
+            connections.retain(|_, (connected_at, _)| now.duration_since(*connected_at) < MAX_AGE);
+        }
+        if connections.len() < MAX_CONNECTIONS {
+            connections.insert(peer, (Instant::now(), fingerprint(session)));
+        }
+    }
+
+    pub fn get(&self, peer: &SocketAddr) -> Option<String> {
+        self.connections.lock().unwrap().get(peer).map(|(_, fingerprint)| fingerprint.clone())
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{clock, delete_upload, gen_rand_id};
+
+// Гостевой режим: анонимные временные «корзины» для демо-стендов
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GuestConfig {
+    pub enabled: bool,
+    pub ttl_secs: u64,
+    pub quota_bytes: u64,
+    pub max_uploads: usize,
+    // Per client IP
+    pub buckets_per_hour: u32,
+    // Per bucket
+    pub uploads_per_minute: u32,
+}
+
+impl Default for GuestConfig {
+    fn default() -> Self {
+        GuestConfig {
+            enabled: false,
+            ttl_secs: 3600,
+            quota_bytes: 20 << 20,
+            max_uploads: 20,
+            buckets_per_hour: 5,
+            uploads_per_minute: 10,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum GuestError {
+    #[error("Unknown or expired guest token")]
+    InvalidToken,
+    #[error("Guest bucket quota exceeded")]
+    QuotaExceeded,
+    #[error("Too many requests, slow down")]
+    RateLimited,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestBucket {
+    pub token: String,
+    pub expires_at: u64,
+    pub bytes_used: u64,
+    pub uploads: Vec<String>,
+}
+
+fn now() -> u64 {
+    clock::unix_now()
+}
+
+// Fixed one-window counter, good enough to blunt abuse
+#[derive(Default)]
+pub(crate) struct RateLimiter {
+    windows: HashMap<String, (SystemTime, u32)>,
+}
+
+impl RateLimiter {
+    pub(crate) fn allow(&mut self, key: &str, limit: u32, window: Duration) -> bool {
+        let now = clock::now();
+        self.windows.retain(|_, (start, _)| clock::since(*start) < window);
+
+        let (_, count) = self.windows.entry(key.to_owned()).or_insert((now, 0));
+        *count += 1;
+        *count <= limit
+    }
+}
+
+// Buckets are journaled to `uploads_dir/guest`, so a restart can't turn
+// guest uploads into permanent ones
+#[derive(Clone)]
+pub struct GuestBuckets {
+    config: GuestConfig,
+    dir: PathBuf,
+    buckets: Arc<Mutex<HashMap<String, GuestBucket>>>,
+    creation_limiter: Arc<Mutex<RateLimiter>>,
+    upload_limiter: Arc<Mutex<RateLimiter>>,
+}
+
+impl GuestBuckets {
+    pub async fn load<P: AsRef<Path>>(config: &GuestConfig, uploads_dir: P) -> Result<GuestBuckets> {
+        let dir = uploads_dir.as_ref().join("guest");
+        let mut buckets = HashMap::new();
+
+        if config.enabled {
+            tokio::fs::create_dir_all(&dir).await?;
+            let mut entries = tokio::fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let data = tokio::fs::read(entry.path()).await?;
+                match serde_json::from_slice::<GuestBucket>(&data) {
+                    Ok(bucket) => {
+                        buckets.insert(bucket.token.clone(), bucket);
+                    }
+                    Err(err) => tracing::warn!("Skipping guest bucket {:?}: {}", entry.path(), err),
+                }
+            }
+        }
+
+        Ok(GuestBuckets {
+            config: config.clone(),
+            dir,
+            buckets: Arc::new(Mutex::new(buckets)),
+            creation_limiter: Default::default(),
+            upload_limiter: Default::default(),
+        })
+    }
+
+    fn journal_path(&self, token: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", token))
+    }
+
+    async fn save(&self, bucket: &GuestBucket) -> Result<()> {
+        let tmp_path = self.journal_path(&bucket.token).with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_vec(bucket)?).await?;
+        tokio::fs::rename(&tmp_path, self.journal_path(&bucket.token)).await?;
+        Ok(())
+    }
+
+    pub async fn create(&self, client: &str) -> Result<GuestBucket> {
+        let allowed = self.creation_limiter.lock().unwrap().allow(
+            client,
+            self.config.buckets_per_hour,
+            Duration::from_secs(3600),
+        );
+        if !allowed {
+            return Err(GuestError::RateLimited.into());
+        }
+
+        let bucket = GuestBucket {
+            token: gen_rand_id(32),
+            expires_at: now() + self.config.ttl_secs,
+            bytes_used: 0,
+            uploads: Vec::new(),
+        };
+        self.save(&bucket).await?;
+        self.buckets.lock().unwrap().insert(bucket.token.clone(), bucket.clone());
+
+        Ok(bucket)
+    }
+
+    // Called before an upload starts
+    pub fn check_upload(&self, token: &str) -> Result<(), GuestError> {
+        let buckets = self.buckets.lock().unwrap();
+        let bucket = buckets
+            .get(token)
+            .filter(|bucket| bucket.expires_at > now())
+            .ok_or(GuestError::InvalidToken)?;
+
+        if bucket.uploads.len() >= self.config.max_uploads || bucket.bytes_used >= self.config.quota_bytes {
+            return Err(GuestError::QuotaExceeded);
+        }
+
+        let allowed = self.upload_limiter.lock().unwrap().allow(
+            token,
+            self.config.uploads_per_minute,
+            Duration::from_secs(60),
+        );
+        if !allowed {
+            return Err(GuestError::RateLimited);
+        }
+
+        Ok(())
+    }
+
+    // Called after an upload is stored. An upload that doesn't fit in the
+    // remaining quota is deleted again.
+    pub async fn record_upload(&self, token: &str, uploads_dir: &Path, id: &str, size: u64) -> Result<()> {
+        let res = {
+            let mut buckets = self.buckets.lock().unwrap();
+            match buckets.get_mut(token) {
+                // Reaped while the upload was running
+                None => Err(GuestError::InvalidToken),
+                Some(bucket)
+                    if bucket.bytes_used + size > self.config.quota_bytes
+                        || bucket.uploads.len() >= self.config.max_uploads =>
+                {
+                    Err(GuestError::QuotaExceeded)
+                }
+                Some(bucket) => {
+                    bucket.bytes_used += size;
+                    bucket.uploads.push(id.to_owned());
+                    Ok(bucket.clone())
+                }
+            }
+        };
+
+        match res {
+            Ok(bucket) => self.save(&bucket).await,
+            Err(err) => {
+                delete_upload(uploads_dir, id).await?;
+                Err(err.into())
+            }
+        }
+    }
+
+    // Deletes expired buckets together with everything uploaded into them
+    pub async fn reap(&self, uploads_dir: &Path) -> Result<usize> {
+        let now = now();
+        let expired: Vec<GuestBucket> = {
+            let mut buckets = self.buckets.lock().unwrap();
+            let tokens: Vec<String> = buckets
+                .values()
+                .filter(|bucket| bucket.expires_at <= now)
+                .map(|bucket| bucket.token.clone())
+                .collect();
+            tokens.iter().filter_map(|token| buckets.remove(token)).collect()
+        };
+
+        for bucket in &expired {
+            for id in &bucket.uploads {
+                if let Err(err) = delete_upload(uploads_dir, id).await {
+                    tracing::warn!("Failed to delete guest upload {}: {}", id, err);
+                }
+            }
+            tokio::fs::remove_file(self.journal_path(&bucket.token)).await?;
+        }
+
+        Ok(expired.len())
+    }
+
+    pub fn spawn_reaper(&self, uploads_dir: PathBuf) {
+        if !self.config.enabled {
+            return;
+        }
+
+        let buckets = self.clone();
+        actix_rt::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                match buckets.reap(&uploads_dir).await {
+                    Ok(0) => {}
+                    Ok(reaped) => tracing::info!("Removed {} expired guest bucket(s)", reaped),
+                    Err(err) => tracing::error!("Guest reaper error: {}", err),
+                }
+            }
+        });
+    }
+}
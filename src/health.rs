@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::concurrency::{self, AdaptiveLimit};
+use crate::thumbnails::ThumbnailQueue;
+use crate::{gen_rand_id, Config};
+
+// Пороги сигнала /lb-health. Queues count as busy (429) from `busy_ratio`
+// of their capacity and as overloaded (503) from `overloaded_ratio`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LbHealthConfig {
+    pub busy_ratio: f64,
+    pub overloaded_ratio: f64,
+    // Jobs waiting for a processing slot that count as a full queue
+    pub max_processing_backlog: usize,
+    // Busy below this many free bytes, overloaded below `min_free_disk_space`
+    pub busy_free_disk_space: u64,
+}
+
+impl Default for LbHealthConfig {
+    fn default() -> Self {
+        LbHealthConfig {
+            busy_ratio: 0.75,
+            overloaded_ratio: 0.95,
+            max_processing_backlog: 256,
+            busy_free_disk_space: 2 << 30,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Check {
+    fn from_result(res: Result<(), String>) -> Check {
+        match res {
+            Ok(()) => Check { ok: true, error: None },
+            Err(error) => Check { ok: false, error: Some(error) },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+// Creates and removes a probe file, a read-only or full volume fails here
+async fn check_writable(uploads_dir: &Path) -> Result<(), String> {
+    let probe = uploads_dir.join(format!(".readyz-{}", gen_rand_id(8)));
+    tokio::fs::write(&probe, b"ok").await.map_err(|e| e.to_string())?;
+    tokio::fs::remove_file(&probe).await.map_err(|e| e.to_string())
+}
+
+fn check_disk_space(uploads_dir: &Path, min_free: u64) -> Result<(), String> {
+    let available = fs2::available_space(uploads_dir).map_err(|e| e.to_string())?;
+    if available < min_free {
+        return Err(format!("{} bytes free, {} required", available, min_free));
+    }
+    Ok(())
+}
+
+// Everything the instance needs to accept uploads
+pub async fn readiness(config: &Config) -> Readiness {
+    let mut checks = BTreeMap::new();
+    checks.insert(
+        "uploads_dir_writable",
+        Check::from_result(check_writable(&config.uploads_dir).await),
+    );
+    checks.insert(
+        "disk_space",
+        Check::from_result(check_disk_space(&config.uploads_dir, config.min_free_disk_space)),
+    );
+
+    Readiness {
+        ready: checks.values().all(|check| check.ok),
+        checks,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LoadLevel {
+    Ok,
+    Busy,
+    Overloaded,
+}
+
+// `value` out of `limit`: used slots of a queue, free bytes of the disk
+#[derive(Debug, Serialize)]
+pub struct LoadCheck {
+    pub level: LoadLevel,
+    pub value: u64,
+    pub limit: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Load {
+    pub level: LoadLevel,
+    pub checks: BTreeMap<&'static str, LoadCheck>,
+}
+
+fn queue_load(lb_health: &LbHealthConfig, used: u64, capacity: u64) -> LoadCheck {
+    let ratio = used as f64 / capacity.max(1) as f64;
+    let level = if ratio >= lb_health.overloaded_ratio {
+        LoadLevel::Overloaded
+    } else if ratio >= lb_health.busy_ratio {
+        LoadLevel::Busy
+    } else {
+        LoadLevel::Ok
+    };
+    LoadCheck { level, value: used, limit: capacity }
+}
+
+// Whether a load balancer should keep sending traffic; unlike `readiness`
+// this only reads counters, so it is cheap enough to poll every second
+pub fn load(config: &Config, thumbnails: &ThumbnailQueue, upload_slots: &AdaptiveLimit) -> Load {
+    let lb_health = &config.lb_health;
+    let mut checks = BTreeMap::new();
+
+    checks.insert(
+        "thumbnail_queue",
+        queue_load(lb_health, thumbnails.queued() as u64, thumbnails.capacity() as u64),
+    );
+    // Without a limit the slots never fill up
+    if upload_slots.limit() != usize::MAX {
+        checks.insert(
+            "upload_slots",
+            queue_load(lb_health, upload_slots.in_flight() as u64, upload_slots.limit() as u64),
+        );
+    }
+    checks.insert(
+        "processing_backlog",
+        queue_load(
+            lb_health,
+            concurrency::processing_backlog() as u64,
+            lb_health.max_processing_backlog as u64,
+        ),
+    );
+
+    let disk = match fs2::available_space(&config.uploads_dir) {
+        Ok(available) if available < config.min_free_disk_space => (LoadLevel::Overloaded, available),
+        Ok(available) if available < lb_health.busy_free_disk_space => (LoadLevel::Busy, available),
+        Ok(available) => (LoadLevel::Ok, available),
+        Err(err) => {
+            tracing::warn!("Error reading free disk space: {}", err);
+            (LoadLevel::Overloaded, 0)
+        }
+    };
+    checks.insert(
+        "disk_space",
+        LoadCheck {
+            level: disk.0,
+            value: disk.1,
+            limit: lb_health.busy_free_disk_space,
+        },
+    );
+
+    let level = checks
+        .values()
+        .map(|check| check.level)
+        .fold(LoadLevel::Ok, |level, check| if check > level { check } else { level });
+    Load { level, checks }
+}
@@ -0,0 +1,3734 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_multipart::{Field, Multipart};
+use actix_web::body::SizedStream;
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{EntityTag, ETag, Header, IfModifiedSince, IfNoneMatch, LastModified};
+use actix_web::http::{self, header, StatusCode};
+use actix_web::{guard, web, FromRequest, HttpRequest, HttpResponse, ResponseError};
+use utoipa::{IntoParams, OpenApi, ToSchema};
+use serde::{Deserialize, Serialize};
+use futures_util::stream::{self, StreamExt, TryStreamExt};
+use futures_util::future;
+use tracing::Instrument;
+
+use crate::base64_stream::{self, Base64Chunks};
+use crate::json_stream;
+use crate::audit::{self, AuditEvent, AuditFilter};
+use crate::auth::{self, Ownership, Principal};
+use crate::jwt::{self, Scope};
+use crate::cluster::{self, Cluster};
+use crate::collections::{self, Collection};
+use crate::derivatives;
+use crate::guest::GuestBuckets;
+use crate::idempotency::{self, Begin, IdempotencyKeys, KeyGuard, StoredResponse};
+use crate::listeners::RouteSet;
+use crate::replication::{self, Replication};
+use crate::import::{self, ImportJob, ImportJobs, ManifestFormat};
+use crate::metadata::{self, ImageMetadata, UploadState};
+use crate::moderation;
+use crate::ocr;
+use crate::negotiate::{self, UploadBody};
+use crate::provenance::{self, Provenance};
+use crate::blocklist::{self, BlockEntry, EntrySource, HashKind};
+use crate::tiering;
+use crate::policy;
+use crate::error::ApiError;
+use crate::openapi;
+use crate::serve;
+use crate::signing::{self, NonceCache, SignatureError};
+use crate::health::LoadLevel;
+use crate::thumbnails::{ThumbnailQueue, ThumbnailStatus};
+use crate::limits::TypeThrottle;
+use crate::concurrency::{self, AdaptiveLimit};
+use crate::fingerprint::TlsFingerprints;
+use crate::cors::CorsConfig;
+use crate::layout;
+use crate::methods;
+use crate::metrics::{self, UnmatchedUpload};
+use crate::error_pages::ErrorPages;
+use crate::fetch::ForwardHeaders;
+use crate::telemetry;
+use crate::tenants::{self, TenantSettings, TenantStore};
+use crate::transform::{self, TransformSpec};
+use crate::tus::{self, TusStore, TusUpload};
+use crate::{Config, OutputOptions, UploadOptions, UploadedFile};
+
+type ServiceFuture = Pin<Box<dyn Future<Output = Result<ServiceResponse, actix_web::Error>>>>;
+
+// Requests that take an upload slot: POST /upload and tus writes
+fn is_upload_request(req: &ServiceRequest) -> bool {
+    let path = req.path();
+    let is_write = matches!(*req.method(), http::Method::POST | http::Method::PATCH);
+    is_write && (path == "/upload" || path.starts_with("/upload/tus"))
+}
+
+fn thumbnail_log(uploaded_file: &UploadedFile) -> &str {
+    match &uploaded_file.thumbnail_path {
+        Some(path) => path.to_str().unwrap_or("?"),
+        None if uploaded_file.thumbnail_pending => "Queued",
+        None if uploaded_file.thumbnail_skipped => "Skipped",
+        None => "Failed to create",
+    }
+}
+
+fn thumbnail_status(uploaded_file: &UploadedFile) -> ThumbnailStatus {
+    match uploaded_file.thumbnail_path {
+        Some(_) => ThumbnailStatus::Ready,
+        None if uploaded_file.thumbnail_pending => ThumbnailStatus::Pending,
+        None if uploaded_file.thumbnail_skipped => ThumbnailStatus::Missing,
+        None => ThumbnailStatus::Failed,
+    }
+}
+
+// How to answer an upload: `?details=` and `?wait=`
+struct Reply {
+    details: bool,
+    // With `?wait=processed`, how long to hold the response for background
+    // thumbnails
+    wait: Option<Duration>,
+}
+
+// Ids only, or with `?details=true` objects with the thumbnail status
+async fn uploaded_files_response(mut uploaded_files: Vec<UploadedFile>, reply: &Reply, options: &UploadOptions) -> HttpResponse {
+    // A lone upload's digest goes in a header too, the plain response is
+    // just ids
+    let mut response = HttpResponse::Ok();
+    if let [uploaded_file] = &uploaded_files[..] {
+        response.insert_header((CONTENT_SHA256, uploaded_file.sha256.clone()));
+    }
+    if !reply.details {
+        return response.json(uploaded_files_to_json_list(uploaded_files));
+    }
+
+    if let (Some(wait), Some(queue)) = (reply.wait, &options.thumbnails) {
+        let deadline = std::time::Instant::now() + wait;
+        for uploaded_file in uploaded_files.iter_mut().filter(|uploaded_file| uploaded_file.thumbnail_pending) {
+            match queue.wait(&uploaded_file.id, deadline).await {
+                ThumbnailStatus::Pending => break,
+                ThumbnailStatus::Ready => {
+                    let extension = uploaded_file.path.extension().and_then(|e| e.to_str()).unwrap_or("");
+                    let extension = options.thumbnail_format.extension(extension);
+                    let file_name = crate::thumbnail_file_name(&uploaded_file.id, extension);
+                    uploaded_file.thumbnail_path = Some(uploaded_file.path.with_file_name(file_name));
+                    uploaded_file.thumbnail_pending = false;
+                }
+                _ => uploaded_file.thumbnail_pending = false,
+            }
+        }
+    }
+
+    let items: Vec<UploadDetails> = uploaded_files
+        .iter()
+        .map(|uploaded_file| UploadDetails {
+            id: &uploaded_file.id,
+            thumbnail: thumbnail_status(uploaded_file),
+            blurhash: uploaded_file.blurhash.as_deref(),
+            dominant_colors: &uploaded_file.dominant_colors,
+            source_url: uploaded_file.source_url.as_deref(),
+            sha256: &uploaded_file.sha256,
+        })
+        .collect();
+    response.json(items)
+}
+
+// An upload as answered with `?details=true`
+#[derive(Serialize, ToSchema)]
+struct UploadDetails<'a> {
+    id: &'a str,
+    thumbnail: ThumbnailStatus,
+    blurhash: Option<&'a str>,
+    dominant_colors: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_url: Option<&'a str>,
+    // Of the bytes as received, see `CONTENT_SHA256`
+    sha256: &'a str,
+}
+
+fn uploaded_files_to_json_list(uploaded_files: Vec<UploadedFile>) -> serde_json::Value {
+    serde_json::Value::Array(
+        uploaded_files
+            .into_iter()
+            .map(|UploadedFile { id, ..}| serde_json::Value::String(id))
+            .collect()
+    )
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct UploadQuery {
+    strip_metadata: Option<bool>,
+    // Seconds until the uploads expire
+    ttl: Option<u64>,
+    // Unix time the uploads become public at
+    publish_at: Option<u64>,
+    // Respond with objects including the thumbnail status instead of ids
+    details: Option<bool>,
+    // `processed`: respond once background thumbnails are done, see `Reply`
+    wait: Option<String>,
+    // See `crate::OutputOptions`
+    format: Option<String>,
+    quality: Option<u8>,
+    thumbnail: Option<bool>,
+    blur_faces: Option<bool>,
+    thumbnail_crop: Option<crate::imagetools::Gravity>,
+    // Presigned upload, see `presign_upload`
+    expires: Option<u64>,
+    nonce: Option<String>,
+    owner: Option<String>,
+    tenant: Option<String>,
+    signature: Option<String>,
+}
+
+impl UploadQuery {
+    fn options(&self, config: &Config) -> UploadOptions {
+        let mut options = UploadOptions::from_config(config);
+        if let Some(strip_metadata) = self.strip_metadata {
+            options.strip_metadata = strip_metadata;
+        }
+        options.ttl = clamp_ttl(self.ttl, config);
+        options.publish_at = self.publish_at;
+        options.byte_limit = Some(crate::ByteLimit::from_config(config));
+        options
+    }
+
+    fn output(&self) -> OutputOptions {
+        OutputOptions {
+            format: self.format.clone(),
+            quality: self.quality,
+            thumbnail: self.thumbnail,
+            blur_faces: self.blur_faces,
+            thumbnail_crop: self.thumbnail_crop,
+        }
+    }
+}
+
+fn clamp_ttl(ttl: Option<u64>, config: &Config) -> Option<u64> {
+    ttl.map(|ttl| ttl.min(config.max_upload_ttl_secs))
+}
+
+// Makes a retried upload return the first response, see `idempotency`;
+// replayed responses are marked with `Idempotent-Replayed: true`
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+
+// Hex SHA-256 of an upload: what the client expects on a raw body or a
+// multipart part (`sha256` in JSON and forms), what was received in the
+// response; a mismatch is refused with 422 `checksum_mismatch`
+const CONTENT_SHA256: &str = "x-content-sha256";
+
+fn content_sha256(headers: &header::HeaderMap) -> Result<Option<String>, String> {
+    match headers.get(CONTENT_SHA256) {
+        Some(value) => expected_sha256(value.to_str().unwrap_or("")).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn expected_sha256(value: &str) -> Result<String, String> {
+    crate::parse_sha256(value).ok_or_else(|| "the expected SHA-256 must be 64 hex digits".to_owned())
+}
+
+// Multipart fields with JSON for the files after them, see `upload_multipart`
+const METADATA_FIELD: &str = "metadata";
+const OPTIONS_FIELD: &str = "options";
+const MAX_JSON_FIELD_SIZE: usize = 64 << 10;
+
+async fn read_json_field<T: serde::de::DeserializeOwned>(field: &mut Field) -> Result<T, String> {
+    let mut data = bytes::BytesMut::new();
+    while let Some(chunk) = field.next().await {
+        data.extend_from_slice(&chunk.map_err(|err| err.to_string())?);
+        if data.len() > MAX_JSON_FIELD_SIZE {
+            return Err("field is too large".to_owned());
+        }
+    }
+
+    serde_json::from_slice(&data).map_err(|err| format!("invalid JSON field: {}", err))
+}
+
+async fn read_custom_metadata(field: &mut Field) -> Result<BTreeMap<String, String>, String> {
+    let custom_metadata = read_json_field(field).await?;
+    metadata::check_custom(&custom_metadata)?;
+    Ok(custom_metadata)
+}
+
+// See `error::ApiError` for the status and code of each error; the body
+// also lists what was uploaded before the failure
+fn upload_error_response(err: &anyhow::Error, uploaded_files: Vec<UploadedFile>) -> HttpResponse {
+    ApiError::from(err)
+        .with("uploaded", uploaded_files_to_json_list(uploaded_files))
+        .error_response()
+}
+
+// Who presigned an upload, see `presign_upload`: their key as it is now, or
+// their token's subject. `None` if the API was open.
+fn presigned_principal(config: &Config, query: &UploadQuery) -> Option<Principal> {
+    let owner = query.owner.as_deref()?;
+    let api_key = config
+        .api_keys
+        .iter()
+        .find(|api_key| api_key.name == owner && api_key.tenant == query.tenant);
+    Some(match api_key {
+        Some(api_key) => Principal::Key(api_key.clone()),
+        None => Principal::Token(jwt::TokenClaims {
+            sub: owner.to_owned(),
+            scopes: vec![Scope::UploadWrite],
+        }),
+    })
+}
+
+// Checks the presigned query or the bearer token; a guest token is returned
+// for quota accounting
+#[allow(clippy::result_large_err)]
+fn authorize_upload(
+    req: &HttpRequest,
+    query: &UploadQuery,
+    config: &Config,
+    guests: &GuestBuckets,
+    nonces: &NonceCache,
+) -> Result<Option<String>, HttpResponse> {
+    if let Some(signature) = &query.signature {
+        let res = signing::check_presigned_upload(
+            &config.signing_key,
+            nonces,
+            query.expires.unwrap_or(0),
+            query.nonce.as_deref().unwrap_or(""),
+            query.owner.as_deref(),
+            query.tenant.as_deref(),
+            signature,
+        );
+        // Unless the tenant's key is gone since
+        let revoked = query.tenant.is_some() && !matches!(presigned_principal(config, query), Some(Principal::Key(_)));
+        return match res {
+            Ok(()) if revoked => {
+                tracing::warn!("Presigned upload refused: key {:?} is gone", query.owner);
+                Err(HttpResponse::Unauthorized().finish())
+            }
+            Ok(()) => Ok(None),
+            Err(err) => {
+                tracing::warn!("Presigned upload refused: {}", err);
+                Err(ApiError::from(&err).error_response())
+            }
+        };
+    }
+
+    match auth::authenticate(config, req.headers()) {
+        Some(Principal::Guest(token)) => match guests.check_upload(&token) {
+            Ok(()) => Ok(Some(token)),
+            Err(err) => {
+                tracing::warn!("Guest upload refused: {}", err);
+                Err(ApiError::from(&err).error_response())
+            }
+        },
+        Some(Principal::Token(claims)) if !claims.has(Scope::UploadWrite) => Err(HttpResponse::Unauthorized().finish()),
+        Some(_) => Ok(None),
+        None => Err(HttpResponse::Unauthorized().finish()),
+    }
+}
+
+// Non-upload writes (imports, tus) aren't open to guests
+fn authorize_write(req: &HttpRequest, config: &Config) -> bool {
+    match auth::authenticate(config, req.headers()) {
+        Some(Principal::Anonymous) | Some(Principal::Key(_)) => true,
+        Some(Principal::Token(claims)) => claims.has(Scope::UploadWrite),
+        Some(Principal::Guest(_)) | None => false,
+    }
+}
+
+async fn record_guest_upload(
+    guests: &GuestBuckets,
+    guest_token: Option<&str>,
+    config: &Config,
+    uploaded_file: &UploadedFile,
+) -> anyhow::Result<()> {
+    if let Some(token) = guest_token {
+        let size = tokio::fs::metadata(&uploaded_file.path).await?.len();
+        guests.record_upload(token, &config.uploads_dir, &uploaded_file.id, size).await?;
+    }
+    Ok(())
+}
+
+async fn upload_multipart(
+    mut multipart: Multipart,
+    options: &UploadOptions,
+    config: &Config,
+    guests: &GuestBuckets,
+    guest_token: Option<&str>,
+    reply: &Reply,
+) -> HttpResponse {
+    let mut uploaded_files = Vec::new();
+    let mut custom_metadata = BTreeMap::new();
+    let mut field_options = options.clone();
+
+    while let Ok(Some(mut field)) = multipart.try_next().await {
+        let content_disposition = field.content_disposition();
+        let field_name = content_disposition.as_ref().and_then(|cd| cd.get_name());
+
+        // A JSON object of custom metadata for the files after it
+        if field_name == Some(METADATA_FIELD) {
+            custom_metadata = match read_custom_metadata(&mut field).await {
+                Ok(custom_metadata) => custom_metadata,
+                Err(err) => {
+                    tracing::error!("Upload error: {}", err);
+
+                    return ApiError::bad_request("invalid_metadata", err)
+                        .with("uploaded", uploaded_files_to_json_list(uploaded_files))
+                        .error_response();
+                }
+            };
+            continue;
+        }
+
+        // Output options for the files after it, over the query's
+        if field_name == Some(OPTIONS_FIELD) {
+            let res = read_json_field::<OutputOptions>(&mut field).await.and_then(|output| output.apply(&mut field_options));
+            if let Err(err) = res {
+                tracing::error!("Upload error: {}", err);
+
+                return ApiError::bad_request("invalid_options", err)
+                    .with("uploaded", uploaded_files_to_json_list(uploaded_files))
+                    .error_response();
+            }
+            continue;
+        }
+
+        let mut options = field_options.clone();
+        options.original_filename = content_disposition
+            .as_ref()
+            .and_then(|cd| cd.get_filename())
+            .and_then(metadata::sanitize_filename);
+        options.custom_metadata = custom_metadata.clone();
+        options.expected_sha256 = match content_sha256(field.headers()) {
+            Ok(expected) => expected,
+            Err(err) => {
+                return ApiError::bad_request("invalid_options", err)
+                    .with("uploaded", uploaded_files_to_json_list(uploaded_files))
+                    .error_response();
+            }
+        };
+
+        // A part without a Content-Type is taken as not an image
+        let declared_type = field.content_type().map(|mime| mime.essence_str().to_owned()).unwrap_or_default();
+        let extension = match crate::mime_type_to_extension(&declared_type) {
+            Some(extension) => extension,
+            None => {
+                return HttpResponse::UnsupportedMediaType()
+                    .json(uploaded_files_to_json_list(uploaded_files));
+            }
+        };
+
+        // The declared type is the client's word, the bytes must agree with it
+        let head = match crate::read_head(&mut field).await {
+            Ok(head) => head,
+            Err(err) => {
+                tracing::error!("Upload error: {}", err);
+
+                return HttpResponse::BadRequest()
+                    .json(uploaded_files_to_json_list(uploaded_files));
+            }
+        };
+
+        let content_type = crate::sniff_type(&head);
+        if crate::mime_type_to_extension(&content_type) != Some(extension) {
+            tracing::error!(
+                "Multipart field declares {} but contains {}",
+                declared_type,
+                content_type
+            );
+
+            return HttpResponse::UnsupportedMediaType()
+                .json(uploaded_files_to_json_list(uploaded_files));
+        }
+
+        let field = field.map(|chunk| chunk.map_err(|err| anyhow::anyhow!("{}", err)));
+        let stream = stream::once(future::ready(Ok(head.freeze()))).chain(field);
+        let res = crate::upload_image(stream, &config.uploads_dir, extension, &options).await;
+        match res {
+            Ok(uploaded_file) => {
+                tracing::info!(
+                    "Upload succeed, id: {}, path: {}, thumbnail: {}",
+                    uploaded_file.id,
+                    uploaded_file.path.to_str().unwrap_or("?"),
+                    thumbnail_log(&uploaded_file),
+                );
+
+                let res = record_guest_upload(guests, guest_token, config, &uploaded_file).await;
+                if let Err(err) = res {
+                    tracing::error!("Upload error: {}", err);
+
+                    return upload_error_response(&err, uploaded_files);
+                }
+
+                uploaded_files.push(uploaded_file);
+            }
+            Err(err) => {
+                tracing::error!("Upload error: {}", err);
+
+                return upload_error_response(&err, uploaded_files);
+            }
+        }
+    }
+
+    if !uploaded_files.is_empty() {
+        tracing::info!(
+            "Uploaded {} file{} in total (multipart/form-data)",
+            uploaded_files.len(),
+            if uploaded_files.len() > 1 { "s" } else { "" },
+        );
+
+        return uploaded_files_response(uploaded_files, reply, options).await;
+    } else {
+        return HttpResponse::BadRequest()
+            .json(uploaded_files_to_json_list(uploaded_files));
+    }
+}
+
+#[derive(Deserialize)]
+enum UploadSource {
+    #[serde(rename = "url")]
+    Url(String),
+    #[serde(rename = "base64")]
+    Base64(String),
+    // A `base64` value decoded as the body was read, see `json_stream`
+    #[serde(skip)]
+    Spooled(json_stream::Spooled),
+}
+
+impl fmt::Debug for UploadSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UploadSource::Url(url) => write!(f, "Url(\"{}\")", url),
+            UploadSource::Base64(data) => write!(f, "Base64({} bytes)", data.len()),
+            UploadSource::Spooled(spooled) => write!(f, "Spooled({} bytes)", spooled.size),
+        }
+    }
+}
+
+// `{"url": "..."}` or `{"base64": "..."}`, plus optional per-item settings
+#[derive(Debug, Deserialize)]
+struct UploadRequest {
+    #[serde(flatten)]
+    source: UploadSource,
+    // Override `?ttl=` and `?publish_at=` for this item
+    ttl: Option<u64>,
+    publish_at: Option<u64>,
+    // Kept in the metadata, like a multipart file name and `metadata` field
+    filename: Option<String>,
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+    // `format`, `quality`, `thumbnail`, `blur_faces` and `thumbnail_crop`
+    // over the query's
+    #[serde(flatten)]
+    output: OutputOptions,
+    // Sent along with a `url` fetch, see `fetch::FetchConfig::forward_headers`
+    #[serde(default)]
+    headers: ForwardHeaders,
+    // Expected of the fetched or decoded bytes, see `CONTENT_SHA256`
+    sha256: Option<String>,
+}
+
+// The form-urlencoded variant: a single `url` or `base64` field, plus `ttl`,
+// `publish_at`, `filename` and `sha256`
+#[derive(Deserialize)]
+struct UploadForm {
+    url: Option<String>,
+    base64: Option<String>,
+    ttl: Option<u64>,
+    publish_at: Option<u64>,
+    filename: Option<String>,
+    sha256: Option<String>,
+}
+
+impl UploadForm {
+    fn into_request(self) -> Option<UploadRequest> {
+        let source = match (self.url, self.base64) {
+            (Some(url), None) => UploadSource::Url(url),
+            (None, Some(data)) => UploadSource::Base64(data),
+            _ => return None,
+        };
+        Some(UploadRequest {
+            source,
+            ttl: self.ttl,
+            publish_at: self.publish_at,
+            filename: self.filename,
+            metadata: BTreeMap::new(),
+            output: OutputOptions::default(),
+            headers: ForwardHeaders::default(),
+            sha256: self.sha256,
+        })
+    }
+}
+
+// What `upload_json` uploads: a form's single item, or the objects of a JSON
+// body as they're read
+enum UploadRequests {
+    Parsed(std::vec::IntoIter<UploadRequest>),
+    Streamed(json_stream::UploadItems<Payload>),
+}
+
+impl UploadRequests {
+    async fn next(&mut self) -> anyhow::Result<Option<UploadRequest>> {
+        let (mut object, spooled) = match self {
+            UploadRequests::Parsed(upload_requests) => return Ok(upload_requests.next()),
+            UploadRequests::Streamed(items) => match items.next().await? {
+                Some(item) => item,
+                None => return Ok(None),
+            },
+        };
+        // Stands in for the spooled value, which `source` then becomes
+        if let (Some(_), Some(object)) = (&spooled, object.as_object_mut()) {
+            object.insert("base64".to_owned(), serde_json::Value::String(String::new()));
+        }
+        let mut upload_request: UploadRequest =
+            serde_json::from_value(object).map_err(|err| crate::UploadError::Body(err.into()))?;
+        if let Some(spooled) = spooled {
+            upload_request.source = UploadSource::Spooled(spooled);
+        }
+        Ok(Some(upload_request))
+    }
+}
+
+// The stored extension of decoded base64 data by its first bytes, which
+// must agree with the type of a data URI
+fn base64_extension(head: &[u8], declared_type: Option<&str>) -> Option<&'static str> {
+    let content_type = crate::sniff_type(head);
+    tracing::debug!("{}", &content_type);
+
+    let extension = crate::mime_type_to_extension(&content_type);
+    if let Some(declared_type) = declared_type {
+        if crate::mime_type_to_extension(declared_type) != extension {
+            tracing::error!("Data URI declares {} but contains {}", declared_type, content_type);
+            return None;
+        }
+    }
+    extension
+}
+
+async fn upload_json(
+    mut upload_requests: UploadRequests,
+    options: &UploadOptions,
+    config: &Config,
+    guests: &GuestBuckets,
+    guest_token: Option<&str>,
+    reply: &Reply,
+) -> HttpResponse {
+    let mut uploaded_files: Vec<UploadedFile> = Vec::new();
+
+    loop {
+        let upload_request = match upload_requests.next().await {
+            Ok(Some(upload_request)) => upload_request,
+            Ok(None) => break,
+            Err(err) => {
+                tracing::error!("Upload error: {}", err);
+
+                return upload_error_response(&err, uploaded_files);
+            }
+        };
+        tracing::debug!("{:?}", upload_request);
+
+        let mut options = options.clone();
+        if upload_request.ttl.is_some() {
+            options.ttl = clamp_ttl(upload_request.ttl, config);
+        }
+        if upload_request.publish_at.is_some() {
+            options.publish_at = upload_request.publish_at;
+        }
+        let res = metadata::check_custom(&upload_request.metadata)
+            .and_then(|()| upload_request.output.apply(&mut options))
+            .and_then(|()| {
+                options.expected_sha256 = upload_request.sha256.as_deref().map(expected_sha256).transpose()?;
+                Ok(())
+            });
+        if let Err(err) = res {
+            return ApiError::bad_request("invalid_options", err)
+                .with("uploaded", uploaded_files_to_json_list(uploaded_files))
+                .error_response();
+        }
+        options.original_filename = upload_request.filename.as_deref().and_then(metadata::sanitize_filename);
+        options.custom_metadata = upload_request.metadata.clone();
+
+        let res = match &upload_request.source {
+            UploadSource::Url(url) => crate::fetch_image(config, &url, &upload_request.headers, &options).await,
+            UploadSource::Base64(data) => {
+                let (declared_type, data) = match base64_stream::split_data_uri(data) {
+                    Ok(parts) => parts,
+                    Err(err) => {
+                        tracing::error!("Data URI error: {}", err);
+
+                        return HttpResponse::BadRequest()
+                            .json(uploaded_files_to_json_list(uploaded_files));
+                    }
+                };
+
+                let mut chunks = Base64Chunks::new(data);
+
+                // The first decoded chunk is enough to sniff the type
+                let first_chunk = match chunks.next() {
+                    Some(Ok(chunk)) => chunk,
+                    Some(Err(err)) => {
+                        tracing::error!("Base64 decode error: {}", err);
+
+                        return HttpResponse::BadRequest()
+                            .json(uploaded_files_to_json_list(uploaded_files));
+                    }
+                    None => {
+                        tracing::error!("Base64 decode error: no data");
+
+                        return HttpResponse::BadRequest()
+                            .json(uploaded_files_to_json_list(uploaded_files));
+                    }
+                };
+
+                let extension = match base64_extension(&first_chunk, declared_type) {
+                    Some(extension) => extension,
+                    None => {
+                        return HttpResponse::UnsupportedMediaType()
+                            .json(uploaded_files_to_json_list(uploaded_files));
+                    }
+                };
+
+                let stream = stream::once(future::ready(Ok(first_chunk))).chain(stream::iter(chunks));
+                crate::upload_image(stream, &config.uploads_dir, extension, &options).await
+            }
+            UploadSource::Spooled(spooled) => {
+                if spooled.size == 0 {
+                    tracing::error!("Base64 decode error: no data");
+
+                    return HttpResponse::BadRequest()
+                        .json(uploaded_files_to_json_list(uploaded_files));
+                }
+                let file = match tokio::fs::File::open(&spooled.path).await {
+                    Ok(file) => file,
+                    Err(err) => {
+                        tracing::error!("Error opening {}: {}", spooled.path.display(), err);
+
+                        return upload_error_response(&crate::UploadError::from(err).into(), uploaded_files);
+                    }
+                };
+                let mut stream = crate::file_stream(file);
+                let head = match crate::read_head(&mut stream).await {
+                    Ok(head) => head,
+                    Err(err) => {
+                        tracing::error!("Error reading {}: {}", spooled.path.display(), err);
+
+                        return upload_error_response(&crate::UploadError::from(err).into(), uploaded_files);
+                    }
+                };
+
+                let extension = match base64_extension(&head, spooled.declared_type.as_deref()) {
+                    Some(extension) => extension,
+                    None => {
+                        return HttpResponse::UnsupportedMediaType()
+                            .json(uploaded_files_to_json_list(uploaded_files));
+                    }
+                };
+
+                let stream = stream::once(future::ready(Ok(head.freeze()))).chain(stream);
+                crate::upload_image(stream, &config.uploads_dir, extension, &options).await
+            }
+        };
+        match res {
+            Ok(uploaded_file) => {
+                tracing::info!(
+                    "Upload succeed, id: {}, path: {}, thumbnail: {}",
+                    uploaded_file.id,
+                    uploaded_file.path.to_str().unwrap_or("?"),
+                    thumbnail_log(&uploaded_file),
+                );
+
+                let res = record_guest_upload(guests, guest_token, config, &uploaded_file).await;
+                if let Err(err) = res {
+                    tracing::error!("Upload error: {}", err);
+
+                    return upload_error_response(&err, uploaded_files);
+                }
+
+                uploaded_files.push(uploaded_file);
+            }
+            Err(err) => {
+                tracing::error!("Upload error: {}", err);
+
+                return upload_error_response(&err, uploaded_files);
+            }
+        }
+    }
+
+    if !uploaded_files.is_empty() {
+        tracing::info!(
+            "Uploaded {} file{} in total (application/json)",
+            uploaded_files.len(),
+            if uploaded_files.len() > 1 { "s" } else { "" },
+        );
+
+        return uploaded_files_response(uploaded_files, reply, options).await;
+    } else {
+        return HttpResponse::BadRequest()
+            .json(uploaded_files_to_json_list(uploaded_files));
+    }
+}
+
+async fn upload_raw(
+    mut payload: Payload,
+    declared_extension: Option<&'static str>,
+    options: &UploadOptions,
+    config: &Config,
+    guests: &GuestBuckets,
+    guest_token: Option<&str>,
+    reply: &Reply,
+) -> HttpResponse {
+    let head = match crate::read_head(&mut payload).await {
+        Ok(head) => head,
+        Err(err) => {
+            tracing::error!("Upload error: {}", err);
+
+            return HttpResponse::BadRequest().json(uploaded_files_to_json_list(Vec::new()));
+        }
+    };
+
+    // application/octet-stream takes whatever the bytes are
+    let content_type = crate::sniff_type(&head);
+    let extension = match crate::mime_type_to_extension(&content_type) {
+        Some(extension) if declared_extension.map(|declared| declared == extension).unwrap_or(true) => extension,
+        _ => {
+            tracing::error!("Raw upload declares {:?} but contains {}", declared_extension, content_type);
+
+            return HttpResponse::UnsupportedMediaType().json(uploaded_files_to_json_list(Vec::new()));
+        }
+    };
+
+    let stream = stream::once(future::ready(Ok(head.freeze()))).chain(payload);
+    let res = crate::upload_image(stream, &config.uploads_dir, extension, options).await;
+    match res {
+        Ok(uploaded_file) => {
+            tracing::info!(
+                "Upload succeed, id: {}, path: {}, thumbnail: {} (raw body)",
+                uploaded_file.id,
+                uploaded_file.path.to_str().unwrap_or("?"),
+                thumbnail_log(&uploaded_file),
+            );
+
+            if let Err(err) = record_guest_upload(guests, guest_token, config, &uploaded_file).await {
+                tracing::error!("Upload error: {}", err);
+
+                return upload_error_response(&err, Vec::new());
+            }
+
+            uploaded_files_response(vec![uploaded_file], reply, options).await
+        }
+        Err(err) => {
+            tracing::error!("Upload error: {}", err);
+
+            upload_error_response(&err, Vec::new())
+        }
+    }
+}
+
+// Any other method on /upload; OPTIONS is answered before routing
+async fn upload_method_not_allowed(req: HttpRequest) -> HttpResponse {
+    tracing::warn!("Unsupported upload method {}", req.method());
+    metrics::unmatched_upload(UnmatchedUpload::Method);
+
+    let error = ApiError::new(
+        StatusCode::METHOD_NOT_ALLOWED,
+        "method_not_allowed",
+        format!("{} is not allowed on /upload", req.method()),
+    );
+    HttpResponse::MethodNotAllowed().insert_header((header::ALLOW, "POST, OPTIONS")).json(
+        error
+            .with("allowed_methods", ["POST", "OPTIONS"])
+            .with("supported_content_types", negotiate::supported_content_types())
+            .body(),
+    )
+}
+
+// POST /upload for every body type, see `negotiate::upload_body`
+#[utoipa::path(
+    post,
+    path = "/upload",
+    tag = "images",
+    params(
+        UploadQuery,
+        ("X-Content-SHA256" = Option<String>, Header, description = "Expected hex SHA-256 of a raw body"),
+        ("Idempotency-Key" = Option<String>, Header, description = "A retry with the same key gets the first response"),
+    ),
+    request_body(
+        content = Vec<u8>,
+        content_type = "multipart/form-data",
+        description = "Files as multipart parts; a JSON list of URLs or base64 data, or a raw image body work too",
+    ),
+    responses(
+        (status = 200, description = "Ids of the stored uploads, objects with `?details=true`", body = [String]),
+        (status = 400, description = "Malformed", body = crate::error::ErrorBody),
+        (status = 413, description = "Over the byte or pixel limits", body = crate::error::ErrorBody),
+        (status = 415, description = "A Content-Type or image type that isn't accepted", body = crate::error::ErrorBody),
+        (status = 409, description = "A request with the same Idempotency-Key is in progress", body = crate::error::ErrorBody),
+        (status = 422, description = "Rejected by the upload policy, not the expected SHA-256, or a reused Idempotency-Key", body = crate::error::ErrorBody),
+        (status = 429, description = "Too many uploads of this type", body = crate::error::ErrorBody),
+        (status = 451, description = "Matches the blocklist", body = crate::error::ErrorBody),
+        (status = 507, description = "Over a storage quota", body = crate::error::ErrorBody),
+    ),
+)]
+#[allow(clippy::too_many_arguments)]
+async fn upload(
+    req: HttpRequest,
+    payload: web::Payload,
+    query: web::Query<UploadQuery>,
+    config: web::Data<Config>,
+    guests: web::Data<GuestBuckets>,
+    nonces: web::Data<NonceCache>,
+    thumbnails: web::Data<ThumbnailQueue>,
+    type_throttle: web::Data<TypeThrottle>,
+    tenants: web::Data<TenantStore>,
+    idempotency_keys: web::Data<IdempotencyKeys>,
+) -> HttpResponse {
+    let content_type = req.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+    let body = match negotiate::upload_body(content_type) {
+        Some(body) => body,
+        None => {
+            tracing::warn!("Unsupported upload body: {:?}", content_type);
+            metrics::unmatched_upload(UnmatchedUpload::ContentType);
+
+            let supported = negotiate::supported_content_types();
+            return HttpResponse::UnsupportedMediaType()
+                .insert_header(("Accept-Post", supported.join(", ")))
+                .json(
+                    ApiError::new(
+                        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                        "unsupported_content_type",
+                        match content_type {
+                            Some(content_type) => format!("Unsupported Content-Type {}", content_type),
+                            None => "Content-Type is missing".to_owned(),
+                        },
+                    )
+                    .with("supported_content_types", supported)
+                    .body(),
+                );
+        }
+    };
+
+    let guest_token = match authorize_upload(&req, &query, &config, &guests, &nonces) {
+        Ok(guest_token) => guest_token,
+        Err(response) => return response,
+    };
+    let guest_token = guest_token.as_deref();
+
+    let mut options = query.options(&config);
+    // A presigned upload is the presigner's
+    let principal = match &query.signature {
+        Some(_) => presigned_principal(&config, &query),
+        None => auth::authenticate(&config, req.headers()),
+    };
+    if let Some(Principal::Token(claims)) = &principal {
+        options.owner = Some(claims.sub.clone());
+    }
+    if let Some(Principal::Key(api_key)) = principal {
+        if let Some(tenant) = &api_key.tenant {
+            match tenants.get(tenant).await {
+                Ok(Some(settings)) => settings.apply(&mut options),
+                Ok(None) => {}
+                Err(err) => {
+                    tracing::error!("Tenant store error: {}", err);
+                    return HttpResponse::InternalServerError().finish();
+                }
+            }
+        }
+        // Refused before the body is read once the day's uploads are used up
+        if let Err(err) = crate::quota::check_key(&api_key.limits, &api_key.name, 0) {
+            tracing::warn!("Upload refused: {}", err);
+            return ApiError::from(&err).error_response();
+        }
+        options.byte_limit = Some(api_key.limits.byte_limit(&config));
+        options.key_limits = Some(api_key.limits);
+        options.owner = Some(api_key.name);
+        options.tenant = api_key.tenant;
+    }
+    if let Err(err) = query.output().apply(&mut options) {
+        return ApiError::bad_request("invalid_options", err).error_response();
+    }
+    options.thumbnails = Some(thumbnails.get_ref().clone());
+    options.type_throttle = Some(type_throttle.get_ref().clone());
+    let wait = match query.wait.as_deref() {
+        None => None,
+        Some("processed") => Some(Duration::from_secs(config.upload_wait_timeout_secs)),
+        Some(_) => {
+            return ApiError::bad_request("invalid_options", "wait must be \"processed\"").error_response();
+        }
+    };
+    // Waiting only pays off with the thumbnail status in the response
+    let reply = Reply {
+        details: query.details.unwrap_or(false) || wait.is_some(),
+        wait,
+    };
+
+    // A retry gets the first response, see `idempotency`
+    let idempotency_key = req.headers().get(IDEMPOTENCY_KEY).filter(|_| config.idempotency.window_secs > 0);
+    let key_guard = match idempotency_key {
+        Some(key) => {
+            let header_value = |name: &str| req.headers().get(name).and_then(|value| value.to_str().ok());
+            let fingerprint = idempotency::fingerprint(
+                req.query_string(),
+                &[content_type, header_value(header::CONTENT_LENGTH.as_str()), header_value(CONTENT_SHA256)],
+            );
+            let scope = options.owner.as_deref().or(guest_token).unwrap_or("");
+            let key = key.to_str().unwrap_or("");
+            let res = idempotency_keys
+                .begin(&config.uploads_dir, &config.idempotency, scope, key, &fingerprint)
+                .await;
+            match res {
+                Ok(Begin::Replay(stored)) => {
+                    tracing::info!("Replaying the response to Idempotency-Key {:?}", key);
+                    return replayed_response(stored);
+                }
+                Ok(Begin::Fresh(key_guard)) => Some(key_guard),
+                Err(err) => {
+                    tracing::warn!("Idempotency-Key {:?} refused: {}", key, err);
+                    return ApiError::from(&err).error_response();
+                }
+            }
+        }
+        None => None,
+    };
+    let mut payload = payload.into_inner();
+
+    let response = match body {
+        UploadBody::Multipart => {
+            let multipart = Multipart::new(req.headers(), payload);
+            upload_multipart(multipart, &options, &config, &guests, guest_token, &reply).await
+        }
+        UploadBody::Json => {
+            let items = json_stream::UploadItems::new(
+                payload,
+                &config.uploads_dir,
+                config.max_json_payload_size,
+                options.byte_limit.clone(),
+            );
+            upload_json(UploadRequests::Streamed(items), &options, &config, &guests, guest_token, &reply).await
+        }
+        UploadBody::Form => match web::Form::<UploadForm>::from_request(&req, &mut payload).await {
+            Ok(form) => match form.into_inner().into_request() {
+                Some(upload_request) => {
+                    let upload_requests = UploadRequests::Parsed(vec![upload_request].into_iter());
+                    upload_json(upload_requests, &options, &config, &guests, guest_token, &reply).await
+                }
+                None => ApiError::bad_request("invalid_body", "exactly one of url and base64 is required")
+                    .error_response(),
+            },
+            Err(err) => HttpResponse::from_error(err),
+        },
+        UploadBody::Raw(extension) => {
+            // `Content-Disposition: attachment; filename="..."`, as with multipart
+            options.original_filename = header::ContentDisposition::parse(&req)
+                .ok()
+                .and_then(|cd| cd.get_filename().and_then(metadata::sanitize_filename));
+            options.expected_sha256 = match content_sha256(req.headers()) {
+                Ok(expected) => expected,
+                Err(err) => return ApiError::bad_request("invalid_options", err).error_response(),
+            };
+            upload_raw(payload, extension, &options, &config, &guests, guest_token, &reply).await
+        }
+    };
+    match key_guard {
+        Some(key_guard) if response.status().is_success() => keep_response(key_guard, response).await,
+        _ => response,
+    }
+}
+
+// Kept for retries with the same key, the client gets the response even if
+// keeping it fails
+async fn keep_response(key_guard: KeyGuard, response: HttpResponse) -> HttpResponse {
+    let (response, body) = response.into_parts();
+    let body = match actix_web::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(err) => {
+            tracing::error!("Error reading the upload response: {}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let header_value = |name| response.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_owned);
+    let res = key_guard
+        .finish(
+            response.status().as_u16(),
+            header_value(header::CONTENT_TYPE),
+            header_value(header::HeaderName::from_static(CONTENT_SHA256)),
+            String::from_utf8_lossy(&body).into_owned(),
+        )
+        .await;
+    if let Err(err) = res {
+        tracing::error!("Error keeping the response for Idempotency-Key: {}", err);
+    }
+    response.set_body(body).map_into_boxed_body()
+}
+
+fn replayed_response(stored: StoredResponse) -> HttpResponse {
+    let mut response = HttpResponse::build(StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK));
+    response.insert_header((IDEMPOTENT_REPLAYED, "true"));
+    if let Some(content_type) = stored.content_type {
+        response.insert_header((header::CONTENT_TYPE, content_type));
+    }
+    if let Some(sha256) = stored.sha256 {
+        response.insert_header((CONTENT_SHA256, sha256));
+    }
+    response.body(stored.body)
+}
+
+// Options of an upload finished apart from the request that started it:
+// stored as the creator's, with their tenant's overrides and their key's limits
+async fn owned_options(config: &Config, tenants: &TenantStore, ownership: &Ownership) -> anyhow::Result<UploadOptions> {
+    let mut options = UploadOptions::from_config(config);
+    if let Some(tenant) = &ownership.tenant {
+        if let Some(settings) = tenants.get(tenant).await? {
+            settings.apply(&mut options);
+        }
+    }
+    options.owner = ownership.owner.clone();
+    options.tenant = ownership.tenant.clone();
+    options.byte_limit = Some(match &ownership.key_limits {
+        Some(key_limits) => key_limits.byte_limit(config),
+        None => crate::ByteLimit::from_config(config),
+    });
+    options.key_limits = ownership.key_limits.clone();
+    Ok(options)
+}
+
+// Refused up front once the key's limits are used up, `size` bytes more
+// don't fit or it's over the key's file size limit; see `quota::check_key`
+fn check_key_limits(ownership: &Ownership, size: u64) -> Result<(), crate::quota::QuotaError> {
+    match (&ownership.key_limits, &ownership.owner) {
+        (Some(key_limits), Some(owner)) => crate::quota::check_key(key_limits, owner, size),
+        _ => Ok(()),
+    }
+}
+
+async fn create_import(
+    req: HttpRequest,
+    body: web::Bytes,
+    config: web::Data<Config>,
+    jobs: web::Data<ImportJobs>,
+    tenants: web::Data<TenantStore>,
+) -> HttpResponse {
+    if !authorize_write(&req, &config) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let format = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|s| s.parse::<mime::Mime>().ok())
+        .and_then(|mime_type| ManifestFormat::from_mime_type(mime_type.essence_str()));
+
+    let format = match format {
+        Some(format) => format,
+        None => return HttpResponse::UnsupportedMediaType().finish(),
+    };
+
+    let rows = match import::parse_manifest(format, &body, config.max_manifest_rows) {
+        Ok(rows) => rows,
+        Err(err) => {
+            tracing::error!("Manifest error: {}", err);
+
+            return ApiError::from(&err).error_response();
+        }
+    };
+
+    let ownership = auth::authenticate(&config, req.headers())
+        .map(|principal| principal.ownership())
+        .unwrap_or_default();
+    if let Err(err) = check_key_limits(&ownership, 0) {
+        tracing::warn!("Import refused: {}", err);
+        return ApiError::from(&err).error_response();
+    }
+    let options = match owned_options(&config, &tenants, &ownership).await {
+        Ok(options) => options,
+        Err(err) => {
+            tracing::error!("Tenant store error: {}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let total = rows.len();
+    let id = jobs.create(rows, ownership);
+
+    tracing::info!(
+        "Import {} created with {} row{}",
+        id,
+        total,
+        if total > 1 { "s" } else { "" },
+    );
+
+    let (jobs, config, job_id) = (jobs.get_ref().clone(), config.get_ref().clone(), id.clone());
+    actix_rt::spawn(async move { jobs.run(config, job_id, options).await });
+
+    HttpResponse::Accepted()
+        .insert_header((header::LOCATION, format!("/imports/{}", id)))
+        .json(serde_json::json!({ "id": id, "total": total }))
+}
+
+// Other callers' jobs don't exist
+fn find_import(req: &HttpRequest, config: &Config, jobs: &ImportJobs, id: &str) -> Result<ImportJob, StatusCode> {
+    let principal = auth::authenticate(config, req.headers()).ok_or(StatusCode::UNAUTHORIZED)?;
+    jobs.get(id)
+        .filter(|job| job.ownership.is_held_by(&principal))
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_import(
+    req: HttpRequest,
+    id: web::Path<String>,
+    config: web::Data<Config>,
+    jobs: web::Data<ImportJobs>,
+) -> HttpResponse {
+    match find_import(&req, &config, &jobs, &id) {
+        Ok(job) => HttpResponse::Ok().json(job),
+        Err(status) => HttpResponse::build(status).finish(),
+    }
+}
+
+#[derive(Deserialize)]
+struct ReportQuery {
+    format: Option<String>,
+}
+
+async fn get_import_report(
+    req: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<ReportQuery>,
+    config: web::Data<Config>,
+    jobs: web::Data<ImportJobs>,
+) -> HttpResponse {
+    let job = match find_import(&req, &config, &jobs, &id) {
+        Ok(job) => job,
+        Err(status) => return HttpResponse::build(status).finish(),
+    };
+
+    let (report, content_type, extension) = match query.format.as_deref() {
+        None | Some("csv") => (import::report_csv(&job), "text/csv", "csv"),
+        Some("jsonl") => (import::report_jsonl(&job), "application/x-ndjson", "jsonl"),
+        Some(_) => return HttpResponse::BadRequest().finish(),
+    };
+
+    match report {
+        Ok(report) => HttpResponse::Ok()
+            .content_type(content_type)
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"import-{}.{}\"", job.id, extension),
+            ))
+            .body(report),
+        Err(err) => {
+            tracing::error!("Import report error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+fn tus_response(status: StatusCode) -> actix_web::HttpResponseBuilder {
+    let mut builder = HttpResponse::build(status);
+    builder.insert_header(("Tus-Resumable", tus::TUS_VERSION));
+    builder
+}
+
+fn header_u64(req: &HttpRequest, name: &str) -> Option<u64> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|s| s.parse().ok())
+}
+
+async fn tus_options(config: web::Data<Config>) -> HttpResponse {
+    tus_response(StatusCode::NO_CONTENT)
+        .insert_header(("Tus-Version", tus::TUS_VERSION))
+        .insert_header(("Tus-Extension", tus::TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", config.max_tus_upload_size.to_string()))
+        .finish()
+}
+
+async fn tus_create(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    store: web::Data<TusStore>,
+    tenants: web::Data<TenantStore>,
+) -> HttpResponse {
+    if !authorize_write(&req, &config) {
+        return tus_response(StatusCode::UNAUTHORIZED).finish();
+    }
+
+    let length = match header_u64(&req, "Upload-Length") {
+        Some(length) => length,
+        None => return tus_response(StatusCode::BAD_REQUEST).finish(),
+    };
+
+    let ownership = auth::authenticate(&config, req.headers())
+        .map(|principal| principal.ownership())
+        .unwrap_or_default();
+    if length > config.max_tus_upload_size || crate::ByteLimit::from_config(&config).check(length).is_err() {
+        return tus_response(StatusCode::PAYLOAD_TOO_LARGE).finish();
+    }
+    if let Err(err) = check_key_limits(&ownership, length) {
+        tracing::warn!("Tus upload refused: {}", err);
+        let error = ApiError::from(&err);
+        return tus_response(error.status).json(error.body());
+    }
+
+    let metadata = match req.headers().get("Upload-Metadata") {
+        Some(value) => match value.to_str().map(tus::parse_metadata) {
+            Ok(Ok(metadata)) => metadata,
+            _ => return tus_response(StatusCode::BAD_REQUEST).finish(),
+        },
+        None => Default::default(),
+    };
+
+    match store.create(length, metadata, ownership).await {
+        Ok(upload) => {
+            tracing::info!("Tus upload {} created, {} bytes expected", upload.id, length);
+
+            let mut response = tus_response(StatusCode::CREATED);
+            response.insert_header((header::LOCATION, format!("/upload/tus/{}", upload.id)));
+            // Complete already, no PATCH would come
+            if upload.is_complete() {
+                match finalize_tus(&config, &store, &tenants, upload).await {
+                    Ok(uploaded_file) => response.insert_header(("Upload-Id", uploaded_file.id)),
+                    Err(response) => return response,
+                };
+            }
+            response.finish()
+        }
+        Err(err) => {
+            tracing::error!("Tus create error: {}", err);
+            tus_response(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+// Other callers' uploads don't exist
+fn holds_tus_upload(req: &HttpRequest, config: &Config, upload: &TusUpload) -> bool {
+    auth::authenticate(config, req.headers())
+        .map(|principal| upload.ownership.is_held_by(&principal))
+        .unwrap_or(false)
+}
+
+async fn tus_head(
+    req: HttpRequest,
+    id: web::Path<String>,
+    config: web::Data<Config>,
+    store: web::Data<TusStore>,
+) -> HttpResponse {
+    if !authorize_write(&req, &config) {
+        return tus_response(StatusCode::UNAUTHORIZED).finish();
+    }
+
+    match store.get(&id).await {
+        Ok(Some(upload)) if holds_tus_upload(&req, &config, &upload) => {
+            let mut response = tus_response(StatusCode::OK);
+            response
+                .insert_header(("Upload-Offset", upload.offset.to_string()))
+                .insert_header(("Upload-Length", upload.length.to_string()))
+                .insert_header((header::CACHE_CONTROL, "no-store"));
+            if let Some(uploaded_id) = upload.uploaded_id {
+                response.insert_header(("Upload-Id", uploaded_id));
+            }
+            response.finish()
+        }
+        Ok(_) => tus_response(StatusCode::NOT_FOUND).finish(),
+        Err(err) => {
+            tracing::error!("Tus head error: {}", err);
+            tus_response(StatusCode::INTERNAL_SERVER_ERROR).finish()
+        }
+    }
+}
+
+async fn tus_patch(
+    req: HttpRequest,
+    id: web::Path<String>,
+    payload: web::Payload,
+    config: web::Data<Config>,
+    store: web::Data<TusStore>,
+    tenants: web::Data<TenantStore>,
+) -> HttpResponse {
+    if !authorize_write(&req, &config) {
+        return tus_response(StatusCode::UNAUTHORIZED).finish();
+    }
+
+    let is_offset_stream = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .map(|content_type| content_type == tus::OFFSET_CONTENT_TYPE)
+        .unwrap_or(false);
+    if !is_offset_stream {
+        return tus_response(StatusCode::UNSUPPORTED_MEDIA_TYPE).finish();
+    }
+
+    let offset = match header_u64(&req, "Upload-Offset") {
+        Some(offset) => offset,
+        None => return tus_response(StatusCode::BAD_REQUEST).finish(),
+    };
+
+    let _lock = match store.lock(&id) {
+        Some(lock) => lock,
+        None => return tus_response(StatusCode::CONFLICT).finish(),
+    };
+
+    let upload = match store.get(&id).await {
+        Ok(Some(upload)) if holds_tus_upload(&req, &config, &upload) => upload,
+        Ok(_) => return tus_response(StatusCode::NOT_FOUND).finish(),
+        Err(err) => {
+            tracing::error!("Tus patch error: {}", err);
+            return tus_response(StatusCode::INTERNAL_SERVER_ERROR).finish();
+        }
+    };
+
+    let upload = match store.append(upload, offset, payload).await {
+        Ok(upload) => upload,
+        Err(err) => {
+            tracing::error!("Tus patch error: {}", err);
+
+            let error = ApiError::from(&err);
+            return tus_response(error.status).json(error.body());
+        }
+    };
+
+    let mut response = tus_response(StatusCode::NO_CONTENT);
+    response.insert_header(("Upload-Offset", upload.offset.to_string()));
+
+    if upload.is_complete() {
+        match finalize_tus(&config, &store, &tenants, upload).await {
+            Ok(uploaded_file) => response.insert_header(("Upload-Id", uploaded_file.id)),
+            Err(response) => return response,
+        };
+    }
+
+    response.finish()
+}
+
+// Stores a complete upload as its creator's. One refused for good is
+// discarded; after a server error it stays complete, and a retried PATCH
+// with an empty body finalizes it again.
+async fn finalize_tus(
+    config: &Config,
+    store: &TusStore,
+    tenants: &TenantStore,
+    upload: TusUpload,
+) -> Result<UploadedFile, HttpResponse> {
+    let id = upload.id.clone();
+    let options = match owned_options(config, tenants, &upload.ownership).await {
+        Ok(options) => options,
+        Err(err) => {
+            tracing::error!("Tenant store error: {}", err);
+            return Err(tus_response(StatusCode::INTERNAL_SERVER_ERROR).finish());
+        }
+    };
+    match store.finalize(upload, &config.uploads_dir, &options).await {
+        Ok(uploaded_file) => {
+            tracing::info!(
+                "Upload succeed, id: {}, path: {} (tus {})",
+                uploaded_file.id,
+                uploaded_file.path.to_str().unwrap_or("?"),
+                id,
+            );
+            Ok(uploaded_file)
+        }
+        Err(err) => {
+            tracing::error!("Tus finalize error: {}", err);
+
+            // A rejected file won't pass on a retry, a server error may
+            let error = ApiError::from(&err);
+            if !error.status.is_server_error() {
+                if let Err(err) = store.discard(&id).await {
+                    tracing::warn!("Failed to discard tus upload {}: {}", id, err);
+                }
+            }
+            Err(tus_response(error.status).json(error.body()))
+        }
+    }
+}
+
+// If-None-Match takes precedence over If-Modified-Since (RFC 7232, 6)
+fn is_not_modified(req: &HttpRequest, etag: &EntityTag, modified: Option<SystemTime>) -> bool {
+    if req.headers().contains_key(header::IF_NONE_MATCH) {
+        return match IfNoneMatch::parse(req) {
+            Ok(IfNoneMatch::Any) => true,
+            Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+            Err(_) => false,
+        };
+    }
+
+    let since = match IfModifiedSince::parse(req) {
+        Ok(IfModifiedSince(since)) => SystemTime::from(since),
+        Err(_) => return false,
+    };
+    // HTTP dates have whole seconds
+    let secs = |time: SystemTime| time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    modified.map(|modified| secs(modified) <= secs(since)).unwrap_or(false)
+}
+
+// `etag` is the content checksum when known; otherwise a weak tag is made
+// from the size and mtime
+async fn serve_file(
+    req: &HttpRequest,
+    config: &Config,
+    path: &Path,
+    mime_type: &str,
+    download_name: &str,
+    etag: Option<EntityTag>,
+) -> HttpResponse {
+    let file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return HttpResponse::NotFound().finish();
+        }
+        Err(err) => {
+            tracing::error!("Serve error: {}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let (size, modified) = match file.metadata().await {
+        Ok(metadata) => (metadata.len(), metadata.modified().ok()),
+        Err(err) => {
+            tracing::error!("Serve error: {}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let etag = etag.unwrap_or_else(|| {
+        let mtime = modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        EntityTag::new_weak(format!("{:x}-{:x}", size, mtime))
+    });
+
+    if is_not_modified(req, &etag, modified) {
+        let mut response = HttpResponse::NotModified();
+        response.insert_header(ETag(etag));
+        if let Some(modified) = modified {
+            response.insert_header(LastModified(modified.into()));
+        }
+        return response.finish();
+    }
+
+    let policy = serve::serve_policy(config, mime_type);
+
+    let mut response = HttpResponse::Ok();
+    response.content_type(policy.content_type.as_deref().unwrap_or(mime_type));
+    response.insert_header(ETag(etag));
+    if let Some(modified) = modified {
+        response.insert_header(LastModified(modified.into()));
+    }
+    if policy.attachment {
+        response.insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", download_name),
+        ));
+    }
+    if let Some(csp) = policy.content_security_policy {
+        response.insert_header((header::CONTENT_SECURITY_POLICY, csp));
+    }
+
+    // Sealed originals are sent decrypted
+    match crate::encryption::plain_stream(file, size).await {
+        Ok((size, body)) => response.body(SizedStream::new(size, body)),
+        Err(err) => {
+            tracing::error!("Serve error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Whether the caller is an admin of the upload's tenant, or an operator.
+// Not anyone while the API is open: moderation and `publish_at` would hide
+// nothing.
+fn admin_covers(req: &HttpRequest, config: &Config, image_metadata: &ImageMetadata) -> bool {
+    match auth::authenticate(config, req.headers()) {
+        Some(Principal::Anonymous) | None => false,
+        Some(principal) => principal
+            .admin_scope()
+            .map(|scope| scope.covers(image_metadata.tenant.as_deref()))
+            .unwrap_or(false),
+    }
+}
+
+// Admins see every upload, owners their own scheduled ones
+fn can_preview(req: &HttpRequest, config: &Config, image_metadata: &ImageMetadata) -> bool {
+    if admin_covers(req, config, image_metadata) {
+        return true;
+    }
+    if !image_metadata.is_available() {
+        return false;
+    }
+
+    match auth::authenticate(config, req.headers()) {
+        Some(Principal::Key(api_key)) => image_metadata.owner.as_deref() == Some(api_key.name.as_str()),
+        Some(Principal::Token(claims)) if claims.has(Scope::ImageRead) => {
+            image_metadata.owner.as_deref() == Some(claims.sub.as_str())
+        }
+        _ => false,
+    }
+}
+
+// Whether the caller uploaded it, or administers its tenant
+fn owns_upload(req: &HttpRequest, config: &Config, image_metadata: &ImageMetadata) -> bool {
+    if admin_covers(req, config, image_metadata) {
+        return true;
+    }
+
+    match auth::authenticate(config, req.headers()) {
+        // The API is open, uploads are nobody's
+        Some(Principal::Anonymous) => true,
+        Some(principal) => {
+            let owner = principal.ownership().owner;
+            owner.is_some() && owner == image_metadata.owner
+        }
+        None => false,
+    }
+}
+
+// With `require_signed_downloads` an upload and everything derived from it
+// need the `?expires=&sig=` of `signing::sign_download_url`. A signature
+// that is present is always checked, even when not required.
+fn check_download_signature(req: &HttpRequest, config: &Config, id: &str) -> Result<(), SignatureError> {
+    let (expires, sig) = match web::Query::<DownloadQuery>::from_query(req.query_string()) {
+        Ok(query) => (query.expires.unwrap_or(0), query.sig.clone()),
+        Err(_) => (0, Some(String::new())),
+    };
+    if !config.require_signed_downloads && sig.is_none() {
+        return Ok(());
+    }
+
+    signing::check_signed_download(&config.signing_key, id, expires, sig.as_deref().unwrap_or("")).map_err(|err| {
+        tracing::warn!("Signed download of {} refused: {}", id, err);
+        err
+    })
+}
+
+// `visible_metadata` behind the download signature, for the routes about
+// one upload
+async fn live_metadata(req: &HttpRequest, config: &Config, id: &str) -> Result<Option<ImageMetadata>, HttpResponse> {
+    check_download_signature(req, config, id).map_err(|err| ApiError::from(&err).error_response())?;
+    visible_metadata(req, config, id).await
+}
+
+// Expired and deleted uploads are gone for everyone, quarantined ones
+// forbidden; pending, processing and not yet published ones are there only
+// for those who may preview them
+async fn visible_metadata(req: &HttpRequest, config: &Config, id: &str) -> Result<Option<ImageMetadata>, HttpResponse> {
+    match metadata::load(&config.uploads_dir, id).await {
+        Ok(Some(image_metadata)) if image_metadata.is_expired() || image_metadata.is_deleted() => {
+            Err(HttpResponse::NotFound().finish())
+        }
+        Ok(Some(image_metadata))
+            if image_metadata.status == UploadState::Quarantined && !admin_covers(req, config, &image_metadata) =>
+        {
+            Err(HttpResponse::Forbidden().finish())
+        }
+        Ok(Some(image_metadata)) if !image_metadata.is_public() && !can_preview(req, config, &image_metadata) => {
+            Err(HttpResponse::NotFound().finish())
+        }
+        Ok(image_metadata) => Ok(image_metadata),
+        Err(err) => {
+            tracing::error!("Metadata error: {}", err);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+fn set_cache_headers(response: &mut HttpResponse, image_metadata: Option<&ImageMetadata>) {
+    if let Some(expires_at) = image_metadata.and_then(ImageMetadata::expires_at_time) {
+        if let Ok(value) = header::HeaderValue::from_str(&header::HttpDate::from(expires_at).to_string()) {
+            response.headers_mut().insert(header::EXPIRES, value);
+        }
+    }
+    // A preview must not end up in a shared cache
+    if image_metadata.map(|image_metadata| !image_metadata.is_public()).unwrap_or(false) {
+        response
+            .headers_mut()
+            .insert(header::CACHE_CONTROL, header::HeaderValue::from_static("private, no-store"));
+    }
+}
+
+// For an original in cold storage: 202 until it is back, 404 if it isn't
+// archived after all
+fn archived_response(config: &Config, image_metadata: Option<&ImageMetadata>) -> HttpResponse {
+    let image_metadata = match image_metadata.filter(|image_metadata| image_metadata.archived_at.is_some()) {
+        Some(image_metadata) => image_metadata,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    if tiering::restore(&config.uploads_dir, image_metadata) {
+        tracing::info!("Restoring {} from cold storage", image_metadata.id);
+    }
+    HttpResponse::Accepted()
+        .insert_header((header::RETRY_AFTER, config.tiering.retry_after_secs.to_string()))
+        .json(serde_json::json!({ "id": image_metadata.id, "status": "restoring" }))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DownloadQuery {
+    expires: Option<u64>,
+    sig: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/images/{id}",
+    tag = "images",
+    params(("id" = String, Path, description = "Upload id"), DownloadQuery),
+    responses(
+        (status = 200, description = "The original, with its own Content-Type"),
+        (status = 202, description = "Archived, being restored; retry after Retry-After"),
+        (status = 304, description = "Not modified"),
+        (status = 403, description = "A bad or expired download signature"),
+        (status = 404, description = "No such upload"),
+    ),
+)]
+async fn get_image(req: HttpRequest, id: web::Path<String>, config: web::Data<Config>) -> HttpResponse {
+    let image_metadata = match live_metadata(&req, &config, &id).await {
+        Ok(image_metadata) => image_metadata,
+        Err(response) => return response,
+    };
+
+    match crate::find_upload(&config.uploads_dir, &id).await {
+        Some((path, extension)) => {
+            let mime_type = crate::extension_to_mime_type(extension).unwrap_or("application/octet-stream");
+            // Originals never change, the checksum is a strong validator
+            let etag = image_metadata
+                .as_ref()
+                .and_then(|image_metadata| image_metadata.checksum.clone())
+                .map(EntityTag::new_strong);
+            let download_name = format!("{}.{}", id, extension);
+            let mut response = serve_file(&req, &config, &path, mime_type, &download_name, etag).await;
+            set_cache_headers(&mut response, image_metadata.as_ref());
+            if let Some(image_metadata) = &image_metadata {
+                if let Err(err) = tiering::touch(&config.uploads_dir, image_metadata).await {
+                    tracing::warn!("Error recording a download of {}: {}", image_metadata.id, err);
+                }
+            }
+            response
+        }
+        None => archived_response(&config, image_metadata.as_ref()),
+    }
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    limit: Option<usize>,
+    cursor: Option<String>,
+    mime: Option<String>,
+    uploaded_after: Option<u64>,
+    // `pending` and `quarantined` list the moderation queue
+    status: Option<UploadState>,
+}
+
+const MAX_LIST_LIMIT: usize = 1000;
+
+async fn list_images(req: HttpRequest, query: web::Query<ListQuery>, config: web::Data<Config>) -> HttpResponse {
+    let scope = match auth::upload_scope(&config, req.headers()) {
+        Some(scope) => scope,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+
+    let extension = match &query.mime {
+        Some(mime_type) => match crate::mime_type_to_extension(mime_type) {
+            Some(extension) => Some(extension.to_owned()),
+            // Nothing is stored with it
+            None => return HttpResponse::Ok().json(serde_json::json!({ "items": [], "next_cursor": null })),
+        },
+        None => None,
+    };
+    let filter = metadata::ListFilter {
+        extension,
+        uploaded_after: query.uploaded_after,
+        status: query.status,
+        tenant: scope.tenant().map(str::to_owned),
+    };
+    let limit = query.limit.unwrap_or(50).clamp(1, MAX_LIST_LIMIT);
+
+    match metadata::list(&config.uploads_dir, &filter, query.cursor.as_deref(), limit).await {
+        Ok((page, next_cursor)) => {
+            let items: Vec<serde_json::Value> = page
+                .into_iter()
+                .map(|image_metadata| {
+                    serde_json::json!({
+                        "id": image_metadata.id,
+                        "size": image_metadata.size,
+                        "mime": crate::extension_to_mime_type(&image_metadata.extension),
+                        "width": image_metadata.width,
+                        "height": image_metadata.height,
+                        "created_at": image_metadata.created_at,
+                        "expires_at": image_metadata.expires_at,
+                        "status": image_metadata.status,
+                        "publish_at": image_metadata.publish_at,
+                        "tenant": image_metadata.tenant,
+                        "urls": {
+                            "image": format!("/images/{}", image_metadata.id),
+                            "thumbnail": format!("/images/{}/thumbnail", image_metadata.id),
+                            "metadata": format!("/images/{}/metadata", image_metadata.id),
+                        },
+                    })
+                })
+                .collect();
+
+            HttpResponse::Ok().json(serde_json::json!({ "items": items, "next_cursor": next_cursor }))
+        }
+        Err(err) => {
+            tracing::error!("Listing error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Uploads of other tenants don't exist for a tenant's key
+async fn check_scope(config: &Config, scope: &auth::UploadScope, id: &str) -> Result<(), HttpResponse> {
+    if *scope == auth::UploadScope::All {
+        return Ok(());
+    }
+    match metadata::load(&config.uploads_dir, id).await {
+        Ok(image_metadata) if scope.covers(image_metadata.as_ref().and_then(|m| m.tenant.as_deref())) => Ok(()),
+        Ok(_) => Err(HttpResponse::NotFound().finish()),
+        Err(err) => {
+            tracing::error!("Metadata error: {}", err);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+async fn approve_image(req: HttpRequest, id: web::Path<String>, config: web::Data<Config>) -> HttpResponse {
+    let scope = match auth::admin_scope(&config, req.headers()) {
+        Some(scope) => scope,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    if let Err(response) = check_scope(&config, &scope, &id).await {
+        return response;
+    }
+
+    match moderation::approve(&config.uploads_dir, &id).await {
+        Ok(true) => {
+            tracing::info!("Upload {} approved", id.as_str());
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(err) => {
+            tracing::error!("Moderation error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+async fn reject_image(req: HttpRequest, id: web::Path<String>, config: web::Data<Config>) -> HttpResponse {
+    let scope = match auth::admin_scope(&config, req.headers()) {
+        Some(scope) => scope,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    if let Err(response) = check_scope(&config, &scope, &id).await {
+        return response;
+    }
+
+    match moderation::reject(&config.uploads_dir, &id).await {
+        Ok(true) => {
+            tracing::info!("Upload {} rejected and deleted", id.as_str());
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(err) => {
+            tracing::error!("Moderation error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/images/{id}",
+    tag = "admin",
+    params(("id" = String, Path, description = "Upload id")),
+    responses(
+        (status = 200, description = "Deleted with its derivatives, which are listed"),
+        (status = 401, description = "Neither an admin nor a tenant's key"),
+        (status = 404, description = "No such upload, or another tenant's"),
+    ),
+)]
+async fn delete_image(req: HttpRequest, id: web::Path<String>, config: web::Data<Config>) -> HttpResponse {
+    let scope = match auth::upload_scope(&config, req.headers()) {
+        Some(scope) => scope,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    if !crate::is_valid_id(&id) {
+        return HttpResponse::NotFound().finish();
+    }
+    if let Err(response) = check_scope(&config, &scope, &id).await {
+        return response;
+    }
+
+    // Listed first, `delete_upload` removes them without a report
+    let invalidated = match derivatives::list(&config.uploads_dir, &id).await {
+        Ok(invalidated) => invalidated,
+        Err(err) => {
+            tracing::error!("Delete error: {}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    match crate::delete_upload(&config.uploads_dir, &id).await {
+        Ok(true) => {
+            tracing::info!("Deleted {} and {} derivative(s)", id.as_str(), invalidated.len());
+            HttpResponse::Ok().json(serde_json::json!({ "id": id.as_str(), "invalidated": invalidated }))
+        }
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(err) => {
+            tracing::error!("Delete error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Drops every derivative of an image and rebuilds the thumbnail; the rest is
+// rendered again on demand
+async fn invalidate_image(
+    req: HttpRequest,
+    id: web::Path<String>,
+    config: web::Data<Config>,
+    cluster: web::Data<Cluster>,
+) -> HttpResponse {
+    let scope = match auth::admin_scope(&config, req.headers()) {
+        Some(scope) => scope,
+        None => return HttpResponse::Unauthorized().finish(),
+    };
+    if let Err(response) = check_scope(&config, &scope, &id).await {
+        return response;
+    }
+    if crate::find_upload(&config.uploads_dir, &id).await.is_none() {
+        return HttpResponse::NotFound().finish();
+    }
+
+    let invalidated = match derivatives::invalidate(&config.uploads_dir, &id).await {
+        Ok(invalidated) => invalidated,
+        Err(err) => {
+            tracing::error!("Invalidation error: {}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let options = UploadOptions::from_config(&config);
+    let regenerated: Vec<String> = crate::ensure_thumbnail(&config.uploads_dir, &cluster, &id, &options)
+        .await
+        .map(|(_, extension)| crate::thumbnail_file_name(&id, extension))
+        .into_iter()
+        .collect();
+
+    tracing::info!("Invalidated {} derivative(s) of {}", invalidated.len(), id.as_str());
+    HttpResponse::Ok().json(serde_json::json!({
+        "id": id.as_str(),
+        "invalidated": invalidated,
+        "regenerated": regenerated,
+    }))
+}
+
+// Options of an image made from `parent` by the caller
+fn derived_options(req: &HttpRequest, config: &Config, parent: &str, operation: String) -> UploadOptions {
+    let mut options = UploadOptions::from_config(config);
+    match auth::authenticate(config, req.headers()) {
+        Some(Principal::Key(api_key)) => {
+            options.key_limits = Some(api_key.limits);
+            options.owner = Some(api_key.name);
+            options.tenant = api_key.tenant;
+        }
+        Some(Principal::Token(claims)) => options.owner = Some(claims.sub),
+        _ => {}
+    }
+    options.provenance = Some(Provenance::derived_from(parent, operation));
+    options
+}
+
+fn derived_response(res: anyhow::Result<UploadedFile>, options: &UploadOptions) -> HttpResponse {
+    match res {
+        Ok(uploaded_file) => {
+            tracing::info!("Derived {} ({:?})", uploaded_file.id, options.provenance);
+            HttpResponse::Ok().json(serde_json::json!({
+                "id": uploaded_file.id,
+                "provenance": options.provenance,
+            }))
+        }
+        Err(err) => {
+            tracing::error!("Upload error: {}", err);
+            upload_error_response(&err, Vec::new())
+        }
+    }
+}
+
+async fn copy_image(req: HttpRequest, id: web::Path<String>, config: web::Data<Config>) -> HttpResponse {
+    if !authorize_write(&req, &config) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    if let Err(response) = live_metadata(&req, &config, &id).await {
+        return response;
+    }
+
+    let (path, extension) = match crate::find_upload(&config.uploads_dir, &id).await {
+        Some(found) => found,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let res = async {
+        let file = tokio::fs::File::open(&path).await?;
+        let size = file.metadata().await?.len();
+        crate::encryption::plain_stream(file, size).await
+    }
+    .await;
+    let body = match res {
+        Ok((_, body)) => body,
+        Err(err) => {
+            tracing::error!("Copy error: {}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let options = derived_options(&req, &config, &id, "copy".to_owned());
+    let res = crate::upload_image(body, &config.uploads_dir, extension, &options).await;
+    derived_response(res, &options)
+}
+
+// Stores a transformation as a new image, unlike GET /images/{id}/transform
+async fn edit_image(
+    req: HttpRequest,
+    id: web::Path<String>,
+    spec: web::Query<TransformSpec>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if !authorize_write(&req, &config) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    if let Err(err) = spec.validate(&config.transform) {
+        return ApiError::from(&err).error_response();
+    }
+    if let Err(response) = live_metadata(&req, &config, &id).await {
+        return response;
+    }
+
+    let (data, extension) = match transform::render_upload(&config.uploads_dir, &id, &spec).await {
+        Ok(Some(rendered)) => rendered,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(err) => {
+            let error = ApiError::from(&err);
+            if error.status.is_server_error() {
+                tracing::error!("Transformation error: {}", err);
+            }
+            return error.error_response();
+        }
+    };
+
+    let options = derived_options(&req, &config, &id, format!("transform?{}", spec.canonical()));
+    let stream = stream::once(future::ready(Ok::<_, std::io::Error>(bytes::Bytes::from(data))));
+    let res = crate::upload_image(stream, &config.uploads_dir, extension, &options).await;
+    derived_response(res, &options)
+}
+
+async fn get_provenance(req: HttpRequest, id: web::Path<String>, config: web::Data<Config>) -> HttpResponse {
+    match live_metadata(&req, &config, &id).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(response) => return response,
+    }
+
+    match provenance::chain(&config.uploads_dir, &id).await {
+        Ok(chain) => HttpResponse::Ok().json(serde_json::json!({ "id": id.as_str(), "chain": chain })),
+        Err(err) => {
+            tracing::error!("Provenance error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+async fn get_image_status(
+    req: HttpRequest,
+    id: web::Path<String>,
+    config: web::Data<Config>,
+    thumbnails: web::Data<ThumbnailQueue>,
+) -> HttpResponse {
+    if let Err(response) = live_metadata(&req, &config, &id).await {
+        return response;
+    }
+    let extension = match crate::find_upload(&config.uploads_dir, &id).await {
+        Some((_, extension)) => extension,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    let queued = thumbnails.status(&id);
+    let file_name = crate::thumbnail_file_name(&id, config.thumbnail_format.extension(extension));
+    let thumbnail_path = layout::stored_file_path(&config.uploads_dir, &file_name);
+    let status = if queued == Some(ThumbnailStatus::Pending) {
+        ThumbnailStatus::Pending
+    } else if tokio::fs::metadata(&thumbnail_path).await.is_ok() {
+        ThumbnailStatus::Ready
+    } else {
+        queued.unwrap_or(ThumbnailStatus::Missing)
+    };
+
+    HttpResponse::Ok().json(serde_json::json!({ "id": id.as_str(), "thumbnail": status }))
+}
+
+// What anyone who may see an upload sees of its metadata; who uploaded it,
+// from where, how the scanners rated it and the client's own fields are for
+// its owner and admins
+#[derive(Serialize)]
+struct PublicMetadata<'a> {
+    id: &'a str,
+    extension: &'a str,
+    size: u64,
+    width: u32,
+    height: u32,
+    created_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checksum: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    original_filename: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frames: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blurhash: Option<&'a str>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    dominant_colors: &'a [String],
+}
+
+impl<'a> From<&'a ImageMetadata> for PublicMetadata<'a> {
+    fn from(image_metadata: &'a ImageMetadata) -> Self {
+        PublicMetadata {
+            id: &image_metadata.id,
+            extension: &image_metadata.extension,
+            size: image_metadata.size,
+            width: image_metadata.width,
+            height: image_metadata.height,
+            created_at: image_metadata.created_at,
+            expires_at: image_metadata.expires_at,
+            checksum: image_metadata.checksum.as_deref(),
+            original_filename: image_metadata.original_filename.as_deref(),
+            frames: image_metadata.frames,
+            duration_ms: image_metadata.duration_ms,
+            blurhash: image_metadata.blurhash.as_deref(),
+            dominant_colors: &image_metadata.dominant_colors,
+        }
+    }
+}
+
+async fn get_image_metadata(req: HttpRequest, id: web::Path<String>, config: web::Data<Config>) -> HttpResponse {
+    match live_metadata(&req, &config, &id).await {
+        Ok(Some(image_metadata)) => {
+            let full = can_preview(&req, &config, &image_metadata);
+            let mut response = if full {
+                HttpResponse::Ok().json(&image_metadata)
+            } else {
+                HttpResponse::Ok().json(PublicMetadata::from(&image_metadata))
+            };
+            set_cache_headers(&mut response, Some(&image_metadata));
+            // Not for a shared cache to hand out to anyone
+            if full {
+                response
+                    .headers_mut()
+                    .insert(header::CACHE_CONTROL, header::HeaderValue::from_static("private, no-store"));
+            }
+            response
+        }
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(response) => response,
+    }
+}
+
+// Dimensions, format, checksum, EXIF and variants of an upload, without its
+// pixels; see `crate::image_info`
+#[utoipa::path(
+    get,
+    path = "/images/{id}/info",
+    tag = "images",
+    params(("id" = String, Path, description = "Upload id"), DownloadQuery),
+    responses(
+        (status = 200, description = "What is known about the upload", body = crate::ImageInfo),
+        (status = 202, description = "Archived, being restored; retry after Retry-After"),
+        (status = 403, description = "A bad or expired download signature"),
+        (status = 404, description = "No such upload"),
+    ),
+)]
+async fn get_image_info(req: HttpRequest, id: web::Path<String>, config: web::Data<Config>) -> HttpResponse {
+    let image_metadata = match live_metadata(&req, &config, &id).await {
+        Ok(image_metadata) => image_metadata,
+        Err(response) => return response,
+    };
+
+    match crate::image_info(&config.uploads_dir, &id, image_metadata.as_ref()).await {
+        Ok(Some(info)) => {
+            let mut response = HttpResponse::Ok().json(&info);
+            set_cache_headers(&mut response, image_metadata.as_ref());
+            response
+        }
+        Ok(None) => archived_response(&config, image_metadata.as_ref()),
+        Err(err) => {
+            tracing::error!("Image info error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct FacesBody {
+    faces: Vec<crate::imagetools::Face>,
+}
+
+// Bounding boxes of the faces in an upload, in pixels of the image as
+// served; see `imagetools::detect_faces`
+#[utoipa::path(
+    get,
+    path = "/images/{id}/faces",
+    tag = "images",
+    params(("id" = String, Path, description = "Upload id"), DownloadQuery),
+    responses(
+        (status = 200, description = "The faces found, maybe none", body = FacesBody),
+        (status = 202, description = "Archived, being restored; retry after Retry-After"),
+        (status = 403, description = "A bad or expired download signature"),
+        (status = 404, description = "No such upload"),
+        (status = 501, description = "This build can't detect faces", body = crate::error::ErrorBody),
+    ),
+)]
+async fn get_faces(req: HttpRequest, id: web::Path<String>, config: web::Data<Config>) -> HttpResponse {
+    if !crate::imagetools::FACE_DETECTION {
+        let message = "this build can't detect faces";
+        return ApiError::new(StatusCode::NOT_IMPLEMENTED, "not_supported", message).error_response();
+    }
+    let image_metadata = match live_metadata(&req, &config, &id).await {
+        Ok(image_metadata) => image_metadata,
+        Err(response) => return response,
+    };
+
+    match crate::detect_faces(&config.uploads_dir, &id, &config.faces).await {
+        Ok(Some(faces)) => {
+            let mut response = HttpResponse::Ok().json(FacesBody { faces });
+            set_cache_headers(&mut response, image_metadata.as_ref());
+            response
+        }
+        Ok(None) => archived_response(&config, image_metadata.as_ref()),
+        Err(err) => {
+            tracing::error!("Face detection error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct OcrQuery {
+    // `ocr.default_language` if unset
+    lang: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct OcrBody {
+    language: String,
+    text: String,
+    // 0-100
+    confidence: f32,
+    // Read from the metadata, not recognized again
+    cached: bool,
+}
+
+// The text in an upload, see `ocr::extract`
+#[utoipa::path(
+    get,
+    path = "/images/{id}/ocr",
+    tag = "images",
+    params(
+        ("id" = String, Path, description = "Upload id"),
+        ("lang" = Option<String>, Query, description = "Tesseract language code, e.g. eng or eng+deu"),
+        DownloadQuery,
+    ),
+    responses(
+        (status = 200, description = "The text found, maybe none", body = OcrBody),
+        (status = 202, description = "Archived, being restored; retry after Retry-After"),
+        (status = 400, description = "Language not available", body = crate::error::ErrorBody),
+        (status = 403, description = "A bad or expired download signature"),
+        (status = 404, description = "No such upload, or OCR is off"),
+        (status = 501, description = "This build has no OCR", body = crate::error::ErrorBody),
+    ),
+)]
+async fn get_ocr(
+    req: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<OcrQuery>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if !ocr::AVAILABLE {
+        let message = "this build can't recognize text";
+        return ApiError::new(StatusCode::NOT_IMPLEMENTED, "not_supported", message).error_response();
+    }
+    if !config.ocr.enabled {
+        return HttpResponse::NotFound().finish();
+    }
+    let language = query.lang.clone().unwrap_or_else(|| config.ocr.default_language.clone());
+    if let Err(err) = config.ocr.check_language(&language) {
+        return ApiError::from(&err).error_response();
+    }
+    let image_metadata = match live_metadata(&req, &config, &id).await {
+        Ok(image_metadata) => image_metadata,
+        Err(response) => return response,
+    };
+
+    match ocr::extract(&config.uploads_dir, &id, &language, &config.ocr).await {
+        Ok(Some((result, cached))) => {
+            let mut response = HttpResponse::Ok().json(OcrBody {
+                language,
+                text: result.text,
+                confidence: result.confidence,
+                cached,
+            });
+            set_cache_headers(&mut response, image_metadata.as_ref());
+            response
+        }
+        Ok(None) => archived_response(&config, image_metadata.as_ref()),
+        Err(err) => {
+            let error = ApiError::from(&err);
+            if error.status.is_server_error() {
+                tracing::error!("OCR error: {}", err);
+            }
+            error.error_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SimilarQuery {
+    // Bits of the perceptual hash that may differ, up to `similar_max_distance`
+    max_distance: Option<u32>,
+    limit: Option<usize>,
+}
+
+const MAX_SIMILAR_LIMIT: usize = 100;
+
+// Visually similar uploads, the closest first; only those the caller may see
+async fn get_similar_images(
+    req: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<SimilarQuery>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    let image_metadata = match live_metadata(&req, &config, &id).await {
+        Ok(image_metadata) => image_metadata,
+        Err(response) => return response,
+    };
+
+    let max_distance = query.max_distance.unwrap_or(config.similar_max_distance);
+    if max_distance > config.similar_max_distance {
+        let error = format!("max_distance is limited to {}", config.similar_max_distance);
+        return ApiError::bad_request("invalid_query", error).error_response();
+    }
+    let limit = query.limit.unwrap_or(20).clamp(1, MAX_SIMILAR_LIMIT);
+
+    let hash = match crate::perceptual_hash_of(&config.uploads_dir, &id, image_metadata.as_ref()).await {
+        Ok(Some(hash)) => hash,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(err) => {
+            tracing::error!("Perceptual hash error: {}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    match metadata::find_similar(&config.uploads_dir, hash, max_distance, Some(&id)).await {
+        Ok(similar) => {
+            let items: Vec<serde_json::Value> = similar
+                .into_iter()
+                .filter(|(image_metadata, _)| image_metadata.is_public() || can_preview(&req, &config, image_metadata))
+                .take(limit)
+                .map(|(image_metadata, distance)| {
+                    serde_json::json!({
+                        "id": image_metadata.id,
+                        "distance": distance,
+                        "mime": crate::extension_to_mime_type(&image_metadata.extension),
+                        "width": image_metadata.width,
+                        "height": image_metadata.height,
+                        "urls": {
+                            "image": format!("/images/{}", image_metadata.id),
+                            "thumbnail": format!("/images/{}/thumbnail", image_metadata.id),
+                            "metadata": format!("/images/{}/metadata", image_metadata.id),
+                        },
+                    })
+                })
+                .collect();
+
+            HttpResponse::Ok().json(serde_json::json!({
+                "phash": metadata::format_perceptual_hash(hash),
+                "items": items,
+            }))
+        }
+        Err(err) => {
+            tracing::error!("Similarity search error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Relays the owner node's response as is
+// The owner authorizes and revalidates the request itself
+const FORWARDED_REQUEST_HEADERS: [header::HeaderName; 5] = [
+    header::AUTHORIZATION,
+    header::IF_NONE_MATCH,
+    header::IF_MODIFIED_SINCE,
+    header::IF_MATCH,
+    header::IF_UNMODIFIED_SINCE,
+];
+const FORWARDED_RESPONSE_HEADERS: [header::HeaderName; 9] = [
+    header::CONTENT_TYPE,
+    header::CONTENT_LENGTH,
+    header::CONTENT_DISPOSITION,
+    header::CONTENT_SECURITY_POLICY,
+    header::ETAG,
+    header::LAST_MODIFIED,
+    header::CACHE_CONTROL,
+    header::EXPIRES,
+    header::VARY,
+];
+
+async fn forward_to_owner(cluster: &Cluster, owner: &str, req: &HttpRequest) -> Option<HttpResponse> {
+    let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let method = reqwest::Method::from_bytes(req.method().as_str().as_bytes()).ok()?;
+    let mut headers = reqwest::header::HeaderMap::new();
+    for name in &FORWARDED_REQUEST_HEADERS {
+        for value in req.headers().get_all(name) {
+            if let (Ok(name), Ok(value)) = (
+                reqwest::header::HeaderName::from_bytes(name.as_str().as_bytes()),
+                reqwest::header::HeaderValue::from_bytes(value.as_bytes()),
+            ) {
+                headers.append(name, value);
+            }
+        }
+    }
+
+    match cluster.forward(owner, method, path, headers).await {
+        Ok(response) => {
+            let status = StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+            let mut builder = HttpResponse::build(status);
+            for name in &FORWARDED_RESPONSE_HEADERS {
+                for value in response.headers().get_all(name.as_str()) {
+                    builder.append_header((name.clone(), value.as_bytes()));
+                }
+            }
+
+            let stream = response
+                .bytes_stream()
+                .map(|chunk| chunk.map_err(actix_web::error::ErrorBadGateway));
+            Some(builder.streaming(stream))
+        }
+        Err(err) => {
+            tracing::warn!("Forwarding to {} failed, serving locally: {}", owner, err);
+            None
+        }
+    }
+}
+
+async fn get_thumbnail(
+    req: HttpRequest,
+    id: web::Path<String>,
+    config: web::Data<Config>,
+    cluster: web::Data<Cluster>,
+) -> HttpResponse {
+    if !req.headers().contains_key(cluster::FORWARDED_HEADER) {
+        if let Some(owner) = cluster.owner(&id) {
+            if let Some(response) = forward_to_owner(&cluster, &owner, &req).await {
+                return response;
+            }
+        }
+    }
+
+    let image_metadata = match live_metadata(&req, &config, &id).await {
+        Ok(image_metadata) => image_metadata,
+        Err(response) => return response,
+    };
+
+    match crate::ensure_thumbnail(&config.uploads_dir, &cluster, &id, &UploadOptions::from_config(&config)).await {
+        Some((path, extension)) => {
+            let file_name = crate::thumbnail_file_name(&id, extension);
+            let mime_type = crate::extension_to_mime_type(extension).unwrap_or("application/octet-stream");
+            let mut response = serve_file(&req, &config, &path, mime_type, &file_name, None).await;
+            set_cache_headers(&mut response, image_metadata.as_ref());
+            response
+        }
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[derive(Deserialize)]
+struct CollectionRequest {
+    name: Option<String>,
+    images: Vec<String>,
+}
+
+// Members are checked when shown, so an image may be added before it is
+// published or be deleted later
+async fn create_collection(
+    req: HttpRequest,
+    body: web::Json<CollectionRequest>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if !authorize_write(&req, &config) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let CollectionRequest { name, images } = body.into_inner();
+    let max_images = config.collections.max_images;
+    if max_images != 0 && images.len() > max_images {
+        let error = format!("at most {} images per collection", max_images);
+        return ApiError::bad_request("too_many_images", error).error_response();
+    }
+    if let Some(id) = images.iter().find(|id| !crate::is_valid_id(id)) {
+        let error = format!("invalid image id {:?}", id);
+        return ApiError::bad_request("invalid_id", error).error_response();
+    }
+
+    let owner = auth::authenticate(&config, req.headers()).and_then(|principal| principal.uploader().map(str::to_owned));
+    match collections::create(&config.uploads_dir, name, images, owner).await {
+        Ok(collection) => HttpResponse::Created()
+            .insert_header((header::LOCATION, format!("/collections/{}", collection.id)))
+            .json(collection),
+        Err(err) => {
+            tracing::error!("Collection error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+async fn load_collection(config: &Config, id: &str) -> Result<Collection, HttpResponse> {
+    match collections::load(&config.uploads_dir, id).await {
+        Ok(Some(collection)) => Ok(collection),
+        Ok(None) => Err(HttpResponse::NotFound().finish()),
+        Err(err) => {
+            tracing::error!("Collection error: {}", err);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+async fn get_collection(id: web::Path<String>, config: web::Data<Config>) -> HttpResponse {
+    match load_collection(&config, &id).await {
+        Ok(collection) => HttpResponse::Ok().json(collection),
+        Err(response) => response,
+    }
+}
+
+// By the key that made it or an admin; the images stay
+async fn delete_collection(req: HttpRequest, id: web::Path<String>, config: web::Data<Config>) -> HttpResponse {
+    let collection = match load_collection(&config, &id).await {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+    let is_owner = match auth::authenticate(&config, req.headers()) {
+        Some(principal) => principal.uploader().is_some() && collection.owner.as_deref() == principal.uploader(),
+        None => false,
+    };
+    if !is_owner && !auth::is_admin(&config, req.headers()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    match collections::remove(&config.uploads_dir, &id).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(err) => {
+            tracing::error!("Collection error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ContactSheetQuery {
+    // From 1
+    page: Option<usize>,
+    per_page: Option<usize>,
+    columns: Option<u32>,
+}
+
+const MAX_SHEET_COLUMNS: u32 = 20;
+
+// One JPEG grid of the member thumbnails the caller may see, a page at a
+// time; X-Total-Count is how many there are over all pages
+async fn get_contact_sheet(
+    req: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<ContactSheetQuery>,
+    config: web::Data<Config>,
+    cluster: web::Data<Cluster>,
+) -> HttpResponse {
+    let collection = match load_collection(&config, &id).await {
+        Ok(collection) => collection,
+        Err(response) => return response,
+    };
+
+    // With whether each is a preview
+    let mut visible = Vec::new();
+    for image_id in &collection.images {
+        match visible_metadata(&req, &config, image_id).await {
+            Ok(image_metadata) => {
+                let preview = image_metadata.map(|image_metadata| !image_metadata.is_public()).unwrap_or(false);
+                visible.push((image_id, preview));
+            }
+            Err(response) if response.status().is_client_error() => {}
+            Err(response) => return response,
+        }
+    }
+
+    let sheet_config = &config.collections;
+    let per_page = query
+        .per_page
+        .unwrap_or(sheet_config.sheet_per_page)
+        .clamp(1, sheet_config.sheet_max_per_page.max(1));
+    let page = query.page.unwrap_or(1).max(1);
+    let members = &visible[((page - 1) * per_page).min(visible.len())..];
+    let members = &members[..per_page.min(members.len())];
+    if members.is_empty() && page > 1 {
+        return HttpResponse::NotFound().finish();
+    }
+    let private = members.iter().any(|(_, preview)| *preview);
+
+    let options = UploadOptions::from_config(&config);
+    let mut tiles = Vec::with_capacity(members.len());
+    for (image_id, _) in members {
+        // Missing or archived originals, or no thumbnail for the type
+        if let Some((path, _)) = crate::ensure_thumbnail(&config.uploads_dir, &cluster, image_id, &options).await {
+            tiles.push((image_id.to_string(), path));
+        }
+    }
+
+    let spec = collections::SheetSpec {
+        columns: query.columns.unwrap_or(sheet_config.sheet_columns).clamp(1, MAX_SHEET_COLUMNS),
+        tile_size: sheet_config.sheet_tile_size,
+        quality: sheet_config.sheet_quality,
+    };
+    let path = match collections::contact_sheet(&config.uploads_dir, &collection.id, &spec, tiles).await {
+        Ok(path) => path,
+        Err(err) => {
+            tracing::error!("Contact sheet error: {}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let file_name = format!("{}_sheet_{}.jpg", collection.id, page);
+    let mut response = serve_file(&req, &config, &path, "image/jpeg", &file_name, None).await;
+    if let Ok(value) = header::HeaderValue::from_str(&visible.len().to_string()) {
+        response.headers_mut().insert(header::HeaderName::from_static("x-total-count"), value);
+    }
+    if private {
+        response
+            .headers_mut()
+            .insert(header::CACHE_CONTROL, header::HeaderValue::from_static("private, no-store"));
+    }
+    response
+}
+
+// Internal, lets peers copy derivatives instead of regenerating them
+#[derive(Deserialize)]
+struct TransformSignature {
+    sig: Option<String>,
+}
+
+async fn get_transformed(
+    req: HttpRequest,
+    id: web::Path<String>,
+    spec: web::Query<TransformSpec>,
+    signature: web::Query<TransformSignature>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if !config.transform.enabled {
+        return HttpResponse::NotFound().finish();
+    }
+    if let Err(err) = spec.validate(&config.transform) {
+        return ApiError::from(&err).error_response();
+    }
+    // Arbitrary sizes are a cheap way to burn CPU and disk, so only specs
+    // signed by the application are rendered. Such a signature also stands
+    // in for the download one, `sig` can't be both.
+    if config.transform.require_signature {
+        let sig = signature.sig.as_deref().unwrap_or("");
+        if let Err(err) = signing::check_transform(&config.signing_key, &id, &spec, sig) {
+            tracing::warn!("Transformation of {} refused: {}", id.as_str(), err);
+            return ApiError::from(&err).error_response();
+        }
+    } else if let Err(err) = check_download_signature(&req, &config, &id) {
+        return ApiError::from(&err).error_response();
+    }
+
+    let image_metadata = match visible_metadata(&req, &config, &id).await {
+        Ok(image_metadata) => image_metadata,
+        Err(response) => return response,
+    };
+
+    let options = UploadOptions::from_config(&config);
+    match transform::ensure_transformed(&config.uploads_dir, &id, &spec, &options).await {
+        Ok(Some((path, extension))) => {
+            let file_name = format!("{}.{}", id, extension);
+            let mime_type = crate::extension_to_mime_type(extension).unwrap_or("application/octet-stream");
+            let mut response = serve_file(&req, &config, &path, mime_type, &file_name, None).await;
+            set_cache_headers(&mut response, image_metadata.as_ref());
+            response
+        }
+        Ok(None) => archived_response(&config, image_metadata.as_ref()),
+        Err(err) => {
+            let error = ApiError::from(&err);
+            if error.status.is_server_error() {
+                tracing::error!("Transformation error: {}", err);
+            }
+            error.error_response()
+        }
+    }
+}
+
+// For `Cluster::fill_from_peers`: the derivatives of uploads anyone may see
+async fn get_cluster_file(
+    req: HttpRequest,
+    name: web::Path<String>,
+    config: web::Data<Config>,
+    cluster: web::Data<Cluster>,
+) -> HttpResponse {
+    let token = req
+        .headers()
+        .get(cluster::TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok());
+    if !cluster.is_authorized(token) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    let id = match crate::parse_derivative_file_name(&name) {
+        Some(id) => id,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    // Asked for without credentials, so only public uploads pass; the
+    // cluster token stands in for a download signature
+    if let Err(response) = visible_metadata(&req, &config, id).await {
+        return response;
+    }
+
+    let path = layout::stored_file_path(&config.uploads_dir, &name);
+    let mime_type = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .and_then(crate::extension_to_mime_type)
+        .unwrap_or("application/octet-stream");
+    serve_file(&req, &config, &path, mime_type, &name, None).await
+}
+
+#[derive(Deserialize)]
+struct PresignQuery {
+    ttl: Option<u64>,
+}
+
+// Issues a one-time upload URL that needs no credentials
+async fn presign_upload(
+    req: HttpRequest,
+    query: web::Query<PresignQuery>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if config.signing_key.is_empty() {
+        return HttpResponse::NotFound().finish();
+    }
+    if !authorize_write(&req, &config) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let ttl = query.ttl.unwrap_or(config.presign_max_ttl_secs).min(config.presign_max_ttl_secs);
+    let expires = signing::now() + ttl;
+    let nonce = crate::gen_rand_id(24);
+    // Stored as the presigner's, with their key's limits and tenant
+    let ownership = auth::authenticate(&config, req.headers())
+        .map(|principal| principal.ownership())
+        .unwrap_or_default();
+    let (owner, tenant) = (ownership.owner.as_deref(), ownership.tenant.as_deref());
+    let signature = signing::sign(&config.signing_key, &signing::upload_message(expires, &nonce, owner, tenant));
+
+    // Only for its query string
+    let mut url = reqwest::Url::parse("http://localhost/upload").expect("a valid URL");
+    {
+        let mut pairs = url.query_pairs_mut();
+        pairs.append_pair("expires", &expires.to_string()).append_pair("nonce", &nonce);
+        if let Some(owner) = owner {
+            pairs.append_pair("owner", owner);
+        }
+        if let Some(tenant) = tenant {
+            pairs.append_pair("tenant", tenant);
+        }
+        pairs.append_pair("signature", &signature);
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "url": format!("{}?{}", url.path(), url.query().unwrap_or("")),
+        "expires": expires,
+    }))
+}
+
+#[derive(Deserialize)]
+struct SignDownloadQuery {
+    ttl: Option<u64>,
+}
+
+async fn sign_download(
+    req: HttpRequest,
+    id: web::Path<String>,
+    query: web::Query<SignDownloadQuery>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if config.signing_key.is_empty() {
+        let message = "Download signing is not configured";
+        return ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "signing_not_configured", message).error_response();
+    }
+    if !authorize_write(&req, &config) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    // Only for the uploads the caller could fetch without one, and owns
+    let owned = match visible_metadata(&req, &config, &id).await {
+        Ok(Some(image_metadata)) => owns_upload(&req, &config, &image_metadata),
+        Ok(None) => auth::upload_scope(&config, req.headers()) == Some(auth::UploadScope::All),
+        Err(response) => return response,
+    };
+    if !owned || crate::find_upload(&config.uploads_dir, &id).await.is_none() {
+        return HttpResponse::NotFound().finish();
+    }
+
+    let ttl = query.ttl.unwrap_or(config.max_download_url_ttl_secs);
+    match signing::sign_download_url(&config, &id, ttl) {
+        Some(url) => HttpResponse::Ok().json(serde_json::json!({ "url": url })),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+async fn create_guest_bucket(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    guests: web::Data<GuestBuckets>,
+) -> HttpResponse {
+    if !config.guest.enabled {
+        return HttpResponse::NotFound().finish();
+    }
+
+    let client = req
+        .peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default();
+    match guests.create(&client).await {
+        Ok(bucket) => HttpResponse::Created().json(bucket),
+        Err(err) => {
+            let error = ApiError::from(&err);
+            if error.status.is_server_error() {
+                tracing::error!("Guest bucket error: {}", err);
+            }
+            error.error_response()
+        }
+    }
+}
+
+async fn capabilities(config: web::Data<Config>) -> HttpResponse {
+    HttpResponse::Ok().json(crate::capabilities::capabilities(&config))
+}
+
+async fn readyz(config: web::Data<Config>) -> HttpResponse {
+    let readiness = crate::health::readiness(&config).await;
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        tracing::warn!("Not ready: {:?}", readiness.checks);
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+// For load balancers: 429 while busy, 503 while overloaded, so traffic is
+// drained before requests start failing
+async fn lb_health(
+    config: web::Data<Config>,
+    thumbnails: web::Data<ThumbnailQueue>,
+    upload_slots: web::Data<AdaptiveLimit>,
+) -> HttpResponse {
+    let load = crate::health::load(&config, &thumbnails, &upload_slots);
+    match load.level {
+        LoadLevel::Ok => HttpResponse::Ok().json(load),
+        LoadLevel::Busy => HttpResponse::TooManyRequests().insert_header(("Retry-After", "1")).json(load),
+        LoadLevel::Overloaded => {
+            tracing::warn!("Overloaded: {:?}", load.checks);
+            HttpResponse::ServiceUnavailable().insert_header(("Retry-After", "5")).json(load)
+        }
+    }
+}
+
+fn replication_authorized(req: &HttpRequest, replication: &Replication) -> bool {
+    let token = req
+        .headers()
+        .get(replication::TOKEN_HEADER)
+        .and_then(|value| value.to_str().ok());
+    replication.is_authorized(token)
+}
+
+async fn replication_info(req: HttpRequest, replication: web::Data<Replication>) -> HttpResponse {
+    if !replication_authorized(&req, &replication) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    HttpResponse::Ok().json(replication.info())
+}
+
+#[derive(Deserialize)]
+struct ManifestQuery {
+    #[serde(default)]
+    since: u64,
+}
+
+async fn replication_manifest(
+    req: HttpRequest,
+    query: web::Query<ManifestQuery>,
+    config: web::Data<Config>,
+    replication: web::Data<Replication>,
+) -> HttpResponse {
+    if !replication_authorized(&req, &replication) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    match replication::manifest(&config.uploads_dir, query.since).await {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(err) => {
+            tracing::error!("Replication manifest error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+async fn replication_file(
+    req: HttpRequest,
+    name: web::Path<String>,
+    config: web::Data<Config>,
+    replication: web::Data<Replication>,
+) -> HttpResponse {
+    if !replication_authorized(&req, &replication) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    if !crate::is_stored_file_name(&name) {
+        return HttpResponse::NotFound().finish();
+    }
+
+    let path = layout::stored_file_path(&config.uploads_dir, &name);
+    serve_file(&req, &config, &path, "application/octet-stream", &name, None).await
+}
+
+async fn replication_promote(
+    req: HttpRequest,
+    replication: web::Data<Replication>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if !replication_authorized(&req, &replication) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    if replication.promote() {
+        // Replicated metadata went around `metadata::save`
+        if let Err(err) = crate::quota::load(&config.uploads_dir).await {
+            tracing::error!("Quota recount error: {}", err);
+        }
+        HttpResponse::Ok().json(replication.info())
+    } else {
+        HttpResponse::Conflict().json(replication.info())
+    }
+}
+
+fn clock_info() -> serde_json::Value {
+    serde_json::json!({
+        "now": crate::clock::unix_now(),
+        "offset_secs": crate::clock::offset().as_secs(),
+    })
+}
+
+// Stored bytes against the quotas, see `quota`
+async fn get_quota(req: HttpRequest, config: web::Data<Config>) -> HttpResponse {
+    if !auth::is_admin(&config, req.headers()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    HttpResponse::Ok().json(crate::quota::report(&config.quota))
+}
+
+// A key's uploads against its limits, by key name, see `auth::KeyLimits`
+async fn get_key_usage(req: HttpRequest, name: web::Path<String>, config: web::Data<Config>) -> HttpResponse {
+    if !auth::is_admin(&config, req.headers()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    match config.api_keys.iter().find(|api_key| api_key.name == *name) {
+        Some(api_key) => HttpResponse::Ok().json(crate::quota::key_report(&api_key.name, &api_key.limits)),
+        None => ApiError::new(StatusCode::NOT_FOUND, "unknown_key", format!("No API key named {:?}", name.as_str()))
+            .error_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    since: Option<u64>,
+    until: Option<u64>,
+    actor: Option<String>,
+    method: Option<String>,
+    path: Option<String>,
+    limit: Option<usize>,
+}
+
+// The audit log, the latest first, see `audit`
+async fn get_audit(req: HttpRequest, query: web::Query<AuditQuery>, config: web::Data<Config>) -> HttpResponse {
+    if !auth::is_admin(&config, req.headers()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let AuditQuery {
+        since,
+        until,
+        actor,
+        method,
+        path,
+        limit,
+    } = query.into_inner();
+    let filter = AuditFilter {
+        since,
+        until,
+        actor,
+        method,
+        path,
+    };
+    let limit = limit.unwrap_or(100).clamp(1, MAX_LIST_LIMIT);
+    match audit::query(&config.uploads_dir, &filter, limit).await {
+        Ok(events) => HttpResponse::Ok().json(serde_json::json!({ "events": events })),
+        Err(err) => {
+            tracing::error!("Audit log error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Entries of the operator file and the ones added here, see `blocklist`
+async fn list_blocklist(req: HttpRequest, config: web::Data<Config>) -> HttpResponse {
+    if !auth::is_admin(&config, req.headers()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    HttpResponse::Ok().json(blocklist::list())
+}
+
+#[derive(Deserialize)]
+struct BlocklistRequest {
+    kind: HashKind,
+    hash: String,
+    reason: String,
+}
+
+// Applies to uploads from now on, stored uploads are left alone
+async fn add_to_blocklist(
+    req: HttpRequest,
+    body: web::Json<BlocklistRequest>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if !auth::is_admin(&config, req.headers()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let entry = match BlockEntry::new(body.kind, &body.hash, &body.reason, EntrySource::Api) {
+        Ok(entry) => entry,
+        Err(err) => return ApiError::from(&err).error_response(),
+    };
+    match blocklist::add(&config.uploads_dir, entry.clone()).await {
+        Ok(()) => HttpResponse::Created().json(entry),
+        Err(err) => {
+            tracing::error!("Blocklist error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Only entries added over the API, the file's stay until it is edited
+async fn remove_from_blocklist(
+    req: HttpRequest,
+    path: web::Path<(HashKind, String)>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if !auth::is_admin(&config, req.headers()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let (kind, hash) = path.into_inner();
+    match blocklist::remove(&config.uploads_dir, kind, &hash).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(err) => {
+            tracing::error!("Blocklist error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Stored uploads and thumbnail failures, see `stats`
+async fn get_stats(req: HttpRequest, config: web::Data<Config>) -> HttpResponse {
+    if !auth::is_admin(&config, req.headers()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    match crate::stats::collect(&config.uploads_dir).await {
+        Ok(stats) => HttpResponse::Ok().json(stats),
+        Err(err) => {
+            tracing::error!("Stats error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Prometheus text format, see `metrics`
+async fn get_metrics(req: HttpRequest, config: web::Data<Config>) -> HttpResponse {
+    if !auth::is_admin(&config, req.headers()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render())
+}
+
+async fn get_clock(req: HttpRequest, config: web::Data<Config>) -> HttpResponse {
+    if !auth::is_admin(&config, req.headers()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    HttpResponse::Ok().json(clock_info())
+}
+
+#[derive(Deserialize)]
+struct AdvanceClockQuery {
+    secs: u64,
+}
+
+// Time travel, see `clock::advance`; expired uploads are reaped right away
+// instead of on the reaper's next tick
+async fn advance_clock(
+    req: HttpRequest,
+    query: web::Query<AdvanceClockQuery>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if !auth::is_admin(&config, req.headers()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    if !config.time_travel {
+        return ApiError::new(StatusCode::FORBIDDEN, "time_travel_off", "time_travel is off").error_response();
+    }
+
+    crate::clock::advance(Duration::from_secs(query.secs));
+    tracing::warn!("Clock moved forward by {}s, {}s in total", query.secs, crate::clock::offset().as_secs());
+
+    let reaped = match metadata::reap_expired(&config.uploads_dir).await {
+        Ok(reaped) => reaped,
+        Err(err) => {
+            tracing::error!("Expiry reaper error: {}", err);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let mut info = clock_info();
+    info["reaped"] = serde_json::json!(reaped);
+    HttpResponse::Ok().json(info)
+}
+
+// Listing, deletion, moderation, replication and cluster internals
+async fn list_tenants(req: HttpRequest, config: web::Data<Config>, tenants: web::Data<TenantStore>) -> HttpResponse {
+    if !auth::is_admin(&config, req.headers()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    match tenants.list().await {
+        Ok(names) => HttpResponse::Ok().json(names),
+        Err(err) => {
+            tracing::error!("Tenant store error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+async fn get_tenant(
+    req: HttpRequest,
+    name: web::Path<String>,
+    config: web::Data<Config>,
+    tenants: web::Data<TenantStore>,
+) -> HttpResponse {
+    // A tenant's admin key may read its own
+    match auth::admin_scope(&config, req.headers()) {
+        Some(scope) if scope.covers(Some(name.as_str())) => {}
+        _ => return HttpResponse::Unauthorized().finish(),
+    }
+
+    match tenants.get(&name).await {
+        Ok(Some(settings)) => HttpResponse::Ok().json(settings.as_ref()),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(err) => {
+            tracing::error!("Tenant store error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Replaces the tenant's overrides as a whole
+async fn put_tenant(
+    req: HttpRequest,
+    name: web::Path<String>,
+    settings: web::Json<TenantSettings>,
+    config: web::Data<Config>,
+    tenants: web::Data<TenantStore>,
+) -> HttpResponse {
+    if !auth::is_admin(&config, req.headers()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    if !tenants::is_valid_tenant(&name) {
+        return ApiError::bad_request("invalid_tenant", "invalid tenant name").error_response();
+    }
+
+    match tenants.put(&name, &settings).await {
+        Ok(()) => {
+            tracing::info!("Tenant {} settings updated", name.as_str());
+            HttpResponse::Ok().json(settings.into_inner())
+        }
+        Err(err) => {
+            tracing::error!("Tenant store error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+async fn delete_tenant(
+    req: HttpRequest,
+    name: web::Path<String>,
+    config: web::Data<Config>,
+    tenants: web::Data<TenantStore>,
+) -> HttpResponse {
+    if !auth::is_admin(&config, req.headers()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+    if !tenants::is_valid_tenant(&name) {
+        return HttpResponse::NotFound().finish();
+    }
+
+    match tenants.remove(&name).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(err) => {
+            tracing::error!("Tenant store error: {}", err);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Answers OPTIONS on a known path: the methods it takes, and the CORS
+// preflight headers for an allowed `origin`
+fn options_response(allow: &str, origin: Option<&str>, cors: &CorsConfig) -> HttpResponse {
+    let mut response = HttpResponse::NoContent();
+    response.insert_header((header::ALLOW, allow));
+    if let Some(origin) = origin {
+        response
+            .insert_header((header::ACCESS_CONTROL_ALLOW_ORIGIN, origin))
+            .insert_header((header::ACCESS_CONTROL_ALLOW_METHODS, cors.allow_methods(allow)))
+            .insert_header((header::ACCESS_CONTROL_ALLOW_HEADERS, cors.allowed_headers.join(", ")))
+            .insert_header((header::ACCESS_CONTROL_MAX_AGE, cors.max_age_secs.to_string()))
+            .insert_header((header::VARY, "Origin"));
+        if cors.allow_credentials {
+            response.insert_header((header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true"));
+        }
+    }
+    response.finish()
+}
+
+fn add_cors_headers(headers: &mut header::HeaderMap, origin: &str, cors: &CorsConfig) {
+    if let Ok(origin) = header::HeaderValue::from_str(origin) {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    }
+    headers.append(header::VARY, header::HeaderValue::from_static("Origin"));
+    if cors.allow_credentials {
+        headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, header::HeaderValue::from_static("true"));
+    }
+    if let Ok(exposed) = header::HeaderValue::from_str(&cors.exposed_headers.join(", ")) {
+        if !cors.exposed_headers.is_empty() {
+            headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, exposed);
+        }
+    }
+}
+
+// The contract of the annotated handlers, see `openapi`
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "rust_rest_api"),
+    paths(upload, get_image, get_image_info, get_faces, get_ocr, delete_image),
+    components(schemas(
+        UploadDetails,
+        crate::error::ErrorBody,
+        ThumbnailStatus,
+        crate::ImageInfo,
+        FacesBody,
+        crate::imagetools::Face,
+        OcrBody,
+        crate::VariantInfo,
+        crate::derivatives::DerivativeKind,
+        crate::imagetools::Probe,
+        crate::imagetools::ColorType,
+        crate::imagetools::ExifSummary,
+    )),
+    tags((name = "images"), (name = "admin", description = "On listeners with admin routes only")),
+)]
+struct ApiDoc;
+
+async fn get_openapi() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+async fn get_docs(config: web::Data<Config>) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_SECURITY_POLICY, config.openapi.docs_csp()))
+        .content_type("text/html; charset=utf-8")
+        .body(config.openapi.docs_page())
+}
+
+async fn get_docs_script() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/javascript")
+        .body(openapi::docs_script())
+}
+
+// Everything on a `redirect_to_https` listener; 308 keeps the method and
+// body of uploads
+pub async fn https_redirect(req: HttpRequest, port: web::Data<u16>) -> HttpResponse {
+    let path_and_query = req.uri().path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+    let url = crate::listeners::https_url(req.connection_info().host(), **port, path_and_query);
+    HttpResponse::PermanentRedirect().insert_header((header::LOCATION, url)).finish()
+}
+
+// GET routes answer HEAD too: same headers, no body. actix-web drops the
+// body of a HEAD response and keeps its Content-Length.
+fn get_or_head() -> actix_web::Route {
+    web::route().guard(guard::Any(guard::Get()).or(guard::Head()))
+}
+
+fn configure_admin(cfg: &mut web::ServiceConfig) {
+    cfg.route(cluster::PING_PATH, get_or_head().to(HttpResponse::Ok))
+        .route(&format!("{}/{{name}}", cluster::FILES_PATH), get_or_head().to(get_cluster_file))
+        .service(
+            web::scope(replication::PATH_PREFIX)
+                .route("/info", get_or_head().to(replication_info))
+                .route("/manifest", get_or_head().to(replication_manifest))
+                .route("/files/{name:.+}", get_or_head().to(replication_file))
+                .route("/promote", web::post().to(replication_promote)),
+        )
+        .route("/images", get_or_head().to(list_images))
+        .route("/images/{id}", web::delete().to(delete_image))
+        .route("/images/{id}/invalidate", web::post().to(invalidate_image))
+        .route("/images/{id}/approve", web::post().to(approve_image))
+        .route("/images/{id}/reject", web::post().to(reject_image))
+        .route("/tenants", get_or_head().to(list_tenants))
+        .route("/tenants/{name}", get_or_head().to(get_tenant))
+        .route("/tenants/{name}", web::put().to(put_tenant))
+        .route("/tenants/{name}", web::delete().to(delete_tenant))
+        .route("/metrics", get_or_head().to(get_metrics))
+        .route("/quota", get_or_head().to(get_quota))
+        .route("/admin/stats", get_or_head().to(get_stats))
+        .route("/admin/keys/{key}/usage", get_or_head().to(get_key_usage))
+        .route("/admin/audit", get_or_head().to(get_audit))
+        .route("/blocklist", get_or_head().to(list_blocklist))
+        .route("/blocklist", web::post().to(add_to_blocklist))
+        .route("/blocklist/{kind}/{hash}", web::delete().to(remove_from_blocklist))
+        .route("/clock", get_or_head().to(get_clock))
+        .route("/clock/advance", web::post().to(advance_clock));
+}
+
+// Uploads and image serving
+fn configure_public(cfg: &mut web::ServiceConfig, config: &Config, tus_store: &TusStore) {
+    cfg.route("/guest/buckets", web::post().to(create_guest_bucket))
+        .app_data(web::JsonConfig::default().limit(config.max_json_payload_size))
+        .app_data(web::FormConfig::default().limit(config.max_json_payload_size))
+        .service(
+            web::resource("/imports")
+                .app_data(web::PayloadConfig::new(config.max_manifest_size))
+                .route(web::post().to(create_import)),
+        )
+        .route("/capabilities", get_or_head().to(capabilities))
+        .route("/imports/{id}", get_or_head().to(get_import))
+        .route("/imports/{id}/report", get_or_head().to(get_import_report))
+        .route("/images/{id}", get_or_head().to(get_image))
+        .route("/images/{id}/thumbnail", get_or_head().to(get_thumbnail))
+        .route("/images/{id}/transform", get_or_head().to(get_transformed))
+        .route("/images/{id}/metadata", get_or_head().to(get_image_metadata))
+        .route("/images/{id}/info", get_or_head().to(get_image_info))
+        .route("/images/{id}/faces", get_or_head().to(get_faces))
+        .route("/images/{id}/ocr", get_or_head().to(get_ocr))
+        .route("/images/{id}/similar", get_or_head().to(get_similar_images))
+        .route("/images/{id}/status", get_or_head().to(get_image_status))
+        .route("/images/{id}/sign", web::post().to(sign_download))
+        .route("/images/{id}/copy", web::post().to(copy_image))
+        .route("/images/{id}/edit", web::post().to(edit_image))
+        .route("/images/{id}/provenance", get_or_head().to(get_provenance))
+        .route("/collections", web::post().to(create_collection))
+        .route("/collections/{id}", get_or_head().to(get_collection))
+        .route("/collections/{id}", web::delete().to(delete_collection))
+        .route("/collections/{id}/contact-sheet", get_or_head().to(get_contact_sheet))
+        .route("/upload/presign", web::post().to(presign_upload))
+        .service(
+            web::scope("/upload/tus")
+                .app_data(web::Data::new(tus_store.clone()))
+                .route("", web::method(http::Method::OPTIONS).to(tus_options))
+                .route("", web::post().to(tus_create))
+                .route("/{id}", web::head().to(tus_head))
+                .route("/{id}", web::patch().to(tus_patch)),
+        )
+        .route("/upload", web::post().to(upload))
+        .route("/upload", web::route().to(upload_method_not_allowed));
+
+    if config.openapi.enabled {
+        cfg.route(openapi::SPEC_PATH, get_or_head().to(get_openapi))
+            .route(openapi::DOCS_PATH, get_or_head().to(get_docs))
+            .route(openapi::DOCS_SCRIPT_PATH, get_or_head().to(get_docs_script));
+    }
+}
+
+// Что разделяют все воркеры: job registries, caches, queues and the
+// stores behind them. Made once by `start`, each `configure_routes` takes
+// clones.
+#[derive(Clone)]
+struct Services {
+    import_jobs: ImportJobs,
+    tus_store: TusStore,
+    cluster: Cluster,
+    replication: Replication,
+    guests: GuestBuckets,
+    nonces: NonceCache,
+    thumbnails: ThumbnailQueue,
+    type_throttle: TypeThrottle,
+    tenants: TenantStore,
+    upload_slots: AdaptiveLimit,
+    error_pages: ErrorPages,
+    fingerprints: TlsFingerprints,
+    idempotency_keys: IdempotencyKeys,
+}
+
+static SERVICES: OnceLock<Services> = OnceLock::new();
+
+fn services() -> &'static Services {
+    SERVICES.get().expect("http::start must run before the routes are configured")
+}
+
+// Once before the server is built: prepares the uploads directory, loads
+// the quota, blocklist, policy and guest buckets, and starts the background
+// tasks. Later calls do nothing.
+pub async fn start(config: &Config) -> anyhow::Result<()> {
+    if SERVICES.get().is_some() {
+        return Ok(());
+    }
+
+    if config.deterministic.enabled() {
+        tracing::warn!("Deterministic mode: generated ids and tokens are predictable, for testing only");
+        crate::deterministic::install(&config.deterministic);
+    }
+    if config.time_travel {
+        tracing::warn!("Time travel is on, admins can move the clock forward");
+    }
+
+    tokio::fs::create_dir_all(&config.uploads_dir).await?;
+    crate::imagetools::set_face_config(config.faces.clone());
+    if config.encryption.enabled {
+        let keyring = crate::encryption::Keyring::from_config(&config.encryption)
+            .map_err(|err| anyhow::anyhow!("Encryption error: {}", err))?;
+        crate::encryption::set_keyring(keyring);
+    }
+    if config.jwt.enabled() {
+        let verifier = jwt::set_verifier(jwt::JwtVerifier::new(&config.jwt));
+        match verifier.fetch_keys().await {
+            Ok(count) => tracing::info!("{} JWT signing key(s)", count),
+            Err(err) => tracing::error!("JWKS fetch error: {}", err),
+        }
+        jwt::spawn_refresher(verifier);
+    }
+
+    // Before anything looks for a file
+    let tenanted = layout::load_tenants(&config.uploads_dir)
+        .await
+        .map_err(|err| anyhow::anyhow!("Layout error: {}", err))?;
+    if tenanted > 0 {
+        tracing::info!("{} upload(s) stored under tenant prefixes", tenanted);
+    }
+    // Uploads a crash cut short, before their temp files go
+    let rolled_back = crate::journal::recover(&config.uploads_dir)
+        .await
+        .map_err(|err| anyhow::anyhow!("Journal error: {}", err))?;
+    if rolled_back > 0 {
+        tracing::warn!("Rolled back {} interrupted upload(s)", rolled_back);
+    }
+    // Left behind by a crash, nothing is uploading yet
+    let removed = crate::shutdown::remove_temp_files(&config.uploads_dir).await?;
+    if removed > 0 {
+        tracing::warn!("Removed {} orphaned temp file(s)", removed);
+    }
+    let moved = layout::migrate_flat_layout(&config.uploads_dir).await?;
+    if moved > 0 {
+        tracing::info!("Moved {} file(s) of the flat layout into shards", moved);
+    }
+    let stored = crate::quota::load(&config.uploads_dir)
+        .await
+        .map_err(|err| anyhow::anyhow!("Quota error: {}", err))?;
+    tracing::info!("{} byte(s) stored", stored);
+    let blocked = blocklist::load(&config.blocklist, &config.uploads_dir)
+        .await
+        .map_err(|err| anyhow::anyhow!("Blocklist error: {}", err))?;
+    if blocked > 0 {
+        tracing::info!("{} blocked hash(es)", blocked);
+    }
+    let rules = policy::load(&config.policy).map_err(|err| anyhow::anyhow!("Policy error: {}", err))?;
+    if rules > 0 {
+        tracing::info!("{} upload policy rule(s)", rules);
+    }
+    let scanners = moderation::install(&config.moderation).map_err(|err| anyhow::anyhow!("Moderation error: {}", err))?;
+    if scanners > 0 {
+        tracing::info!("{} moderation scanner(s)", scanners);
+    }
+
+    let cluster = Cluster::new(&config.cluster);
+    cluster.spawn_health_checks();
+
+    let replication = Replication::new(&config.replication, &config.instance_id);
+    replication.spawn_sync(config.uploads_dir.clone());
+
+    crate::cleanup::spawn_cleanup(config);
+    tiering::install(&config.tiering).map_err(|err| anyhow::anyhow!("Tiering error: {}", err))?;
+    tiering::spawn_archiver(&config.tiering, config.uploads_dir.clone());
+
+    let guests = GuestBuckets::load(&config.guest, &config.uploads_dir)
+        .await
+        .map_err(|err| anyhow::anyhow!("Guest buckets error: {}", err))?;
+    guests.spawn_reaper(config.uploads_dir.clone());
+    idempotency::spawn_reaper(&config.idempotency, config.uploads_dir.clone());
+
+    let error_pages =
+        ErrorPages::load(&config.error_pages).map_err(|err| anyhow::anyhow!("Error pages error: {}", err))?;
+    concurrency::set_processing_slots(match config.concurrency.processing_slots {
+        0 => concurrency::default_processing_slots(),
+        slots => slots,
+    });
+
+    crate::metadata::spawn_reaper(
+        config.uploads_dir.clone(),
+        Duration::from_secs(config.expiry_reap_interval_secs.max(1)),
+    );
+
+    let _ = SERVICES.set(Services {
+        import_jobs: ImportJobs::default(),
+        tus_store: TusStore::new(&config.uploads_dir),
+        cluster,
+        replication,
+        guests,
+        nonces: NonceCache::default(),
+        thumbnails: ThumbnailQueue::start(config.thumbnail_workers, config.thumbnail_queue_size),
+        type_throttle: TypeThrottle::default(),
+        tenants: TenantStore::new(&config.uploads_dir, Duration::from_secs(config.tenant_cache_ttl_secs)),
+        upload_slots: AdaptiveLimit::start(&config.concurrency),
+        error_pages,
+        fingerprints: TlsFingerprints::default(),
+        idempotency_keys: IdempotencyKeys::default(),
+    });
+    Ok(())
+}
+
+// For `HttpServer::on_connect` of HTTPS listeners, see `log_tls_fingerprints`
+pub fn tls_fingerprints() -> TlsFingerprints {
+    services().fingerprints.clone()
+}
+
+// Every route, see `configure_routes`
+pub fn configure(cfg: &mut web::ServiceConfig, config: Config) {
+    configure_routes(cfg, config, RouteSet::All)
+}
+
+// Mounts `routes` and the service's middleware in one scope at the root of
+// the app, which answers every path: an app with routes of its own
+// registers them first. `start` must have run.
+pub fn configure_routes(cfg: &mut web::ServiceConfig, config: Config, routes: RouteSet) {
+    let Services {
+        import_jobs,
+        tus_store,
+        cluster,
+        replication,
+        guests,
+        nonces,
+        thumbnails,
+        type_throttle,
+        tenants,
+        upload_slots,
+        error_pages,
+        fingerprints,
+        idempotency_keys,
+    } = services().clone();
+    let (standby, shed_slots, cors) = (replication.clone(), upload_slots.clone(), config.cors.clone());
+    let audit_config = config.clone();
+    let scope = web::scope("")
+        // Innermost, so the replacement still gets the security headers
+        .wrap_fn(move |req, srv| {
+            let is_image_route = matches!(*req.method(), http::Method::GET | http::Method::HEAD)
+                && req.path().starts_with("/images/");
+            let fut = srv.call(req);
+            if !is_image_route || error_pages.is_empty() {
+                return Box::pin(fut) as ServiceFuture;
+            }
+            let error_pages = error_pages.clone();
+            Box::pin(async move {
+                let res = fut.await?;
+                let status = res.status();
+                if !status.is_client_error() && !status.is_server_error() {
+                    return Ok(res);
+                }
+                match error_pages.render(res.request(), res.response()) {
+                    Some(response) => Ok(res.into_response(response)),
+                    None => Ok(res),
+                }
+            })
+        })
+        // Inside the security headers, so preflights get them as well
+        .wrap_fn(move |req, srv| {
+            let allow = methods::allowed(req.path(), routes);
+            let origin = req
+                .headers()
+                .get(header::ORIGIN)
+                .and_then(|origin| origin.to_str().ok())
+                .and_then(|origin| cors.allow_origin(origin));
+
+            if *req.method() == http::Method::OPTIONS {
+                let is_preflight = req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+                // A plain OPTIONS on the tus endpoint is tus discovery, `tus_options` answers it
+                if let Some(allow) = allow.as_ref().filter(|_| is_preflight || req.path() != "/upload/tus") {
+                    let response = options_response(allow, origin.as_deref().filter(|_| is_preflight), &cors);
+                    let response = req.into_response(response);
+                    return Box::pin(async move { Ok(response) }) as ServiceFuture;
+                }
+            }
+
+            let is_unrouted_method = allow
+                .as_ref()
+                .is_some_and(|allow| !allow.split(", ").any(|method| method == req.method().as_str()));
+            // A method left out of `allowed_methods` gets no CORS headers, the browser blocks it
+            let origin = origin.filter(|_| cors.allows_method(req.method().as_str()));
+            let (fut, cors) = (srv.call(req), cors.clone());
+            Box::pin(async move {
+                let mut res = fut.await?;
+                // Routes are matched with their method, so a path served for other methods 404s
+                if let Some(allow) = allow.filter(|_| is_unrouted_method && res.status() == StatusCode::NOT_FOUND) {
+                    let response = HttpResponse::MethodNotAllowed().insert_header((header::ALLOW, allow)).finish();
+                    res = res.into_response(response);
+                }
+                if let Some(origin) = origin {
+                    add_cors_headers(res.headers_mut(), &origin, &cors);
+                }
+                Ok(res)
+            })
+        })
+        .wrap(crate::security::default_headers(&config))
+        // A standby is read-only until promoted
+        .wrap_fn(move |req, srv| {
+            let is_write =
+                !matches!(*req.method(), http::Method::GET | http::Method::HEAD | http::Method::OPTIONS);
+            if is_write && standby.is_standby() && !req.path().starts_with(replication::PATH_PREFIX) {
+                let response = req.into_response(HttpResponse::ServiceUnavailable().finish());
+                return Box::pin(async move { Ok(response) }) as ServiceFuture;
+            }
+            Box::pin(srv.call(req))
+        })
+        // Sheds uploads above the adaptive limit, see `concurrency::AdaptiveLimit`
+        .wrap_fn(move |req, srv| {
+            if !is_upload_request(&req) {
+                return Box::pin(srv.call(req)) as ServiceFuture;
+            }
+            if let Some(fingerprint) = req.peer_addr().and_then(|peer| fingerprints.get(&peer)) {
+                tracing::info!("{} {} from {:?}, TLS {}", req.method(), req.path(), req.peer_addr(), fingerprint);
+            }
+            match shed_slots.try_acquire() {
+                Some(permit) => {
+                    let fut = srv.call(req);
+                    Box::pin(async move {
+                        let res = fut.await;
+                        drop(permit);
+                        res
+                    })
+                }
+                None => {
+                    tracing::warn!("Upload shed, concurrency limit {} reached", shed_slots.limit());
+                    let response = HttpResponse::ServiceUnavailable()
+                        .insert_header((header::RETRY_AFTER, "1"))
+                        .finish();
+                    let response = req.into_response(response);
+                    Box::pin(async move { Ok(response) })
+                }
+            }
+        })
+        // Outermost, so writes refused on the way in are recorded as well
+        .wrap_fn(move |req, srv| {
+            if !audit_config.audit.enabled || !audit::is_mutating(req.method()) {
+                return Box::pin(srv.call(req)) as ServiceFuture;
+            }
+            let actor = audit::actor(&audit_config, req.headers());
+            let (method, path) = (req.method().to_string(), req.path().to_owned());
+            let ip = req.peer_addr().map(|addr| addr.ip().to_string());
+            let uploads_dir = audit_config.uploads_dir.clone();
+            let fut = srv.call(req);
+            Box::pin(async move {
+                let res = fut.await;
+                let (route, status) = match &res {
+                    Ok(res) => (res.request().match_pattern(), res.status()),
+                    Err(err) => (None, err.as_response_error().status_code()),
+                };
+                let event = AuditEvent {
+                    at: crate::clock::unix_now(),
+                    actor,
+                    method,
+                    path,
+                    route,
+                    ip,
+                    status: status.as_u16(),
+                };
+                if let Err(err) = audit::record(&uploads_dir, &event).await {
+                    tracing::error!("Audit log error: {}", err);
+                }
+                res
+            })
+        })
+        // Around the rest, so everything logged for a request carries its id
+        .wrap_fn(|req, srv| {
+            let request_id = telemetry::request_id(req.headers());
+            let span = tracing::info_span!(
+                "request",
+                request_id = request_id.as_str(),
+                method = %req.method(),
+                path = req.path()
+            );
+            let fut = span.in_scope(|| srv.call(req));
+            Box::pin(
+                async move {
+                    let mut res = fut.await?;
+                    if let Ok(value) = header::HeaderValue::from_str(&request_id) {
+                        res.headers_mut()
+                            .insert(header::HeaderName::from_static(telemetry::REQUEST_ID_HEADER), value);
+                    }
+                    Ok(res)
+                }
+                .instrument(span),
+            )
+        })
+        .app_data(web::Data::new(config.clone()))
+        .app_data(web::Data::new(import_jobs))
+        .app_data(web::Data::new(cluster))
+        .app_data(web::Data::new(replication))
+        .app_data(web::Data::new(guests))
+        .app_data(web::Data::new(nonces))
+        .app_data(web::Data::new(thumbnails))
+        .app_data(web::Data::new(type_throttle))
+        .app_data(web::Data::new(tenants))
+        .app_data(web::Data::new(upload_slots))
+        .app_data(web::Data::new(idempotency_keys))
+        .route("/healthz", get_or_head().to(HttpResponse::Ok))
+        .route("/readyz", get_or_head().to(readyz))
+        .route("/lb-health", get_or_head().to(lb_health))
+        .configure(|cfg| {
+            if routes.has_admin() {
+                configure_admin(cfg);
+            }
+            if routes.has_public() {
+                configure_public(cfg, &config, &tus_store);
+            }
+        });
+    cfg.service(scope);
+}
@@ -0,0 +1,230 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::clock;
+use crate::metadata::METADATA_DIR;
+
+// Under `metadata::METADATA_DIR`, the upload listings skip it
+pub const IDEMPOTENCY_DIR: &str = "idempotency";
+
+// Ключи идемпотентности загрузок: a POST /upload retried with the same
+// `Idempotency-Key` gets the response the first request got instead of
+// storing the files again. Only successful responses are kept, a request
+// that failed may be retried as a new one. Keys are per uploader, and a
+// key sent with a different request is refused.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct IdempotencyConfig {
+    // How long a response is replayed, 0 ignores the header
+    pub window_secs: u64,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        IdempotencyConfig { window_secs: 24 * 3600 }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IdempotencyError {
+    #[error("Idempotency-Key must be 1 to {} visible ASCII characters", MAX_KEY_LEN)]
+    InvalidKey,
+    #[error("A request with this Idempotency-Key is still in progress")]
+    InProgress,
+    #[error("Idempotency-Key was already used for a different request")]
+    Reused,
+}
+
+const MAX_KEY_LEN: usize = 255;
+
+// A response as the first request got it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredResponse {
+    // Of the request it answered, see `fingerprint`
+    pub fingerprint: String,
+    pub created_at: u64,
+    pub status: u16,
+    pub content_type: Option<String>,
+    // `X-Content-SHA256` of a single upload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    pub body: String,
+}
+
+// Of what a retry must repeat: the query and the headers describing the
+// body. The body is streamed to disk before anything could be compared, so
+// its type, length and declared digest stand in for it.
+pub fn fingerprint(query: &str, headers: &[Option<&str>]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(query.as_bytes());
+    for header in headers {
+        hasher.update(b"\n");
+        hasher.update(header.unwrap_or("").as_bytes());
+    }
+    hex::encode(hasher.finalize())
+}
+
+fn dir<P: AsRef<Path>>(uploads_dir: P) -> PathBuf {
+    uploads_dir.as_ref().join(METADATA_DIR).join(IDEMPOTENCY_DIR)
+}
+
+// Hashed, keys are client strings and `scope` may be a guest token
+fn record_name(scope: &str, key: &str) -> String {
+    hex::encode(Sha256::digest(format!("{}\n{}", scope, key).as_bytes()))
+}
+
+fn path<P: AsRef<Path>>(uploads_dir: P, name: &str) -> PathBuf {
+    dir(uploads_dir).join(format!("{}.json", name))
+}
+
+pub enum Begin {
+    // Answered before, within the window
+    Replay(StoredResponse),
+    // Nobody else has the key until the guard is dropped
+    Fresh(KeyGuard),
+}
+
+// Keys whose requests are in progress in this process
+#[derive(Clone, Default)]
+pub struct IdempotencyKeys {
+    in_flight: Arc<Mutex<HashSet<String>>>,
+}
+
+impl IdempotencyKeys {
+    // `scope` is who is uploading: the API key name, guest token or ""
+    pub async fn begin<P: AsRef<Path>>(
+        &self,
+        uploads_dir: P,
+        config: &IdempotencyConfig,
+        scope: &str,
+        key: &str,
+        fingerprint: &str,
+    ) -> Result<Begin> {
+        if key.is_empty() || key.len() > MAX_KEY_LEN || !key.bytes().all(|byte| byte.is_ascii_graphic()) {
+            return Err(IdempotencyError::InvalidKey.into());
+        }
+        let name = record_name(scope, key);
+        if !self.in_flight.lock().unwrap().insert(name.clone()) {
+            return Err(IdempotencyError::InProgress.into());
+        }
+        let guard = KeyGuard {
+            keys: self.clone(),
+            path: path(&uploads_dir, &name),
+            name,
+            fingerprint: fingerprint.to_owned(),
+        };
+
+        match load(&guard.path, config).await? {
+            Some(stored) if stored.fingerprint != fingerprint => Err(IdempotencyError::Reused.into()),
+            Some(stored) => Ok(Begin::Replay(stored)),
+            None => Ok(Begin::Fresh(guard)),
+        }
+    }
+}
+
+async fn load(path: &Path, config: &IdempotencyConfig) -> Result<Option<StoredResponse>> {
+    let stored: StoredResponse = match tokio::fs::read(path).await {
+        Ok(data) => serde_json::from_slice(&data)?,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    // Past the window it's a new request, the record is overwritten
+    if stored.created_at + config.window_secs <= clock::unix_now() {
+        return Ok(None);
+    }
+    Ok(Some(stored))
+}
+
+pub struct KeyGuard {
+    keys: IdempotencyKeys,
+    name: String,
+    path: PathBuf,
+    fingerprint: String,
+}
+
+impl KeyGuard {
+    // Keeps a successful response for retries
+    pub async fn finish(
+        self,
+        status: u16,
+        content_type: Option<String>,
+        sha256: Option<String>,
+        body: String,
+    ) -> Result<()> {
+        let stored = StoredResponse {
+            fingerprint: self.fingerprint.clone(),
+            created_at: clock::unix_now(),
+            status,
+            content_type,
+            sha256,
+            body,
+        };
+        tokio::fs::create_dir_all(self.path.parent().unwrap()).await?;
+        // Write-then-rename, as the metadata
+        let tmp_path = self.path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_vec(&stored)?).await?;
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+        Ok(())
+    }
+}
+
+impl Drop for KeyGuard {
+    fn drop(&mut self) {
+        self.keys.in_flight.lock().unwrap().remove(&self.name);
+    }
+}
+
+// Removes responses past the window
+pub async fn reap<P: AsRef<Path>>(uploads_dir: P, config: &IdempotencyConfig) -> Result<usize> {
+    let mut dir = match tokio::fs::read_dir(dir(uploads_dir)).await {
+        Ok(dir) => dir,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+    let mut reaped = 0;
+    while let Some(entry) = dir.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let expired = match load(&path, config).await {
+            Ok(stored) => stored.is_none(),
+            Err(err) => {
+                tracing::warn!("Removing idempotency record {:?}: {}", path, err);
+                true
+            }
+        };
+        if expired {
+            match tokio::fs::remove_file(&path).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => reaped += 1,
+            }
+        }
+    }
+    Ok(reaped)
+}
+
+pub fn spawn_reaper(config: &IdempotencyConfig, uploads_dir: PathBuf) {
+    if config.window_secs == 0 {
+        return;
+    }
+
+    let config = config.clone();
+    actix_rt::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            match reap(&uploads_dir, &config).await {
+                Ok(0) => {}
+                Ok(reaped) => tracing::info!("Removed {} expired idempotency record(s)", reaped),
+                Err(err) => tracing::error!("Idempotency reaper error: {}", err),
+            }
+        }
+    });
+}
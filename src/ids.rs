@@ -0,0 +1,75 @@
+use std::time::UNIX_EPOCH;
+
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::{clock, deterministic, gen_rand_id};
+
+// Генерация идентификаторов загрузок. Every generated id must pass
+// `is_valid_id`, i.e. be alphanumeric.
+pub trait IdGenerator: Send + Sync {
+    fn generate(&self) -> String;
+}
+
+pub struct RandomAlphanumeric(pub usize);
+
+impl IdGenerator for RandomAlphanumeric {
+    fn generate(&self) -> String {
+        gen_rand_id(self.0)
+    }
+}
+
+// 32 hex digits, without the dashes
+pub struct UuidV4;
+
+impl IdGenerator for UuidV4 {
+    fn generate(&self) -> String {
+        let mut bytes: [u8; 16] = deterministic::with_rng(|rng| rng.gen());
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        hex::encode(bytes)
+    }
+}
+
+// 26 Crockford base32 characters, sorting by creation time
+pub struct Ulid;
+
+const CROCKFORD: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+impl IdGenerator for Ulid {
+    fn generate(&self) -> String {
+        let millis = clock::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let random: u128 = deterministic::with_rng(|rng| rng.gen::<u128>()) & ((1 << 80) - 1);
+        let value = (u128::from(millis & ((1 << 48) - 1)) << 80) | random;
+
+        (0..26)
+            .map(|i| CROCKFORD[((value >> (125 - 5 * i)) & 0x1f) as usize] as char)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IdScheme {
+    // 12 random alphanumeric characters
+    Random,
+    Uuid,
+    Ulid,
+}
+
+pub fn default_id_scheme() -> IdScheme {
+    IdScheme::Random
+}
+
+impl IdScheme {
+    pub fn generator(self) -> &'static dyn IdGenerator {
+        match self {
+            IdScheme::Random => &RandomAlphanumeric(12),
+            IdScheme::Uuid => &UuidV4,
+            IdScheme::Ulid => &Ulid,
+        }
+    }
+}
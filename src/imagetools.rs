@@ -1,32 +1,874 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::Path;
 
-use opencv::core::{ Mat, CV_8UC3, Size_, Vector };
-use opencv::imgcodecs::{ imread, imwrite, IMREAD_COLOR };
-use opencv::imgproc::{ resize, INTER_AREA };
+use serde::Serialize;
+use utoipa::ToSchema;
 
-pub fn create_thumbnail<P>(src: P, dest: P, (w, h): (u16, u16)) -> opencv::Result<()>
+// Кодеки выбираются фичей сборки: `backend-opencv` (по умолчанию) или
+// `backend-image`, на чистом Rust. Both have the same functions over their
+// own `Image` and `Error`; OpenCV wins if both are enabled.
+#[cfg(feature = "backend-opencv")]
+mod opencv_backend;
+#[cfg(feature = "backend-opencv")]
+use opencv_backend as backend;
+
+#[cfg(all(feature = "backend-image", not(feature = "backend-opencv")))]
+mod image_backend;
+#[cfg(all(feature = "backend-image", not(feature = "backend-opencv")))]
+use image_backend as backend;
+
+#[cfg(not(any(feature = "backend-opencv", feature = "backend-image")))]
+compile_error!("enable one of the `backend-opencv` or `backend-image` features");
+
+pub use backend::{
+    blur_image, crop_image, encode_image, flip_image, image_size, resize_image, rgb_pixels, rotate_image, Error, Image,
+    BACKEND, FACE_DETECTION, VIDEO,
+};
+
+mod animation;
+pub use animation::Animation;
+
+mod blurhash;
+mod composite;
+pub use composite::contact_sheet;
+mod faces;
+pub use faces::{blur_faces, detect_faces, Face, FaceConfig};
+mod filters;
+pub use filters::{adjust_image, grayscale_image, sharpen_image};
+mod svg;
+pub use svg::{looks_like_svg, sanitize_svg, SvgError};
+mod smartcrop;
+pub use smartcrop::{crop_window, set_face_config, Gravity};
+mod video;
+pub use video::{Video, VideoConfig, EXTENSIONS as VIDEO_EXTENSIONS};
+mod palette;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Whether this build can write `extension`
+pub fn can_encode(extension: &str) -> bool {
+    backend::ENCODERS.contains(&extension)
+}
+
+// EXIF orientation tag, 1 = upright, 2..=8 = flipped and/or rotated
+pub fn exif_orientation<P: AsRef<Path>>(path: P) -> Option<u32> {
+    let file = File::open(path).ok()?;
+    let exif = exif::Reader::new()
+        .read_from_container(&mut BufReader::new(file))
+        .ok()?;
+
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+        .value
+        .get_uint(0)
+        .filter(|orientation| (1..=8).contains(orientation))
+}
+
+// `at_least` is the size wanted after the EXIF rotation, see `backend::read`.
+// A sealed original is decrypted for as long as it's read.
+fn read_upright(path: &Path, at_least: Option<(u32, u32)>) -> Result<Image> {
+    let plaintext = crate::encryption::plaintext(path).map_err(|err| backend::io_error(err.into()))?;
+    let path = &*plaintext;
+    let orientation = exif_orientation(path).unwrap_or(1);
+
+    // Sizes are as stored for the backend
+    let at_least = match at_least {
+        Some((w, h)) if orientation >= 5 => Some((h, w)),
+        at_least => at_least,
+    };
+    if is_svg(path) {
+        let data = std::fs::read(path).map_err(backend::io_error)?;
+        let png = svg::rasterize(&data, at_least).map_err(|err| {
+            backend::io_error(std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))
+        })?;
+        return backend::decode(&png);
+    }
+    if video_container(path).is_some() {
+        return backend::poster_frame(path);
+    }
+
+    let image = match read_first_frame(path)? {
+        Some(image) => image,
+        None => backend::read(path, at_least)?,
+    };
+
+    if orientation != 1 {
+        backend::apply_orientation(image, orientation)
+    } else {
+        Ok(image)
+    }
+}
+
+// GIF and WebP may hold several frames, only the first is decoded; `None`
+// for the other formats
+fn read_first_frame(path: &Path) -> Result<Option<Image>> {
+    let mut magic = [0; 12];
+    let n = File::open(path)
+        .and_then(|mut file| read_up_to(&mut file, &mut magic))
+        .map_err(backend::io_error)?;
+    let is_webp = n == magic.len() && magic.starts_with(b"RIFF") && &magic[8..] == b"WEBP";
+    if !magic[..n].starts_with(b"GIF8") && !is_webp {
+        return Ok(None);
+    }
+
+    let data = std::fs::read(path).map_err(backend::io_error)?;
+    match animation::first_frame(&data) {
+        Some(frame) => backend::decode(&frame).map(Some),
+        None => Ok(None),
+    }
+}
+
+// Frames of an animated GIF or WebP, `None` for a still image
+pub fn animation<P: AsRef<Path>>(path: P) -> std::io::Result<Option<Animation>> {
+    let data = std::fs::read(path)?;
+    Ok(animation::animation(&data))
+}
+
+// Extension of a clip by its first bytes, see `VideoConfig`
+pub fn video_extension(head: &[u8]) -> Option<&'static str> {
+    video::container(head)
+}
+
+fn video_container(path: &Path) -> Option<&'static str> {
+    let mut head = [0; 64];
+    let n = File::open(path).and_then(|mut file| read_up_to(&mut file, &mut head)).ok()?;
+    video::container(&head[..n])
+}
+
+// Size and length of a clip, `None` for anything else
+pub fn video<P: AsRef<Path>>(path: P) -> std::io::Result<Option<Video>> {
+    let path = path.as_ref();
+    if video_container(path).is_none() {
+        return Ok(None);
+    }
+    backend::video_info(path)
+        .map(Some)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))
+}
+
+// Vectors are rasterized before anything else reads them
+fn is_svg(path: &Path) -> bool {
+    let mut head = [0; 512];
+    File::open(path)
+        .and_then(|mut file| read_up_to(&mut file, &mut head))
+        .map(|n| looks_like_svg(&head[..n]))
+        .unwrap_or(false)
+}
+
+fn is_jpeg(path: &Path) -> bool {
+    let mut magic = [0; 2];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .map(|_| magic == [0xFF, 0xD8])
+        .unwrap_or(false)
+}
+
+// Cameras embed a ~160x120 JPEG preview in EXIF. Decoding it instead of the
+// full image is orders of magnitude cheaper, but only if it's big enough for
+// `(w, h)` and shows the same picture: some cameras letterbox the preview or
+// leave a stale one behind after editing, so the aspect ratio must match.
+fn read_embedded_thumbnail(src: &Path, (w, h): (u32, u32)) -> Option<Image> {
+    let file = File::open(src).ok()?;
+    let exif = exif::Reader::new()
+        .read_from_container(&mut BufReader::new(file))
+        .ok()?;
+
+    let offset = exif.get_field(exif::Tag::JPEGInterchangeFormat, exif::In::THUMBNAIL)?.value.get_uint(0)? as usize;
+    let len = exif.get_field(exif::Tag::JPEGInterchangeFormatLength, exif::In::THUMBNAIL)?.value.get_uint(0)? as usize;
+    let data = exif.buf().get(offset..offset.checked_add(len)?)?;
+
+    let thumbnail = backend::decode(data).ok()?;
+    let (tw, th) = image_size(&thumbnail);
+
+    // The preview is stored unrotated, same as the main image
+    let orientation = exif_orientation(src).unwrap_or(1);
+    let (need_w, need_h) = if orientation >= 5 { (h, w) } else { (w, h) };
+    if tw < need_w || th < need_h {
+        return None;
+    }
+
+    let (iw, ih) = image_dimensions(src).ok()??;
+    if iw == 0 || ih == 0 || th == 0 {
+        return None;
+    }
+    let ratio = (f64::from(tw) / f64::from(th)) / (f64::from(iw) / f64::from(ih));
+    if (ratio - 1.0).abs() > 0.02 {
+        return None;
+    }
+
+    if orientation != 1 {
+        backend::apply_orientation(thumbnail, orientation).ok()
+    } else {
+        Some(thumbnail)
+    }
+}
+
+// Encodes as `extension` and replaces `path` through a temp file
+fn write_image(path: &Path, image: &Image, extension: &str, quality: Option<u8>) -> Result<()> {
+    let data = encode_image(image, extension, quality)?;
+
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("image");
+    let tmp_path = path.with_file_name(format!("{}.write.tmp", stem));
+    std::fs::write(&tmp_path, data)
+        .and_then(|()| std::fs::rename(&tmp_path, path))
+        .map_err(backend::io_error)
+}
+
+// Rewrites the image with upright pixels if its EXIF says it's rotated.
+// `extension` selects the encoder, the file itself may be a `.tmp`.
+// Returns whether the file was changed.
+pub fn auto_orient<P: AsRef<Path>>(path: P, extension: &str) -> Result<bool> {
+    let path = path.as_ref();
+    match exif_orientation(path) {
+        Some(orientation) if orientation != 1 => {}
+        _ => return Ok(false),
+    }
+
+    let image = read_upright(path, None)?;
+    write_image(path, &image, extension, Some(95))?;
+
+    Ok(true)
+}
+
+// Decodes and encodes again as `extension`, in place; metadata is dropped.
+// The file itself may be a `.tmp`.
+pub fn reencode<P: AsRef<Path>>(path: P, extension: &str, quality: Option<u8>) -> Result<()> {
+    let path = path.as_ref();
+    write_image(path, &read_image(path)?, extension, quality)
+}
+
+// Encoded by the extension of `dest`, which may differ from the source's
+// Without `crop` the whole image is squeezed into the size
+pub fn create_thumbnail<P>(
+    src: P,
+    dest: P,
+    (w, h): (u16, u16),
+    quality: Option<u8>,
+    crop: Option<Gravity>,
+) -> Result<()>
 where
     P: AsRef<Path>,
 {
-    let src = src.as_ref().to_str().unwrap();
-    let dest = dest.as_ref().to_str().unwrap();
+    let (src, dest) = (src.as_ref(), dest.as_ref());
+    let size = (u32::from(w), u32::from(h));
+
+    let src_image = match read_embedded_thumbnail(src, size) {
+        Some(image) => {
+            tracing::debug!("Using the embedded EXIF thumbnail of {}", src.to_str().unwrap_or("?"));
+            image
+        }
+        None => read_upright(src, Some(size))?,
+    };
+    let src_image = match crop {
+        Some(gravity) => crop_image(&src_image, crop_window(&src_image, size, gravity)?)?,
+        None => src_image,
+    };
 
-    let src_image = imread(src, IMREAD_COLOR)?;
+    let dest_image = resize_image(&src_image, size)?;
 
-    let size = Size_::new(w as i32, h as i32);
+    let extension = dest.extension().and_then(|extension| extension.to_str()).unwrap_or("jpg");
+    write_image(dest, &dest_image, extension, quality)
+}
+
+// Pixels a side of a `Preview`
+const PREVIEW_SIZE: u32 = 64;
+
+// The upright image (the first frame of an animation) squeezed to a small
+// square, decoded once for the placeholders computed at upload
+pub struct Preview {
+    // Upright, before squeezing
+    pub size: (u32, u32),
+    pixels: Vec<u8>,
+}
+
+pub fn preview<P: AsRef<Path>>(path: P) -> Result<Preview> {
+    let image = read_upright(path.as_ref(), Some((PREVIEW_SIZE, PREVIEW_SIZE)))?;
+    let size = image_size(&image);
+    let image = resize_image(&image, (PREVIEW_SIZE, PREVIEW_SIZE))?;
+    Ok(Preview { size, pixels: rgb_pixels(&image)? })
+}
+
+// The upright image squeezed to `side` x `side` row-major RGB, the input of
+// a classifier, see `moderation`
+pub fn rgb_square<P: AsRef<Path>>(path: P, side: u32) -> Result<Vec<u8>> {
+    let image = read_upright(path.as_ref(), Some((side, side)))?;
+    rgb_pixels(&resize_image(&image, (side, side))?)
+}
 
-    let mut dest_image = unsafe { Mat::new_size(size, CV_8UC3)}?;
+// With 4x3 components, 3x4 for portraits. Squeezing doesn't matter, the
+// components are relative to the size.
+pub fn blurhash(preview: &Preview) -> String {
+    let (w, h) = preview.size;
+    let components = if h > w { (3, 4) } else { (4, 3) };
+    blurhash::encode(&preview.pixels, (PREVIEW_SIZE, PREVIEW_SIZE), components)
+}
+
+// dHash: each bit tells whether a cell of a 9x8 grid of the luma is darker
+// than its right neighbour. Survives resizing, re-encoding and small edits;
+// similar images are a small Hamming distance apart.
+pub fn perceptual_hash(preview: &Preview) -> u64 {
+    let luma: Vec<f64> = preview
+        .pixels
+        .chunks_exact(3)
+        .map(|pixel| 0.299 * f64::from(pixel[0]) + 0.587 * f64::from(pixel[1]) + 0.114 * f64::from(pixel[2]))
+        .collect();
+    let size = PREVIEW_SIZE as usize;
+    let cell = |cx: usize, cy: usize| {
+        let (x0, x1) = (cx * size / 9, (cx + 1) * size / 9);
+        let (y0, y1) = (cy * size / 8, (cy + 1) * size / 8);
+        let sum: f64 = (y0..y1).flat_map(|y| (x0..x1).map(move |x| y * size + x)).map(|i| luma[i]).sum();
+        sum / ((x1 - x0) * (y1 - y0)) as f64
+    };
+
+    let mut hash = 0u64;
+    for cy in 0..8 {
+        for cx in 0..8 {
+            hash = (hash << 1) | u64::from(cell(cx, cy) < cell(cx + 1, cy));
+        }
+    }
+    hash
+}
+
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+// Up to `count` colors as `#rrggbb`, the most common first
+pub fn dominant_colors(preview: &Preview, count: usize) -> Vec<String> {
+    palette::dominant_colors(&preview.pixels, count)
+        .iter()
+        .map(|[r, g, b]| format!("#{:02x}{:02x}{:02x}", r, g, b))
+        .collect()
+}
+
+// Upright decoded image, for transformations
+pub fn read_image<P: AsRef<Path>>(path: P) -> Result<Image> {
+    read_upright(path.as_ref(), None)
+}
+
+// Drops EXIF (GPS included), XMP, IPTC and comments from JPEG and PNG files
+// without re-encoding. ICC profiles are kept, they affect how pixels look.
+// Returns whether the file was changed.
+pub fn strip_metadata<P: AsRef<Path>>(path: P) -> std::io::Result<bool> {
+    let path = path.as_ref();
+    let data = std::fs::read(path)?;
+
+    let stripped = if data.starts_with(&[0xFF, 0xD8]) {
+        strip_jpeg_metadata(&data)
+    } else if data.starts_with(PNG_SIGNATURE) {
+        strip_png_metadata(&data)
+    } else {
+        None
+    };
+
+    match stripped {
+        Some(stripped) => {
+            let tmp_path = path.with_extension("strip");
+            std::fs::write(&tmp_path, stripped)?;
+            std::fs::rename(&tmp_path, path)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+fn strip_jpeg_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&data[..2]);
+
+    let mut changed = false;
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            return None;
+        }
+
+        let marker = data[pos + 1];
+        match marker {
+            // Fill byte
+            0xFF => {
+                pos += 1;
+                continue;
+            }
+            // Start of scan, entropy-coded data follows up to the end
+            0xDA => {
+                out.extend_from_slice(&data[pos..]);
+                return if changed { Some(out) } else { None };
+            }
+            // Markers without a length
+            0x01 | 0xD0..=0xD7 => {
+                out.extend_from_slice(&data[pos..pos + 2]);
+                pos += 2;
+                continue;
+            }
+            _ => {}
+        }
+
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        let end = pos + 2 + len;
+        if len < 2 || end > data.len() {
+            return None;
+        }
+
+        // APP1 (EXIF, XMP), APP13 (IPTC), COM
+        if marker == 0xE1 || marker == 0xED || marker == 0xFE {
+            changed = true;
+        } else {
+            out.extend_from_slice(&data[pos..end]);
+        }
+        pos = end;
+    }
+
+    None
+}
+
+fn strip_png_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(PNG_SIGNATURE);
+
+    let mut changed = false;
+    let mut pos = PNG_SIGNATURE.len();
+    while pos + 12 <= data.len() {
+        let len = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let end = pos.checked_add(12)?.checked_add(len)?;
+        if end > data.len() {
+            return None;
+        }
+
+        match &data[pos + 4..pos + 8] {
+            b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME" => changed = true,
+            _ => out.extend_from_slice(&data[pos..end]),
+        }
+        pos = end;
+    }
+
+    if changed { Some(out) } else { None }
+}
+
+// Width and height as claimed by the file header, read without decoding the
+// pixels. `None` if the header isn't a JPEG, PNG, BMP, GIF or
+// WebP one we understand, or the file an SVG document or a clip.
+pub fn image_dimensions<P: AsRef<Path>>(path: P) -> std::io::Result<Option<(u32, u32)>> {
+    let path = path.as_ref();
+    match read_header(&mut BufReader::new(File::open(path)?))? {
+        Some(header) => Ok(Some(header.size)),
+        None if is_svg(path) => Ok(svg::dimensions(&std::fs::read(path)?)),
+        // One the backend can't open isn't recognized either
+        None if video_container(path).is_some() => Ok(backend::video_info(path)
+            .ok()
+            .map(|video| (video.width, video.height))
+            .filter(|&(width, height)| width > 0 && height > 0)),
+        None => Ok(None),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorType {
+    Gray,
+    GrayAlpha,
+    Rgb,
+    Rgba,
+    // Palette of RGB colors; GIF and low-depth PNG and BMP
+    Indexed,
+    Cmyk,
+}
+
+// What the file header says about the pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Header {
+    format: &'static str,
+    size: (u32, u32),
+    color_type: Option<ColorType>,
+    // Per channel, per index for `Indexed`
+    bit_depth: Option<u8>,
+}
+
+// Camera details worth showing, from the primary EXIF directory
+#[derive(Debug, Clone, Default, PartialEq, Serialize, ToSchema)]
+pub struct ExifSummary {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub make: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lens: Option<String>,
+    // As the camera wrote it, `YYYY-MM-DD HH:MM:SS` without a time zone
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub taken_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exposure_time: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub f_number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iso: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub focal_length: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orientation: Option<u32>,
+    // Only whether there is a location, never the coordinates
+    pub has_location: bool,
+}
+
+// Сведения о файле без декодирования пикселей: the header and EXIF only,
+// cheap enough for large files
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct Probe {
+    // Container format, not the extension it's stored under
+    pub format: &'static str,
+    // Upright, after the EXIF orientation
+    pub width: u32,
+    pub height: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color_type: Option<ColorType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bit_depth: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exif: Option<ExifSummary>,
+}
+
+// `None` if the header isn't one `image_dimensions` understands
+pub fn probe<P: AsRef<Path>>(path: P) -> std::io::Result<Option<Probe>> {
+    let path = path.as_ref();
+    let header = match read_header(&mut BufReader::new(File::open(path)?))? {
+        Some(header) => header,
+        None if is_svg(path) => {
+            return Ok(svg::dimensions(&std::fs::read(path)?).map(|(width, height)| Probe {
+                format: "svg",
+                width,
+                height,
+                color_type: None,
+                bit_depth: None,
+                exif: None,
+            }))
+        }
+        None => match video_container(path) {
+            Some(format) => {
+                return Ok(image_dimensions(path)?.map(|(width, height)| Probe {
+                    format,
+                    width,
+                    height,
+                    color_type: None,
+                    bit_depth: None,
+                    exif: None,
+                }))
+            }
+            None => return Ok(None),
+        },
+    };
+
+    let exif = exif_summary(path);
+    let (width, height) = match exif.as_ref().and_then(|exif| exif.orientation) {
+        Some(orientation) if orientation >= 5 => (header.size.1, header.size.0),
+        _ => header.size,
+    };
+    Ok(Some(Probe {
+        format: header.format,
+        width,
+        height,
+        color_type: header.color_type,
+        bit_depth: header.bit_depth,
+        exif,
+    }))
+}
+
+fn exif_summary(path: &Path) -> Option<ExifSummary> {
+    let file = File::open(path).ok()?;
+    let exif = exif::Reader::new()
+        .read_from_container(&mut BufReader::new(file))
+        .ok()?;
+
+    let text = |tag| match &exif.get_field(tag, exif::In::PRIMARY)?.value {
+        exif::Value::Ascii(values) => {
+            let value = String::from_utf8_lossy(values.first()?);
+            let value = value.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            Some(value.to_owned()).filter(|value| !value.is_empty())
+        }
+        _ => None,
+    };
+    let display = |tag| {
+        let field = exif.get_field(tag, exif::In::PRIMARY)?;
+        Some(field.display_value().with_unit(&exif).to_string())
+    };
+
+    Some(ExifSummary {
+        make: text(exif::Tag::Make),
+        model: text(exif::Tag::Model),
+        lens: text(exif::Tag::LensModel),
+        taken_at: display(exif::Tag::DateTimeOriginal).or_else(|| display(exif::Tag::DateTime)),
+        exposure_time: display(exif::Tag::ExposureTime),
+        f_number: display(exif::Tag::FNumber),
+        iso: display(exif::Tag::PhotographicSensitivity),
+        focal_length: display(exif::Tag::FocalLength),
+        orientation: exif
+            .get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0))
+            .filter(|orientation| (1..=8).contains(orientation)),
+        has_location: exif.get_field(exif::Tag::GPSLatitude, exif::In::PRIMARY).is_some(),
+    })
+}
+
+fn read_header<R: Read + Seek>(file: &mut R) -> std::io::Result<Option<Header>> {
+    let mut head = [0; 30];
+    let n = read_up_to(file, &mut head)?;
+    let head = &head[..n];
+
+    if head.starts_with(PNG_SIGNATURE) && head.len() >= 26 && &head[12..16] == b"IHDR" {
+        let width = u32::from_be_bytes([head[16], head[17], head[18], head[19]]);
+        let height = u32::from_be_bytes([head[20], head[21], head[22], head[23]]);
+        let color_type = match head[25] {
+            0 => Some(ColorType::Gray),
+            2 => Some(ColorType::Rgb),
+            3 => Some(ColorType::Indexed),
+            4 => Some(ColorType::GrayAlpha),
+            6 => Some(ColorType::Rgba),
+            _ => None,
+        };
+        return Ok(Some(Header {
+            format: "png",
+            size: (width, height),
+            color_type,
+            bit_depth: Some(head[24]),
+        }));
+    }
+
+    if head.starts_with(b"BM") && head.len() >= 26 {
+        // BITMAPCOREHEADER has 16-bit sizes, the later headers 32-bit signed ones
+        let header_size = u32::from_le_bytes([head[14], head[15], head[16], head[17]]);
+        let (size, bits_per_pixel) = if header_size == 12 {
+            (
+                (
+                    u32::from(u16::from_le_bytes([head[18], head[19]])),
+                    u32::from(u16::from_le_bytes([head[20], head[21]])),
+                ),
+                u16::from_le_bytes([head[24], head[25]]),
+            )
+        } else {
+            let width = i32::from_le_bytes([head[18], head[19], head[20], head[21]]);
+            // Negative height means a top-down bitmap
+            let height = i32::from_le_bytes([head[22], head[23], head[24], head[25]]);
+            let bits_per_pixel = head.get(28..30).map(|bits| u16::from_le_bytes([bits[0], bits[1]]));
+            ((width.unsigned_abs(), height.unsigned_abs()), bits_per_pixel.unwrap_or(0))
+        };
+        let (color_type, bit_depth) = match bits_per_pixel {
+            1 | 4 | 8 => (Some(ColorType::Indexed), Some(bits_per_pixel as u8)),
+            24 => (Some(ColorType::Rgb), Some(8)),
+            32 => (Some(ColorType::Rgba), Some(8)),
+            // 16 bits are 5 or 6 per channel
+            16 => (Some(ColorType::Rgb), None),
+            _ => (None, None),
+        };
+        return Ok(Some(Header { format: "bmp", size, color_type, bit_depth }));
+    }
+
+    // Logical screen, every frame lies within it
+    if head.starts_with(b"GIF8") && head.len() >= 11 {
+        let width = u16::from_le_bytes([head[6], head[7]]);
+        let height = u16::from_le_bytes([head[8], head[9]]);
+        // Size of the global color table, if there is one
+        let bit_depth = if head[10] & 0x80 != 0 { (head[10] & 0x07) + 1 } else { 8 };
+        return Ok(Some(Header {
+            format: "gif",
+            size: (u32::from(width), u32::from(height)),
+            color_type: Some(ColorType::Indexed),
+            bit_depth: Some(bit_depth),
+        }));
+    }
+
+    if head.starts_with(b"RIFF") && head.len() >= 30 && &head[8..12] == b"WEBP" {
+        let u24 = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]);
+        let color_type = |has_alpha| Some(if has_alpha { ColorType::Rgba } else { ColorType::Rgb });
+        let header = |size, color_type| Header { format: "webp", size, color_type, bit_depth: Some(8) };
+        return Ok(match &head[12..16] {
+            // Extended format, the canvas size minus one
+            b"VP8X" => Some(header(
+                (u24(&head[24..27]) + 1, u24(&head[27..30]) + 1),
+                color_type(head[20] & 0x10 != 0),
+            )),
+            // Lossy, after the frame tag and start code; the top bits are scaling
+            b"VP8 " => Some(header(
+                (
+                    u32::from(u16::from_le_bytes([head[26], head[27]]) & 0x3FFF),
+                    u32::from(u16::from_le_bytes([head[28], head[29]]) & 0x3FFF),
+                ),
+                color_type(false),
+            )),
+            // Lossless, 14 bits each minus one after the signature byte, then
+            // the alpha hint
+            b"VP8L" => {
+                let bits = u32::from_le_bytes([head[21], head[22], head[23], head[24]]);
+                Some(header(
+                    ((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1),
+                    color_type((bits >> 28) & 1 != 0),
+                ))
+            }
+            _ => None,
+        });
+    }
+
+    if head.starts_with(&[0xFF, 0xD8]) {
+        file.seek(SeekFrom::Start(2))?;
+        return jpeg_header(file);
+    }
+
+    Ok(None)
+}
+
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..])? {
+            0 => break,
+            read => n += read,
+        }
+    }
+    Ok(n)
+}
+
+// Skips segments up to the first SOFn one
+fn jpeg_header<R: Read + Seek>(reader: &mut R) -> std::io::Result<Option<Header>> {
+    let mut marker = [0; 2];
+    loop {
+        if read_up_to(reader, &mut marker)? < 2 || marker[0] != 0xFF {
+            return Ok(None);
+        }
+
+        // Fill bytes
+        while marker[1] == 0xFF {
+            if read_up_to(reader, &mut marker[1..])? < 1 {
+                return Ok(None);
+            }
+        }
+
+        match marker[1] {
+            0x01 | 0xD0..=0xD7 => continue,
+            // End of image or start of scan before any frame header
+            0xD9 | 0xDA => return Ok(None),
+            _ => {}
+        }
+
+        let mut len = [0; 2];
+        if read_up_to(reader, &mut len)? < 2 {
+            return Ok(None);
+        }
+        let len = u16::from_be_bytes(len);
+        if len < 2 {
+            return Ok(None);
+        }
+
+        // SOF0..SOF15, except DHT (C4), JPG (C8) and DAC (CC)
+        let is_frame = (0xC0..=0xCF).contains(&marker[1]) && ![0xC4, 0xC8, 0xCC].contains(&marker[1]);
+        if is_frame {
+            let mut frame = [0; 6];
+            if read_up_to(reader, &mut frame)? < 6 {
+                return Ok(None);
+            }
+            let height = u16::from_be_bytes([frame[1], frame[2]]);
+            let width = u16::from_be_bytes([frame[3], frame[4]]);
+            // Components; three are YCbCr, decoded to RGB
+            let color_type = match frame[5] {
+                1 => Some(ColorType::Gray),
+                3 => Some(ColorType::Rgb),
+                4 => Some(ColorType::Cmyk),
+                _ => None,
+            };
+            return Ok(Some(Header {
+                format: "jpeg",
+                size: (u32::from(width), u32::from(height)),
+                color_type,
+                bit_depth: Some(frame[0]),
+            }));
+        }
+
+        reader.seek(SeekFrom::Current(i64::from(len) - 2))?;
+    }
+}
+
+const XMP_NAMESPACE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+// Writes an XMP packet into a JPEG (APP1 after JFIF) or PNG (iTXt after IHDR)
+// file. Returns whether the format supports it.
+pub fn embed_xmp<P: AsRef<Path>>(path: P, xmp: &str) -> std::io::Result<bool> {
+    let path = path.as_ref();
+    let data = std::fs::read(path)?;
+
+    let embedded = if data.starts_with(&[0xFF, 0xD8]) {
+        embed_jpeg_xmp(&data, xmp)
+    } else if data.starts_with(PNG_SIGNATURE) {
+        embed_png_xmp(&data, xmp)
+    } else {
+        None
+    };
+
+    match embedded {
+        Some(embedded) => {
+            let tmp_path = path.with_extension("xmp");
+            std::fs::write(&tmp_path, embedded)?;
+            std::fs::rename(&tmp_path, path)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+fn embed_jpeg_xmp(data: &[u8], xmp: &str) -> Option<Vec<u8>> {
+    let len = 2 + XMP_NAMESPACE.len() + xmp.len();
+    if len > usize::from(u16::MAX) {
+        return None;
+    }
+
+    // JFIF must stay the first segment
+    let mut insert_at = 2;
+    if data.get(2..4) == Some(&[0xFF, 0xE0]) {
+        let app0_len = u16::from_be_bytes([*data.get(4)?, *data.get(5)?]);
+        insert_at += 2 + usize::from(app0_len);
+    }
+    if insert_at > data.len() {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(data.len() + len + 2);
+    out.extend_from_slice(&data[..insert_at]);
+    out.extend_from_slice(&[0xFF, 0xE1]);
+    out.extend_from_slice(&(len as u16).to_be_bytes());
+    out.extend_from_slice(XMP_NAMESPACE);
+    out.extend_from_slice(xmp.as_bytes());
+    out.extend_from_slice(&data[insert_at..]);
+    Some(out)
+}
+
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for part in parts {
+        for byte in part.iter() {
+            crc ^= u32::from(*byte);
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            }
+        }
+    }
+    !crc
+}
 
-    resize(
-        &src_image,
-        &mut dest_image,
-        size,
-        0.0, 0.0,
-        INTER_AREA
-    )?;
+fn embed_png_xmp(data: &[u8], xmp: &str) -> Option<Vec<u8>> {
+    // Signature and the IHDR chunk, which is always 13 bytes long
+    let insert_at = PNG_SIGNATURE.len() + 8 + 13 + 4;
+    if data.get(PNG_SIGNATURE.len() + 4..PNG_SIGNATURE.len() + 8) != Some(b"IHDR") || insert_at > data.len() {
+        return None;
+    }
 
-    let params = Vector::new();
-    imwrite(dest, &dest_image, &params)?;
+    // Keyword, no compression, empty language and translated keyword
+    let mut chunk = b"XML:com.adobe.xmp\0\0\0\0\0".to_vec();
+    chunk.extend_from_slice(xmp.as_bytes());
 
-    Ok(())
+    let mut out = Vec::with_capacity(data.len() + chunk.len() + 12);
+    out.extend_from_slice(&data[..insert_at]);
+    out.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+    out.extend_from_slice(b"iTXt");
+    out.extend_from_slice(&chunk);
+    out.extend_from_slice(&crc32(&[b"iTXt", &chunk]).to_be_bytes());
+    out.extend_from_slice(&data[insert_at..]);
+    Some(out)
 }
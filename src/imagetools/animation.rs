@@ -0,0 +1,211 @@
+use std::io::Cursor;
+
+// Frames of an animated GIF or WebP, or of a clip. Originals are stored as
+// uploaded, only the first (or poster) frame is ever decoded (thumbnails,
+// transformations).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Animation {
+    pub frames: u32,
+    // Sum of the frame delays, one loop
+    pub duration_ms: u64,
+}
+
+// `None` for still images and other formats
+pub fn animation(data: &[u8]) -> Option<Animation> {
+    let animation = if data.starts_with(b"GIF8") {
+        gif_animation(data)
+    } else if is_webp(data) {
+        webp_animation(data)
+    } else {
+        None
+    };
+    animation.filter(|animation| animation.frames > 1)
+}
+
+// The first frame as a still image any backend decodes; `None` if the data
+// isn't a GIF or an animated WebP, which decode as they are
+pub fn first_frame(data: &[u8]) -> Option<Vec<u8>> {
+    if data.starts_with(b"GIF8") {
+        gif_first_frame(data)
+    } else if is_webp(data) {
+        webp_first_frame(data)
+    } else {
+        None
+    }
+}
+
+// Size of a color table from the packed flags of a GIF header or image
+// descriptor
+fn gif_color_table_size(flags: u8) -> usize {
+    if flags & 0x80 != 0 {
+        3 << ((flags & 0x07) + 1)
+    } else {
+        0
+    }
+}
+
+// Returns the position after the terminating empty sub-block
+fn gif_skip_sub_blocks(data: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = usize::from(*data.get(pos)?);
+        pos += 1 + len;
+        if len == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+// Walks the blocks without decompressing any frame. A truncated file counts
+// the frames up to the cut.
+fn gif_animation(data: &[u8]) -> Option<Animation> {
+    let mut pos = 13 + gif_color_table_size(*data.get(10)?);
+    let (mut frames, mut duration_ms) = (0, 0);
+
+    while let Some(block) = data.get(pos) {
+        let next = match block {
+            // Extension; the graphic control one holds the frame delay in 1/100 s
+            0x21 => {
+                if data.get(pos + 1) == Some(&0xF9) && data.get(pos + 2) == Some(&4) {
+                    if let (Some(low), Some(high)) = (data.get(pos + 4), data.get(pos + 5)) {
+                        duration_ms += u64::from(u16::from_le_bytes([*low, *high])) * 10;
+                    }
+                }
+                gif_skip_sub_blocks(data, pos + 2)
+            }
+            // Image descriptor, then an optional color table, the LZW code
+            // size and the image data
+            0x2C => {
+                frames += 1;
+                match data.get(pos + 9) {
+                    Some(flags) => gif_skip_sub_blocks(data, pos + 10 + gif_color_table_size(*flags) + 1),
+                    None => None,
+                }
+            }
+            // Trailer
+            _ => None,
+        };
+        pos = match next {
+            Some(next) => next,
+            None => break,
+        };
+    }
+
+    Some(Animation { frames, duration_ms })
+}
+
+// The first frame on a white canvas, as an uncompressed BMP
+fn gif_first_frame(data: &[u8]) -> Option<Vec<u8>> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(Cursor::new(data)).ok()?;
+
+    let (width, height) = (usize::from(decoder.width()), usize::from(decoder.height()));
+    let frame = decoder.read_next_frame().ok()??;
+
+    let mut canvas = vec![255; width * height * 3];
+    for y in 0..usize::from(frame.height) {
+        for x in 0..usize::from(frame.width) {
+            let (canvas_x, canvas_y) = (usize::from(frame.left) + x, usize::from(frame.top) + y);
+            if canvas_x >= width || canvas_y >= height {
+                continue;
+            }
+
+            let src = (y * usize::from(frame.width) + x) * 4;
+            let pixel = frame.buffer.get(src..src + 4)?;
+            let alpha = u32::from(pixel[3]);
+            let dest = (canvas_y * width + canvas_x) * 3;
+            for channel in 0..3 {
+                let value = (u32::from(pixel[channel]) * alpha + 255 * (255 - alpha)) / 255;
+                canvas[dest + channel] = value as u8;
+            }
+        }
+    }
+
+    Some(bmp(width, height, &canvas))
+}
+
+// 24-bit bottom-up BMP of RGB rows
+fn bmp(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    let row_size = (width * 3 + 3) & !3;
+    let image_size = row_size * height;
+
+    let mut out = Vec::with_capacity(54 + image_size);
+    out.extend_from_slice(b"BM");
+    out.extend_from_slice(&(54 + image_size as u32).to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&54u32.to_le_bytes());
+
+    // BITMAPINFOHEADER, 2835 pixels per meter is 72 DPI
+    out.extend_from_slice(&40u32.to_le_bytes());
+    out.extend_from_slice(&(width as i32).to_le_bytes());
+    out.extend_from_slice(&(height as i32).to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&24u16.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&(image_size as u32).to_le_bytes());
+    out.extend_from_slice(&2835u32.to_le_bytes());
+    out.extend_from_slice(&2835u32.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+
+    for row in rgb.chunks(width * 3).rev() {
+        let start = out.len();
+        for pixel in row.chunks(3) {
+            out.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+        }
+        out.resize(start + row_size, 0);
+    }
+
+    out
+}
+
+fn is_webp(data: &[u8]) -> bool {
+    data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP"
+}
+
+// Chunks of a RIFF payload as (FourCC, data)
+fn riff_chunks(mut data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    std::iter::from_fn(move || {
+        let header = data.get(..8)?;
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let chunk = data.get(8..8usize.checked_add(len)?)?;
+        let fourcc = &header[..4];
+
+        // Chunks are padded to an even length
+        data = data.get(8 + len + (len & 1)..).unwrap_or(&[]);
+        Some((fourcc, chunk))
+    })
+}
+
+// ANMF chunks: x, y, width and height (3 bytes each, 12 in all), then the
+// duration in ms (3 bytes) and flags, then the frame's own chunks
+fn webp_animation(data: &[u8]) -> Option<Animation> {
+    let (mut frames, mut duration_ms) = (0, 0);
+    for (fourcc, chunk) in riff_chunks(&data[12..]) {
+        if fourcc == b"ANMF" && chunk.len() >= 16 {
+            frames += 1;
+            duration_ms += u64::from(u32::from_le_bytes([chunk[12], chunk[13], chunk[14], 0]));
+        }
+    }
+
+    Some(Animation { frames, duration_ms })
+}
+
+// The bitstream of the first ANMF frame in a simple-format WebP. Alpha (an
+// ALPH chunk) would need the extended format and is dropped.
+fn webp_first_frame(data: &[u8]) -> Option<Vec<u8>> {
+    let (_, frame) = riff_chunks(&data[12..]).find(|(fourcc, chunk)| *fourcc == b"ANMF" && chunk.len() >= 16)?;
+    let (fourcc, bitstream) = riff_chunks(&frame[16..]).find(|(fourcc, _)| *fourcc == b"VP8 " || *fourcc == b"VP8L")?;
+
+    let padding = bitstream.len() & 1;
+    let riff_len = 4 + 8 + bitstream.len() + padding;
+    let mut out = Vec::with_capacity(8 + riff_len);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(riff_len as u32).to_le_bytes());
+    out.extend_from_slice(b"WEBP");
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&(bitstream.len() as u32).to_le_bytes());
+    out.extend_from_slice(bitstream);
+    out.resize(out.len() + padding, 0);
+    Some(out)
+}
@@ -0,0 +1,85 @@
+use std::f64::consts::PI;
+
+// Blurhash (https://blurha.sh): the first few cosine components of the image
+// packed into a short base83 string, clients decode it into a blurred
+// placeholder of any size
+
+const BASE83: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+fn push_base83(hash: &mut String, value: u32, digits: u32) {
+    for i in 1..=digits {
+        let digit = value / 83u32.pow(digits - i) % 83;
+        hash.push(char::from(BASE83[digit as usize]));
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f64 {
+    let value = f64::from(value) / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f64) -> u32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.003_130_8 {
+        (value * 12.92 * 255.0 + 0.5) as u32
+    } else {
+        ((1.055 * value.powf(1.0 / 2.4) - 0.055) * 255.0 + 0.5) as u32
+    }
+}
+
+fn sign_sqrt(value: f64) -> f64 {
+    value.abs().sqrt().copysign(value)
+}
+
+// `pixels` are row-major RGB, `components` 1-9 across and down
+pub fn encode(pixels: &[u8], (width, height): (u32, u32), (components_x, components_y): (u32, u32)) -> String {
+    let linear: Vec<f64> = pixels.iter().map(|value| srgb_to_linear(*value)).collect();
+    let scale = 1.0 / f64::from(width * height);
+
+    let mut factors = Vec::with_capacity((components_x * components_y) as usize);
+    for j in 0..components_y {
+        for i in 0..components_x {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0.0; 3];
+            for y in 0..height {
+                let basis_y = (PI * f64::from(j) * f64::from(y) / f64::from(height)).cos();
+                for x in 0..width {
+                    let basis = normalisation * basis_y * (PI * f64::from(i) * f64::from(x) / f64::from(width)).cos();
+                    let offset = ((y * width + x) * 3) as usize;
+                    for (sum, value) in factor.iter_mut().zip(&linear[offset..offset + 3]) {
+                        *sum += basis * value;
+                    }
+                }
+            }
+            factors.push([factor[0] * scale, factor[1] * scale, factor[2] * scale]);
+        }
+    }
+
+    let mut hash = String::new();
+    push_base83(&mut hash, (components_x - 1) + (components_y - 1) * 9, 1);
+
+    let (dc, ac) = (factors[0], &factors[1..]);
+    let max_value = if ac.is_empty() {
+        push_base83(&mut hash, 0, 1);
+        1.0
+    } else {
+        let actual_max = ac.iter().flatten().fold(0.0f64, |max, value| max.max(value.abs()));
+        let quantised = (actual_max * 166.0 - 0.5).floor().clamp(0.0, 82.0) as u32;
+        push_base83(&mut hash, quantised, 1);
+        f64::from(quantised + 1) / 166.0
+    };
+
+    let dc = (linear_to_srgb(dc[0]) << 16) + (linear_to_srgb(dc[1]) << 8) + linear_to_srgb(dc[2]);
+    push_base83(&mut hash, dc, 4);
+
+    let quantise = |value: f64| (sign_sqrt(value / max_value) * 9.0 + 9.5).floor().clamp(0.0, 18.0) as u32;
+    for factor in ac {
+        push_base83(&mut hash, quantise(factor[0]) * 19 * 19 + quantise(factor[1]) * 19 + quantise(factor[2]), 2);
+    }
+
+    hash
+}
@@ -0,0 +1,55 @@
+use std::path::PathBuf;
+
+use super::{backend, image_size, read_upright, resize_image, rgb_pixels, Image, Result};
+
+// Pixels between tiles and around the sheet
+const GAP: u32 = 4;
+const BACKGROUND: u8 = 0xee;
+
+// The largest size with the same aspect ratio that fits a `tile` square
+fn fit((w, h): (u32, u32), tile: u32) -> (u32, u32) {
+    let longest = u64::from(w.max(h));
+    let scale = |side: u32| ((u64::from(side) * u64::from(tile) / longest) as u32).max(1);
+    (scale(w), scale(h))
+}
+
+// Images laid out left to right, top to bottom, each scaled into a `tile`
+// square and centered; one that can't be read leaves its tile empty
+pub fn contact_sheet(tiles: &[PathBuf], columns: u32, tile: u32) -> Result<Image> {
+    let count = tiles.len().max(1) as u32;
+    let columns = columns.clamp(1, count);
+    let rows = count.div_ceil(columns);
+    let (w, h) = (GAP + columns * (tile + GAP), GAP + rows * (tile + GAP));
+    let mut canvas = vec![BACKGROUND; (w * h * 3) as usize];
+
+    for (i, path) in tiles.iter().enumerate() {
+        let image = match read_upright(path, Some((tile, tile))) {
+            Ok(image) => image,
+            Err(err) => {
+                tracing::warn!(
+                    "Leaving out {} from a contact sheet: {}",
+                    path.to_str().unwrap_or("?"),
+                    err
+                );
+                continue;
+            }
+        };
+        let size = image_size(&image);
+        if size.0 == 0 || size.1 == 0 {
+            continue;
+        }
+        let (tw, th) = fit(size, tile);
+        let pixels = rgb_pixels(&resize_image(&image, (tw, th))?)?;
+
+        let (column, row) = (i as u32 % columns, i as u32 / columns);
+        let x = GAP + column * (tile + GAP) + (tile - tw) / 2;
+        let y = GAP + row * (tile + GAP) + (tile - th) / 2;
+        let line = (tw * 3) as usize;
+        for (dy, src) in pixels.chunks_exact(line).enumerate() {
+            let start = (((y + dy as u32) * w + x) * 3) as usize;
+            canvas[start..start + line].copy_from_slice(src);
+        }
+    }
+
+    backend::from_rgb_pixels((w, h), canvas)
+}
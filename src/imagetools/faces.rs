@@ -0,0 +1,94 @@
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use super::{backend, image_size, read_image, read_upright, resize_image, write_image, Image, Result};
+
+// Поиск лиц: a Haar cascade over the image squeezed to `max_side`, with
+// the boxes scaled back. Only the OpenCV backend detects, see
+// `FACE_DETECTION`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FaceConfig {
+    // One of the cascades OpenCV ships, e.g. haarcascade_frontalface_alt2.xml
+    pub cascade: PathBuf,
+    pub scale_factor: f64,
+    // Overlapping hits a face needs, fewer finds more and more wrongly
+    pub min_neighbors: i32,
+    // Pixels a side of the smallest face, in the squeezed image
+    pub min_size: u32,
+    pub max_side: u32,
+}
+
+impl Default for FaceConfig {
+    fn default() -> Self {
+        FaceConfig {
+            cascade: "/usr/share/opencv4/haarcascades/haarcascade_frontalface_default.xml".into(),
+            scale_factor: 1.1,
+            min_neighbors: 5,
+            min_size: 24,
+            max_side: 1024,
+        }
+    }
+}
+
+// In pixels of the image it was found in
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+pub struct Face {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+pub(super) fn detect(image: &Image, config: &FaceConfig) -> Result<Vec<Face>> {
+    let (w, h) = image_size(image);
+    let scale = (f64::from(config.max_side) / f64::from(w.max(h).max(1))).min(1.0);
+    let squeezed;
+    let target = if scale < 1.0 {
+        let size = |side: u32| ((f64::from(side) * scale).round() as u32).max(1);
+        squeezed = resize_image(image, (size(w), size(h)))?;
+        &squeezed
+    } else {
+        image
+    };
+
+    let unscale = |value: u32| (f64::from(value) / scale).round() as u32;
+    let faces = backend::detect_faces(target, config)?
+        .into_iter()
+        .map(|(x, y, width, height)| {
+            let (x, y) = (unscale(x).min(w), unscale(y).min(h));
+            Face {
+                x,
+                y,
+                width: unscale(width).min(w - x),
+                height: unscale(height).min(h - y),
+            }
+        })
+        .filter(|face| face.width > 0 && face.height > 0)
+        .collect();
+    Ok(faces)
+}
+
+// In pixels of the upright image, as served
+pub fn detect_faces<P: AsRef<Path>>(path: P, config: &FaceConfig) -> Result<Vec<Face>> {
+    detect(&read_upright(path.as_ref(), None)?, config)
+}
+
+// Blurs every face found, in place; `extension` selects the encoder, the
+// file itself may be a `.tmp`. Returns the number of faces.
+pub fn blur_faces<P: AsRef<Path>>(path: P, extension: &str, config: &FaceConfig) -> Result<usize> {
+    let path = path.as_ref();
+    let mut image = read_image(path)?;
+    let faces = detect(&image, config)?;
+    if faces.is_empty() {
+        return Ok(0);
+    }
+
+    for face in &faces {
+        backend::blur_region(&mut image, (face.x, face.y, face.width, face.height))?;
+    }
+    write_image(path, &image, extension, Some(95))?;
+    Ok(faces.len())
+}
@@ -0,0 +1,47 @@
+use super::{backend, blur_image, image_size, rgb_pixels, Image, Result};
+
+// Sigma of the blur an unsharp mask subtracts
+const SHARPEN_SIGMA: f32 = 1.0;
+
+// Over the RGB pixels, the same on both backends
+fn map_pixels<F: Fn(&mut [u8])>(image: &Image, f: F) -> Result<Image> {
+    let mut pixels = rgb_pixels(image)?;
+    pixels.chunks_exact_mut(3).for_each(f);
+    backend::from_rgb_pixels(image_size(image), pixels)
+}
+
+fn clamp(value: f32) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
+
+// Kept RGB, so every encoder takes it
+pub fn grayscale_image(image: &Image) -> Result<Image> {
+    map_pixels(image, |pixel| {
+        let luma = (299 * u32::from(pixel[0]) + 587 * u32::from(pixel[1]) + 114 * u32::from(pixel[2])) / 1000;
+        pixel.iter_mut().for_each(|channel| *channel = luma as u8);
+    })
+}
+
+// Both -100..=100, 0 changes nothing; contrast -100 is flat gray, 100
+// doubles the distance from mid-gray
+pub fn adjust_image(image: &Image, brightness: i32, contrast: i32) -> Result<Image> {
+    let offset = brightness as f32 * 255.0 / 100.0;
+    let factor = 1.0 + contrast as f32 / 100.0;
+    map_pixels(image, |pixel| {
+        for channel in pixel.iter_mut() {
+            *channel = clamp((f32::from(*channel) - 128.0) * factor + 128.0 + offset);
+        }
+    })
+}
+
+// Unsharp mask: each pixel moves `amount` times its difference from a
+// blurred copy further away from it
+pub fn sharpen_image(image: &Image, amount: f32) -> Result<Image> {
+    let blurred = rgb_pixels(&blur_image(image, SHARPEN_SIGMA)?)?;
+    let mut pixels = rgb_pixels(image)?;
+    for (channel, blurred) in pixels.iter_mut().zip(blurred) {
+        let value = f32::from(*channel);
+        *channel = clamp(value + amount * (value - f32::from(blurred)));
+    }
+    backend::from_rgb_pixels(image_size(image), pixels)
+}
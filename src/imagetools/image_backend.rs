@@ -0,0 +1,156 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use image::codecs::jpeg::JpegDecoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat};
+
+use super::{is_jpeg, FaceConfig, Video};
+
+pub const BACKEND: &str = "image";
+
+pub type Image = DynamicImage;
+pub type Error = image::ImageError;
+
+// There's no face detector in pure Rust here
+pub const FACE_DETECTION: bool = false;
+
+// Nor a video decoder
+pub const VIDEO: bool = false;
+
+// The crate has no WebP encoder, and its AVIF one needs a `ravif` that
+// can't be resolved any more
+pub const ENCODERS: &[&str] = &["jpg", "png", "bmp"];
+
+pub(super) fn io_error(err: std::io::Error) -> Error {
+    image::ImageError::IoError(err)
+}
+
+// Three channels like OpenCV's IMREAD_COLOR, alpha is dropped
+fn color(image: DynamicImage) -> DynamicImage {
+    DynamicImage::ImageRgb8(image.to_rgb8())
+}
+
+// As stored, EXIF orientation is left to the caller
+pub(super) fn decode(data: &[u8]) -> image::ImageResult<DynamicImage> {
+    image::load_from_memory(data).map(color)
+}
+
+// As stored. With `at_least` (as stored too), a JPEG may be decoded at a
+// reduced scale that still leaves that many pixels.
+pub(super) fn read(path: &Path, at_least: Option<(u32, u32)>) -> image::ImageResult<DynamicImage> {
+    if let (Some((w, h)), true) = (at_least, is_jpeg(path)) {
+        let mut decoder = JpegDecoder::new(BufReader::new(File::open(path)?))?;
+        let clamp = |size: u32| size.min(u32::from(u16::MAX)) as u16;
+        decoder.scale(clamp(w), clamp(h))?;
+        return DynamicImage::from_decoder(decoder).map(color);
+    }
+
+    // The format is sniffed, uploads are processed under a `.tmp` name
+    image::io::Reader::open(path)?.with_guessed_format()?.decode().map(color)
+}
+
+// Turns pixels stored with the given EXIF orientation upright
+pub(super) fn apply_orientation(image: DynamicImage, orientation: u32) -> image::ImageResult<DynamicImage> {
+    let image = match orientation {
+        3 => image.rotate180(),
+        5 | 6 => image.rotate90(),
+        7 | 8 => image.rotate270(),
+        _ => image,
+    };
+
+    Ok(match orientation {
+        2 | 5 | 7 => image.fliph(),
+        4 => image.flipv(),
+        _ => image,
+    })
+}
+
+// By quarter turns clockwise
+pub fn rotate_image(image: &DynamicImage, quarter_turns: u32) -> image::ImageResult<DynamicImage> {
+    Ok(match quarter_turns % 4 {
+        0 => image.clone(),
+        1 => image.rotate90(),
+        2 => image.rotate180(),
+        _ => image.rotate270(),
+    })
+}
+
+// Mirrored left to right and/or top to bottom
+pub fn flip_image(image: &DynamicImage, horizontal: bool, vertical: bool) -> image::ImageResult<DynamicImage> {
+    Ok(match (horizontal, vertical) {
+        (false, false) => image.clone(),
+        (true, false) => image.fliph(),
+        (false, true) => image.flipv(),
+        (true, true) => image.rotate180(),
+    })
+}
+
+pub fn image_size(image: &DynamicImage) -> (u32, u32) {
+    image.dimensions()
+}
+
+// Triangle (bilinear) when shrinking, Catmull-Rom when enlarging
+pub fn resize_image(image: &DynamicImage, (w, h): (u32, u32)) -> image::ImageResult<DynamicImage> {
+    let (src_w, src_h) = image_size(image);
+    let filter = if w <= src_w && h <= src_h { FilterType::Triangle } else { FilterType::CatmullRom };
+
+    Ok(image.resize_exact(w, h, filter))
+}
+
+// Row-major RGB, 3 bytes a pixel
+pub fn rgb_pixels(image: &DynamicImage) -> image::ImageResult<Vec<u8>> {
+    Ok(image.to_rgb8().into_raw())
+}
+
+// From row-major RGB, 3 bytes a pixel
+pub(super) fn from_rgb_pixels((w, h): (u32, u32), pixels: Vec<u8>) -> image::ImageResult<DynamicImage> {
+    match image::RgbImage::from_raw(w, h, pixels) {
+        Some(image) => Ok(DynamicImage::ImageRgb8(image)),
+        None => Err(io_error(std::io::Error::new(std::io::ErrorKind::InvalidInput, "pixels don't match the size"))),
+    }
+}
+
+// The rectangle must lie within the image
+pub fn crop_image(image: &DynamicImage, (x, y, w, h): (u32, u32, u32, u32)) -> image::ImageResult<DynamicImage> {
+    Ok(image.crop_imm(x, y, w, h))
+}
+
+// `quality` only applies to JPEG, 95 by default like OpenCV
+pub fn encode_image(image: &DynamicImage, extension: &str, quality: Option<u8>) -> image::ImageResult<Vec<u8>> {
+    let mut buf = Vec::new();
+
+    let format = match ImageFormat::from_extension(extension) {
+        Some(ImageFormat::Jpeg) => ImageOutputFormat::Jpeg(quality.unwrap_or(95)),
+        Some(format) => format.into(),
+        None => ImageOutputFormat::Unsupported(extension.to_owned()),
+    };
+
+    image.write_to(&mut buf, format)?;
+    Ok(buf)
+}
+
+// Gaussian, `sigma` in pixels
+pub fn blur_image(image: &DynamicImage, sigma: f32) -> image::ImageResult<DynamicImage> {
+    Ok(image.blur(sigma))
+}
+
+pub fn detect_faces(_image: &DynamicImage, _config: &FaceConfig) -> image::ImageResult<Vec<(u32, u32, u32, u32)>> {
+    Err(io_error(std::io::Error::other("face detection needs the opencv backend")))
+}
+
+// Too blurred to tell who it was; the rectangle must lie within the image
+pub fn blur_region(image: &mut DynamicImage, (x, y, w, h): (u32, u32, u32, u32)) -> image::ImageResult<()> {
+    let blurred = image.crop_imm(x, y, w, h).blur(w.max(h) as f32 / 8.0);
+    image::imageops::replace(image, &blurred, x, y);
+    Ok(())
+}
+
+pub(super) fn video_info(_path: &Path) -> image::ImageResult<Video> {
+    Err(io_error(std::io::Error::other("video needs the opencv backend")))
+}
+
+pub(super) fn poster_frame(_path: &Path) -> image::ImageResult<DynamicImage> {
+    Err(io_error(std::io::Error::other("video needs the opencv backend")))
+}
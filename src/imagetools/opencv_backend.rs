@@ -0,0 +1,272 @@
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+
+use opencv::core::{ flip, rotate, Mat, Scalar, Size_, Vec3b, Vector, BORDER_DEFAULT, CV_8UC3 };
+use opencv::core::{ ROTATE_180, ROTATE_90_CLOCKWISE, ROTATE_90_COUNTERCLOCKWISE };
+use opencv::core::Rect_;
+use opencv::imgcodecs::{ imdecode, imencode, imread, IMREAD_COLOR, IMREAD_IGNORE_ORIENTATION };
+use opencv::imgcodecs::{ IMWRITE_JPEG_QUALITY, IMWRITE_WEBP_QUALITY };
+use opencv::imgcodecs::{ IMREAD_REDUCED_COLOR_2, IMREAD_REDUCED_COLOR_4, IMREAD_REDUCED_COLOR_8 };
+use opencv::imgproc::{ cvt_color, equalize_hist, gaussian_blur, resize, COLOR_BGR2GRAY, INTER_AREA, INTER_CUBIC };
+use opencv::objdetect::CascadeClassifier;
+use opencv::prelude::*;
+use opencv::videoio::{VideoCapture, CAP_ANY, CAP_PROP_FPS, CAP_PROP_FRAME_COUNT};
+use opencv::videoio::{CAP_PROP_FRAME_HEIGHT, CAP_PROP_FRAME_WIDTH, CAP_PROP_POS_FRAMES};
+
+use super::{image_dimensions, is_jpeg, FaceConfig, Video};
+
+pub const BACKEND: &str = "opencv";
+
+pub type Image = Mat;
+pub type Error = opencv::Error;
+
+pub const FACE_DETECTION: bool = true;
+
+// Through whatever videoio backend OpenCV was built with, FFmpeg usually
+pub const VIDEO: bool = true;
+
+// WebP needs OpenCV built with libwebp, which the distribution packages are
+pub const ENCODERS: &[&str] = &["jpg", "png", "bmp", "webp"];
+
+pub(super) fn io_error(err: std::io::Error) -> Error {
+    opencv::Error::new(opencv::core::StsError, err.to_string())
+}
+
+// As stored, OpenCV's own EXIF handling is left out so the result is the
+// same whichever OpenCV version is linked
+pub(super) fn decode(data: &[u8]) -> opencv::Result<Mat> {
+    let mut buf = Vector::<u8>::new();
+    for byte in data {
+        buf.push(*byte);
+    }
+    imdecode(&buf, IMREAD_COLOR | IMREAD_IGNORE_ORIENTATION)
+}
+
+// As stored. With `at_least` (as stored too), a JPEG may be decoded smaller:
+// libjpeg can decode straight to 1/2, 1/4 or 1/8 of the size by dropping DCT
+// coefficients, skipping most of the work. Picks the strongest reduction that
+// still leaves at least that many pixels; other formats are read in full.
+pub(super) fn read(path: &Path, at_least: Option<(u32, u32)>) -> opencv::Result<Mat> {
+    let flags = match (at_least, is_jpeg(path)) {
+        (Some(at_least), true) => reduced_read_flags(path, at_least),
+        _ => IMREAD_COLOR,
+    };
+
+    imread(path.to_str().unwrap(), flags | IMREAD_IGNORE_ORIENTATION)
+}
+
+fn reduced_read_flags(path: &Path, (w, h): (u32, u32)) -> i32 {
+    let (iw, ih) = match image_dimensions(path) {
+        Ok(Some(dimensions)) => dimensions,
+        _ => return IMREAD_COLOR,
+    };
+
+    [(8, IMREAD_REDUCED_COLOR_8), (4, IMREAD_REDUCED_COLOR_4), (2, IMREAD_REDUCED_COLOR_2)]
+        .iter()
+        .find(|(factor, _)| iw / factor >= w && ih / factor >= h)
+        .map(|(_, flags)| *flags)
+        .unwrap_or(IMREAD_COLOR)
+}
+
+// Turns pixels stored with the given EXIF orientation upright
+pub(super) fn apply_orientation(image: Mat, orientation: u32) -> opencv::Result<Mat> {
+    let image = match orientation {
+        3 => rotate_image(&image, 2)?,
+        5 | 6 => rotate_image(&image, 1)?,
+        7 | 8 => rotate_image(&image, 3)?,
+        _ => image,
+    };
+
+    match orientation {
+        2 | 5 | 7 => flip_image(&image, true, false),
+        4 => flip_image(&image, false, true),
+        _ => Ok(image),
+    }
+}
+
+// By quarter turns clockwise
+pub fn rotate_image(image: &Mat, quarter_turns: u32) -> opencv::Result<Mat> {
+    let code = match quarter_turns % 4 {
+        0 => return image.try_clone(),
+        1 => ROTATE_90_CLOCKWISE,
+        2 => ROTATE_180,
+        _ => ROTATE_90_COUNTERCLOCKWISE,
+    };
+    let mut dest = Mat::default()?;
+    rotate(image, &mut dest, code)?;
+    Ok(dest)
+}
+
+// Mirrored left to right and/or top to bottom
+pub fn flip_image(image: &Mat, horizontal: bool, vertical: bool) -> opencv::Result<Mat> {
+    let code = match (horizontal, vertical) {
+        (false, false) => return image.try_clone(),
+        (true, false) => 1,
+        (false, true) => 0,
+        (true, true) => -1,
+    };
+    let mut dest = Mat::default()?;
+    flip(image, &mut dest, code)?;
+    Ok(dest)
+}
+
+pub fn image_size(image: &Mat) -> (u32, u32) {
+    (image.cols().max(0) as u32, image.rows().max(0) as u32)
+}
+
+// INTER_AREA when shrinking, INTER_CUBIC when enlarging
+pub fn resize_image(image: &Mat, (w, h): (u32, u32)) -> opencv::Result<Mat> {
+    let (src_w, src_h) = image_size(image);
+    let interpolation = if w <= src_w && h <= src_h { INTER_AREA } else { INTER_CUBIC };
+
+    let size = Size_::new(w as i32, h as i32);
+    let mut dest_image = Mat::default()?;
+    resize(image, &mut dest_image, size, 0.0, 0.0, interpolation)?;
+    Ok(dest_image)
+}
+
+// Row-major RGB, 3 bytes a pixel
+pub fn rgb_pixels(image: &Mat) -> opencv::Result<Vec<u8>> {
+    let mut pixels = Vec::with_capacity((image.rows() * image.cols() * 3) as usize);
+    for row in 0..image.rows() {
+        for col in 0..image.cols() {
+            let [b, g, r] = image.at_2d::<Vec3b>(row, col)?.0;
+            pixels.extend_from_slice(&[r, g, b]);
+        }
+    }
+    Ok(pixels)
+}
+
+// From row-major RGB, 3 bytes a pixel
+pub(super) fn from_rgb_pixels((w, h): (u32, u32), pixels: Vec<u8>) -> opencv::Result<Mat> {
+    let mut image = Mat::new_rows_cols_with_default(h as i32, w as i32, CV_8UC3, Scalar::all(0.0))?;
+    for (i, rgb) in pixels.chunks_exact(3).enumerate() {
+        let (row, col) = ((i as u32 / w) as i32, (i as u32 % w) as i32);
+        image.at_2d_mut::<Vec3b>(row, col)?.0 = [rgb[2], rgb[1], rgb[0]];
+    }
+    Ok(image)
+}
+
+// The rectangle must lie within the image
+pub fn crop_image(image: &Mat, (x, y, w, h): (u32, u32, u32, u32)) -> opencv::Result<Mat> {
+    let roi = Mat::roi(image, Rect_::new(x as i32, y as i32, w as i32, h as i32))?;
+    // The ROI shares the pixels of `image`, copy it out
+    roi.try_clone()
+}
+
+// `quality` only applies to JPEG and WebP
+pub fn encode_image(image: &Mat, extension: &str, quality: Option<u8>) -> opencv::Result<Vec<u8>> {
+    let mut params = Vector::new();
+    let quality_param = match extension {
+        "jpg" => Some(IMWRITE_JPEG_QUALITY),
+        "webp" => Some(IMWRITE_WEBP_QUALITY),
+        _ => None,
+    };
+    if let (Some(param), Some(quality)) = (quality_param, quality) {
+        params.push(param);
+        params.push(i32::from(quality));
+    }
+
+    let mut buf = Vector::new();
+    imencode(&format!(".{}", extension), image, &mut buf, &params)?;
+    Ok(buf.to_vec())
+}
+
+// Gaussian, `sigma` in pixels
+pub fn blur_image(image: &Mat, sigma: f32) -> opencv::Result<Mat> {
+    let mut dest = Mat::default()?;
+    // The kernel size follows from sigma
+    gaussian_blur(image, &mut dest, Size_::new(0, 0), f64::from(sigma), f64::from(sigma), BORDER_DEFAULT)?;
+    Ok(dest)
+}
+
+thread_local! {
+    // Loading a cascade parses its XML, so each processing thread keeps the
+    // one it loaded last
+    static CASCADE: RefCell<Option<(PathBuf, CascadeClassifier)>> = const { RefCell::new(None) };
+}
+
+// (x, y, width, height) of each face
+pub fn detect_faces(image: &Mat, config: &FaceConfig) -> opencv::Result<Vec<(u32, u32, u32, u32)>> {
+    let mut gray = Mat::default()?;
+    cvt_color(image, &mut gray, COLOR_BGR2GRAY, 0)?;
+    let mut equalized = Mat::default()?;
+    equalize_hist(&gray, &mut equalized)?;
+
+    CASCADE.with(|cascade| {
+        let mut cascade = cascade.borrow_mut();
+        if cascade.as_ref().map(|(path, _)| *path != config.cascade).unwrap_or(true) {
+            let classifier = CascadeClassifier::new(config.cascade.to_str().unwrap_or(""))?;
+            if classifier.empty()? {
+                let message = format!("can't load the face cascade {}", config.cascade.display());
+                return Err(opencv::Error::new(opencv::core::StsError, message));
+            }
+            *cascade = Some((config.cascade.clone(), classifier));
+        }
+        let (_, classifier) = cascade.as_mut().expect("loaded above");
+
+        let mut faces = Vector::<Rect_<i32>>::new();
+        let min_size = config.min_size as i32;
+        classifier.detect_multi_scale(
+            &equalized,
+            &mut faces,
+            config.scale_factor,
+            config.min_neighbors,
+            0,
+            Size_::new(min_size, min_size),
+            Size_::new(0, 0),
+        )?;
+        Ok(faces
+            .iter()
+            .map(|face| (face.x.max(0) as u32, face.y.max(0) as u32, face.width.max(0) as u32, face.height.max(0) as u32))
+            .collect())
+    })
+}
+
+// Too blurred to tell who it was; the rectangle must lie within the image
+pub fn blur_region(image: &mut Mat, (x, y, w, h): (u32, u32, u32, u32)) -> opencv::Result<()> {
+    let rect = Rect_::new(x as i32, y as i32, w as i32, h as i32);
+    let mut blurred = Mat::default()?;
+    // An odd kernel half the size of the region
+    let side = (w.max(h) / 2) as i32 | 1;
+    gaussian_blur(&Mat::roi(image, rect)?, &mut blurred, Size_::new(side, side), 0.0, 0.0, BORDER_DEFAULT)?;
+    // The ROI shares the pixels of `image`
+    let mut region = Mat::roi(image, rect)?;
+    blurred.copy_to(&mut region)
+}
+
+fn open_video(path: &Path) -> opencv::Result<VideoCapture> {
+    let capture = VideoCapture::from_file(path.to_str().unwrap_or(""), CAP_ANY)?;
+    if !capture.is_opened()? {
+        return Err(opencv::Error::new(opencv::core::StsError, format!("can't open video {}", path.display())));
+    }
+    Ok(capture)
+}
+
+pub(super) fn video_info(path: &Path) -> opencv::Result<Video> {
+    let capture = open_video(path)?;
+    let (frames, fps) = (capture.get(CAP_PROP_FRAME_COUNT)?.max(0.0), capture.get(CAP_PROP_FPS)?);
+    Ok(Video {
+        width: capture.get(CAP_PROP_FRAME_WIDTH)?.max(0.0) as u32,
+        height: capture.get(CAP_PROP_FRAME_HEIGHT)?.max(0.0) as u32,
+        frames: frames as u32,
+        duration_ms: if fps > 0.0 { (frames * 1000.0 / fps).round() as u64 } else { 0 },
+    })
+}
+
+// A tenth of the way in but within the first second, clips often open on
+// black; the first frame if seeking fails
+pub(super) fn poster_frame(path: &Path) -> opencv::Result<Mat> {
+    let mut capture = open_video(path)?;
+    let at = (capture.get(CAP_PROP_FRAME_COUNT)? / 10.0).min(capture.get(CAP_PROP_FPS)?).floor();
+    let mut frame = Mat::default()?;
+    if at >= 1.0 && capture.set(CAP_PROP_POS_FRAMES, at)? && capture.read(&mut frame)? && !frame.empty()? {
+        return Ok(frame);
+    }
+
+    let mut capture = open_video(path)?;
+    if capture.read(&mut frame)? && !frame.empty()? {
+        return Ok(frame);
+    }
+    Err(opencv::Error::new(opencv::core::StsError, format!("no frame in video {}", path.display())))
+}
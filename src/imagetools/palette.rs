@@ -0,0 +1,87 @@
+// Доминирующие цвета: k-means over RGB pixels. The centers start at evenly
+// spaced luma quantiles rather than random picks, so an image always gets
+// the same colors.
+
+const MAX_ITERATIONS: usize = 20;
+
+fn luma(point: &[f64; 3]) -> f64 {
+    0.299 * point[0] + 0.587 * point[1] + 0.114 * point[2]
+}
+
+fn distance(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+fn nearest(centers: &[[f64; 3]], point: &[f64; 3]) -> usize {
+    centers
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| distance(a, point).partial_cmp(&distance(b, point)).unwrap())
+        .map(|(i, _)| i)
+        .unwrap_or(0)
+}
+
+// `pixels` are row-major RGB. Up to `count` colors, the most common first;
+// fewer if the image has fewer distinct ones.
+pub fn dominant_colors(pixels: &[u8], count: usize) -> Vec<[u8; 3]> {
+    let points: Vec<[f64; 3]> = pixels
+        .chunks_exact(3)
+        .map(|pixel| [f64::from(pixel[0]), f64::from(pixel[1]), f64::from(pixel[2])])
+        .collect();
+    if points.is_empty() || count == 0 {
+        return Vec::new();
+    }
+
+    let mut sorted = points.clone();
+    sorted.sort_by(|a, b| luma(a).partial_cmp(&luma(b)).unwrap());
+    let k = count.min(points.len());
+    let mut centers: Vec<[f64; 3]> = (0..k).map(|i| sorted[(2 * i + 1) * sorted.len() / (2 * k)]).collect();
+
+    let mut assignments = vec![usize::MAX; points.len()];
+    let mut sizes = vec![0usize; k];
+    for _ in 0..MAX_ITERATIONS {
+        let mut changed = false;
+        for (point, assignment) in points.iter().zip(assignments.iter_mut()) {
+            let center = nearest(&centers, point);
+            if center != *assignment {
+                *assignment = center;
+                changed = true;
+            }
+        }
+
+        let mut sums = vec![[0.0; 3]; k];
+        sizes = vec![0; k];
+        for (point, &assignment) in points.iter().zip(&assignments) {
+            for (sum, value) in sums[assignment].iter_mut().zip(point) {
+                *sum += value;
+            }
+            sizes[assignment] += 1;
+        }
+        // An empty cluster keeps its center
+        for ((center, sum), &size) in centers.iter_mut().zip(&sums).zip(&sizes) {
+            if size > 0 {
+                *center = [sum[0] / size as f64, sum[1] / size as f64, sum[2] / size as f64];
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    let mut clusters: Vec<(usize, [u8; 3])> = centers
+        .iter()
+        .zip(&sizes)
+        .filter(|(_, &size)| size > 0)
+        .map(|(center, &size)| (size, [center[0].round() as u8, center[1].round() as u8, center[2].round() as u8]))
+        .collect();
+    clusters.sort_by_key(|(size, _)| std::cmp::Reverse(*size));
+
+    let mut colors: Vec<[u8; 3]> = Vec::with_capacity(clusters.len());
+    for (_, color) in clusters {
+        if !colors.contains(&color) {
+            colors.push(color);
+        }
+    }
+    colors
+}
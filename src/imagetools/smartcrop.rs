@@ -0,0 +1,129 @@
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+
+use super::faces::{self, FaceConfig};
+use super::{image_size, resize_image, rgb_pixels, Image, Result, FACE_DETECTION};
+
+// Where a crop to another aspect ratio keeps its window
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Gravity {
+    Center,
+    // Over the faces if the backend finds any, else over the busiest part
+    Smart,
+}
+
+static FACES: OnceLock<FaceConfig> = OnceLock::new();
+
+// Once at startup, smart crops look for faces with it; the defaults until
+// then
+pub fn set_face_config(config: FaceConfig) {
+    let _ = FACES.set(config);
+}
+
+// Pixels of the longer side the busiest part is looked for at
+const ANALYSIS_SIZE: u32 = 96;
+
+// The largest `w`:`h` window of `image`, placed by `gravity`
+pub fn crop_window(image: &Image, (w, h): (u32, u32), gravity: Gravity) -> Result<(u32, u32, u32, u32)> {
+    let (iw, ih) = image_size(image);
+    let (w, h) = (u64::from(w.max(1)), u64::from(h.max(1)));
+    let (win_w, win_h) = if u64::from(iw) * h > u64::from(ih) * w {
+        (((u64::from(ih) * w / h) as u32).max(1).min(iw), ih)
+    } else {
+        (iw, ((u64::from(iw) * h / w) as u32).max(1).min(ih))
+    };
+    if gravity == Gravity::Center || (win_w, win_h) == (iw, ih) {
+        return Ok(((iw - win_w) / 2, (ih - win_h) / 2, win_w, win_h));
+    }
+
+    // Only one side overflows
+    let horizontal = win_w < iw;
+    let (len, win) = if horizontal { (iw, win_w) } else { (ih, win_h) };
+    let start = match face_span(image, horizontal) {
+        Some((from, to)) => ((from + to) / 2).saturating_sub(win / 2).min(len - win),
+        None => busiest_span(image, horizontal, win)?,
+    };
+    Ok(if horizontal {
+        (start, 0, win_w, win_h)
+    } else {
+        (0, start, win_w, win_h)
+    })
+}
+
+// From the first face to the end of the last along the overflowing side
+fn face_span(image: &Image, horizontal: bool) -> Option<(u32, u32)> {
+    if !FACE_DETECTION {
+        return None;
+    }
+    let config = FACES.get_or_init(FaceConfig::default);
+    let found = match faces::detect(image, config) {
+        Ok(found) => found,
+        Err(err) => {
+            tracing::debug!("No face detection for a smart crop: {}", err);
+            return None;
+        }
+    };
+    found
+        .iter()
+        .map(|face| {
+            if horizontal {
+                (face.x, face.x + face.width)
+            } else {
+                (face.y, face.y + face.height)
+            }
+        })
+        .fold(None, |span, (from, to)| match span {
+            Some((span_from, span_to)) => Some((u32::min(span_from, from), u32::max(span_to, to))),
+            None => Some((from, to)),
+        })
+}
+
+// Where a `win` long window along the overflowing side holds the most edges,
+// measured on a small copy. Going down, higher rows weigh more: heads are
+// above the busy clothes in a portrait.
+fn busiest_span(image: &Image, horizontal: bool, win: u32) -> Result<u32> {
+    let (iw, ih) = image_size(image);
+    let scale = (f64::from(ANALYSIS_SIZE) / f64::from(iw.max(ih))).min(1.0);
+    let scaled = |side: u32| ((f64::from(side) * scale).round() as u32).max(3);
+    let (aw, ah) = (scaled(iw), scaled(ih));
+    let rgb = rgb_pixels(&resize_image(image, (aw, ah))?)?;
+    let luma: Vec<i32> = rgb
+        .chunks_exact(3)
+        .map(|p| (299 * i32::from(p[0]) + 587 * i32::from(p[1]) + 114 * i32::from(p[2])) / 1000)
+        .collect();
+
+    let (aw, ah) = (aw as usize, ah as usize);
+    let mut profile = vec![0.0; if horizontal { aw } else { ah }];
+    for y in 1..ah - 1 {
+        let weight = if horizontal {
+            1.0
+        } else {
+            1.5 - 0.5 * y as f64 / ah as f64
+        };
+        for x in 1..aw - 1 {
+            let i = y * aw + x;
+            let energy = (luma[i + 1] - luma[i - 1]).abs() + (luma[i + aw] - luma[i - aw]).abs();
+            profile[if horizontal { x } else { y }] += f64::from(energy) * weight;
+        }
+    }
+
+    let len = profile.len();
+    let awin = ((f64::from(win) * scale).round() as usize).clamp(1, len);
+    let center = (len - awin) / 2;
+    let mut sum: f64 = profile[..awin].iter().sum();
+    let (mut best, mut best_sum) = (0, sum);
+    for start in 1..=len - awin {
+        sum += profile[start + awin - 1] - profile[start - 1];
+        // Ties go to the window closest to the middle
+        let closer = (start as i64 - center as i64).abs() < (best as i64 - center as i64).abs();
+        if sum > best_sum || (sum == best_sum && closer) {
+            best = start;
+            best_sum = sum;
+        }
+    }
+
+    let full = if horizontal { iw } else { ih };
+    Ok(((best as f64 / scale).round() as u32).min(full - win))
+}
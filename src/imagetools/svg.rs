@@ -0,0 +1,580 @@
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+use resvg::tiny_skia::{Color, Pixmap, Transform};
+use resvg::usvg;
+
+// SVG загружается только очищенным: the document is parsed and written out
+// again keeping only allowlisted elements and attributes. Scripts, styles,
+// foreign content, event handlers and any reference outside the document
+// (links, external images, `url(...)` of another file) are dropped. A
+// DOCTYPE with declarations is refused, entities can't be used to hide
+// anything.
+#[derive(Debug, thiserror::Error)]
+pub enum SvgError {
+    #[error("Not an SVG document")]
+    NotSvg,
+    #[error("Malformed SVG: {0}")]
+    Malformed(&'static str),
+    #[error("SVG can't be rendered: {0}")]
+    Render(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+// Drawing only; `a` is unwrapped, its children kept
+const ALLOWED_ELEMENTS: &[&str] = &[
+    "svg",
+    "g",
+    "defs",
+    "symbol",
+    "use",
+    "switch",
+    "title",
+    "desc",
+    "path",
+    "rect",
+    "circle",
+    "ellipse",
+    "line",
+    "polyline",
+    "polygon",
+    "text",
+    "tspan",
+    "textPath",
+    "image",
+    "linearGradient",
+    "radialGradient",
+    "stop",
+    "clipPath",
+    "mask",
+    "pattern",
+    "marker",
+    "filter",
+    "feBlend",
+    "feColorMatrix",
+    "feComponentTransfer",
+    "feComposite",
+    "feConvolveMatrix",
+    "feDiffuseLighting",
+    "feDisplacementMap",
+    "feDistantLight",
+    "feDropShadow",
+    "feFlood",
+    "feFuncA",
+    "feFuncB",
+    "feFuncG",
+    "feFuncR",
+    "feGaussianBlur",
+    "feMerge",
+    "feMergeNode",
+    "feMorphology",
+    "feOffset",
+    "fePointLight",
+    "feSpecularLighting",
+    "feSpotLight",
+    "feTile",
+    "feTurbulence",
+];
+const UNWRAPPED_ELEMENTS: &[&str] = &["a"];
+
+// Embedded rasters an `image` may hold
+const IMAGE_DATA_TYPES: &[&str] = &["data:image/png", "data:image/jpeg", "data:image/gif", "data:image/webp"];
+
+const SVG_NAMESPACE: &str = "http://www.w3.org/2000/svg";
+
+// Renderers recurse over the tree
+const MAX_DEPTH: usize = 256;
+
+// Pixels a side a rasterized SVG is drawn at, at most
+const MAX_RASTER_SIDE: f32 = 4096.0;
+
+// Whether the first bytes of a file are an SVG document: past the XML
+// declaration, comments and DOCTYPE, the root is `svg`
+pub fn looks_like_svg(head: &[u8]) -> bool {
+    let text = String::from_utf8_lossy(head);
+    let mut rest = text.trim_start_matches('\u{feff}').trim_start();
+    loop {
+        let end = if rest.starts_with("<?") {
+            "?>"
+        } else if rest.starts_with("<!--") {
+            "-->"
+        } else if rest.starts_with("<!") {
+            ">"
+        } else {
+            break;
+        };
+        rest = match rest.find(end) {
+            Some(at) => rest[at + end.len()..].trim_start(),
+            None => return false,
+        };
+    }
+    rest.strip_prefix("<svg")
+        .map(|after| after.starts_with(|c: char| c.is_whitespace() || c == '>' || c == '/'))
+        .unwrap_or(false)
+}
+
+#[derive(Debug)]
+enum Token<'a> {
+    Start {
+        name: &'a str,
+        attributes: Vec<(&'a str, String)>,
+        empty: bool,
+    },
+    End(&'a str),
+    // Entities decoded
+    Text(String),
+}
+
+struct Tokenizer<'a> {
+    rest: &'a str,
+}
+
+fn is_name_end(c: char) -> bool {
+    c.is_whitespace() || c == '/' || c == '>' || c == '='
+}
+
+// Only the predefined entities and character references, there is no DTD
+// to declare others
+fn decode_entities(text: &str) -> Result<String, SvgError> {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find('&') {
+        decoded.push_str(&rest[..at]);
+        let end = rest[at..].find(';').ok_or(SvgError::Malformed("unterminated entity"))? + at;
+        let entity = &rest[at + 1..end];
+        let c = match entity {
+            "lt" => '<',
+            "gt" => '>',
+            "amp" => '&',
+            "quot" => '"',
+            "apos" => '\'',
+            _ => {
+                let code = match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                    None => entity.strip_prefix('#').and_then(|decimal| decimal.parse().ok()),
+                };
+                code.and_then(std::char::from_u32)
+                    .filter(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r'))
+                    .ok_or(SvgError::Malformed("unknown entity"))?
+            }
+        };
+        decoded.push(c);
+        rest = &rest[end + 1..];
+    }
+    decoded.push_str(rest);
+    Ok(decoded)
+}
+
+impl<'a> Tokenizer<'a> {
+    // Past `end`, which must follow
+    fn skip_past(&mut self, end: &str, error: &'static str) -> Result<(), SvgError> {
+        let at = self.rest.find(end).ok_or(SvgError::Malformed(error))?;
+        self.rest = &self.rest[at + end.len()..];
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<&'a str, SvgError> {
+        let end = self.rest.find(is_name_end).unwrap_or(self.rest.len());
+        let (name, rest) = self.rest.split_at(end);
+        if name.is_empty() {
+            return Err(SvgError::Malformed("missing name"));
+        }
+        self.rest = rest;
+        Ok(name)
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    fn start_tag(&mut self) -> Result<Token<'a>, SvgError> {
+        let name = self.name()?;
+        let mut attributes = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if let Some(rest) = self.rest.strip_prefix("/>") {
+                self.rest = rest;
+                return Ok(Token::Start {
+                    name,
+                    attributes,
+                    empty: true,
+                });
+            }
+            if let Some(rest) = self.rest.strip_prefix('>') {
+                self.rest = rest;
+                return Ok(Token::Start {
+                    name,
+                    attributes,
+                    empty: false,
+                });
+            }
+
+            let attribute = self.name()?;
+            self.skip_whitespace();
+            self.rest = self
+                .rest
+                .strip_prefix('=')
+                .ok_or(SvgError::Malformed("attribute without a value"))?;
+            self.skip_whitespace();
+            let quote = match self.rest.chars().next() {
+                Some(quote) if quote == '"' || quote == '\'' => quote,
+                _ => return Err(SvgError::Malformed("unquoted attribute")),
+            };
+            let end = self.rest[1..]
+                .find(quote)
+                .ok_or(SvgError::Malformed("unterminated attribute"))?
+                + 1;
+            let value = &self.rest[1..end];
+            if value.contains('<') {
+                return Err(SvgError::Malformed("< in an attribute"));
+            }
+            attributes.push((attribute, decode_entities(value)?));
+            self.rest = &self.rest[end + 1..];
+        }
+    }
+
+    fn next(&mut self) -> Result<Option<Token<'a>>, SvgError> {
+        loop {
+            if self.rest.is_empty() {
+                return Ok(None);
+            }
+            if let Some(rest) = self.rest.strip_prefix("<!--") {
+                self.rest = rest;
+                self.skip_past("-->", "unterminated comment")?;
+            } else if let Some(rest) = self.rest.strip_prefix("<![CDATA[") {
+                let end = rest.find("]]>").ok_or(SvgError::Malformed("unterminated CDATA"))?;
+                self.rest = &rest[end + 3..];
+                return Ok(Some(Token::Text(rest[..end].to_owned())));
+            } else if let Some(rest) = self.rest.strip_prefix("<!") {
+                // A DOCTYPE naming a DTD is harmless, declarations are not
+                let end = rest.find('>').ok_or(SvgError::Malformed("unterminated declaration"))?;
+                if rest[..end].contains('[') {
+                    return Err(SvgError::Malformed("DOCTYPE with declarations"));
+                }
+                self.rest = &rest[end + 1..];
+            } else if let Some(rest) = self.rest.strip_prefix("<?") {
+                self.rest = rest;
+                self.skip_past("?>", "unterminated processing instruction")?;
+            } else if let Some(rest) = self.rest.strip_prefix("</") {
+                self.rest = rest;
+                let name = self.name()?;
+                self.skip_whitespace();
+                self.rest = self
+                    .rest
+                    .strip_prefix('>')
+                    .ok_or(SvgError::Malformed("unterminated end tag"))?;
+                return Ok(Some(Token::End(name)));
+            } else if let Some(rest) = self.rest.strip_prefix('<') {
+                self.rest = rest;
+                return self.start_tag().map(Some);
+            } else {
+                let end = self.rest.find('<').unwrap_or(self.rest.len());
+                let text = decode_entities(&self.rest[..end])?;
+                self.rest = &self.rest[end..];
+                return Ok(Some(Token::Text(text)));
+            }
+        }
+    }
+}
+
+// Every `url(` must point into the document
+fn local_urls_only(value: &str) -> bool {
+    let lower = value.to_ascii_lowercase();
+    lower.match_indices("url(").all(|(at, _)| {
+        lower[at + 4..]
+            .trim_start()
+            .trim_start_matches(['"', '\''])
+            .starts_with('#')
+    })
+}
+
+// The name it is written out under, `None` to drop it
+fn allowed_attribute<'a>(element: &str, name: &'a str, value: &str) -> Option<&'a str> {
+    let lower = name.to_ascii_lowercase();
+    if lower.starts_with("on") {
+        return None;
+    }
+    let squeezed: String = value
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .collect::<String>()
+        .to_ascii_lowercase();
+    if squeezed.contains("javascript:") || squeezed.contains("vbscript:") || !local_urls_only(value) {
+        return None;
+    }
+
+    match name {
+        "href" | "xlink:href" => {
+            let value = value.trim();
+            let embedded = element == "image"
+                && IMAGE_DATA_TYPES.iter().any(|prefix| {
+                    value
+                        .get(..prefix.len())
+                        .is_some_and(|head| head.eq_ignore_ascii_case(prefix))
+                        && matches!(value.as_bytes().get(prefix.len()), Some(b';') | Some(b','))
+                });
+            // SVG 2 takes a plain `href`, so no xlink namespace is needed
+            if value.starts_with('#') || embedded {
+                Some("href")
+            } else {
+                None
+            }
+        }
+        // Escapes could spell anything in CSS
+        "style" if value.contains('\\') || squeezed.contains("@import") || squeezed.contains("expression(") => None,
+        "xml:space" | "xml:lang" => Some(name),
+        // Written out by `sanitize` for the root
+        "xmlns" => None,
+        _ if name.contains(':') => None,
+        _ => Some(name),
+    }
+}
+
+fn escape(text: &str, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+}
+
+// The document rewritten, and how many elements and attributes were dropped
+pub fn sanitize(data: &[u8]) -> Result<(String, usize), SvgError> {
+    let text = std::str::from_utf8(data).map_err(|_| SvgError::Malformed("not UTF-8"))?;
+    let mut tokens = Tokenizer {
+        rest: text.trim_start_matches('\u{feff}'),
+    };
+
+    let mut out = String::with_capacity(text.len());
+    let mut removed = 0;
+    // Open elements, and whether each was written out
+    let mut open: Vec<(&str, bool)> = Vec::new();
+    // Depth inside a dropped element
+    let mut skipping = 0;
+    let mut root_seen = false;
+
+    while let Some(token) = tokens.next()? {
+        match token {
+            Token::Start {
+                name,
+                attributes,
+                empty,
+            } => {
+                if skipping > 0 {
+                    if !empty {
+                        skipping += 1;
+                    }
+                    continue;
+                }
+                if open.is_empty() {
+                    if root_seen {
+                        return Err(SvgError::Malformed("several root elements"));
+                    }
+                    if name != "svg" {
+                        return Err(SvgError::NotSvg);
+                    }
+                    root_seen = true;
+                }
+                if open.len() >= MAX_DEPTH {
+                    return Err(SvgError::Malformed("nested too deep"));
+                }
+
+                let written = ALLOWED_ELEMENTS.contains(&name);
+                if !written {
+                    removed += 1;
+                    if !UNWRAPPED_ELEMENTS.contains(&name) {
+                        if !empty {
+                            skipping = 1;
+                        }
+                        continue;
+                    }
+                } else {
+                    out.push('<');
+                    out.push_str(name);
+                    if open.is_empty() {
+                        out.push_str(" xmlns=\"");
+                        out.push_str(SVG_NAMESPACE);
+                        out.push('"');
+                    }
+                    for (attribute, value) in &attributes {
+                        match allowed_attribute(name, attribute, value) {
+                            Some(attribute) => {
+                                out.push(' ');
+                                out.push_str(attribute);
+                                out.push_str("=\"");
+                                escape(value, &mut out);
+                                out.push('"');
+                            }
+                            None if *attribute == "xmlns" || attribute.starts_with("xmlns:") => {}
+                            None => removed += 1,
+                        }
+                    }
+                    out.push_str(if empty { "/>" } else { ">" });
+                }
+                if !empty {
+                    open.push((name, written));
+                }
+            }
+            Token::End(name) => {
+                if skipping > 0 {
+                    skipping -= 1;
+                    continue;
+                }
+                match open.pop() {
+                    Some((open_name, written)) if open_name == name => {
+                        if written {
+                            out.push_str("</");
+                            out.push_str(name);
+                            out.push('>');
+                        }
+                    }
+                    _ => return Err(SvgError::Malformed("mismatched end tag")),
+                }
+            }
+            Token::Text(text) => {
+                if open.is_empty() {
+                    if !text.trim().is_empty() {
+                        return Err(SvgError::Malformed("text outside the root element"));
+                    }
+                } else if skipping == 0 {
+                    escape(&text, &mut out);
+                }
+            }
+        }
+    }
+
+    if !root_seen {
+        return Err(SvgError::NotSvg);
+    }
+    if !open.is_empty() || skipping > 0 {
+        return Err(SvgError::Malformed("unclosed element"));
+    }
+    Ok((out, removed))
+}
+
+// Sanitizes a file in place, returns how much was dropped
+pub fn sanitize_svg<P: AsRef<Path>>(path: P) -> Result<usize, SvgError> {
+    let path = path.as_ref();
+    let (sanitized, removed) = sanitize(&std::fs::read(path)?)?;
+    let tmp_path = path.with_extension("svg-clean");
+    std::fs::write(&tmp_path, sanitized)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(removed)
+}
+
+// CSS pixels of a `width` or `height`; `None` for relative units
+fn length(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let units = [
+        ("px", 1.0),
+        ("pt", 4.0 / 3.0),
+        ("pc", 16.0),
+        ("mm", 96.0 / 25.4),
+        ("cm", 96.0 / 2.54),
+        ("in", 96.0),
+    ];
+    let (number, factor) = units
+        .iter()
+        .find_map(|(unit, factor)| value.strip_suffix(unit).map(|number| (number, *factor)))
+        .unwrap_or((value, 1.0));
+    number
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|number| *number > 0.0)
+        .map(|number| number * factor)
+}
+
+// Width and height of the root element, from `viewBox` where it leaves
+// them out; 100x100 like browsers when neither says
+pub fn dimensions(data: &[u8]) -> Option<(u32, u32)> {
+    let text = std::str::from_utf8(data).ok()?;
+    let mut tokens = Tokenizer {
+        rest: text.trim_start_matches('\u{feff}'),
+    };
+    let attributes = loop {
+        match tokens.next().ok()?? {
+            Token::Start {
+                name: "svg",
+                attributes,
+                ..
+            } => break attributes,
+            Token::Start { .. } | Token::End(_) => return None,
+            Token::Text(_) => {}
+        }
+    };
+    let attribute = |name: &str| {
+        attributes
+            .iter()
+            .find(|(attribute, _)| *attribute == name)
+            .map(|(_, value)| value.as_str())
+    };
+
+    let (width, height) = (
+        attribute("width").and_then(length),
+        attribute("height").and_then(length),
+    );
+    let view_box = attribute("viewBox").and_then(|view_box| {
+        let numbers: Vec<f64> = view_box
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|number| !number.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .ok()?;
+        match numbers[..] {
+            [_, _, w, h] if w > 0.0 && h > 0.0 => Some((w, h)),
+            _ => None,
+        }
+    });
+    let (width, height) = match (width, height, view_box) {
+        (Some(width), Some(height), _) => (width, height),
+        (Some(width), None, Some((w, h))) => (width, width * h / w),
+        (None, Some(height), Some((w, h))) => (height * w / h, height),
+        (None, None, Some((w, h))) => (w, h),
+        (width, height, None) => (width.unwrap_or(100.0), height.unwrap_or(100.0)),
+    };
+    Some(((width.round() as u32).max(1), (height.round() as u32).max(1)))
+}
+
+// Loaded once, looking through the system's fonts takes a while
+fn fonts() -> Arc<usvg::fontdb::Database> {
+    static FONTS: OnceLock<Arc<usvg::fontdb::Database>> = OnceLock::new();
+    FONTS
+        .get_or_init(|| {
+            let mut fonts = usvg::fontdb::Database::new();
+            fonts.load_system_fonts();
+            Arc::new(fonts)
+        })
+        .clone()
+}
+
+// A PNG on white, at its own size or scaled up to cover `at_least`: a
+// vector is drawn sharp at the size it's needed at
+pub fn rasterize(data: &[u8], at_least: Option<(u32, u32)>) -> Result<Vec<u8>, SvgError> {
+    let options = usvg::Options {
+        fontdb: fonts(),
+        ..Default::default()
+    };
+    let tree = usvg::Tree::from_data(data, &options).map_err(|err| SvgError::Render(err.to_string()))?;
+    let (width, height) = (tree.size().width(), tree.size().height());
+
+    let scale = match at_least {
+        Some((w, h)) => (w as f32 / width).max(h as f32 / height).max(1.0),
+        None => 1.0,
+    };
+    let scale = scale.min(MAX_RASTER_SIDE / width.max(height));
+    let side = |length: f32| ((length * scale).round() as u32).max(1);
+    let (w, h) = (side(width), side(height));
+
+    let mut pixmap = Pixmap::new(w, h).ok_or_else(|| SvgError::Render(format!("no {}x{} canvas", w, h)))?;
+    // The backends decode without alpha
+    pixmap.fill(Color::WHITE);
+    let transform = Transform::from_scale(w as f32 / width, h as f32 / height);
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+    pixmap.encode_png().map_err(|err| SvgError::Render(err.to_string()))
+}
@@ -0,0 +1,52 @@
+use serde::Deserialize;
+
+// Короткие ролики вперемешку с фото: MP4 and WebM are stored as uploaded,
+// the poster frame stands in for them wherever pixels are needed
+// (thumbnails, placeholders, transformations). Off by default, and only the
+// OpenCV backend can read them, see `VIDEO`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct VideoConfig {
+    pub enabled: bool,
+    // Bytes, on top of `max_file_size`; 0 is unlimited
+    pub max_size: u64,
+}
+
+impl Default for VideoConfig {
+    fn default() -> Self {
+        VideoConfig {
+            enabled: false,
+            max_size: 50 << 20,
+        }
+    }
+}
+
+// Extensions videos are stored under
+pub const EXTENSIONS: &[&str] = &["mp4", "webm"];
+
+// What the container says, see `backend::video_info`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Video {
+    pub width: u32,
+    pub height: u32,
+    // 0 when the container doesn't tell
+    pub frames: u32,
+    pub duration_ms: u64,
+}
+
+// ISO BMFF brands of MP4 video; AVIF and HEIC share the container
+const MP4_BRANDS: &[&[u8]] = &[
+    b"isom", b"iso2", b"iso4", b"iso5", b"iso6", b"mp41", b"mp42", b"avc1", b"dash", b"M4V ",
+];
+
+// Extension of an MP4 or WebM by its first bytes
+pub fn container(head: &[u8]) -> Option<&'static str> {
+    if head.len() >= 12 && &head[4..8] == b"ftyp" && MP4_BRANDS.contains(&&head[8..12]) {
+        return Some("mp4");
+    }
+    // An EBML header with a `webm` DocType, Matroska proper isn't accepted
+    if head.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) && head.windows(4).take(64).any(|window| window == b"webm") {
+        return Some("webm");
+    }
+    None
+}
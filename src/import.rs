@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Ownership;
+use crate::{clock, fetch_image, gen_rand_id, ByteLimit, Config, UploadOptions};
+
+// Finished jobs are forgotten after this long
+const FINISHED_JOB_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImportError {
+    #[error("Manifest is empty")]
+    Empty,
+    #[error("Manifest has more than {0} rows")]
+    TooManyRows(usize),
+    #[error("Manifest row {0}: {1}")]
+    InvalidRow(usize, String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ManifestFormat {
+    Csv,
+    Jsonl,
+}
+
+impl ManifestFormat {
+    pub fn from_mime_type(mime_type: &str) -> Option<ManifestFormat> {
+        match mime_type {
+            "text/csv" => Some(ManifestFormat::Csv),
+            "application/x-ndjson" | "application/jsonl" | "application/x-jsonlines" => {
+                Some(ManifestFormat::Jsonl)
+            }
+            _ => None,
+        }
+    }
+}
+
+// Одна строка манифеста: что скачать и с какими пометками
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestRow {
+    pub url: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub preset: Option<String>,
+}
+
+// CSV keeps tags in a single `;`-separated column
+#[derive(Deserialize)]
+struct CsvRow {
+    url: String,
+    #[serde(default)]
+    tags: Option<String>,
+    #[serde(default)]
+    preset: Option<String>,
+}
+
+impl From<CsvRow> for ManifestRow {
+    fn from(row: CsvRow) -> Self {
+        ManifestRow {
+            url: row.url,
+            tags: row
+                .tags
+                .unwrap_or_default()
+                .split(';')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(String::from)
+                .collect(),
+            preset: row.preset.filter(|preset| !preset.is_empty()),
+        }
+    }
+}
+
+pub fn parse_manifest(
+    format: ManifestFormat,
+    data: &[u8],
+    max_rows: usize,
+) -> Result<Vec<ManifestRow>, ImportError> {
+    let mut rows = Vec::new();
+
+    match format {
+        ManifestFormat::Csv => {
+            let mut reader = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .flexible(true)
+                .from_reader(data);
+
+            for (n, row) in reader.deserialize::<CsvRow>().enumerate() {
+                // +2: the header is line 1
+                let row = row.map_err(|e| ImportError::InvalidRow(n + 2, e.to_string()))?;
+                rows.push(row.into());
+
+                if rows.len() > max_rows {
+                    return Err(ImportError::TooManyRows(max_rows));
+                }
+            }
+        }
+        ManifestFormat::Jsonl => {
+            let text = std::str::from_utf8(data)
+                .map_err(|e| ImportError::InvalidRow(0, e.to_string()))?;
+
+            for (n, line) in text.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let row = serde_json::from_str::<ManifestRow>(line)
+                    .map_err(|e| ImportError::InvalidRow(n + 1, e.to_string()))?;
+                rows.push(row);
+
+                if rows.len() > max_rows {
+                    return Err(ImportError::TooManyRows(max_rows));
+                }
+            }
+        }
+    }
+
+    if rows.is_empty() {
+        return Err(ImportError::Empty);
+    }
+
+    Ok(rows)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Finished,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RowResult {
+    pub row: usize,
+    pub url: String,
+    pub tags: Vec<String>,
+    pub preset: Option<String>,
+    pub id: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportJob {
+    pub id: String,
+    pub state: JobState,
+    pub created_at: u64,
+    pub total: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub results: Vec<RowResult>,
+    // The creator's, only they and their tenant's admins see the job
+    #[serde(skip)]
+    pub ownership: Ownership,
+    #[serde(skip)]
+    rows: Vec<ManifestRow>,
+    #[serde(skip)]
+    finished_at: Option<SystemTime>,
+}
+
+// Реестр задач импорта, общий для всех воркеров
+#[derive(Clone, Default)]
+pub struct ImportJobs {
+    jobs: Arc<Mutex<HashMap<String, ImportJob>>>,
+}
+
+impl ImportJobs {
+    pub fn create(&self, rows: Vec<ManifestRow>, ownership: Ownership) -> String {
+        let id = gen_rand_id(12);
+        let job = ImportJob {
+            id: id.clone(),
+            state: JobState::Queued,
+            created_at: clock::unix_now(),
+            total: rows.len(),
+            succeeded: 0,
+            failed: 0,
+            results: Vec::with_capacity(rows.len()),
+            ownership,
+            rows,
+            finished_at: None,
+        };
+
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, job| match job.finished_at {
+            Some(t) => t.elapsed().map(|age| age < FINISHED_JOB_TTL).unwrap_or(true),
+            None => true,
+        });
+        jobs.insert(id.clone(), job);
+
+        id
+    }
+
+    pub fn get(&self, id: &str) -> Option<ImportJob> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    // Ingests rows one by one, so a big manifest doesn't hammer the origins.
+    // `options` are the job creator's, see `auth::Ownership`.
+    pub async fn run(&self, config: Config, id: String, options: UploadOptions) {
+        let rows = match self.jobs.lock().unwrap().get_mut(&id) {
+            Some(job) => {
+                job.state = JobState::Running;
+                job.rows.clone()
+            }
+            None => return,
+        };
+
+        for (n, row) in rows.into_iter().enumerate() {
+            // Each row is limited like an upload request of its own
+            let mut options = options.clone();
+            options.byte_limit = options.byte_limit.as_ref().map(ByteLimit::fresh);
+            options.tags = row.tags.clone();
+            let preset = match &row.preset {
+                Some(name) => match config.import_presets.get(name) {
+                    Some(preset) => preset.apply(&mut options),
+                    None => Err(format!("unknown preset {:?}", name)),
+                },
+                None => Ok(()),
+            };
+            let res = match preset {
+                Ok(()) => fetch_image(&config, &row.url, &Default::default(), &options)
+                    .await
+                    .map_err(|err| err.to_string()),
+                Err(err) => Err(err),
+            };
+
+            let mut result = RowResult {
+                row: n + 1,
+                url: row.url,
+                tags: row.tags,
+                preset: row.preset,
+                id: None,
+                error: None,
+            };
+
+            match res {
+                Ok(uploaded_file) => {
+                    tracing::info!("Import {}: row {} -> {}", id, n + 1, uploaded_file.id);
+                    result.id = Some(uploaded_file.id);
+                }
+                Err(err) => {
+                    tracing::warn!("Import {}: row {} failed: {}", id, n + 1, err);
+                    result.error = Some(err);
+                }
+            }
+
+            if let Some(job) = self.jobs.lock().unwrap().get_mut(&id) {
+                if result.error.is_some() {
+                    job.failed += 1;
+                } else {
+                    job.succeeded += 1;
+                }
+                job.results.push(result);
+            }
+        }
+
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(&id) {
+            job.state = JobState::Finished;
+            job.finished_at = Some(clock::now());
+            job.rows = Vec::new();
+        }
+    }
+}
+
+pub fn report_csv(job: &ImportJob) -> Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(["row", "url", "tags", "preset", "id", "error"])?;
+
+    for result in &job.results {
+        writer.write_record(&[
+            result.row.to_string(),
+            result.url.clone(),
+            result.tags.join(";"),
+            result.preset.clone().unwrap_or_default(),
+            result.id.clone().unwrap_or_default(),
+            result.error.clone().unwrap_or_default(),
+        ])?;
+    }
+
+    Ok(writer.into_inner().map_err(|e| e.into_error())?)
+}
+
+pub fn report_jsonl(job: &ImportJob) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    for result in &job.results {
+        serde_json::to_writer(&mut out, result)?;
+        out.push(b'\n');
+    }
+    Ok(out)
+}
@@ -0,0 +1,111 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::{self, METADATA_DIR};
+use crate::{find_upload, layout};
+
+// Under `metadata::METADATA_DIR`, the upload listings skip it
+pub const JOURNAL_DIR: &str = "journal";
+
+// Журнал незавершённых загрузок, with `Durability::Strict`: an entry is
+// written and synced before an upload's first byte, and removed once its
+// file is in place or it has failed. An entry left by a crash names an
+// upload that was never acknowledged; `recover` takes back what it left,
+// its metadata most of all, which is saved before the file is moved into
+// place and would list an upload that isn't there.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub id: String,
+    // Relative to `uploads_dir`
+    pub tmp_path: PathBuf,
+    pub started_at: u64,
+}
+
+fn dir<P: AsRef<Path>>(uploads_dir: P) -> PathBuf {
+    uploads_dir.as_ref().join(METADATA_DIR).join(JOURNAL_DIR)
+}
+
+fn entry_path<P: AsRef<Path>>(uploads_dir: P, id: &str) -> PathBuf {
+    dir(uploads_dir).join(format!("{}.json", id))
+}
+
+// Blocking; synced with its directory before it returns
+pub fn begin(uploads_dir: &Path, id: &str, tmp_path: &Path) -> std::io::Result<()> {
+    let entry = JournalEntry {
+        id: id.to_owned(),
+        tmp_path: tmp_path.strip_prefix(uploads_dir).unwrap_or(tmp_path).to_owned(),
+        started_at: metadata::now(),
+    };
+    let dir = dir(uploads_dir);
+    std::fs::create_dir_all(&dir)?;
+    let mut file = std::fs::File::create(entry_path(uploads_dir, id))?;
+    file.write_all(&serde_json::to_vec(&entry)?)?;
+    file.sync_all()?;
+    sync_dir(&dir)
+}
+
+// Blocking, and quick enough for a drop
+pub fn end(uploads_dir: &Path, id: &str) {
+    if let Err(err) = std::fs::remove_file(entry_path(uploads_dir, id)) {
+        if err.kind() != std::io::ErrorKind::NotFound {
+            tracing::warn!("Error ending journal entry of {}: {}", id, err);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    std::fs::File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+// Entries left by a crash, unreadable ones skipped
+pub async fn pending<P: AsRef<Path>>(uploads_dir: P) -> Result<Vec<JournalEntry>> {
+    let mut entries = match tokio::fs::read_dir(dir(&uploads_dir)).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut pending = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.path().extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        match serde_json::from_slice(&tokio::fs::read(entry.path()).await?) {
+            Ok(journal_entry) => pending.push(journal_entry),
+            Err(err) => {
+                tracing::warn!("Removing unreadable journal entry {}: {}", entry.path().display(), err);
+                tokio::fs::remove_file(entry.path()).await?;
+            }
+        }
+    }
+    Ok(pending)
+}
+
+// Run at startup before anything uploads, returns how many uploads were
+// rolled back. One whose file made it into place before the crash is kept.
+pub async fn recover<P: AsRef<Path>>(uploads_dir: P) -> Result<usize> {
+    let uploads_dir = uploads_dir.as_ref();
+    let mut rolled_back = 0;
+    for entry in pending(uploads_dir).await? {
+        if find_upload(uploads_dir, &entry.id).await.is_none() {
+            tracing::warn!("Rolling back upload {} interrupted at {}", entry.id, entry.started_at);
+            match tokio::fs::remove_file(uploads_dir.join(&entry.tmp_path)).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+            metadata::remove(uploads_dir, &entry.id).await?;
+            layout::forget_tenant(&entry.id);
+            rolled_back += 1;
+        }
+        end(uploads_dir, &entry.id);
+    }
+    Ok(rolled_back)
+}
@@ -0,0 +1,327 @@
+use std::path::{Path, PathBuf};
+
+use bytes::{Buf, Bytes};
+use anyhow::Result;
+use rand::Rng;
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+use futures_util::stream::{Stream, StreamExt};
+
+use crate::base64_stream::Base64Decoder;
+use crate::{ByteLimit, UploadError};
+
+// A `base64` value decoded to disk as the body came in, removed when dropped
+#[derive(Debug)]
+pub struct Spooled {
+    pub path: PathBuf,
+    // Of a data URI, see `base64_stream::split_data_uri`
+    pub declared_type: Option<String>,
+    pub size: u64,
+}
+
+impl Drop for Spooled {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!("Error removing {}: {}", self.path.display(), err);
+            }
+        }
+    }
+}
+
+fn is_scalar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'-' || b == b'+' || b == b'.'
+}
+
+fn syntax_error(message: &str) -> anyhow::Error {
+    UploadError::Body(anyhow::anyhow!("Invalid JSON: {}", message)).into()
+}
+
+// Reads `[{"base64": "...", ...}, ...]` off a request body an object at a
+// time, so a large base64 upload is never in memory as a whole: the
+// `base64` value is decoded into a spool file as it arrives, the rest of the
+// object is kept for serde, within `json_limit` bytes for the whole body.
+pub struct UploadItems<S> {
+    stream: S,
+    chunk: Bytes,
+    spool_dir: PathBuf,
+    json_limit: usize,
+    json_left: usize,
+    byte_limit: Option<ByteLimit>,
+    started: bool,
+    done: bool,
+}
+
+impl<S, E> UploadItems<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + std::marker::Unpin,
+    E: Into<anyhow::Error>,
+{
+    // Spools go to `spool_dir` as temp files, see `is_temp_file_name`
+    pub fn new(stream: S, spool_dir: &Path, json_limit: usize, byte_limit: Option<ByteLimit>) -> UploadItems<S> {
+        UploadItems {
+            stream,
+            chunk: Bytes::new(),
+            spool_dir: spool_dir.to_owned(),
+            json_limit,
+            json_left: json_limit,
+            byte_limit,
+            started: false,
+            done: false,
+        }
+    }
+
+    // The next object without its `base64` member, and the spooled value of
+    // that member if it had one
+    pub async fn next(&mut self) -> Result<Option<(Value, Option<Spooled>)>> {
+        if self.done {
+            return Ok(None);
+        }
+        let started = std::mem::replace(&mut self.started, true);
+        match (started, self.token().await?) {
+            (false, Some(b'[')) => {
+                self.bump().await?;
+                if self.token().await? == Some(b']') {
+                    return self.end().await;
+                }
+            }
+            (false, _) => return Err(syntax_error("expected a list of objects")),
+            (true, Some(b',')) => {
+                self.bump().await?;
+            }
+            (true, Some(b']')) => return self.end().await,
+            (true, _) => return Err(syntax_error("expected ',' or ']'")),
+        }
+        self.read_object().await.map(Some)
+    }
+
+    // After the closing ']', only whitespace may follow
+    async fn end(&mut self) -> Result<Option<(Value, Option<Spooled>)>> {
+        self.bump().await?;
+        self.done = true;
+        match self.token().await? {
+            None => Ok(None),
+            Some(_) => Err(syntax_error("trailing characters")),
+        }
+    }
+
+    async fn read_object(&mut self) -> Result<(Value, Option<Spooled>)> {
+        if self.token().await? != Some(b'{') {
+            return Err(syntax_error("expected an object"));
+        }
+        self.bump().await?;
+        let mut object = b"{".to_vec();
+        let mut spooled = None;
+        if self.token().await? == Some(b'}') {
+            self.bump().await?;
+        } else {
+            loop {
+                if self.token().await? != Some(b'"') {
+                    return Err(syntax_error("expected a key"));
+                }
+                let mut key = Vec::new();
+                self.read_string(&mut key).await?;
+                if self.token().await? != Some(b':') {
+                    return Err(syntax_error("expected ':'"));
+                }
+                self.bump().await?;
+                let is_string = self.token().await? == Some(b'"');
+                if key == b"\"base64\"" && is_string {
+                    if spooled.is_some() {
+                        return Err(syntax_error("duplicate base64 key"));
+                    }
+                    spooled = Some(self.spool_string().await?);
+                } else {
+                    if object.len() > 1 {
+                        object.push(b',');
+                    }
+                    object.extend_from_slice(&key);
+                    object.push(b':');
+                    self.read_value(&mut object).await?;
+                }
+                match self.token().await? {
+                    Some(b',') => {
+                        self.bump().await?;
+                    }
+                    Some(b'}') => {
+                        self.bump().await?;
+                        break;
+                    }
+                    _ => return Err(syntax_error("expected ',' or '}'")),
+                }
+            }
+        }
+        object.push(b'}');
+        let object = serde_json::from_slice(&object).map_err(|err| UploadError::Body(err.into()))?;
+        Ok((object, spooled))
+    }
+
+    // Copies a value as it is, serde checks it later
+    async fn read_value(&mut self, out: &mut Vec<u8>) -> Result<()> {
+        let mut depth = 0usize;
+        loop {
+            match self.token().await? {
+                Some(b'"') => self.read_string(out).await?,
+                Some(b) if is_scalar(b) => {
+                    // A number, `true`, `false` or `null`
+                    while let Some(b) = self.peek().await?.filter(|&b| is_scalar(b)) {
+                        self.bump().await?;
+                        out.push(b);
+                    }
+                }
+                Some(b) => {
+                    match b {
+                        b'{' | b'[' => depth += 1,
+                        b'}' | b']' if depth > 0 => depth -= 1,
+                        b',' | b':' if depth > 0 => {}
+                        _ => return Err(syntax_error("unexpected character")),
+                    }
+                    self.bump().await?;
+                    out.push(b);
+                }
+                None => return Err(syntax_error("unexpected end")),
+            }
+            if depth == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    // Copies a string with its quotes and escapes
+    async fn read_string(&mut self, out: &mut Vec<u8>) -> Result<()> {
+        out.push(b'"');
+        self.bump().await?;
+        loop {
+            match self.bump().await? {
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    out.push(b'\\');
+                    match self.bump().await? {
+                        Some(b) => out.push(b),
+                        None => return Err(syntax_error("unexpected end")),
+                    }
+                }
+                Some(b) => out.push(b),
+                None => return Err(syntax_error("unexpected end")),
+            }
+        }
+        out.push(b'"');
+        Ok(())
+    }
+
+    // Decodes a string into a spool file without keeping it, only the
+    // escapes base64 may need (`\/` and whitespace) are allowed
+    async fn spool_string(&mut self) -> Result<Spooled> {
+        self.bump().await?;
+        let name: String = rand::thread_rng()
+            .sample_iter(rand::distributions::Alphanumeric)
+            .take(16)
+            .collect();
+        let path = self.spool_dir.join(format!(".base64-{}.tmp", name));
+        let file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .await
+            .map_err(UploadError::from)?;
+        let mut spooled = Spooled {
+            path,
+            declared_type: None,
+            size: 0,
+        };
+        let mut writer = tokio::io::BufWriter::new(file);
+        let mut decoder = Base64Decoder::default();
+
+        loop {
+            if !self.fill().await? {
+                return Err(syntax_error("unexpected end"));
+            }
+            let end = self
+                .chunk
+                .iter()
+                .position(|&b| b == b'"' || b == b'\\')
+                .unwrap_or(self.chunk.len());
+            let text = self.chunk.split_to(end);
+            let chunks = decoder.push(&text).map_err(|err| UploadError::Body(err.into()))?;
+            self.spool(&mut writer, &mut spooled, chunks).await?;
+
+            match self.peek().await? {
+                Some(b'"') => {
+                    self.chunk.advance(1);
+                    break;
+                }
+                Some(b'\\') => {
+                    self.chunk.advance(1);
+                    let escaped = match self.peek().await? {
+                        Some(b'/') => &b"/"[..],
+                        Some(b'n') | Some(b'r') | Some(b't') => &b""[..],
+                        _ => return Err(syntax_error("unsupported escape in base64 data")),
+                    };
+                    self.chunk.advance(1);
+                    let chunks = decoder.push(escaped).map_err(|err| UploadError::Body(err.into()))?;
+                    self.spool(&mut writer, &mut spooled, chunks).await?;
+                }
+                _ => {}
+            }
+        }
+
+        spooled.declared_type = decoder.declared_type().map(str::to_owned);
+        let last = decoder.finish().map_err(|err| UploadError::Body(err.into()))?;
+        self.spool(&mut writer, &mut spooled, last.into_iter().collect()).await?;
+        writer.flush().await.map_err(UploadError::from)?;
+        Ok(spooled)
+    }
+
+    async fn spool<W>(&self, writer: &mut W, spooled: &mut Spooled, chunks: Vec<Bytes>) -> Result<()>
+    where
+        W: tokio::io::AsyncWrite + std::marker::Unpin,
+    {
+        for chunk in chunks {
+            spooled.size += chunk.len() as u64;
+            if let Some(byte_limit) = &self.byte_limit {
+                byte_limit.check(spooled.size)?;
+            }
+            writer.write_all(&chunk).await.map_err(UploadError::from)?;
+        }
+        Ok(())
+    }
+
+    // Skips whitespace, returns the next byte without taking it
+    async fn token(&mut self) -> Result<Option<u8>> {
+        while let Some(b) = self.peek().await? {
+            if !matches!(b, b' ' | b'\t' | b'\n' | b'\r') {
+                return Ok(Some(b));
+            }
+            self.bump().await?;
+        }
+        Ok(None)
+    }
+
+    async fn peek(&mut self) -> Result<Option<u8>> {
+        Ok(if self.fill().await? { Some(self.chunk[0]) } else { None })
+    }
+
+    // Takes a byte outside the `base64` values, they count towards `json_limit`
+    async fn bump(&mut self) -> Result<Option<u8>> {
+        let b = self.peek().await?;
+        if b.is_some() {
+            self.json_left = self
+                .json_left
+                .checked_sub(1)
+                .ok_or(UploadError::BodyTooLarge(self.json_limit as u64))?;
+            self.chunk.advance(1);
+        }
+        Ok(b)
+    }
+
+    // Whether there's anything left of the body
+    async fn fill(&mut self) -> Result<bool> {
+        while self.chunk.is_empty() {
+            match self.stream.next().await {
+                Some(chunk) => self.chunk = chunk.map_err(|e| UploadError::Body(e.into()))?,
+                None => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,268 @@
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+// JWT вместо статических ключей: bearer tokens signed by an identity
+// provider, checked against the keys it publishes at `jwks_url`. The
+// token's scopes say what it may do, see `Scope`; its `sub` is recorded as
+// the uploader, as an API key's name is.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct JwtConfig {
+    // Empty disables JWTs
+    pub jwks_url: String,
+    // `iss` and `aud` tokens must carry, unchecked when empty
+    pub issuer: String,
+    pub audience: String,
+    // Clock skew allowed on `exp` and `nbf`
+    pub leeway_secs: u64,
+    // How often the keys are fetched again; a token signed with a key not
+    // seen yet fetches them sooner, at most once a minute
+    pub jwks_refresh_secs: u64,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        JwtConfig {
+            jwks_url: String::new(),
+            issuer: String::new(),
+            audience: String::new(),
+            leeway_secs: 60,
+            jwks_refresh_secs: 3600,
+        }
+    }
+}
+
+impl JwtConfig {
+    pub fn enabled(&self) -> bool {
+        !self.jwks_url.is_empty()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum JwtError {
+    #[error("Malformed token: {0}")]
+    Malformed(String),
+    #[error("Token algorithm {0:?} isn't accepted")]
+    Algorithm(Algorithm),
+    #[error("Token signed with unknown key {0:?}")]
+    UnknownKey(String),
+    #[error("Invalid token: {0}")]
+    Invalid(String),
+}
+
+// Что разрешает токен. Tokens without any get nothing but being identified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    // Uploads and the other writes an API key may make
+    UploadWrite,
+    // Previews of the subject's own uploads before they are public
+    ImageRead,
+    // What admin API keys may do
+    Admin,
+}
+
+impl Scope {
+    pub fn parse(scope: &str) -> Option<Scope> {
+        match scope {
+            "upload:write" => Some(Scope::UploadWrite),
+            "image:read" => Some(Scope::ImageRead),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+// A verified token
+#[derive(Debug, Clone)]
+pub struct TokenClaims {
+    pub sub: String,
+    pub scopes: Vec<Scope>,
+}
+
+impl TokenClaims {
+    pub fn has(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+}
+
+// Scopes come space-separated in `scope` (RFC 8693) or as an `scp` list,
+// as some providers send them; unknown ones are ignored
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    scope: Option<String>,
+    #[serde(default)]
+    scp: Vec<String>,
+}
+
+// Signatures with a provider's public keys only, never a shared secret
+const ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+const MIN_REFETCH_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct JwtVerifier {
+    config: JwtConfig,
+    keys: RwLock<JwkSet>,
+    // When the keys were last asked for, see `MIN_REFETCH_INTERVAL`
+    fetched_at: Mutex<Option<Instant>>,
+    client: reqwest::Client,
+}
+
+impl JwtVerifier {
+    pub fn new(config: &JwtConfig) -> JwtVerifier {
+        JwtVerifier {
+            config: config.clone(),
+            keys: RwLock::new(JwkSet { keys: Vec::new() }),
+            fetched_at: Mutex::new(None),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn set_keys(&self, keys: JwkSet) {
+        *self.keys.write().unwrap() = keys;
+    }
+
+    // Replaces the keys with those the provider publishes now, returns how
+    // many there are
+    pub async fn fetch_keys(&self) -> Result<usize> {
+        *self.fetched_at.lock().unwrap() = Some(Instant::now());
+        let response = self
+            .client
+            .get(&self.config.jwks_url)
+            .send()
+            .await?
+            .error_for_status()?;
+        let keys: JwkSet = serde_json::from_slice(&response.bytes().await?)?;
+        let count = keys.keys.len();
+        self.set_keys(keys);
+        Ok(count)
+    }
+
+    pub fn verify(&self, token: &str) -> Result<TokenClaims, JwtError> {
+        let header = jsonwebtoken::decode_header(token).map_err(|err| JwtError::Malformed(err.to_string()))?;
+        if !ALGORITHMS.contains(&header.alg) {
+            return Err(JwtError::Algorithm(header.alg));
+        }
+        let kid = header.kid.unwrap_or_default();
+        let key = {
+            let keys = self.keys.read().unwrap();
+            match keys.find(&kid) {
+                Some(jwk) => DecodingKey::from_jwk(jwk).map_err(|err| JwtError::Invalid(err.to_string()))?,
+                None => return Err(JwtError::UnknownKey(kid)),
+            }
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = self.config.leeway_secs;
+        if self.config.issuer.is_empty() {
+            validation.iss = None;
+        } else {
+            validation.set_issuer(&[&self.config.issuer]);
+        }
+        if self.config.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&[&self.config.audience]);
+        }
+        let claims = jsonwebtoken::decode::<Claims>(token, &key, &validation)
+            .map_err(|err| JwtError::Invalid(err.to_string()))?
+            .claims;
+
+        let scopes = claims
+            .scope
+            .iter()
+            .flat_map(|scope| scope.split_whitespace())
+            .chain(claims.scp.iter().map(String::as_str))
+            .filter_map(Scope::parse)
+            .collect();
+        Ok(TokenClaims {
+            sub: claims.sub,
+            scopes,
+        })
+    }
+
+    // Provider keys rotate; a token signed with a new one is refused until
+    // the keys are fetched again, which it triggers
+    fn refetch_soon(self: &Arc<Self>) {
+        {
+            let mut fetched_at = self.fetched_at.lock().unwrap();
+            if matches!(*fetched_at, Some(at) if at.elapsed() < MIN_REFETCH_INTERVAL) {
+                return;
+            }
+            *fetched_at = Some(Instant::now());
+        }
+
+        let verifier = self.clone();
+        actix_rt::spawn(async move {
+            if let Err(err) = verifier.fetch_keys().await {
+                tracing::error!("JWKS fetch error: {}", err);
+            }
+        });
+    }
+}
+
+static VERIFIER: OnceLock<Arc<JwtVerifier>> = OnceLock::new();
+
+// Installed by `http::start` when JWTs are enabled; the first one wins
+pub fn set_verifier(verifier: JwtVerifier) -> Arc<JwtVerifier> {
+    let _ = VERIFIER.set(Arc::new(verifier));
+    VERIFIER.get().cloned().unwrap()
+}
+
+pub fn verifier() -> Option<Arc<JwtVerifier>> {
+    VERIFIER.get().cloned()
+}
+
+// Whether a bearer token looks like a JWT rather than an API key or a guest
+// token: three base64url parts
+pub fn is_jwt(token: &str) -> bool {
+    token.split('.').count() == 3 && token.starts_with("eyJ")
+}
+
+// `None` for tokens that don't verify, which are logged
+pub fn authenticate(token: &str) -> Option<TokenClaims> {
+    let verifier = verifier()?;
+    match verifier.verify(token) {
+        Ok(claims) => Some(claims),
+        Err(err) => {
+            if let JwtError::UnknownKey(_) = err {
+                verifier.refetch_soon();
+            }
+            tracing::warn!("JWT refused: {}", err);
+            None
+        }
+    }
+}
+
+// Fetches the keys every `jwks_refresh_secs` after the first fetch at
+// startup. A provider that is down then doesn't stop the service, tokens
+// are refused until its keys are fetched.
+pub fn spawn_refresher(verifier: Arc<JwtVerifier>) {
+    let period = Duration::from_secs(verifier.config.jwks_refresh_secs.max(60));
+    actix_rt::spawn(async move {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            match verifier.fetch_keys().await {
+                Ok(count) => tracing::debug!("Fetched {} JWT signing key(s)", count),
+                Err(err) => tracing::error!("JWKS fetch error: {}", err),
+            }
+        }
+    });
+}
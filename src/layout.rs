@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::Result;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::finalize;
+use crate::metadata::METADATA_DIR;
+use crate::tenants::{is_valid_tenant, TENANTS_DIR};
+use crate::{is_stored_file_name, is_valid_id, parse_derivative_file_name, STORED_EXTENSIONS};
+
+// Originals and their derivatives live in `uploads_dir/ab/cd/`, two levels
+// of hex digits of the id's hash. Hashed rather than taken from the id, whose
+// instance prefix would put every upload of an instance into one directory.
+// Stored file names stay flat everywhere else (replication manifests, peer
+// copies), `stored_file_path` maps them to disk.
+//
+// Uploads made with a tenant's key get the same shards under the tenant's
+// prefix, `uploads_dir/tenants/{tenant}/ab/cd/`, next to its settings. Ids
+// don't tell the tenant, so the tenants of the ids are kept in memory: read
+// from the metadata at startup, then kept by `metadata::save`.
+
+// Id to tenant, of tenants' uploads only
+fn tenants() -> &'static Mutex<HashMap<String, String>> {
+    static TENANTS: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+    TENANTS.get_or_init(Default::default)
+}
+
+pub fn tenant_of(id: &str) -> Option<String> {
+    tenants().lock().unwrap().get(id).cloned()
+}
+
+// Files of the id are looked for under the tenant's prefix from now on.
+// Kept after the upload is deleted, whatever it left behind is still there.
+pub fn set_tenant(id: &str, tenant: &str) {
+    if !is_valid_tenant(tenant) {
+        tracing::warn!("Upload {} has an invalid tenant {:?}", id, tenant);
+        return;
+    }
+    tenants().lock().unwrap().insert(id.to_owned(), tenant.to_owned());
+}
+
+// Of an id that turned out to be taken by an upload of no tenant
+pub fn forget_tenant(id: &str) {
+    tenants().lock().unwrap().remove(id);
+}
+
+fn tenant_root<P: AsRef<Path>>(uploads_dir: P, tenant: &str) -> PathBuf {
+    uploads_dir.as_ref().join(TENANTS_DIR).join(tenant)
+}
+
+fn hashed_dir(root: &Path, id: &str) -> PathBuf {
+    let hash = hex::encode(&Sha256::digest(id.as_bytes())[..2]);
+    root.join(&hash[..2]).join(&hash[2..])
+}
+
+pub fn shard_dir<P: AsRef<Path>>(uploads_dir: P, id: &str) -> PathBuf {
+    match tenant_of(id) {
+        Some(tenant) => hashed_dir(&tenant_root(uploads_dir, &tenant), id),
+        None => hashed_dir(uploads_dir.as_ref(), id),
+    }
+}
+
+#[derive(Deserialize)]
+struct TenantOnly {
+    #[serde(default)]
+    tenant: Option<String>,
+}
+
+// Reads the tenants of the ids from the metadata. Run at startup, before
+// any file is looked up; returns how many uploads belong to a tenant.
+pub async fn load_tenants<P: AsRef<Path>>(uploads_dir: P) -> Result<usize> {
+    let mut loaded = HashMap::new();
+
+    let mut dir = match tokio::fs::read_dir(uploads_dir.as_ref().join(METADATA_DIR)).await {
+        Ok(dir) => dir,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+    while let Some(entry) = dir.next_entry().await? {
+        let name = entry.file_name();
+        let id = match name.to_str().and_then(|name| name.strip_suffix(".json")) {
+            Some(id) if is_valid_id(id) => id.to_owned(),
+            _ => continue,
+        };
+
+        match serde_json::from_slice::<TenantOnly>(&tokio::fs::read(entry.path()).await?) {
+            Ok(TenantOnly { tenant: Some(tenant) }) if is_valid_tenant(&tenant) => {
+                loaded.insert(id, tenant);
+            }
+            Ok(_) => {}
+            Err(err) => tracing::warn!("Skipping metadata {:?}: {}", entry.path(), err),
+        }
+    }
+
+    let count = loaded.len();
+    tenants().lock().unwrap().extend(loaded);
+    Ok(count)
+}
+
+// For metadata that went around `metadata::save` (replication): records the
+// tenant and moves the id's files already stored outside its prefix
+pub async fn adopt<P: AsRef<Path>>(uploads_dir: P, id: &str, tenant: &str) -> std::io::Result<usize> {
+    if tenant_of(id).as_deref() == Some(tenant) {
+        return Ok(0);
+    }
+    let from = shard_dir(&uploads_dir, id);
+    set_tenant(id, tenant);
+    let to = shard_dir(&uploads_dir, id);
+
+    let mut moved = 0;
+    let mut entries = match tokio::fs::read_dir(&from).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if stored_file_id(&name) != Some(id) {
+            continue;
+        }
+
+        tokio::fs::create_dir_all(&to).await?;
+        // A tenant's directory may be a mount of its own
+        finalize::finalize(entry.path(), to.join(&name), false).await?;
+        moved += 1;
+    }
+
+    Ok(moved)
+}
+
+// Id of an original (`{id}.{ext}`) or of a derivative's original
+fn stored_file_id(name: &str) -> Option<&str> {
+    let mut parts = name.splitn(2, '.');
+    match (parts.next(), parts.next()) {
+        (Some(id), Some(extension)) if is_valid_id(id) && STORED_EXTENSIONS.contains(&extension) => Some(id),
+        _ => parse_derivative_file_name(name),
+    }
+}
+
+// Where a stored file (see `is_stored_file_name`) is on disk; metadata keeps
+// its own directory
+pub fn stored_file_path<P: AsRef<Path>>(uploads_dir: P, name: &str) -> PathBuf {
+    match stored_file_id(name) {
+        Some(id) => shard_dir(&uploads_dir, id).join(name),
+        None => uploads_dir.as_ref().join(name),
+    }
+}
+
+fn is_shard_name(name: &str) -> bool {
+    name.len() == 2 && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+async fn subdirs(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut subdirs = Vec::new();
+
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let is_shard = entry.file_name().to_str().map(is_shard_name).unwrap_or(false);
+        if is_shard && entry.file_type().await?.is_dir() {
+            subdirs.push(entry.path());
+        }
+    }
+
+    Ok(subdirs)
+}
+
+async fn shards(root: &Path, dirs: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for first in subdirs(root).await? {
+        dirs.extend(subdirs(&first).await?);
+    }
+    Ok(())
+}
+
+// Every directory holding uploads: the shards, the tenants' shards, then
+// `uploads_dir` itself for files of the flat layout and temp files
+pub async fn upload_dirs<P: AsRef<Path>>(uploads_dir: P) -> std::io::Result<Vec<PathBuf>> {
+    let uploads_dir = uploads_dir.as_ref();
+    let mut dirs = Vec::new();
+
+    shards(uploads_dir, &mut dirs).await?;
+    match tokio::fs::read_dir(uploads_dir.join(TENANTS_DIR)).await {
+        Ok(mut entries) => {
+            while let Some(entry) = entries.next_entry().await? {
+                let is_tenant = entry.file_name().to_str().map(is_valid_tenant).unwrap_or(false);
+                if is_tenant && entry.file_type().await?.is_dir() {
+                    shards(&entry.path(), &mut dirs).await?;
+                }
+            }
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    dirs.push(uploads_dir.to_owned());
+
+    Ok(dirs)
+}
+
+// Moves originals and derivatives of the flat layout into their shards.
+// Run at startup, before anything is served; returns how many were moved.
+pub async fn migrate_flat_layout<P: AsRef<Path>>(uploads_dir: P) -> std::io::Result<usize> {
+    let uploads_dir = uploads_dir.as_ref();
+    let mut moved = 0;
+
+    let mut entries = tokio::fs::read_dir(uploads_dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if !is_stored_file_name(&name) || !entry.file_type().await?.is_file() {
+            continue;
+        }
+
+        let path = stored_file_path(uploads_dir, &name);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        finalize::finalize(entry.path(), &path, false).await?;
+        moved += 1;
+    }
+
+    Ok(moved)
+}
@@ -1,24 +1,437 @@
+use std::collections::{BTreeMap, HashMap};
 use std::convert::AsRef;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
-use actix_web::http::header;
+use reqwest::header;
 use bytes::Bytes;
-use failure::Fallible;
-use failure_derive::Fail;
+use anyhow::Result;
 use rand::prelude::*;
-use tokio::prelude::*;
-use tokio::stream::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use futures_util::stream::{Stream, StreamExt};
+use sha2::{Digest, Sha256};
 
 //обработка изображения
 pub mod imagetools;
+// импорт по манифесту (CSV/JSONL)
+pub mod import;
+// докачка по протоколу tus
+pub mod tus;
+// заголовки безопасности для всех ответов
+pub mod security;
+// потоковое декодирование base64
+pub mod base64_stream;
+// потоковый разбор JSON-загрузок
+pub mod json_stream;
+// отдача сохранённых файлов
+pub mod serve;
+// распределение работы между узлами
+pub mod cluster;
+// тёплый резерв
+pub mod replication;
+// проверки для /healthz и /readyz
+pub mod health;
+// авторство в производных изображениях
+pub mod attribution;
+// корректное завершение: ожидание загрузок и уборка временных файлов
+pub mod shutdown;
+// периодическая уборка осиротевших файлов
+pub mod cleanup;
+// ключи API
+pub mod auth;
+// гостевые временные корзины
+pub mod guest;
+// HMAC-подписи: одноразовые presigned-ссылки и ссылки на скачивание
+pub mod signing;
+// метаданные загрузок (срок хранения и т.п.)
+pub mod metadata;
+// сокеты: HTTP, HTTPS, UDS
+pub mod listeners;
+// разбор Content-Type для POST /upload
+pub mod negotiate;
+// премодерация загрузок
+pub mod moderation;
+// проверка загрузок антивирусом ClamAV
+pub mod antivirus;
+// распознавание текста Tesseract
+pub mod ocr;
+// трансформации по подписанным URL
+pub mod transform;
+// производные файлы и их инвалидация
+pub mod derivatives;
+// происхождение копий и отредактированных изображений
+pub mod provenance;
+// фоновая очередь миниатюр
+pub mod thumbnails;
+// ограничения загрузок по типу файла
+pub mod limits;
+// адаптивный предел одновременных загрузок
+pub mod concurrency;
+// отпечатки TLS-клиентов для разбора злоупотреблений
+pub mod fingerprint;
+// раскладка загрузок по подкаталогам
+pub mod layout;
+// генераторы идентификаторов загрузок
+pub mod ids;
+// свои страницы ошибок
+pub mod error_pages;
+// настройки арендаторов
+pub mod tenants;
+// возможности сборки и конфигурации для клиентов
+pub mod capabilities;
+// детерминированный режим: сид и фиксированные часы
+pub mod deterministic;
+// часы сервиса с перемоткой вперёд
+pub mod clock;
+// CORS для браузерных клиентов
+pub mod cors;
+// методы маршрутов для OPTIONS и заголовка Allow
+pub mod methods;
+// счётчики для мониторинга
+pub mod metrics;
+// квоты на место на диске
+pub mod quota;
+// список блокировки загрузок по отпечаткам
+pub mod blocklist;
+// сводка для администраторов
+pub mod stats;
+// внешние хранилища объектов
+pub mod storage;
+// шифрование оригиналов на диске
+pub mod encryption;
+// перенос готовых файлов на место, в том числе между разделами
+pub mod finalize;
+// журнал незавершённых загрузок для строгой надёжности
+pub mod journal;
+// повторы загрузок по Idempotency-Key
+pub mod idempotency;
+// аутентификация по JWT с областями доступа
+pub mod jwt;
+// журнал аудита изменяющих запросов
+pub mod audit;
+// структурированные логи, идентификаторы запросов и трассировка
+pub mod telemetry;
+// перенос давно не запрошенных оригиналов в холодное хранилище
+pub mod tiering;
+// описание API в OpenAPI и страница Swagger UI
+pub mod openapi;
+// подборки загрузок и их контактные листы
+pub mod collections;
+// правила приёма загрузок
+pub mod policy;
+// ошибки в ответах API: статус, код и сообщение
+pub mod error;
+// маршруты и обработчики HTTP, для встраивания в своё приложение actix
+pub mod http;
+// повторные попытки скачивания по ссылке
+pub mod retry;
+// прокси и пересылаемые заголовки при скачивании по ссылке
+pub mod fetch;
+
+// Path to a TOML config file, every key is optional
+pub const CONFIG_ENV_VAR: &str = "RR_API_CONFIG";
 
 //юазовые настройки хоста, для сохранения изображений
-#[derive(Clone)]
+#[derive(Clone, Deserialize)]
+#[serde(default)]
 pub struct Config {
+    // Prefix of every generated image id, must differ between instances
+    // sharing ids (e.g. a primary and its standby)
+    pub instance_id: String,
+    // Follows `instance_id` in generated image ids
+    #[serde(default = "ids::default_id_scheme")]
+    pub id_scheme: ids::IdScheme,
     pub host: String,
     pub port: u16,
     pub uploads_dir: PathBuf,
+    // Replaces host/port when set, see `Config::listeners`
+    pub listeners: Vec<listeners::ListenerConfig>,
+    // Logs a TLS client fingerprint with each upload over HTTPS, see
+    // `fingerprint::fingerprint`; kept in memory only, off by default
+    pub log_tls_fingerprints: bool,
+    // Of JSON and form bodies; the `base64` values of a JSON upload don't
+    // count, they're held to the file size limits instead
     pub max_json_payload_size: usize,
+    pub max_manifest_size: usize,
+    pub max_manifest_rows: usize,
+    // Output options a manifest row may name in its `preset` column; a row
+    // naming another preset fails
+    pub import_presets: HashMap<String, OutputOptions>,
+    pub max_tus_upload_size: u64,
+    // Bytes one file of POST /upload may have, and all files of a request
+    // together; 0 is unlimited. See `ByteLimit`.
+    pub max_file_size: u64,
+    pub max_request_size: u64,
+    // Overrides for the default security headers, "" removes a header
+    pub security_headers: BTreeMap<String, String>,
+    // Per MIME type serving rules, on top of `serve::default_serve_policy`
+    pub serve_policies: HashMap<String, serve::ServePolicy>,
+    // Custom 4xx/5xx bodies of GET /images/... by status code, e.g. `[error_pages.404]`
+    pub error_pages: HashMap<String, error_pages::ErrorPageConfig>,
+    // Remove EXIF/XMP/IPTC (GPS included) before storing, `?strip_metadata=` overrides
+    pub strip_metadata: bool,
+    // Blurhash placeholder in the metadata of each upload, see `imagetools::blurhash`
+    pub blurhash: bool,
+    // Colors kept in the metadata of each upload, see
+    // `imagetools::dominant_colors`; 0 turns them off
+    pub dominant_colors: usize,
+    // Default and upper bound of `max_distance` in GET /images/{id}/similar,
+    // in bits of `imagetools::perceptual_hash`
+    pub similar_max_distance: u32,
+    // Decompression bomb guard, checked against the file header before decoding
+    pub max_image_width: u32,
+    pub max_image_height: u32,
+    pub max_image_pixels: u64,
+    // /readyz fails below this many free bytes on the uploads volume
+    pub min_free_disk_space: u64,
+    // Thresholds of GET /lb-health, see `health::load`
+    pub lb_health: health::LbHealthConfig,
+    // Written into generated derivatives as XMP
+    pub attribution: attribution::AttributionConfig,
+    // How long SIGTERM waits for in-flight requests and uploads
+    pub shutdown_timeout_secs: u64,
+    // Background orphan cleanup, 0 disables it
+    pub orphan_cleanup_interval_secs: u64,
+    // Temp files and orphaned thumbnails younger than this are left alone
+    pub orphan_max_age_secs: u64,
+    // Uploads require one of these as a bearer token, empty leaves them open
+    // unless `jwt` is enabled
+    pub api_keys: Vec<auth::ApiKey>,
+    // Bearer JWTs of an identity provider, next to or instead of the keys
+    pub jwt: jwt::JwtConfig,
+    // How long tenant overrides are cached, see `tenants::TenantStore`
+    pub tenant_cache_ttl_secs: u64,
+    pub guest: guest::GuestConfig,
+    // HMAC key for presigned upload and download URLs, empty disables them
+    pub signing_key: String,
+    pub presign_max_ttl_secs: u64,
+    // Uploads and their derivatives only with `?expires=&sig=`, see
+    // `signing::sign_download_url`
+    pub require_signed_downloads: bool,
+    pub max_download_url_ttl_secs: u64,
+    // Upper bound for `?ttl=` on uploads
+    pub max_upload_ttl_secs: u64,
+    pub expiry_reap_interval_secs: u64,
+    // Background thumbnail generation, see `thumbnails::ThumbnailQueue`
+    pub thumbnail_workers: usize,
+    pub thumbnail_queue_size: usize,
+    // Encoding of every thumbnail, `source` keeps the original's format
+    #[serde(default = "thumbnails::default_thumbnail_format")]
+    pub thumbnail_format: thumbnails::ThumbnailFormat,
+    // JPEG, WebP or AVIF quality of thumbnails, the encoder's default if unset
+    pub thumbnail_quality: Option<u8>,
+    // Thumbnails are cropped to their square, kept centered or over the
+    // faces and busy parts with `smart`; unset squeezes the whole image in
+    pub thumbnail_crop: Option<imagetools::Gravity>,
+    // Upper bound for holding an upload response with `?wait=processed`
+    pub upload_wait_timeout_secs: u64,
+    // New uploads are `processing`, not served to clients, until their
+    // background thumbnail is done
+    pub hold_until_processed: bool,
+    // Syncs the directory of each stored upload before answering, see
+    // `finalize`; slower, for storage that may lose power
+    pub fsync_dir: bool,
+    // `strict` syncs uploads and their directory before answering, and
+    // journals them while in progress, see `journal`; implies `fsync_dir`
+    pub durability: finalize::Durability,
+    // For GET /images/{id}/faces and `blur_faces` uploads
+    pub faces: imagetools::FaceConfig,
+    // For GET /images/{id}/ocr
+    pub ocr: ocr::OcrConfig,
+    // MP4 and WebM uploads
+    pub video: imagetools::VideoConfig,
+    // Per MIME type upload limits, on top of the global ones
+    pub type_limits: HashMap<String, limits::TypeLimits>,
+    // Upload slots (503 with Retry-After when taken) and image processing slots
+    pub concurrency: concurrency::ConcurrencyConfig,
+    pub moderation: moderation::ModerationConfig,
+    pub antivirus: antivirus::AntivirusConfig,
+    // Of stored originals
+    pub encryption: encryption::EncryptionConfig,
+    // Retried uploads with an `Idempotency-Key`
+    pub idempotency: idempotency::IdempotencyConfig,
+    // Who changed what, see `audit`
+    pub audit: audit::AuditConfig,
+    // Log format and span export, see `telemetry`
+    pub telemetry: telemetry::TelemetryConfig,
+    pub transform: transform::TransformConfig,
+    pub cluster: cluster::ClusterConfig,
+    pub replication: replication::ReplicationConfig,
+    // Seeded ids and a frozen clock, for tests only
+    pub deterministic: deterministic::DeterministicConfig,
+    // Allows moving the clock forward with POST /clock/advance, see
+    // `clock::advance`; for tests and debugging expiry only
+    pub time_travel: bool,
+    pub cors: cors::CorsConfig,
+    pub quota: quota::QuotaConfig,
+    pub blocklist: blocklist::BlocklistConfig,
+    pub tiering: tiering::TieringConfig,
+    pub openapi: openapi::OpenApiConfig,
+    pub collections: collections::CollectionsConfig,
+    pub policy: policy::PolicyConfig,
+    // Of GET requests for URL uploads and imports
+    pub fetch_retry: retry::RetryConfig,
+    pub fetch: fetch::FetchConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            instance_id: String::new(),
+            id_scheme: ids::default_id_scheme(),
+            host: "0.0.0.0".into(),
+            port: 8080,
+            uploads_dir: "/tmp/uploads".into(),
+            listeners: Vec::new(),
+            log_tls_fingerprints: false,
+            max_json_payload_size: 1 << 20,
+            max_manifest_size: 4 << 20,
+            max_manifest_rows: 10_000,
+            import_presets: HashMap::new(),
+            max_tus_upload_size: 256 << 20,
+            max_file_size: 256 << 20,
+            max_request_size: 1 << 30,
+            security_headers: BTreeMap::new(),
+            serve_policies: HashMap::new(),
+            error_pages: HashMap::new(),
+            strip_metadata: false,
+            blurhash: true,
+            dominant_colors: 5,
+            similar_max_distance: 10,
+            max_image_width: 20_000,
+            max_image_height: 20_000,
+            max_image_pixels: 50_000_000,
+            min_free_disk_space: 512 << 20,
+            lb_health: Default::default(),
+            attribution: Default::default(),
+            shutdown_timeout_secs: 30,
+            orphan_cleanup_interval_secs: 3600,
+            orphan_max_age_secs: 24 * 3600,
+            api_keys: Vec::new(),
+            jwt: Default::default(),
+            tenant_cache_ttl_secs: 30,
+            guest: Default::default(),
+            signing_key: String::new(),
+            presign_max_ttl_secs: 3600,
+            require_signed_downloads: false,
+            max_download_url_ttl_secs: 7 * 24 * 3600,
+            max_upload_ttl_secs: 30 * 24 * 3600,
+            expiry_reap_interval_secs: 60,
+            thumbnail_workers: 2,
+            thumbnail_queue_size: 1000,
+            thumbnail_format: thumbnails::default_thumbnail_format(),
+            thumbnail_quality: None,
+            thumbnail_crop: None,
+            upload_wait_timeout_secs: 30,
+            hold_until_processed: false,
+            fsync_dir: false,
+            durability: Default::default(),
+            faces: Default::default(),
+            ocr: Default::default(),
+            video: Default::default(),
+            type_limits: HashMap::new(),
+            concurrency: Default::default(),
+            moderation: Default::default(),
+            antivirus: Default::default(),
+            encryption: Default::default(),
+            idempotency: Default::default(),
+            audit: Default::default(),
+            telemetry: Default::default(),
+            transform: Default::default(),
+            cluster: Default::default(),
+            replication: Default::default(),
+            deterministic: Default::default(),
+            time_travel: false,
+            cors: Default::default(),
+            quota: Default::default(),
+            blocklist: Default::default(),
+            tiering: Default::default(),
+            openapi: Default::default(),
+            collections: Default::default(),
+            policy: Default::default(),
+            fetch_retry: Default::default(),
+            fetch: Default::default(),
+        }
+    }
+}
+
+impl Config {
+    // Reads the file named by `RR_API_CONFIG`, falling back to defaults
+    pub fn load() -> Result<Config> {
+        Config::load_from(std::env::var_os(CONFIG_ENV_VAR).map(PathBuf::from).as_deref())
+    }
+
+    // Reads and checks a config file, the defaults without one
+    pub fn load_from(path: Option<&Path>) -> Result<Config> {
+        let config: Config = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)?;
+                toml::from_str(&text)?
+            }
+            None => Config::default(),
+        };
+
+        if !config.instance_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(anyhow::anyhow!("instance_id must be alphanumeric"));
+        }
+        if config.replication.role == replication::Role::Standby
+            && (config.instance_id.is_empty() || config.replication.primary_url.is_empty())
+        {
+            return Err(anyhow::anyhow!("a standby needs instance_id and replication.primary_url"));
+        }
+        if config.require_signed_downloads && config.signing_key.is_empty() {
+            return Err(anyhow::anyhow!("require_signed_downloads needs signing_key"));
+        }
+        if config.transform.enabled && config.transform.require_signature && config.signing_key.is_empty() {
+            return Err(anyhow::anyhow!("signed transformations need signing_key"));
+        }
+        let thumbnail_extension = config.thumbnail_format.extension("jpg");
+        if !imagetools::can_encode(thumbnail_extension) {
+            return Err(anyhow::anyhow!("this build can't encode {} thumbnails", thumbnail_extension));
+        }
+        if matches!(config.thumbnail_quality, Some(quality) if quality == 0 || quality > 100) {
+            return Err(anyhow::anyhow!("thumbnail_quality must be within 1-100"));
+        }
+        if config.fetch_retry.max_attempts == 0 {
+            return Err(anyhow::anyhow!("fetch_retry.max_attempts must be at least 1"));
+        }
+        config.fetch.client().map_err(|err| anyhow::anyhow!("fetch: {}", err))?;
+        if !config.antivirus.clamd.is_empty() && antivirus::Address::parse(&config.antivirus.clamd).is_none() {
+            return Err(anyhow::anyhow!("antivirus.clamd must be tcp://host:port or unix:/path"));
+        }
+        if config.encryption.enabled {
+            encryption::Keyring::from_config(&config.encryption)
+                .map_err(|err| anyhow::anyhow!("encryption: {}", err))?;
+        }
+        if config.video.enabled && !imagetools::VIDEO {
+            return Err(anyhow::anyhow!("this build can't read video, see video.enabled"));
+        }
+
+        Ok(config)
+    }
+
+    // The configured listeners, or a single HTTP one on host:port serving
+    // every route
+    pub fn listeners(&self) -> Vec<listeners::ListenerConfig> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+
+        vec![listeners::ListenerConfig {
+            kind: listeners::ListenerKind::Http,
+            address: format!("{}:{}", self.host, self.port),
+            routes: listeners::RouteSet::All,
+            tls_cert: None,
+            tls_key: None,
+            tls_reload_secs: 0,
+            redirect_to_https: None,
+            listen_fd: None,
+            unix_mode: None,
+        }]
+    }
 }
 
 // успешное сохранение
@@ -26,190 +439,1394 @@ pub struct UploadedFile {
     pub id: String,
     pub path: PathBuf,
     pub thumbnail_path: Option<PathBuf>,
+    // Handed to the thumbnail queue, `thumbnail_path` is `None` until it's done
+    pub thumbnail_pending: bool,
+    // Not asked for, see `UploadOptions::make_thumbnail`
+    pub thumbnail_skipped: bool,
+    pub blurhash: Option<String>,
+    pub dominant_colors: Vec<String>,
+    pub source_url: Option<String>,
+    // Key id and nonce the original was sealed with, see `encryption`
+    pub encryption: Option<encryption::Envelope>,
+    // Hex SHA-256 of the bytes as received; the metadata `checksum` is of
+    // the stored file, which fixes such as `strip_metadata` may have changed
+    pub sha256: String,
 }
 
-// ошибка при записи файла
-#[derive(Debug, Fail)]
+// ошибка при записи файла; see `error::ApiError` for what clients get
+#[derive(Debug, thiserror::Error)]
 pub enum UploadError {
-    #[fail(display = "Client error: {}", 0)]
-    Client(failure::Error),
-    #[fail(display = "Server error: {}", 0)]
-    Server(failure::Error),
+    // The request body broke off or couldn't be read
+    #[error("Error reading the upload: {0}")]
+    Body(anyhow::Error),
+    #[error("Unrecognized image header")]
+    UnrecognizedImage,
+    #[error("Unsupported media type {0}")]
+    UnsupportedType(String),
+    #[error("Image is {0}x{1} pixels, which exceeds the configured limit")]
+    TooLarge(u32, u32),
+    #[error("{0} uploads are not allowed")]
+    NotAllowed(String),
+    #[error("Upload exceeds the limit of {0} bytes")]
+    BodyTooLarge(u64),
+    #[error("Upload SHA-256 is {actual}, {expected} was expected")]
+    ChecksumMismatch { expected: String, actual: String },
+    #[error(transparent)]
+    Storage(#[from] storage::StorageError),
+    // Decoding or fixing up a file that passed the header checks
+    #[error("Image processing error: {0}")]
+    Processing(anyhow::Error),
+}
+
+impl From<std::io::Error> for UploadError {
+    fn from(err: std::io::Error) -> Self {
+        UploadError::Storage(err.into())
+    }
 }
 
-#[derive(Debug, Fail)]
+// Ошибка при скачивании по ссылке
+#[derive(Debug, thiserror::Error)]
 pub enum FetchError {
-    #[fail(display = "Server returned non-success code")]
-    ServerReturnedError,
-    #[fail(display = "Server returned unsupported media type")]
-    UnsupportedMediaType,
-    #[fail(display = "Fetch failed: {}", 0)]
-    FetchError(reqwest::Error),
+    #[error("Server returned {0}")]
+    Status(u16),
+    #[error("Server returned unsupported media type {0:?}")]
+    UnsupportedType(String),
+    #[error("Fetch failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Invalid URL {0:?}")]
+    InvalidUrl(String),
+    #[error("Fetching {0} is not allowed")]
+    UrlNotAllowed(String),
+    #[error("Can't resolve {0}")]
+    Unresolvable(String),
+    #[error("More than {0} redirects")]
+    TooManyRedirects(usize),
+    #[error("Header {0:?} may not be forwarded")]
+    HeaderNotAllowed(String),
+    #[error("Invalid header {0:?}")]
+    InvalidHeader(String),
+    // The error of the last attempt, see `retry::RetryConfig`
+    #[error("{last} (after {attempts} attempts)")]
+    GaveUp { attempts: u32, last: Box<FetchError> },
 }
 
 pub fn mime_type_to_extension(mime_type: &str) -> Option<&'static str> {
     match mime_type {
         "image/bmp" => Some("bmp"),
-        "image/jpeg" => Some("jpg"),
+        "image/jpeg" | "image/jpg" => Some("jpg"),
         "image/png" => Some("png"),
+        "image/gif" => Some("gif"),
+        "image/webp" => Some("webp"),
+        "image/svg+xml" => Some("svg"),
+        "video/mp4" => Some("mp4"),
+        "video/webm" => Some("webm"),
         _ => None,
     }
 }
 
+pub fn extension_to_mime_type(extension: &str) -> Option<&'static str> {
+    match extension {
+        "bmp" => Some("image/bmp"),
+        "jpg" => Some("image/jpeg"),
+        "png" => Some("image/png"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        "avif" => Some("image/avif"),
+        "svg" => Some("image/svg+xml"),
+        "mp4" => Some("video/mp4"),
+        "webm" => Some("video/webm"),
+        _ => None,
+    }
+}
+
+// Extensions an upload may be stored with. Not every backend encodes all of
+// them, see `imagetools::can_encode`; GIF and WebP may be animated. SVG is
+// stored sanitized and rasterized for everything derived from it, MP4 and
+// WebM only with `video.enabled`.
+pub const STORED_EXTENSIONS: &[&str] = &["jpg", "png", "bmp", "gif", "webp", "svg", "mp4", "webm"];
+
+// Derivatives may also be in formats that are only ever encoded, see
+// `thumbnails::ThumbnailFormat`
+pub const DERIVATIVE_EXTENSIONS: &[&str] = &["jpg", "png", "bmp", "gif", "webp", "avif"];
+
+// Ids are generated alphanumeric, anything else can't name a stored file
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+pub fn thumbnail_file_name(id: &str, extension: &str) -> String {
+    format!("{}_thumbnail.{}", id, extension)
+}
+
+// Originals, derivatives and metadata (relative to uploads_dir), the files
+// replicated to a standby
+pub fn is_stored_file_name(name: &str) -> bool {
+    if let Some(metadata_name) = name.strip_prefix("meta/") {
+        return metadata_name
+            .strip_suffix(".json")
+            .map(is_valid_id)
+            .unwrap_or(false);
+    }
+
+    let mut parts = name.splitn(2, '.');
+    match (parts.next(), parts.next()) {
+        (Some(id), Some(extension)) if is_valid_id(id) => STORED_EXTENSIONS.contains(&extension),
+        _ => is_derivative_file_name(name),
+    }
+}
+
+// Names of the files peers may copy from each other
+pub fn is_derivative_file_name(name: &str) -> bool {
+    parse_derivative_file_name(name).is_some()
+}
+
+// Id of the original a derivative was made from: a thumbnail, or a cached
+// transformation (`{id}_t{hash}.{ext}`, see `transform::TransformSpec`)
+pub fn parse_derivative_file_name(name: &str) -> Option<&str> {
+    let mut parts = name.rsplitn(2, '.');
+    let (extension, stem) = (parts.next()?, parts.next()?);
+    if !DERIVATIVE_EXTENSIONS.contains(&extension) {
+        return None;
+    }
+
+    let id = match stem.strip_suffix("_thumbnail") {
+        Some(id) => id,
+        None => {
+            let mut parts = stem.rsplitn(2, "_t");
+            let (hash, id) = (parts.next()?, parts.next()?);
+            if hash.len() != 16 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            id
+        }
+    };
+
+    if is_valid_id(id) {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+// Intermediate files of the upload pipeline, the replication and the peer
+// cache fill; never served, so anything left over is an orphan
+pub fn is_temp_file_name(name: &str) -> bool {
+    const SUFFIXES: &[&str] = &[
+        ".tmp", ".strip", ".xmp", ".fill", ".replica", ".restore", ".svg-clean", ".seal", ".finalize",
+    ];
+    SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+        || name.contains(".orient.")
+        || name.contains(".plain.")
+        || name.starts_with(".readyz-")
+}
+
+// Locates a stored original by id, returns its path and extension
+pub async fn find_upload<P: AsRef<Path>>(uploads_dir: P, id: &str) -> Option<(PathBuf, &'static str)> {
+    if !is_valid_id(id) {
+        return None;
+    }
+
+    let dir = layout::shard_dir(uploads_dir, id);
+    for extension in STORED_EXTENSIONS {
+        let mut path = dir.join(id);
+        path.set_extension(extension);
+
+        if tokio::fs::metadata(&path).await.is_ok() {
+            return Some((path, extension));
+        }
+    }
+
+    None
+}
+
+// Removes an original and everything derived from it, returns whether the
+// original existed
+pub async fn delete_upload<P: AsRef<Path>>(uploads_dir: P, id: &str) -> Result<bool, storage::StorageError> {
+    // Gone for clients even if removing the files breaks off; deleting
+    // again finishes the job
+    metadata::mark_deleted(&uploads_dir, id).await?;
+
+    let (path, _) = match find_upload(&uploads_dir, id).await {
+        Some(found) => found,
+        None => {
+            // Derivatives of an archived original stay here
+            let archived = tiering::archived(&uploads_dir, id).await;
+            if let Some(image_metadata) = &archived {
+                derivatives::invalidate(&uploads_dir, id).await?;
+                tiering::delete_archived(image_metadata).await?;
+            }
+            metadata::remove(&uploads_dir, id).await?;
+            return Ok(archived.is_some());
+        }
+    };
+
+    derivatives::invalidate(&uploads_dir, id).await?;
+    tokio::fs::remove_file(&path).await?;
+    metadata::remove(&uploads_dir, id).await?;
+
+    Ok(true)
+}
+
+// Blocking, creates the thumbnail and tags it with the attribution XMP
+pub(crate) fn write_thumbnail(
+    upload_path: &Path,
+    thumbnail_path: &Path,
+    quality: Option<u8>,
+    crop: Option<imagetools::Gravity>,
+    xmp: Option<String>,
+) -> imagetools::Result<()> {
+    let res = imagetools::create_thumbnail(upload_path, thumbnail_path, (100, 100), quality, crop);
+    metrics::thumbnail(res.is_ok());
+    res?;
+
+    if let Some(xmp) = xmp {
+        if let Err(err) = imagetools::embed_xmp(thumbnail_path, &xmp) {
+            tracing::warn!("Error embedding attribution: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+// Perceptual hash of a stored upload: the stored one, or computed from the
+// file for uploads made before hashes were stored. `None` if there is no
+// such upload.
+pub async fn perceptual_hash_of<P: AsRef<Path>>(
+    uploads_dir: P,
+    id: &str,
+    image_metadata: Option<&metadata::ImageMetadata>,
+) -> Result<Option<u64>> {
+    if let Some(hash) = image_metadata.and_then(metadata::ImageMetadata::perceptual_hash) {
+        return Ok(Some(hash));
+    }
+
+    let (path, _) = match find_upload(&uploads_dir, id).await {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let preview = concurrency::run_blocking(move || imagetools::preview(path)).await??;
+    Ok(Some(imagetools::perceptual_hash(&preview)))
+}
+
+// A derivative of an upload as stored, see `derivatives::list`
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct VariantInfo {
+    pub kind: derivatives::DerivativeKind,
+    pub format: String,
+    // `None` for formats `imagetools::image_dimensions` can't read (AVIF)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    pub size: u64,
+}
+
+// Everything about a stored upload that can be told without decoding it
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ImageInfo {
+    pub id: String,
+    pub mime: Option<&'static str>,
+    // Format, upright dimensions, color depth and EXIF
+    #[serde(flatten)]
+    pub probe: Option<imagetools::Probe>,
+    pub size: u64,
+    // Hex SHA-256, as in `metadata::ImageMetadata::checksum`
+    pub checksum: String,
+    // Thumbnails and cached transformations on disk now
+    pub variants: Vec<VariantInfo>,
+}
+
+// `None` if there is no such upload. The checksum is computed for uploads
+// stored before checksums were.
+pub async fn image_info<P: AsRef<Path>>(
+    uploads_dir: P,
+    id: &str,
+    image_metadata: Option<&metadata::ImageMetadata>,
+) -> Result<Option<ImageInfo>> {
+    let (path, extension) = match find_upload(&uploads_dir, id).await {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let checksum = image_metadata.and_then(|image_metadata| image_metadata.checksum.clone());
+    let (probe, checksum, size) = tokio::task::spawn_blocking(move || -> std::io::Result<_> {
+        // Of the original as uploaded, not as sealed
+        let path = encryption::plaintext(&path)?;
+        let checksum = match checksum {
+            Some(checksum) => checksum,
+            None => metadata::file_checksum(&*path)?,
+        };
+        Ok((imagetools::probe(&*path)?, checksum, std::fs::metadata(&*path)?.len()))
+    })
+    .await??;
+
+    let mut variants = Vec::new();
+    for derivative in derivatives::list(&uploads_dir, id).await? {
+        let path = layout::stored_file_path(&uploads_dir, &derivative.file_name);
+        let size = match tokio::fs::metadata(&path).await {
+            Ok(file_metadata) => file_metadata.len(),
+            // Invalidated meanwhile
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let dimensions = tokio::task::spawn_blocking(move || imagetools::image_dimensions(path)).await?.ok().flatten();
+        variants.push(VariantInfo {
+            kind: derivative.kind,
+            format: derivative.file_name.rsplit('.').next().unwrap_or_default().to_owned(),
+            width: dimensions.map(|(width, _)| width),
+            height: dimensions.map(|(_, height)| height),
+            size,
+        });
+    }
+
+    Ok(Some(ImageInfo {
+        id: id.to_owned(),
+        mime: extension_to_mime_type(extension),
+        probe,
+        size,
+        checksum,
+        variants,
+    }))
+}
+
+// The faces in a stored upload, `None` if it isn't here (archived or gone)
+pub async fn detect_faces<P: AsRef<Path>>(
+    uploads_dir: P,
+    id: &str,
+    config: &imagetools::FaceConfig,
+) -> Result<Option<Vec<imagetools::Face>>> {
+    let (path, _) = match find_upload(&uploads_dir, id).await {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let config = config.clone();
+    let faces = concurrency::run_blocking(move || imagetools::detect_faces(path, &config)).await??;
+    Ok(Some(faces))
+}
+
+// Returns the thumbnail of a locally stored upload and the thumbnail's
+// extension. A missing one is copied from a peer, or regenerated if no peer
+// has it.
+pub async fn ensure_thumbnail<P: AsRef<Path>>(
+    uploads_dir: P,
+    cluster: &cluster::Cluster,
+    id: &str,
+    options: &UploadOptions,
+) -> Option<(PathBuf, &'static str)> {
+    let (upload_path, source_extension) = find_upload(&uploads_dir, id).await?;
+    let extension = options.thumbnail_format.extension(source_extension);
+
+    let file_name = thumbnail_file_name(id, extension);
+    let thumbnail_path = upload_path.with_file_name(&file_name);
+    if tokio::fs::metadata(&thumbnail_path).await.is_ok() {
+        return Some((thumbnail_path, extension));
+    }
+
+    if cluster.fill_from_peers(id, &file_name, &thumbnail_path).await {
+        return Some((thumbnail_path, extension));
+    }
+
+    tracing::debug!("Regenerating thumbnail {}", thumbnail_path.to_str().unwrap_or("?"));
+    let thumbnail_path_clone = thumbnail_path.clone();
+    let (quality, crop, xmp) = (
+        options.thumbnail_quality,
+        options.thumbnail_crop,
+        options.attribution.xmp_packet(id),
+    );
+    let res = concurrency::run_blocking(move || {
+        write_thumbnail(&upload_path, &thumbnail_path_clone, quality, crop, xmp)
+    })
+    .await
+    .ok()?;
+
+    match res {
+        Ok(_) => Some((thumbnail_path, extension)),
+        Err(err) => {
+            tracing::warn!("Error creating thumbnail: {}", err);
+            None
+        }
+    }
+}
+
 pub fn gen_rand_id(len: usize) -> String {
-    let mut rng = thread_rng();
+    deterministic::with_rng(|rng| {
+        (0..len)
+            .map(|_| rng.sample(rand::distributions::Alphanumeric))
+            .take(len)
+            .collect()
+    })
+}
+
+// Per-upload processing settings: config defaults plus request overrides
+#[derive(Debug, Clone)]
+pub struct UploadOptions {
+    pub id_prefix: String,
+    pub id_scheme: ids::IdScheme,
+    pub strip_metadata: bool,
+    pub blurhash: bool,
+    pub dominant_colors: usize,
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_pixels: u64,
+    pub attribution: attribution::AttributionConfig,
+    // Seconds until the upload is deleted, `None` keeps it forever
+    pub ttl: Option<u64>,
+    // Unix time the upload becomes public at, `None` publishes it right away
+    pub publish_at: Option<u64>,
+    // API key name of the uploader, may see it before `publish_at`
+    pub owner: Option<String>,
+    // Of the uploader's API key, for `policy`; the upload is stored under
+    // the tenant's prefix
+    pub tenant: Option<String>,
+    pub provenance: Option<provenance::Provenance>,
+    // Stored in the metadata as is, see `metadata::sanitize_filename` and
+    // `metadata::check_custom`
+    pub original_filename: Option<String>,
+    pub custom_metadata: BTreeMap<String, String>,
+    // Of an imported manifest row, stored in the metadata
+    pub tags: Vec<String>,
+    // Where a `url` upload was fetched from, after redirects
+    pub source_url: Option<String>,
+    // MIME types that may be uploaded, any stored type when `None`
+    pub allowed_types: Option<Vec<String>>,
+    // Re-encode to this stored extension, see `OutputOptions`
+    pub output_format: Option<&'static str>,
+    // JPEG quality of a re-encoding, forces one for JPEG uploads
+    pub quality: Option<u8>,
+    // Off: no thumbnail until GET /images/{id}/thumbnail asks for it
+    pub make_thumbnail: bool,
+    // Blurs the faces in the stored image, see `imagetools::blur_faces`
+    pub blur_faces: bool,
+    pub faces: imagetools::FaceConfig,
+    // Clips are refused while off
+    pub video: imagetools::VideoConfig,
+    pub thumbnail_format: thumbnails::ThumbnailFormat,
+    pub thumbnail_quality: Option<u8>,
+    pub thumbnail_crop: Option<imagetools::Gravity>,
+    // Thumbnails are made in the background when set, inline otherwise
+    pub thumbnails: Option<thumbnails::ThumbnailQueue>,
+    pub type_limits: HashMap<String, limits::TypeLimits>,
+    // Counts `uploads_per_minute`, unlimited when unset
+    pub type_throttle: Option<limits::TypeThrottle>,
+    // Enabled: uploads stay pending until approved
+    pub moderation: moderation::ModerationConfig,
+    pub antivirus: antivirus::AntivirusConfig,
+    // Seals the stored original when set; the one `http::start` installed,
+    // so whatever reads originals back can open them
+    pub encryption: Option<std::sync::Arc<encryption::Keyring>>,
+    // Keeps an upload `processing` until its background thumbnail is done
+    pub hold_until_processed: bool,
+    // See `Config::fsync_dir`
+    pub fsync_dir: bool,
+    pub durability: finalize::Durability,
+    // Bytes the client may stream, unlimited for files already on disk
+    pub byte_limit: Option<ByteLimit>,
+    // Hex SHA-256 the received bytes must have, see `parse_sha256`
+    pub expected_sha256: Option<String>,
+    // Checked against `owner`'s usage and the instance's
+    pub quota: quota::QuotaConfig,
+    // Of `owner`'s API key, see `quota::check_key`
+    pub key_limits: Option<auth::KeyLimits>,
+    pub blocklist: blocklist::BlocklistConfig,
+}
+
+impl UploadOptions {
+    pub fn from_config(config: &Config) -> UploadOptions {
+        UploadOptions {
+            id_prefix: config.instance_id.clone(),
+            id_scheme: config.id_scheme,
+            strip_metadata: config.strip_metadata,
+            blurhash: config.blurhash,
+            dominant_colors: config.dominant_colors,
+            max_width: config.max_image_width,
+            max_height: config.max_image_height,
+            max_pixels: config.max_image_pixels,
+            attribution: config.attribution.clone(),
+            ttl: None,
+            publish_at: None,
+            owner: None,
+            tenant: None,
+            provenance: None,
+            original_filename: None,
+            custom_metadata: BTreeMap::new(),
+            tags: Vec::new(),
+            source_url: None,
+            allowed_types: None,
+            output_format: None,
+            quality: None,
+            make_thumbnail: true,
+            blur_faces: false,
+            faces: config.faces.clone(),
+            video: config.video.clone(),
+            thumbnail_format: config.thumbnail_format,
+            thumbnail_quality: config.thumbnail_quality,
+            thumbnail_crop: config.thumbnail_crop,
+            thumbnails: None,
+            type_limits: config.type_limits.clone(),
+            type_throttle: None,
+            moderation: config.moderation.clone(),
+            antivirus: config.antivirus.clone(),
+            encryption: encryption::keyring().filter(|_| config.encryption.enabled),
+            hold_until_processed: config.hold_until_processed,
+            fsync_dir: config.fsync_dir || config.durability == finalize::Durability::Strict,
+            durability: config.durability,
+            byte_limit: None,
+            expected_sha256: None,
+            quota: config.quota.clone(),
+            key_limits: None,
+            blocklist: config.blocklist.clone(),
+        }
+    }
+
+    fn holds_until_processed(&self) -> bool {
+        self.hold_until_processed && self.make_thumbnail && self.thumbnails.is_some()
+    }
+
+    fn check_dimensions(&self, (width, height): (u32, u32)) -> Result<(), UploadError> {
+        if width > self.max_width || height > self.max_height || u64::from(width) * u64::from(height) > self.max_pixels {
+            return Err(UploadError::TooLarge(width, height));
+        }
+        Ok(())
+    }
+}
+
+// A client-supplied SHA-256, 64 hex digits in either case; lowercase as
+// `UploadedFile::sha256` is
+pub fn parse_sha256(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.len() == 64 && value.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        Some(value.to_ascii_lowercase())
+    } else {
+        None
+    }
+}
 
-    (0..len)
-        .map(|_| rng.sample(rand::distributions::Alphanumeric))
-        .take(len)
-        .collect()
+// Output options a client may send with an upload: in the query, a
+// multipart `options` field or the keys of a JSON item
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct OutputOptions {
+    // A stored extension, e.g. `png`
+    pub format: Option<String>,
+    // 1-100
+    pub quality: Option<u8>,
+    pub thumbnail: Option<bool>,
+    pub blur_faces: Option<bool>,
+    pub thumbnail_crop: Option<imagetools::Gravity>,
+}
+
+impl OutputOptions {
+    pub fn apply(&self, options: &mut UploadOptions) -> Result<(), String> {
+        if let Some(format) = &self.format {
+            match STORED_EXTENSIONS.iter().find(|extension| *extension == format && imagetools::can_encode(extension)) {
+                Some(extension) => options.output_format = Some(extension),
+                None => return Err(format!("unsupported format {:?}", format)),
+            }
+        }
+        if let Some(quality) = self.quality {
+            if quality == 0 || quality > 100 {
+                return Err("quality must be within 1-100".to_owned());
+            }
+            options.quality = Some(quality);
+        }
+        if let Some(thumbnail) = self.thumbnail {
+            options.make_thumbnail = thumbnail;
+        }
+        if let Some(blur_faces) = self.blur_faces {
+            if blur_faces && !imagetools::FACE_DETECTION {
+                return Err("this build can't detect faces".to_owned());
+            }
+            options.blur_faces = blur_faces;
+        }
+        if let Some(crop) = self.thumbnail_crop {
+            options.thumbnail_crop = Some(crop);
+        }
+        Ok(())
+    }
+}
+
+// GET with the retries of `config.fetch_retry`, the response is a success
+// or a redirect
+async fn send_with_retries(
+    config: &Config,
+    client: &reqwest::Client,
+    url: &reqwest::Url,
+    headers: &reqwest::header::HeaderMap,
+) -> Result<reqwest::Response, FetchError> {
+    let mut attempt = 1;
+    loop {
+        let err = match client.get(url.clone()).headers(headers.clone()).send().await {
+            Ok(response) if response.status().is_success() || response.status().is_redirection() => {
+                return Ok(response)
+            }
+            Ok(response) => FetchError::Status(response.status().as_u16()),
+            Err(err) => err.into(),
+        };
+        if !config.fetch_retry.should_retry(attempt, &err) {
+            return Err(match attempt {
+                1 => err,
+                attempts => FetchError::GaveUp {
+                    attempts,
+                    last: Box::new(err),
+                },
+            });
+        }
+
+        let backoff = config.fetch_retry.backoff(attempt);
+        tracing::warn!(
+            "Fetching {} failed (attempt {}/{}), retrying in {:?}: {}",
+            url,
+            attempt,
+            config.fetch_retry.max_attempts,
+            backoff,
+            err
+        );
+        tokio::time::sleep(backoff).await;
+        attempt += 1;
+    }
 }
 
-pub async fn fetch_image(config: &Config, uri: &str) -> Fallible<UploadedFile> {
-    let client = reqwest::Client::new();
+// Follows up to `fetch.max_redirects` redirects, checking each hop with
+// `fetch::FetchConfig::client_for`. Forwarded headers don't follow a
+// redirect to another origin.
+async fn fetch_response(
+    config: &Config,
+    uri: &str,
+    forwarded: reqwest::header::HeaderMap,
+) -> Result<reqwest::Response, FetchError> {
+    let mut url = reqwest::Url::parse(uri).map_err(|_| FetchError::InvalidUrl(uri.to_owned()))?;
 
     let mut headers = reqwest::header::HeaderMap::new();
     headers.insert(
         header::ACCEPT,
-        "image/jpeg, image/png, image/bmp".parse().unwrap(),
+        "image/jpeg, image/png, image/bmp, image/gif, image/webp".parse().unwrap(),
     );
+    let accept_only = headers.clone();
+    headers.extend(forwarded);
 
-    let response = client
-        .get(uri)
-        .headers(headers)
-        .send()
-        .await?;
+    for _ in 0..=config.fetch.max_redirects {
+        let client = config.fetch.client_for(&url).await?;
+        let response = send_with_retries(config, &client, &url, &headers).await?;
+        if response.status().is_success() {
+            return Ok(response);
+        }
+
+        let next = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| url.join(location).ok())
+            .ok_or_else(|| FetchError::Status(response.status().as_u16()))?;
+        tracing::debug!("{} redirects to {}", url, next);
+        if next.origin() != url.origin() {
+            headers = accept_only.clone();
+        }
+        url = next;
+    }
+    Err(FetchError::TooManyRedirects(config.fetch.max_redirects))
+}
+
+// Bytes of a body read before its type is checked
+pub const SNIFF_SIZE: usize = 8192;
+
+// MIME type of the first bytes of a body; SVG is text to tree_magic, and
+// its MP4 may be QuickTime or AVIF
+pub fn sniff_type(head: &[u8]) -> String {
+    if imagetools::looks_like_svg(head) {
+        return "image/svg+xml".to_owned();
+    }
+    if let Some(mime_type) = imagetools::video_extension(head).and_then(extension_to_mime_type) {
+        return mime_type.to_owned();
+    }
+    tree_magic::from_u8(head)
+}
 
-    dbg!(&response);
+// Reads up to SNIFF_SIZE bytes, fewer for a short body
+pub async fn read_head<S, E>(stream: &mut S) -> Result<bytes::BytesMut, E>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    let mut head = bytes::BytesMut::new();
+    while head.len() < SNIFF_SIZE {
+        match stream.next().await {
+            Some(chunk) => head.extend_from_slice(&chunk?),
+            None => break,
+        }
+    }
+    Ok(head)
+}
 
-    if !response.status().is_success() {
-        return Err(FetchError::ServerReturnedError.into());
+// The stored extension of a fetched body by its Content-Type; `None` when
+// the origin didn't say, and the bytes have to be sniffed
+fn fetched_extension(response: &reqwest::Response) -> Result<Option<&'static str>, FetchError> {
+    let mime_type = match response.headers().get(header::CONTENT_TYPE) {
+        Some(mime_type) => mime_type,
+        None => return Ok(None),
+    };
+    let mime_type = mime_type
+        .to_str()
+        .map_err(|_| FetchError::UnsupportedType(String::from_utf8_lossy(mime_type.as_bytes()).into_owned()))?;
+    // What object stores send when nobody set a type
+    if mime_type.is_empty() || mime_type == "application/octet-stream" || mime_type == "binary/octet-stream" {
+        return Ok(None);
+    }
+    match mime_type_to_extension(mime_type) {
+        Some(extension) => Ok(Some(extension)),
+        None => Err(FetchError::UnsupportedType(mime_type.to_owned())),
     }
+}
 
-    let headers = response.headers();
+// `headers` are checked against `fetch.forward_headers`
+#[tracing::instrument(skip_all, fields(uri = uri))]
+pub async fn fetch_image(
+    config: &Config,
+    uri: &str,
+    headers: &fetch::ForwardHeaders,
+    options: &UploadOptions,
+) -> Result<UploadedFile> {
+    let forwarded = config.fetch.forwarded(headers)?;
+    let response = fetch_response(config, uri, forwarded).await?;
 
-    let extension = match headers.get(header::CONTENT_TYPE) {
-        Some(mime_type) => {
-            let mime_type_str = mime_type
-                .to_str()
-                .map_err(|_| FetchError::UnsupportedMediaType)?;
+    let mut options = options.clone();
+    options.source_url = Some(response.url().to_string());
 
-            match mime_type_to_extension(mime_type_str) {
-                Some(ext) => ext,
-                None => return Err(FetchError::UnsupportedMediaType.into()),
+    let declared_extension = fetched_extension(&response)?;
+    let content_length = response.content_length();
+    let mut stream = response.bytes_stream();
+
+    let (extension, head) = match declared_extension {
+        Some(extension) => (extension, bytes::BytesMut::new()),
+        None => {
+            let head = read_head(&mut stream).await.map_err(|err| UploadError::Body(err.into()))?;
+            let content_type = sniff_type(&head);
+            tracing::debug!("{} has no Content-Type, sniffed {}", uri, content_type);
+            match mime_type_to_extension(&content_type) {
+                Some(extension) => (extension, head),
+                None => return Err(FetchError::UnsupportedType(content_type).into()),
             }
         }
-        None => return Err(FetchError::UnsupportedMediaType.into()),
     };
 
-    let stream = response.bytes_stream();
+    // Refused before streaming what would go over a limit anyway
+    if let Some(content_length) = content_length {
+        if let Some(byte_limit) = &options.byte_limit {
+            byte_limit.check(content_length)?;
+        }
+        let mime_type = extension_to_mime_type(extension).unwrap_or("application/octet-stream");
+        if let Some(type_limits) = options.type_limits.get(mime_type) {
+            type_limits.check_size(mime_type, content_length)?;
+        }
+    }
 
-    upload_image(stream, &config.uploads_dir, extension).await
+    let stream = futures_util::stream::once(futures_util::future::ready(Ok(head.freeze()))).chain(stream);
+    upload_image(stream, &config.uploads_dir, extension, &options).await
 }
 
+// In a span with the upload's id once it has one
+#[tracing::instrument(skip_all, fields(extension = extension, id = tracing::field::Empty))]
 pub async fn upload_image<S, P, E>(
     stream: S,
     uploads_dir: P,
     extension: &str,
-) -> Fallible<UploadedFile>
+    options: &UploadOptions,
+) -> Result<UploadedFile>
 where
     S: Stream<Item = Result<Bytes, E>> + std::marker::Unpin,
     P: AsRef<Path>,
-    E: Into<failure::Error>,
+    E: Into<anyhow::Error>,
 {
-    let id = gen_rand_id(12);
+    let _in_flight = shutdown::track_upload();
+
+    let mime_type = extension_to_mime_type(extension).unwrap_or("application/octet-stream");
+    let is_video = imagetools::VIDEO_EXTENSIONS.contains(&extension);
+    if is_video && !options.video.enabled {
+        return Err(UploadError::UnsupportedType(mime_type.to_owned()).into());
+    }
+    if let Some(allowed_types) = &options.allowed_types {
+        if !allowed_types.iter().any(|allowed| allowed == mime_type) {
+            return Err(UploadError::NotAllowed(mime_type.to_owned()).into());
+        }
+    }
+    let type_limits = options.type_limits.get(mime_type);
+    if let (Some(type_limits), Some(throttle)) = (type_limits, &options.type_throttle) {
+        throttle.check(mime_type, type_limits)?;
+    }
+
+    let (id, tmp_path) = reserve_id(&uploads_dir, options).await.map_err(UploadError::from)?;
+    tracing::Span::current().record("id", id.as_str());
+    // From here on any error, or a panic, leaves nothing behind
+    let mut pending = PendingUpload::new(uploads_dir.as_ref(), &id, &tmp_path);
+    if options.durability == finalize::Durability::Strict {
+        let (uploads_dir, id, tmp_path) = (uploads_dir.as_ref().to_owned(), id.clone(), tmp_path.clone());
+        tokio::task::spawn_blocking(move || journal::begin(&uploads_dir, &id, &tmp_path))
+            .await
+            .map_err(|e| UploadError::Processing(e.into()))?
+            .map_err(UploadError::from)?;
+        pending.journaled = true;
+    }
+
+    tracing::debug!("Uploading to {}", tmp_path.to_str().unwrap_or("?"));
+
+    // Hashed as it's written, what the client sent is never read back
+    let mut hasher = Sha256::new();
+    let stream = stream.inspect(|chunk| {
+        if let Ok(chunk) = chunk {
+            hasher.update(chunk);
+        }
+    });
+    stream_to_file(stream, &tmp_path, options.byte_limit.as_ref(), options.durability).await?;
+    let sha256 = hex::encode(hasher.finalize());
+    if let Some(expected) = options.expected_sha256.as_ref().filter(|&expected| *expected != sha256) {
+        return Err(UploadError::ChecksumMismatch {
+            expected: expected.clone(),
+            actual: sha256,
+        }
+        .into());
+    }
+
+    let size = tokio::fs::metadata(&tmp_path).await.map_err(UploadError::from)?.len();
+    let owner = options.owner.as_deref();
+    quota::reserve(&options.quota, options.key_limits.as_ref(), owner, &id, size)?;
+    pending.reserved = true;
+
+    if let Some(type_limits) = type_limits {
+        type_limits.check_size(mime_type, size)?;
+    }
+    if is_video && options.video.max_size > 0 && size > options.video.max_size {
+        return Err(UploadError::BodyTooLarge(options.video.max_size).into());
+    }
 
-    let mut tmp_path = PathBuf::with_capacity(64);
-    tmp_path.push(&uploads_dir);
-    tmp_path.push(&id);
-    tmp_path.set_extension("tmp");
+    // Infected files are refused before any decoder sees them
+    antivirus::check(&options.antivirus, &tmp_path).await?;
 
-    log::debug!("Uploading to {}", tmp_path.to_str().unwrap_or("?"));
+    // Scripts, event handlers and outside references are gone before
+    // anything else reads an SVG
+    if extension == "svg" {
+        let tmp_path_clone = tmp_path.clone();
+        let res = concurrency::run_blocking(move || imagetools::sanitize_svg(&tmp_path_clone))
+            .await
+            .map_err(|e| UploadError::Processing(e.into()))?;
+        match res {
+            Ok(removed) => tracing::debug!("Sanitized {}, {} removed", tmp_path.to_str().unwrap_or("?"), removed),
+            Err(err) => {
+                tracing::warn!("Refusing SVG upload: {}", err);
+                return Err(match err {
+                    imagetools::SvgError::Io(err) => UploadError::Processing(err.into()),
+                    _ => UploadError::UnrecognizedImage,
+                }
+                .into());
+            }
+        }
+    }
+
+    // Nothing gets decoded before the header passes the size limits
+    let tmp_path_clone = tmp_path.clone();
+    let dimensions = tokio::task::spawn_blocking(move || imagetools::image_dimensions(tmp_path_clone))
+        .await
+        .map_err(|e| UploadError::Processing(e.into()))?
+        .map_err(|e| UploadError::Processing(e.into()));
+    let res: Result<(u32, u32)> = match dimensions {
+        Ok(Some(dimensions)) => match (options.check_dimensions(dimensions), type_limits) {
+            (Err(err), _) => Err(err.into()),
+            (Ok(()), Some(type_limits)) => type_limits
+                .check_dimensions(mime_type, dimensions)
+                .map(|()| dimensions)
+                .map_err(Into::into),
+            (Ok(()), None) => Ok(dimensions),
+        },
+        Ok(None) => Err(UploadError::UnrecognizedImage.into()),
+        Err(err) => Err(err.into()),
+    };
+    let (width, height) = res?;
+    let subject = policy::Subject {
+        mime: mime_type,
+        size,
+        width,
+        height,
+        tenant: options.tenant.as_deref(),
+        metadata: &options.custom_metadata,
+    };
+    policy::check(&subject)?;
 
-    let res = stream_to_file(stream, &tmp_path).await;
-    if let Err(err) = res {
-        // log::error!("Upload error: {}", err);
-        return Err(err);
+    // Pixel and metadata fixes are applied to the temp file, so the stored
+    // original never contains anything the options asked to remove
+    let (tmp_path_clone, extension_clone) = (tmp_path.clone(), extension.to_owned());
+    let strip_metadata = options.strip_metadata;
+    let blur_faces = Some(options.faces.clone()).filter(|_| options.blur_faces);
+    // Converted, or a JPEG with a requested quality
+    let reencode_as = match (options.output_format, options.quality) {
+        (Some(format), quality) if format != extension || quality.is_some() => Some((format, quality)),
+        (None, Some(quality)) if extension == "jpg" => Some(("jpg", Some(quality))),
+        _ => None,
+    };
+    // Blurred faces are written back before any re-encoding
+    if options.blur_faces && !imagetools::can_encode(extension) {
+        return Err(UploadError::UnsupportedType(format!("{} with blur_faces", mime_type)).into());
     }
+    let res = concurrency::run_blocking(move || {
+        // Animations are stored untouched, every fix below would keep only
+        // the first frame
+        if let Some(animation) = imagetools::animation(&tmp_path_clone)? {
+            tracing::debug!("{} is animated, {} frames", tmp_path_clone.to_str().unwrap_or("?"), animation.frames);
+            return Ok(Some(animation));
+        }
+        // So are clips, only their poster frame is ever decoded
+        if let Some(video) = imagetools::video(&tmp_path_clone)? {
+            tracing::debug!("{} is a video, {} ms", tmp_path_clone.to_str().unwrap_or("?"), video.duration_ms);
+            return Ok(Some(imagetools::Animation {
+                frames: video.frames,
+                duration_ms: video.duration_ms,
+            }));
+        }
+
+        // Phone photos are stored sideways with an EXIF hint, fix the pixels
+        // once so neither the original nor the thumbnail depends on it
+        match imagetools::auto_orient(&tmp_path_clone, &extension_clone) {
+            Ok(true) => tracing::debug!("Auto-oriented {}", tmp_path_clone.to_str().unwrap_or("?")),
+            Ok(false) => {}
+            Err(err) => tracing::warn!("Error auto-orienting image: {}", err),
+        }
+
+        if let Some(faces) = &blur_faces {
+            let blurred = imagetools::blur_faces(&tmp_path_clone, &extension_clone, faces)
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            tracing::debug!("Blurred {} face(s) in {}", blurred, tmp_path_clone.to_str().unwrap_or("?"));
+        }
+
+        if strip_metadata && imagetools::strip_metadata(&tmp_path_clone)? {
+            tracing::debug!("Stripped metadata from {}", tmp_path_clone.to_str().unwrap_or("?"));
+        }
+
+        if let Some((format, quality)) = reencode_as {
+            imagetools::reencode(&tmp_path_clone, format, quality)
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+            tracing::debug!("Re-encoded {} as {}", tmp_path_clone.to_str().unwrap_or("?"), format);
+        }
+
+        Ok::<_, std::io::Error>(None)
+    })
+    .await
+    .map_err(|e| UploadError::Processing(e.into()))?;
+
+    let animation = res.map_err(|err| UploadError::Processing(err.into()))?;
+    // Stored untouched, faces included
+    if animation.is_some() && options.blur_faces {
+        return Err(UploadError::UnsupportedType(format!("animated {} with blur_faces", mime_type)).into());
+    }
+    let extension = match (animation, reencode_as) {
+        (None, Some((format, _))) => format,
+        _ => extension,
+    };
+
+    // Saved before the rename, so an upload is never visible without its expiry
+    pending.metadata_saved = true;
+    let image_metadata = save_metadata(&uploads_dir, &id, extension, &tmp_path, animation, options).await?;
+
+    // Sealed last, everything above needs the plaintext
+    let envelope = match options.encryption.clone() {
+        Some(keyring) => {
+            let tmp_path_clone = tmp_path.clone();
+            let res = concurrency::run_blocking(move || keyring.seal_file(&tmp_path_clone))
+                .await
+                .map_err(|e| UploadError::Processing(e.into()))?;
+            Some(res.map_err(|err| UploadError::Processing(err.into()))?)
+        }
+        None => None,
+    };
 
     let mut upload_path = tmp_path.clone();
     upload_path.set_extension(extension);
 
-    log::debug!(
+    tracing::debug!(
         "Renaming {} -> {}",
         tmp_path.to_str().unwrap_or("?"),
         upload_path.to_str().unwrap_or("?")
     );
-    tokio::fs::rename(&tmp_path, &upload_path).await.unwrap();
+    pending.persist(&upload_path, options.fsync_dir).await?;
 
     let mut thumbnail_path = upload_path.clone();
-    thumbnail_path.set_file_name(format!("{}_thumbnail.{}", id, extension));
+    thumbnail_path.set_file_name(thumbnail_file_name(&id, options.thumbnail_format.extension(extension)));
 
-    log::debug!(
+    tracing::debug!(
         "Thumbnail {} -> {}",
         upload_path.to_str().unwrap_or("?"),
         thumbnail_path.to_str().unwrap_or("?")
     );
 
-    let (upload_path_clone, thumbnail_path_clone) = (upload_path.clone(), thumbnail_path.clone());
-    // Processing of a big image may be a hard task,
-    // let's do it on a dedicated thread
-    let res = tokio::task::spawn_blocking(move || {
-        imagetools::create_thumbnail(&upload_path_clone, &thumbnail_path_clone, (100, 100))
-    })
-    .await
-    .unwrap();
+    let (quality, crop, xmp) = (
+        options.thumbnail_quality,
+        options.thumbnail_crop,
+        options.attribution.xmp_packet(&id),
+    );
+    let thumbnail_pending = match &options.thumbnails {
+        Some(queue) if options.make_thumbnail => {
+            queue.enqueue(&id, upload_path.clone(), thumbnail_path.clone(), quality, crop, xmp.clone())
+        }
+        _ => false,
+    };
 
-    let thumbnail_path = if let Err(err) = res {
-        log::warn!("Error creating thumbnail: {}", err);
+    let thumbnail_path = if thumbnail_pending || !options.make_thumbnail {
         None
     } else {
-        Some(thumbnail_path)
+        let (upload_path_clone, thumbnail_path_clone) = (upload_path.clone(), thumbnail_path.clone());
+        // Processing of a big image may be a hard task,
+        // let's do it on a dedicated thread
+        let res = concurrency::run_blocking(move || {
+            write_thumbnail(&upload_path_clone, &thumbnail_path_clone, quality, crop, xmp)
+        })
+        .await;
+
+        // The upload is stored by now, it stays without a thumbnail
+        match res {
+            Ok(Ok(())) => Some(thumbnail_path),
+            Ok(Err(err)) => {
+                tracing::warn!("Error creating thumbnail: {}", err);
+                None
+            }
+            Err(err) => {
+                tracing::error!("Thumbnail task failed: {}", err);
+                None
+            }
+        }
     };
 
+    if image_metadata.status == metadata::UploadState::Processing {
+        finish_processing(uploads_dir.as_ref(), &id, thumbnail_pending, options);
+    }
+
+    if options.moderation.enabled {
+        moderation::spawn_review(
+            &options.moderation,
+            uploads_dir.as_ref().to_owned(),
+            id.clone(),
+            upload_path.clone(),
+        );
+    }
+
     Ok(UploadedFile {
         id,
         path: upload_path,
         thumbnail_path,
+        thumbnail_pending,
+        thumbnail_skipped: !options.make_thumbnail,
+        blurhash: image_metadata.blurhash,
+        dominant_colors: image_metadata.dominant_colors,
+        source_url: image_metadata.source_url,
+        encryption: envelope,
+        sha256,
     })
 }
 
-pub async fn stream_to_file<S, P, E>(stream: S, filename: P) -> Fallible<()>
+// A thumbnail not done by then isn't coming
+const PROCESSING_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3600);
+
+// Makes a processing upload available once its queued thumbnail is done or
+// has failed, right away if it was made inline. One left processing by a
+// restart is approved like a pending one.
+fn finish_processing(uploads_dir: &Path, id: &str, thumbnail_pending: bool, options: &UploadOptions) {
+    let (uploads_dir, id, queue) = (uploads_dir.to_owned(), id.to_owned(), options.thumbnails.clone());
+    actix_rt::spawn(async move {
+        if let Some(queue) = queue.filter(|_| thumbnail_pending) {
+            queue.wait(&id, std::time::Instant::now() + PROCESSING_TIMEOUT).await;
+        }
+        let (from, to) = (metadata::UploadState::Processing, metadata::UploadState::Available);
+        if let Err(err) = metadata::transition(&uploads_dir, &id, from, to).await {
+            tracing::error!("Error making {} available: {}", id, err);
+        }
+    });
+}
+
+// The temp file of an upload not stored yet, and its metadata once saved:
+// removed when dropped before `persist`, however `upload_image` ends. Its
+// journal entry, if any, goes once either is done.
+struct PendingUpload {
+    uploads_dir: PathBuf,
+    id: String,
+    tmp_path: PathBuf,
+    metadata_saved: bool,
+    // Its bytes, see `quota::reserve`
+    reserved: bool,
+    journaled: bool,
+    persisted: bool,
+}
+
+impl PendingUpload {
+    fn new(uploads_dir: &Path, id: &str, tmp_path: &Path) -> PendingUpload {
+        PendingUpload {
+            uploads_dir: uploads_dir.to_owned(),
+            id: id.to_owned(),
+            tmp_path: tmp_path.to_owned(),
+            metadata_saved: false,
+            reserved: false,
+            journaled: false,
+            persisted: false,
+        }
+    }
+
+    // Moves the temp file into place, after which the upload is kept
+    async fn persist(&mut self, upload_path: &Path, fsync_dir: bool) -> Result<(), UploadError> {
+        finalize::finalize(&self.tmp_path, upload_path, fsync_dir).await?;
+        self.persisted = true;
+        if self.journaled {
+            journal::end(&self.uploads_dir, &self.id);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PendingUpload {
+    fn drop(&mut self) {
+        if self.persisted {
+            return;
+        }
+        if let Err(err) = std::fs::remove_file(&self.tmp_path) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!("Error removing {}: {}", self.tmp_path.display(), err);
+            }
+        }
+        if self.reserved {
+            quota::release(&self.id);
+        }
+        if self.metadata_saved {
+            quota::forget(&self.id);
+            let _ = std::fs::remove_file(metadata::metadata_path(&self.uploads_dir, &self.id));
+        }
+        layout::forget_tenant(&self.id);
+        if self.journaled {
+            journal::end(&self.uploads_dir, &self.id);
+        }
+    }
+}
+
+// Generated ids are retried this many times before giving up
+const MAX_ID_ATTEMPTS: usize = 8;
+
+// A fresh id and its temp file, created exclusively. The temp file is taken
+// before looking for a stored file, so a concurrent upload of the same id
+// either still holds it or has already renamed it into place.
+async fn reserve_id<P: AsRef<Path>>(uploads_dir: P, options: &UploadOptions) -> std::io::Result<(String, PathBuf)> {
+    let generator = options.id_scheme.generator();
+
+    for _ in 0..MAX_ID_ATTEMPTS {
+        let id = format!("{}{}", options.id_prefix, generator.generate());
+        // Taken by a tenant's upload, whose files aren't where this one's go
+        if layout::tenant_of(&id).is_some() {
+            continue;
+        }
+        if let Some(tenant) = &options.tenant {
+            layout::set_tenant(&id, tenant);
+        }
+
+        let mut tmp_path = layout::shard_dir(&uploads_dir, &id);
+        tokio::fs::create_dir_all(&tmp_path).await?;
+        tmp_path.push(&id);
+        tmp_path.set_extension("tmp");
+
+        let res = tokio::fs::OpenOptions::new().write(true).create_new(true).open(&tmp_path).await;
+        match res {
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+
+        let metadata_exists = tokio::fs::metadata(metadata::metadata_path(&uploads_dir, &id)).await.is_ok();
+        if metadata_exists || find_upload(&uploads_dir, &id).await.is_some() {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            if options.tenant.is_some() {
+                layout::forget_tenant(&id);
+            }
+            continue;
+        }
+
+        return Ok((id, tmp_path));
+    }
+
+    tracing::error!("No free id after {} attempts", MAX_ID_ATTEMPTS);
+    Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, "no free id"))
+}
+
+async fn save_metadata<P: AsRef<Path>>(
+    uploads_dir: P,
+    id: &str,
+    extension: &str,
+    path: &Path,
+    animation: Option<imagetools::Animation>,
+    options: &UploadOptions,
+) -> Result<metadata::ImageMetadata> {
+    let size = tokio::fs::metadata(path).await?.len();
+    // Re-read, auto-orientation may have swapped them
+    let path_clone = path.to_owned();
+    let (width, height) = tokio::task::spawn_blocking(move || imagetools::image_dimensions(path_clone))
+        .await??
+        .unwrap_or((0, 0));
+    let created_at = metadata::now();
+    let path_clone = path.to_owned();
+    let checksum = tokio::task::spawn_blocking(move || metadata::file_checksum(path_clone)).await??;
+
+    // Only placeholders and the similarity hash, an upload isn't refused
+    // over them
+    let (path_clone, with_blurhash, colors) = (path.to_owned(), options.blurhash, options.dominant_colors);
+    let res = concurrency::run_blocking(move || {
+        imagetools::preview(path_clone).map(|preview| {
+            let blurhash = if with_blurhash { Some(imagetools::blurhash(&preview)) } else { None };
+            let phash = metadata::format_perceptual_hash(imagetools::perceptual_hash(&preview));
+            (blurhash, imagetools::dominant_colors(&preview, colors), Some(phash))
+        })
+    })
+    .await?;
+    let (blurhash, dominant_colors, phash) = res.unwrap_or_else(|err| {
+        tracing::warn!("Error computing placeholders: {}", err);
+        (None, Vec::new(), None)
+    });
+
+    let mut image_metadata = metadata::ImageMetadata {
+        id: id.to_owned(),
+        extension: extension.to_owned(),
+        size,
+        width,
+        height,
+        created_at,
+        expires_at: options.ttl.map(|ttl| created_at + ttl),
+        checksum: Some(checksum),
+        publish_at: options.publish_at,
+        owner: options.owner.clone(),
+        tenant: options.tenant.clone(),
+        provenance: options.provenance.clone(),
+        status: if options.moderation.enabled {
+            metadata::UploadState::Pending
+        } else if options.holds_until_processed() {
+            metadata::UploadState::Processing
+        } else {
+            metadata::UploadState::Available
+        },
+        original_filename: options.original_filename.clone(),
+        custom: options.custom_metadata.clone(),
+        tags: options.tags.clone(),
+        source_url: options.source_url.clone(),
+        frames: animation.map(|animation| animation.frames),
+        duration_ms: animation.map(|animation| animation.duration_ms),
+        blurhash,
+        dominant_colors,
+        phash,
+        accessed_at: None,
+        archived_at: None,
+        quarantine: None,
+        moderation_scores: BTreeMap::new(),
+        moderation_hold: None,
+        ocr: BTreeMap::new(),
+    };
+    blocklist::apply(&options.blocklist, &uploads_dir, &mut image_metadata)?;
+    moderation::scan(&options.moderation, path, &mut image_metadata).await?;
+    metadata::save(uploads_dir, &image_metadata).await?;
+    Ok(image_metadata)
+}
+
+pub fn file_stream(file: tokio::fs::File) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Unpin {
+    tokio_util::codec::FramedRead::new(file, tokio_util::codec::BytesCodec::new())
+        .map(|chunk| chunk.map(bytes::BytesMut::freeze))
+}
+
+// Bytes a request may still stream to disk: `per_file` for each file, and
+// what's left of `per_request` for all the files of a multipart request
+// together. Clones share what's left.
+#[derive(Debug, Clone)]
+pub struct ByteLimit {
+    per_file: u64,
+    per_request: u64,
+    request_left: Arc<AtomicU64>,
+}
+
+impl ByteLimit {
+    // 0 is unlimited
+    pub fn new(per_file: u64, per_request: u64) -> ByteLimit {
+        let unlimited_if_zero = |limit| if limit == 0 { u64::MAX } else { limit };
+        let per_request = unlimited_if_zero(per_request);
+        ByteLimit {
+            per_file: unlimited_if_zero(per_file),
+            per_request,
+            request_left: Arc::new(AtomicU64::new(per_request)),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> ByteLimit {
+        ByteLimit::new(config.max_file_size, config.max_request_size)
+    }
+
+    // The same limits with nothing taken yet, for another request
+    pub fn fresh(&self) -> ByteLimit {
+        ByteLimit::new(self.per_file, self.per_request)
+    }
+
+    // Whether a file of `len` bytes could still be taken, for a declared
+    // length
+    pub fn check(&self, len: u64) -> Result<(), UploadError> {
+        if len > self.per_file {
+            return Err(UploadError::BodyTooLarge(self.per_file));
+        }
+        if len > self.request_left.load(Ordering::SeqCst) {
+            return Err(UploadError::BodyTooLarge(self.per_request));
+        }
+        Ok(())
+    }
+
+    // Takes `len` more bytes of a file that now has `file_size` of them
+    fn take(&self, len: u64, file_size: u64) -> Result<(), UploadError> {
+        if file_size > self.per_file {
+            return Err(UploadError::BodyTooLarge(self.per_file));
+        }
+        self.request_left
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| left.checked_sub(len))
+            .map(|_| ())
+            .map_err(|_| UploadError::BodyTooLarge(self.per_request))
+    }
+}
+
+// Synced to disk before it returns with `Durability::Strict`
+pub async fn stream_to_file<S, P, E>(
+    stream: S,
+    filename: P,
+    limit: Option<&ByteLimit>,
+    durability: finalize::Durability,
+) -> Result<()>
 where
     S: Stream<Item = Result<Bytes, E>> + std::marker::Unpin,
     P: AsRef<Path>,
-    E: Into<failure::Error>,
+    E: Into<anyhow::Error>,
 {
-    let file = tokio::fs::File::create(&filename)
-        .await
-        .map_err(|e| UploadError::Server(e.into()))?;
-    let writer = tokio::io::BufWriter::new(file);
+    let file = tokio::fs::File::create(&filename).await.map_err(UploadError::from)?;
+    let mut writer = tokio::io::BufWriter::new(file);
 
-    let res = stream_to_writer(stream, writer).await;
+    let res = match stream_to_writer(stream, &mut writer, limit).await {
+        Ok(()) if durability == finalize::Durability::Strict => {
+            writer.get_ref().sync_all().await.map_err(|err| UploadError::from(err).into())
+        }
+        res => res,
+    };
     if res.is_err() {
-        tokio::fs::remove_file(&filename).await.unwrap();
+        if let Err(err) = tokio::fs::remove_file(&filename).await {
+            tracing::warn!("Error removing partial {}: {}", filename.as_ref().display(), err);
+        }
     }
     res
 }
 
-pub async fn stream_to_writer<S, W, E>(mut stream: S, mut writer: W) -> Fallible<()>
+// Aborts with `UploadError::BodyTooLarge` once the stream goes over `limit`
+pub async fn stream_to_writer<S, W, E>(mut stream: S, mut writer: W, limit: Option<&ByteLimit>) -> Result<()>
 where
     S: Stream<Item = Result<Bytes, E>> + std::marker::Unpin,
     W: AsyncWrite + std::marker::Unpin,
-    E: Into<failure::Error>,
+    E: Into<anyhow::Error>,
 {
+    let mut written = 0u64;
     while let Some(chunk) = stream.next().await {
-        let chunk = chunk.map_err(|e| UploadError::Client(e.into()))?;
-        writer
-            .write_all(&chunk)
-            .await
-            .map_err(|e| UploadError::Server(e.into()))?;
+        let chunk = chunk.map_err(|e| UploadError::Body(e.into()))?;
+        written += chunk.len() as u64;
+        if let Some(limit) = limit {
+            limit.take(chunk.len() as u64, written)?;
+        }
+        writer.write_all(&chunk).await.map_err(UploadError::from)?;
     }
 
-    writer
-        .flush()
-        .await
-        .map_err(|e| UploadError::Server(e.into()))?;
+    writer.flush().await.map_err(UploadError::from)?;
 
     Ok(())
 }
@@ -0,0 +1,112 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::guest::RateLimiter;
+
+// Ограничения для отдельных MIME-типов, поверх общих
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TypeLimits {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_height: Option<u32>,
+    // Across all clients
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uploads_per_minute: Option<u32>,
+}
+
+// Which per-type limit was exceeded, for the error body
+#[derive(Debug, Clone, Serialize)]
+pub struct ExceededLimit {
+    pub mime: String,
+    pub limit: &'static str,
+    pub value: u64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TypeLimitError {
+    #[error("Upload exceeds the {0}")]
+    TooLarge(ExceededLimit),
+    #[error("Too many uploads, over the {0}")]
+    RateLimited(ExceededLimit),
+}
+
+impl std::fmt::Display for ExceededLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} limit of {} for {}", self.limit, self.value, self.mime)
+    }
+}
+
+impl TypeLimitError {
+    pub fn exceeded(&self) -> &ExceededLimit {
+        match self {
+            TypeLimitError::TooLarge(exceeded) | TypeLimitError::RateLimited(exceeded) => exceeded,
+        }
+    }
+}
+
+impl TypeLimits {
+    pub fn check_size(&self, mime: &str, size: u64) -> Result<(), TypeLimitError> {
+        match self.max_size {
+            Some(max_size) if size > max_size => Err(TypeLimitError::TooLarge(ExceededLimit {
+                mime: mime.to_owned(),
+                limit: "max_size",
+                value: max_size,
+            })),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_dimensions(&self, mime: &str, (width, height): (u32, u32)) -> Result<(), TypeLimitError> {
+        let exceeded = |limit, value: u32| {
+            Err(TypeLimitError::TooLarge(ExceededLimit {
+                mime: mime.to_owned(),
+                limit,
+                value: u64::from(value),
+            }))
+        };
+
+        match (self.max_width, self.max_height) {
+            (Some(max_width), _) if width > max_width => exceeded("max_width", max_width),
+            (_, Some(max_height)) if height > max_height => exceeded("max_height", max_height),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Per-type upload counters, shared by all requests
+#[derive(Clone, Default)]
+pub struct TypeThrottle {
+    limiter: Arc<Mutex<RateLimiter>>,
+}
+
+impl std::fmt::Debug for TypeThrottle {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("TypeThrottle")
+    }
+}
+
+impl TypeThrottle {
+    // Counts the upload
+    pub fn check(&self, mime: &str, limits: &TypeLimits) -> Result<(), TypeLimitError> {
+        let limit = match limits.uploads_per_minute {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+
+        if self.limiter.lock().unwrap().allow(mime, limit, Duration::from_secs(60)) {
+            Ok(())
+        } else {
+            Err(TypeLimitError::RateLimited(ExceededLimit {
+                mime: mime.to_owned(),
+                limit: "uploads_per_minute",
+                value: u64::from(limit),
+            }))
+        }
+    }
+}
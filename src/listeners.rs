@@ -0,0 +1,232 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use listenfd::ListenFd;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerKind {
+    Http,
+    Https,
+    // Unix domain socket, `address` is the socket path
+    Unix,
+}
+
+// Which routes a listener serves
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteSet {
+    // Uploads and image serving
+    Public,
+    // Listing, deletion, moderation, replication and cluster internals
+    Admin,
+    All,
+}
+
+impl RouteSet {
+    pub fn has_public(self) -> bool {
+        self != RouteSet::Admin
+    }
+
+    pub fn has_admin(self) -> bool {
+        self != RouteSet::Public
+    }
+}
+
+fn default_route_set() -> RouteSet {
+    RouteSet::All
+}
+
+fn default_tls_reload_secs() -> u64 {
+    60
+}
+
+// Один сокет, на котором слушает сервер
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListenerConfig {
+    pub kind: ListenerKind,
+    // "host:port", or a socket path for `unix`; only named in the logs
+    // with `listen_fd`
+    #[serde(default)]
+    pub address: String,
+    #[serde(default = "default_route_set")]
+    pub routes: RouteSet,
+    // PEM files, `https` only
+    #[serde(default)]
+    pub tls_cert: Option<PathBuf>,
+    #[serde(default)]
+    pub tls_key: Option<PathBuf>,
+    // How often the PEM files are checked for changes, 0 never reloads them
+    #[serde(default = "default_tls_reload_secs")]
+    pub tls_reload_secs: u64,
+    // `http` only: answers everything with a redirect to the same host and
+    // path on HTTPS at this port
+    #[serde(default)]
+    pub redirect_to_https: Option<u16>,
+    // Serve the socket systemd passed at this index (LISTEN_FDS, in the
+    // order of the .socket unit) instead of binding `address`
+    #[serde(default)]
+    pub listen_fd: Option<usize>,
+    // `unix` only, permissions of the socket file, e.g. 0o660 for a proxy
+    // in the same group
+    #[serde(default)]
+    pub unix_mode: Option<u32>,
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| anyhow::anyhow!("can't parse certificates in {:?}", cert_path))?;
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("no certificate in {:?}", cert_path));
+    }
+
+    // PKCS#8, RSA or SEC1, whichever comes first
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))
+        .map_err(|_| anyhow::anyhow!("can't parse private key in {:?}", key_path))?
+        .ok_or_else(|| anyhow::anyhow!("no private key in {:?}", key_path))?;
+    let key = rustls::crypto::ring::sign::any_supported_type(&key)
+        .map_err(|_| anyhow::anyhow!("unsupported private key in {:?}", key_path))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+fn modified_times(cert_path: &Path, key_path: &Path) -> Option<(SystemTime, SystemTime)> {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    Some((modified(cert_path)?, modified(key_path)?))
+}
+
+// Сертификат, который подменяется без перезапуска, when its files change.
+// New handshakes get the new one, open connections keep theirs.
+#[derive(Debug)]
+pub struct ReloadingCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+    // Of the files loaded last
+    modified: Mutex<Option<(SystemTime, SystemTime)>>,
+}
+
+impl ReloadingCert {
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<ReloadingCert> {
+        let modified = modified_times(cert_path, key_path);
+        Ok(ReloadingCert {
+            cert_path: cert_path.to_owned(),
+            key_path: key_path.to_owned(),
+            current: RwLock::new(Arc::new(load_certified_key(cert_path, key_path)?)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    // Whether a changed certificate was loaded. A pair that doesn't load,
+    // e.g. halfway through being replaced, keeps the old one until the next
+    // check.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let modified = modified_times(&self.cert_path, &self.key_path);
+        if modified.is_none() || modified == *self.modified.lock().unwrap() {
+            return Ok(false);
+        }
+
+        let certified_key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(certified_key);
+        *self.modified.lock().unwrap() = modified;
+        Ok(true)
+    }
+
+    pub fn spawn_reloader(self: &Arc<Self>, interval: Duration) {
+        if interval.as_secs() == 0 {
+            return;
+        }
+
+        let cert = self.clone();
+        actix_rt::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                match cert.reload_if_changed() {
+                    Ok(true) => tracing::info!("Reloaded the TLS certificate {:?}", cert.cert_path),
+                    Ok(false) => {}
+                    Err(err) => tracing::warn!("TLS certificate reload error: {}", err),
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn not_passed(index: usize) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("no socket {} passed by systemd (LISTEN_FDS)", index),
+    )
+}
+
+// A socket of systemd socket activation, see `listen_fd`; an error if it
+// isn't there or isn't a TCP listener
+pub fn activated_tcp(sockets: &mut ListenFd, index: usize) -> std::io::Result<TcpListener> {
+    sockets.take_tcp_listener(index)?.ok_or_else(|| not_passed(index))
+}
+
+#[cfg(unix)]
+pub fn activated_unix(sockets: &mut ListenFd, index: usize) -> std::io::Result<UnixListener> {
+    sockets.take_unix_listener(index)?.ok_or_else(|| not_passed(index))
+}
+
+#[cfg(unix)]
+pub fn set_unix_mode(listener: &ListenerConfig) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    match listener.unix_mode {
+        Some(mode) => std::fs::set_permissions(&listener.address, std::fs::Permissions::from_mode(mode)),
+        None => Ok(()),
+    }
+}
+
+pub fn load_tls_config(listener: &ListenerConfig) -> Result<(rustls::ServerConfig, Arc<ReloadingCert>)> {
+    let (cert_path, key_path) = match (&listener.tls_cert, &listener.tls_key) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "https listener {} needs tls_cert and tls_key",
+                listener.address
+            ))
+        }
+    };
+
+    let cert = Arc::new(ReloadingCert::load(cert_path, key_path)?);
+    let tls_config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(cert.clone());
+    Ok((tls_config, cert))
+}
+
+// Where `redirect_to_https` sends a request for `path_and_query` on `host`,
+// which may carry the plain HTTP port
+pub fn https_url(host: &str, port: u16, path_and_query: &str) -> String {
+    // "[::1]:8080" or "example.com:8080"
+    let host = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => &host[..i],
+        _ => host,
+    };
+    if port == 443 {
+        format!("https://{}{}", host, path_and_query)
+    } else {
+        format!("https://{}:{}{}", host, port, path_and_query)
+    }
+}
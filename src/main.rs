@@ -1,260 +1,274 @@
-use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use actix_multipart::Multipart;
-use actix_web::{guard, web, App, FromRequest, HttpResponse, HttpServer};
-use serde::Deserialize;
-use tokio::stream::StreamExt;
+use actix_web::{web, App, HttpServer};
+use clap::{Args, Parser, Subcommand};
+use rand::RngCore;
 
-use lib::{Config, UploadedFile};
+use lib::listeners::ListenerKind;
+use lib::{cleanup, layout, policy, Config};
 use rust_rest_api as lib;
 
-fn uploaded_files_to_json_list(uploaded_files: Vec<UploadedFile>) -> serde_json::Value {
-    serde_json::Value::Array(
-        uploaded_files
-            .into_iter()
-            .map(|UploadedFile { id, ..}| serde_json::Value::String(id))
-            .collect()
-    )
+// Командная строка: the server by default, or one of the maintenance
+// commands. Doc comments here are the `--help` text.
+#[derive(Debug, Parser)]
+#[command(name = "rr-api", version, about = "Image upload and processing server")]
+struct Cli {
+    /// Config file, instead of the one named by RR_API_CONFIG
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-async fn upload_multipart(mut multipart: Multipart, config: web::Data<Config>) -> HttpResponse {
-    let mut uploaded_files = Vec::new();
-
-    while let Ok(Some(field)) = multipart.try_next().await {
-        let extension = match lib::mime_type_to_extension(field.content_type().essence_str()) {
-            Some(extension) => extension,
-            None => {
-                return web::HttpResponse::UnsupportedMediaType()
-                    .json(uploaded_files_to_json_list(uploaded_files));
-            }
-        };
-
-        let res = lib::upload_image(field, &config.get_ref().uploads_dir, extension).await;
-        match res {
-            Ok(uploaded_file) => {
-                log::info!(
-                    "Upload succeed, id: {}, path: {}, thumbnail: {}",
-                    uploaded_file.id,
-                    uploaded_file.path.to_str().unwrap_or("?"),
-                    if let Some(ref path) = uploaded_file.thumbnail_path {
-                        path.to_str().unwrap_or("?")
-                    } else {
-                        "Failed to create"
-                    },
-                );
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run the server (the default)
+    Serve(ServeArgs),
+    /// Remove old temp files and derivatives whose original is gone
+    Cleanup {
+        /// Leave files younger than this alone, orphan_max_age_secs by default
+        #[arg(long, value_name = "SECS")]
+        max_age_secs: Option<u64>,
+    },
+    /// Move uploads of the flat layout into their shard directories
+    Migrate,
+    /// Print a random hex key for signing_key
+    GenKey {
+        #[arg(long, default_value_t = 32)]
+        bytes: usize,
+    },
+    /// Run the [[tests]] of a policy file, the configured one by default
+    CheckPolicy { file: Option<PathBuf> },
+}
 
-                uploaded_files.push(uploaded_file);
-            }
-            Err(err) => {
-                log::error!("Upload error: {}", err);
+// Overrides of the config file
+#[derive(Debug, Default, Args)]
+struct ServeArgs {
+    #[arg(long)]
+    host: Option<String>,
+    #[arg(long)]
+    port: Option<u16>,
+    #[arg(long, value_name = "DIR")]
+    uploads_dir: Option<PathBuf>,
+}
 
-                if let Some(lib::UploadError::Client(_)) = err.downcast_ref() {
-                    return web::HttpResponse::BadRequest()
-                        .json(uploaded_files_to_json_list(uploaded_files));
-                } else {
-                    return web::HttpResponse::InternalServerError()
-                        .json(uploaded_files_to_json_list(uploaded_files));
-                }
-            }
+impl ServeArgs {
+    // Host and port only matter without `listeners` in the config
+    fn apply(self, config: &mut Config) {
+        if let Some(host) = self.host {
+            config.host = host;
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(uploads_dir) = self.uploads_dir {
+            config.uploads_dir = uploads_dir;
         }
     }
+}
 
-    if !uploaded_files.is_empty() {
-        log::info!(
-            "Uploaded {} file{} in total (multipart/form-data)",
-            uploaded_files.len(),
-            if uploaded_files.len() > 1 { "s" } else { "" },
-        );
+// `check-policy [FILE]`: parses a policy file, the configured one by
+// default, runs its `[[tests]]` and exits non-zero if any fails
+fn check_policy(file: Option<&Path>, config: &Config) -> std::io::Result<()> {
+    let file = match file.or(config.policy.file.as_deref()) {
+        Some(file) => file,
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "no policy file configured or given",
+            ));
+        }
+    };
+    let report = policy::check_file(file)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Policy error: {}", err)))?;
 
-        return web::HttpResponse::Ok()
-            .json(uploaded_files_to_json_list(uploaded_files));
-    } else {
-        return web::HttpResponse::BadRequest()
-            .json(uploaded_files_to_json_list(uploaded_files));
+    for (test, failure) in &report.failures {
+        println!("FAIL {}: {}", test, failure);
     }
+    println!(
+        "{} rule(s), {} test(s) passed, {} failed",
+        report.rules,
+        report.passed,
+        report.failures.len()
+    );
+    if !report.failures.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
 }
 
-#[derive(Deserialize)]
-enum UploadRequest {
-    #[serde(rename = "url")]
-    Url(String),
-    #[serde(rename = "base64")]
-    Base64(String),
+// `cleanup`: what the background cleanup does, once
+async fn cleanup(config: &Config, max_age_secs: Option<u64>) -> std::io::Result<()> {
+    let max_age = Duration::from_secs(max_age_secs.unwrap_or(config.orphan_max_age_secs));
+    let stats = cleanup::cleanup_orphans(&config.uploads_dir, max_age).await?;
+    println!(
+        "Removed {} temp file(s) and {} thumbnail(s)",
+        stats.temp_files, stats.orphaned_thumbnails
+    );
+    Ok(())
 }
 
-impl fmt::Debug for UploadRequest {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            UploadRequest::Url(url) => write!(f, "Url(\"{}\")", url),
-            UploadRequest::Base64(data) => write!(f, "Base64({} bytes)", data.len()),
-        }
-    }
+// `migrate`: the layout migration `serve` also runs at startup
+async fn migrate(config: &Config) -> std::io::Result<()> {
+    let moved = layout::migrate_flat_layout(&config.uploads_dir).await?;
+    println!("Moved {} file(s) into shards", moved);
+    Ok(())
 }
 
-async fn upload_json(
-    req: web::Json<Vec<UploadRequest>>,
-    config: web::Data<Config>,
-) -> HttpResponse {
-    let mut uploaded_files: Vec<UploadedFile> = Vec::new();
+fn gen_key(bytes: usize) {
+    let mut key = vec![0; bytes];
+    rand::thread_rng().fill_bytes(&mut key);
+    println!("{}", hex::encode(key));
+}
 
-    for item in req.iter() {
-        log::debug!("{:?}", item)
+#[actix_rt::main]
+async fn main() -> std::io::Result<()> {
+    let cli = Cli::parse();
+    // Doesn't need a valid config
+    if let Some(Command::GenKey { bytes }) = cli.command {
+        gen_key(bytes);
+        return Ok(());
     }
 
-    for upload_request in req.iter() {
-        match upload_request {
-            UploadRequest::Url(url) => {
-                let res = lib::fetch_image(&config.get_ref(), &url).await;
-                match res {
-                    Ok(uploaded_file) => {
-                        log::info!(
-                            "Upload succeed, id: {}, path: {}, thumbnail: {}",
-                            uploaded_file.id,
-                            uploaded_file.path.to_str().unwrap_or("?"),
-                            if let Some(ref path) = uploaded_file.thumbnail_path {
-                                path.to_str().unwrap_or("?")
-                            } else {
-                                "Failed to create"
-                            },
-                        );
+    let config = match &cli.config {
+        Some(path) => Config::load_from(Some(path)),
+        None => Config::load(),
+    };
+    let mut config = config
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Config error: {}", err)))?;
+    // Kept to the end, it flushes exported spans
+    let _telemetry = lib::telemetry::init(&config.telemetry)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Telemetry error: {}", err)))?;
 
-                        uploaded_files.push(uploaded_file);
-                    }
-                    Err(err) => {
-                        log::error!("Upload error: {}", err);
+    match cli.command.unwrap_or_else(|| Command::Serve(ServeArgs::default())) {
+        Command::Serve(args) => {
+            args.apply(&mut config);
+            serve(config).await
+        }
+        Command::Cleanup { max_age_secs } => cleanup(&config, max_age_secs).await,
+        Command::Migrate => migrate(&config).await,
+        Command::GenKey { .. } => unreachable!(),
+        Command::CheckPolicy { file } => check_policy(file.as_deref(), &config),
+    }
+}
 
-                        if let Some(lib::UploadError::Client(_)) = err.downcast_ref() {
-                            return web::HttpResponse::BadRequest()
-                                .json(uploaded_files_to_json_list(uploaded_files));
-                        } else {
-                            return web::HttpResponse::InternalServerError()
-                                .json(uploaded_files_to_json_list(uploaded_files));
-                        }
-                    }
-                }
-            }
-            UploadRequest::Base64(data) => match base64::decode(&data) {
-                Ok(data) => {
-                    let content_type = tree_magic::from_u8(&data);
-                    log::debug!("{}", &content_type);
+async fn serve(config: Config) -> std::io::Result<()> {
+    lib::http::start(&config)
+        .await
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))?;
+    let uploads_dir = config.uploads_dir.clone();
+    let shutdown_timeout = Duration::from_secs(config.shutdown_timeout_secs);
 
-                    let extension = match lib::mime_type_to_extension(&content_type) {
-                        Some(extension) => extension,
-                        None => {
-                            return web::HttpResponse::UnsupportedMediaType()
-                                .json(uploaded_files_to_json_list(uploaded_files));
-                        }
-                    };
+    // Sockets systemd opened for us, see `ListenerConfig::listen_fd`
+    let mut activated = listenfd::ListenFd::from_env();
+    let mut servers = Vec::new();
+    for listener in config.listeners() {
+        if let Some(port) = listener.redirect_to_https {
+            if listener.kind != ListenerKind::Http {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("redirect_to_https on {} needs an http listener", listener.address),
+                ));
+            }
+            tracing::info!("Redirecting {} to HTTPS on port {}", listener.address, port);
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(web::Data::new(port))
+                    .default_service(web::route().to(lib::http::https_redirect))
+            })
+            .shutdown_timeout(shutdown_timeout.as_secs());
+            let server = match listener.listen_fd {
+                Some(index) => server.listen(lib::listeners::activated_tcp(&mut activated, index)?)?,
+                None => server.bind(&listener.address)?,
+            };
+            servers.push(server.run());
+            continue;
+        }
 
-                    let data = bytes::Bytes::from(data);
-                    let stream = tokio::stream::once(Ok::<_, failure::Error>(data));
-                    let res =
-                        lib::upload_image(stream, &config.get_ref().uploads_dir, extension).await;
-                    match res {
-                        Ok(uploaded_file) => {
-                            log::info!(
-                                "Upload succeed, id: {}, path: {}, thumbnail: {}",
-                                uploaded_file.id,
-                                uploaded_file.path.to_str().unwrap_or("?"),
-                                if let Some(ref path) = uploaded_file.thumbnail_path {
-                                    path.to_str().unwrap_or("?")
-                                } else {
-                                    "Failed to create"
-                                },
-                            );
+        let config = config.clone();
+        let log_tls_fingerprints = config.log_tls_fingerprints;
+        let routes = listener.routes;
 
-                            uploaded_files.push(uploaded_file);
-                        }
-                        Err(err) => {
-                            log::error!("Upload error: {}", err);
+        let server = HttpServer::new(move || {
+            let config = config.clone();
+            App::new().configure(move |cfg| lib::http::configure_routes(cfg, config, routes))
+        })
+        // Stops accepting on SIGTERM/SIGINT and waits for running requests
+        .shutdown_timeout(shutdown_timeout.as_secs());
 
-                            if let Some(lib::UploadError::Client(_)) = err.downcast_ref() {
-                                return web::HttpResponse::BadRequest()
-                                    .json(uploaded_files_to_json_list(uploaded_files));
-                            } else {
-                                return web::HttpResponse::InternalServerError()
-                                    .json(uploaded_files_to_json_list(uploaded_files));
-                            }
-                        }
+        match listener.listen_fd {
+            Some(index) => tracing::info!(
+                "Listening on {:?} socket {} from systemd ({:?} routes)",
+                listener.kind,
+                index,
+                listener.routes
+            ),
+            None => tracing::info!(
+                "Listening on {:?} {} ({:?} routes)",
+                listener.kind,
+                listener.address,
+                listener.routes
+            ),
+        }
+        let server = match listener.kind {
+            ListenerKind::Http => match listener.listen_fd {
+                Some(index) => server.listen(lib::listeners::activated_tcp(&mut activated, index)?)?,
+                None => server.bind(&listener.address)?,
+            },
+            ListenerKind::Https => {
+                let server = if log_tls_fingerprints {
+                    let connections = lib::http::tls_fingerprints();
+                    server.on_connect(move |connection, _| connections.on_connect(connection))
+                } else {
+                    server
+                };
+                let (tls_config, cert) = lib::listeners::load_tls_config(&listener).map_err(|err| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("TLS error: {}", err))
+                })?;
+                cert.spawn_reloader(Duration::from_secs(listener.tls_reload_secs));
+                match listener.listen_fd {
+                    Some(index) => {
+                        server.listen_rustls_0_23(lib::listeners::activated_tcp(&mut activated, index)?, tls_config)?
                     }
+                    None => server.bind_rustls_0_23(&listener.address, tls_config)?,
                 }
-                Err(err) => {
-                    log::error!("Base64 decode error: {}", err);
-
-                    return web::HttpResponse::BadRequest()
-                        .json(uploaded_files_to_json_list(uploaded_files));
+            }
+            #[cfg(unix)]
+            ListenerKind::Unix => match listener.listen_fd {
+                Some(index) => server.listen_uds(lib::listeners::activated_unix(&mut activated, index)?)?,
+                None => {
+                    let server = server.bind_uds(&listener.address)?;
+                    lib::listeners::set_unix_mode(&listener)?;
+                    server
                 }
             },
-        }
+            #[cfg(not(unix))]
+            ListenerKind::Unix => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "unix listeners are not supported on this platform",
+                ))
+            }
+        };
+        servers.push(server.run());
     }
 
-    if !uploaded_files.is_empty() {
-        log::info!(
-            "Uploaded {} file{} in total (application/json)",
-            uploaded_files.len(),
-            if uploaded_files.len() > 1 { "s" } else { "" },
-        );
-
-        return web::HttpResponse::Ok()
-            .json(uploaded_files_to_json_list(uploaded_files));
-    } else {
-        return web::HttpResponse::BadRequest()
-            .json(uploaded_files_to_json_list(uploaded_files));
+    // Every server stops on the same signal
+    for server in servers {
+        server.await?;
     }
-}
-
-#[actix_rt::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init();
-
-    let config = Config {
-        host: "0.0.0.0".into(),
-        port: 8080,
-        uploads_dir: "/tmp/uploads".into(),
-        max_json_payload_size: 1 << 20,
-    };
 
-    tokio::fs::create_dir_all(&config.uploads_dir).await?;
+    // Import jobs and tus finalization aren't requests, wait for them too
+    if !lib::shutdown::drain(shutdown_timeout).await {
+        tracing::warn!("{} upload(s) still running at shutdown", lib::shutdown::in_flight());
+    }
 
-    let (host, port) = (config.host.clone(), config.port);
+    match lib::shutdown::remove_temp_files(&uploads_dir).await {
+        Ok(0) => {}
+        Ok(removed) => tracing::info!("Removed {} temp file(s) at shutdown", removed),
+        Err(err) => tracing::error!("Temp file cleanup error: {}", err),
+    }
 
-    HttpServer::new(move || {
-        App::new()
-            .data(config.clone())
-            .app_data(web::Json::<Vec<UploadRequest>>::configure(|cfg| {
-                cfg.limit(config.max_json_payload_size)
-            }))
-            .service(
-                web::scope("/upload")
-                    .guard(guard::Post())
-                    .guard(guard::fn_guard(|req| {
-                        if let Some(content_type) = req.headers().get("content-type") {
-                            if let Ok(s) = content_type.to_str() {
-                                s.starts_with("multipart/form-data;")
-                            } else { false }
-                        } else { false }
-                    }))
-                    .route("", web::post().to(upload_multipart)),
-            )
-            .service(
-                web::scope("/upload")
-                    .guard(guard::Post())
-                    .guard(guard::fn_guard(|req| {
-                        if let Some(content_type) = req.headers().get("content-type") {
-                            if let Ok(s) = content_type.to_str() {
-                                s == "application/json"
-                            } else { false }
-                        } else { false }
-                    }))
-                    .route("", web::post().to(upload_json)),
-            )
-            // Handle application/x-www-form-urlencoded ?
-            .service(
-                web::scope("/upload")
-                    .route("", web::to(|| HttpResponse::BadRequest()))
-            )
-    })
-    .bind((host.as_ref(), port))?
-    .run()
-    .await
+    Ok(())
 }
@@ -0,0 +1,444 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::blocklist::HashKind;
+use crate::ocr::OcrResult;
+use crate::provenance::Provenance;
+use crate::{clock, delete_upload, imagetools, is_valid_id, layout, quota};
+
+pub const METADATA_DIR: &str = "meta";
+
+// Сведения о загруженном изображении, хранятся рядом в meta/{id}.json
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageMetadata {
+    pub id: String,
+    pub extension: String,
+    pub size: u64,
+    pub width: u32,
+    pub height: u32,
+    pub created_at: u64,
+    // Unix time after which the upload is deleted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    // Hex SHA-256 of the stored file, the ETag of downloads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    // Unix time before which only the owner and admins see the upload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publish_at: Option<u64>,
+    // Name of the API key that uploaded it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    // Of the uploader's API key; its files are under the tenant's prefix,
+    // see `layout::shard_dir`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    // Set for images made from other images (copies, edits)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provenance: Option<Provenance>,
+    // Only available uploads are served to anyone, see `UploadState`
+    #[serde(default = "default_state")]
+    pub status: UploadState,
+    // As sent by the client, see `sanitize_filename`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original_filename: Option<String>,
+    // Client key/value pairs, see `check_custom`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub custom: BTreeMap<String, String>,
+    // Of the manifest row an import fetched it for
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    // The URL a `url` upload was fetched from, after redirects
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_url: Option<String>,
+    // Animated GIF or WebP or a video only, see `imagetools::Animation`; a
+    // video's frames may be 0, when its container doesn't tell
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frames: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    // Placeholder for frontends, see `imagetools::blurhash`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+    // `#rrggbb`, the most common first, see `imagetools::dominant_colors`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dominant_colors: Vec<String>,
+    // 16 hex digits, see `imagetools::perceptual_hash`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phash: Option<String>,
+    // Unix time of the last download, recorded at most daily, see
+    // `tiering::touch`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accessed_at: Option<u64>,
+    // Unix time the original moved to cold storage, see `tiering`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archived_at: Option<u64>,
+    // Set when the upload matched the blocklist and was held pending
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quarantine: Option<Quarantine>,
+    // Of the moderation scanners, see `moderation::scan`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub moderation_scores: BTreeMap<String, f32>,
+    // Why a scanner held the upload pending or quarantined it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub moderation_hold: Option<String>,
+    // By language, see `ocr::extract`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub ocr: BTreeMap<String, OcrResult>,
+}
+
+// Где загрузка в своём жизненном цикле. A new upload is pending, processing
+// or available; the blocklist and scanners may quarantine it, an admin
+// approves it to available, a deletion passes through deleted. Only
+// available ones are served to anyone, admins see the rest but deleted ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadState {
+    // Waiting for a moderator or the moderation hook
+    Pending,
+    // Stored, its thumbnail still being made, see `UploadOptions::hold_until_processed`
+    Processing,
+    // Uploads from before there were states said "approved"
+    #[serde(alias = "approved")]
+    Available,
+    // Matched the blocklist or a scanner's quarantine threshold
+    Quarantined,
+    // Being deleted; left behind if the deletion broke off
+    Deleted,
+}
+
+// Uploads made before moderation are available
+pub fn default_state() -> UploadState {
+    UploadState::Available
+}
+
+// The blocklist entry a quarantined upload matched
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quarantine {
+    pub kind: HashKind,
+    pub hash: String,
+    pub reason: String,
+}
+
+pub fn now() -> u64 {
+    clock::unix_now()
+}
+
+impl ImageMetadata {
+    pub fn is_available(&self) -> bool {
+        self.status == UploadState::Available
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.status == UploadState::Deleted
+    }
+
+    pub fn is_published(&self) -> bool {
+        self.publish_at.map(|publish_at| publish_at <= now()).unwrap_or(true)
+    }
+
+    // Served to anyone
+    pub fn is_public(&self) -> bool {
+        self.is_available() && self.is_published()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.map(|expires_at| expires_at <= now()).unwrap_or(false)
+    }
+
+    pub fn expires_at_time(&self) -> Option<SystemTime> {
+        self.expires_at.map(|expires_at| UNIX_EPOCH + Duration::from_secs(expires_at))
+    }
+
+    pub fn perceptual_hash(&self) -> Option<u64> {
+        self.phash.as_ref().and_then(|phash| u64::from_str_radix(phash, 16).ok())
+    }
+}
+
+pub fn format_perceptual_hash(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+const MAX_FILENAME_LEN: usize = 255;
+const MAX_CUSTOM_ENTRIES: usize = 32;
+const MAX_CUSTOM_KEY_LEN: usize = 64;
+const MAX_CUSTOM_VALUE_LEN: usize = 1024;
+
+// The last path component without control characters, cut to 255 bytes;
+// `None` if nothing is left
+pub fn sanitize_filename(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let mut sanitized = String::new();
+    for c in name.chars().filter(|c| !c.is_control()) {
+        if sanitized.len() + c.len_utf8() > MAX_FILENAME_LEN {
+            break;
+        }
+        sanitized.push(c);
+    }
+
+    let sanitized = sanitized.trim();
+    if sanitized.is_empty() || sanitized == "." || sanitized == ".." {
+        None
+    } else {
+        Some(sanitized.to_owned())
+    }
+}
+
+// Keys are `[A-Za-z0-9_.-]`, both keys and values are bounded
+pub fn check_custom(custom: &BTreeMap<String, String>) -> Result<(), String> {
+    if custom.len() > MAX_CUSTOM_ENTRIES {
+        return Err(format!("at most {} metadata entries", MAX_CUSTOM_ENTRIES));
+    }
+    for (key, value) in custom {
+        let valid_key = key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-');
+        if key.is_empty() || key.len() > MAX_CUSTOM_KEY_LEN || !valid_key {
+            return Err(format!("invalid metadata key {:?}", key));
+        }
+        if value.len() > MAX_CUSTOM_VALUE_LEN {
+            return Err(format!("metadata value of {:?} is over {} bytes", key, MAX_CUSTOM_VALUE_LEN));
+        }
+    }
+    Ok(())
+}
+
+// Blocking, run it on a dedicated thread
+pub fn file_checksum<P: AsRef<Path>>(path: P) -> std::io::Result<String> {
+    use std::io::Read;
+
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 64 * 1024];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+pub fn metadata_path<P: AsRef<Path>>(uploads_dir: P, id: &str) -> PathBuf {
+    uploads_dir.as_ref().join(METADATA_DIR).join(format!("{}.json", id))
+}
+
+pub async fn save<P: AsRef<Path>>(uploads_dir: P, metadata: &ImageMetadata) -> Result<()> {
+    let path = metadata_path(&uploads_dir, &metadata.id);
+    tokio::fs::create_dir_all(uploads_dir.as_ref().join(METADATA_DIR)).await?;
+
+    // Write-then-rename, a reader never sees a torn file
+    let tmp_path = path.with_extension("json.tmp");
+    tokio::fs::write(&tmp_path, serde_json::to_vec(metadata)?).await?;
+    tokio::fs::rename(&tmp_path, &path).await?;
+    quota::record(metadata);
+    if let Some(tenant) = &metadata.tenant {
+        layout::set_tenant(&metadata.id, tenant);
+    }
+    Ok(())
+}
+
+// Uploads made before the metadata store existed have none
+pub async fn load<P: AsRef<Path>>(uploads_dir: P, id: &str) -> Result<Option<ImageMetadata>> {
+    if !is_valid_id(id) {
+        return Ok(None);
+    }
+
+    match tokio::fs::read(metadata_path(uploads_dir, id)).await {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Moves an upload from one state to another; false if it is gone or in
+// another state by now
+pub async fn transition<P: AsRef<Path>>(uploads_dir: P, id: &str, from: UploadState, to: UploadState) -> Result<bool> {
+    match load(&uploads_dir, id).await? {
+        Some(mut metadata) if metadata.status == from => {
+            metadata.status = to;
+            save(&uploads_dir, &metadata).await?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+// Hides an upload before its files go, see `delete_upload`
+pub async fn mark_deleted<P: AsRef<Path>>(uploads_dir: P, id: &str) -> std::io::Result<()> {
+    let to_io = |err: anyhow::Error| std::io::Error::other(err.to_string());
+    match load(&uploads_dir, id).await.map_err(to_io)? {
+        Some(mut metadata) if !metadata.is_deleted() => {
+            metadata.status = UploadState::Deleted;
+            save(&uploads_dir, &metadata).await.map_err(to_io)
+        }
+        _ => Ok(()),
+    }
+}
+
+pub async fn remove<P: AsRef<Path>>(uploads_dir: P, id: &str) -> std::io::Result<()> {
+    quota::forget(id);
+    match tokio::fs::remove_file(metadata_path(uploads_dir, id)).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ListFilter {
+    pub extension: Option<String>,
+    pub uploaded_after: Option<u64>,
+    // Any but deleted when unset
+    pub status: Option<UploadState>,
+    // Only the tenant's uploads when set
+    pub tenant: Option<String>,
+}
+
+impl ListFilter {
+    fn matches(&self, metadata: &ImageMetadata) -> bool {
+        self.extension.as_ref().map(|e| *e == metadata.extension).unwrap_or(true)
+            && self.uploaded_after.map(|after| metadata.created_at > after).unwrap_or(true)
+            && self
+                .status
+                .map(|status| status == metadata.status)
+                .unwrap_or(!metadata.is_deleted())
+            && self.tenant.as_ref().map(|t| metadata.tenant.as_ref() == Some(t)).unwrap_or(true)
+            && !metadata.is_expired()
+    }
+}
+
+// A page of records ordered by id, starting after `cursor`. Returns the
+// cursor of the next page, if there is one.
+pub async fn list<P: AsRef<Path>>(
+    uploads_dir: P,
+    filter: &ListFilter,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Result<(Vec<ImageMetadata>, Option<String>)> {
+    let mut ids = Vec::new();
+
+    let mut dir = match tokio::fs::read_dir(uploads_dir.as_ref().join(METADATA_DIR)).await {
+        Ok(dir) => dir,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok((Vec::new(), None)),
+        Err(err) => return Err(err.into()),
+    };
+    while let Some(entry) = dir.next_entry().await? {
+        let name = entry.file_name();
+        let id = match name.to_str().and_then(|name| name.strip_suffix(".json")) {
+            Some(id) if is_valid_id(id) && cursor.map(|cursor| id > cursor).unwrap_or(true) => id.to_owned(),
+            _ => continue,
+        };
+        ids.push(id);
+    }
+    ids.sort();
+
+    let mut page = Vec::new();
+    for id in ids {
+        if page.len() == limit {
+            let next_cursor = page.last().map(|metadata: &ImageMetadata| metadata.id.clone());
+            return Ok((page, next_cursor));
+        }
+
+        // `None` if it was deleted since the scan
+        if let Some(metadata) = load(&uploads_dir, &id).await? {
+            if filter.matches(&metadata) {
+                page.push(metadata);
+            }
+        }
+    }
+
+    Ok((page, None))
+}
+
+// Uploads whose perceptual hash is within `max_distance` bits of `hash`,
+// the closest first, then by id; expired ones and `exclude` are left out.
+// A scan of the whole store, like the expiry reaper.
+pub async fn find_similar<P: AsRef<Path>>(
+    uploads_dir: P,
+    hash: u64,
+    max_distance: u32,
+    exclude: Option<&str>,
+) -> Result<Vec<(ImageMetadata, u32)>> {
+    let mut similar = Vec::new();
+
+    let mut dir = match tokio::fs::read_dir(uploads_dir.as_ref().join(METADATA_DIR)).await {
+        Ok(dir) => dir,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(similar),
+        Err(err) => return Err(err.into()),
+    };
+    while let Some(entry) = dir.next_entry().await? {
+        let name = entry.file_name();
+        match name.to_str().and_then(|name| name.strip_suffix(".json")) {
+            Some(id) if is_valid_id(id) && Some(id) != exclude => {}
+            _ => continue,
+        }
+
+        let metadata: ImageMetadata = match serde_json::from_slice(&tokio::fs::read(entry.path()).await?) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                tracing::warn!("Skipping metadata {:?}: {}", entry.path(), err);
+                continue;
+            }
+        };
+        if metadata.is_expired() {
+            continue;
+        }
+        if let Some(distance) = metadata.perceptual_hash().map(|other| imagetools::hamming_distance(hash, other)) {
+            if distance <= max_distance {
+                similar.push((metadata, distance));
+            }
+        }
+    }
+
+    similar.sort_by(|(a, a_distance), (b, b_distance)| a_distance.cmp(b_distance).then_with(|| a.id.cmp(&b.id)));
+    Ok(similar)
+}
+
+// Deletes uploads past their expiry together with their metadata
+pub async fn reap_expired<P: AsRef<Path>>(uploads_dir: P) -> Result<usize> {
+    let uploads_dir = uploads_dir.as_ref();
+    let mut reaped = 0;
+
+    let mut dir = match tokio::fs::read_dir(uploads_dir.join(METADATA_DIR)).await {
+        Ok(dir) => dir,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+    while let Some(entry) = dir.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+
+        let metadata: ImageMetadata = match serde_json::from_slice(&tokio::fs::read(&path).await?) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                tracing::warn!("Skipping metadata {:?}: {}", path, err);
+                continue;
+            }
+        };
+        if metadata.is_expired() {
+            delete_upload(uploads_dir, &metadata.id).await?;
+            reaped += 1;
+        }
+    }
+
+    Ok(reaped)
+}
+
+pub fn spawn_reaper(uploads_dir: PathBuf, interval: Duration) {
+    actix_rt::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match reap_expired(&uploads_dir).await {
+                Ok(0) => {}
+                Ok(reaped) => tracing::info!("Deleted {} expired upload(s)", reaped),
+                Err(err) => tracing::error!("Expiry reaper error: {}", err),
+            }
+        }
+    });
+}
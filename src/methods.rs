@@ -0,0 +1,125 @@
+use crate::{cluster, openapi};
+use crate::listeners::RouteSet;
+
+// Методы каждого маршрута, для OPTIONS и заголовка Allow. Keep in step with
+// the route table in main; HEAD goes wherever GET does.
+pub struct RouteMethods {
+    // `{name}` matches one segment, `{name:.+}` the rest of the path
+    pub pattern: &'static str,
+    pub admin: bool,
+    pub methods: &'static [&'static str],
+}
+
+const fn route(pattern: &'static str, admin: bool, methods: &'static [&'static str]) -> RouteMethods {
+    RouteMethods {
+        pattern,
+        admin,
+        methods,
+    }
+}
+
+// The order of methods in Allow
+const METHOD_ORDER: &[&str] = &["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"];
+
+const GET: &[&str] = &["GET", "HEAD"];
+const POST: &[&str] = &["POST"];
+const DELETE: &[&str] = &["DELETE"];
+
+pub const ROUTES: &[RouteMethods] = &[
+    route("/healthz", false, GET),
+    route("/readyz", false, GET),
+    route("/lb-health", false, GET),
+    route("/healthz", true, GET),
+    route("/readyz", true, GET),
+    route("/lb-health", true, GET),
+    route(cluster::PING_PATH, true, GET),
+    route("/cluster/files/{name}", true, GET),
+    route("/replication/info", true, GET),
+    route("/replication/manifest", true, GET),
+    route("/replication/files/{name:.+}", true, GET),
+    route("/replication/promote", true, POST),
+    route("/images", true, GET),
+    route("/images/{id}", true, DELETE),
+    route("/images/{id}/invalidate", true, POST),
+    route("/images/{id}/approve", true, POST),
+    route("/images/{id}/reject", true, POST),
+    route("/tenants", true, GET),
+    route("/tenants/{name}", true, &["GET", "HEAD", "PUT", "DELETE"]),
+    route("/metrics", true, GET),
+    route("/quota", true, GET),
+    route("/admin/stats", true, GET),
+    route("/admin/keys/{key}/usage", true, GET),
+    route("/admin/audit", true, GET),
+    route("/blocklist", true, &["GET", "HEAD", "POST"]),
+    route("/blocklist/{kind}/{hash}", true, DELETE),
+    route("/clock", true, GET),
+    route("/clock/advance", true, POST),
+    route("/guest/buckets", false, POST),
+    route("/imports", false, POST),
+    route("/capabilities", false, GET),
+    route(openapi::SPEC_PATH, false, GET),
+    route(openapi::DOCS_PATH, false, GET),
+    route(openapi::DOCS_SCRIPT_PATH, false, GET),
+    route("/imports/{id}", false, GET),
+    route("/imports/{id}/report", false, GET),
+    route("/images/{id}", false, GET),
+    route("/images/{id}/thumbnail", false, GET),
+    route("/images/{id}/transform", false, GET),
+    route("/images/{id}/metadata", false, GET),
+    route("/images/{id}/info", false, GET),
+    route("/images/{id}/similar", false, GET),
+    route("/images/{id}/status", false, GET),
+    route("/images/{id}/sign", false, POST),
+    route("/images/{id}/copy", false, POST),
+    route("/images/{id}/edit", false, POST),
+    route("/images/{id}/provenance", false, GET),
+    route("/collections", false, POST),
+    route("/collections/{id}", false, &["GET", "HEAD", "DELETE"]),
+    route("/collections/{id}/contact-sheet", false, GET),
+    route("/upload/presign", false, POST),
+    route("/upload/tus", false, POST),
+    route("/upload/tus/{id}", false, &["HEAD", "PATCH"]),
+    route("/upload", false, POST),
+];
+
+fn matches(pattern: &str, path: &str) -> bool {
+    let mut path_segments = path.trim_start_matches('/').split('/');
+    for segment in pattern.trim_start_matches('/').split('/') {
+        if segment.starts_with('{') && segment.ends_with(":.+}") {
+            return path_segments.any(|segment| !segment.is_empty());
+        }
+        match path_segments.next() {
+            Some(part) if segment.starts_with('{') && !part.is_empty() => {}
+            Some(part) if part == segment => {}
+            _ => return false,
+        }
+    }
+    path_segments.next().is_none()
+}
+
+// The Allow header value for `path` on a listener serving `routes`, `None`
+// if nothing is served there
+pub fn allowed(path: &str, routes: RouteSet) -> Option<String> {
+    let mut methods: Vec<&str> = Vec::new();
+    for route in ROUTES {
+        let served = if route.admin {
+            routes.has_admin()
+        } else {
+            routes.has_public()
+        };
+        if served && matches(route.pattern, path) {
+            for method in route.methods {
+                if !methods.contains(method) {
+                    methods.push(method);
+                }
+            }
+        }
+    }
+
+    if methods.is_empty() {
+        return None;
+    }
+    methods.sort_by_key(|method| METHOD_ORDER.iter().position(|known| known == method));
+    methods.push("OPTIONS");
+    Some(methods.join(", "))
+}
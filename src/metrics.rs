@@ -0,0 +1,68 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Счётчики для мониторинга, GET /metrics отдаёт их в текстовом формате
+// Prometheus. Process-wide and reset on restart, as Prometheus expects.
+
+// Why a POST /upload (or another method on /upload) matched no handler
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnmatchedUpload {
+    Method,
+    ContentType,
+}
+
+impl UnmatchedUpload {
+    fn label(self) -> &'static str {
+        match self {
+            UnmatchedUpload::Method => "method",
+            UnmatchedUpload::ContentType => "content_type",
+        }
+    }
+}
+
+const UNMATCHED_UPLOAD_REASONS: [UnmatchedUpload; 2] = [UnmatchedUpload::Method, UnmatchedUpload::ContentType];
+
+static UNMATCHED_UPLOADS: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+// Mostly misconfigured clients: a wrong method or Content-Type
+pub fn unmatched_upload(reason: UnmatchedUpload) {
+    UNMATCHED_UPLOADS[reason as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn unmatched_uploads(reason: UnmatchedUpload) -> u64 {
+    UNMATCHED_UPLOADS[reason as usize].load(Ordering::Relaxed)
+}
+
+static THUMBNAILS_CREATED: AtomicU64 = AtomicU64::new(0);
+static THUMBNAILS_FAILED: AtomicU64 = AtomicU64::new(0);
+
+// Every thumbnail attempt: inline, queued or regenerated
+pub fn thumbnail(created: bool) {
+    let counter = if created { &THUMBNAILS_CREATED } else { &THUMBNAILS_FAILED };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+// Created and failed since the start
+pub fn thumbnails() -> (u64, u64) {
+    (THUMBNAILS_CREATED.load(Ordering::Relaxed), THUMBNAILS_FAILED.load(Ordering::Relaxed))
+}
+
+pub fn render() -> String {
+    let mut out = String::new();
+    out.push_str("# HELP rr_api_unmatched_uploads_total Upload requests with an unsupported method or Content-Type\n");
+    out.push_str("# TYPE rr_api_unmatched_uploads_total counter\n");
+    for reason in UNMATCHED_UPLOAD_REASONS {
+        let _ = writeln!(
+            out,
+            "rr_api_unmatched_uploads_total{{reason=\"{}\"}} {}",
+            reason.label(),
+            unmatched_uploads(reason)
+        );
+    }
+    let (created, failed) = thumbnails();
+    out.push_str("# HELP rr_api_thumbnails_total Thumbnails made, by whether making them succeeded\n");
+    out.push_str("# TYPE rr_api_thumbnails_total counter\n");
+    let _ = writeln!(out, "rr_api_thumbnails_total{{result=\"created\"}} {}", created);
+    let _ = writeln!(out, "rr_api_thumbnails_total{{result=\"failed\"}} {}", failed);
+    out
+}
@@ -0,0 +1,321 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::metadata::{ImageMetadata, UploadState};
+use crate::{delete_upload, encryption, extension_to_mime_type, metadata};
+
+// Классификатор ONNX, see `onnx::OnnxScanner`
+#[cfg(feature = "moderation-onnx")]
+mod onnx;
+
+// Модерация: новые загрузки не публикуются до одобрения
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ModerationConfig {
+    pub enabled: bool,
+    // Every new upload is POSTed here; `{"approved": true}` publishes it,
+    // anything else leaves it for review via the admin API
+    pub hook_url: String,
+    pub hook_timeout_secs: u64,
+    // Score every upload before it is stored, see `scan`; independent of
+    // `enabled`
+    pub scanners: Vec<ScannerConfig>,
+    // By label, what a score over each threshold does
+    pub thresholds: BTreeMap<String, Threshold>,
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        ModerationConfig {
+            enabled: false,
+            hook_url: String::new(),
+            hook_timeout_secs: 30,
+            scanners: Vec::new(),
+            thresholds: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum ScannerConfig {
+    // POSTs the file, expects `{"scores": {"nsfw": 0.97, ...}}`
+    Http {
+        url: String,
+        #[serde(default = "default_scanner_timeout_secs")]
+        timeout_secs: u64,
+    },
+    // Needs the `moderation-onnx` feature
+    Onnx(OnnxConfig),
+}
+
+fn default_scanner_timeout_secs() -> u64 {
+    30
+}
+
+// An image classifier taking one RGB image and giving a probability per
+// label, e.g. an NSFW model
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OnnxConfig {
+    pub model: PathBuf,
+    // Of the outputs, in order
+    pub labels: Vec<String>,
+    // Pixels a side of the input
+    pub input_size: u32,
+    // NHWC input instead of NCHW
+    pub channels_last: bool,
+    // Per channel, applied to values scaled to 0-1
+    pub mean: [f32; 3],
+    pub std: [f32; 3],
+    // For models giving logits
+    pub softmax: bool,
+}
+
+impl Default for OnnxConfig {
+    fn default() -> Self {
+        OnnxConfig {
+            model: PathBuf::new(),
+            labels: Vec::new(),
+            input_size: 224,
+            channels_last: false,
+            mean: [0.485, 0.456, 0.406],
+            std: [0.229, 0.224, 0.225],
+            softmax: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Threshold {
+    // Held pending for review
+    pub quarantine: Option<f32>,
+    // Refused
+    pub reject: Option<f32>,
+}
+
+// Makes a pending or quarantined upload available; returns false if there
+// is no such upload
+pub async fn approve<P: AsRef<Path>>(uploads_dir: P, id: &str) -> Result<bool> {
+    match metadata::load(&uploads_dir, id).await? {
+        Some(image_metadata) if image_metadata.is_deleted() => Ok(false),
+        Some(mut image_metadata) => {
+            image_metadata.status = UploadState::Available;
+            metadata::save(&uploads_dir, &image_metadata).await?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+// A rejected upload is deleted, there is nothing to keep it for
+pub async fn reject<P: AsRef<Path>>(uploads_dir: P, id: &str) -> Result<bool> {
+    if metadata::load(&uploads_dir, id).await?.is_none() {
+        return Ok(false);
+    }
+    delete_upload(uploads_dir.as_ref(), id).await?;
+    Ok(true)
+}
+
+#[derive(Deserialize)]
+struct HookResponse {
+    #[serde(default)]
+    approved: bool,
+}
+
+async fn ask_hook(config: &ModerationConfig, id: &str, path: &Path) -> Result<bool> {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    // The original may be sealed by now
+    let body = encryption::read(path).await?;
+
+    let resp = reqwest::Client::new()
+        .post(&config.hook_url)
+        .timeout(Duration::from_secs(config.hook_timeout_secs))
+        .header("Content-Type", extension_to_mime_type(extension).unwrap_or("application/octet-stream"))
+        .header("X-Image-Id", id)
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    let hook_response: HookResponse = serde_json::from_slice(&resp.bytes().await?)?;
+    Ok(hook_response.approved)
+}
+
+// Sends a pending upload to the hook in the background
+pub fn spawn_review(config: &ModerationConfig, uploads_dir: PathBuf, id: String, path: PathBuf) {
+    if config.hook_url.is_empty() {
+        return;
+    }
+
+    let config = config.clone();
+    actix_rt::spawn(async move {
+        match ask_hook(&config, &id, &path).await {
+            Ok(true) => match approve(&uploads_dir, &id).await {
+                Ok(_) => tracing::info!("Upload {} auto-approved by the moderation hook", id),
+                Err(err) => tracing::error!("Error approving {}: {}", id, err),
+            },
+            Ok(false) => tracing::info!("Upload {} left for manual review", id),
+            Err(err) => tracing::warn!("Moderation hook failed for {}, left for manual review: {}", id, err),
+        }
+    });
+}
+
+// Label to score, 0 to 1
+pub type Scores = BTreeMap<String, f32>;
+
+pub type ScanFuture<'a> = Pin<Box<dyn Future<Output = Result<Scores>> + Send + 'a>>;
+
+// Сканер загрузок: scores a new upload before it is stored. The configured
+// ones are installed at startup; an application embedding `http` may
+// `register` its own.
+pub trait Scanner: Send + Sync {
+    fn name(&self) -> &str;
+    // `path` is the uploaded file, not yet in place
+    fn scan<'a>(&'a self, id: &'a str, path: &'a Path, mime: &'a str) -> ScanFuture<'a>;
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Rejected by moderation: {label} score {score} is over {threshold}")]
+pub struct ModerationRejected {
+    pub label: String,
+    pub score: f32,
+    pub threshold: f32,
+}
+
+static SCANNERS: OnceLock<RwLock<Vec<Arc<dyn Scanner>>>> = OnceLock::new();
+
+fn scanners() -> &'static RwLock<Vec<Arc<dyn Scanner>>> {
+    SCANNERS.get_or_init(Default::default)
+}
+
+pub fn register(scanner: Arc<dyn Scanner>) {
+    scanners().write().unwrap().push(scanner);
+}
+
+// Once at startup, returns the number of scanners
+pub fn install(config: &ModerationConfig) -> Result<usize> {
+    for scanner in &config.scanners {
+        match scanner {
+            ScannerConfig::Http { url, timeout_secs } => register(Arc::new(HttpScanner {
+                url: url.clone(),
+                timeout: Duration::from_secs(*timeout_secs),
+            })),
+            #[cfg(feature = "moderation-onnx")]
+            ScannerConfig::Onnx(onnx_config) => register(Arc::new(onnx::OnnxScanner::load(onnx_config)?)),
+            #[cfg(not(feature = "moderation-onnx"))]
+            ScannerConfig::Onnx(_) => {
+                return Err(anyhow::anyhow!("this build has no ONNX scanner, see the moderation-onnx feature"))
+            }
+        }
+    }
+    Ok(scanners().read().unwrap().len())
+}
+
+// An external moderation API
+pub struct HttpScanner {
+    pub url: String,
+    pub timeout: Duration,
+}
+
+#[derive(Deserialize)]
+struct ScanResponse {
+    scores: Scores,
+}
+
+impl Scanner for HttpScanner {
+    fn name(&self) -> &str {
+        &self.url
+    }
+
+    fn scan<'a>(&'a self, id: &'a str, path: &'a Path, mime: &'a str) -> ScanFuture<'a> {
+        Box::pin(async move {
+            let body = tokio::fs::read(path).await?;
+            let resp = reqwest::Client::new()
+                .post(&self.url)
+                .timeout(self.timeout)
+                .header("Content-Type", mime)
+                .header("X-Image-Id", id)
+                .body(body)
+                .send()
+                .await?
+                .error_for_status()?;
+            let scan_response: ScanResponse = serde_json::from_slice(&resp.bytes().await?)?;
+            Ok(scan_response.scores)
+        })
+    }
+}
+
+// Runs every scanner over a new upload and records the scores, the highest
+// of each label. A score over a `reject` threshold is an error, over a
+// `quarantine` one quarantines the upload; if a scanner fails, it is held
+// pending.
+pub async fn scan(
+    config: &ModerationConfig,
+    path: &Path,
+    image_metadata: &mut ImageMetadata,
+) -> Result<(), ModerationRejected> {
+    let scanners = scanners().read().unwrap().clone();
+    if scanners.is_empty() {
+        return Ok(());
+    }
+
+    let mime = extension_to_mime_type(&image_metadata.extension).unwrap_or("application/octet-stream");
+    let (mut hold, mut quarantine_reason) = (None, None);
+    for scanner in &scanners {
+        match scanner.scan(&image_metadata.id, path, mime).await {
+            Ok(scores) => {
+                for (label, score) in scores {
+                    let best = image_metadata.moderation_scores.entry(label).or_insert(score);
+                    *best = best.max(score);
+                }
+            }
+            Err(err) => {
+                tracing::error!(
+                    "Moderation scanner {} failed for {}: {}",
+                    scanner.name(),
+                    image_metadata.id,
+                    err
+                );
+                hold.get_or_insert_with(|| format!("scanner {} failed", scanner.name()));
+            }
+        }
+    }
+
+    for (label, score) in &image_metadata.moderation_scores {
+        let threshold = match config.thresholds.get(label) {
+            Some(threshold) => threshold,
+            None => continue,
+        };
+        if let Some(reject) = threshold.reject.filter(|reject| score > reject) {
+            tracing::warn!("Upload {} rejected, {} score {}", image_metadata.id, label, score);
+            return Err(ModerationRejected {
+                label: label.clone(),
+                score: *score,
+                threshold: reject,
+            });
+        }
+        if let Some(quarantine) = threshold.quarantine.filter(|quarantine| score > quarantine) {
+            quarantine_reason.get_or_insert_with(|| format!("{} score {} is over {}", label, score, quarantine));
+        }
+    }
+
+    if let Some(reason) = quarantine_reason {
+        tracing::warn!("Upload {} quarantined: {}", image_metadata.id, reason);
+        image_metadata.status = UploadState::Quarantined;
+        image_metadata.moderation_hold = Some(reason);
+    } else if let Some(reason) = hold {
+        tracing::warn!("Upload {} held for review: {}", image_metadata.id, reason);
+        image_metadata.status = UploadState::Pending;
+        image_metadata.moderation_hold = Some(reason);
+    }
+    Ok(())
+}
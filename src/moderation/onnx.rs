@@ -0,0 +1,91 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use ort::session::Session;
+use ort::value::Tensor;
+
+use super::{OnnxConfig, ScanFuture, Scanner, Scores};
+use crate::{concurrency, imagetools};
+
+// Локальный классификатор на ONNX Runtime. The runtime library is loaded
+// when the first model is, from ORT_DYLIB_PATH or the library path, so the
+// build doesn't need it.
+pub struct OnnxScanner {
+    name: String,
+    config: Arc<OnnxConfig>,
+    // A session runs one input at a time
+    session: Arc<Mutex<Session>>,
+}
+
+impl OnnxScanner {
+    pub fn load(config: &OnnxConfig) -> Result<OnnxScanner> {
+        if config.labels.is_empty() || config.input_size == 0 {
+            return Err(anyhow::anyhow!("an onnx scanner needs labels and an input_size"));
+        }
+        let session = Session::builder()?.commit_from_file(&config.model)?;
+        Ok(OnnxScanner {
+            name: config.model.display().to_string(),
+            config: Arc::new(config.clone()),
+            session: Arc::new(Mutex::new(session)),
+        })
+    }
+}
+
+// Normalized floats of row-major RGB pixels, NCHW or NHWC
+fn input(config: &OnnxConfig, pixels: &[u8]) -> Vec<f32> {
+    let normalize = |value: u8, channel: usize| (f32::from(value) / 255.0 - config.mean[channel]) / config.std[channel];
+    if config.channels_last {
+        return pixels.iter().enumerate().map(|(i, value)| normalize(*value, i % 3)).collect();
+    }
+    (0..3)
+        .flat_map(|channel| pixels.chunks_exact(3).map(move |pixel| normalize(pixel[channel], channel)))
+        .collect()
+}
+
+fn softmax(values: &mut [f32]) {
+    let max = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let mut sum = 0.0;
+    for value in values.iter_mut() {
+        *value = (*value - max).exp();
+        sum += *value;
+    }
+    for value in values.iter_mut() {
+        *value /= sum;
+    }
+}
+
+fn classify(config: &OnnxConfig, session: &Mutex<Session>, path: &Path) -> Result<Scores> {
+    let side = config.input_size as usize;
+    let pixels = imagetools::rgb_square(path, config.input_size)?;
+    let shape = if config.channels_last { [1, side, side, 3] } else { [1, 3, side, side] };
+    let tensor = Tensor::from_array((shape, input(config, &pixels)))?;
+
+    let mut session = session.lock().unwrap();
+    let outputs = session.run(ort::inputs![tensor])?;
+    let (_, output) = outputs[0].try_extract_tensor::<f32>()?;
+    if output.len() != config.labels.len() {
+        return Err(anyhow::anyhow!(
+            "the model gives {} scores for {} labels",
+            output.len(),
+            config.labels.len()
+        ));
+    }
+
+    let mut scores = output.to_vec();
+    if config.softmax {
+        softmax(&mut scores);
+    }
+    Ok(config.labels.iter().cloned().zip(scores).collect())
+}
+
+impl Scanner for OnnxScanner {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn scan<'a>(&'a self, _id: &'a str, path: &'a Path, _mime: &'a str) -> ScanFuture<'a> {
+        let (config, session, path) = (self.config.clone(), self.session.clone(), path.to_owned());
+        Box::pin(async move { concurrency::run_blocking(move || classify(&config, &session, &path)).await? })
+    }
+}
@@ -0,0 +1,44 @@
+use mime::Mime;
+
+use crate::{extension_to_mime_type, mime_type_to_extension, STORED_EXTENSIONS};
+
+// How the body of POST /upload is read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UploadBody {
+    // One image per field
+    Multipart,
+    // `[{"url": ...}, {"base64": ...}]`
+    Json,
+    // `url=...` or `base64=...`, a single image
+    Form,
+    // The body is the image; `None` for application/octet-stream, the
+    // type is sniffed from the bytes
+    Raw(Option<&'static str>),
+}
+
+// Parses the Content-Type the way clients actually send it: any case, with
+// or without parameters, `+json` suffixes included. `None` is a 415.
+pub fn upload_body(content_type: Option<&str>) -> Option<UploadBody> {
+    let mime: Mime = content_type?.trim().parse().ok()?;
+
+    match (mime.type_(), mime.subtype(), mime.suffix()) {
+        (mime::MULTIPART, mime::FORM_DATA, _) => Some(UploadBody::Multipart),
+        (mime::APPLICATION, mime::JSON, _) | (mime::APPLICATION, _, Some(mime::JSON)) => Some(UploadBody::Json),
+        (mime::APPLICATION, mime::WWW_FORM_URLENCODED, _) => Some(UploadBody::Form),
+        (mime::APPLICATION, mime::OCTET_STREAM, _) => Some(UploadBody::Raw(None)),
+        (mime::IMAGE, _, _) | (mime::VIDEO, _, _) => mime_type_to_extension(mime.essence_str()).map(|ext| UploadBody::Raw(Some(ext))),
+        _ => None,
+    }
+}
+
+// Everything `upload_body` accepts, for the 415 and 405 of POST /upload
+pub fn supported_content_types() -> Vec<&'static str> {
+    let mut content_types = vec![
+        "multipart/form-data",
+        "application/json",
+        "application/x-www-form-urlencoded",
+        "application/octet-stream",
+    ];
+    content_types.extend(STORED_EXTENSIONS.iter().filter_map(|extension| extension_to_mime_type(extension)));
+    content_types
+}
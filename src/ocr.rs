@@ -0,0 +1,139 @@
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{concurrency, encryption, find_upload, metadata};
+
+// Распознавание текста: Tesseract through leptess, in builds with the `ocr`
+// feature. A result is kept per language in the upload's metadata, asking
+// again reads it from there.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct OcrConfig {
+    pub enabled: bool,
+    // The tessdata directory, Tesseract's own default when unset
+    pub data_path: Option<String>,
+    // When a request names none
+    pub default_language: String,
+    // Codes of installed traineddata clients may ask for; "eng+deu"
+    // combines listed ones
+    pub languages: Vec<String>,
+}
+
+impl Default for OcrConfig {
+    fn default() -> Self {
+        OcrConfig {
+            enabled: false,
+            data_path: None,
+            default_language: "eng".to_owned(),
+            languages: vec!["eng".to_owned()],
+        }
+    }
+}
+
+// Whether this build links Tesseract
+pub const AVAILABLE: bool = cfg!(feature = "ocr");
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct OcrResult {
+    pub text: String,
+    // Tesseract's mean word confidence, 0-100
+    pub confidence: f32,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum OcrError {
+    #[error("Language {0:?} is not available for OCR")]
+    UnknownLanguage(String),
+    // The cause is only logged
+    #[error("Text recognition failed")]
+    Failed,
+}
+
+impl OcrConfig {
+    pub fn check_language(&self, language: &str) -> Result<(), OcrError> {
+        if language
+            .split('+')
+            .all(|part| self.languages.iter().any(|listed| listed == part))
+        {
+            Ok(())
+        } else {
+            Err(OcrError::UnknownLanguage(language.to_owned()))
+        }
+    }
+}
+
+#[cfg(feature = "ocr")]
+thread_local! {
+    // Loading a language parses its traineddata, so each processing thread
+    // keeps the engine it used last
+    static ENGINE: std::cell::RefCell<Option<(String, leptess::LepTess)>> = const { std::cell::RefCell::new(None) };
+}
+
+// Blocking
+#[cfg(feature = "ocr")]
+fn recognize(path: &Path, language: &str, config: &OcrConfig) -> Result<OcrResult, OcrError> {
+    let failed = |err: &dyn std::fmt::Debug| {
+        tracing::error!("Text recognition of {} failed: {:?}", path.to_str().unwrap_or("?"), err);
+        OcrError::Failed
+    };
+
+    ENGINE.with(|engine| {
+        let mut engine = engine.borrow_mut();
+        if engine.as_ref().map(|(loaded, _)| loaded != language).unwrap_or(true) {
+            let tesseract = leptess::LepTess::new(config.data_path.as_deref(), language).map_err(|err| failed(&err))?;
+            *engine = Some((language.to_owned(), tesseract));
+        }
+        let (_, tesseract) = engine.as_mut().expect("loaded above");
+
+        tesseract.set_image(path).map_err(|err| failed(&err))?;
+        let text = tesseract.get_utf8_text().map_err(|err| failed(&err))?;
+        Ok(OcrResult {
+            text: text.trim_end().to_owned(),
+            confidence: tesseract.mean_text_conf().clamp(0, 100) as f32,
+        })
+    })
+}
+
+#[cfg(not(feature = "ocr"))]
+fn recognize(path: &Path, _language: &str, _config: &OcrConfig) -> Result<OcrResult, OcrError> {
+    tracing::error!("No OCR in this build for {}", path.to_str().unwrap_or("?"));
+    Err(OcrError::Failed)
+}
+
+// The text of an upload in `language`, and whether it was cached. `None` if
+// there is no such upload.
+pub async fn extract<P: AsRef<Path>>(
+    uploads_dir: P,
+    id: &str,
+    language: &str,
+    config: &OcrConfig,
+) -> Result<Option<(OcrResult, bool)>> {
+    config.check_language(language)?;
+    if let Some(cached) = metadata::load(&uploads_dir, id)
+        .await?
+        .and_then(|mut image_metadata| image_metadata.ocr.remove(language))
+    {
+        return Ok(Some((cached, true)));
+    }
+
+    let (path, _) = match find_upload(&uploads_dir, id).await {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let (language_clone, config) = (language.to_owned(), config.clone());
+    let result = concurrency::run_blocking(move || {
+        let path = encryption::plaintext(&path).map_err(|_| OcrError::Failed)?;
+        recognize(&path, &language_clone, &config)
+    })
+    .await??;
+
+    // Loaded again, the metadata may have changed meanwhile
+    if let Some(mut image_metadata) = metadata::load(&uploads_dir, id).await? {
+        image_metadata.ocr.insert(language.to_owned(), result.clone());
+        metadata::save(&uploads_dir, &image_metadata).await?;
+    }
+    Ok(Some((result, false)))
+}
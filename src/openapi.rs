@@ -0,0 +1,74 @@
+use serde::Deserialize;
+
+// Описание API для клиентов: GET /openapi.json, generated from the handler
+// annotations in main, and a Swagger UI page for it at /docs
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct OpenApiConfig {
+    pub enabled: bool,
+    // Where the page loads swagger-ui-dist from, without a trailing slash
+    pub swagger_ui_url: String,
+}
+
+impl Default for OpenApiConfig {
+    fn default() -> Self {
+        OpenApiConfig {
+            enabled: true,
+            swagger_ui_url: "https://unpkg.com/swagger-ui-dist@5".to_owned(),
+        }
+    }
+}
+
+pub const SPEC_PATH: &str = "/openapi.json";
+pub const DOCS_PATH: &str = "/docs";
+// The page's own script, served from here so the CSP needs no inline scripts
+pub const DOCS_SCRIPT_PATH: &str = "/docs/init.js";
+
+impl OpenApiConfig {
+    // `https://host` of `swagger_ui_url`
+    fn swagger_ui_origin(&self) -> &str {
+        let url = self.swagger_ui_url.as_str();
+        let host_start = url.find("://").map(|i| i + 3).unwrap_or(0);
+        match url[host_start..].find('/') {
+            Some(i) => &url[..host_start + i],
+            None => url,
+        }
+    }
+
+    // Replaces the locked down default, see `security`
+    pub fn docs_csp(&self) -> String {
+        let origin = self.swagger_ui_origin();
+        format!(
+            "default-src 'none'; script-src 'self' {0}; style-src 'self' {0}; img-src 'self' data: {0}; \
+             connect-src 'self'; frame-ancestors 'none'",
+            origin
+        )
+    }
+
+    pub fn docs_page(&self) -> String {
+        format!(
+            r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>API</title>
+<link rel="stylesheet" href="{0}/swagger-ui.css">
+</head>
+<body>
+<div id="swagger-ui"></div>
+<script src="{0}/swagger-ui-bundle.js"></script>
+<script src="{1}"></script>
+</body>
+</html>
+"#,
+            self.swagger_ui_url, DOCS_SCRIPT_PATH
+        )
+    }
+}
+
+pub fn docs_script() -> String {
+    format!(
+        "window.ui = SwaggerUIBundle({{ url: \"{}\", dom_id: \"#swagger-ui\" }});\n",
+        SPEC_PATH
+    )
+}
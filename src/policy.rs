@@ -0,0 +1,266 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{OnceLock, RwLock};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+// Правила приёма загрузок из одного файла политики (TOML), checked in order
+// once the type, size and dimensions of an upload are known. The first rule
+// it breaks rejects it. The file may carry its own `[[tests]]`, see
+// `check_file` and `rr-api check-policy`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PolicyConfig {
+    // No policy when unset
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    // Which uploads it applies to, all when empty. "image/*" matches a
+    // whole type.
+    #[serde(default)]
+    pub mime: Vec<String>,
+    // API key tenants
+    #[serde(default)]
+    pub tenants: Vec<String>,
+    // Rejects whatever it applies to
+    #[serde(default)]
+    pub deny: bool,
+    pub max_size: Option<u64>,
+    pub min_width: Option<u32>,
+    pub max_width: Option<u32>,
+    pub min_height: Option<u32>,
+    pub max_height: Option<u32>,
+    // Custom metadata keys that must be set and not empty
+    #[serde(default)]
+    pub required_metadata: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    // Only for `check_file`, ignored by the server
+    #[serde(default)]
+    pub tests: Vec<TestCase>,
+}
+
+// What a rule sees of an upload
+#[derive(Debug, Clone, Copy)]
+pub struct Subject<'a> {
+    pub mime: &'a str,
+    pub size: u64,
+    pub width: u32,
+    pub height: u32,
+    pub tenant: Option<&'a str>,
+    pub metadata: &'a BTreeMap<String, String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Rejected by policy rule {rule:?}: {reason}")]
+pub struct PolicyViolation {
+    pub rule: String,
+    pub reason: String,
+}
+
+fn mime_matches(pattern: &str, mime: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(type_) => mime.split('/').next() == Some(type_),
+        None => pattern.eq_ignore_ascii_case(mime),
+    }
+}
+
+impl Rule {
+    fn applies_to(&self, subject: &Subject) -> bool {
+        (self.mime.is_empty() || self.mime.iter().any(|pattern| mime_matches(pattern, subject.mime)))
+            && (self.tenants.is_empty()
+                || subject
+                    .tenant
+                    .map(|tenant| self.tenants.iter().any(|allowed| allowed == tenant))
+                    .unwrap_or(false))
+    }
+
+    // Why `subject` breaks the rule, if it does
+    fn violation(&self, subject: &Subject) -> Option<String> {
+        if self.deny {
+            return Some(format!("{} uploads are not accepted", subject.mime));
+        }
+        if let Some(max_size) = self.max_size.filter(|max_size| subject.size > *max_size) {
+            return Some(format!("{} bytes is over the limit of {}", subject.size, max_size));
+        }
+
+        let bounds = [
+            ("width", subject.width, self.min_width, self.max_width),
+            ("height", subject.height, self.min_height, self.max_height),
+        ];
+        for (side, value, min, max) in bounds.iter() {
+            if let Some(min) = min.filter(|min| value < min) {
+                return Some(format!("{} of {} is under the minimum of {}", side, value, min));
+            }
+            if let Some(max) = max.filter(|max| value > max) {
+                return Some(format!("{} of {} is over the maximum of {}", side, value, max));
+            }
+        }
+
+        self.required_metadata
+            .iter()
+            .find(|key| subject.metadata.get(*key).map(String::is_empty).unwrap_or(true))
+            .map(|key| format!("metadata field {:?} is required", key))
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.name.is_empty() {
+            return Err(anyhow::anyhow!("a rule needs a name"));
+        }
+        if let Some(pattern) = self.mime.iter().find(|pattern| pattern.split('/').count() != 2) {
+            return Err(anyhow::anyhow!(
+                "rule {:?}: {:?} isn't a MIME type",
+                self.name,
+                pattern
+            ));
+        }
+        let inverted = |min: Option<u32>, max: Option<u32>| matches!((min, max), (Some(min), Some(max)) if min > max);
+        if inverted(self.min_width, self.max_width) || inverted(self.min_height, self.max_height) {
+            return Err(anyhow::anyhow!(
+                "rule {:?}: a minimum is over its maximum",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Policy {
+    pub fn parse(text: &str) -> Result<Policy> {
+        let policy: Policy = toml::from_str(text)?;
+        let mut names = HashSet::new();
+        for rule in &policy.rules {
+            rule.validate()?;
+            if !names.insert(rule.name.as_str()) {
+                return Err(anyhow::anyhow!("rule {:?} is defined twice", rule.name));
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn evaluate(&self, subject: &Subject) -> Result<(), PolicyViolation> {
+        for rule in self.rules.iter().filter(|rule| rule.applies_to(subject)) {
+            if let Some(reason) = rule.violation(subject) {
+                return Err(PolicyViolation {
+                    rule: rule.name.clone(),
+                    reason,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+static POLICY: OnceLock<RwLock<Policy>> = OnceLock::new();
+
+fn policy() -> &'static RwLock<Policy> {
+    POLICY.get_or_init(Default::default)
+}
+
+// At startup, returns the number of rules
+pub fn load(config: &PolicyConfig) -> Result<usize> {
+    let loaded = match &config.file {
+        Some(file) => Policy::parse(&std::fs::read_to_string(file)?)
+            .map_err(|err| anyhow::anyhow!("{}: {}", file.to_str().unwrap_or("?"), err))?,
+        None => Policy::default(),
+    };
+    let count = loaded.rules.len();
+    *policy().write().unwrap() = loaded;
+    Ok(count)
+}
+
+pub fn check(subject: &Subject) -> Result<(), PolicyViolation> {
+    policy().read().unwrap().evaluate(subject)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Expect {
+    Accept,
+    Reject,
+}
+
+// An upload and what the policy should do with it
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestCase {
+    pub name: Option<String>,
+    pub mime: String,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub width: u32,
+    #[serde(default)]
+    pub height: u32,
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    pub expect: Expect,
+    // The rule that should reject it
+    pub rule: Option<String>,
+}
+
+impl TestCase {
+    fn subject(&self) -> Subject<'_> {
+        Subject {
+            mime: &self.mime,
+            size: self.size,
+            width: self.width,
+            height: self.height,
+            tenant: self.tenant.as_deref(),
+            metadata: &self.metadata,
+        }
+    }
+
+    // What went wrong, if the policy doesn't do what is expected
+    fn run(&self, policy: &Policy) -> Option<String> {
+        match (policy.evaluate(&self.subject()), self.expect) {
+            (Ok(()), Expect::Accept) => None,
+            (Ok(()), Expect::Reject) => Some("accepted, expected a rejection".to_owned()),
+            (Err(violation), Expect::Accept) => Some(format!("expected to be accepted: {}", violation)),
+            (Err(violation), Expect::Reject) => match &self.rule {
+                Some(rule) if *rule != violation.rule => {
+                    Some(format!("rejected by {:?}, expected {:?}", violation.rule, rule))
+                }
+                _ => None,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub rules: usize,
+    pub passed: usize,
+    // Test name or number, and what went wrong
+    pub failures: Vec<(String, String)>,
+}
+
+// Parses a policy file and runs its tests, for `rr-api check-policy`
+pub fn check_file<P: AsRef<Path>>(path: P) -> Result<CheckReport> {
+    let policy = Policy::parse(&std::fs::read_to_string(path)?)?;
+    let mut report = CheckReport {
+        rules: policy.rules.len(),
+        ..Default::default()
+    };
+    for (n, test) in policy.tests.iter().enumerate() {
+        match test.run(&policy) {
+            None => report.passed += 1,
+            Some(failure) => {
+                let name = test.name.clone().unwrap_or_else(|| format!("test {}", n + 1));
+                report.failures.push((name, failure));
+            }
+        }
+    }
+    Ok(report)
+}
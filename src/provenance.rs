@@ -0,0 +1,77 @@
+use std::collections::{HashSet, VecDeque};
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::metadata;
+
+// Откуда взялось изображение: исходники и применённые операции
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Provenance {
+    pub parents: Vec<String>,
+    // In order of application, e.g. `copy` or `transform?w=100&fit=contain`
+    pub operations: Vec<String>,
+}
+
+impl Provenance {
+    pub fn derived_from(parent: &str, operation: String) -> Provenance {
+        Provenance {
+            parents: vec![parent.to_owned()],
+            operations: vec![operation],
+        }
+    }
+}
+
+// An image of the derivation chain
+#[derive(Debug, Serialize)]
+pub struct ChainStep {
+    pub id: String,
+    // No metadata: deleted since, or stored before metadata existed
+    pub deleted: bool,
+    pub created_at: Option<u64>,
+    #[serde(flatten)]
+    pub provenance: Provenance,
+}
+
+// Guards against absurdly deep or corrupted chains
+const MAX_CHAIN_LENGTH: usize = 1000;
+
+// `id` followed by its ancestors, breadth first
+pub async fn chain<P: AsRef<Path>>(uploads_dir: P, id: &str) -> Result<Vec<ChainStep>> {
+    let mut steps = Vec::new();
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::new();
+    queue.push_back(id.to_owned());
+
+    while let Some(id) = queue.pop_front() {
+        if steps.len() == MAX_CHAIN_LENGTH {
+            break;
+        }
+        if !seen.insert(id.clone()) {
+            continue;
+        }
+
+        let step = match metadata::load(&uploads_dir, &id).await? {
+            Some(image_metadata) => {
+                let provenance = image_metadata.provenance.unwrap_or_default();
+                queue.extend(provenance.parents.iter().cloned());
+                ChainStep {
+                    id,
+                    deleted: false,
+                    created_at: Some(image_metadata.created_at),
+                    provenance,
+                }
+            }
+            None => ChainStep {
+                id,
+                deleted: true,
+                created_at: None,
+                provenance: Provenance::default(),
+            },
+        };
+        steps.push(step);
+    }
+
+    Ok(steps)
+}
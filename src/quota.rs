@@ -0,0 +1,305 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::KeyLimits;
+use crate::metadata::{ImageMetadata, METADATA_DIR};
+use crate::{clock, is_valid_id};
+
+// Квоты на место: bytes of stored originals, for the instance and per API
+// key. Derivatives aren't counted, they can always be dropped and remade.
+// 0 is unlimited.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct QuotaConfig {
+    pub max_total_bytes: u64,
+    // For every key without its own entry in `keys`
+    pub max_bytes_per_key: u64,
+    // Key name to its quota
+    pub keys: HashMap<String, u64>,
+}
+
+impl QuotaConfig {
+    fn key_limit(&self, key: &str) -> u64 {
+        self.keys.get(key).copied().unwrap_or(self.max_bytes_per_key)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum QuotaError {
+    #[error("Storage quota of {0} bytes is exhausted")]
+    Instance(u64),
+    #[error("Storage quota of {1} bytes for key {0} is exhausted")]
+    Key(String, u64),
+    #[error("Key {0} may upload {1} files a day")]
+    KeyUploads(String, u64),
+    #[error("Key {0} may upload files of up to {1} bytes")]
+    KeyFileSize(String, u64),
+}
+
+const DAY_SECS: u64 = 24 * 3600;
+
+#[derive(Default)]
+struct Usage {
+    // Id to owner and size, so a removal knows what to give back
+    files: HashMap<String, (Option<String>, u64)>,
+    total: u64,
+    per_key: HashMap<String, u64>,
+    // Key to the UTC day and the uploads made that day; a removal doesn't
+    // change it
+    per_key_day: HashMap<String, (u64, u64)>,
+    // Uploads in flight, counted in `files` since `reserve`
+    reserved: HashSet<String>,
+}
+
+impl Usage {
+    fn insert(&mut self, metadata: &ImageMetadata) {
+        // Saved again on every state change, only a new id is an upload; a
+        // reserved one was counted as one already
+        let is_new = !self.reserved.remove(&metadata.id) && !self.files.contains_key(&metadata.id);
+        self.remove(&metadata.id);
+        self.add(&metadata.id, metadata.owner.as_deref(), metadata.size);
+        if let Some(owner) = &metadata.owner {
+            if is_new && metadata.created_at / DAY_SECS == clock::unix_now() / DAY_SECS {
+                self.count_upload(owner);
+            }
+        }
+    }
+
+    fn add(&mut self, id: &str, owner: Option<&str>, size: u64) {
+        self.total += size;
+        if let Some(owner) = owner {
+            *self.per_key.entry(owner.to_owned()).or_insert(0) += size;
+        }
+        self.files.insert(id.to_owned(), (owner.map(str::to_owned), size));
+    }
+
+    fn count_upload(&mut self, key: &str) {
+        let today = clock::unix_now() / DAY_SECS;
+        let (day, uploads) = self.per_key_day.entry(key.to_owned()).or_insert((today, 0));
+        if *day != today {
+            *day = today;
+            *uploads = 0;
+        }
+        *uploads += 1;
+    }
+
+    fn uploads_today(&self, key: &str) -> u64 {
+        match self.per_key_day.get(key) {
+            Some(&(day, uploads)) if day == clock::unix_now() / DAY_SECS => uploads,
+            _ => 0,
+        }
+    }
+
+    fn remove(&mut self, id: &str) {
+        if let Some((owner, size)) = self.files.remove(id) {
+            self.total -= size;
+            if let Some(used) = owner.and_then(|owner| self.per_key.get_mut(&owner)) {
+                *used -= size;
+            }
+        }
+    }
+}
+
+static USAGE: OnceLock<Mutex<Usage>> = OnceLock::new();
+
+fn usage() -> &'static Mutex<Usage> {
+    USAGE.get_or_init(Default::default)
+}
+
+// Counts the stored uploads from their metadata, at startup and when a
+// standby is promoted; from then on `metadata::save` and `metadata::remove`
+// keep the count. Returns the total. Uploads of today deleted before a
+// restart aren't counted again.
+pub async fn load<P: AsRef<Path>>(uploads_dir: P) -> Result<u64> {
+    let mut counted = Usage::default();
+
+    let mut dir = match tokio::fs::read_dir(uploads_dir.as_ref().join(METADATA_DIR)).await {
+        Ok(dir) => dir,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            *usage().lock().unwrap() = counted;
+            return Ok(0);
+        }
+        Err(err) => return Err(err.into()),
+    };
+    while let Some(entry) = dir.next_entry().await? {
+        let name = entry.file_name();
+        match name.to_str().and_then(|name| name.strip_suffix(".json")) {
+            Some(id) if is_valid_id(id) => {}
+            _ => continue,
+        }
+
+        match serde_json::from_slice::<ImageMetadata>(&tokio::fs::read(entry.path()).await?) {
+            Ok(metadata) => counted.insert(&metadata),
+            Err(err) => tracing::warn!("Skipping metadata {:?}: {}", entry.path(), err),
+        }
+    }
+
+    let total = counted.total;
+    *usage().lock().unwrap() = counted;
+    Ok(total)
+}
+
+pub fn record(metadata: &ImageMetadata) {
+    usage().lock().unwrap().insert(metadata);
+}
+
+pub fn forget(id: &str) {
+    usage().lock().unwrap().remove(id);
+}
+
+// Whether `size` more bytes owned by `owner` fit. Only a hint for
+// concurrent uploads, `reserve` is what takes the bytes.
+pub fn check(config: &QuotaConfig, owner: Option<&str>, size: u64) -> Result<(), QuotaError> {
+    usage().lock().unwrap().check(config, owner, size)
+}
+
+// Whether a file of `size` bytes fits the limits of the key `owner`
+// uploads with; `size` 0 checks only the daily count, before a request's
+// body is read
+pub fn check_key(limits: &KeyLimits, owner: &str, size: u64) -> Result<(), QuotaError> {
+    usage().lock().unwrap().check_key(limits, owner, size)
+}
+
+// Checks `size` bytes of upload `id` against the quotas and the key's limits
+// and takes them in one go, so concurrent uploads can't overshoot together.
+// `metadata::save` turns the reservation into the upload, `release` gives
+// it back.
+pub fn reserve(
+    config: &QuotaConfig,
+    key_limits: Option<&KeyLimits>,
+    owner: Option<&str>,
+    id: &str,
+    size: u64,
+) -> Result<(), QuotaError> {
+    let mut usage = usage().lock().unwrap();
+    usage.check(config, owner, size)?;
+    if let (Some(key_limits), Some(owner)) = (key_limits, owner) {
+        usage.check_key(key_limits, owner, size)?;
+    }
+
+    usage.add(id, owner, size);
+    if let Some(owner) = owner {
+        usage.count_upload(owner);
+    }
+    usage.reserved.insert(id.to_owned());
+    Ok(())
+}
+
+// Gives back the reservation of an upload that failed; nothing once its
+// metadata was saved
+pub fn release(id: &str) {
+    let mut usage = usage().lock().unwrap();
+    if !usage.reserved.remove(id) {
+        return;
+    }
+    let today = clock::unix_now() / DAY_SECS;
+    if let Some(owner) = usage.files.get(id).and_then(|(owner, _)| owner.clone()) {
+        match usage.per_key_day.get_mut(&owner) {
+            Some((day, uploads)) if *day == today => *uploads -= 1,
+            _ => {}
+        }
+    }
+    usage.remove(id);
+}
+
+impl Usage {
+    fn check(&self, config: &QuotaConfig, owner: Option<&str>, size: u64) -> Result<(), QuotaError> {
+        if config.max_total_bytes != 0 && self.total + size > config.max_total_bytes {
+            return Err(QuotaError::Instance(config.max_total_bytes));
+        }
+        if let Some(owner) = owner {
+            let limit = config.key_limit(owner);
+            let used = self.per_key.get(owner).copied().unwrap_or(0);
+            if limit != 0 && used + size > limit {
+                return Err(QuotaError::Key(owner.to_owned(), limit));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_key(&self, limits: &KeyLimits, owner: &str, size: u64) -> Result<(), QuotaError> {
+        if limits.max_file_size != 0 && size > limits.max_file_size {
+            return Err(QuotaError::KeyFileSize(owner.to_owned(), limits.max_file_size));
+        }
+        if limits.max_uploads_per_day != 0 && self.uploads_today(owner) >= limits.max_uploads_per_day {
+            return Err(QuotaError::KeyUploads(owner.to_owned(), limits.max_uploads_per_day));
+        }
+        let used = self.per_key.get(owner).copied().unwrap_or(0);
+        if limits.max_total_bytes != 0 && used + size > limits.max_total_bytes {
+            return Err(QuotaError::Key(owner.to_owned(), limits.max_total_bytes));
+        }
+        Ok(())
+    }
+}
+
+// Of one key against its `KeyLimits`, for GET /admin/keys/{key}/usage
+#[derive(Debug, Serialize)]
+pub struct KeyReport {
+    pub key: String,
+    pub files: usize,
+    pub bytes: u64,
+    pub uploads_today: u64,
+    // Unix time the daily count starts over
+    pub resets_at: u64,
+    pub max_uploads_per_day: u64,
+    pub max_total_bytes: u64,
+    pub max_file_size: u64,
+}
+
+pub fn key_report(key: &str, limits: &KeyLimits) -> KeyReport {
+    let usage = usage().lock().unwrap();
+    KeyReport {
+        key: key.to_owned(),
+        files: usage
+            .files
+            .values()
+            .filter(|(owner, _)| owner.as_deref() == Some(key))
+            .count(),
+        bytes: usage.per_key.get(key).copied().unwrap_or(0),
+        uploads_today: usage.uploads_today(key),
+        resets_at: (clock::unix_now() / DAY_SECS + 1) * DAY_SECS,
+        max_uploads_per_day: limits.max_uploads_per_day,
+        max_total_bytes: limits.max_total_bytes,
+        max_file_size: limits.max_file_size,
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct KeyUsage {
+    pub bytes: u64,
+    // 0 is unlimited
+    pub limit: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageReport {
+    pub total_bytes: u64,
+    pub files: usize,
+    pub limit: u64,
+    // Keys with uploads or a quota of their own
+    pub keys: BTreeMap<String, KeyUsage>,
+}
+
+pub fn report(config: &QuotaConfig) -> UsageReport {
+    let usage = usage().lock().unwrap();
+
+    let mut keys: BTreeMap<String, KeyUsage> = usage
+        .per_key
+        .iter()
+        .map(|(key, &bytes)| (key.clone(), KeyUsage { bytes, limit: config.key_limit(key) }))
+        .collect();
+    for (key, &limit) in &config.keys {
+        keys.entry(key.clone()).or_insert(KeyUsage { bytes: 0, limit });
+    }
+
+    UsageReport {
+        total_bytes: usage.total,
+        files: usage.files.len(),
+        limit: config.max_total_bytes,
+        keys,
+    }
+}
@@ -0,0 +1,253 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::finalize::Durability;
+use crate::metadata::{ImageMetadata, METADATA_DIR};
+use crate::{is_stored_file_name, layout, stream_to_file};
+
+pub const TOKEN_HEADER: &str = "X-RR-Replication-Token";
+pub const PATH_PREFIX: &str = "/replication";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Primary,
+    Standby,
+}
+
+// Тёплый резерв: standby забирает файлы с primary, пока его не повысят
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ReplicationConfig {
+    pub role: Role,
+    // Base URL of the primary, used by a standby
+    pub primary_url: String,
+    // Shared secret for the /replication endpoints, empty disables the check
+    pub token: String,
+    pub sync_interval_secs: u64,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        ReplicationConfig {
+            role: Role::Primary,
+            primary_url: String::new(),
+            token: String::new(),
+            sync_interval_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub size: u64,
+    pub modified: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplicationInfo {
+    pub instance_id: String,
+    pub standby: bool,
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Stored originals, thumbnails and metadata modified at or after `since`
+pub async fn manifest<P: AsRef<Path>>(uploads_dir: P, since: u64) -> Result<Vec<ManifestEntry>> {
+    let mut entries = Vec::new();
+    for dir in layout::upload_dirs(&uploads_dir).await? {
+        list_dir(&dir, "", since, &mut entries).await?;
+    }
+
+    let metadata_dir = uploads_dir.as_ref().join(METADATA_DIR);
+    if tokio::fs::metadata(&metadata_dir).await.is_ok() {
+        list_dir(&metadata_dir, "meta/", since, &mut entries).await?;
+    }
+
+    Ok(entries)
+}
+
+async fn list_dir(dir: &Path, prefix: &str, since: u64, entries: &mut Vec<ManifestEntry>) -> Result<()> {
+    let mut dir = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        let name = match entry.file_name().into_string() {
+            Ok(name) => format!("{}{}", prefix, name),
+            Err(_) => continue,
+        };
+        if !is_stored_file_name(&name) {
+            continue;
+        }
+
+        let metadata = entry.metadata().await?;
+        let modified = metadata.modified().map(unix_time).unwrap_or(0);
+        if metadata.is_file() && modified >= since {
+            entries.push(ManifestEntry {
+                name,
+                size: metadata.len(),
+                modified,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// Copied metadata went around `metadata::save`, the layout learns the
+// upload's tenant here
+async fn adopt_tenant(uploads_dir: &Path, metadata_path: &Path) -> Result<()> {
+    let metadata: ImageMetadata = serde_json::from_slice(&tokio::fs::read(metadata_path).await?)?;
+    if let Some(tenant) = &metadata.tenant {
+        layout::adopt(uploads_dir, &metadata.id, tenant).await?;
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct Replication {
+    config: ReplicationConfig,
+    instance_id: String,
+    standby: Arc<AtomicBool>,
+    // Modification time the next manifest request starts from
+    synced_until: Arc<Mutex<u64>>,
+    client: reqwest::Client,
+}
+
+impl Replication {
+    pub fn new(config: &ReplicationConfig, instance_id: &str) -> Replication {
+        Replication {
+            config: ReplicationConfig {
+                primary_url: config.primary_url.trim_end_matches('/').to_owned(),
+                ..config.clone()
+            },
+            instance_id: instance_id.to_owned(),
+            standby: Arc::new(AtomicBool::new(config.role == Role::Standby)),
+            synced_until: Arc::new(Mutex::new(0)),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn is_standby(&self) -> bool {
+        self.standby.load(Ordering::SeqCst)
+    }
+
+    // Stops replication and starts accepting writes. Returns false if this
+    // instance already was the primary.
+    pub fn promote(&self) -> bool {
+        let was_standby = self.standby.swap(false, Ordering::SeqCst);
+        if was_standby {
+            tracing::warn!(
+                "Promoted to primary, replication from {} stopped",
+                self.config.primary_url
+            );
+        }
+        was_standby
+    }
+
+    pub fn info(&self) -> ReplicationInfo {
+        ReplicationInfo {
+            instance_id: self.instance_id.clone(),
+            standby: self.is_standby(),
+        }
+    }
+
+    pub fn is_authorized(&self, token: Option<&str>) -> bool {
+        self.config.token.is_empty() || token == Some(self.config.token.as_str())
+    }
+
+    fn get(&self, path: &str) -> reqwest::RequestBuilder {
+        self.client
+            .get(format!("{}{}{}", self.config.primary_url, PATH_PREFIX, path))
+            .header(TOKEN_HEADER, self.config.token.as_str())
+    }
+
+    // Copies everything changed on the primary since the previous pass
+    pub async fn sync_once(&self, uploads_dir: &Path) -> Result<usize> {
+        let response = self.get("/info").send().await?.error_for_status()?;
+        let info: ReplicationInfo = serde_json::from_slice(&response.bytes().await?)?;
+        // Ids only stay unique after a promotion if the prefixes differ
+        if info.instance_id == self.instance_id {
+            return Err(anyhow::anyhow!(
+                "primary and standby share instance_id {:?}",
+                self.instance_id
+            ));
+        }
+
+        // Files modified within the same second as the last pass are listed
+        // again, the size check below skips those already copied
+        let since = *self.synced_until.lock().unwrap();
+        let started_at = unix_time(SystemTime::now());
+        let response = self
+            .get(&format!("/manifest?since={}", since))
+            .send()
+            .await?
+            .error_for_status()?;
+        let mut entries: Vec<ManifestEntry> = serde_json::from_slice(&response.bytes().await?)?;
+
+        // Metadata first, it tells where the files of a tenant's upload go
+        entries.sort_by_key(|entry| !entry.name.starts_with("meta/"));
+
+        let mut copied = 0;
+        for entry in entries {
+            if !is_stored_file_name(&entry.name) {
+                continue;
+            }
+
+            let dest = layout::stored_file_path(uploads_dir, &entry.name);
+            match tokio::fs::metadata(&dest).await {
+                Ok(metadata) if metadata.len() == entry.size => continue,
+                _ => {}
+            }
+
+            self.copy_file(&entry.name, &dest).await?;
+            copied += 1;
+            if entry.name.starts_with("meta/") {
+                adopt_tenant(uploads_dir, &dest).await?;
+            }
+        }
+
+        *self.synced_until.lock().unwrap() = started_at;
+        Ok(copied)
+    }
+
+    async fn copy_file(&self, name: &str, dest: &Path) -> Result<()> {
+        let response = self.get(&format!("/files/{}", name)).send().await?.error_for_status()?;
+
+        if let Some(parent) = dest.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // Write-then-rename, a half-copied file must never be served
+        let tmp_path = dest.with_extension("replica");
+        stream_to_file(response.bytes_stream(), &tmp_path, None, Durability::Relaxed).await?;
+        tokio::fs::rename(&tmp_path, dest).await?;
+
+        Ok(())
+    }
+
+    pub fn spawn_sync(&self, uploads_dir: PathBuf) {
+        if !self.is_standby() {
+            return;
+        }
+
+        let replication = self.clone();
+        actix_rt::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(replication.config.sync_interval_secs.max(1)));
+            while replication.is_standby() {
+                interval.tick().await;
+                match replication.sync_once(&uploads_dir).await {
+                    Ok(0) => {}
+                    Ok(copied) => tracing::info!("Replicated {} file(s) from the primary", copied),
+                    Err(err) => tracing::error!("Replication error: {}", err),
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::FetchError;
+
+// Повторы скачивания по ссылке: a failed attempt is retried after a delay
+// that doubles each time, up to `max_backoff_ms`. Only what may pass on its
+// own is retried, a 5xx or a failed connection; a 404 won't.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    // The first attempt included, 1 turns retries off
+    pub max_attempts: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 3,
+            initial_backoff_ms: 200,
+            max_backoff_ms: 5000,
+        }
+    }
+}
+
+impl RetryConfig {
+    // The wait after failed attempt number `attempt`, counted from 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u64.checked_shl(attempt.saturating_sub(1)).unwrap_or(u64::MAX);
+        Duration::from_millis(self.initial_backoff_ms.saturating_mul(factor).min(self.max_backoff_ms))
+    }
+
+    pub fn should_retry(&self, attempt: u32, err: &FetchError) -> bool {
+        attempt < self.max_attempts && is_retryable(err)
+    }
+}
+
+pub fn is_retryable(err: &FetchError) -> bool {
+    match err {
+        FetchError::Status(status) => *status >= 500,
+        FetchError::Request(err) => err.is_connect(),
+        FetchError::UnsupportedType(_)
+        | FetchError::InvalidUrl(_)
+        | FetchError::UrlNotAllowed(_)
+        | FetchError::Unresolvable(_)
+        | FetchError::TooManyRedirects(_)
+        | FetchError::HeaderNotAllowed(_)
+        | FetchError::InvalidHeader(_)
+        | FetchError::GaveUp { .. } => false,
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::DefaultHeaders;
+
+use crate::Config;
+
+// Served files are user content, so lock the browser down by default.
+// Routes that need something else set their own header, DefaultHeaders
+// never overwrites an existing one.
+const DEFAULT_SECURITY_HEADERS: &[(&str, &str)] = &[
+    ("X-Content-Type-Options", "nosniff"),
+    ("X-Frame-Options", "DENY"),
+    ("Referrer-Policy", "no-referrer"),
+    (
+        "Content-Security-Policy",
+        "default-src 'none'; img-src 'self' data:; style-src 'unsafe-inline'; frame-ancestors 'none'",
+    ),
+    ("Cross-Origin-Opener-Policy", "same-origin"),
+];
+
+// Defaults merged with `Config::security_headers`; an empty override value
+// drops the header altogether.
+pub fn security_headers(overrides: &BTreeMap<String, String>) -> Vec<(HeaderName, HeaderValue)> {
+    let mut headers: BTreeMap<String, String> = DEFAULT_SECURITY_HEADERS
+        .iter()
+        .map(|(name, value)| (name.to_ascii_lowercase(), (*value).to_owned()))
+        .collect();
+
+    for (name, value) in overrides {
+        if value.is_empty() {
+            headers.remove(&name.to_ascii_lowercase());
+        } else {
+            headers.insert(name.to_ascii_lowercase(), value.clone());
+        }
+    }
+
+    headers
+        .into_iter()
+        .filter_map(|(name, value)| {
+            match (HeaderName::try_from(name.as_str()), HeaderValue::try_from(value.as_str())) {
+                (Ok(name), Ok(value)) => Some((name, value)),
+                _ => {
+                    tracing::warn!("Ignoring invalid security header {}: {}", name, value);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+pub fn default_headers(config: &Config) -> DefaultHeaders {
+    security_headers(&config.security_headers)
+        .into_iter()
+        .fold(DefaultHeaders::new(), |headers, (name, value)| headers.add((name.as_str(), value.as_bytes())))
+}
@@ -0,0 +1,34 @@
+use serde::Deserialize;
+
+use crate::Config;
+
+// Как отдавать файлы определённого типа
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ServePolicy {
+    // Force a download instead of rendering in the browser
+    pub attachment: bool,
+    // Replaces the default Content-Security-Policy
+    pub content_security_policy: Option<String>,
+    // Served Content-Type instead of the stored one
+    pub content_type: Option<String>,
+}
+
+// Types that can carry script are never rendered inline by default
+pub fn default_serve_policy(mime_type: &str) -> ServePolicy {
+    match mime_type {
+        "image/svg+xml" | "text/html" | "application/xhtml+xml" | "text/xml" | "application/xml" => ServePolicy {
+            attachment: true,
+            content_security_policy: Some("default-src 'none'; style-src 'unsafe-inline'; sandbox".into()),
+            content_type: None,
+        },
+        _ => ServePolicy::default(),
+    }
+}
+
+pub fn serve_policy(config: &Config, mime_type: &str) -> ServePolicy {
+    match config.serve_policies.get(mime_type) {
+        Some(policy) => policy.clone(),
+        None => default_serve_policy(mime_type),
+    }
+}
@@ -0,0 +1,68 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::{is_temp_file_name, layout};
+
+// Uploads between the first byte and the final rename, across all callers
+// (handlers, import jobs, tus)
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+pub struct InFlightGuard(());
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+pub fn track_upload() -> InFlightGuard {
+    IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+    InFlightGuard(())
+}
+
+pub fn in_flight() -> usize {
+    IN_FLIGHT.load(Ordering::SeqCst)
+}
+
+// Waits for in-flight uploads to finish, returns false on timeout
+pub async fn drain(timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while in_flight() > 0 {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    true
+}
+
+// Only safe while no upload is running, i.e. at startup and after `drain`
+pub async fn remove_temp_files<P: AsRef<Path>>(uploads_dir: P) -> std::io::Result<usize> {
+    let mut removed = 0;
+
+    for dir in layout::upload_dirs(uploads_dir).await? {
+        removed += remove_temp_files_in(&dir).await?;
+    }
+
+    Ok(removed)
+}
+
+async fn remove_temp_files_in(dir: &Path) -> std::io::Result<usize> {
+    let mut removed = 0;
+
+    let mut dir = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        let is_temp = entry
+            .file_name()
+            .to_str()
+            .map(is_temp_file_name)
+            .unwrap_or(false);
+        if is_temp && entry.file_type().await?.is_file() {
+            tokio::fs::remove_file(entry.path()).await?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
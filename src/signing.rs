@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use crate::transform::TransformSpec;
+use crate::{clock, Config};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SignatureError {
+    #[error("Signature is invalid")]
+    Invalid,
+    #[error("Signed request has expired")]
+    Expired,
+    #[error("Signed request has already been used")]
+    Replayed,
+}
+
+pub fn now() -> u64 {
+    clock::unix_now()
+}
+
+// Hex encoded HMAC-SHA256
+pub fn sign(key: &str, message: &str) -> String {
+    let mut mac = HmacSha256::new_varkey(key.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(message.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// Constant time, via `Mac::verify`
+pub fn verify(key: &str, message: &str, signature: &str) -> bool {
+    let signature = match hex::decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    let mut mac = HmacSha256::new_varkey(key.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(message.as_bytes());
+    mac.verify(&signature).is_ok()
+}
+
+// `owner` and `tenant` are the presigner's, the upload is stored as theirs;
+// as JSON, so no name can pass for another with a newline in it
+pub fn upload_message(expires: u64, nonce: &str, owner: Option<&str>, tenant: Option<&str>) -> String {
+    format!("upload\n{}\n{}\n{}", expires, nonce, serde_json::json!([owner, tenant]))
+}
+
+// Nonces of signed requests seen so far, each kept until its request expires;
+// after that the expiry check alone rejects a replay
+#[derive(Clone, Default)]
+pub struct NonceCache {
+    seen: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl NonceCache {
+    // Returns false if the nonce was already used
+    pub fn consume(&self, nonce: &str, expires: u64) -> bool {
+        let now = now();
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, expires| *expires > now);
+
+        if seen.contains_key(nonce) {
+            return false;
+        }
+        seen.insert(nonce.to_owned(), expires);
+        true
+    }
+}
+
+// Checks a presigned upload and burns its nonce, so each presigned URL
+// creates at most one upload request
+pub fn check_presigned_upload(
+    key: &str,
+    nonces: &NonceCache,
+    expires: u64,
+    nonce: &str,
+    owner: Option<&str>,
+    tenant: Option<&str>,
+    signature: &str,
+) -> Result<(), SignatureError> {
+    if key.is_empty() || nonce.is_empty() || !verify(key, &upload_message(expires, nonce, owner, tenant), signature) {
+        return Err(SignatureError::Invalid);
+    }
+    if expires <= now() {
+        return Err(SignatureError::Expired);
+    }
+    if !nonces.consume(nonce, expires) {
+        return Err(SignatureError::Replayed);
+    }
+    Ok(())
+}
+
+pub fn download_message(id: &str, expires: u64) -> String {
+    format!("download\n{}\n{}", id, expires)
+}
+
+// `/images/{id}?expires=...&sig=...`, valid for `ttl` seconds (capped by
+// `max_download_url_ttl_secs`). `None` without a signing key.
+pub fn sign_download_url(config: &Config, id: &str, ttl: u64) -> Option<String> {
+    if config.signing_key.is_empty() {
+        return None;
+    }
+
+    let expires = now() + ttl.min(config.max_download_url_ttl_secs);
+    let sig = sign(&config.signing_key, &download_message(id, expires));
+    Some(format!("/images/{}?expires={}&sig={}", id, expires, sig))
+}
+
+// Unlike uploads, a download URL may be used any number of times until it
+// expires
+pub fn check_signed_download(key: &str, id: &str, expires: u64, sig: &str) -> Result<(), SignatureError> {
+    if key.is_empty() || !verify(key, &download_message(id, expires), sig) {
+        return Err(SignatureError::Invalid);
+    }
+    if expires <= now() {
+        return Err(SignatureError::Expired);
+    }
+    Ok(())
+}
+
+pub fn transform_message(id: &str, spec: &TransformSpec) -> String {
+    format!("transform\n{}\n{}", id, spec.canonical())
+}
+
+// `/images/{id}/transform?{spec}&sig=...`; no expiry, so the result stays
+// cacheable. `None` without a signing key.
+pub fn sign_transform_url(config: &Config, id: &str, spec: &TransformSpec) -> Option<String> {
+    if config.signing_key.is_empty() {
+        return None;
+    }
+
+    let sig = sign(&config.signing_key, &transform_message(id, spec));
+    Some(format!("/images/{}/transform?{}&sig={}", id, spec.canonical(), sig))
+}
+
+pub fn check_transform(key: &str, id: &str, spec: &TransformSpec, sig: &str) -> Result<(), SignatureError> {
+    if key.is_empty() || !verify(key, &transform_message(id, spec), sig) {
+        return Err(SignatureError::Invalid);
+    }
+    Ok(())
+}
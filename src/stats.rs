@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::metadata::{ImageMetadata, METADATA_DIR};
+use crate::{clock, extension_to_mime_type, is_valid_id, metrics};
+
+// Сводка для GET /admin/stats: stored uploads from the metadata, thumbnails
+// from the counters since the start.
+
+const HOUR_SECS: u64 = 60 * 60;
+const DAY_SECS: u64 = 24 * HOUR_SECS;
+// MIME types listed, the rest are left out
+const TOP_MIME_TYPES: usize = 10;
+
+#[derive(Debug, Serialize)]
+pub struct MimeTypeStats {
+    pub mime: String,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ThumbnailStats {
+    pub created: u64,
+    pub failed: u64,
+    // Of all attempts, 0 before the first one
+    pub failure_rate: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub files: u64,
+    pub total_bytes: u64,
+    pub uploads_last_hour: u64,
+    pub uploads_last_day: u64,
+    pub thumbnails: ThumbnailStats,
+    // Most files first
+    pub top_mime_types: Vec<MimeTypeStats>,
+}
+
+fn thumbnail_stats() -> ThumbnailStats {
+    let (created, failed) = metrics::thumbnails();
+    let attempts = created + failed;
+    ThumbnailStats {
+        created,
+        failed,
+        failure_rate: if attempts == 0 { 0.0 } else { failed as f64 / attempts as f64 },
+    }
+}
+
+// Reads all the metadata, so it takes a while for many uploads; only admins
+// ask for it
+pub async fn collect<P: AsRef<Path>>(uploads_dir: P) -> Result<Stats> {
+    let mut stats = Stats {
+        files: 0,
+        total_bytes: 0,
+        uploads_last_hour: 0,
+        uploads_last_day: 0,
+        thumbnails: thumbnail_stats(),
+        top_mime_types: Vec::new(),
+    };
+    let mut mime_types: HashMap<&'static str, (u64, u64)> = HashMap::new();
+
+    let mut dir = match tokio::fs::read_dir(uploads_dir.as_ref().join(METADATA_DIR)).await {
+        Ok(dir) => dir,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(stats),
+        Err(err) => return Err(err.into()),
+    };
+    let now = clock::unix_now();
+    while let Some(entry) = dir.next_entry().await? {
+        let name = entry.file_name();
+        match name.to_str().and_then(|name| name.strip_suffix(".json")) {
+            Some(id) if is_valid_id(id) => {}
+            _ => continue,
+        }
+
+        // Removed since the scan, or unreadable
+        let metadata = match tokio::fs::read(entry.path()).await {
+            Ok(data) => match serde_json::from_slice::<ImageMetadata>(&data) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            },
+            Err(_) => continue,
+        };
+
+        stats.files += 1;
+        stats.total_bytes += metadata.size;
+        let age = now.saturating_sub(metadata.created_at);
+        if age < HOUR_SECS {
+            stats.uploads_last_hour += 1;
+        }
+        if age < DAY_SECS {
+            stats.uploads_last_day += 1;
+        }
+        let mime = extension_to_mime_type(&metadata.extension).unwrap_or("application/octet-stream");
+        let (files, bytes) = mime_types.entry(mime).or_insert((0, 0));
+        *files += 1;
+        *bytes += metadata.size;
+    }
+
+    let mut top_mime_types: Vec<MimeTypeStats> = mime_types
+        .into_iter()
+        .map(|(mime, (files, bytes))| MimeTypeStats {
+            mime: mime.to_owned(),
+            files,
+            bytes,
+        })
+        .collect();
+    top_mime_types.sort_by(|a, b| b.files.cmp(&a.files).then_with(|| a.mime.cmp(&b.mime)));
+    top_mime_types.truncate(TOP_MIME_TYPES);
+    stats.top_mime_types = top_mime_types;
+    Ok(stats)
+}
@@ -0,0 +1,212 @@
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures_util::stream::StreamExt;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+
+// Google Cloud Storage, see `gcs::GcsStorage`
+#[cfg(feature = "storage-gcs")]
+mod gcs;
+// Azure Blob Storage, see `azure::AzureStorage`
+#[cfg(feature = "storage-azure")]
+mod azure;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("No object {0} in storage")]
+    NotFound(String),
+    #[error("Storage I/O error: {0}")]
+    Io(#[from] io::Error),
+    // What a cloud storage service answered, or failing to reach it
+    #[error("Storage service error: {0}")]
+    Service(String),
+}
+
+impl From<reqwest::Error> for StorageError {
+    fn from(err: reqwest::Error) -> Self {
+        StorageError::Service(err.to_string())
+    }
+}
+
+pub type StorageFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T, StorageError>> + Send + 'a>>;
+
+// Хранилище объектов вне uploads_dir, по имени файла. The cold tier is one;
+// a `get` from an archive-class backend may take hours, so callers run it in
+// the background.
+pub trait Storage: Send + Sync {
+    // Copies a local file in as `name`, replacing what was there
+    fn put<'a>(&'a self, name: &'a str, from: &'a Path) -> StorageFuture<'a, ()>;
+    // Copies `name` out to a local file
+    fn get<'a>(&'a self, name: &'a str, to: &'a Path) -> StorageFuture<'a, ()>;
+    // Succeeds if there is no such object
+    fn delete<'a>(&'a self, name: &'a str) -> StorageFuture<'a, ()>;
+}
+
+// A directory, e.g. a mount of cheaper storage
+pub struct DirectoryStorage {
+    root: PathBuf,
+}
+
+impl DirectoryStorage {
+    pub fn new<P: Into<PathBuf>>(root: P) -> DirectoryStorage {
+        DirectoryStorage { root: root.into() }
+    }
+}
+
+impl Storage for DirectoryStorage {
+    fn put<'a>(&'a self, name: &'a str, from: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            tokio::fs::create_dir_all(&self.root).await?;
+            // Copied aside first, so a crash never leaves half an object
+            let path = self.root.join(name);
+            let tmp_path = self.root.join(format!("{}.tmp", name));
+            tokio::fs::copy(from, &tmp_path).await?;
+            tokio::fs::rename(&tmp_path, &path).await?;
+            Ok(())
+        })
+    }
+
+    fn get<'a>(&'a self, name: &'a str, to: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            match tokio::fs::copy(self.root.join(name), to).await {
+                Ok(_) => Ok(()),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Err(StorageError::NotFound(name.to_owned())),
+                Err(err) => Err(err.into()),
+            }
+        })
+    }
+
+    fn delete<'a>(&'a self, name: &'a str) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            match tokio::fs::remove_file(self.root.join(name)).await {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum StorageConfig {
+    // See `DirectoryStorage`
+    Directory { path: PathBuf },
+    // Needs the `storage-gcs` feature
+    Gcs(GcsConfig),
+    // Needs the `storage-azure` feature
+    Azure(AzureConfig),
+}
+
+// Objects are written with resumable uploads, `chunk_size` at a time, so a
+// file is never held in memory whole
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GcsConfig {
+    pub bucket: String,
+    // Of every object name, `archive/` and the like
+    pub prefix: String,
+    // Another for an emulator
+    pub endpoint: String,
+    // A service account key; without one, the token of the instance's
+    // service account from the metadata server
+    pub credentials_file: Option<PathBuf>,
+    // A fixed OAuth token instead, for emulators and tests
+    pub access_token: String,
+    // Rounded down to a multiple of 256 KiB, as GCS wants, and at least one
+    pub chunk_size: usize,
+}
+
+impl Default for GcsConfig {
+    fn default() -> Self {
+        GcsConfig {
+            bucket: String::new(),
+            prefix: String::new(),
+            endpoint: "https://storage.googleapis.com".to_owned(),
+            credentials_file: None,
+            access_token: String::new(),
+            chunk_size: 8 << 20,
+        }
+    }
+}
+
+// Objects are block blobs, written `block_size` at a time and committed
+// with their block list
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AzureConfig {
+    pub account: String,
+    pub container: String,
+    pub prefix: String,
+    // `https://{account}.blob.core.windows.net` when empty; another for
+    // Azurite
+    pub endpoint: String,
+    // A SAS token allowing read, write and delete on the container
+    pub sas_token: String,
+    pub block_size: usize,
+}
+
+impl Default for AzureConfig {
+    fn default() -> Self {
+        AzureConfig {
+            account: String::new(),
+            container: String::new(),
+            prefix: String::new(),
+            endpoint: String::new(),
+            sas_token: String::new(),
+            block_size: 8 << 20,
+        }
+    }
+}
+
+// Once at startup, a backend not built in is a config error
+pub fn open(config: &StorageConfig) -> Result<Arc<dyn Storage>> {
+    match config {
+        StorageConfig::Directory { path } => Ok(Arc::new(DirectoryStorage::new(path))),
+        #[cfg(feature = "storage-gcs")]
+        StorageConfig::Gcs(gcs_config) => Ok(Arc::new(gcs::GcsStorage::new(gcs_config)?)),
+        #[cfg(not(feature = "storage-gcs"))]
+        StorageConfig::Gcs(_) => Err(anyhow::anyhow!("this build has no GCS storage, see the storage-gcs feature")),
+        #[cfg(feature = "storage-azure")]
+        StorageConfig::Azure(azure_config) => Ok(Arc::new(azure::AzureStorage::new(azure_config)?)),
+        #[cfg(not(feature = "storage-azure"))]
+        StorageConfig::Azure(_) => {
+            Err(anyhow::anyhow!("this build has no Azure storage, see the storage-azure feature"))
+        }
+    }
+}
+
+// Error for anything but a 2xx, with the start of what the service said
+#[cfg_attr(not(any(feature = "storage-gcs", feature = "storage-azure")), allow(dead_code))]
+async fn check(response: reqwest::Response) -> Result<reqwest::Response, StorageError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    let body: String = body.chars().take(200).collect();
+    Err(StorageError::Service(format!("{} {}", status, body.trim())))
+}
+
+// Streams an object being downloaded to `to`, removed if it breaks off
+#[cfg_attr(not(any(feature = "storage-gcs", feature = "storage-azure")), allow(dead_code))]
+async fn download(response: reqwest::Response, to: &Path) -> Result<(), StorageError> {
+    let mut file = tokio::fs::File::create(to).await?;
+    let mut stream = response.bytes_stream();
+    let res = async {
+        while let Some(chunk) = stream.next().await {
+            file.write_all(&chunk?).await?;
+        }
+        file.flush().await?;
+        Ok(())
+    }
+    .await;
+    if res.is_err() {
+        let _ = tokio::fs::remove_file(to).await;
+    }
+    res
+}
@@ -0,0 +1,140 @@
+use std::path::Path;
+
+use anyhow::Result;
+use reqwest::{header, StatusCode, Url};
+use tokio::io::AsyncReadExt;
+
+use super::{check, download, AzureConfig, Storage, StorageError, StorageFuture};
+
+const API_VERSION: &str = "2021-08-06";
+const VERSION_HEADER: &str = "x-ms-version";
+
+pub struct AzureStorage {
+    // The account's blob endpoint
+    endpoint: Url,
+    container: String,
+    prefix: String,
+    sas_token: String,
+    block_size: usize,
+    client: reqwest::Client,
+}
+
+impl AzureStorage {
+    pub fn new(config: &AzureConfig) -> Result<AzureStorage> {
+        if config.container.is_empty() || (config.account.is_empty() && config.endpoint.is_empty()) {
+            return Err(anyhow::anyhow!("Azure storage needs an account and a container"));
+        }
+        let endpoint = match config.endpoint.as_str() {
+            "" => Url::parse(&format!("https://{}.blob.core.windows.net", config.account))?,
+            endpoint => Url::parse(endpoint)?,
+        };
+        if endpoint.cannot_be_a_base() {
+            return Err(anyhow::anyhow!("Invalid Azure endpoint {:?}", config.endpoint));
+        }
+        Ok(AzureStorage {
+            endpoint,
+            container: config.container.clone(),
+            prefix: config.prefix.clone(),
+            sas_token: config.sas_token.trim_start_matches('?').to_owned(),
+            // Blocks are at most 4000 MiB
+            block_size: config.block_size.clamp(1, 4000 << 20),
+            client: reqwest::Client::new(),
+        })
+    }
+
+    // The blob's URL with the SAS token and `query`
+    fn url(&self, name: &str, query: &[(&str, &str)]) -> Url {
+        let mut url = self.endpoint.clone();
+        let blob = format!("{}{}", self.prefix, name);
+        if let Ok(mut path) = url.path_segments_mut() {
+            path.pop_if_empty().push(&self.container).extend(blob.split('/'));
+        }
+        if !self.sas_token.is_empty() {
+            url.set_query(Some(&self.sas_token));
+        }
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+        url
+    }
+}
+
+// Block ids of a blob must all be as long
+fn block_id(index: usize) -> String {
+    base64::encode(format!("{:08}", index))
+}
+
+impl Storage for AzureStorage {
+    fn put<'a>(&'a self, name: &'a str, from: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let mut file = tokio::fs::File::open(from).await?;
+            let size = file.metadata().await?.len();
+
+            let mut blocks = Vec::new();
+            let mut offset = 0;
+            while offset < size {
+                let len = (size - offset).min(self.block_size as u64) as usize;
+                let mut block = vec![0; len];
+                file.read_exact(&mut block).await?;
+                let id = block_id(blocks.len());
+                let response = self
+                    .client
+                    .put(self.url(name, &[("comp", "block"), ("blockid", &id)]))
+                    .header(VERSION_HEADER, API_VERSION)
+                    .body(block)
+                    .send()
+                    .await?;
+                check(response).await?;
+                blocks.push(id);
+                offset += len as u64;
+            }
+
+            // Nothing is visible before the list is committed, an empty one
+            // makes an empty blob
+            let list: String = blocks.iter().map(|id| format!("<Latest>{}</Latest>", id)).collect();
+            let response = self
+                .client
+                .put(self.url(name, &[("comp", "blocklist")]))
+                .header(VERSION_HEADER, API_VERSION)
+                .header(header::CONTENT_TYPE, "application/xml")
+                .body(format!(
+                    "<?xml version=\"1.0\" encoding=\"utf-8\"?><BlockList>{}</BlockList>",
+                    list
+                ))
+                .send()
+                .await?;
+            check(response).await?;
+            Ok(())
+        })
+    }
+
+    fn get<'a>(&'a self, name: &'a str, to: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .get(self.url(name, &[]))
+                .header(VERSION_HEADER, API_VERSION)
+                .send()
+                .await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Err(StorageError::NotFound(name.to_owned()));
+            }
+            download(check(response).await?, to).await
+        })
+    }
+
+    fn delete<'a>(&'a self, name: &'a str) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .delete(self.url(name, &[]))
+                .header(VERSION_HEADER, API_VERSION)
+                .send()
+                .await?;
+            if response.status() != StatusCode::NOT_FOUND {
+                check(response).await?;
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,237 @@
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::{header, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+
+use super::{check, download, GcsConfig, Storage, StorageError, StorageFuture};
+
+// Every chunk of a resumable upload but the last is a multiple of this
+const CHUNK_ALIGNMENT: usize = 256 << 10;
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+const METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+// A token is fetched again this long before it expires
+const TOKEN_MARGIN: Duration = Duration::from_secs(60);
+
+// The fields of a service account key file that matter here
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+// Exchanged for an access token, RFC 7523
+#[derive(Serialize)]
+struct Assertion<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: u64,
+    exp: u64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+enum Credentials {
+    Fixed(String),
+    ServiceAccount(ServiceAccountKey, EncodingKey),
+    MetadataServer,
+}
+
+pub struct GcsStorage {
+    bucket: String,
+    prefix: String,
+    endpoint: Url,
+    chunk_size: usize,
+    credentials: Credentials,
+    // Held while a new one is fetched, so concurrent requests wait for it
+    token: tokio::sync::Mutex<Option<(String, Instant)>>,
+    client: reqwest::Client,
+}
+
+impl GcsStorage {
+    pub fn new(config: &GcsConfig) -> Result<GcsStorage> {
+        if config.bucket.is_empty() {
+            return Err(anyhow::anyhow!("GCS storage needs a bucket"));
+        }
+        let endpoint = Url::parse(&config.endpoint)?;
+        if endpoint.cannot_be_a_base() {
+            return Err(anyhow::anyhow!("Invalid GCS endpoint {:?}", config.endpoint));
+        }
+        let credentials = match &config.credentials_file {
+            _ if !config.access_token.is_empty() => Credentials::Fixed(config.access_token.clone()),
+            Some(file) => {
+                let key: ServiceAccountKey = serde_json::from_slice(&std::fs::read(file)?)?;
+                let encoding_key = EncodingKey::from_rsa_pem(key.private_key.as_bytes())?;
+                Credentials::ServiceAccount(key, encoding_key)
+            }
+            None => Credentials::MetadataServer,
+        };
+        Ok(GcsStorage {
+            bucket: config.bucket.clone(),
+            prefix: config.prefix.clone(),
+            endpoint,
+            chunk_size: (config.chunk_size / CHUNK_ALIGNMENT).max(1) * CHUNK_ALIGNMENT,
+            credentials,
+            token: tokio::sync::Mutex::new(None),
+            // A 308 between chunks isn't a redirect
+            client: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()?,
+        })
+    }
+
+    async fn token(&self) -> Result<String, StorageError> {
+        if let Credentials::Fixed(token) = &self.credentials {
+            return Ok(token.clone());
+        }
+        let mut cached = self.token.lock().await;
+        if let Some((token, expires_at)) = &*cached {
+            if Instant::now() + TOKEN_MARGIN < *expires_at {
+                return Ok(token.clone());
+            }
+        }
+
+        let response = match &self.credentials {
+            Credentials::ServiceAccount(key, encoding_key) => {
+                // The provider's clock, never the service's frozen one
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                let assertion = Assertion {
+                    iss: &key.client_email,
+                    scope: SCOPE,
+                    aud: &key.token_uri,
+                    iat: now,
+                    exp: now + 3600,
+                };
+                let assertion = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &assertion, encoding_key)
+                    .map_err(|err| StorageError::Service(err.to_string()))?;
+                self.client
+                    .post(&key.token_uri)
+                    .form(&[
+                        ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                        ("assertion", &assertion),
+                    ])
+                    .send()
+                    .await?
+            }
+            _ => {
+                self.client
+                    .get(METADATA_TOKEN_URL)
+                    .header("Metadata-Flavor", "Google")
+                    .send()
+                    .await?
+            }
+        };
+        let body = check(response).await?.bytes().await?;
+        let token: TokenResponse =
+            serde_json::from_slice(&body).map_err(|err| StorageError::Service(format!("Token response: {}", err)))?;
+        *cached = Some((
+            token.access_token.clone(),
+            Instant::now() + Duration::from_secs(token.expires_in),
+        ));
+        Ok(token.access_token)
+    }
+
+    // Below the endpoint, an object name being one segment
+    fn url(&self, segments: &[&str]) -> Url {
+        let mut url = self.endpoint.clone();
+        if let Ok(mut path) = url.path_segments_mut() {
+            path.pop_if_empty().extend(segments);
+        }
+        url
+    }
+
+    fn object_url(&self, name: &str) -> Url {
+        let object = format!("{}{}", self.prefix, name);
+        self.url(&["storage", "v1", "b", &self.bucket, "o", &object])
+    }
+}
+
+impl Storage for GcsStorage {
+    fn put<'a>(&'a self, name: &'a str, from: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let mut file = tokio::fs::File::open(from).await?;
+            let size = file.metadata().await?.len();
+
+            let mut url = self.url(&["upload", "storage", "v1", "b", &self.bucket, "o"]);
+            url.query_pairs_mut()
+                .append_pair("uploadType", "resumable")
+                .append_pair("name", &format!("{}{}", self.prefix, name));
+            let response = self
+                .client
+                .post(url)
+                .bearer_auth(self.token().await?)
+                .header("X-Upload-Content-Length", size)
+                .header(header::CONTENT_LENGTH, 0)
+                .send()
+                .await?;
+            let response = check(response).await?;
+            let session = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or_else(|| StorageError::Service("No resumable upload session".to_owned()))?
+                .to_owned();
+
+            let mut offset = 0;
+            loop {
+                let len = (size - offset).min(self.chunk_size as u64) as usize;
+                let mut chunk = vec![0; len];
+                file.read_exact(&mut chunk).await?;
+                let range = match len {
+                    0 => format!("bytes */{}", size),
+                    _ => format!("bytes {}-{}/{}", offset, offset + len as u64 - 1, size),
+                };
+                let response = self
+                    .client
+                    .put(&session)
+                    .header(header::CONTENT_RANGE, range)
+                    .body(chunk)
+                    .send()
+                    .await?;
+                offset += len as u64;
+                // Asks for the rest
+                if response.status() == StatusCode::PERMANENT_REDIRECT && offset < size {
+                    continue;
+                }
+                check(response).await?;
+                return Ok(());
+            }
+        })
+    }
+
+    fn get<'a>(&'a self, name: &'a str, to: &'a Path) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let mut url = self.object_url(name);
+            url.query_pairs_mut().append_pair("alt", "media");
+            let response = self.client.get(url).bearer_auth(self.token().await?).send().await?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Err(StorageError::NotFound(name.to_owned()));
+            }
+            download(check(response).await?, to).await
+        })
+    }
+
+    fn delete<'a>(&'a self, name: &'a str) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let url = self.object_url(name);
+            let response = self.client.delete(url).bearer_auth(self.token().await?).send().await?;
+            if response.status() != StatusCode::NOT_FOUND {
+                check(response).await?;
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,141 @@
+use actix_web::http::header::HeaderMap;
+use anyhow::Result;
+use serde::Deserialize;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer, Registry};
+
+// Журнал и трассировка: `tracing` events and spans on stderr, filtered by
+// RUST_LOG as before (errors only without it). Every request gets a span
+// with its id, see `request_id`; the uploads, fetches and everything else
+// logged while serving it are inside. With the `otel` feature and an
+// `otlp_endpoint`, spans also go to an OpenTelemetry collector.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    pub format: LogFormat,
+    // OTLP over HTTP, `http://collector:4318/v1/traces`; empty exports nothing
+    pub otlp_endpoint: String,
+    // `service.name` of the exported spans
+    pub service_name: String,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            format: LogFormat::Text,
+            otlp_endpoint: String::new(),
+            service_name: "rr-api".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // A line per event, its spans' fields in front
+    Text,
+    // An object per line, for log collectors
+    Json,
+}
+
+// Sent back on every response, and taken from the request when a proxy in
+// front already set it
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+// The client's or proxy's id if it's a sane one, else 16 random hex digits.
+// Never from the seeded generator: that would shift the ids of uploads in
+// deterministic mode.
+pub fn request_id(headers: &HeaderMap) -> String {
+    let given = headers
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id
+                    .bytes()
+                    .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.'))
+        });
+    match given {
+        Some(id) => id.to_owned(),
+        None => format!("{:016x}", rand::random::<u64>()),
+    }
+}
+
+// Flushes exported spans when dropped, keep it until the end of `main`
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(err) = provider.shutdown() {
+                eprintln!("OpenTelemetry shutdown error: {}", err);
+            }
+        }
+    }
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+// Installs the process-wide subscriber, which also takes the `log` records
+// of dependencies; once per process
+pub fn init(config: &TelemetryConfig) -> Result<Telemetry> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("error"));
+    let fmt = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    let fmt = match config.format {
+        LogFormat::Text => fmt.boxed(),
+        LogFormat::Json => fmt.json().boxed(),
+    };
+
+    #[cfg(feature = "otel")]
+    let (otlp, provider) = match config.otlp_endpoint.as_str() {
+        "" => (None, None),
+        endpoint => {
+            let (layer, provider) = otlp_layer(endpoint, &config.service_name)?;
+            (Some(layer), Some(provider))
+        }
+    };
+    #[cfg(not(feature = "otel"))]
+    let otlp: Option<BoxedLayer> = None;
+
+    let layers: Vec<BoxedLayer> = std::iter::once(fmt).chain(otlp).collect();
+    tracing_subscriber::registry().with(layers).with(filter).try_init()?;
+
+    #[cfg(not(feature = "otel"))]
+    if !config.otlp_endpoint.is_empty() {
+        tracing::warn!("telemetry.otlp_endpoint is ignored, built without the otel feature");
+    }
+
+    Ok(Telemetry {
+        #[cfg(feature = "otel")]
+        provider,
+    })
+}
+
+#[cfg(feature = "otel")]
+fn otlp_layer(endpoint: &str, service_name: &str) -> Result<(BoxedLayer, opentelemetry_sdk::trace::SdkTracerProvider)> {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::WithExportConfig;
+
+    // Batched and sent from a thread of its own, never from the request's
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+    let resource = opentelemetry_sdk::Resource::builder()
+        .with_service_name(service_name.to_owned())
+        .build();
+    let provider = opentelemetry_sdk::trace::SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource)
+        .build();
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("rr-api"));
+    Ok((layer.boxed(), provider))
+}
@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::limits::TypeLimits;
+use crate::UploadOptions;
+
+pub const TENANTS_DIR: &str = "tenants";
+
+// Настройки арендатора поверх общего конфига, хранятся в tenants/{name}.json.
+// Unset fields fall back to the global config.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantSettings {
+    // MIME types the tenant may upload, any stored type when unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_types: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strip_metadata: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_image_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_image_height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_image_pixels: Option<u64>,
+    // Replace the global `type_limits` as a whole
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub type_limits: Option<HashMap<String, TypeLimits>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub moderation_hook_url: Option<String>,
+}
+
+impl TenantSettings {
+    pub fn apply(&self, options: &mut UploadOptions) {
+        if let Some(allowed_types) = &self.allowed_types {
+            options.allowed_types = Some(allowed_types.clone());
+        }
+        if let Some(strip_metadata) = self.strip_metadata {
+            options.strip_metadata = strip_metadata;
+        }
+        if let Some(max_width) = self.max_image_width {
+            options.max_width = max_width;
+        }
+        if let Some(max_height) = self.max_image_height {
+            options.max_height = max_height;
+        }
+        if let Some(max_pixels) = self.max_image_pixels {
+            options.max_pixels = max_pixels;
+        }
+        if let Some(type_limits) = &self.type_limits {
+            options.type_limits = type_limits.clone();
+        }
+        if let Some(hook_url) = &self.moderation_hook_url {
+            options.moderation.hook_url = hook_url.clone();
+        }
+    }
+}
+
+// Tenant names end up in file names
+pub fn is_valid_tenant(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 64 && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+// Settings are cached for `cache_ttl`; writes through the store drop the
+// entry at once, instances sharing the directory see them after the TTL
+#[derive(Clone)]
+pub struct TenantStore {
+    dir: PathBuf,
+    cache_ttl: Duration,
+    cache: Arc<Mutex<HashMap<String, CacheEntry>>>,
+}
+
+// When it was read, `None` for a tenant without a file
+type CacheEntry = (Instant, Option<Arc<TenantSettings>>);
+
+impl TenantStore {
+    pub fn new<P: AsRef<Path>>(uploads_dir: P, cache_ttl: Duration) -> TenantStore {
+        TenantStore {
+            dir: uploads_dir.as_ref().join(TENANTS_DIR),
+            cache_ttl,
+            cache: Default::default(),
+        }
+    }
+
+    fn path(&self, tenant: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", tenant))
+    }
+
+    // `None` for a tenant without overrides
+    pub async fn get(&self, tenant: &str) -> Result<Option<Arc<TenantSettings>>> {
+        if !is_valid_tenant(tenant) {
+            return Ok(None);
+        }
+        if let Some((cached_at, settings)) = self.cache.lock().unwrap().get(tenant) {
+            if cached_at.elapsed() < self.cache_ttl {
+                return Ok(settings.clone());
+            }
+        }
+
+        let settings = match tokio::fs::read(self.path(tenant)).await {
+            Ok(data) => Some(Arc::new(serde_json::from_slice(&data)?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(tenant.to_owned(), (Instant::now(), settings.clone()));
+        Ok(settings)
+    }
+
+    pub async fn put(&self, tenant: &str, settings: &TenantSettings) -> Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+
+        // Write-then-rename, a reader never sees a torn file
+        let path = self.path(tenant);
+        let tmp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_vec(settings)?).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+
+        self.cache.lock().unwrap().remove(tenant);
+        Ok(())
+    }
+
+    // Returns whether the tenant had overrides
+    pub async fn remove(&self, tenant: &str) -> std::io::Result<bool> {
+        self.cache.lock().unwrap().remove(tenant);
+        match tokio::fs::remove_file(self.path(tenant)).await {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Names of the tenants with overrides, sorted
+    pub async fn list(&self) -> std::io::Result<Vec<String>> {
+        let mut tenants = Vec::new();
+
+        let mut dir = match tokio::fs::read_dir(&self.dir).await {
+            Ok(dir) => dir,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(tenants),
+            Err(err) => return Err(err),
+        };
+        while let Some(entry) = dir.next_entry().await? {
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            if let Some(tenant) = name.strip_suffix(".json") {
+                if is_valid_tenant(tenant) {
+                    tenants.push(tenant.to_owned());
+                }
+            }
+        }
+        tenants.sort();
+
+        Ok(tenants)
+    }
+}
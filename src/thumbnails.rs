@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use tokio::sync::mpsc;
+
+use crate::{concurrency, imagetools, write_thumbnail};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailStatus {
+    Pending,
+    Ready,
+    Failed,
+    // Not made yet, GET /images/{id}/thumbnail creates it on demand
+    Missing,
+}
+
+// Формат миниатюр: как у оригинала или один для всех
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThumbnailFormat {
+    Source,
+    Webp,
+    Avif,
+}
+
+pub fn default_thumbnail_format() -> ThumbnailFormat {
+    ThumbnailFormat::Source
+}
+
+impl ThumbnailFormat {
+    // Extension of the thumbnail of an original stored as `source`; PNG for
+    // one the backend can't write
+    pub fn extension(self, source: &str) -> &str {
+        match self {
+            ThumbnailFormat::Source if imagetools::can_encode(source) => source,
+            ThumbnailFormat::Source => "png",
+            ThumbnailFormat::Webp => "webp",
+            ThumbnailFormat::Avif => "avif",
+        }
+    }
+}
+
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug)]
+struct ThumbnailJob {
+    id: String,
+    upload_path: PathBuf,
+    thumbnail_path: PathBuf,
+    quality: Option<u8>,
+    crop: Option<imagetools::Gravity>,
+    xmp: Option<String>,
+}
+
+// Очередь фоновой генерации миниатюр: ограниченный канал и пул обработчиков.
+// Jobs still queued at shutdown are lost; those thumbnails are made on their
+// first request instead.
+#[derive(Debug, Clone)]
+pub struct ThumbnailQueue {
+    sender: mpsc::Sender<ThumbnailJob>,
+    capacity: usize,
+    // Only pending and failed jobs, a finished one is visible on disk
+    statuses: Arc<Mutex<HashMap<String, ThumbnailStatus>>>,
+}
+
+impl ThumbnailQueue {
+    pub fn start(workers: usize, capacity: usize) -> ThumbnailQueue {
+        let capacity = capacity.max(1);
+        let (sender, receiver) = mpsc::channel(capacity);
+        let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+        let statuses: Arc<Mutex<HashMap<String, ThumbnailStatus>>> = Default::default();
+
+        for _ in 0..workers.max(1) {
+            let (receiver, statuses) = (receiver.clone(), statuses.clone());
+            actix_rt::spawn(async move {
+                loop {
+                    let job = receiver.lock().await.recv().await;
+                    match job {
+                        Some(job) => run(job, &statuses).await,
+                        None => break,
+                    }
+                }
+            });
+        }
+
+        ThumbnailQueue {
+            sender,
+            capacity,
+            statuses,
+        }
+    }
+
+    // Returns false if the queue is full, the caller then makes the
+    // thumbnail itself
+    pub fn enqueue(
+        &self,
+        id: &str,
+        upload_path: PathBuf,
+        thumbnail_path: PathBuf,
+        quality: Option<u8>,
+        crop: Option<imagetools::Gravity>,
+        xmp: Option<String>,
+    ) -> bool {
+        self.statuses.lock().unwrap().insert(id.to_owned(), ThumbnailStatus::Pending);
+
+        let job = ThumbnailJob {
+            id: id.to_owned(),
+            upload_path,
+            thumbnail_path,
+            quality,
+            crop,
+            xmp,
+        };
+        match self.sender.clone().try_send(job) {
+            Ok(()) => true,
+            Err(_) => {
+                tracing::warn!("Thumbnail queue is full, creating the thumbnail of {} inline", id);
+                self.statuses.lock().unwrap().remove(id);
+                false
+            }
+        }
+    }
+
+    // `None` if the queue knows nothing about the id
+    pub fn status(&self, id: &str) -> Option<ThumbnailStatus> {
+        self.statuses.lock().unwrap().get(id).copied()
+    }
+
+    // Queued or in progress
+    pub fn queued(&self) -> usize {
+        let statuses = self.statuses.lock().unwrap();
+        statuses.values().filter(|status| **status == ThumbnailStatus::Pending).count()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Until a queued thumbnail is done or `deadline` passes; `Pending` on
+    // timeout. Only for ids this queue took, finished ones aren't tracked.
+    pub async fn wait(&self, id: &str, deadline: Instant) -> ThumbnailStatus {
+        loop {
+            match self.status(id) {
+                Some(ThumbnailStatus::Pending) => {}
+                Some(status) => return status,
+                None => return ThumbnailStatus::Ready,
+            }
+            if Instant::now() >= deadline {
+                return ThumbnailStatus::Pending;
+            }
+            tokio::time::sleep(WAIT_POLL_INTERVAL).await;
+        }
+    }
+}
+
+async fn run(job: ThumbnailJob, statuses: &Mutex<HashMap<String, ThumbnailStatus>>) {
+    let ThumbnailJob {
+        id,
+        upload_path,
+        thumbnail_path,
+        quality,
+        crop,
+        xmp,
+    } = job;
+
+    let res =
+        concurrency::run_blocking(move || write_thumbnail(&upload_path, &thumbnail_path, quality, crop, xmp)).await;
+    let mut statuses = statuses.lock().unwrap();
+    match res {
+        Ok(Ok(())) => {
+            tracing::debug!("Thumbnail of {} created", id);
+            statuses.remove(&id);
+        }
+        Ok(Err(err)) => {
+            tracing::warn!("Error creating thumbnail of {}: {}", id, err);
+            statuses.insert(id, ThumbnailStatus::Failed);
+        }
+        Err(err) => {
+            tracing::error!("Thumbnail worker error for {}: {}", id, err);
+            statuses.insert(id, ThumbnailStatus::Failed);
+        }
+    }
+}
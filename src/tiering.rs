@@ -0,0 +1,218 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::metadata::{self, ImageMetadata, ListFilter};
+use crate::storage::{self, Storage, StorageConfig, StorageError};
+use crate::{find_upload, layout};
+
+const DAY_SECS: u64 = 24 * 60 * 60;
+
+// Холодный ярус: originals untouched for `after_days` move to cheaper
+// storage, derivatives stay. An archived original is restored on the first
+// request for it, which is answered 202 with Retry-After meanwhile.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TieringConfig {
+    // 0 is off
+    pub after_days: u64,
+    // Where archived originals go, see `storage::DirectoryStorage`
+    pub cold_dir: Option<PathBuf>,
+    // Any other backend instead, a GCS bucket or an Azure container
+    pub cold_storage: Option<StorageConfig>,
+    pub interval_secs: u64,
+    pub retry_after_secs: u64,
+}
+
+impl Default for TieringConfig {
+    fn default() -> Self {
+        TieringConfig {
+            after_days: 0,
+            cold_dir: None,
+            cold_storage: None,
+            interval_secs: 3600,
+            retry_after_secs: 60,
+        }
+    }
+}
+
+static COLD_STORAGE: OnceLock<Arc<dyn Storage>> = OnceLock::new();
+// Ids being copied back
+static RESTORING: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+
+fn restoring() -> &'static Mutex<HashSet<String>> {
+    RESTORING.get_or_init(Default::default)
+}
+
+// Once at startup. Without a cold tier nothing is archived, and originals
+// archived earlier can't be restored.
+pub fn install(config: &TieringConfig) -> Result<()> {
+    let storage_config = match (&config.cold_storage, &config.cold_dir) {
+        (Some(storage_config), _) => storage_config.clone(),
+        (None, Some(cold_dir)) => StorageConfig::Directory { path: cold_dir.clone() },
+        (None, None) => return Ok(()),
+    };
+    let _ = COLD_STORAGE.set(storage::open(&storage_config)?);
+    Ok(())
+}
+
+fn cold_storage() -> Option<Arc<dyn Storage>> {
+    COLD_STORAGE.get().cloned()
+}
+
+fn object_name(image_metadata: &ImageMetadata) -> String {
+    format!("{}.{}", image_metadata.id, image_metadata.extension)
+}
+
+// Last download or the upload, whichever is later
+fn last_touched(image_metadata: &ImageMetadata) -> u64 {
+    image_metadata.accessed_at.unwrap_or(0).max(image_metadata.created_at)
+}
+
+// Records a download; written at most daily, `after_days` doesn't need more
+pub async fn touch<P: AsRef<Path>>(uploads_dir: P, image_metadata: &ImageMetadata) -> Result<()> {
+    let now = metadata::now();
+    if image_metadata
+        .accessed_at
+        .map(|accessed_at| accessed_at + DAY_SECS > now)
+        .unwrap_or(false)
+    {
+        return Ok(());
+    }
+    let mut image_metadata = image_metadata.clone();
+    image_metadata.accessed_at = Some(now);
+    metadata::save(uploads_dir, &image_metadata).await
+}
+
+// Copies the original out, marks it archived, then removes the hot copy
+async fn archive<P: AsRef<Path>>(
+    storage: &dyn Storage,
+    uploads_dir: P,
+    mut image_metadata: ImageMetadata,
+) -> Result<bool> {
+    let (path, _) = match find_upload(&uploads_dir, &image_metadata.id).await {
+        Some(found) => found,
+        None => return Ok(false),
+    };
+
+    storage.put(&object_name(&image_metadata), &path).await?;
+    image_metadata.archived_at = Some(metadata::now());
+    metadata::save(&uploads_dir, &image_metadata).await?;
+    tokio::fs::remove_file(&path).await?;
+    Ok(true)
+}
+
+// One pass of the policy, returns how many originals were archived
+pub async fn archive_untouched<P: AsRef<Path>>(config: &TieringConfig, uploads_dir: P) -> Result<usize> {
+    let storage = match cold_storage() {
+        Some(storage) if config.after_days != 0 => storage,
+        _ => return Ok(0),
+    };
+
+    let cutoff = metadata::now().saturating_sub(config.after_days * DAY_SECS);
+    let (all, _) = metadata::list(&uploads_dir, &ListFilter::default(), None, usize::MAX).await?;
+    let mut archived = 0;
+    for image_metadata in all {
+        if image_metadata.archived_at.is_some() || last_touched(&image_metadata) > cutoff {
+            continue;
+        }
+        if restoring().lock().unwrap().contains(&image_metadata.id) {
+            continue;
+        }
+        let id = image_metadata.id.clone();
+        match archive(&*storage, &uploads_dir, image_metadata).await {
+            Ok(true) => archived += 1,
+            Ok(false) => {}
+            Err(err) => tracing::error!("Error archiving {}: {}", id, err),
+        }
+    }
+    Ok(archived)
+}
+
+// Copies an archived original back in the background; false if a restore
+// was already running. Errors are logged, the next request tries again.
+pub fn restore<P: AsRef<Path>>(uploads_dir: P, image_metadata: &ImageMetadata) -> bool {
+    let storage = match cold_storage() {
+        Some(storage) => storage,
+        None => {
+            tracing::error!("{} is archived, but there is no cold storage", image_metadata.id);
+            return false;
+        }
+    };
+    if !restoring().lock().unwrap().insert(image_metadata.id.clone()) {
+        return false;
+    }
+
+    let uploads_dir = uploads_dir.as_ref().to_owned();
+    let (id, name) = (image_metadata.id.clone(), object_name(image_metadata));
+    actix_rt::spawn(async move {
+        let dir = layout::shard_dir(&uploads_dir, &id);
+        let (path, tmp_path) = (dir.join(&name), dir.join(format!("{}.restore", name)));
+
+        let res: Result<()> = async {
+            storage.get(&name, &tmp_path).await?;
+            tokio::fs::rename(&tmp_path, &path).await?;
+            // Reloaded, it may have changed while the copy ran
+            if let Some(mut image_metadata) = metadata::load(&uploads_dir, &id).await? {
+                image_metadata.archived_at = None;
+                image_metadata.accessed_at = Some(metadata::now());
+                metadata::save(&uploads_dir, &image_metadata).await?;
+            }
+            storage.delete(&name).await?;
+            Ok(())
+        }
+        .await;
+        match res {
+            Ok(()) => tracing::info!("Restored {} from cold storage", id),
+            Err(err) => {
+                tracing::error!("Error restoring {}: {}", id, err);
+                let _ = tokio::fs::remove_file(&tmp_path).await;
+            }
+        }
+        restoring().lock().unwrap().remove(&id);
+    });
+    true
+}
+
+// The metadata of an upload whose original is in cold storage
+pub async fn archived<P: AsRef<Path>>(uploads_dir: P, id: &str) -> Option<ImageMetadata> {
+    match metadata::load(uploads_dir, id).await {
+        Ok(Some(image_metadata)) if image_metadata.archived_at.is_some() => Some(image_metadata),
+        Ok(_) => None,
+        Err(err) => {
+            tracing::warn!("Metadata error for {}: {}", id, err);
+            None
+        }
+    }
+}
+
+// For a deleted upload
+pub async fn delete_archived(image_metadata: &ImageMetadata) -> Result<(), StorageError> {
+    match cold_storage() {
+        Some(storage) => storage.delete(&object_name(image_metadata)).await,
+        None => Ok(()),
+    }
+}
+
+pub fn spawn_archiver(config: &TieringConfig, uploads_dir: PathBuf) {
+    if config.after_days == 0 || (config.cold_dir.is_none() && config.cold_storage.is_none()) {
+        return;
+    }
+
+    let config = config.clone();
+    actix_rt::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+        loop {
+            interval.tick().await;
+            match archive_untouched(&config, &uploads_dir).await {
+                Ok(0) => {}
+                Ok(archived) => tracing::info!("Moved {} original(s) to cold storage", archived),
+                Err(err) => tracing::error!("Tiering error: {}", err),
+            }
+        }
+    });
+}
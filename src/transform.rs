@@ -0,0 +1,417 @@
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::imagetools::Gravity;
+use crate::{concurrency, find_upload, imagetools, layout, tiering, UploadOptions, STORED_EXTENSIONS};
+
+// Трансформации на лету: размер, вписывание, формат, качество
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TransformConfig {
+    pub enabled: bool,
+    pub max_width: u32,
+    pub max_height: u32,
+    // Unsigned specs are refused; turning this off is only safe behind an
+    // admin-only listener
+    pub require_signature: bool,
+}
+
+impl Default for TransformConfig {
+    fn default() -> Self {
+        TransformConfig {
+            enabled: false,
+            max_width: 4096,
+            max_height: 4096,
+            require_signature: true,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransformError {
+    #[error("Invalid transformation: {0}")]
+    InvalidSpec(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    // Inside the box, aspect ratio kept
+    Contain,
+    // Fills the box, the overflow is cropped as `gravity` says
+    Cover,
+    // Exactly the box, stretched
+    Fill,
+}
+
+impl Fit {
+    fn as_str(self) -> &'static str {
+        match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Flip {
+    // Left to right
+    H,
+    // Top to bottom
+    V,
+    Hv,
+}
+
+impl Flip {
+    fn as_str(self) -> &'static str {
+        match self {
+            Flip::H => "h",
+            Flip::V => "v",
+            Flip::Hv => "hv",
+        }
+    }
+}
+
+// x, y, width, height
+type Rect = (u32, u32, u32, u32);
+
+// `crop=x,y,w,h`, in pixels of the original
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl TryFrom<String> for Crop {
+    type Error = TransformError;
+
+    fn try_from(value: String) -> Result<Crop, TransformError> {
+        let invalid = || TransformError::InvalidSpec("crop must be x,y,w,h".to_owned());
+        let numbers = value
+            .split(',')
+            .map(|number| number.trim().parse::<u32>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+        match numbers[..] {
+            [x, y, width, height] => Ok(Crop { x, y, width, height }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl Crop {
+    // The part inside an image of `size`, `None` if nothing is
+    fn within(&self, (w, h): (u32, u32)) -> Option<Rect> {
+        if self.x >= w || self.y >= h {
+            return None;
+        }
+        Some((self.x, self.y, self.width.min(w - self.x), self.height.min(h - self.y)))
+    }
+}
+
+fn default_fit() -> Fit {
+    Fit::Contain
+}
+
+// Larger blurs cost a lot and look no different
+pub const MAX_BLUR: f32 = 50.0;
+pub const MAX_SHARPEN: f32 = 10.0;
+
+fn default_gravity() -> Gravity {
+    Gravity::Center
+}
+
+// `?w=&h=&fit=&gravity=&rotate=&flip=&crop=&grayscale=&brightness=&contrast=
+// &blur=&sharpen=&format=&quality=` of GET /images/{id}/transform. The
+// original is cropped, then rotated, then flipped, then sized, then
+// filtered. The whole spec is signed, see `signing::sign_transform_url`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TransformSpec {
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    #[serde(default = "default_fit")]
+    pub fit: Fit,
+    // Only matters with `fit=cover`
+    #[serde(default = "default_gravity")]
+    pub gravity: Gravity,
+    // Degrees clockwise, a multiple of 90
+    pub rotate: Option<u32>,
+    pub flip: Option<Flip>,
+    pub crop: Option<Crop>,
+    #[serde(default)]
+    pub grayscale: bool,
+    // -100..=100
+    pub brightness: Option<i32>,
+    pub contrast: Option<i32>,
+    // Gaussian sigma in pixels, up to `MAX_BLUR`
+    pub blur: Option<f32>,
+    // Unsharp mask strength, up to `MAX_SHARPEN`
+    pub sharpen: Option<f32>,
+    // One of the stored extensions the backend can write, defaults to the
+    // original's
+    pub format: Option<String>,
+    // 1-100, JPEG only
+    pub quality: Option<u8>,
+}
+
+impl TransformSpec {
+    pub fn validate(&self, config: &TransformConfig) -> Result<(), TransformError> {
+        let invalid = |message: &str| Err(TransformError::InvalidSpec(message.to_owned()));
+
+        match (self.w, self.h) {
+            (Some(0), _) | (_, Some(0)) => return invalid("w and h must be positive"),
+            (Some(w), _) if w > config.max_width => return invalid("w is over the limit"),
+            (_, Some(h)) if h > config.max_height => return invalid("h is over the limit"),
+            _ => {}
+        }
+        if matches!(self.rotate, Some(rotate) if rotate % 90 != 0) {
+            return invalid("rotate must be a multiple of 90");
+        }
+        if matches!(self.crop, Some(crop) if crop.width == 0 || crop.height == 0) {
+            return invalid("crop must be positive");
+        }
+        let adjustment = |value: Option<i32>| matches!(value, Some(value) if !(-100..=100).contains(&value));
+        if adjustment(self.brightness) || adjustment(self.contrast) {
+            return invalid("brightness and contrast must be within -100-100");
+        }
+        // Written so NaN fails too
+        if matches!(self.blur, Some(sigma) if !(sigma > 0.0 && sigma <= MAX_BLUR)) {
+            return invalid("blur is out of range");
+        }
+        if matches!(self.sharpen, Some(amount) if !(amount > 0.0 && amount <= MAX_SHARPEN)) {
+            return invalid("sharpen is out of range");
+        }
+        if let Some(format) = &self.format {
+            if !STORED_EXTENSIONS.contains(&format.as_str()) || !imagetools::can_encode(format) {
+                return invalid("unsupported format");
+            }
+        }
+        if let Some(quality) = self.quality {
+            if quality == 0 || quality > 100 {
+                return invalid("quality must be within 1-100");
+            }
+        }
+
+        Ok(())
+    }
+
+    // Fixed order and spelling, so equal specs sign and cache the same
+    pub fn canonical(&self) -> String {
+        let mut params = Vec::new();
+        if let Some(crop) = self.crop {
+            params.push(format!("crop={},{},{},{}", crop.x, crop.y, crop.width, crop.height));
+        }
+        if self.quarter_turns() != 0 {
+            params.push(format!("rotate={}", self.quarter_turns() * 90));
+        }
+        if let Some(flip) = self.flip {
+            params.push(format!("flip={}", flip.as_str()));
+        }
+        if let Some(w) = self.w {
+            params.push(format!("w={}", w));
+        }
+        if let Some(h) = self.h {
+            params.push(format!("h={}", h));
+        }
+        params.push(format!("fit={}", self.fit.as_str()));
+        // Left out when centered, so older names and signatures stay valid
+        if self.fit == Fit::Cover && self.gravity == Gravity::Smart {
+            params.push("gravity=smart".to_owned());
+        }
+        if self.grayscale {
+            params.push("grayscale=true".to_owned());
+        }
+        if let Some(brightness) = self.brightness {
+            params.push(format!("brightness={}", brightness));
+        }
+        if let Some(contrast) = self.contrast {
+            params.push(format!("contrast={}", contrast));
+        }
+        if let Some(sigma) = self.blur {
+            params.push(format!("blur={}", sigma));
+        }
+        if let Some(amount) = self.sharpen {
+            params.push(format!("sharpen={}", amount));
+        }
+        if let Some(format) = &self.format {
+            params.push(format!("format={}", format));
+        }
+        if let Some(quality) = self.quality {
+            params.push(format!("quality={}", quality));
+        }
+        params.join("&")
+    }
+
+    // The original's format unless asked otherwise, PNG for one the backend
+    // can't write (GIF, WebP with the image backend)
+    pub fn output_extension(&self, original: &'static str) -> &'static str {
+        self.format
+            .as_ref()
+            .and_then(|format| STORED_EXTENSIONS.iter().find(|extension| *extension == format))
+            .copied()
+            .unwrap_or(if imagetools::can_encode(original) { original } else { "png" })
+    }
+
+    fn quarter_turns(&self) -> u32 {
+        self.rotate.unwrap_or(0) / 90 % 4
+    }
+
+    // `{id}_t{hash}.{ext}`, next to the original
+    pub fn cache_file_name(&self, id: &str, extension: &str) -> String {
+        let hash = Sha256::digest(self.canonical().as_bytes());
+        format!("{}_t{}.{}", id, hex::encode(&hash[..8]), extension)
+    }
+
+    // Size to resize to, then the rectangle to crop from it, if any
+    fn geometry(&self, (src_w, src_h): (u32, u32)) -> ((u32, u32), Option<Rect>) {
+        let (src_w, src_h) = (src_w.max(1), src_h.max(1));
+        let scaled = |side: u32, scale: f64| ((f64::from(side) * scale).round() as u32).max(1);
+
+        match (self.w, self.h) {
+            (None, None) => ((src_w, src_h), None),
+            (Some(w), None) => ((w, scaled(src_h, f64::from(w) / f64::from(src_w))), None),
+            (None, Some(h)) => ((scaled(src_w, f64::from(h) / f64::from(src_h)), h), None),
+            (Some(w), Some(h)) => {
+                let (scale_w, scale_h) = (f64::from(w) / f64::from(src_w), f64::from(h) / f64::from(src_h));
+                match self.fit {
+                    Fit::Fill => ((w, h), None),
+                    Fit::Contain => {
+                        let scale = scale_w.min(scale_h);
+                        ((scaled(src_w, scale).min(w), scaled(src_h, scale).min(h)), None)
+                    }
+                    Fit::Cover => {
+                        let scale = scale_w.max(scale_h);
+                        let (resized_w, resized_h) = (scaled(src_w, scale).max(w), scaled(src_h, scale).max(h));
+                        let crop = ((resized_w - w) / 2, (resized_h - h) / 2, w, h);
+                        ((resized_w, resized_h), Some(crop))
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn render(src: &Path, spec: &TransformSpec, extension: &str) -> Result<Vec<u8>> {
+    let mut image = imagetools::read_image(src)?;
+
+    if let Some(crop) = spec.crop {
+        let outside = || TransformError::InvalidSpec("crop is outside the image".to_owned());
+        let rect = crop.within(imagetools::image_size(&image)).ok_or_else(outside)?;
+        image = imagetools::crop_image(&image, rect)?;
+    }
+    if spec.quarter_turns() != 0 {
+        image = imagetools::rotate_image(&image, spec.quarter_turns())?;
+    }
+    if let Some(flip) = spec.flip {
+        image = imagetools::flip_image(&image, flip != Flip::V, flip != Flip::H)?;
+    }
+
+    if let (Fit::Cover, Gravity::Smart, Some(w), Some(h)) = (spec.fit, spec.gravity, spec.w, spec.h) {
+        // Cropped first, where the window lands depends on the content
+        let window = imagetools::crop_window(&image, (w, h), Gravity::Smart)?;
+        image = imagetools::resize_image(&imagetools::crop_image(&image, window)?, (w, h))?;
+    } else {
+        let src_size = imagetools::image_size(&image);
+        let (size, crop) = spec.geometry(src_size);
+        if size != src_size {
+            image = imagetools::resize_image(&image, size)?;
+        }
+        if let Some(rect) = crop {
+            image = imagetools::crop_image(&image, rect)?;
+        }
+    }
+
+    // Filters work on the output size, `blur` is in its pixels
+    if spec.grayscale {
+        image = imagetools::grayscale_image(&image)?;
+    }
+    if spec.brightness.is_some() || spec.contrast.is_some() {
+        image = imagetools::adjust_image(&image, spec.brightness.unwrap_or(0), spec.contrast.unwrap_or(0))?;
+    }
+    if let Some(sigma) = spec.blur {
+        image = imagetools::blur_image(&image, sigma)?;
+    }
+    if let Some(amount) = spec.sharpen {
+        image = imagetools::sharpen_image(&image, amount)?;
+    }
+
+    Ok(imagetools::encode_image(&image, extension, spec.quality)?)
+}
+
+// Renders into memory, for edits stored as new uploads. `None` if there is
+// no such upload.
+pub async fn render_upload<P: AsRef<Path>>(
+    uploads_dir: P,
+    id: &str,
+    spec: &TransformSpec,
+) -> Result<Option<(Vec<u8>, &'static str)>> {
+    let (upload_path, original_extension) = match find_upload(&uploads_dir, id).await {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+
+    let extension = spec.output_extension(original_extension);
+    let spec = spec.clone();
+    let data = concurrency::run_blocking(move || render(&upload_path, &spec, extension)).await??;
+    Ok(Some((data, extension)))
+}
+
+// The cached result, rendered first if needed. `None` if there is no such
+// upload, or its original is archived and nothing is cached.
+pub async fn ensure_transformed<P: AsRef<Path>>(
+    uploads_dir: P,
+    id: &str,
+    spec: &TransformSpec,
+    options: &UploadOptions,
+) -> Result<Option<(PathBuf, &'static str)>> {
+    let (upload_path, original_extension) = match find_upload(&uploads_dir, id).await {
+        Some(found) => found,
+        None => return Ok(archived_transformation(&uploads_dir, id, spec).await),
+    };
+
+    let extension = spec.output_extension(original_extension);
+    let path = upload_path.with_file_name(spec.cache_file_name(id, extension));
+    if tokio::fs::metadata(&path).await.is_ok() {
+        return Ok(Some((path, extension)));
+    }
+
+    tracing::debug!("Rendering {} ({})", path.to_str().unwrap_or("?"), spec.canonical());
+    let spec_clone = spec.clone();
+    let data = concurrency::run_blocking(move || render(&upload_path, &spec_clone, extension)).await??;
+
+    let tmp_path = path.with_extension(format!("{}.tmp", extension));
+    tokio::fs::write(&tmp_path, data).await?;
+    if let Some(xmp) = options.attribution.xmp_packet(id) {
+        let tmp_path = tmp_path.clone();
+        if let Err(err) = tokio::task::spawn_blocking(move || imagetools::embed_xmp(tmp_path, &xmp)).await? {
+            tracing::warn!("Error embedding attribution: {}", err);
+        }
+    }
+    tokio::fs::rename(&tmp_path, &path).await?;
+
+    Ok(Some((path, extension)))
+}
+
+// Derivatives stay hot when their original is archived
+async fn archived_transformation<P: AsRef<Path>>(
+    uploads_dir: P,
+    id: &str,
+    spec: &TransformSpec,
+) -> Option<(PathBuf, &'static str)> {
+    let image_metadata = tiering::archived(&uploads_dir, id).await?;
+    let original_extension = STORED_EXTENSIONS.iter().find(|extension| **extension == image_metadata.extension)?;
+    let extension = spec.output_extension(original_extension);
+    let path = layout::shard_dir(&uploads_dir, id).join(spec.cache_file_name(id, extension));
+    match tokio::fs::metadata(&path).await {
+        Ok(_) => Some((path, extension)),
+        Err(_) => None,
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use futures_util::stream::{Stream, StreamExt};
+
+use crate::auth::Ownership;
+use crate::{
+    clock, file_stream, gen_rand_id, mime_type_to_extension, upload_image, UploadError, UploadOptions,
+    UploadedFile,
+};
+
+// Протокол tus 1.0.0: https://tus.io/protocols/resumable-upload.html
+pub const TUS_VERSION: &str = "1.0.0";
+pub const TUS_EXTENSIONS: &str = "creation";
+pub const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+#[derive(Debug, thiserror::Error)]
+pub enum TusError {
+    #[error("Upload-Metadata header is malformed")]
+    InvalidMetadata,
+    #[error("Upload-Offset {0} doesn't match the stored offset {1}")]
+    OffsetMismatch(u64, u64),
+    #[error("Body exceeds the declared Upload-Length")]
+    LengthExceeded,
+    #[error("Upload is already complete")]
+    AlreadyComplete,
+}
+
+// Журнал загрузки: хранится рядом с частичным файлом
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TusUpload {
+    pub id: String,
+    pub length: u64,
+    pub offset: u64,
+    pub metadata: HashMap<String, String>,
+    pub created_at: u64,
+    // Id of the stored image once the upload has been finalized
+    pub uploaded_id: Option<String>,
+    // Of the caller who created it, the stored image is theirs
+    #[serde(default)]
+    pub ownership: Ownership,
+}
+
+impl TusUpload {
+    pub fn is_complete(&self) -> bool {
+        self.offset == self.length
+    }
+}
+
+// `Upload-Metadata: filename d29ybGQ=,is_confidential`
+pub fn parse_metadata(header: &str) -> Result<HashMap<String, String>> {
+    let mut metadata = HashMap::new();
+
+    for pair in header.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let mut parts = pair.splitn(2, ' ');
+        let key = parts.next().ok_or(TusError::InvalidMetadata)?;
+        let value = match parts.next() {
+            Some(value) => {
+                let value = base64::decode(value.trim()).map_err(|_| TusError::InvalidMetadata)?;
+                String::from_utf8(value).map_err(|_| TusError::InvalidMetadata)?
+            }
+            None => String::new(),
+        };
+
+        metadata.insert(key.to_owned(), value);
+    }
+
+    Ok(metadata)
+}
+
+#[derive(Clone)]
+pub struct TusStore {
+    dir: PathBuf,
+    busy: Arc<Mutex<HashSet<String>>>,
+}
+
+// Only one PATCH per upload at a time
+pub struct TusLock {
+    id: String,
+    busy: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Drop for TusLock {
+    fn drop(&mut self) {
+        self.busy.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl TusStore {
+    pub fn new<P: AsRef<Path>>(uploads_dir: P) -> TusStore {
+        TusStore {
+            dir: uploads_dir.as_ref().join("tus"),
+            busy: Default::default(),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn journal_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn part_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.part", id))
+    }
+
+    fn is_valid_id(id: &str) -> bool {
+        !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric())
+    }
+
+    pub fn lock(&self, id: &str) -> Option<TusLock> {
+        let mut busy = self.busy.lock().unwrap();
+        if busy.insert(id.to_owned()) {
+            Some(TusLock {
+                id: id.to_owned(),
+                busy: self.busy.clone(),
+            })
+        } else {
+            None
+        }
+    }
+
+    pub async fn create(
+        &self,
+        length: u64,
+        metadata: HashMap<String, String>,
+        ownership: Ownership,
+    ) -> Result<TusUpload> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+
+        let upload = TusUpload {
+            id: gen_rand_id(16),
+            length,
+            offset: 0,
+            metadata,
+            created_at: clock::unix_now(),
+            uploaded_id: None,
+            ownership,
+        };
+
+        tokio::fs::File::create(self.part_path(&upload.id)).await?;
+        self.save(&upload).await?;
+
+        Ok(upload)
+    }
+
+    pub async fn get(&self, id: &str) -> Result<Option<TusUpload>> {
+        if !Self::is_valid_id(id) {
+            return Ok(None);
+        }
+
+        match tokio::fs::read(self.journal_path(id)).await {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn save(&self, upload: &TusUpload) -> Result<()> {
+        // Write-then-rename, so a crash never leaves a torn journal
+        let tmp_path = self.journal_path(&upload.id).with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_vec(upload)?).await?;
+        tokio::fs::rename(&tmp_path, self.journal_path(&upload.id)).await?;
+        Ok(())
+    }
+
+    // Appends the PATCH body. Whatever arrived before a client error is kept,
+    // so the client can resume from the new offset. An upload that is
+    // complete but wasn't stored takes an empty body, for a retry of the
+    // finalization.
+    pub async fn append<S, E>(&self, mut upload: TusUpload, offset: u64, mut stream: S) -> Result<TusUpload>
+    where
+        S: Stream<Item = Result<Bytes, E>> + std::marker::Unpin,
+        E: Into<anyhow::Error>,
+    {
+        if upload.uploaded_id.is_some() {
+            return Err(TusError::AlreadyComplete.into());
+        }
+        if offset != upload.offset {
+            return Err(TusError::OffsetMismatch(offset, upload.offset).into());
+        }
+
+        let file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(self.part_path(&upload.id))
+            .await
+            .map_err(UploadError::from)?;
+        let mut writer = tokio::io::BufWriter::new(file);
+
+        let mut res = Ok(());
+        while let Some(chunk) = stream.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    res = Err(UploadError::Body(err.into()).into());
+                    break;
+                }
+            };
+
+            if upload.offset + chunk.len() as u64 > upload.length {
+                res = Err(TusError::LengthExceeded.into());
+                break;
+            }
+
+            if let Err(err) = writer.write_all(&chunk).await {
+                res = Err(UploadError::from(err).into());
+                break;
+            }
+            upload.offset += chunk.len() as u64;
+        }
+
+        writer.flush().await.map_err(UploadError::from)?;
+        self.save(&upload).await?;
+
+        res.map(|_| upload)
+    }
+
+    pub async fn discard(&self, id: &str) -> Result<()> {
+        for path in &[self.part_path(id), self.journal_path(id)] {
+            match tokio::fs::remove_file(path).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Hands the assembled file over to the regular upload pipeline
+    pub async fn finalize<P: AsRef<Path>>(
+        &self,
+        mut upload: TusUpload,
+        uploads_dir: P,
+        options: &UploadOptions,
+    ) -> Result<UploadedFile> {
+        let part_path = self.part_path(&upload.id);
+
+        let mut head = vec![0; 8192];
+        let mut file = tokio::fs::File::open(&part_path).await?;
+        let n = file.read(&mut head).await?;
+        head.truncate(n);
+
+        let content_type = crate::sniff_type(&head);
+        let extension = mime_type_to_extension(&content_type)
+            .ok_or_else(|| UploadError::UnsupportedType(content_type.clone()))?;
+
+        let file = tokio::fs::File::open(&part_path).await?;
+        let uploaded_file = upload_image(file_stream(file), uploads_dir, extension, options).await?;
+
+        upload.uploaded_id = Some(uploaded_file.id.clone());
+        self.save(&upload).await?;
+        tokio::fs::remove_file(&part_path).await?;
+
+        Ok(uploaded_file)
+    }
+}
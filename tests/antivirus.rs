@@ -0,0 +1,137 @@
+mod common;
+
+use bytes::Bytes;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+use rust_rest_api::antivirus::{self, AntivirusConfig, AntivirusError};
+use rust_rest_api::error::ApiError;
+use rust_rest_api::{upload_image, Config, UploadOptions};
+
+use common::ScratchDir;
+
+const EICAR: &[u8] = b"X5O!P%@AP[4\\PZX54(P^)7CC)7}$EICAR-STANDARD-ANTIVIRUS-TEST-FILE!$H+H*";
+
+// Answers INSTREAM like clamd, finding EICAR and choking on "broken"
+async fn fake_clamd() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    actix_rt::spawn(async move {
+        loop {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut command = [0; 10];
+            conn.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+            let mut data = Vec::new();
+            loop {
+                let len = conn.read_u32().await.unwrap() as usize;
+                if len == 0 {
+                    break;
+                }
+                let mut chunk = vec![0; len];
+                conn.read_exact(&mut chunk).await.unwrap();
+                data.extend(chunk);
+            }
+            let reply: &[u8] = if data.windows(5).any(|window| window == b"EICAR") {
+                b"stream: Eicar-Signature FOUND\0"
+            } else if data.starts_with(b"broken") {
+                b"INSTREAM size limit exceeded. ERROR\0"
+            } else {
+                b"stream: OK\0"
+            };
+            conn.write_all(reply).await.unwrap();
+        }
+    });
+    format!("tcp://{}", address)
+}
+
+// Under `dir`, shard directories aside
+fn files(dir: &std::path::Path) -> usize {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .map(|path| if path.is_dir() { files(&path) } else { 1 })
+        .sum()
+}
+
+#[test]
+fn parses_addresses_and_replies() {
+    assert_eq!(
+        antivirus::Address::parse("tcp://clamav:3310"),
+        Some(antivirus::Address::Tcp("clamav:3310".to_owned()))
+    );
+    assert!(antivirus::Address::parse("unix:/run/clamav/clamd.ctl").is_some());
+    assert_eq!(antivirus::Address::parse("clamav:3310"), None);
+
+    assert_eq!(antivirus::parse_reply("stream: OK\0"), Ok(None));
+    assert_eq!(
+        antivirus::parse_reply("stream: Win.Test.EICAR_HDB-1 FOUND\0"),
+        Ok(Some("Win.Test.EICAR_HDB-1".to_owned()))
+    );
+    assert!(antivirus::parse_reply("INSTREAM size limit exceeded. ERROR\0").is_err());
+}
+
+#[actix_rt::test]
+async fn scans_with_clamd() {
+    let dir = ScratchDir::new("antivirus");
+    let mut config = AntivirusConfig {
+        clamd: fake_clamd().await,
+        // Several chunks per file
+        chunk_size: 16,
+        ..Default::default()
+    };
+    let (clean, infected, broken) = (dir.join("clean.txt"), dir.join("eicar.com"), dir.join("broken.txt"));
+    std::fs::write(&clean, b"nothing to see here, move along").unwrap();
+    std::fs::write(&infected, EICAR).unwrap();
+    std::fs::write(&broken, b"broken").unwrap();
+
+    antivirus::check(&config, &clean).await.unwrap();
+    match antivirus::check(&config, &infected).await {
+        Err(AntivirusError::Infected(signature)) => assert_eq!(signature, "Eicar-Signature"),
+        other => panic!("{:?}", other),
+    }
+    assert!(matches!(
+        antivirus::check(&config, &broken).await,
+        Err(AntivirusError::Unavailable)
+    ));
+
+    // Fail-open lets through what couldn't be scanned, never what was found
+    config.fail_open = true;
+    antivirus::check(&config, &broken).await.unwrap();
+    assert!(antivirus::check(&config, &infected).await.is_err());
+
+    let unreachable = AntivirusConfig {
+        clamd: "tcp://127.0.0.1:1".to_owned(),
+        ..Default::default()
+    };
+    assert!(matches!(
+        antivirus::check(&unreachable, &clean).await,
+        Err(AntivirusError::Unavailable)
+    ));
+    antivirus::check(
+        &AntivirusConfig {
+            fail_open: true,
+            ..unreachable
+        },
+        &clean,
+    )
+    .await
+    .unwrap();
+}
+
+#[actix_rt::test]
+async fn infected_uploads_are_deleted() {
+    let dir = ScratchDir::new("antivirus-upload");
+    let mut options = UploadOptions::from_config(&Config::default());
+    options.antivirus.clamd = fake_clamd().await;
+
+    let stream = futures_util::stream::iter(vec![Ok::<_, std::io::Error>(Bytes::from_static(EICAR))]);
+    let err = match upload_image(stream, &*dir, "png", &options).await {
+        Ok(uploaded) => panic!("stored as {}", uploaded.id),
+        Err(err) => err,
+    };
+    let error = ApiError::from(&err);
+    assert_eq!((error.status.as_u16(), error.code), (422, "infected"));
+    assert_eq!(error.body()["signature"], "Eicar-Signature");
+    assert_eq!(files(&dir), 0);
+}
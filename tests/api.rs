@@ -0,0 +1,509 @@
+// End-to-end tests against the real binary: each test starts a server on a
+// free port with its own uploads directory and talks HTTP to it. The service
+// keeps everything on the local filesystem, so no other containers or
+// services are needed.
+
+mod common;
+
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+
+use common::{png, ScratchDir};
+
+const BOUNDARY: &str = "rr-api-test-boundary";
+
+struct Server {
+    child: Child,
+    // Removed once the child is gone
+    _dir: ScratchDir,
+    base: String,
+    client: Client,
+}
+
+impl Server {
+    // `extra_config` is appended to the generated TOML
+    async fn start(extra_config: &str) -> Server {
+        let dir = ScratchDir::new("api");
+        let uploads_dir = dir.join("uploads");
+        std::fs::create_dir_all(&uploads_dir).unwrap();
+
+        // Freed right away for the server to take; good enough for tests
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let config_path = dir.join("config.toml");
+        let config = format!(
+            "host = \"127.0.0.1\"\nport = {}\nuploads_dir = {:?}\norphan_cleanup_interval_secs = 0\nmin_free_disk_space = 0\n{}\n\n[fetch]\nallow_private_addresses = true\n\n[transform]\nenabled = true\nrequire_signature = false\n\n[lb_health]\nbusy_free_disk_space = 0\n",
+            port, uploads_dir, extra_config
+        );
+        std::fs::write(&config_path, config).unwrap();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_rust_rest_api"))
+            .env("RR_API_CONFIG", &config_path)
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let server = Server {
+            child,
+            _dir: dir,
+            base: format!("http://127.0.0.1:{}", port),
+            client: Client::new(),
+        };
+        server.wait_ready().await;
+        server
+    }
+
+    async fn wait_ready(&self) {
+        for _ in 0..100 {
+            if let Ok(response) = self.client.get(self.url("/healthz")).send().await {
+                if response.status().is_success() {
+                    return;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("server at {} didn't start", self.base);
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base, path)
+    }
+
+    async fn get(&self, path: &str) -> reqwest::Response {
+        self.client.get(self.url(path)).send().await.unwrap()
+    }
+
+    // Ids of the uploaded files
+    async fn upload(&self, request: reqwest::RequestBuilder) -> Vec<String> {
+        let response = request.send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let ids: Vec<String> = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        assert!(!ids.is_empty());
+        ids
+    }
+
+    async fn upload_multipart(&self, files: &[(&str, &str, &[u8])]) -> reqwest::Response {
+        let mut body = Vec::new();
+        for (filename, content_type, data) in files {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+                    BOUNDARY, filename, content_type
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+
+        self.client
+            .post(self.url("/upload"))
+            .header("Content-Type", format!("multipart/form-data; boundary={}", BOUNDARY))
+            .body(body)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    async fn upload_json(&self, items: Value) -> Vec<String> {
+        self.upload(
+            self.client
+                .post(self.url("/upload"))
+                .header("Content-Type", "application/json")
+                .body(items.to_string()),
+        )
+        .await
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn png_size(data: &[u8]) -> (u32, u32) {
+    assert!(data.starts_with(b"\x89PNG"), "not a PNG");
+    (
+        u32::from_be_bytes([data[16], data[17], data[18], data[19]]),
+        u32::from_be_bytes([data[20], data[21], data[22], data[23]]),
+    )
+}
+
+fn content_type(response: &reqwest::Response) -> String {
+    response
+        .headers()
+        .get("Content-Type")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .to_owned()
+}
+
+#[actix_rt::test]
+async fn multipart_upload_serve_and_delete() {
+    let server = Server::start("").await;
+    let image = png(64, 48);
+
+    let response = server.upload_multipart(&[("a.png", "image/png", &image), ("b.png", "image/png", &image)]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let ids: Vec<String> = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+    assert_eq!(ids.len(), 2);
+
+    let response = server.get(&format!("/images/{}", ids[0])).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(content_type(&response), "image/png");
+    assert_eq!(&response.bytes().await.unwrap()[..], &image[..]);
+
+    let response = server.get(&format!("/images/{}/thumbnail", ids[0])).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(png_size(&response.bytes().await.unwrap()), (100, 100));
+
+    let response = server.get(&format!("/images/{}/metadata", ids[0])).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let metadata: Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+    assert_eq!(metadata["width"], 64);
+    assert_eq!(metadata["height"], 48);
+    assert_eq!(metadata["original_filename"], "a.png");
+
+    let response = server
+        .client
+        .delete(server.url(&format!("/images/{}", ids[0])))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(server.get(&format!("/images/{}", ids[0])).await.status(), StatusCode::NOT_FOUND);
+    assert_eq!(server.get(&format!("/images/{}/thumbnail", ids[0])).await.status(), StatusCode::NOT_FOUND);
+
+    // The other upload is untouched
+    assert_eq!(server.get(&format!("/images/{}", ids[1])).await.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn base64_and_url_uploads() {
+    let server = Server::start("").await;
+    let image = png(32, 32);
+
+    let ids = server.upload_json(json!([{ "base64": base64::encode(&image) }])).await;
+    let response = server.get(&format!("/images/{}", ids[0])).await;
+    assert_eq!(&response.bytes().await.unwrap()[..], &image[..]);
+
+    // Fetched back from the server itself
+    let url = server.url(&format!("/images/{}", ids[0]));
+    let copies = server.upload_json(json!([{ "url": url }])).await;
+    assert_ne!(copies[0], ids[0]);
+    let response = server.get(&format!("/images/{}", copies[0])).await;
+    assert_eq!(&response.bytes().await.unwrap()[..], &image[..]);
+}
+
+#[actix_rt::test]
+async fn raw_upload() {
+    let server = Server::start("").await;
+    let image = png(16, 8);
+
+    let ids = server
+        .upload(
+            server
+                .client
+                .post(server.url("/upload"))
+                .header("Content-Type", "image/png")
+                .body(image.clone()),
+        )
+        .await;
+    let response = server.get(&format!("/images/{}", ids[0])).await;
+    assert_eq!(&response.bytes().await.unwrap()[..], &image[..]);
+}
+
+#[actix_rt::test]
+async fn transforms() {
+    let server = Server::start("").await;
+    let ids = server.upload_json(json!([{ "base64": base64::encode(png(64, 48)) }])).await;
+
+    for (query, size) in &[
+        ("w=32&h=32&fit=cover", (32, 32)),
+        ("w=32&h=32&fit=contain", (32, 24)),
+        ("w=20&h=10&fit=fill", (20, 10)),
+    ] {
+        let response = server.get(&format!("/images/{}/transform?{}", ids[0], query)).await;
+        assert_eq!(response.status(), StatusCode::OK, "{}", query);
+        assert_eq!(content_type(&response), "image/png");
+        assert_eq!(png_size(&response.bytes().await.unwrap()), *size, "{}", query);
+    }
+
+    let response = server.get(&format!("/images/{}/transform?w=0", ids[0])).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_rt::test]
+async fn rejects_what_isnt_an_image() {
+    let server = Server::start("").await;
+
+    let response = server.upload_multipart(&[("a.txt", "text/plain", b"hello")]).await;
+    assert!(response.status().is_client_error(), "{}", response.status());
+
+    let response = server
+        .client
+        .post(server.url("/upload"))
+        .header("Content-Type", "application/json")
+        .body(json!([{ "base64": base64::encode(b"not an image") }]).to_string())
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_client_error(), "{}", response.status());
+}
+
+#[actix_rt::test]
+async fn api_keys() {
+    let server = Server::start(
+        "[[api_keys]]\nkey = \"uploader-key\"\nname = \"uploader\"\n\n[[api_keys]]\nkey = \"admin-key\"\nname = \"admin\"\nadmin = true\n",
+    )
+    .await;
+    let body = json!([{ "base64": base64::encode(png(8, 8)) }]).to_string();
+
+    let response = server
+        .client
+        .post(server.url("/upload"))
+        .header("Content-Type", "application/json")
+        .body(body.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let ids = server
+        .upload(
+            server
+                .client
+                .post(server.url("/upload"))
+                .header("Content-Type", "application/json")
+                .bearer_auth("uploader-key")
+                .body(body),
+        )
+        .await;
+
+    let delete = |key: &'static str| {
+        server
+            .client
+            .delete(server.url(&format!("/images/{}", ids[0])))
+            .bearer_auth(key)
+            .send()
+    };
+    assert_eq!(delete("uploader-key").await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(delete("admin-key").await.unwrap().status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn expiry_with_time_travel() {
+    let server = Server::start("time_travel = true").await;
+
+    let ids = server
+        .upload(
+            server
+                .client
+                .post(server.url("/upload?ttl=3600"))
+                .header("Content-Type", "image/png")
+                .body(png(8, 8)),
+        )
+        .await;
+    let kept = server.upload_json(json!([{ "base64": base64::encode(png(8, 8)) }])).await;
+
+    let response = server.client.post(server.url("/clock/advance?secs=1800")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(server.get(&format!("/images/{}", ids[0])).await.status(), StatusCode::OK);
+
+    let response = server.client.post(server.url("/clock/advance?secs=1800")).send().await.unwrap();
+    let clock: Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+    assert_eq!(clock["offset_secs"], 3600);
+    assert_eq!(clock["reaped"], 1);
+    assert_eq!(server.get(&format!("/images/{}", ids[0])).await.status(), StatusCode::NOT_FOUND);
+    assert_eq!(server.get(&format!("/images/{}", kept[0])).await.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn service_endpoints() {
+    let server = Server::start("").await;
+
+    assert_eq!(server.get("/readyz").await.status(), StatusCode::OK);
+    assert_eq!(server.get("/lb-health").await.status(), StatusCode::OK);
+
+    let response = server.get("/capabilities").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let capabilities: Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+    assert!(capabilities["input_formats"]
+        .as_array()
+        .unwrap()
+        .contains(&json!("image/png")));
+    assert_eq!(capabilities["encoders"]["png"], true);
+}
+
+#[actix_rt::test]
+async fn head_and_options() {
+    let server = Server::start("\n[cors]\nallowed_origins = [\"https://app.example\"]\n").await;
+    let image = png(16, 8);
+    let ids = server.upload_json(json!([{ "base64": base64::encode(&image) }])).await;
+    let path = format!("/images/{}", ids[0]);
+
+    let response = server.client.head(server.url(&path)).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["Content-Length"], image.len().to_string().as_str());
+    assert_eq!(content_type(&response), "image/png");
+    assert!(response.bytes().await.unwrap().is_empty());
+    let response = server.client.head(server.url("/healthz")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = server.client.request(reqwest::Method::OPTIONS, server.url(&path)).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(response.headers()["Allow"], "GET, HEAD, DELETE, OPTIONS");
+    assert!(response.headers().get("Access-Control-Allow-Origin").is_none());
+    let response = server.client.request(reqwest::Method::OPTIONS, server.url("/upload")).send().await.unwrap();
+    assert_eq!(response.headers()["Allow"], "POST, OPTIONS");
+
+    // tus discovery still answers a plain OPTIONS
+    let response = server.client.request(reqwest::Method::OPTIONS, server.url("/upload/tus")).send().await.unwrap();
+    assert!(response.headers().contains_key("Tus-Version"));
+
+    let response = server.client.put(server.url(&path)).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(response.headers()["Allow"], "GET, HEAD, DELETE, OPTIONS");
+
+    let response = server
+        .client
+        .request(reqwest::Method::OPTIONS, server.url("/upload"))
+        .header("Origin", "https://app.example")
+        .header("Access-Control-Request-Method", "POST")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(response.headers()["Access-Control-Allow-Origin"], "https://app.example");
+    assert_eq!(response.headers()["Access-Control-Allow-Methods"], "POST, OPTIONS");
+    assert!(response.headers().contains_key("Access-Control-Allow-Headers"));
+
+    let response = server.client.get(server.url(&path)).header("Origin", "https://app.example").send().await.unwrap();
+    assert_eq!(response.headers()["Access-Control-Allow-Origin"], "https://app.example");
+    assert!(response.headers().contains_key("Access-Control-Expose-Headers"));
+    let response = server.client.get(server.url(&path)).header("Origin", "https://evil.example").send().await.unwrap();
+    assert!(response.headers().get("Access-Control-Allow-Origin").is_none());
+}
+
+#[actix_rt::test]
+async fn cors_methods_and_credentials() {
+    let server = Server::start(
+        "\n[cors]\nallowed_origins = [\"*\"]\nallowed_methods = [\"GET\", \"POST\"]\nallow_credentials = true\n",
+    )
+    .await;
+
+    let response = server
+        .client
+        .request(reqwest::Method::OPTIONS, server.url("/images/abc"))
+        .header("Origin", "https://app.example")
+        .header("Access-Control-Request-Method", "GET")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["Access-Control-Allow-Origin"], "https://app.example");
+    assert_eq!(response.headers()["Access-Control-Allow-Methods"], "GET");
+    assert_eq!(response.headers()["Access-Control-Allow-Credentials"], "true");
+
+    let response = server
+        .client
+        .get(server.url("/capabilities"))
+        .header("Origin", "https://app.example")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["Access-Control-Allow-Origin"], "https://app.example");
+    let response = server
+        .client
+        .delete(server.url("/images/abc"))
+        .header("Origin", "https://app.example")
+        .send()
+        .await
+        .unwrap();
+    assert!(response.headers().get("Access-Control-Allow-Origin").is_none());
+}
+
+#[actix_rt::test]
+async fn image_info() {
+    let server = Server::start("").await;
+    let image = png(64, 48);
+    let ids = server.upload_json(json!([{ "base64": base64::encode(&image) }])).await;
+    // Thumbnails are made in the background, this one waits for it
+    assert_eq!(server.get(&format!("/images/{}/thumbnail", ids[0])).await.status(), StatusCode::OK);
+
+    let response = server.get(&format!("/images/{}/info", ids[0])).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let info: Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+    assert_eq!(info["format"], "png");
+    assert_eq!(info["mime"], "image/png");
+    assert_eq!((info["width"].clone(), info["height"].clone()), (json!(64), json!(48)));
+    assert_eq!((info["color_type"].clone(), info["bit_depth"].clone()), (json!("rgb"), json!(8)));
+    assert_eq!(info["size"], image.len());
+
+    let response = server.get(&format!("/images/{}/metadata", ids[0])).await;
+    let image_metadata: Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+    assert_eq!(info["checksum"], image_metadata["checksum"]);
+
+    let thumbnail = &info["variants"].as_array().unwrap()[0];
+    assert_eq!(thumbnail["kind"], "thumbnail");
+    assert_eq!(thumbnail["width"], 100);
+
+    assert_eq!(server.get("/images/missing/info").await.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn upload_negotiation_errors() {
+    let server = Server::start("").await;
+
+    let response = server
+        .client
+        .post(server.url("/upload"))
+        .header("Content-Type", "text/plain")
+        .body("hello")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert!(response.headers()["Accept-Post"].to_str().unwrap().contains("multipart/form-data"));
+    let error: Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+    assert!(error["supported_content_types"].as_array().unwrap().contains(&json!("image/png")));
+
+    let response = server.get("/upload").await;
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(response.headers()["Allow"], "POST, OPTIONS");
+    let error: Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+    assert_eq!(error["allowed_methods"], json!(["POST", "OPTIONS"]));
+
+    let metrics = server.get("/metrics").await.text().await.unwrap();
+    assert!(metrics.contains("rr_api_unmatched_uploads_total{reason=\"method\"} 1"));
+    assert!(metrics.contains("rr_api_unmatched_uploads_total{reason=\"content_type\"} 1"));
+}
+
+#[actix_rt::test]
+async fn openapi_spec() {
+    let server = Server::start("").await;
+
+    let response = server.get("/openapi.json").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let spec: Value = serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+    assert!(spec["openapi"].as_str().unwrap().starts_with("3."));
+    for (path, method) in &[("/upload", "post"), ("/images/{id}", "get"), ("/images/{id}", "delete")] {
+        assert!(spec["paths"][path][method].is_object(), "{} {}", method, path);
+    }
+    assert!(spec["components"]["schemas"]["ImageInfo"].is_object());
+
+    let response = server.get("/docs").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let csp = response.headers()["Content-Security-Policy"].to_str().unwrap().to_owned();
+    assert!(csp.contains("script-src 'self' https://unpkg.com"));
+    assert!(response.text().await.unwrap().contains("/docs/init.js"));
+
+    let disabled = Server::start("\n[openapi]\nenabled = false\n").await;
+    assert_eq!(disabled.get("/openapi.json").await.status(), StatusCode::NOT_FOUND);
+}
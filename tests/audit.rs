@@ -0,0 +1,119 @@
+mod common;
+
+use actix_web::{test, App};
+
+use rust_rest_api::audit::{self, AuditEvent, AuditFilter};
+use rust_rest_api::auth::ApiKey;
+use rust_rest_api::{http, Config};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+fn api_key(value: serde_json::Value) -> ApiKey {
+    serde_json::from_value(value).unwrap()
+}
+
+fn request(request: test::TestRequest, key: &str) -> test::TestRequest {
+    request.insert_header(("Authorization", format!("Bearer {}", key)))
+}
+
+#[actix_rt::test]
+async fn writes_are_recorded() {
+    let dir = ScratchDir::new("audit_http");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        api_keys: vec![
+            api_key(serde_json::json!({"key": "ops-secret", "name": "ops", "admin": true})),
+            api_key(serde_json::json!({"key": "app-secret", "name": "app"})),
+        ],
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let upload = test::TestRequest::post()
+        .uri("/upload?details=true")
+        .peer_addr("10.0.0.7:4000".parse().unwrap())
+        .insert_header(("Content-Type", "image/svg+xml"))
+        .set_payload(SVG);
+    let response = test::call_service(&app, request(upload, "app-secret").to_request()).await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let id = body[0]["id"].as_str().unwrap().to_owned();
+    let image = format!("/images/{}", id);
+
+    let delete = |key: &str| request(test::TestRequest::delete().uri(&image), key).to_request();
+    assert_eq!(test::call_service(&app, delete("app-secret")).await.status(), 401);
+    assert_eq!(test::call_service(&app, delete("ops-secret")).await.status(), 200);
+    // Reads aren't
+    let read = test::TestRequest::get().uri(&image).to_request();
+    assert_eq!(test::call_service(&app, read).await.status(), 404);
+
+    let log = |key: &str, query: &str| {
+        request(test::TestRequest::get().uri(&format!("/admin/audit{}", query)), key).to_request()
+    };
+    assert_eq!(test::call_service(&app, log("app-secret", "")).await.status(), 401);
+    let response = test::call_service(&app, log("ops-secret", "")).await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let events: Vec<AuditEvent> = serde_json::from_value(body["events"].clone()).unwrap();
+    let summary: Vec<_> = events
+        .iter()
+        .map(|event| (event.actor.as_str(), event.method.as_str(), event.status))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("key:ops", "DELETE", 200),
+            ("key:app", "DELETE", 401),
+            ("key:app", "POST", 200)
+        ]
+    );
+    assert_eq!(events[0].path, image);
+    assert_eq!(events[0].route.as_deref(), Some("/images/{id}"));
+    assert_eq!(events[2].path, "/upload");
+    assert_eq!(events[2].ip.as_deref(), Some("10.0.0.7"));
+
+    let body: serde_json::Value =
+        test::read_body_json(test::call_service(&app, log("ops-secret", "?actor=key:app&limit=1")).await).await;
+    assert_eq!(body["events"].as_array().unwrap().len(), 1);
+    assert_eq!(body["events"][0]["method"], "DELETE");
+    let body: serde_json::Value =
+        test::read_body_json(test::call_service(&app, log("ops-secret", "?method=post")).await).await;
+    assert_eq!(body["events"].as_array().unwrap().len(), 1);
+}
+
+#[actix_rt::test]
+async fn events_are_kept_by_day() {
+    let dir = ScratchDir::new("audit_days");
+    let event = |at: u64, method: &str| AuditEvent {
+        at,
+        actor: "key:ops".to_owned(),
+        method: method.to_owned(),
+        path: "/clock/advance".to_owned(),
+        route: None,
+        ip: None,
+        status: 200,
+    };
+    // A leap day, the last second of the year and the next
+    for (at, method) in &[(951_782_400, "POST"), (978_307_199, "PUT"), (978_307_200, "DELETE")] {
+        audit::record(&*dir, &event(*at, method)).await.unwrap();
+    }
+    let audit_dir = dir.join("meta/audit");
+    for name in &["2000-02-29.jsonl", "2000-12-31.jsonl", "2001-01-01.jsonl"] {
+        assert!(audit_dir.join(name).is_file(), "{}", name);
+    }
+
+    let filter = AuditFilter {
+        since: Some(951_782_400),
+        until: Some(978_307_199),
+        ..Default::default()
+    };
+    let events = audit::query(&*dir, &filter, 10).await.unwrap();
+    assert_eq!(events, vec![event(978_307_199, "PUT"), event(951_782_400, "POST")]);
+    assert_eq!(audit::query(&*dir, &Default::default(), 10).await.unwrap().len(), 3);
+}
@@ -0,0 +1,79 @@
+mod common;
+
+use rust_rest_api::blocklist::{self, BlockAction, BlockEntry, BlocklistConfig, BlocklistError, EntrySource, HashKind};
+use rust_rest_api::metadata::{ImageMetadata, UploadState};
+
+use common::ScratchDir;
+
+const SHA: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+fn image_metadata(id: &str, checksum: &str, phash: &str) -> ImageMetadata {
+    serde_json::from_value(serde_json::json!({
+        "id": id,
+        "extension": "png",
+        "size": 1,
+        "width": 1,
+        "height": 1,
+        "created_at": 0,
+        "checksum": checksum,
+        "phash": phash,
+    }))
+    .unwrap()
+}
+
+// The entries are process-wide, so one test walks through it all
+#[actix_rt::test]
+async fn entries_and_matches() {
+    let dir = ScratchDir::new("blocklist");
+    let file = dir.join("blocked.txt");
+    std::fs::write(
+        &file,
+        format!(
+            "# takedowns\nsha256 {} notice 1\n\nphash 00000000000000ff notice 2\n",
+            SHA
+        ),
+    )
+    .unwrap();
+    let mut config = BlocklistConfig {
+        file: Some(file.clone()),
+        ..Default::default()
+    };
+    assert_eq!(blocklist::load(&config, &*dir).await.unwrap(), 2);
+
+    // Exact checksums, perceptual hashes within `max_distance` bits
+    let found = blocklist::find_match(&config, Some(SHA), None).unwrap();
+    assert_eq!((found.kind, found.reason.as_str()), (HashKind::Sha256, "notice 1"));
+    assert!(blocklist::find_match(&config, None, Some(0xf0)).is_some());
+    assert!(blocklist::find_match(&config, None, Some(0xf00)).is_none());
+    assert!(blocklist::find_match(&config, Some(&SHA.replace('9', "0")), None).is_none());
+
+    let mut rejected = image_metadata("a", SHA, "ffff000000000000");
+    assert!(matches!(
+        blocklist::apply(&config, &*dir, &mut rejected),
+        Err(BlocklistError::Blocked(HashKind::Sha256, _))
+    ));
+
+    config.action = BlockAction::Quarantine;
+    let mut quarantined = image_metadata("b", SHA, "ffff000000000000");
+    blocklist::apply(&config, &*dir, &mut quarantined).unwrap();
+    assert_eq!(quarantined.status, UploadState::Quarantined);
+    assert_eq!(quarantined.quarantine.unwrap().reason, "notice 1");
+    let audit = std::fs::read_to_string(dir.join("blocklist/audit.jsonl")).unwrap();
+    assert_eq!(audit.lines().count(), 2);
+
+    // API entries survive a reload, the file can't be removed over the API
+    assert!(BlockEntry::new(HashKind::Phash, "xyz", "", EntrySource::Api).is_err());
+    let entry = BlockEntry::new(HashKind::Phash, "FFFF000000000000", "notice 3", EntrySource::Api).unwrap();
+    blocklist::add(&*dir, entry).await.unwrap();
+    assert_eq!(blocklist::load(&config, &*dir).await.unwrap(), 3);
+    let found = blocklist::find_match(&config, None, Some(0xffff_0000_0000_0001)).unwrap();
+    assert_eq!(found.reason, "notice 3");
+    assert!(!blocklist::remove(&*dir, HashKind::Sha256, SHA).await.unwrap());
+    assert!(blocklist::remove(&*dir, HashKind::Phash, "ffff000000000000")
+        .await
+        .unwrap());
+    assert_eq!(blocklist::list().len(), 2);
+
+    std::fs::write(&file, "md5 abc\n").unwrap();
+    assert!(blocklist::load(&config, &*dir).await.is_err());
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use actix_web::{test, App};
+use bytes::Bytes;
+use rust_rest_api::{http, stream_to_writer, ByteLimit, Config, UploadError};
+
+use common::ScratchDir;
+
+fn chunks(sizes: &[usize]) -> impl futures_util::stream::Stream<Item = Result<Bytes, std::io::Error>> + Unpin {
+    let chunks: Vec<_> = sizes.iter().map(|&size| Ok(Bytes::from(vec![0; size]))).collect();
+    futures_util::stream::iter(chunks)
+}
+
+fn too_large(res: anyhow::Result<()>) -> Option<u64> {
+    match res.unwrap_err().downcast_ref() {
+        Some(UploadError::BodyTooLarge(limit)) => Some(*limit),
+        _ => None,
+    }
+}
+
+#[actix_rt::test]
+async fn per_file_limit() {
+    let limit = ByteLimit::new(100, 0);
+    let mut out = Vec::new();
+    stream_to_writer(chunks(&[60, 40]), &mut out, Some(&limit)).await.unwrap();
+    assert_eq!(out.len(), 100);
+
+    // The limit is per file, the next one starts over
+    let res = stream_to_writer(chunks(&[60, 41]), Vec::new(), Some(&limit)).await;
+    assert_eq!(too_large(res), Some(100));
+}
+
+#[actix_rt::test]
+async fn per_request_limit_is_shared() {
+    let limit = ByteLimit::new(0, 150);
+    // Each file of a request gets a clone
+    let first_file = limit.clone();
+    stream_to_writer(chunks(&[100]), Vec::new(), Some(&first_file)).await.unwrap();
+
+    let res = stream_to_writer(chunks(&[30, 30]), Vec::new(), Some(&limit)).await;
+    assert_eq!(too_large(res), Some(150));
+}
+
+#[actix_rt::test]
+async fn fresh_limits_start_over() {
+    let limit = ByteLimit::new(100, 150);
+    stream_to_writer(chunks(&[100]), Vec::new(), Some(&limit)).await.unwrap();
+    assert!(limit.check(100).is_err());
+
+    let fresh = limit.fresh();
+    assert!(fresh.check(100).is_ok());
+    assert!(fresh.check(101).is_err());
+    stream_to_writer(chunks(&[100]), Vec::new(), Some(&fresh)).await.unwrap();
+}
+
+// Tus uploads are held to the file size limit from their declared length
+#[actix_rt::test]
+async fn tus_uploads_over_the_file_limit_are_refused() {
+    let dir = ScratchDir::new("byte_limit_tus");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        max_file_size: 100,
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let create = |length: u64| {
+        test::TestRequest::post()
+            .uri("/upload/tus")
+            .insert_header(("Tus-Resumable", "1.0.0"))
+            .insert_header(("Upload-Length", length.to_string()))
+            .to_request()
+    };
+    assert_eq!(test::call_service(&app, create(101)).await.status(), 413);
+    assert_eq!(test::call_service(&app, create(100)).await.status(), 201);
+}
+
+#[actix_rt::test]
+async fn unlimited() {
+    let res = stream_to_writer(chunks(&[1 << 20, 1 << 20]), Vec::new(), None).await;
+    assert!(res.is_ok());
+    let res = stream_to_writer(chunks(&[1 << 20]), Vec::new(), Some(&ByteLimit::new(0, 0))).await;
+    assert!(res.is_ok());
+}
@@ -0,0 +1,96 @@
+mod common;
+
+use actix_web::{test, App};
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+
+use rust_rest_api::error::ApiError;
+use rust_rest_api::{http, parse_sha256, upload_image, Config, UploadOptions};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+fn sha256(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn stream(data: &[u8]) -> impl futures_util::Stream<Item = std::io::Result<Bytes>> + Unpin {
+    // In pieces, as a request body comes
+    let chunks: Vec<_> = data.chunks(7).map(|chunk| Ok(Bytes::copy_from_slice(chunk))).collect();
+    futures_util::stream::iter(chunks)
+}
+
+// Under `dir`, shard directories aside
+fn files(dir: &std::path::Path) -> usize {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .map(|path| if path.is_dir() { files(&path) } else { 1 })
+        .sum()
+}
+
+#[actix_rt::test]
+async fn expected_digests_are_hex() {
+    let digest = sha256(SVG);
+    assert_eq!(parse_sha256(&digest.to_uppercase()), Some(digest.clone()));
+    assert_eq!(parse_sha256(&format!(" {}\n", digest)), Some(digest.clone()));
+    assert_eq!(parse_sha256(&digest[1..]), None);
+    assert_eq!(parse_sha256(&digest.replace(&digest[..1], "g")), None);
+}
+
+#[actix_rt::test]
+async fn digest_is_checked_while_streaming() {
+    let dir = ScratchDir::new("checksum_upload");
+    let mut options = UploadOptions::from_config(&Config::default());
+
+    options.expected_sha256 = Some(sha256(b"something else"));
+    let err = upload_image(stream(SVG), &*dir, "svg", &options).await.err().unwrap();
+    let error = ApiError::from(&err);
+    assert_eq!((error.status.as_u16(), error.code), (422, "checksum_mismatch"));
+    assert_eq!(error.body()["expected"], sha256(b"something else"));
+    assert_eq!(error.body()["actual"], sha256(SVG));
+    // Nothing is kept of a mismatch
+    assert_eq!(files(&dir), 0);
+
+    options.expected_sha256 = Some(sha256(SVG));
+    let uploaded = upload_image(stream(SVG), &*dir, "svg", &options).await.unwrap();
+    assert_eq!(uploaded.sha256, sha256(SVG));
+}
+
+#[actix_rt::test]
+async fn header_is_checked_and_answered() {
+    let dir = ScratchDir::new("checksum_http");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+    let upload = |sha256: &str| {
+        test::TestRequest::post()
+            .uri("/upload?details=true")
+            .insert_header(("Content-Type", "image/svg+xml"))
+            .insert_header(("X-Content-SHA256", sha256))
+            .set_payload(SVG)
+            .to_request()
+    };
+
+    let response = test::call_service(&app, upload("not-a-digest")).await;
+    assert_eq!(response.status(), 400);
+
+    let response = test::call_service(&app, upload(&sha256(b"something else"))).await;
+    assert_eq!(response.status(), 422);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body["code"], "checksum_mismatch");
+    assert_eq!(body["actual"], sha256(SVG));
+
+    let response = test::call_service(&app, upload(&sha256(SVG).to_uppercase())).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("x-content-sha256").unwrap(), sha256(SVG).as_str());
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body[0]["sha256"], sha256(SVG));
+}
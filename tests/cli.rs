@@ -0,0 +1,48 @@
+// The maintenance subcommands of the binary
+mod common;
+
+use std::process::Command;
+
+use common::ScratchDir;
+
+fn rr_api(dir: &ScratchDir, args: &[&str]) -> String {
+    let config_path = dir.join("config.toml");
+    std::fs::write(&config_path, format!("uploads_dir = {:?}\n", dir.join("uploads"))).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rust_rest_api"))
+        .arg("--config")
+        .arg(&config_path)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn gen_key_prints_hex() {
+    let dir = ScratchDir::new("cli");
+    let key = rr_api(&dir, &["gen-key"]);
+    assert_eq!(key.trim().len(), 64);
+    assert!(key.trim().chars().all(|c| c.is_ascii_hexdigit()));
+    assert_ne!(key, rr_api(&dir, &["gen-key", "--bytes", "32"]));
+}
+
+#[test]
+fn migrate_and_cleanup() {
+    let dir = ScratchDir::new("cli");
+    let uploads_dir = dir.join("uploads");
+    std::fs::create_dir_all(&uploads_dir).unwrap();
+    std::fs::write(uploads_dir.join("abcdef.png"), b"png").unwrap();
+    std::fs::write(uploads_dir.join("abcdef.png.tmp"), b"partial").unwrap();
+
+    assert_eq!(rr_api(&dir, &["migrate"]).trim(), "Moved 1 file(s) into shards");
+    assert!(!uploads_dir.join("abcdef.png").exists());
+    assert_eq!(rr_api(&dir, &["migrate"]).trim(), "Moved 0 file(s) into shards");
+
+    assert_eq!(
+        rr_api(&dir, &["cleanup", "--max-age-secs", "0"]).trim(),
+        "Removed 1 temp file(s) and 0 thumbnail(s)"
+    );
+    assert!(!uploads_dir.join("abcdef.png.tmp").exists());
+}
@@ -0,0 +1,251 @@
+// Cloud storage backends against local servers speaking just enough of
+// the GCS JSON API and the Azure Blob REST API
+mod common;
+
+#[cfg(any(feature = "storage-gcs", feature = "storage-azure"))]
+use std::collections::HashMap;
+#[cfg(any(feature = "storage-gcs", feature = "storage-azure"))]
+use std::sync::{Arc, Mutex};
+
+#[cfg(any(feature = "storage-gcs", feature = "storage-azure"))]
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+
+use rust_rest_api::storage::{self, StorageConfig};
+#[cfg(feature = "storage-azure")]
+use rust_rest_api::storage::AzureConfig;
+#[cfg(feature = "storage-gcs")]
+use rust_rest_api::storage::GcsConfig;
+#[cfg(any(feature = "storage-gcs", feature = "storage-azure"))]
+use rust_rest_api::storage::{Storage, StorageError};
+
+#[cfg(any(feature = "storage-gcs", feature = "storage-azure"))]
+use common::ScratchDir;
+
+#[cfg(any(feature = "storage-gcs", feature = "storage-azure"))]
+#[derive(Default)]
+struct Bucket {
+    objects: HashMap<String, Vec<u8>>,
+    // GCS upload sessions or uncommitted Azure blocks
+    pending: HashMap<String, Vec<u8>>,
+    // Chunks or blocks received
+    parts: usize,
+}
+
+#[cfg(any(feature = "storage-gcs", feature = "storage-azure"))]
+type Shared = Arc<Mutex<Bucket>>;
+
+#[cfg(any(feature = "storage-gcs", feature = "storage-azure"))]
+fn serve<F>(handler: F) -> (String, Shared)
+where
+    F: Fn(&HttpRequest, &[u8], &mut Bucket) -> HttpResponse + Clone + Send + 'static,
+{
+    let bucket = Shared::default();
+    let state = bucket.clone();
+    let server = HttpServer::new(move || {
+        let (state, handler) = (state.clone(), handler.clone());
+        App::new().default_service(web::to(move |req: HttpRequest, body: web::Bytes| {
+            let response = handler(&req, &body, &mut state.lock().unwrap());
+            async move { response }
+        }))
+    })
+    .workers(1)
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let base = format!("http://{}", server.addrs()[0]);
+    actix_rt::spawn(server.run());
+    (base, bucket)
+}
+
+#[cfg(any(feature = "storage-gcs", feature = "storage-azure"))]
+fn query(req: &HttpRequest) -> HashMap<String, String> {
+    web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .unwrap()
+        .into_inner()
+}
+
+#[cfg(any(feature = "storage-gcs", feature = "storage-azure"))]
+fn header<'a>(req: &'a HttpRequest, name: &str) -> &'a str {
+    req.headers()
+        .get(name)
+        .map(|value| value.to_str().unwrap())
+        .unwrap_or("")
+}
+
+#[cfg(feature = "storage-gcs")]
+fn gcs(req: &HttpRequest, body: &[u8], bucket: &mut Bucket) -> HttpResponse {
+    let path = req.path().to_owned();
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    if let ["session", id] = segments[..] {
+        // bytes first-last/total, or bytes */total
+        let range = header(req, "content-range").trim_start_matches("bytes ").to_owned();
+        let (span, total) = range.split_once('/').unwrap();
+        let total: usize = total.parse().unwrap();
+        let received = bucket.pending.get_mut(id).unwrap();
+        if span != "*" {
+            let first: usize = span.split_once('-').unwrap().0.parse().unwrap();
+            assert_eq!(first, received.len());
+        }
+        received.extend_from_slice(body);
+        bucket.parts += 1;
+        if received.len() < total {
+            return HttpResponse::build(actix_web::http::StatusCode::PERMANENT_REDIRECT)
+                .insert_header(("Range", format!("bytes=0-{}", received.len() - 1)))
+                .finish();
+        }
+        let (name, received) = (
+            id.split_once(':').unwrap().1.to_owned(),
+            bucket.pending.remove(id).unwrap(),
+        );
+        bucket.objects.insert(name, received);
+        return HttpResponse::Ok().finish();
+    }
+
+    if header(req, "authorization") != "Bearer test-token" {
+        return HttpResponse::Unauthorized().body("no token");
+    }
+    match (req.method().as_str(), &segments[..]) {
+        ("POST", ["upload", "storage", "v1", "b", "media", "o"]) => {
+            let query = query(req);
+            assert_eq!(query["uploadType"], "resumable");
+            let id = format!("{}:{}", bucket.pending.len(), query["name"]);
+            bucket.pending.insert(id.clone(), Vec::new());
+            let location = format!("http://{}/session/{}", req.connection_info().host(), id);
+            HttpResponse::Ok().insert_header(("Location", location)).finish()
+        }
+        ("GET", ["storage", "v1", "b", "media", "o", name])
+            if query(req).get("alt").map(String::as_str) == Some("media") =>
+        {
+            match bucket.objects.get(*name) {
+                Some(object) => HttpResponse::Ok().body(object.clone()),
+                None => HttpResponse::NotFound().finish(),
+            }
+        }
+        ("DELETE", ["storage", "v1", "b", "media", "o", name]) => match bucket.objects.remove(*name) {
+            Some(_) => HttpResponse::NoContent().finish(),
+            None => HttpResponse::NotFound().finish(),
+        },
+        _ => HttpResponse::BadRequest().body(format!("unexpected {} {}", req.method(), path)),
+    }
+}
+
+#[cfg(feature = "storage-azure")]
+fn azure(req: &HttpRequest, body: &[u8], bucket: &mut Bucket) -> HttpResponse {
+    let query = query(req);
+    if query.get("sig").map(String::as_str) != Some("secret") || header(req, "x-ms-version").is_empty() {
+        return HttpResponse::Forbidden().finish();
+    }
+    let name = match req.path().strip_prefix("/media/") {
+        Some(name) => name.to_owned(),
+        None => return HttpResponse::NotFound().finish(),
+    };
+    match (req.method().as_str(), query.get("comp").map(String::as_str)) {
+        ("PUT", Some("block")) => {
+            bucket
+                .pending
+                .insert(format!("{}:{}", name, query["blockid"]), body.to_vec());
+            bucket.parts += 1;
+            HttpResponse::Created().finish()
+        }
+        ("PUT", Some("blocklist")) => {
+            let list = std::str::from_utf8(body).unwrap();
+            let mut blob = Vec::new();
+            for latest in list.split("<Latest>").skip(1) {
+                let id = latest.split("</Latest>").next().unwrap();
+                blob.extend(bucket.pending.remove(&format!("{}:{}", name, id)).unwrap());
+            }
+            bucket.objects.insert(name, blob);
+            HttpResponse::Created().finish()
+        }
+        ("GET", None) => match bucket.objects.get(&name) {
+            Some(blob) => HttpResponse::Ok().body(blob.clone()),
+            None => HttpResponse::NotFound().finish(),
+        },
+        ("DELETE", None) => match bucket.objects.remove(&name) {
+            Some(_) => HttpResponse::Accepted().finish(),
+            None => HttpResponse::NotFound().finish(),
+        },
+        _ => HttpResponse::BadRequest().finish(),
+    }
+}
+
+// Not a multiple of the GCS chunk size, the last chunk is short
+#[cfg(any(feature = "storage-gcs", feature = "storage-azure"))]
+fn contents() -> Vec<u8> {
+    (0..600_000u32).map(|i| (i % 251) as u8).collect()
+}
+
+// `object` is `name` as the service stores it
+#[cfg(any(feature = "storage-gcs", feature = "storage-azure"))]
+async fn round_trip(storage: &dyn Storage, dir: &ScratchDir, bucket: &Shared, name: &str, object: &str) {
+    let (from, to) = (dir.join("from.bin"), dir.join("to.bin"));
+    std::fs::write(&from, contents()).unwrap();
+    storage.put(name, &from).await.unwrap();
+    assert_eq!(bucket.lock().unwrap().objects[object].len(), 600_000);
+    assert!(bucket.lock().unwrap().pending.is_empty());
+
+    storage.get(name, &to).await.unwrap();
+    assert_eq!(std::fs::read(&to).unwrap(), contents());
+
+    storage.delete(name).await.unwrap();
+    storage.delete(name).await.unwrap();
+    assert!(matches!(storage.get(name, &to).await, Err(StorageError::NotFound(_))));
+
+    // Empty, in one request
+    std::fs::write(&from, b"").unwrap();
+    storage.put(name, &from).await.unwrap();
+    storage.get(name, &to).await.unwrap();
+    assert_eq!(std::fs::read(&to).unwrap(), b"");
+}
+
+#[test]
+fn cloud_backends_are_configured_by_kind() {
+    let config: StorageConfig = toml::from_str("kind = \"gcs\"\nbucket = \"media\"\nchunk_size = 1").unwrap();
+    assert!(matches!(&config, StorageConfig::Gcs(gcs) if gcs.bucket == "media" && gcs.prefix.is_empty()));
+    let config: StorageConfig = toml::from_str("kind = \"azure\"\naccount = \"acme\"\ncontainer = \"media\"").unwrap();
+    assert!(matches!(&config, StorageConfig::Azure(azure) if azure.block_size == 8 << 20));
+    assert!(toml::from_str::<StorageConfig>("kind = \"s3\"").is_err());
+
+    // Without its feature, or without a bucket
+    assert!(storage::open(&StorageConfig::Gcs(Default::default())).is_err());
+    assert!(storage::open(&StorageConfig::Azure(Default::default())).is_err());
+}
+
+#[cfg(feature = "storage-gcs")]
+#[actix_rt::test]
+async fn gcs_objects_are_uploaded_in_chunks() {
+    let dir = ScratchDir::new("cloud_storage_gcs");
+    let (endpoint, bucket) = serve(gcs);
+    let storage = storage::open(&StorageConfig::Gcs(GcsConfig {
+        bucket: "media".to_owned(),
+        prefix: "archive-".to_owned(),
+        endpoint,
+        access_token: "test-token".to_owned(),
+        // At least 256 KiB
+        chunk_size: 1000,
+        ..Default::default()
+    }))
+    .unwrap();
+
+    round_trip(&*storage, &dir, &bucket, "abc.bin", "archive-abc.bin").await;
+    // 600 000 bytes in three chunks, then the empty one
+    assert_eq!(bucket.lock().unwrap().parts, 4);
+}
+
+#[cfg(feature = "storage-azure")]
+#[actix_rt::test]
+async fn azure_blobs_are_committed_from_blocks() {
+    let dir = ScratchDir::new("cloud_storage_azure");
+    let (endpoint, bucket) = serve(azure);
+    let storage = storage::open(&StorageConfig::Azure(AzureConfig {
+        container: "media".to_owned(),
+        endpoint,
+        sas_token: "?sv=2021-08-06&sig=secret".to_owned(),
+        block_size: 100_000,
+        ..Default::default()
+    }))
+    .unwrap();
+
+    round_trip(&*storage, &dir, &bucket, "abc.bin", "abc.bin").await;
+    // No blocks for the empty one
+    assert_eq!(bucket.lock().unwrap().parts, 6);
+}
@@ -0,0 +1,79 @@
+// Nodes of a cluster serving each other
+mod common;
+
+use actix_web::{test, App};
+
+use rust_rest_api::cluster::{Cluster, ClusterConfig, TOKEN_HEADER};
+use rust_rest_api::{clock, http, layout, Config};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+fn config(dir: &ScratchDir) -> Config {
+    Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        cluster: ClusterConfig {
+            token: "peer-secret".to_owned(),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+#[actix_rt::test]
+async fn peers_get_the_files_of_live_uploads_only() {
+    let dir = ScratchDir::new("cluster_files");
+    let config = config(&dir);
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let mut names = Vec::new();
+    for query in [String::new(), format!("?publish_at={}", clock::unix_now() + 3600)] {
+        let response = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri(&format!("/upload{}", query))
+                .insert_header(("Content-Type", "image/svg+xml"))
+                .set_payload(SVG)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        let name = format!("{}_thumbnail.png", body[0].as_str().unwrap());
+        std::fs::write(layout::stored_file_path(&config.uploads_dir, &name), b"thumbnail").unwrap();
+        names.push(name);
+    }
+
+    let get = |name: &str, token: Option<&str>| {
+        let request = test::TestRequest::get().uri(&format!("/cluster/files/{}", name));
+        match token {
+            Some(token) => request.insert_header((TOKEN_HEADER, token)),
+            None => request,
+        }
+        .to_request()
+    };
+    assert_eq!(test::call_service(&app, get(&names[0], None)).await.status(), 401);
+    assert_eq!(
+        test::call_service(&app, get(&names[0], Some("guess"))).await.status(),
+        401
+    );
+    let response = test::call_service(&app, get(&names[0], Some("peer-secret"))).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(test::read_body(response).await, "thumbnail");
+
+    // Not published yet
+    let response = test::call_service(&app, get(&names[1], Some("peer-secret"))).await;
+    assert_eq!(response.status(), 404);
+}
+
+#[actix_rt::test]
+async fn files_are_not_served_without_a_token() {
+    let cluster = Cluster::new(&ClusterConfig::default());
+    assert!(!cluster.is_authorized(Some("")));
+    assert!(!cluster.is_authorized(None));
+}
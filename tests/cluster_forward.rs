@@ -0,0 +1,138 @@
+// Thumbnails forwarded to the node that owns them, against a local peer
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use actix_web::{test, web, App, HttpRequest, HttpResponse, HttpServer};
+
+use rust_rest_api::cluster::{self, Cluster, ClusterConfig};
+use rust_rest_api::{http, Config};
+
+use common::ScratchDir;
+
+// Method, Authorization and whether it came from a node, per request
+type Seen = Arc<Mutex<Vec<(String, Option<String>, bool)>>>;
+
+fn header(req: &HttpRequest, name: &str) -> Option<String> {
+    req.headers().get(name).map(|value| value.to_str().unwrap().to_owned())
+}
+
+// A peer that owns everything: checks the credentials and revalidates like a real node would
+fn serve_peer() -> (String, Seen) {
+    let seen = Seen::default();
+    let state = seen.clone();
+    let server = HttpServer::new(move || {
+        let state = state.clone();
+        App::new().default_service(web::to(move |req: HttpRequest| {
+            if req.path() != cluster::PING_PATH {
+                state.lock().unwrap().push((
+                    req.method().to_string(),
+                    header(&req, "Authorization"),
+                    req.headers().contains_key(cluster::FORWARDED_HEADER),
+                ));
+            }
+            let response = if req.path() == cluster::PING_PATH {
+                HttpResponse::Ok().finish()
+            } else if header(&req, "Authorization").as_deref() != Some("Bearer secret") {
+                HttpResponse::Unauthorized().finish()
+            } else if header(&req, "If-None-Match").as_deref() == Some("\"v1\"") {
+                HttpResponse::NotModified().insert_header(("ETag", "\"v1\"")).finish()
+            } else {
+                HttpResponse::Ok()
+                    .insert_header(("Content-Type", "image/png"))
+                    .insert_header(("ETag", "\"v1\""))
+                    .insert_header(("Cache-Control", "private, max-age=60"))
+                    .insert_header(("Last-Modified", "Thu, 01 Jan 2026 00:00:00 GMT"))
+                    .body("thumbnail")
+            };
+            async move { response }
+        }))
+    })
+    .workers(1)
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let base = format!("http://{}", server.addrs()[0]);
+    actix_rt::spawn(server.run());
+    (base, seen)
+}
+
+#[actix_rt::test]
+async fn thumbnails_are_forwarded_with_credentials_and_validators() {
+    let dir = ScratchDir::new("cluster_forward");
+    let (peer, seen) = serve_peer();
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        cluster: ClusterConfig {
+            self_url: "http://127.0.0.1:9".to_owned(),
+            peers: vec![peer],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let ring = Cluster::new(&config.cluster);
+    let id = (0..)
+        .map(|n| format!("image{}", n))
+        .find(|id| ring.owner(id).is_some())
+        .unwrap();
+    let uri = format!("/images/{}/thumbnail", id);
+
+    let response = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(("Authorization", "Bearer secret"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    let headers = response.headers();
+    assert_eq!(headers.get("etag").unwrap(), "\"v1\"");
+    assert_eq!(headers.get("cache-control").unwrap(), "private, max-age=60");
+    assert_eq!(headers.get("last-modified").unwrap(), "Thu, 01 Jan 2026 00:00:00 GMT");
+    assert_eq!(headers.get("content-length").unwrap(), "9");
+    assert_eq!(test::read_body(response).await, "thumbnail");
+
+    let response = test::call_service(
+        &app,
+        test::TestRequest::default()
+            .method(actix_web::http::Method::HEAD)
+            .uri(&uri)
+            .insert_header(("Authorization", "Bearer secret"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("content-length").unwrap(), "9");
+
+    let response = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(("Authorization", "Bearer secret"))
+            .insert_header(("If-None-Match", "\"v1\""))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 304);
+    assert_eq!(response.headers().get("etag").unwrap(), "\"v1\"");
+
+    // The owner's refusal is passed on, not retried locally
+    let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(response.status(), 401);
+
+    let auth = Some("Bearer secret".to_owned());
+    assert_eq!(
+        *seen.lock().unwrap(),
+        [
+            ("GET".to_owned(), auth.clone(), true),
+            ("HEAD".to_owned(), auth.clone(), true),
+            ("GET".to_owned(), auth, true),
+            ("GET".to_owned(), None, true),
+        ]
+    );
+}
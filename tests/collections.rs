@@ -0,0 +1,36 @@
+mod common;
+
+use std::path::PathBuf;
+
+use rust_rest_api::collections::{self, SheetSpec};
+
+use common::ScratchDir;
+
+#[actix_rt::test]
+async fn create_load_remove() {
+    let dir = ScratchDir::new("collections");
+    let images = vec!["a".to_owned(), "b".to_owned()];
+    let collection = collections::create(&*dir, Some("Trip".to_owned()), images.clone(), Some("alice".to_owned()))
+        .await
+        .unwrap();
+
+    let loaded = collections::load(&*dir, &collection.id).await.unwrap().unwrap();
+    assert_eq!((loaded.name.as_deref(), loaded.images), (Some("Trip"), images));
+    assert_eq!(loaded.owner.as_deref(), Some("alice"));
+    assert!(collections::load(&*dir, "missing").await.unwrap().is_none());
+    assert!(collections::load(&*dir, "../etc").await.unwrap().is_none());
+
+    // A cached sheet goes with its collection
+    let spec = SheetSpec {
+        columns: 2,
+        tile_size: 10,
+        quality: 80,
+    };
+    let sheet = collections::contact_sheet(&*dir, &collection.id, &spec, Vec::<(String, PathBuf)>::new())
+        .await
+        .unwrap();
+    assert!(sheet.exists());
+    assert!(collections::remove(&*dir, &collection.id).await.unwrap());
+    assert!(!sheet.exists());
+    assert!(!collections::remove(&*dir, &collection.id).await.unwrap());
+}
@@ -0,0 +1,119 @@
+// Validators on downloads and conditional GETs
+mod common;
+
+use actix_web::{test, App};
+
+use rust_rest_api::{http, Config};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+#[actix_rt::test]
+async fn downloads_revalidate_with_304() {
+    let dir = ScratchDir::new("conditional_get");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let response = test::call_service(
+        &app,
+        test::TestRequest::post()
+            .uri("/upload")
+            .insert_header(("Content-Type", "image/svg+xml"))
+            .set_payload(SVG)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let id = body[0].as_str().unwrap().to_owned();
+    let uri = format!("/images/{}", id);
+
+    let response = test::call_service(
+        &app,
+        test::TestRequest::get().uri(&format!("{}/metadata", uri)).to_request(),
+    )
+    .await;
+    let metadata: serde_json::Value = test::read_body_json(response).await;
+    let etag = format!("\"{}\"", metadata["checksum"].as_str().unwrap());
+
+    let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("etag").unwrap().to_str().unwrap(), etag);
+    let last_modified = response
+        .headers()
+        .get("last-modified")
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_owned();
+
+    let get = |headers: &[(&str, &str)]| {
+        let mut request = test::TestRequest::get().uri(&uri);
+        for &(name, value) in headers {
+            request = request.insert_header((name, value.to_owned()));
+        }
+        request.to_request()
+    };
+    let (weak, listed) = (format!("W/{}", etag), format!("\"other\", {}", etag));
+    let cases: [(&[(&str, &str)], u16); 9] = [
+        (&[("If-None-Match", etag.as_str())], 304),
+        (&[("If-None-Match", weak.as_str())], 304),
+        (&[("If-None-Match", listed.as_str())], 304),
+        (&[("If-None-Match", "*")], 304),
+        (&[("If-None-Match", "\"other\"")], 200),
+        (&[("If-Modified-Since", last_modified.as_str())], 304),
+        (&[("If-Modified-Since", "Mon, 01 Jan 1990 00:00:00 GMT")], 200),
+        (&[("If-Modified-Since", "yesterday")], 200),
+        // If-None-Match wins over If-Modified-Since
+        (
+            &[
+                ("If-None-Match", "\"other\""),
+                ("If-Modified-Since", last_modified.as_str()),
+            ],
+            200,
+        ),
+    ];
+    for (headers, status) in cases.iter() {
+        let response = test::call_service(&app, get(headers)).await;
+        assert_eq!(response.status(), *status, "{:?}", headers);
+        if *status == 304 {
+            assert_eq!(response.headers().get("etag").unwrap().to_str().unwrap(), etag);
+            assert_eq!(response.headers().get("last-modified").unwrap(), last_modified.as_str());
+            assert!(test::read_body(response).await.is_empty());
+        }
+    }
+
+    // Without a checksum, the tag is weak and made from the file
+    let file_uri = format!("/replication/files/{}.svg", id);
+    let response = test::call_service(&app, test::TestRequest::get().uri(&file_uri).to_request()).await;
+    assert_eq!(response.status(), 200);
+    let weak_etag = response.headers().get("etag").unwrap().to_str().unwrap().to_owned();
+    assert!(weak_etag.starts_with("W/\""), "{}", weak_etag);
+    let response = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri(&file_uri)
+            .insert_header(("If-None-Match", weak_etag))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 304);
+
+    let response = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri("/images/missing")
+            .insert_header(("If-None-Match", "*"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 404);
+}
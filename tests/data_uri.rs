@@ -0,0 +1,111 @@
+// Data URIs in base64 uploads
+mod common;
+
+use actix_web::{test, App};
+
+use rust_rest_api::base64_stream::{split_data_uri, Base64Decoder, Base64Error, DataUriError};
+use rust_rest_api::{http, Config};
+
+use common::ScratchDir;
+
+const SVG: &str = r#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+#[actix_rt::test]
+async fn splits_off_the_declared_type() {
+    assert_eq!(split_data_uri("iVBOR").unwrap(), (None, "iVBOR"));
+    assert_eq!(
+        split_data_uri("data:image/png;base64,iVBOR").unwrap(),
+        (Some("image/png"), "iVBOR")
+    );
+    assert_eq!(
+        split_data_uri("DATA:image/png; charset=x; BASE64,iVBOR").unwrap(),
+        (Some("image/png"), "iVBOR")
+    );
+    assert_eq!(split_data_uri("data:;base64,iVBOR").unwrap(), (None, "iVBOR"));
+    assert!(matches!(
+        split_data_uri("data:image/png;base64"),
+        Err(DataUriError::Malformed)
+    ));
+    assert!(matches!(
+        split_data_uri("data:image/svg+xml,<svg/>"),
+        Err(DataUriError::NotBase64)
+    ));
+}
+
+#[actix_rt::test]
+async fn the_decoder_splits_them_off_as_they_arrive() {
+    let text = format!("data:image/svg+xml;base64,{}", base64::encode(SVG));
+    for piece in [1, 3, 30, text.len()] {
+        let mut decoder = Base64Decoder::default();
+        let mut decoded = Vec::new();
+        for chunk in text.as_bytes().chunks(piece) {
+            for bytes in decoder.push(chunk).unwrap() {
+                decoded.extend_from_slice(&bytes);
+            }
+        }
+        assert_eq!(decoder.declared_type(), Some("image/svg+xml"));
+        decoded.extend_from_slice(&decoder.finish().unwrap().unwrap());
+        assert_eq!(decoded, SVG.as_bytes());
+    }
+
+    let mut decoder = Base64Decoder::default();
+    assert!(decoder.push(b"data:image/png;base64").unwrap().is_empty());
+    assert!(matches!(
+        decoder.finish(),
+        Err(Base64Error::DataUri(DataUriError::Malformed))
+    ));
+    let mut decoder = Base64Decoder::default();
+    assert!(matches!(
+        decoder.push(b"data:image/svg+xml,<svg/>"),
+        Err(Base64Error::DataUri(DataUriError::NotBase64))
+    ));
+    // A prefix that never ends
+    let mut decoder = Base64Decoder::default();
+    assert!(decoder.push(b"data:").unwrap().is_empty());
+    assert!(decoder.push(&[b'x'; 300]).is_err());
+}
+
+#[actix_rt::test]
+async fn declared_types_must_match_the_bytes() {
+    let dir = ScratchDir::new("data_uri");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+    let json = |data: String| {
+        test::TestRequest::post()
+            .uri("/upload")
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(serde_json::json!([{ "base64": data }]).to_string())
+            .to_request()
+    };
+    let form = |data: String| {
+        let mut url = reqwest::Url::parse("http://localhost/").unwrap();
+        url.query_pairs_mut().append_pair("base64", &data);
+        let body = url.query().unwrap().to_owned();
+        test::TestRequest::post()
+            .uri("/upload")
+            .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+            .set_payload(body)
+            .to_request()
+    };
+
+    let encoded = base64::encode(SVG);
+    let cases = [
+        (format!("data:image/svg+xml;base64,{}", encoded), 200),
+        (format!("data:;base64,{}", encoded), 200),
+        (format!("data:image/png;base64,{}", encoded), 415),
+        (format!("data:image/svg+xml,{}", SVG), 400),
+        (format!("data:image/svg+xml;base64{}", encoded), 400),
+    ];
+    for (data, status) in &cases {
+        let response = test::call_service(&app, json(data.clone())).await;
+        assert_eq!(response.status(), *status, "JSON {}", data);
+        let response = test::call_service(&app, form(data.clone())).await;
+        assert_eq!(response.status(), *status, "form {}", data);
+    }
+}
@@ -0,0 +1,138 @@
+mod common;
+
+use std::sync::Arc;
+
+use actix_web::{test, App};
+use futures_util::TryStreamExt;
+
+use rust_rest_api::encryption::{self, EncryptionConfig, EncryptionError, Keyring};
+use rust_rest_api::{http, layout, Config};
+
+use common::ScratchDir;
+
+const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+fn config() -> EncryptionConfig {
+    EncryptionConfig {
+        enabled: true,
+        active_key: "2026-10".to_owned(),
+        keys: vec![("2026-10".to_owned(), KEY.to_owned())].into_iter().collect(),
+    }
+}
+
+// The process-wide keyring, the same in every test here
+fn keyring() -> Arc<Keyring> {
+    encryption::set_keyring(Keyring::from_config(&config()).unwrap());
+    encryption::keyring().unwrap()
+}
+
+// Not a multiple of the chunk size, so the last chunk is a short one
+fn data(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+#[actix_rt::test]
+async fn keys_are_checked() {
+    assert!(Keyring::from_config(&config()).is_ok());
+
+    let mut bad = config();
+    bad.keys.insert("short".to_owned(), "0011".to_owned());
+    assert!(matches!(Keyring::from_config(&bad), Err(EncryptionError::InvalidKey(id)) if id == "short"));
+
+    let mut bad = config();
+    bad.active_key = "2027-01".to_owned();
+    assert!(matches!(Keyring::from_config(&bad), Err(EncryptionError::UnknownKey(id)) if id == "2027-01"));
+
+    let mut from_env = config();
+    from_env.keys.insert("kms".to_owned(), "env:RR_API_TEST_KEY".to_owned());
+    assert!(matches!(
+        Keyring::from_config(&from_env),
+        Err(EncryptionError::InvalidKey(_))
+    ));
+    std::env::set_var("RR_API_TEST_KEY", KEY);
+    from_env.active_key = "kms".to_owned();
+    assert!(Keyring::from_config(&from_env).is_ok());
+
+    // Ids, but never the keys, in logs
+    let debug = format!("{:?}", Keyring::from_config(&config()).unwrap());
+    assert!(debug.contains("2026-10") && !debug.contains(KEY));
+}
+
+#[actix_rt::test]
+async fn sealed_files_read_back() {
+    let dir = ScratchDir::new("encryption_roundtrip");
+    let keyring = keyring();
+    for &size in &[0, 100, 64 << 10, 200_000] {
+        let path = dir.join("original.png");
+        let original = data(size);
+        std::fs::write(&path, &original).unwrap();
+        assert_eq!(encryption::envelope(&path).unwrap(), None);
+
+        let envelope = keyring.seal_file(&path).unwrap();
+        assert_eq!(envelope.key_id, "2026-10");
+        assert_eq!(encryption::envelope(&path).unwrap(), Some(envelope));
+        let sealed = std::fs::read(&path).unwrap();
+        assert!(sealed.starts_with(b"RRENC"));
+        assert!(size < 16 || !sealed.windows(16).any(|window| window == &original[..16]));
+
+        assert_eq!(encryption::read(&path).await.unwrap(), original);
+        {
+            let plaintext = encryption::plaintext(&path).unwrap();
+            assert_ne!(&*plaintext, &*path);
+            assert_eq!(plaintext.extension().unwrap(), "png");
+            assert_eq!(std::fs::read(&*plaintext).unwrap(), original);
+        }
+        let (plain_size, stream) =
+            encryption::plain_stream(tokio::fs::File::open(&path).await.unwrap(), sealed.len() as u64)
+                .await
+                .unwrap();
+        assert_eq!(plain_size, size as u64);
+        let streamed: Vec<u8> = stream.map_ok(|chunk| chunk.to_vec()).try_concat().await.unwrap();
+        assert_eq!(streamed, original);
+    }
+    // The copy is gone, no seal temp file is left behind
+    assert_eq!(std::fs::read_dir(&*dir).unwrap().count(), 1);
+}
+
+#[actix_rt::test]
+async fn tampering_is_detected() {
+    let dir = ScratchDir::new("encryption_tamper");
+    let path = dir.join("original.jpg");
+    std::fs::write(&path, data(150_000)).unwrap();
+    keyring().seal_file(&path).unwrap();
+    let sealed = std::fs::read(&path).unwrap();
+
+    let mut flipped = sealed.clone();
+    flipped[sealed.len() / 2] ^= 1;
+    std::fs::write(&path, &flipped).unwrap();
+    assert!(matches!(encryption::plaintext(&path), Err(EncryptionError::Corrupt)));
+    assert!(matches!(encryption::read(&path).await, Err(EncryptionError::Corrupt)));
+
+    // Cut after the first chunk, which still opens on its own
+    std::fs::write(&path, &sealed[..sealed.len() - (150_000 - (64 << 10)) - 16]).unwrap();
+    assert!(matches!(encryption::read(&path).await, Err(EncryptionError::Corrupt)));
+    // Nothing decrypted is left behind
+    assert_eq!(std::fs::read_dir(&*dir).unwrap().count(), 1);
+}
+
+#[actix_rt::test]
+async fn downloads_are_decrypted() {
+    let dir = ScratchDir::new("encryption_download");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        encryption: config(),
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let path = layout::stored_file_path(&config.uploads_dir, "sealed.png");
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(&path, data(100_000)).unwrap();
+    keyring().seal_file(&path).unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let response = test::call_service(&app, test::TestRequest::get().uri("/images/sealed").to_request()).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(test::read_body(response).await.to_vec(), data(100_000));
+}
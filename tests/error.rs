@@ -0,0 +1,83 @@
+use actix_web::http::StatusCode;
+use anyhow::Context;
+use serde_json::json;
+
+use rust_rest_api::error::ApiError;
+use rust_rest_api::guest::GuestError;
+use rust_rest_api::policy::PolicyViolation;
+use rust_rest_api::storage::StorageError;
+use rust_rest_api::tus::TusError;
+use rust_rest_api::UploadError;
+
+fn classify<E: std::error::Error + Send + Sync + 'static>(err: E) -> ApiError {
+    ApiError::from(anyhow::Error::new(err))
+}
+
+#[test]
+fn maps_typed_errors_to_status_and_code() {
+    let cases = vec![
+        (
+            classify(UploadError::UnrecognizedImage),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unrecognized_image",
+        ),
+        (
+            classify(UploadError::BodyTooLarge(10)),
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "body_too_large",
+        ),
+        (
+            classify(GuestError::RateLimited),
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limited",
+        ),
+        (
+            classify(TusError::OffsetMismatch(1, 2)),
+            StatusCode::CONFLICT,
+            "offset_mismatch",
+        ),
+        (
+            classify(UploadError::Storage(StorageError::NotFound("a.png".to_owned()))),
+            StatusCode::NOT_FOUND,
+            "not_found",
+        ),
+    ];
+    for (error, status, code) in cases {
+        assert_eq!((error.status, error.code), (status, code), "{}", error);
+    }
+}
+
+#[test]
+fn body_carries_code_and_details() {
+    let error = classify(PolicyViolation {
+        rule: "no-gifs".to_owned(),
+        reason: "image/gif uploads are not accepted".to_owned(),
+    })
+    .with("uploaded", json!([]));
+    assert_eq!(error.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let body = error.body();
+    assert_eq!(body["code"], "policy_violation");
+    assert_eq!(body["rule"], "no-gifs");
+    assert_eq!(body["uploaded"], json!([]));
+    assert!(body["error"].as_str().unwrap().contains("no-gifs"));
+}
+
+#[test]
+fn finds_errors_under_context_and_hides_internal_ones() {
+    let err = anyhow::Error::new(GuestError::InvalidToken).context("checking the guest token");
+    assert_eq!(ApiError::from(&err).status, StatusCode::UNAUTHORIZED);
+
+    let err = Err::<(), _>(std::io::Error::other("disk on fire"))
+        .context("writing /srv/uploads/a.png")
+        .unwrap_err();
+    let error = ApiError::from(&err);
+    assert_eq!(
+        (error.status, error.code),
+        (StatusCode::INTERNAL_SERVER_ERROR, "internal_error")
+    );
+    assert!(!error.body().to_string().contains("disk on fire"));
+
+    let error = classify(UploadError::Processing(anyhow::anyhow!("decoder panicked at /srv")));
+    assert_eq!(error.status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(!error.message.contains("/srv"));
+}
@@ -0,0 +1,53 @@
+// Uploads with a TTL; alone in its binary, it moves the clock
+mod common;
+
+use std::time::Duration;
+
+use actix_web::{test, App};
+
+use rust_rest_api::{clock, http, metadata, Config};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+#[actix_rt::test]
+async fn expired_uploads_are_gone() {
+    let dir = ScratchDir::new("expiry");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let response = test::call_service(
+        &app,
+        test::TestRequest::post()
+            .uri("/upload?ttl=60&details=true")
+            .insert_header(("Content-Type", "image/svg+xml"))
+            .set_payload(SVG)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let id = body[0]["id"].as_str().unwrap().to_owned();
+    let get = || test::TestRequest::get().uri(&format!("/images/{}", id)).to_request();
+
+    let response = test::call_service(&app, get()).await;
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().contains_key("expires"));
+
+    clock::advance(Duration::from_secs(61));
+    assert_eq!(test::call_service(&app, get()).await.status(), 404);
+
+    // Until the reaper deletes it
+    assert!(metadata::load(&config.uploads_dir, &id).await.unwrap().is_some());
+    assert_eq!(metadata::reap_expired(&config.uploads_dir).await.unwrap(), 1);
+    assert!(metadata::load(&config.uploads_dir, &id).await.unwrap().is_none());
+    assert_eq!(test::call_service(&app, get()).await.status(), 404);
+}
@@ -0,0 +1,37 @@
+mod common;
+
+use actix_web::{test, App};
+
+use rust_rest_api::imagetools::FACE_DETECTION;
+use rust_rest_api::{http, Config, OutputOptions, UploadOptions};
+
+use common::ScratchDir;
+
+// Only the OpenCV backend detects faces, the rest must say so
+#[actix_rt::test]
+async fn detection_depends_on_the_backend() {
+    let dir = ScratchDir::new("faces");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let response = test::call_service(&app, test::TestRequest::get().uri("/images/missing/faces").to_request()).await;
+    assert_eq!(response.status(), if FACE_DETECTION { 404 } else { 501 });
+
+    let response = test::call_service(&app, test::TestRequest::get().uri("/capabilities").to_request()).await;
+    let capabilities: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(capabilities["features"]["face_detection"], FACE_DETECTION);
+
+    let mut options = UploadOptions::from_config(&config);
+    let output = OutputOptions {
+        blur_faces: Some(true),
+        ..Default::default()
+    };
+    assert_eq!(output.apply(&mut options).is_ok(), FACE_DETECTION);
+    assert_eq!(options.blur_faces, FACE_DETECTION);
+}
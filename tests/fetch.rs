@@ -0,0 +1,269 @@
+// URL fetches: proxy, forwarded headers, redirects and type sniffing
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+
+use rust_rest_api::fetch::{is_public, FetchConfig, ForwardHeaders};
+use rust_rest_api::{fetch_image, Config, FetchError, UploadOptions};
+
+use rust_rest_api::limits::{TypeLimitError, TypeLimits};
+use rust_rest_api::{ByteLimit, UploadError};
+
+use common::{png, ScratchDir};
+
+fn headers(pairs: &[(&str, &str)]) -> ForwardHeaders {
+    ForwardHeaders(
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+    )
+}
+
+#[test]
+fn forwards_allowed_headers_only() {
+    let config = FetchConfig {
+        forward_headers: vec!["Authorization".to_owned()],
+        ..Default::default()
+    };
+    let forwarded = config
+        .forwarded(&headers(&[("authorization", "Bearer t0ken")]))
+        .unwrap();
+    assert_eq!(forwarded["authorization"], "Bearer t0ken");
+
+    assert!(matches!(
+        config.forwarded(&headers(&[("Cookie", "a=b")])),
+        Err(FetchError::HeaderNotAllowed(name)) if name == "Cookie"
+    ));
+    assert!(matches!(
+        config.forwarded(&headers(&[("Authorization", "a\nb")])),
+        Err(FetchError::InvalidHeader(_))
+    ));
+    assert_eq!(
+        format!("{:?}", headers(&[("Authorization", "Bearer t0ken")])),
+        "[\"Authorization\"]"
+    );
+}
+
+type Seen = Arc<Mutex<Vec<(String, Option<String>)>>>;
+
+// Records what it is asked for and the Authorization it gets; redirects to
+// the rest of the query after `to=`, answers 404 otherwise
+fn recording_server() -> (String, Seen) {
+    let seen: Seen = Default::default();
+    let recorder = seen.clone();
+    let server = HttpServer::new(move || {
+        let recorder = recorder.clone();
+        App::new().default_service(web::to(move |req: HttpRequest| {
+            let authorization = req
+                .headers()
+                .get("authorization")
+                .map(|value| value.to_str().unwrap().to_owned());
+            recorder.lock().unwrap().push((req.uri().to_string(), authorization));
+            let response = match req.query_string().strip_prefix("to=") {
+                Some(to) => HttpResponse::Found().insert_header(("location", to)).finish(),
+                None => HttpResponse::NotFound().finish(),
+            };
+            async { response }
+        }))
+    })
+    .workers(1)
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let base = format!("http://{}", server.addrs()[0]);
+    actix_rt::spawn(server.run());
+    (base, seen)
+}
+
+async fn fetch(config: &Config, url: &str) -> FetchError {
+    let res = fetch_image(
+        config,
+        url,
+        &headers(&[("Authorization", "Bearer t0ken")]),
+        &UploadOptions::from_config(config),
+    )
+    .await;
+    match res {
+        Err(err) => err.downcast::<FetchError>().unwrap(),
+        Ok(_) => panic!("{} was fetched", url),
+    }
+}
+
+fn taken(seen: &Seen) -> Vec<(String, Option<String>)> {
+    std::mem::take(&mut *seen.lock().unwrap())
+}
+
+fn token() -> Option<String> {
+    Some("Bearer t0ken".to_owned())
+}
+
+#[test]
+fn public_addresses() {
+    for ip in &["93.184.216.34", "2606:2800:220:1::1"] {
+        assert!(is_public(ip.parse().unwrap()), "{}", ip);
+    }
+    for ip in &[
+        "127.0.0.1",
+        "10.1.2.3",
+        "172.16.0.1",
+        "192.168.1.1",
+        "169.254.169.254",
+        "100.64.0.1",
+        "0.0.0.0",
+        "::1",
+        "fd00::1",
+        "fe80::1",
+        "::ffff:127.0.0.1",
+    ] {
+        assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+    }
+}
+
+#[actix_rt::test]
+async fn fetches_through_the_proxy() {
+    let (proxy_url, seen) = recording_server();
+
+    let dir = ScratchDir::new("fetch");
+    let config = Config {
+        uploads_dir: dir.to_path_buf(),
+        fetch: FetchConfig {
+            proxy: Some(proxy_url),
+            forward_headers: vec!["authorization".to_owned()],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    assert!(matches!(
+        fetch(&config, "http://images.example/a.png").await,
+        FetchError::Status(404)
+    ));
+    assert_eq!(taken(&seen), vec![("http://images.example/a.png".to_owned(), token())]);
+}
+
+#[actix_rt::test]
+async fn checks_every_redirect() {
+    let (base, seen) = recording_server();
+    // The same server, another origin
+    let other = base.replace("127.0.0.1", "localhost");
+
+    let dir = ScratchDir::new("fetch");
+    let mut config = Config {
+        uploads_dir: dir.to_path_buf(),
+        fetch: FetchConfig {
+            forward_headers: vec!["authorization".to_owned()],
+            max_redirects: 2,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let url = format!("{}/a.png", base);
+    assert!(matches!(fetch(&config, &url).await, FetchError::UrlNotAllowed(refused) if refused == url));
+    assert!(taken(&seen).is_empty());
+
+    config.fetch.allow_private_addresses = true;
+    let url = format!("{}/a?to={}/b.png", base, base);
+    assert!(matches!(fetch(&config, &url).await, FetchError::Status(404)));
+    assert_eq!(
+        taken(&seen),
+        vec![
+            (format!("/a?to={}/b.png", base), token()),
+            ("/b.png".to_owned(), token())
+        ]
+    );
+
+    // Credentials stay with their origin
+    let url = format!("{}/a?to={}/b.png", base, other);
+    assert!(matches!(fetch(&config, &url).await, FetchError::Status(404)));
+    assert_eq!(taken(&seen)[1], ("/b.png".to_owned(), None));
+
+    let url = format!("{0}/a?to={0}/a?to={0}/a?to={0}/b.png", base);
+    assert!(matches!(fetch(&config, &url).await, FetchError::TooManyRedirects(2)));
+    assert_eq!(taken(&seen).len(), 3);
+
+    config.fetch.allowed_hosts = vec!["127.0.0.1".to_owned()];
+    let url = format!("{}/a?to={}/b.png", base, other);
+    assert!(
+        matches!(fetch(&config, &url).await, FetchError::UrlNotAllowed(refused) if refused == format!("{}/b.png", other))
+    );
+    assert_eq!(taken(&seen).len(), 1);
+}
+
+// Answers every request with `body`, typed as `content_type`
+fn serving(body: Vec<u8>, content_type: Option<&'static str>) -> String {
+    let server = HttpServer::new(move || {
+        let body = body.clone();
+        App::new().default_service(web::to(move || {
+            let mut response = HttpResponse::Ok();
+            if let Some(content_type) = content_type {
+                response.insert_header(("content-type", content_type));
+            }
+            let response = response.body(body.clone());
+            async { response }
+        }))
+    })
+    .workers(1)
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let base = format!("http://{}/a", server.addrs()[0]);
+    actix_rt::spawn(server.run());
+    base
+}
+
+async fn fetch_with(config: &Config, url: &str, options: &UploadOptions) -> anyhow::Error {
+    match fetch_image(config, url, &Default::default(), options).await {
+        Err(err) => err,
+        Ok(_) => panic!("{} was fetched", url),
+    }
+}
+
+#[actix_rt::test]
+async fn sniffs_untyped_bodies_and_checks_their_length() {
+    let dir = ScratchDir::new("fetch");
+    let mut config = Config {
+        uploads_dir: dir.to_path_buf(),
+        fetch: FetchConfig {
+            allow_private_addresses: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let image = png(16, 16);
+
+    // Sniffed as a PNG, then refused by its Content-Length
+    let mut options = UploadOptions::from_config(&config);
+    options.byte_limit = Some(ByteLimit::new(image.len() as u64 - 1, 0));
+    let url = serving(image.clone(), None);
+    let err = fetch_with(&config, &url, &options).await;
+    assert!(
+        matches!(err.downcast_ref::<UploadError>(), Some(UploadError::BodyTooLarge(_))),
+        "{}",
+        err
+    );
+
+    config.type_limits.insert(
+        "image/png".to_owned(),
+        TypeLimits {
+            max_size: Some(10),
+            ..Default::default()
+        },
+    );
+    let options = UploadOptions::from_config(&config);
+    let url = serving(image, Some("application/octet-stream"));
+    let err = fetch_with(&config, &url, &options).await;
+    assert!(
+        matches!(err.downcast_ref::<TypeLimitError>(), Some(TypeLimitError::TooLarge(_))),
+        "{}",
+        err
+    );
+
+    let url = serving(b"<html></html>".to_vec(), None);
+    let err = fetch_with(&config, &url, &options).await;
+    assert!(
+        matches!(err.downcast_ref::<FetchError>(), Some(FetchError::UnsupportedType(_))),
+        "{}",
+        err
+    );
+    assert!(std::fs::read_dir(&*dir).unwrap().next().is_none());
+}
@@ -0,0 +1,68 @@
+mod common;
+
+use std::path::Path;
+
+use bytes::Bytes;
+use futures_util::stream;
+
+use rust_rest_api::finalize::{copy_over, finalize};
+use rust_rest_api::{is_temp_file_name, upload_image, Config, UploadOptions};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+fn names(dir: &Path) -> Vec<String> {
+    let mut names: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    names
+}
+
+#[actix_rt::test]
+async fn files_are_moved_or_copied_into_place() {
+    let dir = ScratchDir::new("finalize");
+    let (from, to) = (dir.join("abc.tmp"), dir.join("abc.svg"));
+
+    std::fs::write(&from, SVG).unwrap();
+    finalize(&from, &to, true).await.unwrap();
+    assert_eq!(names(&dir), vec!["abc.svg"]);
+
+    // The fallback, over what was there
+    std::fs::write(&from, b"newer").unwrap();
+    copy_over(&from, &to).unwrap();
+    assert_eq!(names(&dir), vec!["abc.svg"]);
+    assert_eq!(std::fs::read(&to).unwrap(), b"newer");
+    assert!(is_temp_file_name("abc.svg.finalize"));
+
+    // Across mounts where the sandbox has another one
+    let shm = Path::new("/dev/shm");
+    if shm.is_dir() {
+        let other = shm.join(format!("rr-api-test-finalize-{}", std::process::id()));
+        std::fs::create_dir_all(&other).unwrap();
+        let from = other.join("def.tmp");
+        std::fs::write(&from, SVG).unwrap();
+        let res = finalize(&from, dir.join("def.svg"), false).await;
+        let left = names(&other);
+        let _ = std::fs::remove_dir_all(&other);
+        res.unwrap();
+        assert!(left.is_empty(), "{:?}", left);
+        assert_eq!(std::fs::read(dir.join("def.svg")).unwrap(), SVG);
+    }
+}
+
+#[actix_rt::test]
+async fn uploads_may_sync_their_directory() {
+    let dir = ScratchDir::new("finalize_upload");
+    let config = Config {
+        fsync_dir: true,
+        ..Default::default()
+    };
+    let options = UploadOptions::from_config(&config);
+    let body = stream::iter(vec![Ok::<_, std::io::Error>(Bytes::from_static(SVG))]);
+    let uploaded = upload_image(body, &*dir, "svg", &options).await.unwrap();
+    assert_eq!(std::fs::read(&uploaded.path).unwrap(), SVG);
+}
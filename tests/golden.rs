@@ -0,0 +1,269 @@
+// Golden-image tests: thumbnails, transformations and conversions of a fixed
+// test image are compared with the outputs stored under
+// tests/golden/{backend}/, so a resize or codec change that alters what
+// clients get fails here. Outputs are compared decoded, by mean SSIM on luma,
+// which tolerates the small differences between codec versions.
+//
+// A missing golden fails like a changed output; a backend with no goldens at
+// all is skipped. `UPDATE_GOLDEN=1 cargo test --test golden` writes them all
+// from the current outputs, for a new backend or after an intended change;
+// check the new ones before committing them.
+
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use rust_rest_api::imagetools::{self, Gravity};
+use rust_rest_api::layout;
+use rust_rest_api::transform::{self, Fit, TransformSpec};
+
+use common::{encode_png, ScratchDir};
+
+// Mean SSIM below which an output counts as changed
+const LOSSLESS_MIN_SSIM: f64 = 0.99;
+const LOSSY_MIN_SSIM: f64 = 0.95;
+
+// Formats that can be decoded again for the comparison; the image backend
+// only encodes AVIF
+const FORMATS: &[&str] = &["png", "jpg", "bmp", "webp"];
+
+const SOURCE_SIZE: (u32, u32) = (320, 240);
+
+fn formats() -> impl Iterator<Item = &'static str> {
+    FORMATS.iter().copied().filter(|format| imagetools::can_encode(format))
+}
+
+// Gradient, diagonal stripes and a disc: smooth areas, hard edges and curves
+fn test_image((width, height): (u32, u32)) -> Vec<u8> {
+    let (cx, cy, r) = (width as i64 * 2 / 3, height as i64 / 2, height as i64 / 3);
+    let mut rgb = Vec::with_capacity((width * height * 3) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let (dx, dy) = (x as i64 - cx, y as i64 - cy);
+            let pixel = if dx * dx + dy * dy <= r * r {
+                [230, 60, 40]
+            } else if (x + y) / 12 % 2 == 0 {
+                [(x * 255 / width) as u8, (y * 255 / height) as u8, 160]
+            } else {
+                [20, 40, (x * 255 / width) as u8]
+            };
+            rgb.extend_from_slice(&pixel);
+        }
+    }
+    encode_png(width, height, &rgb)
+}
+
+// The test image stored as an upload `golden{format}` in each format
+fn store_sources(uploads_dir: &Path) -> Vec<(String, &'static str)> {
+    let png_path = uploads_dir.join("source.png");
+    std::fs::write(&png_path, test_image(SOURCE_SIZE)).unwrap();
+    let image = imagetools::read_image(&png_path).unwrap();
+
+    formats()
+        .map(|format| {
+            let id = format!("golden{}", format);
+            let path = layout::stored_file_path(uploads_dir, &format!("{}.{}", id, format));
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, imagetools::encode_image(&image, format, Some(95)).unwrap()).unwrap();
+            (id, format)
+        })
+        .collect()
+}
+
+fn min_ssim(extension: &str) -> f64 {
+    match extension {
+        "png" | "bmp" => LOSSLESS_MIN_SSIM,
+        _ => LOSSY_MIN_SSIM,
+    }
+}
+
+// Decoded size and row-major RGB pixels
+fn decode(path: &Path) -> ((u32, u32), Vec<u8>) {
+    let image = imagetools::read_image(path).unwrap_or_else(|err| panic!("decoding {:?}: {}", path, err));
+    (imagetools::image_size(&image), imagetools::rgb_pixels(&image).unwrap())
+}
+
+fn luma(rgb: &[u8]) -> Vec<f64> {
+    rgb.chunks(3)
+        .map(|pixel| 0.299 * f64::from(pixel[0]) + 0.587 * f64::from(pixel[1]) + 0.114 * f64::from(pixel[2]))
+        .collect()
+}
+
+// Mean SSIM of two equally sized images over 8x8 windows, 4 pixels apart
+fn ssim(a: &[u8], b: &[u8], (width, height): (u32, u32)) -> f64 {
+    let (a, b) = (luma(a), luma(b));
+    let (c1, c2) = ((0.01f64 * 255.0).powi(2), (0.03f64 * 255.0).powi(2));
+    let (window_w, window_h) = (width.min(8), height.min(8));
+    let n = f64::from(window_w * window_h);
+
+    let (mut total, mut windows) = (0.0, 0);
+    for top in (0..=height - window_h).step_by(4) {
+        for left in (0..=width - window_w).step_by(4) {
+            let index = |x: u32, y: u32| ((top + y) * width + left + x) as usize;
+            let pixels = || (0..window_h).flat_map(move |y| (0..window_w).map(move |x| index(x, y)));
+
+            let mean_a = pixels().map(|i| a[i]).sum::<f64>() / n;
+            let mean_b = pixels().map(|i| b[i]).sum::<f64>() / n;
+            let (mut var_a, mut var_b, mut cov) = (0.0, 0.0, 0.0);
+            for i in pixels() {
+                let (da, db) = (a[i] - mean_a, b[i] - mean_b);
+                var_a += da * da;
+                var_b += db * db;
+                cov += da * db;
+            }
+            let (var_a, var_b, cov) = (var_a / n, var_b / n, cov / n);
+
+            total += ((2.0 * mean_a * mean_b + c1) * (2.0 * cov + c2))
+                / ((mean_a * mean_a + mean_b * mean_b + c1) * (var_a + var_b + c2));
+            windows += 1;
+        }
+    }
+    total / f64::from(windows)
+}
+
+struct Goldens {
+    dir: PathBuf,
+    update: bool,
+    failures: Vec<String>,
+}
+
+impl Goldens {
+    // `None` when there is nothing to compare with
+    fn new() -> Option<Goldens> {
+        let goldens = Goldens {
+            dir: Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(imagetools::BACKEND),
+            update: std::env::var_os("UPDATE_GOLDEN").is_some(),
+            failures: Vec::new(),
+        };
+        if !goldens.update && !goldens.dir.is_dir() {
+            eprintln!("Skipped, no goldens in {:?}, see UPDATE_GOLDEN", goldens.dir);
+            return None;
+        }
+        Some(goldens)
+    }
+
+    fn check(&mut self, name: &str, output: &Path) {
+        let golden_path = self.dir.join(format!("{}.png", name));
+        if self.update {
+            let image = imagetools::read_image(output).unwrap();
+            std::fs::create_dir_all(&self.dir).unwrap();
+            std::fs::write(&golden_path, imagetools::encode_image(&image, "png", None).unwrap()).unwrap();
+            eprintln!("Wrote golden {:?}", golden_path);
+            return;
+        }
+        if !golden_path.exists() {
+            self.failures.push(format!("{}: no golden {:?}, see UPDATE_GOLDEN", name, golden_path));
+            return;
+        }
+
+        let (size, pixels) = decode(output);
+        let (golden_size, golden_pixels) = decode(&golden_path);
+        if size != golden_size {
+            self.failures.push(format!("{}: {:?}, golden {:?}", name, size, golden_size));
+            return;
+        }
+
+        let extension = output.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+        let score = ssim(&pixels, &golden_pixels, size);
+        if score < min_ssim(extension) {
+            self.failures.push(format!("{}: SSIM {:.4} below {}", name, score, min_ssim(extension)));
+        }
+    }
+
+    fn finish(self) {
+        assert!(self.failures.is_empty(), "outputs differ from goldens:\n{}", self.failures.join("\n"));
+    }
+}
+
+fn spec(w: Option<u32>, h: Option<u32>, fit: Fit, format: Option<&str>, quality: Option<u8>) -> TransformSpec {
+    TransformSpec {
+        w,
+        h,
+        fit,
+        gravity: Gravity::Center,
+        rotate: None,
+        flip: None,
+        crop: None,
+        grayscale: false,
+        brightness: None,
+        contrast: None,
+        blur: None,
+        sharpen: None,
+        format: format.map(str::to_owned),
+        quality,
+    }
+}
+
+// Renders `spec` of upload `id` next to the uploads and checks it against `name`
+async fn check_transform(goldens: &mut Goldens, uploads_dir: &Path, id: &str, name: &str, spec: &TransformSpec) {
+    let (data, extension) = transform::render_upload(uploads_dir, id, spec).await.unwrap().unwrap();
+    let output = uploads_dir.join(format!("{}.{}", name, extension));
+    std::fs::write(&output, data).unwrap();
+    goldens.check(name, &output);
+}
+
+#[test]
+fn thumbnails() {
+    let dir = ScratchDir::new("golden");
+    let mut goldens = match Goldens::new() {
+        Some(goldens) => goldens,
+        None => return,
+    };
+
+    for (id, format) in store_sources(&dir) {
+        let src = layout::stored_file_path(&*dir, &format!("{}.{}", id, format));
+        let dest = dir.join(format!("thumbnail.{}", format));
+        imagetools::create_thumbnail(&src, &dest, (100, 100), None, None).unwrap();
+        goldens.check(&format!("thumbnail-{}", format), &dest);
+    }
+
+    goldens.finish();
+}
+
+#[actix_rt::test]
+async fn transforms() {
+    let dir = ScratchDir::new("golden");
+    let mut goldens = match Goldens::new() {
+        Some(goldens) => goldens,
+        None => return,
+    };
+
+    let specs = [
+        ("cover-64x64", spec(Some(64), Some(64), Fit::Cover, None, None)),
+        ("contain-80x80", spec(Some(80), Some(80), Fit::Contain, None, None)),
+        ("fill-40x90", spec(Some(40), Some(90), Fit::Fill, None, None)),
+        ("w-50", spec(Some(50), None, Fit::Contain, None, None)),
+        ("h-480", spec(None, Some(480), Fit::Contain, None, None)),
+    ];
+    for (id, format) in store_sources(&dir) {
+        for (name, spec) in &specs {
+            let name = format!("transform-{}-{}", format, name);
+            check_transform(&mut goldens, &dir, &id, &name, spec).await;
+        }
+    }
+
+    goldens.finish();
+}
+
+#[actix_rt::test]
+async fn conversions() {
+    let dir = ScratchDir::new("golden");
+    let mut goldens = match Goldens::new() {
+        Some(goldens) => goldens,
+        None => return,
+    };
+
+    store_sources(&dir);
+    for format in formats() {
+        for quality in &[None, Some(50)] {
+            let spec = spec(Some(160), None, Fit::Contain, Some(format), *quality);
+            let name = match quality {
+                Some(quality) => format!("convert-{}-q{}", format, quality),
+                None => format!("convert-{}", format),
+            };
+            check_transform(&mut goldens, &dir, "goldenpng", &name, &spec).await;
+        }
+    }
+
+    goldens.finish();
+}
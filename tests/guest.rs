@@ -0,0 +1,80 @@
+// Guest upload buckets; alone in its binary, it moves the clock
+mod common;
+
+use std::time::Duration;
+
+use actix_web::{test, App};
+
+use rust_rest_api::auth::ApiKey;
+use rust_rest_api::guest::GuestConfig;
+use rust_rest_api::{clock, http, Config};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+fn upload(token: &str) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri("/upload")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .insert_header(("Content-Type", "image/svg+xml"))
+        .set_payload(SVG)
+}
+
+#[actix_rt::test]
+async fn guest_buckets_are_limited_and_expire() {
+    let dir = ScratchDir::new("guest");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        api_keys: vec![serde_json::from_value::<ApiKey>(serde_json::json!({"key": "secret", "name": "app"})).unwrap()],
+        guest: GuestConfig {
+            enabled: true,
+            ttl_secs: 600,
+            max_uploads: 1,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/guest/buckets").to_request()).await;
+    assert_eq!(response.status(), 201);
+    let bucket: serde_json::Value = test::read_body_json(response).await;
+    let token = bucket["token"].as_str().unwrap().to_owned();
+    assert!(bucket["expires_at"].as_u64().unwrap() <= clock::unix_now() + 600);
+
+    assert_eq!(
+        test::call_service(&app, upload(&token).to_request()).await.status(),
+        200
+    );
+    let response = test::call_service(&app, upload(&token).to_request()).await;
+    assert_eq!(response.status(), 413);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body["code"], "guest_quota_exceeded");
+    assert_eq!(
+        test::call_service(&app, upload("made-up").to_request()).await.status(),
+        401
+    );
+
+    // Uploads only, no imports
+    let import = test::TestRequest::post()
+        .uri("/imports")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .insert_header(("Content-Type", "text/csv"))
+        .set_payload("url\nhttp://127.0.0.1:9/a.png\n")
+        .to_request();
+    assert_eq!(test::call_service(&app, import).await.status(), 401);
+
+    let response = test::call_service(&app, test::TestRequest::post().uri("/guest/buckets").to_request()).await;
+    let bucket: serde_json::Value = test::read_body_json(response).await;
+    let token = bucket["token"].as_str().unwrap().to_owned();
+    clock::advance(Duration::from_secs(601));
+    let response = test::call_service(&app, upload(&token).to_request()).await;
+    assert_eq!(response.status(), 401);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body["code"], "invalid_guest_token");
+}
@@ -0,0 +1,85 @@
+// Liveness and readiness probes
+mod common;
+
+use actix_web::{test, App};
+
+use rust_rest_api::health::readiness;
+use rust_rest_api::{http, Config};
+
+use common::ScratchDir;
+
+fn config(dir: &ScratchDir) -> Config {
+    Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        ..Default::default()
+    }
+}
+
+#[actix_rt::test]
+async fn readiness_checks_the_uploads_dir_and_free_space() {
+    let dir = ScratchDir::new("health_checks");
+    let config = config(&dir);
+    std::fs::create_dir_all(&config.uploads_dir).unwrap();
+    let ready = readiness(&config).await;
+    assert!(ready.ready);
+    assert!(ready.checks.values().all(|check| check.ok && check.error.is_none()));
+    // The probe file is gone again
+    assert_eq!(std::fs::read_dir(&config.uploads_dir).unwrap().count(), 0);
+
+    let config = Config {
+        uploads_dir: dir.join("missing"),
+        min_free_disk_space: u64::MAX,
+        ..config
+    };
+    let ready = readiness(&config).await;
+    assert!(!ready.ready);
+    assert!(!ready.checks["uploads_dir_writable"].ok);
+    assert!(!ready.checks["disk_space"].ok);
+}
+
+#[actix_rt::test]
+async fn readyz_answers_503_until_uploads_can_be_taken() {
+    let dir = ScratchDir::new("health");
+    let config = config(&dir);
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let response = test::call_service(&app, test::TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body["ready"], true);
+    assert_eq!(body["checks"]["uploads_dir_writable"]["ok"], true);
+    assert_eq!(body["checks"]["disk_space"]["ok"], true);
+
+    // A volume that filled up
+    let full = Config {
+        min_free_disk_space: u64::MAX,
+        ..config.clone()
+    };
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, full.clone()))).await;
+    let response = test::call_service(&app, test::TestRequest::get().uri("/readyz").to_request()).await;
+    assert_eq!(response.status(), 503);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body["ready"], false);
+    assert_eq!(body["checks"]["uploads_dir_writable"]["ok"], true);
+    assert_eq!(body["checks"]["disk_space"]["ok"], false);
+    assert!(body["checks"]["disk_space"]["error"]
+        .as_str()
+        .unwrap()
+        .contains("required"));
+    let response = test::call_service(
+        &app,
+        test::TestRequest::default()
+            .method(actix_web::http::Method::HEAD)
+            .uri("/readyz")
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 503);
+
+    // Alive all the same
+    let response = test::call_service(&app, test::TestRequest::get().uri("/healthz").to_request()).await;
+    assert_eq!(response.status(), 200);
+}
@@ -0,0 +1,36 @@
+// Mounting `http::configure` in an app of one's own, next to its routes
+mod common;
+
+use actix_web::{test, web, App, HttpResponse};
+
+use rust_rest_api::{http, Config};
+
+use common::ScratchDir;
+
+#[actix_rt::test]
+async fn embeds_next_to_other_routes() {
+    let dir = ScratchDir::new("http");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+
+    let app = test::init_service(
+        App::new()
+            .route("/mine", web::get().to(|| async { HttpResponse::Ok().body("mine") }))
+            .configure(|cfg| http::configure(cfg, config)),
+    )
+    .await;
+
+    let response = test::call_service(&app, test::TestRequest::get().uri("/mine").to_request()).await;
+    assert_eq!(test::read_body(response).await, "mine");
+
+    let response = test::call_service(&app, test::TestRequest::get().uri("/healthz").to_request()).await;
+    assert!(response.status().is_success());
+
+    let response = test::call_service(&app, test::TestRequest::get().uri("/missing-upload").to_request()).await;
+    assert_eq!(response.status(), 404);
+}
@@ -0,0 +1,107 @@
+mod common;
+
+use actix_web::{test, App};
+use sha2::{Digest, Sha256};
+
+use rust_rest_api::idempotency::{self, Begin, IdempotencyConfig, IdempotencyError, IdempotencyKeys};
+use rust_rest_api::{http, metadata, Config};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+fn upload(key: &str, query: &str) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri(&format!("/upload{}", query))
+        .insert_header(("Content-Type", "image/svg+xml"))
+        .insert_header(("Idempotency-Key", key))
+        .set_payload(SVG)
+}
+
+#[actix_rt::test]
+async fn retries_get_the_first_response() {
+    let dir = ScratchDir::new("idempotency_http");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let response = test::call_service(&app, upload("photo-1", "").to_request()).await;
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().get("idempotent-replayed").is_none());
+    let first = test::read_body(response).await;
+
+    let response = test::call_service(&app, upload("photo-1", "").to_request()).await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("idempotent-replayed").unwrap(), "true");
+    let sha256 = hex::encode(Sha256::digest(SVG));
+    assert_eq!(response.headers().get("x-content-sha256").unwrap(), sha256.as_str());
+    assert_eq!(test::read_body(response).await, first);
+    let (stored, _) = metadata::list(&config.uploads_dir, &Default::default(), None, 10)
+        .await
+        .unwrap();
+    assert_eq!(stored.len(), 1);
+
+    // The same key for another request
+    let response = test::call_service(&app, upload("photo-1", "?ttl=60").to_request()).await;
+    assert_eq!(response.status(), 422);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body["code"], "idempotency_key_reused");
+
+    // A failure isn't kept, the request may be made again
+    let wrong = upload("photo-2", "").insert_header(("X-Content-SHA256", "00".repeat(32)));
+    assert_eq!(test::call_service(&app, wrong.to_request()).await.status(), 422);
+    let right = upload("photo-2", "").insert_header(("X-Content-SHA256", sha256.as_str()));
+    let response = test::call_service(&app, right.to_request()).await;
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().get("idempotent-replayed").is_none());
+
+    let response = test::call_service(&app, upload("bad key", "").to_request()).await;
+    assert_eq!(response.status(), 400);
+}
+
+#[actix_rt::test]
+async fn keys_are_held_scoped_and_expire() {
+    let dir = ScratchDir::new("idempotency_keys");
+    let keys = IdempotencyKeys::default();
+    let config = IdempotencyConfig::default();
+    let fingerprint = idempotency::fingerprint("", &[Some("image/png"), Some("100")]);
+
+    let guard = match keys.begin(&*dir, &config, "app", "k", &fingerprint).await.unwrap() {
+        Begin::Fresh(guard) => guard,
+        Begin::Replay(_) => panic!("nothing was kept yet"),
+    };
+    let err = keys
+        .begin(&*dir, &config, "app", "k", &fingerprint)
+        .await
+        .err()
+        .unwrap();
+    assert!(matches!(err.downcast_ref(), Some(IdempotencyError::InProgress)));
+    // Another uploader's key of the same name
+    assert!(matches!(
+        keys.begin(&*dir, &config, "other", "k", &fingerprint).await.unwrap(),
+        Begin::Fresh(_)
+    ));
+
+    guard
+        .finish(200, Some("application/json".to_owned()), None, r#"["id"]"#.to_owned())
+        .await
+        .unwrap();
+    match keys.begin(&*dir, &config, "app", "k", &fingerprint).await.unwrap() {
+        Begin::Replay(stored) => assert_eq!((stored.status, stored.body.as_str()), (200, r#"["id"]"#)),
+        Begin::Fresh(_) => panic!("the response wasn't kept"),
+    }
+
+    assert_eq!(idempotency::reap(&*dir, &config).await.unwrap(), 0);
+    let expired = IdempotencyConfig { window_secs: 0 };
+    assert!(matches!(
+        keys.begin(&*dir, &expired, "app", "k", &fingerprint).await.unwrap(),
+        Begin::Fresh(_)
+    ));
+    assert_eq!(idempotency::reap(&*dir, &expired).await.unwrap(), 1);
+}
@@ -0,0 +1,178 @@
+// Manifest imports against a local origin
+mod common;
+
+use std::time::Duration;
+
+use actix_web::{test, web, App, HttpResponse, HttpServer};
+
+use rust_rest_api::auth::ApiKey;
+use rust_rest_api::fetch::FetchConfig;
+use rust_rest_api::{http, metadata, Config, OutputOptions};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+// Serves `SVG` at every path, returns the base URL
+fn origin() -> String {
+    let server = HttpServer::new(|| {
+        App::new().default_service(web::to(|| async {
+            HttpResponse::Ok().content_type("image/svg+xml").body(SVG)
+        }))
+    })
+    .workers(1)
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let base = format!("http://{}", server.addrs()[0]);
+    actix_rt::spawn(server.run());
+    base
+}
+
+#[actix_rt::test]
+async fn rows_keep_their_tags_and_presets() {
+    let dir = ScratchDir::new("import_presets");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        fetch: FetchConfig {
+            allow_private_addresses: true,
+            ..Default::default()
+        },
+        import_presets: [
+            (
+                "bare".to_owned(),
+                OutputOptions {
+                    thumbnail: Some(false),
+                    ..Default::default()
+                },
+            ),
+            (
+                "broken".to_owned(),
+                OutputOptions {
+                    quality: Some(0),
+                    ..Default::default()
+                },
+            ),
+        ]
+        .iter()
+        .cloned()
+        .collect(),
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let base = origin();
+    let manifest = format!(
+        "url,tags,preset\n{0}/a.svg,cats; kittens,bare\n{0}/b.svg,dogs,glossy\n{0}/c.svg,,broken\n{0}/d.svg,,\n",
+        base
+    );
+    let response = test::call_service(
+        &app,
+        test::TestRequest::post()
+            .uri("/imports")
+            .insert_header(("Content-Type", "text/csv"))
+            .set_payload(manifest)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 202);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let location = format!("/imports/{}", body["id"].as_str().unwrap());
+
+    let job = loop {
+        let response = test::call_service(&app, test::TestRequest::get().uri(&location).to_request()).await;
+        let job: serde_json::Value = test::read_body_json(response).await;
+        if job["state"] == "finished" {
+            break job;
+        }
+        actix_rt::time::sleep(Duration::from_millis(20)).await;
+    };
+    assert_eq!((job["succeeded"].as_u64(), job["failed"].as_u64()), (Some(2), Some(2)));
+
+    let results = job["results"].as_array().unwrap();
+    let id = results[0]["id"].as_str().unwrap();
+    let stored = metadata::load(&config.uploads_dir, id).await.unwrap().unwrap();
+    assert_eq!(stored.tags, vec!["cats", "kittens"]);
+
+    // Never fetched
+    assert_eq!(results[1]["id"], serde_json::Value::Null);
+    assert_eq!(results[1]["error"], "unknown preset \"glossy\"");
+
+    assert_eq!(results[2]["error"], "quality must be within 1-100");
+
+    let id = results[3]["id"].as_str().unwrap();
+    assert!(metadata::load(&config.uploads_dir, id)
+        .await
+        .unwrap()
+        .unwrap()
+        .tags
+        .is_empty());
+}
+
+#[actix_rt::test]
+async fn jobs_are_seen_by_their_creators_only() {
+    let dir = ScratchDir::new("import_owners");
+    let key = |key: &str, name: &str, admin: bool| {
+        serde_json::from_value::<ApiKey>(serde_json::json!({"key": key, "name": name, "admin": admin})).unwrap()
+    };
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        api_keys: vec![
+            key("secret", "app", false),
+            key("other-secret", "other", false),
+            key("admin-secret", "ops", true),
+        ],
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let response = test::call_service(
+        &app,
+        test::TestRequest::post()
+            .uri("/imports")
+            .insert_header(("Authorization", "Bearer secret"))
+            .insert_header(("Content-Type", "text/csv"))
+            .set_payload("url\nhttp://127.0.0.1:9/a.svg\n")
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 202);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let location = format!("/imports/{}", body["id"].as_str().unwrap());
+
+    for uri in [location.clone(), format!("{}/report", location)] {
+        let get = |token: Option<&str>| {
+            let request = test::TestRequest::get().uri(&uri);
+            match token {
+                Some(token) => request.insert_header(("Authorization", format!("Bearer {}", token))),
+                None => request,
+            }
+            .to_request()
+        };
+        assert_eq!(test::call_service(&app, get(None)).await.status(), 401, "{}", uri);
+        assert_eq!(
+            test::call_service(&app, get(Some("other-secret"))).await.status(),
+            404,
+            "{}",
+            uri
+        );
+        assert_eq!(
+            test::call_service(&app, get(Some("secret"))).await.status(),
+            200,
+            "{}",
+            uri
+        );
+        assert_eq!(
+            test::call_service(&app, get(Some("admin-secret"))).await.status(),
+            200,
+            "{}",
+            uri
+        );
+    }
+}
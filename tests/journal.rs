@@ -0,0 +1,68 @@
+mod common;
+
+use bytes::Bytes;
+use futures_util::stream;
+
+use rust_rest_api::finalize::Durability;
+use rust_rest_api::{journal, layout, metadata, upload_image, Config, UploadOptions};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+fn body() -> impl futures_util::Stream<Item = std::io::Result<Bytes>> + Unpin {
+    stream::iter(vec![Ok(Bytes::from_static(SVG))])
+}
+
+#[actix_rt::test]
+async fn strict_uploads_are_journaled_while_in_progress() {
+    let dir = ScratchDir::new("journal_uploads");
+    let config = Config {
+        durability: Durability::Strict,
+        ..Default::default()
+    };
+    let mut options = UploadOptions::from_config(&config);
+    assert!(options.fsync_dir);
+
+    let uploaded = upload_image(body(), &*dir, "svg", &options).await.unwrap();
+    assert!(uploaded.path.is_file());
+    assert!(journal::pending(&*dir).await.unwrap().is_empty());
+
+    options.expected_sha256 = Some("0".repeat(64));
+    assert!(upload_image(body(), &*dir, "svg", &options).await.is_err());
+    assert!(journal::pending(&*dir).await.unwrap().is_empty());
+}
+
+#[actix_rt::test]
+async fn interrupted_uploads_are_rolled_back() {
+    let dir = ScratchDir::new("journal_recover");
+    let meta = dir.join("meta");
+    std::fs::create_dir_all(&meta).unwrap();
+
+    // Cut short after its metadata was saved, before the file was in place
+    let (lost, kept) = ("journallost1", "journalkept1");
+    let lost_tmp = layout::shard_dir(&*dir, lost).join(format!("{}.tmp", lost));
+    std::fs::create_dir_all(lost_tmp.parent().unwrap()).unwrap();
+    std::fs::write(&lost_tmp, SVG).unwrap();
+    std::fs::write(metadata::metadata_path(&*dir, lost), br#"{"extension": "svg"}"#).unwrap();
+    journal::begin(&dir, lost, &lost_tmp).unwrap();
+
+    // In place, only its entry was left
+    let kept_path = layout::shard_dir(&*dir, kept).join(format!("{}.svg", kept));
+    std::fs::create_dir_all(kept_path.parent().unwrap()).unwrap();
+    std::fs::write(&kept_path, SVG).unwrap();
+    std::fs::write(metadata::metadata_path(&*dir, kept), br#"{"extension": "svg"}"#).unwrap();
+    journal::begin(&dir, kept, &kept_path.with_extension("tmp")).unwrap();
+
+    let pending = journal::pending(&*dir).await.unwrap();
+    assert_eq!(pending.len(), 2);
+    assert!(pending.iter().all(|entry| entry.tmp_path.is_relative()));
+
+    assert_eq!(journal::recover(&*dir).await.unwrap(), 1);
+    assert!(!lost_tmp.exists());
+    assert!(!metadata::metadata_path(&*dir, lost).exists());
+    assert!(kept_path.is_file());
+    assert!(metadata::metadata_path(&*dir, kept).is_file());
+    assert!(journal::pending(&*dir).await.unwrap().is_empty());
+}
@@ -0,0 +1,154 @@
+// JSON uploads read as the body streams in
+mod common;
+
+use actix_web::{test, App};
+use bytes::Bytes;
+use futures_util::stream;
+use serde_json::json;
+
+use rust_rest_api::json_stream::UploadItems;
+use rust_rest_api::{http, Config};
+
+use common::ScratchDir;
+
+const SVG: &str = r#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+// Well over the JSON limit once encoded
+fn large_svg() -> String {
+    format!("{}{}", SVG, " ".repeat(1000))
+}
+
+fn spool_files(dir: &std::path::Path) -> Vec<String> {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with(".base64-"))
+        .collect()
+}
+
+#[actix_rt::test]
+async fn base64_values_stream_past_the_json_limit() {
+    let dir = ScratchDir::new("json_upload");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        max_json_payload_size: 512,
+        max_file_size: 4096,
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+    let upload = |body: String| {
+        test::TestRequest::post()
+            .uri("/upload")
+            .insert_header(("Content-Type", "application/json"))
+            .set_payload(body)
+            .to_request()
+    };
+
+    // Line-wrapped, and the settings after the data
+    let wrapped = base64::encode(large_svg())
+        .as_bytes()
+        .chunks(76)
+        .map(|line| std::str::from_utf8(line).unwrap())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let body = json!([{"base64": wrapped, "filename": "large.svg", "metadata": {"album": "a"}}]).to_string();
+    assert!(body.len() > 1024);
+    let response = test::call_service(&app, upload(body)).await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let id = body[0].as_str().unwrap().to_owned();
+    let response = test::call_service(
+        &app,
+        test::TestRequest::get()
+            .uri(&format!("/images/{}/metadata", id))
+            .to_request(),
+    )
+    .await;
+    let metadata: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(metadata["original_filename"], "large.svg");
+
+    // The rest of the body is still held to the limit
+    let body = json!([{"base64": base64::encode(SVG), "metadata": {"album": "a".repeat(600)}}]).to_string();
+    let response = test::call_service(&app, upload(body)).await;
+    assert_eq!(response.status(), 413);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body["code"], "body_too_large");
+
+    // And the decoded data to the file size limit
+    let body = json!([{"base64": base64::encode(format!("{}{}", SVG, " ".repeat(5000)))}]).to_string();
+    let response = test::call_service(&app, upload(body)).await;
+    assert_eq!(response.status(), 413);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(
+        (body["code"].as_str(), body["limit"].as_u64()),
+        (Some("body_too_large"), Some(4096))
+    );
+
+    // The first item is kept when a later one is broken off
+    let first = json!({"base64": base64::encode(SVG)}).to_string();
+    for broken in [
+        format!("[{}, {{\"base64\": \"{}", first, base64::encode(SVG)),
+        format!("[{}, {{\"base64\": \"not base64!\"}}]", first),
+        format!(
+            "[{}, {{\"base64\": \"{}\", \"ttl\": \"soon\"}}]",
+            first,
+            base64::encode(SVG)
+        ),
+        format!("[{}] trailing", first),
+    ] {
+        let response = test::call_service(&app, upload(broken.clone())).await;
+        assert_eq!(response.status(), 400, "{}", broken);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(body["code"], "invalid_body", "{}", broken);
+        assert_eq!(body["uploaded"].as_array().unwrap().len(), 1, "{}", broken);
+    }
+
+    for body in ["[{\"base64\": \"\"}]", "{\"base64\": \"\"}", "[]"] {
+        let response = test::call_service(&app, upload(body.to_owned())).await;
+        assert_eq!(response.status(), 400, "{}", body);
+    }
+
+    assert!(spool_files(&config.uploads_dir).is_empty());
+}
+
+#[actix_rt::test]
+async fn items_are_read_however_the_body_is_split() {
+    let dir = ScratchDir::new("json_upload_items");
+    let data = large_svg();
+    let body = json!([
+        {"url": "http://127.0.0.1:9/a.png", "headers": {"x-a": "[]{},:"}},
+        {"ttl": 60, "base64": format!("data:image/svg+xml;base64,{}", base64::encode(&data)), "sha256": null},
+    ])
+    .to_string()
+    // As some encoders send it
+    .replace("image/svg", "image\\/svg");
+
+    for chunk_size in [1, 7, 64, body.len()] {
+        let chunks: Vec<Result<Bytes, std::io::Error>> = body
+            .as_bytes()
+            .chunks(chunk_size)
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        let mut items = UploadItems::new(stream::iter(chunks), &dir, 1024, None);
+
+        let (object, spooled) = items.next().await.unwrap().unwrap();
+        assert_eq!(
+            object,
+            json!({"url": "http://127.0.0.1:9/a.png", "headers": {"x-a": "[]{},:"}})
+        );
+        assert!(spooled.is_none());
+
+        let (object, spooled) = items.next().await.unwrap().unwrap();
+        assert_eq!(object, json!({"ttl": 60, "sha256": null}));
+        let spooled = spooled.unwrap();
+        assert_eq!(spooled.declared_type.as_deref(), Some("image/svg+xml"));
+        assert_eq!(std::fs::read(&spooled.path).unwrap(), data.as_bytes());
+        drop(spooled);
+
+        assert!(items.next().await.unwrap().is_none());
+        assert!(spool_files(&dir).is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+mod common;
+
+use actix_web::{test, App};
+
+use rust_rest_api::auth::{ApiKey, KeyLimits};
+use rust_rest_api::metadata::{self, ImageMetadata};
+use rust_rest_api::quota::{self, QuotaError};
+use rust_rest_api::{clock, http, Config};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+fn api_key(value: serde_json::Value) -> ApiKey {
+    serde_json::from_value(value).unwrap()
+}
+
+fn image_metadata(id: &str, owner: &str, size: u64, created_at: u64) -> ImageMetadata {
+    serde_json::from_value(serde_json::json!({
+        "id": id,
+        "extension": "png",
+        "size": size,
+        "width": 1,
+        "height": 1,
+        "created_at": created_at,
+        "owner": owner,
+    }))
+    .unwrap()
+}
+
+fn upload(key: &str) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri("/upload")
+        .insert_header(("Authorization", format!("Bearer {}", key)))
+        .insert_header(("Content-Type", "image/svg+xml"))
+        .set_payload(SVG)
+}
+
+// Usage is process-wide, so one test walks through it all
+#[actix_rt::test]
+async fn limits_are_enforced_and_reported() {
+    let dir = ScratchDir::new("key_limits");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        api_keys: vec![
+            api_key(serde_json::json!({"key": "admin-secret", "name": "ops", "admin": true})),
+            api_key(serde_json::json!({
+                "key": "app-secret",
+                "name": "app",
+                "limits": {"max_uploads_per_day": 1, "max_file_size": 1000},
+            })),
+            api_key(serde_json::json!({"key": "tiny-secret", "name": "tiny", "limits": {"max_file_size": 10}})),
+        ],
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    assert_eq!(
+        test::call_service(&app, upload("app-secret").to_request())
+            .await
+            .status(),
+        200
+    );
+    let response = test::call_service(&app, upload("app-secret").to_request()).await;
+    assert_eq!(response.status(), 429);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(
+        (body["code"].as_str(), body["limit"].as_u64()),
+        (Some("daily_upload_limit"), Some(1))
+    );
+
+    let response = test::call_service(&app, upload("tiny-secret").to_request()).await;
+    assert_eq!(response.status(), 413);
+
+    // Tus uploads and imports are held to them as well
+    let tus = |key: &str, length: usize| {
+        test::TestRequest::post()
+            .uri("/upload/tus")
+            .insert_header(("Authorization", format!("Bearer {}", key)))
+            .insert_header(("Tus-Resumable", "1.0.0"))
+            .insert_header(("Upload-Length", length.to_string()))
+            .to_request()
+    };
+    assert_eq!(test::call_service(&app, tus("app-secret", SVG.len())).await.status(), 429);
+    assert_eq!(test::call_service(&app, tus("tiny-secret", SVG.len())).await.status(), 413);
+    let import = test::TestRequest::post()
+        .uri("/imports")
+        .insert_header(("Authorization", "Bearer app-secret"))
+        .insert_header(("Content-Type", "text/csv"))
+        .set_payload("url\nhttp://127.0.0.1:9/a.png\n")
+        .to_request();
+    assert_eq!(test::call_service(&app, import).await.status(), 429);
+
+    let usage = |key: &str, name: &str| {
+        test::TestRequest::get()
+            .uri(&format!("/admin/keys/{}/usage", name))
+            .insert_header(("Authorization", format!("Bearer {}", key)))
+            .to_request()
+    };
+    let response = test::call_service(&app, usage("admin-secret", "app")).await;
+    assert_eq!(response.status(), 200);
+    let report: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(report["uploads_today"], 1);
+    assert_eq!(report["files"], 1);
+    assert_eq!(report["bytes"], SVG.len());
+    assert_eq!(report["max_uploads_per_day"], 1);
+    assert!(report["resets_at"].as_u64().unwrap() > clock::unix_now());
+    assert_eq!(
+        test::call_service(&app, usage("admin-secret", "nobody")).await.status(),
+        404
+    );
+    assert_eq!(test::call_service(&app, usage("app-secret", "app")).await.status(), 401);
+
+    // Counted from the metadata: re-saves and removals don't change the
+    // day's count, earlier days don't count
+    let limits = KeyLimits {
+        max_uploads_per_day: 2,
+        max_total_bytes: 1000,
+        max_file_size: 0,
+    };
+    let now = clock::unix_now();
+    metadata::save(
+        &config.uploads_dir,
+        &image_metadata("old", "bulk", 100, now - 2 * 24 * 3600),
+    )
+    .await
+    .unwrap();
+    metadata::save(&config.uploads_dir, &image_metadata("new", "bulk", 100, now))
+        .await
+        .unwrap();
+    metadata::save(&config.uploads_dir, &image_metadata("new", "bulk", 100, now))
+        .await
+        .unwrap();
+    assert!(quota::check_key(&limits, "bulk", 800).is_ok());
+    assert!(matches!(
+        quota::check_key(&limits, "bulk", 801),
+        Err(QuotaError::Key(_, 1000))
+    ));
+    metadata::save(&config.uploads_dir, &image_metadata("newer", "bulk", 100, now))
+        .await
+        .unwrap();
+    metadata::remove(&config.uploads_dir, "newer").await.unwrap();
+    assert!(matches!(
+        quota::check_key(&limits, "bulk", 0),
+        Err(QuotaError::KeyUploads(_, 2))
+    ));
+    assert_eq!(quota::key_report("bulk", &limits).uploads_today, 2);
+}
@@ -0,0 +1,67 @@
+// Paging through GET /images
+mod common;
+
+use std::collections::BTreeSet;
+
+use actix_web::{test, App};
+
+use rust_rest_api::{http, Config};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+#[actix_rt::test]
+async fn listings_page_through_every_upload_once() {
+    let dir = ScratchDir::new("listing");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let mut uploaded = BTreeSet::new();
+    for _ in 0..3 {
+        let response = test::call_service(
+            &app,
+            test::TestRequest::post()
+                .uri("/upload")
+                .insert_header(("Content-Type", "image/svg+xml"))
+                .set_payload(SVG)
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        uploaded.insert(body[0].as_str().unwrap().to_owned());
+    }
+
+    let list = |query: &str| test::TestRequest::get().uri(&format!("/images?{}", query)).to_request();
+    let mut listed = BTreeSet::new();
+    let mut pages = 0;
+    let mut query = "limit=2".to_owned();
+    loop {
+        let body: serde_json::Value = test::read_body_json(test::call_service(&app, list(&query)).await).await;
+        for item in body["items"].as_array().unwrap() {
+            assert_eq!(item["mime"], "image/svg+xml");
+            assert_eq!((item["width"].as_u64(), item["height"].as_u64()), (Some(4), Some(2)));
+            assert!(listed.insert(item["id"].as_str().unwrap().to_owned()));
+        }
+        pages += 1;
+        match body["next_cursor"].as_str() {
+            Some(cursor) => query = format!("limit=2&cursor={}", cursor),
+            None => break,
+        }
+    }
+    assert_eq!((listed, pages), (uploaded, 2));
+
+    let body: serde_json::Value = test::read_body_json(test::call_service(&app, list("mime=image/png")).await).await;
+    assert!(body["items"].as_array().unwrap().is_empty());
+    let body: serde_json::Value =
+        test::read_body_json(test::call_service(&app, list("mime=image/svg%2Bxml")).await).await;
+    assert_eq!(body["items"].as_array().unwrap().len(), 3);
+}
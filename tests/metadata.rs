@@ -0,0 +1,75 @@
+// What GET /images/{id}/metadata shows to whom
+mod common;
+
+use actix_web::{test, App};
+
+use rust_rest_api::auth::ApiKey;
+use rust_rest_api::{http, Config};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+fn key(key: &str, name: &str, admin: bool) -> ApiKey {
+    serde_json::from_value(serde_json::json!({"key": key, "name": name, "admin": admin})).unwrap()
+}
+
+#[actix_rt::test]
+async fn only_owners_and_admins_see_the_whole_record() {
+    let dir = ScratchDir::new("metadata_view");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        api_keys: vec![
+            key("secret", "app", false),
+            key("other-secret", "other", false),
+            key("admin-secret", "ops", true),
+        ],
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let response = test::call_service(
+        &app,
+        test::TestRequest::post()
+            .uri("/upload")
+            .insert_header(("Authorization", "Bearer secret"))
+            .insert_header(("Content-Type", "image/svg+xml"))
+            .set_payload(SVG)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let uri = format!("/images/{}/metadata", body[0].as_str().unwrap());
+
+    let get = |token: &str| {
+        test::TestRequest::get()
+            .uri(&uri)
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .to_request()
+    };
+    let response = test::call_service(&app, get("other-secret")).await;
+    assert_eq!(response.status(), 200);
+    let public: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(
+        (public["width"].as_u64(), public["height"].as_u64()),
+        (Some(4), Some(2))
+    );
+    for field in ["owner", "status", "moderation_scores"] {
+        assert!(public.get(field).is_none(), "{}", field);
+    }
+
+    for token in ["secret", "admin-secret"] {
+        let response = test::call_service(&app, get(token)).await;
+        assert_eq!(response.headers().get("cache-control").unwrap(), "private, no-store");
+        let full: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(
+            (full["owner"].as_str(), full["status"].as_str()),
+            (Some("app"), Some("available"))
+        );
+    }
+}
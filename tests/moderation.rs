@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use rust_rest_api::metadata::{ImageMetadata, UploadState};
+use rust_rest_api::moderation::{self, ModerationConfig, ScanFuture, Scanner, Scores, Threshold};
+
+fn image_metadata(id: &str) -> ImageMetadata {
+    serde_json::from_value(serde_json::json!({
+        "id": id,
+        "extension": "png",
+        "size": 1,
+        "width": 1,
+        "height": 1,
+        "created_at": 0,
+    }))
+    .unwrap()
+}
+
+// Scores by upload id, fails for the others
+struct FixedScanner(BTreeMap<&'static str, Scores>);
+
+impl Scanner for FixedScanner {
+    fn name(&self) -> &str {
+        "fixed"
+    }
+
+    fn scan<'a>(&'a self, id: &'a str, _path: &'a Path, mime: &'a str) -> ScanFuture<'a> {
+        Box::pin(async move {
+            assert_eq!(mime, "image/png");
+            self.0
+                .get(id)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("no scores for {}", id))
+        })
+    }
+}
+
+fn scores(pairs: &[(&str, f32)]) -> Scores {
+    pairs.iter().map(|(label, score)| (label.to_string(), *score)).collect()
+}
+
+// The scanners are process-wide, so one test walks through it all
+#[actix_rt::test]
+async fn scores_hold_and_reject() {
+    let mut config = ModerationConfig::default();
+    config.thresholds.insert(
+        "nsfw".to_owned(),
+        Threshold {
+            quarantine: Some(0.5),
+            reject: Some(0.9),
+        },
+    );
+    let path = Path::new("unused.png");
+
+    // Nothing registered, nothing recorded
+    let mut clean = image_metadata("clean");
+    moderation::scan(&config, path, &mut clean).await.unwrap();
+    assert!(clean.moderation_scores.is_empty());
+
+    moderation::register(Arc::new(FixedScanner(
+        vec![
+            ("clean", scores(&[("nsfw", 0.1), ("violence", 0.2)])),
+            ("racy", scores(&[("nsfw", 0.3)])),
+            ("explicit", scores(&[("nsfw", 0.95)])),
+        ]
+        .into_iter()
+        .collect(),
+    )));
+    moderation::register(Arc::new(FixedScanner(
+        vec![
+            ("clean", scores(&[("nsfw", 0.05)])),
+            ("racy", scores(&[("nsfw", 0.7)])),
+            ("explicit", scores(&[])),
+            ("broken", scores(&[])),
+        ]
+        .into_iter()
+        .collect(),
+    )));
+
+    // The highest score of each label is kept
+    let mut clean = image_metadata("clean");
+    moderation::scan(&config, path, &mut clean).await.unwrap();
+    assert_eq!(clean.moderation_scores, scores(&[("nsfw", 0.1), ("violence", 0.2)]));
+    assert_eq!(clean.status, UploadState::Available);
+    assert_eq!(clean.moderation_hold, None);
+
+    let mut racy = image_metadata("racy");
+    moderation::scan(&config, path, &mut racy).await.unwrap();
+    assert_eq!(racy.status, UploadState::Quarantined);
+    assert!(racy.moderation_hold.unwrap().contains("nsfw"));
+
+    let mut explicit = image_metadata("explicit");
+    let rejected = moderation::scan(&config, path, &mut explicit).await.unwrap_err();
+    assert_eq!((rejected.label.as_str(), rejected.threshold), ("nsfw", 0.9));
+
+    // A failing scanner holds the upload rather than letting it through
+    let mut broken = image_metadata("broken");
+    moderation::scan(&config, path, &mut broken).await.unwrap();
+    assert_eq!(broken.status, UploadState::Pending);
+    assert!(broken.moderation_hold.unwrap().contains("fixed"));
+}
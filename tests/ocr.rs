@@ -0,0 +1,82 @@
+mod common;
+
+use actix_web::{test, App};
+
+use rust_rest_api::metadata::{self, ImageMetadata};
+use rust_rest_api::ocr::{self, OcrConfig, OcrError};
+use rust_rest_api::{http, Config};
+
+use common::ScratchDir;
+
+// Recognized before, so no Tesseract is needed
+fn image_metadata(id: &str) -> ImageMetadata {
+    serde_json::from_value(serde_json::json!({
+        "id": id,
+        "extension": "png",
+        "size": 1,
+        "width": 1,
+        "height": 1,
+        "created_at": 0,
+        "ocr": {"eng": {"text": "Hello", "confidence": 91.0}},
+    }))
+    .unwrap()
+}
+
+#[actix_rt::test]
+async fn results_are_cached_per_language() {
+    let dir = ScratchDir::new("ocr");
+    let config = OcrConfig {
+        enabled: true,
+        languages: vec!["eng".to_owned(), "deu".to_owned()],
+        ..Default::default()
+    };
+    assert!(config.check_language("eng+deu").is_ok());
+    assert!(config.check_language("eng+fra").is_err());
+
+    metadata::save(&*dir, &image_metadata("scan")).await.unwrap();
+    let (result, cached) = ocr::extract(&*dir, "scan", "eng", &config).await.unwrap().unwrap();
+    assert!(cached);
+    assert_eq!(result.text, "Hello");
+
+    let err = ocr::extract(&*dir, "scan", "fra", &config).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(OcrError::UnknownLanguage(_))));
+    // Not cached and no such file
+    assert!(ocr::extract(&*dir, "scan", "deu", &config).await.unwrap().is_none());
+}
+
+#[actix_rt::test]
+async fn endpoint_depends_on_the_build() {
+    let dir = ScratchDir::new("ocr_endpoint");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        ocr: OcrConfig {
+            enabled: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    metadata::save(&config.uploads_dir, &image_metadata("scan"))
+        .await
+        .unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let response = test::call_service(&app, test::TestRequest::get().uri("/images/scan/ocr").to_request()).await;
+    if !ocr::AVAILABLE {
+        assert_eq!(response.status(), 501);
+        return;
+    }
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body["text"], "Hello");
+    assert_eq!(body["cached"], true);
+
+    let response = test::call_service(
+        &app,
+        test::TestRequest::get().uri("/images/scan/ocr?lang=deu").to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 400);
+}
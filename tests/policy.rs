@@ -0,0 +1,104 @@
+mod common;
+
+use std::collections::BTreeMap;
+
+use rust_rest_api::policy::{self, Policy, Subject};
+
+use common::ScratchDir;
+
+const POLICY: &str = r#"
+[[rules]]
+name = "small gifs"
+mime = ["image/gif"]
+max_size = 1000
+
+[[rules]]
+name = "photo sizes"
+mime = ["image/*"]
+min_width = 16
+max_width = 4000
+
+[[rules]]
+name = "acme alt text"
+tenants = ["acme"]
+required_metadata = ["alt"]
+
+[[rules]]
+name = "no webp for beta"
+tenants = ["beta"]
+mime = ["image/webp"]
+deny = true
+
+[[tests]]
+name = "large gif"
+mime = "image/gif"
+size = 2000
+width = 100
+height = 100
+expect = "reject"
+rule = "small gifs"
+
+[[tests]]
+mime = "image/png"
+size = 2000
+width = 100
+height = 100
+tenant = "acme"
+metadata = { alt = "A cat" }
+expect = "accept"
+
+[[tests]]
+name = "wrong rule"
+mime = "image/webp"
+width = 100
+tenant = "beta"
+expect = "reject"
+rule = "small gifs"
+"#;
+
+fn subject<'a>(mime: &'a str, tenant: Option<&'a str>, metadata: &'a BTreeMap<String, String>) -> Subject<'a> {
+    Subject {
+        mime,
+        size: 500,
+        width: 100,
+        height: 100,
+        tenant,
+        metadata,
+    }
+}
+
+#[test]
+fn evaluates_rules_in_order() {
+    let policy = Policy::parse(POLICY).unwrap();
+    let (none, mut alt) = (BTreeMap::new(), BTreeMap::new());
+    assert!(policy.evaluate(&subject("image/png", None, &none)).is_ok());
+    assert!(policy.evaluate(&subject("image/webp", Some("acme"), &none)).is_err());
+    alt.insert("alt".to_owned(), "A cat".to_owned());
+    assert!(policy.evaluate(&subject("image/webp", Some("acme"), &alt)).is_ok());
+
+    let violation = policy
+        .evaluate(&subject("image/webp", Some("beta"), &none))
+        .unwrap_err();
+    assert_eq!(violation.rule, "no webp for beta");
+    let tiny = Subject {
+        width: 8,
+        ..subject("image/jpeg", None, &none)
+    };
+    assert_eq!(policy.evaluate(&tiny).unwrap_err().rule, "photo sizes");
+
+    assert!(Policy::parse("[[rules]]\nname = \"a\"\nmin_width = 10\nmax_width = 5\n").is_err());
+    assert!(Policy::parse("[[rules]]\nname = \"a\"\n[[rules]]\nname = \"a\"\n").is_err());
+    assert!(Policy::parse("[[rules]]\nname = \"a\"\nmax_sise = 1\n").is_err());
+}
+
+#[test]
+fn checks_policy_files() {
+    let dir = ScratchDir::new("policy");
+    let path = dir.join("policy.toml");
+    std::fs::write(&path, POLICY).unwrap();
+
+    let report = policy::check_file(&path).unwrap();
+    assert_eq!((report.rules, report.passed), (4, 2));
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!(report.failures[0].0, "wrong rule");
+}
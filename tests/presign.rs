@@ -0,0 +1,140 @@
+// One-time presigned upload URLs
+mod common;
+
+use actix_web::{test, App};
+
+use rust_rest_api::auth::ApiKey;
+use rust_rest_api::{http, metadata, signing, Config};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+fn upload(url: &str) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri(url)
+        .insert_header(("Content-Type", "image/svg+xml"))
+        .set_payload(SVG)
+}
+
+#[actix_rt::test]
+async fn presigned_urls_expire_and_work_once() {
+    let dir = ScratchDir::new("presign");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        signing_key: "signing-secret".to_owned(),
+        api_keys: vec![serde_json::from_value::<ApiKey>(serde_json::json!({"key": "secret", "name": "app"})).unwrap()],
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let presign = test::TestRequest::post().uri("/upload/presign?ttl=60");
+    assert_eq!(test::call_service(&app, presign.to_request()).await.status(), 401);
+    let response = test::call_service(
+        &app,
+        test::TestRequest::post()
+            .uri("/upload/presign?ttl=60")
+            .insert_header(("Authorization", "Bearer secret"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let url = body["url"].as_str().unwrap().to_owned();
+
+    // No credentials needed, but only once
+    assert_eq!(test::call_service(&app, upload(&url).to_request()).await.status(), 200);
+    let response = test::call_service(&app, upload(&url).to_request()).await;
+    assert_eq!(response.status(), 409);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body["code"], "signature_replayed");
+
+    // Signed with the right key, a second ago
+    let expires = signing::now() - 1;
+    let signature = signing::sign(
+        &config.signing_key,
+        &signing::upload_message(expires, "late", Some("app"), None),
+    );
+    let url = format!(
+        "/upload?expires={}&nonce=late&owner=app&signature={}",
+        expires, signature
+    );
+    let response = test::call_service(&app, upload(&url).to_request()).await;
+    assert_eq!(response.status(), 403);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body["code"], "signature_expired");
+
+    // Pushing the expiry back breaks the signature
+    let url = format!(
+        "/upload?expires={}&nonce=late&owner=app&signature={}",
+        expires + 3600,
+        signature
+    );
+    let response = test::call_service(&app, upload(&url).to_request()).await;
+    assert_eq!(response.status(), 403);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body["code"], "invalid_signature");
+}
+
+#[actix_rt::test]
+async fn presigned_uploads_are_the_presigners() {
+    let dir = ScratchDir::new("presign_owner");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        signing_key: "signing-secret".to_owned(),
+        api_keys: vec![
+            serde_json::from_value::<ApiKey>(serde_json::json!({"key": "secret", "name": "app"})).unwrap(),
+            serde_json::from_value::<ApiKey>(serde_json::json!({
+                "key": "shop-secret",
+                "name": "shop",
+                "tenant": "acme",
+                "limits": {"max_uploads_per_day": 1},
+            }))
+            .unwrap(),
+        ],
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let presign = || {
+        test::TestRequest::post()
+            .uri("/upload/presign?ttl=60")
+            .insert_header(("Authorization", "Bearer shop-secret"))
+            .to_request()
+    };
+    let body: serde_json::Value = test::read_body_json(test::call_service(&app, presign()).await).await;
+    let url = body["url"].as_str().unwrap().to_owned();
+    assert!(url.contains("owner=shop&tenant=acme"));
+
+    // Someone else's name breaks the signature
+    let response = test::call_service(&app, upload(&url.replace("owner=shop", "owner=app")).to_request()).await;
+    assert_eq!(response.status(), 403);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body["code"], "invalid_signature");
+
+    let response = test::call_service(&app, upload(&format!("{}&details=true", url)).to_request()).await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let stored = metadata::load(&config.uploads_dir, body[0]["id"].as_str().unwrap())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        (stored.owner.as_deref(), stored.tenant.as_deref()),
+        (Some("shop"), Some("acme"))
+    );
+
+    // The key's daily limit counts presigned uploads too
+    let body: serde_json::Value = test::read_body_json(test::call_service(&app, presign()).await).await;
+    let response = test::call_service(&app, upload(body["url"].as_str().unwrap()).to_request()).await;
+    assert_eq!(response.status(), 429);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(body["code"], "daily_upload_limit");
+}
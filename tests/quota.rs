@@ -0,0 +1,72 @@
+mod common;
+
+use std::collections::HashMap;
+
+use rust_rest_api::auth::KeyLimits;
+use rust_rest_api::metadata::{self, ImageMetadata};
+use rust_rest_api::quota::{self, QuotaConfig, QuotaError};
+
+use common::ScratchDir;
+
+fn image_metadata(id: &str, owner: Option<&str>, size: u64) -> ImageMetadata {
+    serde_json::from_value(serde_json::json!({
+        "id": id,
+        "extension": "png",
+        "size": size,
+        "width": 1,
+        "height": 1,
+        "created_at": 0,
+        "owner": owner,
+    }))
+    .unwrap()
+}
+
+// Usage is process-wide, so one test walks through it all
+#[actix_rt::test]
+async fn usage_and_limits() {
+    let dir = ScratchDir::new("quota");
+    metadata::save(&*dir, &image_metadata("a", Some("alice"), 600)).await.unwrap();
+    metadata::save(&*dir, &image_metadata("b", None, 300)).await.unwrap();
+    assert_eq!(quota::load(&*dir).await.unwrap(), 900);
+
+    let config = QuotaConfig {
+        max_total_bytes: 1000,
+        max_bytes_per_key: 500,
+        keys: vec![("alice".to_owned(), 700)].into_iter().collect::<HashMap<_, _>>(),
+    };
+    assert!(quota::check(&config, None, 100).is_ok());
+    assert!(matches!(quota::check(&config, None, 101), Err(QuotaError::Instance(1000))));
+    assert!(quota::check(&config, Some("alice"), 100).is_ok());
+    let keys_only = QuotaConfig { max_total_bytes: 0, ..config.clone() };
+    assert!(matches!(quota::check(&keys_only, Some("alice"), 101), Err(QuotaError::Key(_, 700))));
+    assert!(matches!(quota::check(&keys_only, Some("bob"), 501), Err(QuotaError::Key(_, 500))));
+
+    // Saving and removing metadata keeps the count
+    metadata::remove(&*dir, "a").await.unwrap();
+    assert!(quota::check(&keys_only, Some("alice"), 700).is_ok());
+    metadata::save(&*dir, &image_metadata("c", Some("bob"), 200)).await.unwrap();
+
+    let report = quota::report(&config);
+    assert_eq!((report.total_bytes, report.files), (500, 2));
+    assert_eq!(report.keys["bob"].bytes, 200);
+    assert_eq!((report.keys["alice"].bytes, report.keys["alice"].limit), (0, 700));
+
+    // Reserved bytes count until they're released or the upload is saved
+    quota::reserve(&config, None, Some("bob"), "d", 300).unwrap();
+    assert!(matches!(quota::reserve(&config, None, Some("bob"), "e", 1), Err(QuotaError::Key(_, 500))));
+    assert!(matches!(quota::reserve(&config, None, None, "e", 201), Err(QuotaError::Instance(1000))));
+    quota::release("d");
+    quota::reserve(&config, None, Some("bob"), "e", 300).unwrap();
+    metadata::save(&*dir, &image_metadata("e", Some("bob"), 300)).await.unwrap();
+    quota::release("e");
+    let report = quota::report(&config);
+    assert_eq!((report.total_bytes, report.files, report.keys["bob"].bytes), (800, 3, 500));
+
+    // So do the day's uploads of a key
+    let limits = KeyLimits { max_uploads_per_day: 1, ..Default::default() };
+    quota::reserve(&config, Some(&limits), Some("carol"), "f", 0).unwrap();
+    let res = quota::reserve(&config, Some(&limits), Some("carol"), "g", 0);
+    assert!(matches!(res, Err(QuotaError::KeyUploads(_, 1))));
+    quota::release("f");
+    quota::reserve(&config, Some(&limits), Some("carol"), "g", 0).unwrap();
+}
@@ -0,0 +1,147 @@
+// A warm standby copying from its primary, and its promotion
+mod common;
+
+use actix_web::{test, web, App, HttpRequest, HttpResponse, HttpServer};
+
+use rust_rest_api::replication::{self, Replication, ReplicationConfig, Role};
+use rust_rest_api::{http, layout, Config};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+// The replication endpoints of a primary with instance id "a" over `dir`
+fn serve_primary(dir: std::path::PathBuf) -> String {
+    let server = HttpServer::new(move || {
+        let dir = dir.clone();
+        App::new().default_service(web::to(move |req: HttpRequest| {
+            let dir = dir.clone();
+            async move {
+                let token = req.headers().get(replication::TOKEN_HEADER);
+                if token.map(|token| token.as_bytes()) != Some(b"replica-secret") {
+                    return HttpResponse::Unauthorized().finish();
+                }
+                let path = req.path().trim_start_matches(replication::PATH_PREFIX);
+                if path == "/info" {
+                    HttpResponse::Ok().json(serde_json::json!({"instance_id": "a", "standby": false}))
+                } else if path == "/manifest" {
+                    HttpResponse::Ok().json(replication::manifest(&dir, 0).await.unwrap())
+                } else if let Some(name) = path.strip_prefix("/files/") {
+                    HttpResponse::Ok().body(std::fs::read(layout::stored_file_path(&dir, name)).unwrap())
+                } else {
+                    HttpResponse::NotFound().finish()
+                }
+            }
+        }))
+    })
+    .workers(1)
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let base = format!("http://{}", server.addrs()[0]);
+    actix_rt::spawn(server.run());
+    base
+}
+
+fn standby_config(primary_url: &str, token: &str) -> ReplicationConfig {
+    ReplicationConfig {
+        role: Role::Standby,
+        primary_url: primary_url.to_owned(),
+        token: token.to_owned(),
+        ..Default::default()
+    }
+}
+
+#[actix_rt::test]
+async fn standbys_copy_what_changed_on_the_primary() {
+    let primary_dir = ScratchDir::new("replication_primary");
+    let standby_dir = ScratchDir::new("replication_standby");
+    let original = layout::stored_file_path(&*primary_dir, "a1.svg");
+    std::fs::create_dir_all(original.parent().unwrap()).unwrap();
+    std::fs::write(&original, SVG).unwrap();
+    let primary_url = serve_primary(primary_dir.to_path_buf());
+
+    let standby = Replication::new(&standby_config(&primary_url, "replica-secret"), "b");
+    assert_eq!(standby.sync_once(&standby_dir).await.unwrap(), 1);
+    assert_eq!(
+        std::fs::read(layout::stored_file_path(&*standby_dir, "a1.svg")).unwrap(),
+        SVG
+    );
+    // Already there
+    assert_eq!(standby.sync_once(&standby_dir).await.unwrap(), 0);
+
+    // Ids of the two would collide after a promotion
+    let twin = Replication::new(&standby_config(&primary_url, "replica-secret"), "a");
+    let err = twin.sync_once(&standby_dir).await.unwrap_err();
+    assert!(err.to_string().contains("share instance_id"), "{}", err);
+    let stranger = Replication::new(&standby_config(&primary_url, "guess"), "b");
+    assert!(stranger.sync_once(&standby_dir).await.is_err());
+}
+
+#[actix_rt::test]
+async fn standbys_are_read_only_until_promoted() {
+    let dir = ScratchDir::new("replication_promote");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        instance_id: "b".to_owned(),
+        replication: ReplicationConfig {
+            sync_interval_secs: 3600,
+            ..standby_config("http://127.0.0.1:9", "replica-secret")
+        },
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+    let upload = || {
+        test::TestRequest::post()
+            .uri("/upload")
+            .insert_header(("Content-Type", "image/svg+xml"))
+            .set_payload(SVG)
+            .to_request()
+    };
+    let promote = |token: &str| {
+        test::TestRequest::post()
+            .uri("/replication/promote")
+            .insert_header((replication::TOKEN_HEADER, token))
+            .to_request()
+    };
+
+    assert_eq!(test::call_service(&app, upload()).await.status(), 503);
+    let response = test::call_service(&app, test::TestRequest::get().uri("/healthz").to_request()).await;
+    assert_eq!(response.status(), 200);
+
+    assert_eq!(test::call_service(&app, promote("guess")).await.status(), 401);
+    let response = test::call_service(&app, promote("replica-secret")).await;
+    assert_eq!(response.status(), 200);
+    let info: serde_json::Value = test::read_body_json(response).await;
+    assert_eq!(info, serde_json::json!({"instance_id": "b", "standby": false}));
+    assert_eq!(test::call_service(&app, promote("replica-secret")).await.status(), 409);
+
+    let response = test::call_service(&app, upload()).await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    assert!(body[0].as_str().unwrap().starts_with('b'));
+}
+
+#[actix_rt::test]
+async fn standbys_need_an_instance_id_and_a_primary() {
+    let dir = ScratchDir::new("replication_config");
+    let path = dir.join("config.toml");
+    for (toml, error) in [
+        ("instance_id = \"b-1\"\n", "instance_id must be alphanumeric"),
+        (
+            "[replication]\nrole = \"standby\"\nprimary_url = \"http://primary\"\n",
+            "a standby needs",
+        ),
+        (
+            "instance_id = \"b\"\n[replication]\nrole = \"standby\"\n",
+            "a standby needs",
+        ),
+    ] {
+        std::fs::write(&path, toml).unwrap();
+        let err = Config::load_from(Some(&path)).err().unwrap();
+        assert!(err.to_string().contains(error), "{}: {}", toml, err);
+    }
+}
@@ -0,0 +1,87 @@
+// Retries of URL uploads against a local server that fails on purpose
+mod common;
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::{web, App, HttpResponse, HttpServer};
+
+use rust_rest_api::fetch::FetchConfig;
+use rust_rest_api::retry::RetryConfig;
+use rust_rest_api::{fetch_image, Config, FetchError, UploadOptions};
+
+use common::ScratchDir;
+
+#[test]
+fn backoff_doubles_up_to_the_cap() {
+    let retry = RetryConfig {
+        max_attempts: 10,
+        initial_backoff_ms: 100,
+        max_backoff_ms: 1000,
+    };
+    let backoffs: Vec<_> = (1..=6).map(|attempt| retry.backoff(attempt).as_millis()).collect();
+    assert_eq!(backoffs, vec![100, 200, 400, 800, 1000, 1000]);
+    assert_eq!(retry.backoff(200), Duration::from_millis(1000));
+}
+
+// Answers every request with `status`, returns the base URL and a hit counter
+fn failing_server(status: u16) -> (String, Arc<AtomicU32>) {
+    let hits = Arc::new(AtomicU32::new(0));
+    let counter = hits.clone();
+    let server = HttpServer::new(move || {
+        let counter = counter.clone();
+        App::new().default_service(web::to(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            async move { HttpResponse::build(actix_web::http::StatusCode::from_u16(status).unwrap()).finish() }
+        }))
+    })
+    .workers(1)
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let base = format!("http://{}", server.addrs()[0]);
+    actix_rt::spawn(server.run());
+    (base, hits)
+}
+
+async fn fetch(config: &Config, url: &str) -> FetchError {
+    match fetch_image(config, url, &Default::default(), &UploadOptions::from_config(config)).await {
+        Ok(_) => panic!("{} was fetched", url),
+        Err(err) => err.downcast::<FetchError>().unwrap(),
+    }
+}
+
+#[actix_rt::test]
+async fn retries_server_errors_only() {
+    let dir = ScratchDir::new("retry");
+    let config = Config {
+        uploads_dir: dir.to_path_buf(),
+        fetch_retry: RetryConfig {
+            max_attempts: 3,
+            initial_backoff_ms: 10,
+            max_backoff_ms: 10,
+        },
+        fetch: FetchConfig {
+            allow_private_addresses: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let (base, hits) = failing_server(503);
+    match fetch(&config, &format!("{}/a.png", base)).await {
+        FetchError::GaveUp { attempts, last } => {
+            assert_eq!(attempts, 3);
+            assert!(matches!(*last, FetchError::Status(503)));
+        }
+        err => panic!("unexpected {}", err),
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 3);
+
+    let (base, hits) = failing_server(404);
+    assert!(matches!(
+        fetch(&config, &format!("{}/a.png", base)).await,
+        FetchError::Status(404)
+    ));
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,107 @@
+// Security headers on every response
+mod common;
+
+use std::collections::BTreeMap;
+
+use actix_web::{test, App};
+
+use rust_rest_api::security::security_headers;
+use rust_rest_api::{http, Config};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+fn overrides(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+    pairs
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+#[actix_rt::test]
+async fn overrides_replace_or_drop_the_defaults() {
+    let headers = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+        security_headers(&overrides(pairs))
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_owned()))
+            .collect()
+    };
+
+    let defaults = headers(&[]);
+    assert_eq!(defaults["x-content-type-options"], "nosniff");
+    assert_eq!(defaults["x-frame-options"], "DENY");
+    assert_eq!(defaults["referrer-policy"], "no-referrer");
+    assert!(defaults["content-security-policy"].starts_with("default-src 'none'"));
+
+    let changed = headers(&[
+        ("Referrer-Policy", "same-origin"),
+        ("X-FRAME-OPTIONS", ""),
+        ("Permissions-Policy", "camera=()"),
+        // Not a header, left out
+        ("Bad Name", "x"),
+        ("X-Bad-Value", "a\nb"),
+    ]);
+    assert_eq!(changed["referrer-policy"], "same-origin");
+    assert_eq!(changed["permissions-policy"], "camera=()");
+    assert_eq!(changed["x-content-type-options"], "nosniff");
+    assert!(!changed.contains_key("x-frame-options"));
+    assert!(!changed.contains_key("bad name") && !changed.contains_key("x-bad-value"));
+}
+
+#[actix_rt::test]
+async fn every_response_gets_them() {
+    let dir = ScratchDir::new("security_headers");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        security_headers: overrides(&[("Referrer-Policy", "same-origin"), ("X-Frame-Options", "")]),
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let response = test::call_service(
+        &app,
+        test::TestRequest::post()
+            .uri("/upload")
+            .insert_header(("Content-Type", "image/svg+xml"))
+            .set_payload(SVG)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let id = body[0].as_str().unwrap().to_owned();
+
+    for (uri, status) in [
+        ("/healthz".to_owned(), 200),
+        ("/images/missing".to_owned(), 404),
+        ("/no/such/route".to_owned(), 404),
+        (format!("/images/{}", id), 200),
+    ] {
+        let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(response.status(), status, "{}", uri);
+        let headers = response.headers();
+        assert_eq!(headers.get("x-content-type-options").unwrap(), "nosniff", "{}", uri);
+        assert_eq!(headers.get("referrer-policy").unwrap(), "same-origin", "{}", uri);
+        assert!(headers.get("x-frame-options").is_none(), "{}", uri);
+        assert!(headers.get("content-security-policy").is_some(), "{}", uri);
+    }
+
+    // A route's own header is kept: the SVG's sandbox
+    let response = test::call_service(
+        &app,
+        test::TestRequest::get().uri(&format!("/images/{}", id)).to_request(),
+    )
+    .await;
+    let csp = response
+        .headers()
+        .get("content-security-policy")
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert!(csp.ends_with("sandbox"), "{}", csp);
+}
@@ -0,0 +1,102 @@
+// How stored files of risky types are served
+mod common;
+
+use std::collections::HashMap;
+
+use actix_web::{test, App};
+
+use rust_rest_api::serve::{default_serve_policy, serve_policy, ServePolicy};
+use rust_rest_api::{http, Config};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+fn inline_svg() -> HashMap<String, ServePolicy> {
+    let policy = ServePolicy {
+        attachment: false,
+        content_security_policy: Some("sandbox".to_owned()),
+        content_type: Some("text/plain".to_owned()),
+    };
+    vec![("image/svg+xml".to_owned(), policy)].into_iter().collect()
+}
+
+#[actix_rt::test]
+async fn script_capable_types_are_sandboxed_by_default() {
+    for mime_type in [
+        "image/svg+xml",
+        "text/html",
+        "application/xhtml+xml",
+        "text/xml",
+        "application/xml",
+    ] {
+        let policy = default_serve_policy(mime_type);
+        assert!(policy.attachment, "{}", mime_type);
+        assert!(
+            policy.content_security_policy.unwrap().contains("sandbox"),
+            "{}",
+            mime_type
+        );
+    }
+    assert_eq!(default_serve_policy("image/png"), ServePolicy::default());
+
+    let config = Config {
+        serve_policies: inline_svg(),
+        ..Default::default()
+    };
+    assert_eq!(serve_policy(&config, "image/svg+xml"), inline_svg()["image/svg+xml"]);
+    assert!(serve_policy(&config, "text/html").attachment);
+}
+
+#[actix_rt::test]
+async fn svgs_are_downloads_unless_configured_otherwise() {
+    let dir = ScratchDir::new("serve_policies");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let response = test::call_service(
+        &app,
+        test::TestRequest::post()
+            .uri("/upload")
+            .insert_header(("Content-Type", "image/svg+xml"))
+            .set_payload(SVG)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let uri = format!("/images/{}", body[0].as_str().unwrap());
+
+    let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(response.status(), 200);
+    let headers = response.headers();
+    assert_eq!(headers.get("content-type").unwrap(), "image/svg+xml");
+    let disposition = headers.get("content-disposition").unwrap().to_str().unwrap();
+    assert!(disposition.starts_with("attachment; filename=\""), "{}", disposition);
+    assert!(disposition.ends_with(".svg\""), "{}", disposition);
+    assert_eq!(
+        headers.get("content-security-policy").unwrap(),
+        "default-src 'none'; style-src 'unsafe-inline'; sandbox"
+    );
+    assert_eq!(headers.get("x-content-type-options").unwrap(), "nosniff");
+
+    // Same files, with the type's policy overridden
+    let config = Config {
+        serve_policies: inline_svg(),
+        ..config
+    };
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+    let response = test::call_service(&app, test::TestRequest::get().uri(&uri).to_request()).await;
+    assert_eq!(response.status(), 200);
+    let headers = response.headers();
+    assert_eq!(headers.get("content-type").unwrap(), "text/plain");
+    assert!(headers.get("content-disposition").is_none());
+    assert_eq!(headers.get("content-security-policy").unwrap(), "sandbox");
+}
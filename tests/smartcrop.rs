@@ -0,0 +1,61 @@
+mod common;
+
+use rust_rest_api::imagetools::{self, Gravity};
+use rust_rest_api::transform::TransformSpec;
+
+use common::{encode_png, ScratchDir};
+
+fn spec(query: serde_json::Value) -> TransformSpec {
+    serde_json::from_value(query).unwrap()
+}
+
+// A centered cover crop keeps the names and signatures it always had
+#[test]
+fn gravity_in_the_canonical_spec() {
+    let centered = spec(serde_json::json!({"w": 100, "h": 100, "fit": "cover"}));
+    assert_eq!(centered.gravity, Gravity::Center);
+    assert_eq!(centered.canonical(), "w=100&h=100&fit=cover");
+
+    let smart = spec(serde_json::json!({"w": 100, "h": 100, "fit": "cover", "gravity": "smart"}));
+    assert_eq!(smart.canonical(), "w=100&h=100&fit=cover&gravity=smart");
+    assert_ne!(smart.cache_file_name("a", "png"), centered.cache_file_name("a", "png"));
+
+    // Nothing is cropped without `cover`
+    let contained = spec(serde_json::json!({"w": 100, "fit": "contain", "gravity": "smart"}));
+    assert_eq!(contained.canonical(), "w=100&fit=contain");
+}
+
+// Flat gray with a checkerboard near the right edge
+#[test]
+fn smart_window_follows_the_detail() {
+    let dir = ScratchDir::new("smartcrop");
+    let (width, height) = (300, 100);
+    let mut rgb = Vec::new();
+    for y in 0..height {
+        for x in 0..width {
+            let value = if (200..290).contains(&x) && (x / 10 + y / 10) % 2 == 0 {
+                255
+            } else {
+                128
+            };
+            rgb.extend_from_slice(&[value, value, value]);
+        }
+    }
+    let path = dir.join("wide.png");
+    std::fs::write(&path, encode_png(width, height, &rgb)).unwrap();
+    let image = imagetools::read_image(&path).unwrap();
+
+    assert_eq!(
+        imagetools::crop_window(&image, (100, 100), Gravity::Center).unwrap(),
+        (100, 0, 100, 100)
+    );
+    let (x, y, w, h) = imagetools::crop_window(&image, (100, 100), Gravity::Smart).unwrap();
+    assert_eq!((y, w, h), (0, 100, 100));
+    assert!((180..=200).contains(&x), "window at {}", x);
+
+    // Same aspect ratio, nothing to place
+    assert_eq!(
+        imagetools::crop_window(&image, (30, 10), Gravity::Smart).unwrap(),
+        (0, 0, 300, 100)
+    );
+}
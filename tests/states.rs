@@ -0,0 +1,107 @@
+mod common;
+
+use actix_web::{test, App};
+
+use rust_rest_api::metadata::{self, ImageMetadata, ListFilter, UploadState};
+use rust_rest_api::{clock, delete_upload, http, Config};
+
+use common::ScratchDir;
+
+fn image_metadata(id: &str, status: &str) -> ImageMetadata {
+    serde_json::from_value(serde_json::json!({
+        "id": id,
+        "extension": "png",
+        "size": 1,
+        "width": 1,
+        "height": 1,
+        "created_at": 0,
+        "status": status,
+    }))
+    .unwrap()
+}
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+async fn listed(dir: &ScratchDir, status: Option<UploadState>) -> Vec<String> {
+    let filter = ListFilter {
+        status,
+        ..Default::default()
+    };
+    let (items, _) = metadata::list(&**dir, &filter, None, 100).await.unwrap();
+    items.into_iter().map(|image_metadata| image_metadata.id).collect()
+}
+
+#[actix_rt::test]
+async fn transitions_and_tombstones() {
+    let dir = ScratchDir::new("states");
+    // Stored before there were states
+    let old = image_metadata("old", "approved");
+    assert_eq!(old.status, UploadState::Available);
+    assert!(old.is_public());
+    metadata::save(&*dir, &old).await.unwrap();
+    metadata::save(&*dir, &image_metadata("fresh", "processing"))
+        .await
+        .unwrap();
+
+    // Only from the expected state
+    let (processing, available) = (UploadState::Processing, UploadState::Available);
+    assert!(metadata::transition(&*dir, "fresh", processing, available)
+        .await
+        .unwrap());
+    assert!(!metadata::transition(&*dir, "fresh", processing, available)
+        .await
+        .unwrap());
+    assert!(!metadata::transition(&*dir, "missing", processing, available)
+        .await
+        .unwrap());
+    let fresh = metadata::load(&*dir, "fresh").await.unwrap().unwrap();
+    assert!(fresh.is_public());
+
+    // A deletion that broke off leaves a tombstone, listed only on request
+    metadata::mark_deleted(&*dir, "old").await.unwrap();
+    assert!(metadata::load(&*dir, "old").await.unwrap().unwrap().is_deleted());
+    assert_eq!(listed(&dir, None).await, vec!["fresh"]);
+    assert_eq!(listed(&dir, Some(UploadState::Deleted)).await, vec!["old"]);
+
+    // Deleting again clears it
+    delete_upload(&*dir, "old").await.unwrap();
+    assert!(metadata::load(&*dir, "old").await.unwrap().is_none());
+}
+
+// Nobody is an admin of an open API as far as hidden uploads go
+#[actix_rt::test]
+async fn hidden_uploads_stay_hidden_on_an_open_api() {
+    let dir = ScratchDir::new("states_open");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let upload = |query: String| {
+        test::TestRequest::post()
+            .uri(&format!("/upload{}", query))
+            .insert_header(("Content-Type", "image/svg+xml"))
+            .set_payload(SVG)
+            .to_request()
+    };
+    let mut ids = Vec::new();
+    for query in [format!("?publish_at={}", clock::unix_now() + 3600), String::new()] {
+        let response = test::call_service(&app, upload(query)).await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        ids.push(body[0].as_str().unwrap().to_owned());
+    }
+    let (scheduled, quarantined) = (&ids[0], &ids[1]);
+    let mut image_metadata = metadata::load(&config.uploads_dir, quarantined).await.unwrap().unwrap();
+    image_metadata.status = UploadState::Quarantined;
+    metadata::save(&config.uploads_dir, &image_metadata).await.unwrap();
+
+    let get = |id: &str| test::TestRequest::get().uri(&format!("/images/{}", id)).to_request();
+    assert_eq!(test::call_service(&app, get(scheduled)).await.status(), 404);
+    assert_eq!(test::call_service(&app, get(quarantined)).await.status(), 403);
+}
@@ -0,0 +1,35 @@
+mod common;
+
+use rust_rest_api::metadata::{self, ImageMetadata};
+use rust_rest_api::{clock, stats};
+
+use common::ScratchDir;
+
+fn image_metadata(id: &str, extension: &str, size: u64, age_secs: u64) -> ImageMetadata {
+    serde_json::from_value(serde_json::json!({
+        "id": id,
+        "extension": extension,
+        "size": size,
+        "width": 1,
+        "height": 1,
+        "created_at": clock::unix_now() - age_secs,
+    }))
+    .unwrap()
+}
+
+#[actix_rt::test]
+async fn counts_stored_uploads() {
+    let dir = ScratchDir::new("stats");
+    let empty = stats::collect(&*dir).await.unwrap();
+    assert_eq!((empty.files, empty.top_mime_types.len()), (0, 0));
+
+    metadata::save(&*dir, &image_metadata("a", "png", 100, 10)).await.unwrap();
+    metadata::save(&*dir, &image_metadata("b", "png", 200, 2 * 60 * 60)).await.unwrap();
+    metadata::save(&*dir, &image_metadata("c", "jpg", 50, 2 * 24 * 60 * 60)).await.unwrap();
+
+    let stats = stats::collect(&*dir).await.unwrap();
+    assert_eq!((stats.files, stats.total_bytes), (3, 350));
+    assert_eq!((stats.uploads_last_hour, stats.uploads_last_day), (1, 2));
+    let top: Vec<_> = stats.top_mime_types.iter().map(|mime| (mime.mime.as_str(), mime.files, mime.bytes)).collect();
+    assert_eq!(top, vec![("image/png", 2, 300), ("image/jpeg", 1, 50)]);
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use rust_rest_api::imagetools::{self, SvgError};
+use rust_rest_api::{extension_to_mime_type, mime_type_to_extension, sniff_type};
+
+use common::ScratchDir;
+
+const HOSTILE: &str = r##"<?xml version="1.0"?>
+<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="40" height="20" onload="alert(1)">
+  <script>alert(document.cookie)</script>
+  <defs><linearGradient id="g"><stop offset="0" stop-color="red"/></linearGradient></defs>
+  <foreignObject><iframe src="https://evil.example"/></foreignObject>
+  <a href="javascript:alert(2)"><rect width="10" height="10" fill="url(#g)"/></a>
+  <image xlink:href="https://evil.example/track.png" width="5" height="5"/>
+  <use xlink:href="#g"/>
+  <rect style="fill: url(https://evil.example/x)" onclick="alert(3)" width="5" height="5"/>
+</svg>"##;
+
+#[test]
+fn scripts_and_external_references_are_removed() {
+    let dir = ScratchDir::new("svg_sanitize");
+    let path = dir.join("image.svg");
+    std::fs::write(&path, HOSTILE).unwrap();
+
+    assert!(imagetools::sanitize_svg(&path).unwrap() > 0);
+    let clean = std::fs::read_to_string(&path).unwrap();
+    for gone in &[
+        "script",
+        "alert",
+        "onload",
+        "onclick",
+        "foreignObject",
+        "iframe",
+        "evil.example",
+        "javascript",
+    ] {
+        assert!(!clean.contains(gone), "{} left in {}", gone, clean);
+    }
+    // Local references and the drawing itself stay
+    assert!(clean.starts_with("<svg"));
+    assert!(clean.contains(r##"fill="url(#g)""##));
+    assert!(clean.contains(r##"<use href="#g""##));
+    assert!(clean.contains("<rect"));
+
+    // Sanitizing again changes nothing
+    assert_eq!(imagetools::sanitize_svg(&path).unwrap(), 0);
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), clean);
+    assert_eq!(imagetools::image_dimensions(&path).unwrap(), Some((40, 20)));
+}
+
+#[test]
+fn entities_and_other_documents_are_refused() {
+    let dir = ScratchDir::new("svg_refused");
+    let path = dir.join("image.svg");
+
+    std::fs::write(
+        &path,
+        r#"<!DOCTYPE svg [<!ENTITY lol "lol">]><svg xmlns="http://www.w3.org/2000/svg">&lol;</svg>"#,
+    )
+    .unwrap();
+    assert!(matches!(imagetools::sanitize_svg(&path), Err(SvgError::Malformed(_))));
+
+    std::fs::write(&path, "<html><body>not an image</body></html>").unwrap();
+    assert!(matches!(imagetools::sanitize_svg(&path), Err(SvgError::NotSvg)));
+
+    std::fs::write(&path, r#"<svg xmlns="http://www.w3.org/2000/svg"><g></svg>"#).unwrap();
+    assert!(matches!(imagetools::sanitize_svg(&path), Err(SvgError::Malformed(_))));
+}
+
+#[test]
+fn svg_is_sniffed_and_mapped() {
+    assert_eq!(
+        sniff_type(b"<?xml version=\"1.0\"?>\n<!-- logo -->\n<svg>"),
+        "image/svg+xml"
+    );
+    assert_ne!(sniff_type(b"<html><svg></svg></html>"), "image/svg+xml");
+    assert!(!imagetools::looks_like_svg(b"\x89PNG\r\n\x1a\n"));
+    assert_eq!(mime_type_to_extension("image/svg+xml"), Some("svg"));
+    assert_eq!(extension_to_mime_type("svg"), Some("image/svg+xml"));
+}
@@ -0,0 +1,115 @@
+mod common;
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use actix_web::{test, App};
+
+use rust_rest_api::{http, Config};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+// What the subscriber of a test writes
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn config(dir: &ScratchDir) -> Config {
+    Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        ..Default::default()
+    }
+}
+
+#[actix_rt::test]
+async fn responses_carry_a_request_id() {
+    let dir = ScratchDir::new("telemetry_ids");
+    let config = config(&dir);
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let request_id = |given: Option<&str>| {
+        let mut request = test::TestRequest::get().uri("/healthz");
+        if let Some(given) = given {
+            request = request.insert_header(("X-Request-Id", given));
+        }
+        let app = &app;
+        async move {
+            let response = test::call_service(app, request.to_request()).await;
+            response
+                .headers()
+                .get("x-request-id")
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_owned()
+        }
+    };
+
+    let generated = request_id(None).await;
+    assert_eq!(generated.len(), 16);
+    assert!(generated.bytes().all(|byte| byte.is_ascii_hexdigit()));
+    assert_ne!(request_id(None).await, generated);
+    // A proxy's id is kept, unless it's something else than an id
+    assert_eq!(request_id(Some("lb-7f3a.42")).await, "lb-7f3a.42");
+    for bad in &["two words", &"x".repeat(65)] {
+        let replaced = request_id(Some(bad)).await;
+        assert_eq!(replaced.len(), 16, "{}", bad);
+    }
+}
+
+#[actix_rt::test]
+async fn upload_logs_are_in_the_request_span() {
+    let dir = ScratchDir::new("telemetry_spans");
+    let config = config(&dir);
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let captured = Captured::default();
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .with_writer(move || writer.clone())
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let upload = test::TestRequest::post()
+        .uri("/upload?details=true")
+        .insert_header(("Content-Type", "image/svg+xml"))
+        .insert_header(("X-Request-Id", "upload-req-1"))
+        .set_payload(SVG)
+        .to_request();
+    let response = test::call_service(&app, upload).await;
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = test::read_body_json(response).await;
+    let id = body[0]["id"].as_str().unwrap();
+
+    let logs = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    let line = logs
+        .lines()
+        .find(|line| line.contains("Uploading to"))
+        .unwrap_or_else(|| panic!("no upload line in {:?}", logs));
+    assert!(line.contains("request_id=\"upload-req-1\""), "{}", line);
+    assert!(line.contains("method=POST"), "{}", line);
+    assert!(
+        line.contains(&format!("upload_image{{extension=\"svg\" id=\"{}\"}}", id)),
+        "{}",
+        line
+    );
+}
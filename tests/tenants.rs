@@ -0,0 +1,190 @@
+mod common;
+
+use actix_web::{test, App};
+
+use rust_rest_api::auth::ApiKey;
+use rust_rest_api::{http, layout, metadata, Config};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+fn api_key(value: serde_json::Value) -> ApiKey {
+    serde_json::from_value(value).unwrap()
+}
+
+fn request(request: test::TestRequest, key: &str) -> test::TestRequest {
+    request.insert_header(("Authorization", format!("Bearer {}", key)))
+}
+
+fn listed(body: &serde_json::Value) -> Vec<&str> {
+    let mut ids: Vec<&str> = body["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["id"].as_str().unwrap())
+        .collect();
+    ids.sort();
+    ids
+}
+
+#[actix_rt::test]
+async fn tenants_see_only_their_uploads() {
+    let dir = ScratchDir::new("tenants_http");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        api_keys: vec![
+            api_key(serde_json::json!({"key": "ops-secret", "name": "ops", "admin": true})),
+            api_key(serde_json::json!({"key": "acme-secret", "name": "acme-app", "tenant": "acme"})),
+            api_key(
+                serde_json::json!({"key": "globex-secret", "name": "globex-app", "tenant": "globex", "admin": true}),
+            ),
+        ],
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let mut ids = Vec::new();
+    for key in &["acme-secret", "globex-secret", "ops-secret"] {
+        let upload = test::TestRequest::post()
+            .uri("/upload?details=true")
+            .insert_header(("Content-Type", "image/svg+xml"))
+            .set_payload(SVG);
+        let response = test::call_service(&app, request(upload, key).to_request()).await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = test::read_body_json(response).await;
+        ids.push(body[0]["id"].as_str().unwrap().to_owned());
+    }
+    let (acme, globex, ops) = (&ids[0], &ids[1], &ids[2]);
+
+    // Under the tenant's prefix, and served as any other upload
+    assert_eq!(layout::tenant_of(acme).as_deref(), Some("acme"));
+    assert!(layout::shard_dir(&config.uploads_dir, acme).starts_with(config.uploads_dir.join("tenants/acme")));
+    assert!(!layout::shard_dir(&config.uploads_dir, ops).starts_with(config.uploads_dir.join("tenants")));
+    let response = test::call_service(
+        &app,
+        test::TestRequest::get().uri(&format!("/images/{}", acme)).to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    let dirs = layout::upload_dirs(&config.uploads_dir).await.unwrap();
+    assert!(dirs.contains(&layout::shard_dir(&config.uploads_dir, globex)));
+
+    let list = |key: &str| request(test::TestRequest::get().uri("/images"), key).to_request();
+    let body: serde_json::Value = test::read_body_json(test::call_service(&app, list("acme-secret")).await).await;
+    assert_eq!(listed(&body), vec![acme.as_str()]);
+    assert_eq!(body["items"][0]["tenant"], "acme");
+    // A tenant's admin key is still the tenant's
+    let body: serde_json::Value = test::read_body_json(test::call_service(&app, list("globex-secret")).await).await;
+    assert_eq!(listed(&body), vec![globex.as_str()]);
+    let body: serde_json::Value = test::read_body_json(test::call_service(&app, list("ops-secret")).await).await;
+    let mut all = vec![acme.as_str(), globex.as_str(), ops.as_str()];
+    all.sort();
+    assert_eq!(listed(&body), all);
+
+    // A tenant's admin key is no operator: other tenants' uploads and the
+    // instance's settings are out of its reach
+    let post = |uri: String, key: &str| request(test::TestRequest::post().uri(&uri), key).to_request();
+    let invalidate = |id: &str| format!("/images/{}/invalidate", id);
+    assert_eq!(test::call_service(&app, post(invalidate(acme), "globex-secret")).await.status(), 404);
+    assert_eq!(test::call_service(&app, post(invalidate(globex), "globex-secret")).await.status(), 200);
+    assert_eq!(test::call_service(&app, post(invalidate(globex), "acme-secret")).await.status(), 401);
+    let put_tenant = request(test::TestRequest::put().uri("/tenants/acme"), "globex-secret")
+        .set_json(serde_json::json!({}))
+        .to_request();
+    assert_eq!(test::call_service(&app, put_tenant).await.status(), 401);
+    let get = |uri: &str, key: &str| request(test::TestRequest::get().uri(uri), key).to_request();
+    for uri in &["/admin/audit", "/admin/stats", "/tenants"] {
+        assert_eq!(test::call_service(&app, get(uri, "globex-secret")).await.status(), 401, "{}", uri);
+    }
+    assert_eq!(test::call_service(&app, get("/tenants/acme", "globex-secret")).await.status(), 401);
+    assert_eq!(test::call_service(&app, get("/tenants/globex", "globex-secret")).await.status(), 404);
+
+    let delete =
+        |id: &str, key: &str| request(test::TestRequest::delete().uri(&format!("/images/{}", id)), key).to_request();
+    assert_eq!(
+        test::call_service(&app, delete(acme, "globex-secret")).await.status(),
+        404
+    );
+    assert_eq!(test::call_service(&app, delete(ops, "acme-secret")).await.status(), 404);
+    assert_eq!(
+        test::call_service(&app, delete(acme, "acme-secret")).await.status(),
+        200
+    );
+    assert_eq!(
+        test::call_service(&app, delete(globex, "ops-secret")).await.status(),
+        200
+    );
+}
+
+#[actix_rt::test]
+async fn replicated_files_move_under_the_tenant() {
+    let dir = ScratchDir::new("tenants_adopt");
+    let id = "tenantsadopt1";
+    let plain = layout::shard_dir(&*dir, id);
+    std::fs::create_dir_all(&plain).unwrap();
+    std::fs::write(plain.join(format!("{}.svg", id)), SVG).unwrap();
+    std::fs::write(plain.join(format!("{}_thumbnail.png", id)), b"").unwrap();
+    std::fs::write(plain.join("tenantsother.svg"), SVG).unwrap();
+
+    assert_eq!(layout::adopt(&*dir, id, "acme").await.unwrap(), 2);
+    let moved = layout::shard_dir(&*dir, id);
+    assert!(moved.starts_with(dir.join("tenants/acme")));
+    assert!(moved.join(format!("{}.svg", id)).is_file());
+    assert!(plain.join("tenantsother.svg").is_file());
+    assert_eq!(layout::adopt(&*dir, id, "acme").await.unwrap(), 0);
+
+    // Read back from the metadata at startup
+    let meta = dir.join("meta");
+    std::fs::create_dir_all(&meta).unwrap();
+    std::fs::write(meta.join("tenantsload1.json"), br#"{"tenant": "globex"}"#).unwrap();
+    std::fs::write(meta.join("tenantsload2.json"), br#"{"tenant": "../up"}"#).unwrap();
+    assert_eq!(layout::load_tenants(&*dir).await.unwrap(), 1);
+    assert_eq!(layout::tenant_of("tenantsload1").as_deref(), Some("globex"));
+    assert_eq!(layout::tenant_of("tenantsload2"), None);
+}
+
+#[actix_rt::test]
+async fn tus_uploads_are_the_creators() {
+    let dir = ScratchDir::new("tenants_tus");
+    let config = Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        api_keys: vec![api_key(
+            serde_json::json!({"key": "acme-secret", "name": "acme-app", "tenant": "acme"}),
+        )],
+        ..Default::default()
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let create = test::TestRequest::post()
+        .uri("/upload/tus")
+        .insert_header(("Tus-Resumable", "1.0.0"))
+        .insert_header(("Upload-Length", SVG.len().to_string()));
+    let response = test::call_service(&app, request(create, "acme-secret").to_request()).await;
+    assert_eq!(response.status(), 201);
+    let location = response.headers().get("location").unwrap().to_str().unwrap().to_owned();
+
+    let patch = test::TestRequest::patch()
+        .uri(&location)
+        .insert_header(("Tus-Resumable", "1.0.0"))
+        .insert_header(("Content-Type", "application/offset+octet-stream"))
+        .insert_header(("Upload-Offset", "0"))
+        .set_payload(SVG);
+    let response = test::call_service(&app, request(patch, "acme-secret").to_request()).await;
+    assert_eq!(response.status(), 204);
+    let id = response.headers().get("upload-id").unwrap().to_str().unwrap().to_owned();
+
+    assert_eq!(layout::tenant_of(&id).as_deref(), Some("acme"));
+    let list = request(test::TestRequest::get().uri("/images"), "acme-secret").to_request();
+    let body: serde_json::Value = test::read_body_json(test::call_service(&app, list).await).await;
+    assert_eq!(listed(&body), vec![id.as_str()]);
+    let stored = metadata::load(&config.uploads_dir, &id).await.unwrap().unwrap();
+    assert_eq!(stored.owner.as_deref(), Some("acme-app"));
+}
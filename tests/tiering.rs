@@ -0,0 +1,74 @@
+mod common;
+
+use std::time::Duration;
+
+use rust_rest_api::metadata::{self, ImageMetadata};
+use rust_rest_api::tiering::{self, TieringConfig};
+use rust_rest_api::{cleanup, clock, delete_upload, find_upload, layout};
+
+use common::ScratchDir;
+
+fn image_metadata(id: &str, age_days: u64) -> ImageMetadata {
+    serde_json::from_value(serde_json::json!({
+        "id": id,
+        "extension": "png",
+        "size": 3,
+        "width": 1,
+        "height": 1,
+        "created_at": clock::unix_now() - age_days * 24 * 60 * 60,
+    }))
+    .unwrap()
+}
+
+async fn store(uploads_dir: &std::path::Path, image_metadata: &ImageMetadata) {
+    let dir = layout::shard_dir(uploads_dir, &image_metadata.id);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join(format!("{}.png", image_metadata.id)), b"png").unwrap();
+    std::fs::write(dir.join(format!("{}_thumbnail.png", image_metadata.id)), b"thumb").unwrap();
+    metadata::save(uploads_dir, image_metadata).await.unwrap();
+}
+
+// The cold storage is process-wide, so one test walks through it all
+#[actix_rt::test]
+async fn archive_restore_delete() {
+    let (dir, cold_dir) = (ScratchDir::new("tiering"), ScratchDir::new("tiering-cold"));
+    let config = TieringConfig {
+        after_days: 30,
+        cold_dir: Some(cold_dir.to_path_buf()),
+        ..Default::default()
+    };
+    tiering::install(&config).unwrap();
+
+    store(&dir, &image_metadata("old", 40)).await;
+    store(&dir, &image_metadata("new", 10)).await;
+    assert_eq!(tiering::archive_untouched(&config, &*dir).await.unwrap(), 1);
+    assert!(find_upload(&*dir, "old").await.is_none());
+    assert!(find_upload(&*dir, "new").await.is_some());
+    assert!(cold_dir.join("old.png").exists());
+    let archived = tiering::archived(&*dir, "old").await.unwrap();
+
+    // The thumbnail stays hot and isn't an orphan
+    let stats = cleanup::cleanup_orphans(&*dir, Duration::from_secs(0)).await.unwrap();
+    assert_eq!(stats.orphaned_thumbnails, 0);
+
+    assert!(tiering::restore(&*dir, &archived));
+    assert!(!tiering::restore(&*dir, &archived));
+    for _ in 0..100 {
+        if tiering::archived(&*dir, "old").await.is_none() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(find_upload(&*dir, "old").await.is_some());
+    assert!(!cold_dir.join("old.png").exists());
+    // A restore counts as a download, so it isn't archived again
+    assert_eq!(tiering::archive_untouched(&config, &*dir).await.unwrap(), 0);
+
+    // Deleting an archived upload removes the cold copy and the derivatives
+    store(&dir, &image_metadata("gone", 40)).await;
+    assert_eq!(tiering::archive_untouched(&config, &*dir).await.unwrap(), 1);
+    assert!(delete_upload(&*dir, "gone").await.unwrap());
+    assert!(!cold_dir.join("gone.png").exists());
+    assert!(!layout::shard_dir(&*dir, "gone").join("gone_thumbnail.png").exists());
+    assert!(metadata::load(&*dir, "gone").await.unwrap().is_none());
+}
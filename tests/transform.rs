@@ -0,0 +1,63 @@
+use actix_web::web::Query;
+
+use rust_rest_api::transform::{Crop, Flip, TransformConfig, TransformSpec};
+
+fn spec(query: &str) -> TransformSpec {
+    Query::<TransformSpec>::from_query(query).unwrap().into_inner()
+}
+
+#[test]
+fn rotate_flip_and_crop() {
+    let config = TransformConfig::default();
+
+    let edited = spec("w=100&rotate=450&flip=h&crop=10,20,300,200");
+    assert_eq!(edited.flip, Some(Flip::H));
+    assert_eq!(
+        edited.crop,
+        Some(Crop {
+            x: 10,
+            y: 20,
+            width: 300,
+            height: 200
+        })
+    );
+    assert!(edited.validate(&config).is_ok());
+    assert_eq!(
+        edited.canonical(),
+        "crop=10,20,300,200&rotate=90&flip=h&w=100&fit=contain"
+    );
+
+    // Without them, names and signatures stay what they were
+    assert_eq!(spec("w=100").canonical(), "w=100&fit=contain");
+    assert_eq!(spec("w=100&rotate=360").canonical(), "w=100&fit=contain");
+
+    assert!(spec("rotate=45").validate(&config).is_err());
+    assert!(spec("crop=0,0,0,10").validate(&config).is_err());
+    for query in &["crop=1,2,3", "crop=a,b,c,d", "flip=x"] {
+        assert!(Query::<TransformSpec>::from_query(query).is_err(), "{}", query);
+    }
+}
+
+#[test]
+fn filters() {
+    let config = TransformConfig::default();
+
+    let filtered = spec("w=100&grayscale=true&brightness=-20&contrast=30&blur=1.5&sharpen=2");
+    assert!(filtered.validate(&config).is_ok());
+    assert_eq!(
+        filtered.canonical(),
+        "w=100&fit=contain&grayscale=true&brightness=-20&contrast=30&blur=1.5&sharpen=2"
+    );
+    assert_eq!(spec("w=100&grayscale=false").canonical(), "w=100&fit=contain");
+
+    for query in &[
+        "brightness=101",
+        "contrast=-101",
+        "blur=0",
+        "blur=NaN",
+        "blur=51",
+        "sharpen=-1",
+    ] {
+        assert!(spec(query).validate(&config).is_err(), "{}", query);
+    }
+}
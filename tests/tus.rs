@@ -0,0 +1,209 @@
+mod common;
+
+use actix_web::dev::ServiceResponse;
+use actix_web::http::Method;
+use actix_web::{test, App};
+use bytes::Bytes;
+use futures_util::stream;
+
+use rust_rest_api::auth::ApiKey;
+use rust_rest_api::tus::TusStore;
+use rust_rest_api::{http, Config};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+fn config(dir: &ScratchDir) -> Config {
+    Config {
+        uploads_dir: dir.join("uploads"),
+        orphan_cleanup_interval_secs: 0,
+        min_free_disk_space: 0,
+        ..Default::default()
+    }
+}
+
+fn create(length: usize) -> test::TestRequest {
+    test::TestRequest::post()
+        .uri("/upload/tus")
+        .insert_header(("Tus-Resumable", "1.0.0"))
+        .insert_header(("Upload-Length", length.to_string()))
+}
+
+fn patch(location: &str, offset: usize, body: &'static [u8]) -> test::TestRequest {
+    test::TestRequest::patch()
+        .uri(location)
+        .insert_header(("Tus-Resumable", "1.0.0"))
+        .insert_header(("Content-Type", "application/offset+octet-stream"))
+        .insert_header(("Upload-Offset", offset.to_string()))
+        .set_payload(body)
+}
+
+fn header(response: &ServiceResponse, name: &str) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .map(|value| value.to_str().unwrap().to_owned())
+}
+
+#[actix_rt::test]
+async fn uploads_resume_from_their_offset() {
+    let dir = ScratchDir::new("tus_resume");
+    let config = config(&dir);
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let response = test::call_service(&app, create(SVG.len()).to_request()).await;
+    assert_eq!(response.status(), 201);
+    let location = header(&response, "location").unwrap();
+    let (head, tail) = SVG.split_at(40);
+    let response = test::call_service(&app, patch(&location, 0, head).to_request()).await;
+    assert_eq!(response.status(), 204);
+    assert_eq!(header(&response, "upload-offset").as_deref(), Some("40"));
+    assert_eq!(header(&response, "upload-id"), None);
+
+    // Where to resume from
+    let response = test::call_service(
+        &app,
+        test::TestRequest::default()
+            .method(Method::HEAD)
+            .uri(&location)
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(header(&response, "upload-offset").as_deref(), Some("40"));
+    assert_eq!(header(&response, "upload-length"), Some(SVG.len().to_string()));
+
+    let response = test::call_service(&app, patch(&location, 0, tail).to_request()).await;
+    assert_eq!(response.status(), 409);
+    let response = test::call_service(&app, patch(&location, 40, tail).to_request()).await;
+    assert_eq!(response.status(), 204);
+    let id = header(&response, "upload-id").unwrap();
+
+    let response = test::call_service(
+        &app,
+        test::TestRequest::get().uri(&format!("/images/{}", id)).to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(test::read_body(response).await, Bytes::from_static(SVG));
+
+    // Stored once
+    let response = test::call_service(&app, patch(&location, SVG.len(), b"").to_request()).await;
+    assert_eq!(response.status(), 409);
+}
+
+#[actix_rt::test]
+async fn complete_uploads_are_finalized_on_retry() {
+    let dir = ScratchDir::new("tus_retry");
+    let config = config(&dir);
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    // All of it arrived, then storing it failed
+    let store = TusStore::new(&config.uploads_dir);
+    let upload = store
+        .create(SVG.len() as u64, Default::default(), Default::default())
+        .await
+        .unwrap();
+    let body = stream::iter(vec![Ok::<_, std::io::Error>(Bytes::from_static(SVG))]);
+    let upload = store.append(upload, 0, body).await.unwrap();
+    assert!(upload.is_complete() && upload.uploaded_id.is_none());
+
+    let location = format!("/upload/tus/{}", upload.id);
+    let response = test::call_service(&app, patch(&location, SVG.len(), b"").to_request()).await;
+    assert_eq!(response.status(), 204);
+    let id = header(&response, "upload-id").unwrap();
+    assert_eq!(store.get(&upload.id).await.unwrap().unwrap().uploaded_id, Some(id));
+
+    // Nothing to wait for, and no image either
+    let response = test::call_service(&app, create(0).to_request()).await;
+    assert_eq!(response.status(), 415);
+    assert!(std::fs::read_dir(store.dir()).unwrap().all(|entry| {
+        let name = entry.unwrap().file_name().into_string().unwrap();
+        name.starts_with(&upload.id)
+    }));
+}
+
+#[actix_rt::test]
+async fn patches_need_write_access() {
+    let dir = ScratchDir::new("tus_auth");
+    let config = Config {
+        api_keys: vec![serde_json::from_value::<ApiKey>(serde_json::json!({"key": "secret", "name": "app"})).unwrap()],
+        ..config(&dir)
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let response = test::call_service(
+        &app,
+        create(SVG.len())
+            .insert_header(("Authorization", "Bearer secret"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 201);
+    let location = header(&response, "location").unwrap();
+
+    let response = test::call_service(&app, patch(&location, 0, SVG).to_request()).await;
+    assert_eq!(response.status(), 401);
+    let response = test::call_service(
+        &app,
+        patch(&location, 0, SVG)
+            .insert_header(("Authorization", "Bearer secret"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 204);
+    assert!(header(&response, "upload-id").is_some());
+}
+
+#[actix_rt::test]
+async fn uploads_are_resumed_by_their_creator_only() {
+    let dir = ScratchDir::new("tus_owner");
+    let key = |key: &str, name: &str| serde_json::from_value::<ApiKey>(serde_json::json!({"key": key, "name": name}));
+    let config = Config {
+        api_keys: vec![key("secret", "app").unwrap(), key("other-secret", "other").unwrap()],
+        ..config(&dir)
+    };
+    http::start(&config).await.unwrap();
+    let app = test::init_service(App::new().configure(|cfg| http::configure(cfg, config.clone()))).await;
+
+    let response = test::call_service(
+        &app,
+        create(SVG.len())
+            .insert_header(("Authorization", "Bearer secret"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 201);
+    let location = header(&response, "location").unwrap();
+
+    let head = |token: Option<&str>| {
+        let request = test::TestRequest::default()
+            .method(Method::HEAD)
+            .uri(&location)
+            .insert_header(("Tus-Resumable", "1.0.0"));
+        match token {
+            Some(token) => request.insert_header(("Authorization", format!("Bearer {}", token))),
+            None => request,
+        }
+        .to_request()
+    };
+    assert_eq!(test::call_service(&app, head(None)).await.status(), 401);
+    assert_eq!(test::call_service(&app, head(Some("other-secret"))).await.status(), 404);
+    assert_eq!(test::call_service(&app, head(Some("secret"))).await.status(), 200);
+
+    let response = test::call_service(
+        &app,
+        patch(&location, 0, SVG)
+            .insert_header(("Authorization", "Bearer other-secret"))
+            .to_request(),
+    )
+    .await;
+    assert_eq!(response.status(), 404);
+    let response = test::call_service(&app, head(Some("secret"))).await;
+    assert_eq!(header(&response, "upload-offset").as_deref(), Some("0"));
+}
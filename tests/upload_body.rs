@@ -0,0 +1,50 @@
+use rust_rest_api::negotiate::{upload_body, UploadBody};
+
+#[test]
+fn multipart_with_and_without_parameters() {
+    assert_eq!(upload_body(Some("multipart/form-data; boundary=xyz")), Some(UploadBody::Multipart));
+    assert_eq!(upload_body(Some("multipart/form-data;boundary=xyz")), Some(UploadBody::Multipart));
+    assert_eq!(upload_body(Some("multipart/form-data")), Some(UploadBody::Multipart));
+    assert_eq!(upload_body(Some("Multipart/Form-Data; Boundary=xyz")), Some(UploadBody::Multipart));
+    assert_eq!(upload_body(Some("multipart/mixed; boundary=xyz")), None);
+}
+
+#[test]
+fn json_variants() {
+    assert_eq!(upload_body(Some("application/json")), Some(UploadBody::Json));
+    assert_eq!(upload_body(Some("application/json; charset=utf-8")), Some(UploadBody::Json));
+    assert_eq!(upload_body(Some("APPLICATION/JSON")), Some(UploadBody::Json));
+    assert_eq!(upload_body(Some(" application/json ")), Some(UploadBody::Json));
+    assert_eq!(upload_body(Some("application/vnd.api+json")), Some(UploadBody::Json));
+    assert_eq!(upload_body(Some("text/json")), None);
+}
+
+#[test]
+fn form_urlencoded() {
+    assert_eq!(upload_body(Some("application/x-www-form-urlencoded")), Some(UploadBody::Form));
+    assert_eq!(
+        upload_body(Some("application/x-www-form-urlencoded; charset=UTF-8")),
+        Some(UploadBody::Form)
+    );
+}
+
+#[test]
+fn raw_images() {
+    assert_eq!(upload_body(Some("image/png")), Some(UploadBody::Raw(Some("png"))));
+    assert_eq!(upload_body(Some("image/jpeg")), Some(UploadBody::Raw(Some("jpg"))));
+    assert_eq!(upload_body(Some("image/JPEG; q=1")), Some(UploadBody::Raw(Some("jpg"))));
+    assert_eq!(upload_body(Some("application/octet-stream")), Some(UploadBody::Raw(None)));
+    assert_eq!(upload_body(Some("image/gif")), Some(UploadBody::Raw(Some("gif"))));
+    assert_eq!(upload_body(Some("image/webp")), Some(UploadBody::Raw(Some("webp"))));
+    // Known to be an image, but not one that is accepted
+    assert_eq!(upload_body(Some("image/tiff")), None);
+}
+
+#[test]
+fn missing_or_malformed() {
+    assert_eq!(upload_body(None), None);
+    assert_eq!(upload_body(Some("")), None);
+    assert_eq!(upload_body(Some("multipart")), None);
+    assert_eq!(upload_body(Some("text/plain")), None);
+    assert_eq!(upload_body(Some(";;;")), None);
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_util::stream::{self, StreamExt};
+
+use rust_rest_api::error::ApiError;
+use rust_rest_api::{upload_image, Config, UploadOptions};
+
+use common::ScratchDir;
+
+const SVG: &[u8] =
+    br#"<svg xmlns="http://www.w3.org/2000/svg" width="4" height="2"><rect width="4" height="2"/></svg>"#;
+
+// Under `dir`, shard directories aside
+fn files(dir: &std::path::Path) -> usize {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .map(|path| if path.is_dir() { files(&path) } else { 1 })
+        .sum()
+}
+
+fn chunks(data: &[u8]) -> Vec<std::io::Result<Bytes>> {
+    data.chunks(7).map(|chunk| Ok(Bytes::copy_from_slice(chunk))).collect()
+}
+
+#[actix_rt::test]
+async fn failed_uploads_leave_nothing() {
+    let dir = ScratchDir::new("upload_cleanup_errors");
+    let options = UploadOptions::from_config(&Config::default());
+
+    // The body breaks off
+    let mut body = chunks(SVG);
+    body.truncate(3);
+    body.push(Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset")));
+    let err = upload_image(stream::iter(body), &*dir, "svg", &options)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(ApiError::from(&err).status.as_u16(), 400);
+    assert_eq!(files(&dir), 0);
+
+    // Not what it claims to be
+    let res = upload_image(stream::iter(chunks(b"GIF89a but not really")), &*dir, "gif", &options).await;
+    assert!(res.is_err());
+    assert_eq!(files(&dir), 0);
+
+    let uploaded = upload_image(stream::iter(chunks(SVG)), &*dir, "svg", &options)
+        .await
+        .unwrap();
+    assert!(uploaded.path.is_file());
+}
+
+#[actix_rt::test]
+async fn abandoned_uploads_leave_nothing() {
+    let dir = ScratchDir::new("upload_cleanup_abandoned");
+    let options = UploadOptions::from_config(&Config::default());
+
+    // A client that stops sending and is given up on, the upload future is dropped
+    let body = stream::iter(chunks(&SVG[..20])).chain(stream::pending());
+    let upload = upload_image(body, &*dir, "svg", &options);
+    assert!(tokio::time::timeout(Duration::from_millis(100), upload).await.is_err());
+    assert_eq!(files(&dir), 0);
+}
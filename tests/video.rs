@@ -0,0 +1,89 @@
+mod common;
+
+use bytes::Bytes;
+
+use rust_rest_api::capabilities::capabilities;
+use rust_rest_api::error::ApiError;
+use rust_rest_api::imagetools::{self, VideoConfig};
+use rust_rest_api::negotiate::{upload_body, UploadBody};
+use rust_rest_api::{sniff_type, upload_image, Config, UploadOptions};
+
+use common::ScratchDir;
+
+// An `ftyp` box and the start of an EBML header, as files begin
+fn mp4_head(brand: &[u8; 4]) -> Vec<u8> {
+    let mut head = vec![0, 0, 0, 0x20];
+    head.extend_from_slice(b"ftyp");
+    head.extend_from_slice(brand);
+    head.extend_from_slice(&[0; 20]);
+    head
+}
+
+const WEBM_HEAD: &[u8] = b"\x1a\x45\xdf\xa3\x9f\x42\x86\x81\x01\x42\xf7\x81\x01\x42\x82\x84webm\x42\x87\x81\x04";
+
+#[test]
+fn clips_are_sniffed_by_container() {
+    assert_eq!(sniff_type(&mp4_head(b"isom")), "video/mp4");
+    assert_eq!(sniff_type(&mp4_head(b"mp42")), "video/mp4");
+    assert_eq!(sniff_type(WEBM_HEAD), "video/webm");
+    // Same container, but images
+    assert_eq!(imagetools::video_extension(&mp4_head(b"avif")), None);
+    assert_eq!(imagetools::video_extension(&mp4_head(b"heic")), None);
+    // Matroska that isn't WebM
+    let mkv = String::from_utf8_lossy(WEBM_HEAD).replace("webm", "mkvx");
+    assert_eq!(imagetools::video_extension(mkv.as_bytes()), None);
+
+    assert_eq!(upload_body(Some("video/mp4")), Some(UploadBody::Raw(Some("mp4"))));
+    assert_eq!(upload_body(Some("video/webm")), Some(UploadBody::Raw(Some("webm"))));
+    assert_eq!(upload_body(Some("video/quicktime")), None);
+}
+
+// Under `dir`, shard directories aside
+fn files(dir: &std::path::Path) -> usize {
+    std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .map(|path| if path.is_dir() { files(&path) } else { 1 })
+        .sum()
+}
+
+async fn upload_error(options: &UploadOptions, dir: &ScratchDir, data: Vec<u8>) -> ApiError {
+    let stream = futures_util::stream::iter(vec![Ok::<_, std::io::Error>(Bytes::from(data))]);
+    match upload_image(stream, &**dir, "mp4", options).await {
+        Ok(uploaded) => panic!("stored as {}", uploaded.id),
+        Err(err) => ApiError::from(&err),
+    }
+}
+
+#[actix_rt::test]
+async fn refused_unless_enabled_and_small() {
+    let dir = ScratchDir::new("video-upload");
+    let mut options = UploadOptions::from_config(&Config::default());
+
+    let error = upload_error(&options, &dir, mp4_head(b"isom")).await;
+    assert_eq!((error.status.as_u16(), error.code), (415, "unsupported_type"));
+
+    options.video = VideoConfig {
+        enabled: true,
+        max_size: 16,
+    };
+    let error = upload_error(&options, &dir, mp4_head(b"isom")).await;
+    assert_eq!((error.status.as_u16(), error.code), (413, "body_too_large"));
+    assert_eq!(error.body()["limit"], 16);
+    assert_eq!(files(&dir), 0);
+}
+
+#[test]
+fn capabilities_follow_the_config() {
+    let mut config = Config::default();
+    let off = capabilities(&config);
+    assert!(!off.features["video"]);
+    assert!(!off.input_formats.contains(&"video/mp4"));
+
+    config.video.enabled = true;
+    let on = capabilities(&config);
+    assert_eq!(on.features["video"], imagetools::VIDEO);
+    assert!(on.input_formats.contains(&"video/mp4"));
+    assert!(on.input_formats.contains(&"video/webm"));
+    assert_eq!(on.limits["max_video_size"], 50 << 20);
+}